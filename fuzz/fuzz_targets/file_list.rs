@@ -0,0 +1,12 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// the first byte picks the protocol version, and the rest is the list
+fuzz_target!(|data: &[u8]| {
+    // renamed files would be printed otherwise
+    sf::set_quiet(true);
+    if let Some((&version, list)) = data.split_first() {
+        sf::fuzz::file_list(version, list);
+    }
+});
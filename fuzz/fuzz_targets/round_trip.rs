@@ -0,0 +1,10 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// the first byte picks the protocol version, and the rest is what was sent
+fuzz_target!(|data: &[u8]| {
+    if let Some((&version, sent)) = data.split_first() {
+        sf::fuzz::round_trip(version, sent);
+    }
+});
@@ -0,0 +1,111 @@
+//! Encryption to a recipient in the age format (https://age-encryption.org/v1), so that files
+//! can be stored where anyone can read them, and yet only the one with the identity of the
+//! recipient can decrypt them, with `age -d -i key.txt` or any other implementation.
+//!
+//! The format itself is left to the `age` crate, which is the reference implementation in
+//! Rust, rather than put together again from its primitives.
+
+use age::stream::StreamWriter;
+use age::x25519;
+use std::io::{self, Write};
+use std::iter;
+
+/// The extension of the files once encrypted.
+pub const EXTENSION: &str = "age";
+
+/// The public key of someone files can be encrypted to, which only they can decrypt.
+#[derive(Clone)]
+pub struct Recipient(x25519::Recipient);
+
+impl Recipient {
+    /// Parses a recipient in its usual form, like `age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p`.
+    pub fn parse(text: &str) -> Option<Recipient> {
+        text.parse().ok().map(Recipient)
+    }
+}
+
+/// Encrypts everything written through it into the inner writer. Every chunk of the data is
+/// only encrypted once the next one starts, since the last one is marked as such.
+pub struct Writer<W: Write>(StreamWriter<W>);
+
+impl<W: Write> Writer<W> {
+    /// Writes the header, with a new key for the data only the recipient can get back.
+    pub fn new(inner: W, recipient: &Recipient) -> io::Result<Self> {
+        let encryptor = age::Encryptor::with_recipients(iter::once(&recipient.0 as _))
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        Ok(Writer(encryptor.wrap_output(inner)?))
+    }
+
+    /// Writes the last chunk, and gives back the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        self.0.finish()
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::secrecy::ExposeSecret;
+    use std::io::Read;
+
+    fn decrypt(data: &[u8], identity: &x25519::Identity) -> Result<Vec<u8>, age::DecryptError> {
+        let decryptor = age::Decryptor::new(data)?;
+        let mut reader = decryptor.decrypt(iter::once(identity as _))?;
+        let mut decrypted = Vec::new();
+        reader.read_to_end(&mut decrypted)?;
+        Ok(decrypted)
+    }
+
+    #[test]
+    fn parses_recipients() {
+        // the example of the specification, in both cases but not mixed
+        let recipient = "age1ql3z7hjy54pw3hyww5ayyfg7zqgvc7w3j2elw8zmrj2kg5sfn9aqmcac8p";
+        assert!(Recipient::parse(recipient).is_some());
+        assert!(Recipient::parse(&recipient.to_ascii_uppercase()).is_some());
+        assert!(Recipient::parse(&recipient.replacen('q', "Q", 1)).is_none());
+        // a wrong checksum, and the identity rather than the recipient
+        assert!(Recipient::parse(&recipient.replace("8p", "9p")).is_none());
+        let identity = x25519::Identity::generate();
+        assert!(Recipient::parse(identity.to_string().expose_secret()).is_none());
+    }
+
+    #[test]
+    fn only_the_recipient_can_decrypt() {
+        let identity = x25519::Identity::generate();
+        let recipient = Recipient::parse(&identity.to_public().to_string()).unwrap();
+        // more than a chunk, so that not only the last one is checked
+        let data = (0..200_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        let mut writer = Writer::new(Vec::new(), &recipient).unwrap();
+        writer.write_all(&data).unwrap();
+        let encrypted = writer.finish().unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/v1\n-> X25519 "));
+        assert_eq!(decrypt(&encrypted, &identity).unwrap(), data);
+
+        let other = x25519::Identity::generate();
+        assert!(decrypt(&encrypted, &other).is_err());
+        let mut tampered = encrypted.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&tampered, &identity).is_err());
+    }
+
+    #[test]
+    fn empty_files_can_be_decrypted() {
+        let identity = x25519::Identity::generate();
+        let recipient = Recipient::parse(&identity.to_public().to_string()).unwrap();
+        let encrypted = Writer::new(Vec::new(), &recipient)
+            .unwrap()
+            .finish()
+            .unwrap();
+        assert_eq!(decrypt(&encrypted, &identity).unwrap(), b"");
+    }
+}
@@ -0,0 +1,248 @@
+//! Directories sent as a single archive, and the archives a receiver stores every file in.
+//!
+//! Plain tar archives are made on the fly as they're sent, as their length is known from that
+//! of their members. Those compressed or with checksums in front of the data can't be, and
+//! are made up front in a temporary file instead, which is kept in case they're sent again.
+
+use crate::sink::{self, Sink};
+use crate::{cancel, tar, ArchiveFormat, CancelToken, Error, Result};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+const COPY_BUFFER_LEN: usize = 64 * 1024;
+
+// Tells the spools of the same process apart.
+static SPOOLS: AtomicUsize = AtomicUsize::new(0);
+
+/// The sink that writes every file into an archive of the given `format` in `out`.
+pub(crate) fn sink(format: ArchiveFormat, out: File) -> io::Result<Box<dyn Sink + Send>> {
+    let out = BufWriter::new(out);
+    Ok(match format {
+        ArchiveFormat::Tar => Box::new(sink::Tar::new(out)),
+        ArchiveFormat::TarZst => Box::new(sink::TarZst::new(out)?),
+        ArchiveFormat::Zip => Box::new(sink::Zip::new(out)),
+    })
+}
+
+/// The files of a directory, sent as a single archive.
+pub(crate) struct Archive {
+    pub format: ArchiveFormat,
+    pub members: Vec<tar::Member>,
+    // the archive and its length, if it was made up front
+    spool: Option<(PathBuf, u64)>,
+}
+
+impl Archive {
+    /// Archives the `members` in the given `format`, which reads all of them right away
+    /// unless it's a plain tar archive.
+    pub fn new(
+        format: ArchiveFormat,
+        members: Vec<tar::Member>,
+        cancel: &Option<CancelToken>,
+    ) -> Result<Self> {
+        let mut archive = Archive {
+            format,
+            members,
+            spool: None,
+        };
+        if format == ArchiveFormat::Tar {
+            return Ok(archive);
+        }
+        let spool = env::temp_dir().join(format!(
+            "sf-archive-{}-{}.{}",
+            std::process::id(),
+            SPOOLS.fetch_add(1, Ordering::Relaxed),
+            format.extension()
+        ));
+        let f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&spool)
+            .map_err(|e| Error::from(e).at(&spool))?;
+        // kept first, so that the spool is removed if making it fails
+        archive.spool = Some((spool.clone(), 0));
+        let mut out = sink(format, f).map_err(|e| Error::from(e).at(&spool))?;
+        let mut buffer = vec![0; COPY_BUFFER_LEN];
+        for member in &archive.members {
+            cancel::check(cancel)?;
+            let at = |e| Error::from(e).at(&member.path);
+            let mut f = File::open(&member.path).map_err(at)?.take(member.len);
+            out.open_entry(&member.name, member.len, Some(member.mtime))
+                .map_err(|e| Error::from(e).at(&spool))?;
+            let mut left = member.len;
+            while left != 0 {
+                let n = f.read(&mut buffer).map_err(at)?;
+                if n == 0 {
+                    return Err(format!("{:?} shrunk while being archived", member.path).into());
+                }
+                out.write_chunk(&buffer[..n])
+                    .map_err(|e| Error::from(e).at(&spool))?;
+                left -= n as u64;
+            }
+            out.close_entry().map_err(|e| Error::from(e).at(&spool))?;
+        }
+        out.finish().map_err(|e| Error::from(e).at(&spool))?;
+        drop(out);
+        let len = fs::metadata(&spool)
+            .map_err(|e| Error::from(e).at(&spool))?
+            .len();
+        archive.spool = Some((spool, len));
+        Ok(archive)
+    }
+
+    /// Length of the entire archive.
+    pub fn len(&self) -> u64 {
+        match &self.spool {
+            Some((_, len)) => *len,
+            None => tar::ArchiveReader::new(&self.members).len(),
+        }
+    }
+
+    /// Reads the archive from `offset` on.
+    pub fn open(&self, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        match &self.spool {
+            Some((path, len)) => {
+                let mut f = File::open(path)?;
+                f.seek(SeekFrom::Start(offset))?;
+                Ok(Box::new(f.take(len - offset)))
+            }
+            None => {
+                let mut archive = tar::ArchiveReader::new(&self.members);
+                archive.skip(offset);
+                Ok(Box::new(archive))
+            }
+        }
+    }
+}
+
+impl Drop for Archive {
+    fn drop(&mut self) {
+        if let Some((path, _)) = &self.spool {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extract::{Extraction, Format, Limits};
+    use crate::sink::Directory;
+    use std::io::Write;
+    use std::path::Path;
+
+    // A directory of its own for every test, emptied before it runs.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("sf-archive-test-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn members(dir: &Path) -> Vec<tar::Member> {
+        let files: [(&str, Vec<u8>); 3] = [
+            ("empty", Vec::new()),
+            ("hello.txt", b"hello, world\n".to_vec()),
+            (
+                "nested/data.bin",
+                (0..70_000u32).map(|i| (i * 7 % 251) as u8).collect(),
+            ),
+        ];
+        files
+            .iter()
+            .map(|(name, data)| {
+                let path = dir.join(name);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(&path, data).unwrap();
+                tar::Member {
+                    path,
+                    name: format!("tree/{}", name).into_bytes(),
+                    len: data.len() as u64,
+                    mtime: 1_600_000_000,
+                }
+            })
+            .collect()
+    }
+
+    // Unpack the archive in the given format into `output`, as a receiver would.
+    fn extract(format: Format, archive: &[u8], output: &Path) -> Vec<PathBuf> {
+        let directory = Directory::new(output);
+        let limits = Limits::default();
+        let mut extraction = Extraction::start(format, output.to_path_buf(), directory, limits);
+        for chunk in archive.chunks(1000) {
+            extraction.write_all(chunk).unwrap();
+        }
+        let (_, extracted) = extraction.finish(Ok(()));
+        extracted.unwrap().paths
+    }
+
+    #[test]
+    fn archives_are_unpacked_as_written() {
+        for (format, unpacked_as) in [
+            (ArchiveFormat::Tar, Format::Tar),
+            (ArchiveFormat::TarZst, Format::TarZst),
+            (ArchiveFormat::Zip, Format::Zip),
+        ] {
+            let dir = scratch(format.extension());
+            let members = members(&dir.join("tree"));
+            let path = dir.join("archive");
+            let mut out = sink(format, File::create(&path).unwrap()).unwrap();
+            for member in &members {
+                let data = fs::read(&member.path).unwrap();
+                out.open_entry(&member.name, member.len, Some(member.mtime))
+                    .unwrap();
+                for chunk in data.chunks(4096) {
+                    out.write_chunk(chunk).unwrap();
+                }
+                out.close_entry().unwrap();
+            }
+            out.finish().unwrap();
+            drop(out);
+
+            let output = dir.join("out");
+            let paths = extract(unpacked_as, &fs::read(&path).unwrap(), &output);
+            assert_eq!(paths.len(), members.len());
+            for member in &members {
+                let name = String::from_utf8(member.name.clone()).unwrap();
+                assert_eq!(
+                    fs::read(output.join(&name)).unwrap(),
+                    fs::read(&member.path).unwrap(),
+                    "{} was unpacked with other data from a {} archive",
+                    name,
+                    format.extension()
+                );
+            }
+            fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    // What is sent again after reconnecting is the rest of the same archive, which is only
+    // made once, and removed once it's no longer needed.
+    #[test]
+    fn spooled_archives_are_read_from_anywhere() {
+        let dir = scratch("spooled");
+        let archive = Archive::new(ArchiveFormat::Zip, members(&dir), &None).unwrap();
+        let mut whole = Vec::new();
+        archive.open(0).unwrap().read_to_end(&mut whole).unwrap();
+        assert_eq!(whole.len() as u64, archive.len());
+        for offset in [1, 4096, whole.len() as u64 - 1, whole.len() as u64] {
+            let mut rest = Vec::new();
+            archive
+                .open(offset)
+                .unwrap()
+                .read_to_end(&mut rest)
+                .unwrap();
+            assert!(rest == whole[offset as usize..], "differs from {}", offset);
+        }
+
+        let (spool, _) = archive.spool.clone().unwrap();
+        drop(archive);
+        assert!(!spool.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
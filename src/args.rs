@@ -1,100 +1,3157 @@
+use crate::profile;
+use sf::{
+    sink, source, ArchiveFormat, CaseCollisions, Compression, Duplicates, Normalization, Order,
+    PathPrefix, ReceiveOptions, SendOptions, ServeOptions, Sink, SocketOptions, Source, SumsFormat,
+    VerifyOptions, XattrNamespace,
+};
 use std::env;
-use std::net::IpAddr;
-use std::path::PathBuf;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::iter;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
 use std::process;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime};
 
 const HELP: [&str; 2] = ["-h", "--help"];
+const VERSION: [&str; 2] = ["-V", "--version"];
+const CAPABILITIES: [&str; 1] = ["--capabilities"];
 const STRIP_PREFIX: [&str; 2] = ["-s", "--strip-prefix"];
+const STRIP_DRIVE: [&str; 1] = ["--strip-drive"];
+const STRIP_COMPONENTS: [&str; 1] = ["--strip-components"];
+const MAP: [&str; 1] = ["--map"];
+const OUTPUT: [&str; 2] = ["-o", "--output"];
+const SESSION_DIRS: [&str; 1] = ["--session-dirs"];
+const STAGING: [&str; 1] = ["--staging"];
+const CASE_COLLISIONS: [&str; 1] = ["--case-collisions"];
+const CASE_POLICIES: [&str; 3] = ["ignore", "rename", "abort"];
+const DUPLICATES: [&str; 1] = ["--duplicates"];
+const DUPLICATE_POLICIES: [&str; 3] = ["error", "keep-first", "keep-last"];
+const NORMALIZE: [&str; 1] = ["--normalize"];
+const NORMALIZATION_FORMS: [&str; 3] = ["nfc", "nfd", "none"];
+// the filesystems of these tell apart names that differ only in case, by default
+const DEFAULT_CASE_POLICY: &str = if cfg!(any(windows, target_os = "macos")) {
+    "rename"
+} else {
+    "ignore"
+};
+const MIRROR: [&str; 1] = ["--mirror"];
+const DRY_RUN: [&str; 1] = ["--dry-run"];
+const UPDATE: [&str; 2] = ["-u", "--update"];
+const RESEND_CHANGED: [&str; 1] = ["--resend-changed"];
+const ARCHIVE: [&str; 2] = ["-a", "--archive"];
+const ARCHIVE_FORMATS: [&str; 3] = ["tar", "tar.zst", "zip"];
+const ORDER: [&str; 1] = ["--order"];
+const ORDERS: [&str; 4] = ["as-given", "small-first", "large-first", "alpha"];
+// given among the files, after the one it renames
+const AS: [&str; 1] = ["--as"];
+// everything after it is a file, even if it looks like an option
+const END_OF_OPTIONS: &str = "--";
+const RECV_TAR: [&str; 1] = ["--recv-tar"];
+const DISCARD: [&str; 1] = ["--discard"];
+const S3: [&str; 1] = ["--s3"];
+const LEGACY: [&str; 1] = ["--legacy"];
+const DEDUP: [&str; 1] = ["--dedup"];
+const HASHES: [&str; 1] = ["--hashes"];
+const RECEIVER_PROGRESS: [&str; 1] = ["--receiver-progress"];
+const ALLOW_METERED: [&str; 1] = ["--allow-metered"];
+const AUTO_PACK: [&str; 1] = ["--auto-pack"];
+const COMPRESS: [&str; 1] = ["--compress"];
+const COMPRESSIONS: [&str; 3] = ["never", "always", "auto"];
+const WAKE: [&str; 1] = ["--wake"];
+const CHECKSUM_DB: [&str; 1] = ["--checksum-db"];
+const WRITE_SUMS: [&str; 1] = ["--write-sums"];
+const SUMS_FORMATS: [&str; 2] = ["sha256sums", "json"];
+const LIST_ONLY: [&str; 1] = ["--list-only"];
+const LIST_JSON: [&str; 1] = ["--list-json"];
+const EXTRACT: [&str; 1] = ["--extract"];
+const ENCRYPT_AT_REST: [&str; 1] = ["--encrypt-at-rest"];
+const IDENTITY: [&str; 1] = ["--identity"];
+const AUTHORIZED_SENDERS: [&str; 1] = ["--authorized-senders"];
+const HARDLINKS: [&str; 1] = ["--hardlinks"];
+const SPECIALS: [&str; 1] = ["--specials"];
+const MAX_DEPTH: [&str; 1] = ["--max-depth"];
+const ONE_FILE_SYSTEM: [&str; 1] = ["--one-file-system"];
+const XATTRS: [&str; 1] = ["--xattrs"];
+const ATTRIBUTES: [&str; 1] = ["--attributes"];
+const CHOWN: [&str; 1] = ["--chown"];
+const MODE: [&str; 1] = ["--mode"];
+const DIR_MODE: [&str; 1] = ["--dir-mode"];
+const MANIFEST: [&str; 1] = ["--manifest"];
+const FROM_TAR: [&str; 1] = ["--from-tar"];
+const FROM_STDIN: [&str; 1] = ["--from-stdin"];
+const FILES_FROM: [&str; 1] = ["--files-from"];
+const NULL: [&str; 2] = ["-0", "--null"];
+const BENCH_SIZE: [&str; 1] = ["--bench-size"];
+const DEFAULT_BENCH_MIB: u64 = 256;
+const BENCH_PATTERN: [&str; 1] = ["--bench-pattern"];
+const BENCH_PATTERNS: [&str; 3] = ["random", "zeros", "text"];
+const BENCH_NAME: &str = "sf-bench";
+const XATTR_NAMESPACES: [&str; 4] = ["user", "security", "trusted", "acl"];
+const DEFAULT_RECV_XATTRS: [XattrNamespace; 2] = [XattrNamespace::User, XattrNamespace::Acl];
+const TIMEOUT: [&str; 2] = ["-t", "--timeout"];
+const DEFAULT_TIMEOUT_SECS: u64 = 30;
+const RECONNECT: [&str; 2] = ["-r", "--reconnect"];
+const DEFAULT_RECONNECT_SECS: u64 = 60;
+const RETRY: [&str; 1] = ["--retry"];
+const RETRY_FOR: [&str; 1] = ["--retry-for"];
+const AT: [&str; 1] = ["--at"];
+const AFTER: [&str; 1] = ["--after"];
+const BUSY: [&str; 1] = ["--busy"];
+const DEFAULT_RETRY_SECS: u64 = 60;
+const CHUNK_SIZE: [&str; 2] = ["-c", "--chunk-size"];
+const SEND_BUFFER: [&str; 1] = ["--send-buffer"];
+const RECV_BUFFER: [&str; 1] = ["--recv-buffer"];
+const NODELAY: [&str; 1] = ["--nodelay"];
+const TUI: [&str; 1] = ["--tui"];
+const NOTIFY: [&str; 1] = ["--notify"];
+const JSON: [&str; 1] = ["--json"];
+const STATS: [&str; 1] = ["--stats"];
+const OPEN: [&str; 1] = ["--open"];
+const QR: [&str; 1] = ["--qr"];
+const HTTP: [&str; 1] = ["--http"];
+const DEFAULT_HTTP_PORT: u16 = 8371;
+const METRICS: [&str; 1] = ["--metrics"];
+const DOWNLOADS: [&str; 1] = ["--downloads"];
+const TOKEN: [&str; 1] = ["--token"];
+const EXPIRE: [&str; 1] = ["--expire"];
+const DEFAULT_TOKEN_EXPIRE_SECS: u64 = 60 * 60;
+const INCLUDE_VIRTUAL: [&str; 1] = ["--include-virtual"];
+const SERVICE: [&str; 1] = ["--service"];
+const QUOTA_PER_PEER: [&str; 1] = ["--quota-per-peer"];
+const QUOTA: [&str; 1] = ["--quota"];
+const ACCEPT_EXT: [&str; 1] = ["--accept-ext"];
+const REJECT_EXT: [&str; 1] = ["--reject-ext"];
+const REJECT_LARGER: [&str; 1] = ["--reject-larger"];
+const ON_COMPLETE: [&str; 1] = ["--on-complete"];
+const ON_SESSION_COMPLETE: [&str; 1] = ["--on-session-complete"];
+const WEBHOOK: [&str; 1] = ["--webhook"];
+const VERBOSE: [&str; 2] = ["-v", "--verbose"];
+const VERY_VERBOSE: &str = "-vv";
+const QUIET: [&str; 2] = ["-q", "--quiet"];
+const BYTES: [&str; 1] = ["--bytes"];
+const LOG_FILE: [&str; 1] = ["--log-file"];
+const PROFILE: [&str; 1] = ["--profile"];
+const DEFAULT_DOWNLOADS: usize = 1;
 const AUTO_IP: &str = "auto";
+const SEND_COMMAND: &str = "send";
+const RECV_COMMAND: &str = "recv";
+const DISCOVER_COMMAND: &str = "discover";
+const HISTORY_COMMAND: &str = "history";
+const VERIFY: &str = "verify";
+const SERVE: &str = "serve";
+const BENCH: &str = "bench";
+const WAKE_COMMAND: &str = "wake";
+const SERVICE_COMMAND: &str = "service";
+const SERVICE_ACTIONS: [&str; 3] = ["install", "uninstall", "run"];
+const QUEUE_COMMAND: &str = "queue";
+const QUEUE_ACTIONS: [&str; 3] = ["add", "run", "status"];
+const QUEUE_FILE: [&str; 1] = ["--queue-file"];
+const RETRIES: [&str; 1] = ["--retries"];
+const DEFAULT_RETRIES: u64 = 3;
+const RETRY_DELAY: [&str; 1] = ["--retry-delay"];
+const DEFAULT_RETRY_DELAY_SECS: u64 = 60;
+const JOBS: [&str; 2] = ["-j", "--jobs"];
+const DEFAULT_JOBS: usize = 1;
 
 pub struct Settings {
     pub mode: Mode,
+    /// Show the progress of the receiver in a full screen interface.
+    pub tui: bool,
+    /// Show a desktop notification once done.
+    pub notify: bool,
+    /// Print the summary as a JSON object, like those kept in the history.
+    pub json: bool,
+    /// Print a graph of the throughput over the last minute every few seconds.
+    pub stats: bool,
+    /// Open the received file, or the directory with them if there were more.
+    pub open: bool,
+    /// Show a QR code with the address of the receiver once it's listening.
+    pub qr: bool,
+    /// Serve the metrics of the receiver for Prometheus on this address.
+    pub metrics: Option<SocketAddr>,
+    /// Keep receiving as a service, until terminated.
+    pub service: bool,
+    /// Write the received files to the standard output as an archive.
+    pub recv_tar: bool,
+    /// Command to run after each file is received.
+    pub on_complete: Option<String>,
+    /// Command to run after each session of the receiver.
+    pub on_session_complete: Option<String>,
+    /// How many times more detail was asked for in the log.
+    pub verbosity: u8,
+    /// Print only the errors and the summary, and not what's being done meanwhile.
+    pub quiet: bool,
+    /// Print sizes as the exact number of bytes.
+    pub exact_sizes: bool,
+    /// Where to log to, instead of only the details to the standard error.
+    pub log_file: Option<PathBuf>,
+    /// Run under the Windows service manager, which started the process.
+    pub system_service: bool,
+    /// Wake the machine with this hardware address before connecting to it.
+    pub wake: Option<sf::MacAddress>,
 }
 
 pub enum Mode {
-    Receiver {
-        prefix: PathPrefix,
-    },
+    Receiver(ReceiveOptions),
     Sender {
         ip: ServerAddress,
         files: Vec<PathBuf>,
+        options: SendOptions,
+    },
+    Verify {
+        ip: ServerAddress,
+        files: Vec<PathBuf>,
+        options: VerifyOptions,
+    },
+    Serve {
+        files: Vec<PathBuf>,
+        options: ServeOptions,
+    },
+    Bench {
+        ip: ServerAddress,
+        options: SendOptions,
+    },
+    /// Wake the machine with this hardware address, and do nothing else.
+    Wake {
+        mac: sf::MacAddress,
+    },
+    /// Install the receiver as a Windows service, with these arguments.
+    InstallService {
+        args: Vec<String>,
+    },
+    UninstallService,
+    /// Add a send job with these arguments to the queue, to be run from the directory.
+    QueueAdd {
+        queue: PathBuf,
+        args: Vec<String>,
+        dir: PathBuf,
+        retries: u64,
+        retry_delay: Duration,
+    },
+    /// Run the jobs in the queue until none are left, this many at a time.
+    QueueRun {
+        queue: PathBuf,
+        jobs: usize,
+    },
+    QueueStatus {
+        queue: PathBuf,
+    },
+    /// Look for a receiver announcing itself, for up to this long if given, and print it.
+    Discover {
+        window: Option<Duration>,
+    },
+    /// List the transfers made so far.
+    History {
+        path: PathBuf,
     },
-}
-
-pub enum PathPrefix {
-    Keep,
-    Strip,
 }
 
 pub enum ServerAddress {
     Auto,
-    Direct(IpAddr),
+    Direct(SocketAddr),
 }
 
-pub fn parse() -> Settings {
+/// An option as the help of the commands that take it lists it.
+struct Opt {
+    names: &'static [&'static str],
+    /// What it's followed by, such as `<DIR>`, or `[PORT]` if it may be left out.
+    value: &'static str,
+    /// What it does, a line at a time, usually ending with what it is by default.
+    help: &'static [&'static str],
+}
+
+/// A command, with what the help says about it and the options it takes.
+struct Command {
+    name: &'static str,
+    /// What it's for, as the title of its usage.
+    title: &'static str,
+    /// How it's used, after the name of the program.
+    usage: &'static [&'static str],
+    about: &'static [&'static str],
+    options: &'static [&'static [Opt]],
+}
+
+const CASE_DEFAULT: &str = if cfg!(any(windows, target_os = "macos")) {
+    "default = rename"
+} else {
+    "default = ignore"
+};
+
+const GENERAL_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &HELP,
+        value: "",
+        help: &["display this message and exit"],
+    },
+    Opt {
+        names: &VERSION,
+        value: "",
+        help: &["display the version, and that of the protocol, and exit"],
+    },
+    Opt {
+        names: &CAPABILITIES,
+        value: "",
+        help: &[
+            "display what this build supports as JSON, such as the protocol versions,",
+            "transports, compression and encryption, and exit",
+        ],
+    },
+    Opt {
+        names: &VERBOSE,
+        value: "",
+        help: &[
+            "log more details to the standard error, such as the handshake and the",
+            "packets with which receivers announce themselves",
+            "given twice (or as -vv), also every batch of the file list and every chunk",
+            "SF_LOG replaces this with filters like `debug` or `sf=trace,warn`",
+            "every line says which transfer session it's about",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &QUIET,
+        value: "",
+        help: &[
+            "print only the errors and the summary, such as when running from cron",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &BYTES,
+        value: "",
+        help: &[
+            "print sizes as the exact number of bytes, rather than like 1.4 GiB",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &LOG_FILE,
+        value: "<PATH>",
+        help: &[
+            "append everything logged to PATH, including what is printed",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &PROFILE,
+        value: "<NAME>",
+        help: &[
+            "use the options of the [profile.NAME] section in the config file",
+            "as if given in its place, so that those after it override them; the file",
+            "is ~/.config/sf/config.toml, or %APPDATA%\\sf\\config.toml on Windows",
+            "default = none",
+        ],
+    },
+];
+
+const REPORT_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &NOTIFY,
+        value: "",
+        help: &[
+            "show a desktop notification summarizing the transfer once it's over",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &JSON,
+        value: "",
+        help: &[
+            "print the summary of the transfer as a JSON object, with the same fields",
+            "as the entries of the history",
+            "default = false",
+        ],
+    },
+];
+
+const STATS_OPTIONS: &[Opt] = &[Opt {
+    names: &STATS,
+    value: "",
+    help: &[
+        "every few seconds, print a graph of how fast the transfer went during",
+        "every second of the last minute, to the standard error",
+        "default = false",
+    ],
+}];
+
+const SOCKET_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &TIMEOUT,
+        value: "<SECS>",
+        help: &[
+            "abort if the peer does not respond for this long (0 to wait forever)",
+            "default = 30",
+        ],
+    },
+    Opt {
+        names: &SEND_BUFFER,
+        value: "<SIZE>",
+        help: &[
+            "size of the kernel send buffer for the connection",
+            "default = system default",
+        ],
+    },
+    Opt {
+        names: &RECV_BUFFER,
+        value: "<SIZE>",
+        help: &[
+            "size of the kernel receive buffer for the connection",
+            "default = system default",
+        ],
+    },
+    Opt {
+        names: &NODELAY,
+        value: "",
+        help: &[
+            "send small writes immediately instead of coalescing them",
+            "default = false",
+        ],
+    },
+];
+
+const TRANSFER_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &RECONNECT,
+        value: "<SECS>",
+        help: &[
+            "how long to wait for the peer to reconnect after a connection is lost",
+            "the transfer then continues where it left off (0 to fail instead)",
+            "default = 60",
+        ],
+    },
+    Opt {
+        names: &CHUNK_SIZE,
+        value: "<SIZE>",
+        help: &[
+            "how much data is read or written at once (e.g. 512K or 8M)",
+            "when sending, this is proposed to the receiver instead of picking one",
+            "based on the file sizes; when receiving, this is the largest accepted",
+            "default = auto",
+        ],
+    },
+];
+
+const FIND_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &RETRY,
+        value: "",
+        help: &[
+            "keep trying to find the receiver until it's listening, waiting longer",
+            "between attempts, and for up to 60s, whether it's connected to directly",
+            "or announces itself",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &RETRY_FOR,
+        value: "<SECS>",
+        help: &[
+            "like --retry, but for up to this long instead",
+            "default = 60",
+        ],
+    },
+];
+
+const WAKE_OPTIONS: &[Opt] = &[Opt {
+    names: &WAKE,
+    value: "<MAC>",
+    help: &[
+        "wake the machine with this hardware address first, and keep trying to",
+        "find the receiver while it comes up, as with --retry",
+        "default = none",
+    ],
+}];
+
+const SCHEDULE_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &AT,
+        value: "<HH:MM>",
+        help: &[
+            "wait until this time of the day (in local time) before looking at the",
+            "files and connecting to the receiver",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &AFTER,
+        value: "<DURATION>",
+        help: &[
+            "like --at, but wait this long instead (e.g. 90s, 30m or 2h)",
+            "default = none",
+        ],
+    },
+];
+
+const PROTOCOL_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &LEGACY,
+        value: "",
+        help: &[
+            "send using the oldest protocol version, for receivers not yet upgraded",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &COMPRESS,
+        value: "<WHEN>",
+        help: &[
+            "compress the data of the files as it's sent",
+            "with auto, only while it shrinks and that gets it there sooner;",
+            "files already compressed, such as jpg or zip, are sent as they are",
+            "one of: never, always, auto",
+            "default = never",
+        ],
+    },
+    Opt {
+        names: &HASHES,
+        value: "",
+        help: &[
+            "send the hash of every file along with it",
+            "so that receivers with a checksum database can copy the files they",
+            "already have instead, at the cost of hashing them first",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &RECEIVER_PROGRESS,
+        value: "",
+        help: &[
+            "have the receiver say every second how much it has stored",
+            "which is shown instead of what is still in the network buffers, and warn",
+            "if it stops storing anything for a while",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &ALLOW_METERED,
+        value: "",
+        help: &[
+            "send more than a gigabyte over a metered network",
+            "such as a phone's hotspot, which Windows and macOS can tell apart;",
+            "without it, smaller transfers over one only warn about it",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &IDENTITY,
+        value: "<FILE>",
+        help: &[
+            "prove to the receiver that the files come from the identity in FILE,",
+            "which is created if it's not there yet; its public key is what goes in",
+            "the list of the receiver",
+            "default = none",
+        ],
+    },
+];
+
+const SEND_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &AS,
+        value: "<NAME>",
+        help: &[
+            "given right after one of the FILES, send it under NAME instead, such as",
+            "`build/output --as release' to send build/output/app as release/app",
+        ],
+    },
+    Opt {
+        names: &UPDATE,
+        value: "",
+        help: &[
+            "skip sending files that the receiver already has with the same size",
+            "and modification time",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &RESEND_CHANGED,
+        value: "",
+        help: &[
+            "send the files that changed while being sent once more at the end, up to",
+            "3 times, so that the receiver ends up with them as they are now",
+            "this relies on the receiver keeping the last of files with the same name",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &ARCHIVE,
+        value: "<FORMAT>",
+        help: &[
+            "send each directory as a single archive",
+            "the available formats are: tar, tar.zst, zip",
+            "all but tar are made in a temporary file before sending anything",
+        ],
+    },
+    Opt {
+        names: &ORDER,
+        value: "<ORDER>",
+        help: &[
+            "the order in which to send the files",
+            "one of: as-given, small-first, large-first, alpha",
+            "what's inside each directory given is sorted by name with as-given",
+            "default = as-given",
+        ],
+    },
+    Opt {
+        names: &DEDUP,
+        value: "",
+        help: &[
+            "send the contents of identical files only once",
+            "the receiver then copies them itself, at the cost of hashing them first",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &AUTO_PACK,
+        value: "",
+        help: &[
+            "when sending many small files, pack those of each directory together",
+            "into an archive the receiver unpacks, rather than listing each of them;",
+            "receivers that can't unpack it still get them one by one",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &HARDLINKS,
+        value: "",
+        help: &[
+            "have the receiver keep hard links to the same file linked",
+            "rather than storing a copy for each of them",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &SPECIALS,
+        value: "",
+        help: &[
+            "send FIFOs for the receiver to make again, rather than",
+            "skipping them; sockets and devices are always skipped",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &MAX_DEPTH,
+        value: "<N>",
+        help: &[
+            "only look N directories deep inside the directories given,",
+            "so 1 sends the files directly in them and none of their folders",
+            "default = no limit",
+        ],
+    },
+    Opt {
+        names: &ONE_FILE_SYSTEM,
+        value: "",
+        help: &[
+            "don't look inside the directories that are on another file system than",
+            "the directory given, like mounted network shares, which are sent empty",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &XATTRS,
+        value: "<NAMESPACES>",
+        help: &[
+            "extended attributes to send",
+            "a comma-separated list of: user, security, trusted, acl (the POSIX ACLs)",
+            "files whose attributes can't be read are still sent",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &ATTRIBUTES,
+        value: "",
+        help: &[
+            "also send when every file was created, and whether it's",
+            "read-only or hidden, for the receiver to restore where it can",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &MANIFEST,
+        value: "<FILE>",
+        help: &[
+            "first write the files and their hashes to FILE as JSON",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &FROM_TAR,
+        value: "<ARCHIVE>",
+        help: &[
+            "send the files inside the tar ARCHIVE instead",
+            "as if it had been extracted, without extracting it",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &FROM_STDIN,
+        value: "<NAME>",
+        help: &[
+            "send what is read from the standard input instead,",
+            "as a file called NAME, once the input ends",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &FILES_FROM,
+        value: "<FILE>",
+        help: &[
+            "send the paths listed in FILE, one per line, instead",
+            "of the paths given, or those read from the standard input if FILE is -;",
+            "the directories listed are not looked inside, unless --max-depth says how deep",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &NULL,
+        value: "",
+        help: &[
+            "the paths listed for --files-from are separated by NUL characters rather",
+            "than lines, like `find -print0` writes them",
+            "default = false",
+        ],
+    },
+];
+
+const BENCH_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &BENCH_SIZE,
+        value: "<MIB>",
+        help: &["how many MiB to send", "default = 256"],
+    },
+    Opt {
+        names: &BENCH_PATTERN,
+        value: "<PATTERN>",
+        help: &[
+            "what the data sent looks like",
+            "the available patterns are: random, zeros, text",
+            "default = random",
+        ],
+    },
+];
+
+const VERIFY_OPTIONS: &[Opt] = &[Opt {
+    names: &MANIFEST,
+    value: "<FILE>",
+    help: &[
+        "check the files in FILE, as written when sending with it, instead of",
+        "local ones",
+        "default = none",
+    ],
+}];
+
+const SERVE_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &HTTP,
+        value: "[PORT]",
+        help: &[
+            "the port the files are served on; given alone, as in",
+            "`sf serve --http FILES...', it only says how they're served",
+            "default = 8371",
+        ],
+    },
+    Opt {
+        names: &DOWNLOADS,
+        value: "<N>",
+        help: &["how many times each file can be downloaded", "default = 1"],
+    },
+    Opt {
+        names: &TOKEN,
+        value: "",
+        help: &[
+            "print a single code granting access to exactly the files served, and",
+            "the link to a page listing them, from which the whole tree can be",
+            "fetched (such as with `wget -r -np -nH <LINK>`), instead of a link for",
+            "every file; it expires after 3600 seconds unless --expire says otherwise",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &EXPIRE,
+        value: "<SECS>",
+        help: &[
+            "stop after SECS even if the files can still be downloaded",
+            "default = none, or 3600 with --token",
+        ],
+    },
+    Opt {
+        names: &INCLUDE_VIRTUAL,
+        value: "",
+        help: &[
+            "consider virtual interfaces like any other",
+            "those of containers and virtual machines are otherwise used last",
+            "default = false",
+        ],
+    },
+];
+
+const RECEIVE_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &STRIP_PREFIX,
+        value: "",
+        help: &[
+            "strip the common prefix from the received file paths",
+            "this is useful when receiving absolute paths from a drive you don't have,",
+            "since the drive portion will be removed as long as all paths share it",
+            "when several paths are sent, each keeps the name it was sent with",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &STRIP_DRIVE,
+        value: "",
+        help: &[
+            "remove the drive, like C:, from the received file paths that have one",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &STRIP_COMPONENTS,
+        value: "<N>",
+        help: &[
+            "remove the first N directories from the received file paths, after",
+            "the prefix and the drive; files with no more than N are rejected",
+            "default = 0",
+        ],
+    },
+    Opt {
+        names: &MAP,
+        value: "<FROM=>TO>",
+        help: &[
+            "store the files sent in the directory FROM in TO instead, after",
+            "stripping their paths, such as 'home/alice/=>projects/'",
+            "may be given more than once, and the first that applies to a file is used",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &OUTPUT,
+        value: "<DIR>",
+        help: &[
+            "directory where the received files are stored",
+            "default = .",
+        ],
+    },
+    Opt {
+        names: &SESSION_DIRS,
+        value: "",
+        help: &[
+            "store the files of every transfer in their own directory inside the output",
+            "named sf-<timestamp>-<sender>, so that transfers don't mix",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &STAGING,
+        value: "<DIR>",
+        help: &[
+            "write the files of every transfer into its own directory inside DIR first,",
+            "and only move them into the output once all of them were received",
+            "(and match their hash, if the sender sent them with --hashes), so that",
+            "nobody sees half of them; they're still compared with those in the output,",
+            "and said to be there; DIR must be on the same file system",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &CASE_COLLISIONS,
+        value: "<POLICY>",
+        help: &[
+            "what to do with received names that differ only in case, which",
+            "are the same file on case-insensitive filesystems: store them as sent,",
+            "rename the later ones like \"README (2).md\", or abort the transfer",
+            "the available policies are: ignore, rename, abort",
+            CASE_DEFAULT,
+        ],
+    },
+    Opt {
+        names: &DUPLICATES,
+        value: "<POLICY>",
+        help: &[
+            "what to do with received files listed under the same name as",
+            "another before them, as when the sender was given overlapping paths:",
+            "abort the transfer, keep the one listed first, or keep the last one",
+            "the available policies are: error, keep-first, keep-last",
+            "default = keep-last",
+        ],
+    },
+    Opt {
+        names: &NORMALIZE,
+        value: "<FORM>",
+        help: &[
+            "normalize the unicode in received names to this form, such as the",
+            "decomposed names of macOS to the composed names most systems use; names",
+            "that end up the same are handled like those that differ only in case",
+            "the available forms are: nfc, nfd, none",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &MIRROR,
+        value: "",
+        help: &[
+            "delete files in the output directory that were not sent",
+            "this happens only after every file was received, and asks first",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &DRY_RUN,
+        value: "",
+        help: &[
+            "only list the files that would be deleted by --mirror",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &ARCHIVE,
+        value: "<FORMAT>",
+        help: &[
+            "store all the files in a single archive",
+            "the available formats are: tar, tar.zst, zip",
+        ],
+    },
+    Opt {
+        names: &RECV_TAR,
+        value: "",
+        help: &[
+            "write a tar archive with the files to the standard output",
+            "so that it can be piped elsewhere; messages go to the standard error",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &DISCARD,
+        value: "",
+        help: &[
+            "throw the files away, to measure how fast the network is",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &S3,
+        value: "<URL>",
+        help: &[
+            "upload the files to an S3 bucket, given like",
+            "https://host:port/bucket/prefix, with the credentials in AWS_ACCESS_KEY_ID",
+            "and AWS_SECRET_ACCESS_KEY (and optionally AWS_REGION, AWS_SESSION_TOKEN);",
+            "only in builds with the s3 feature",
+            "default = (none)",
+        ],
+    },
+    Opt {
+        names: &CHECKSUM_DB,
+        value: "<FILE>",
+        help: &[
+            "index the files in the output by their hash in FILE",
+            "and copy those sent with their hash from there, rather than receiving",
+            "them again; the files that changed are hashed again on every start",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &WRITE_SUMS,
+        value: "<FORMAT>",
+        help: &[
+            "once the files are stored, hash them and list",
+            "them in the output, in a SHA256SUMS file for sha256sum -c, or in a JSON",
+            "manifest with their sizes too, named sf-manifest.json",
+            "one of: sha256sums, json",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &LIST_ONLY,
+        value: "",
+        help: &[
+            "only list the files the sender is about to send, with",
+            "their sizes, and then decline all of them",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &LIST_JSON,
+        value: "<FILE>",
+        help: &[
+            "with --list-only, also write the list to FILE as JSON",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &EXTRACT,
+        value: "",
+        help: &[
+            "unpack the .tar, .tar.gz, .tgz, .tar.zst and .zip files sent into",
+            "the directory they were sent to as they arrive, instead of storing them;",
+            "their files are held to the filters and quotas, and archives that",
+            "unpack to over 100 times their size (or 64 MiB) are refused",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &ENCRYPT_AT_REST,
+        value: "<RECIPIENT>",
+        help: &[
+            "encrypt every file to the age RECIPIENT",
+            "(like age1...) as it's stored, with .age after its name, so that only",
+            "the one with its identity can read it, with `age -d -i key.txt`",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &AUTHORIZED_SENDERS,
+        value: "<FILE>",
+        help: &[
+            "only accept files from the senders whose",
+            "public keys are listed in FILE, one per line, such as",
+            "`sf-ed25519 <key in hex> laptop`, and say which one sent them",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &XATTRS,
+        value: "<NAMESPACES>",
+        help: &[
+            "extended attributes to set",
+            "a comma-separated list of: user, security, trusted, acl (the POSIX ACLs)",
+            "files whose attributes can't be set are still stored",
+            "default = user,acl",
+        ],
+    },
+    Opt {
+        names: &CHOWN,
+        value: "<USER[:GROUP]>",
+        help: &[
+            "give the files and the directories made",
+            "for them to this user and group (names or numbers, or just :GROUP)",
+            "before they get their final name; mostly useful when running as root",
+            "default = whoever receives them",
+        ],
+    },
+    Opt {
+        names: &MODE,
+        value: "<MODE>",
+        help: &[
+            "give the files these octal permissions (such as 0644) whatever the umask",
+            "is, before they get their final name; only on unix",
+            "default = whatever the umask leaves",
+        ],
+    },
+    Opt {
+        names: &DIR_MODE,
+        value: "<MODE>",
+        help: &[
+            "like --mode, but for the directories made for the files (such as 0755)",
+            "default = whatever the umask leaves",
+        ],
+    },
+    Opt {
+        names: &TUI,
+        value: "",
+        help: &[
+            "show the progress in a full screen interface",
+            "where the transfer can also be paused (p) or aborted (q)",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &OPEN,
+        value: "",
+        help: &[
+            "open the file once it's received with the program for",
+            "its type, or the directory they're in if there were more files",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &QR,
+        value: "",
+        help: &[
+            "show a QR code a sender can scan to connect, and only",
+            "accept the senders that know the key in it",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &HTTP,
+        value: "<PORT>",
+        help: &[
+            "also serve a page on PORT to upload files from a browser",
+            "for those without sf, while waiting for and during the transfer",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &METRICS,
+        value: "<[ADDR:]PORT>",
+        help: &[
+            "serve the counts of sessions, failures and",
+            "bytes received, in total and by sender, and the current throughput, at",
+            "/metrics on PORT for Prometheus to scrape, only locally unless ADDR says",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &INCLUDE_VIRTUAL,
+        value: "",
+        help: &[
+            "consider virtual interfaces like any other",
+            "those of containers and virtual machines are otherwise used last",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &SERVICE,
+        value: "",
+        help: &[
+            "keep receiving from one sender after another until terminated",
+            "meant to run under a service manager, so the output is kept to lines,",
+            "and a listening socket handed over by systemd is used if there is one",
+            "default = false",
+        ],
+    },
+    Opt {
+        names: &BUSY,
+        value: "<HH:MM-HH:MM,...>",
+        help: &[
+            "turn away the senders that connect",
+            "within these times of the day (in local time), which may go past",
+            "midnight; senders are told when the receiver is free again, and wait",
+            "until then to connect again",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &QUOTA_PER_PEER,
+        value: "<SIZE>",
+        help: &[
+            "the most bytes accepted from each sender",
+            "counted by address over every transfer received, and those that would",
+            "go over it are refused when their files are listed",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &QUOTA,
+        value: "<SIZE>",
+        help: &[
+            "the most bytes accepted from all senders together",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &ACCEPT_EXT,
+        value: "<EXT,...>",
+        help: &[
+            "only accept the files with one of these",
+            "extensions, in any case, and reject the rest; those with the extensions",
+            "given to --reject-ext are rejected too, as are those larger than",
+            "--reject-larger; the sender is told which files were rejected and",
+            "doesn't send them",
+            "default = accept all",
+        ],
+    },
+    Opt {
+        names: &REJECT_EXT,
+        value: "<EXT,...>",
+        help: &[
+            "reject the files with one of these extensions, in any case",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &REJECT_LARGER,
+        value: "<SIZE>",
+        help: &["reject the files larger than SIZE", "default = none"],
+    },
+    Opt {
+        names: &ON_COMPLETE,
+        value: "<CMD>",
+        help: &[
+            "run CMD through the shell after each file",
+            "with SF_PATH, SF_BYTES, SF_PEER and SF_STATUS describing the file",
+            "commands run one at a time, while the transfer goes on",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &ON_SESSION_COMPLETE,
+        value: "<CMD>",
+        help: &[
+            "run CMD through the shell after each session",
+            "with SF_PATH (the output directory), SF_FILES, SF_BYTES, SF_PEER,",
+            "SF_STATUS (done or failed), and SF_ERROR if it failed",
+            "default = none",
+        ],
+    },
+    Opt {
+        names: &WEBHOOK,
+        value: "<URL>",
+        help: &[
+            "POST a JSON summary of each session to URL, with",
+            "its peer, status, duration, and the count, size and hash of the files",
+            "stored, over https:// or http://",
+            "default = none",
+        ],
+    },
+];
+
+const QUEUE_OPTIONS: &[Opt] = &[
+    Opt {
+        names: &HELP,
+        value: "",
+        help: &["display this message and exit"],
+    },
+    Opt {
+        names: &QUEUE_FILE,
+        value: "<FILE>",
+        help: &[
+            "keep the jobs in FILE instead",
+            "default = sf/queue.json in the state directory of the user",
+        ],
+    },
+    Opt {
+        names: &RETRIES,
+        value: "<N>",
+        help: &[
+            "when adding, how many more times to try the job if it fails",
+            "default = 3",
+        ],
+    },
+    Opt {
+        names: &RETRY_DELAY,
+        value: "<DURATION>",
+        help: &[
+            "when adding, how long to wait before trying the job again (e.g. 90s or 5m)",
+            "default = 60",
+        ],
+    },
+    Opt {
+        names: &JOBS,
+        value: "<N>",
+        help: &[
+            "when running, how many jobs to send at a time",
+            "default = 1",
+        ],
+    },
+];
+
+const RECEIVER_OPTIONS: &[&[Opt]] = &[
+    GENERAL_OPTIONS,
+    REPORT_OPTIONS,
+    STATS_OPTIONS,
+    SOCKET_OPTIONS,
+    TRANSFER_OPTIONS,
+    RECEIVE_OPTIONS,
+];
+
+// The commands as the help lists them, the first being what runs when no other is given.
+const COMMANDS: [Command; 10] = [
+    Command {
+        name: RECV_COMMAND,
+        title: "receive files",
+        usage: &["[recv] [OPTIONS...]"],
+        about: &[
+            "press enter (or send SIGUSR1) to pause and resume receiving, such as when",
+            "the disk is busy; the sender waits meanwhile",
+        ],
+        options: RECEIVER_OPTIONS,
+    },
+    Command {
+        name: SEND_COMMAND,
+        title: "send files",
+        usage: &["[send] [OPTIONS...] <IP> [FILES...]"],
+        about: &[
+            "IP must be either an IP address or `auto' to enable server discovery",
+            "it may also be the descriptor a receiver shows with --qr, like sf://192.168.1.2:8370/KEY",
+            "options may also come after the IP or the FILES, and anything after `--'",
+            "is a file even if it starts with -",
+        ],
+        options: &[
+            GENERAL_OPTIONS,
+            REPORT_OPTIONS,
+            STATS_OPTIONS,
+            SOCKET_OPTIONS,
+            TRANSFER_OPTIONS,
+            FIND_OPTIONS,
+            WAKE_OPTIONS,
+            SCHEDULE_OPTIONS,
+            PROTOCOL_OPTIONS,
+            SEND_OPTIONS,
+        ],
+    },
+    Command {
+        name: DISCOVER_COMMAND,
+        title: "find a receiver on the network",
+        usage: &["discover [OPTIONS...]"],
+        about: &[
+            "prints the address of the first receiver that announces itself, waiting",
+            "for one as with the same options when sending",
+        ],
+        options: &[GENERAL_OPTIONS, FIND_OPTIONS],
+    },
+    Command {
+        name: HISTORY_COMMAND,
+        title: "list the transfers made so far",
+        usage: &["history [OPTIONS...]"],
+        about: &[
+            "every transfer is remembered with who the peer was, how many files and",
+            "bytes went through and whether it failed",
+        ],
+        options: &[GENERAL_OPTIONS],
+    },
+    Command {
+        name: WAKE_COMMAND,
+        title: "wake a sleeping machine over the network",
+        usage: &["wake [OPTIONS...] <MAC>"],
+        about: &[
+            "broadcasts a Wake-on-LAN packet for the hardware address MAC, such as",
+            "01:23:45:67:89:ab, to every network the interfaces are in",
+        ],
+        options: &[GENERAL_OPTIONS],
+    },
+    Command {
+        name: VERIFY,
+        title: "verify that the receiver has identical files",
+        usage: &["verify [OPTIONS...] <IP> [FILES...]"],
+        about: &[
+            "reports files that are missing, different or extra in the receiver,",
+            "which should be running as if it were to receive the files again",
+        ],
+        options: &[
+            GENERAL_OPTIONS,
+            REPORT_OPTIONS,
+            SOCKET_OPTIONS,
+            VERIFY_OPTIONS,
+        ],
+    },
+    Command {
+        name: SERVE,
+        title: "serve files over HTTP to those without sf",
+        usage: &["serve [OPTIONS...] [FILES...]"],
+        about: &[
+            "prints a link for every file, which a browser or curl can download from",
+            "until it has been downloaded as many times as allowed, or with --token",
+            "a single code and link that only grant access to those files, for a while",
+        ],
+        options: &[GENERAL_OPTIONS, REPORT_OPTIONS, SERVE_OPTIONS],
+    },
+    Command {
+        name: BENCH,
+        title: "measure how fast files can be sent to the receiver",
+        usage: &["bench [OPTIONS...] <IP>"],
+        about: &[
+            "sends made-up data and reports the throughput and round trip time; the",
+            "receiver running with --discard measures the network alone, and without it, its disk too",
+        ],
+        options: &[
+            GENERAL_OPTIONS,
+            REPORT_OPTIONS,
+            STATS_OPTIONS,
+            SOCKET_OPTIONS,
+            TRANSFER_OPTIONS,
+            FIND_OPTIONS,
+            WAKE_OPTIONS,
+            SCHEDULE_OPTIONS,
+            PROTOCOL_OPTIONS,
+            BENCH_OPTIONS,
+        ],
+    },
+    Command {
+        name: SERVICE_COMMAND,
+        title: "keep receiving as a Windows service",
+        usage: &["service <install|uninstall|run> [OPTIONS...]"],
+        about: &[
+            "install adds the `sf' service, which receives with the OPTIONS from the time",
+            "the system starts and logs to the event log; uninstall removes it, and",
+            "run is what the service manager starts",
+        ],
+        options: RECEIVER_OPTIONS,
+    },
+    Command {
+        name: QUEUE_COMMAND,
+        title: "send files later, one job after another",
+        usage: &[
+            "queue add [OPTIONS...] [SEND OPTIONS...] <IP> [FILES...]",
+            "queue run [OPTIONS...]",
+            "queue status [OPTIONS...]",
+        ],
+        about: &[
+            "add keeps a job that sends the FILES with the options of send, as listed",
+            "by `sf send --help'; run sends those waiting, and tries failed ones again;",
+            "status lists them",
+        ],
+        options: &[QUEUE_OPTIONS],
+    },
+];
+
+pub fn parse() -> Result<Settings, String> {
     let mut args = env::args();
-    let prog_name = args.next().expect("program name missing");
+    let prog_name = args.next().ok_or("program name missing")?;
+    parse_command(&prog_name, expand_profiles(args)?)
+}
+
+fn parse_command(prog_name: &str, mut args: Vec<String>) -> Result<Settings, String> {
+    let name = take_command(&mut args);
+    // only asking for help, without saying about which command, shows all of them
+    if name.is_none() && args.len() == 1 && HELP.contains(&args[0].as_str()) {
+        print_overview(prog_name);
+        process::exit(0);
+    }
+    let command = name.map_or(&COMMANDS[0], find_command);
+    match command.name {
+        RECV_COMMAND => parse_receiver(prog_name, command, args),
+        SEND_COMMAND => parse_sender(prog_name, command, args),
+        DISCOVER_COMMAND => parse_discover(prog_name, command, args),
+        HISTORY_COMMAND => parse_history(prog_name, command, args),
+        WAKE_COMMAND => parse_wake(prog_name, command, args),
+        VERIFY => parse_verify(prog_name, command, args),
+        SERVE => parse_serve(prog_name, command, args),
+        BENCH => parse_bench(prog_name, command, args),
+        SERVICE_COMMAND => parse_service(prog_name, command, args),
+        _ => parse_queue(prog_name, command, args),
+    }
+}
+
+fn find_command(name: &str) -> &'static Command {
+    COMMANDS
+        .iter()
+        .find(|command| command.name == name)
+        .expect("only the names of commands are looked up")
+}
+
+// Take the name of the command out of the arguments, if any was given. It usually comes first,
+// but those that used to be told apart from an ip can also come after the options, and an ip
+// (or anything else but options) means it's sending without saying so.
+fn take_command(args: &mut Vec<String>) -> Option<&'static str> {
+    if let Some(command) = COMMANDS
+        .iter()
+        .find(|command| args.first().is_some_and(|arg| *arg == command.name))
+    {
+        args.remove(0);
+        return Some(command.name);
+    }
+    let mut i = 0;
+    while let Some(arg) = args.get(i) {
+        if arg == END_OF_OPTIONS {
+            return (i + 1 < args.len()).then_some(SEND_COMMAND);
+        }
+        if !arg.starts_with('-') {
+            break;
+        }
+        i += if takes_value(arg) { 2 } else { 1 };
+    }
+    let arg = args.get(i)?;
+    match [VERIFY, SERVE, BENCH, WAKE_COMMAND]
+        .iter()
+        .find(|name| arg == **name)
+    {
+        Some(&name) => {
+            args.remove(i);
+            Some(name)
+        }
+        None => Some(SEND_COMMAND),
+    }
+}
+
+// Whether the option is followed by a value in any of the commands that take it.
+fn takes_value(arg: &str) -> bool {
+    COMMANDS
+        .iter()
+        .flat_map(|command| command.options.iter().copied().flatten())
+        .any(|opt| opt.names.contains(&arg) && !opt.value.is_empty())
+}
+
+fn find_option(command: &Command, arg: &str) -> Option<&'static Opt> {
+    let arg = if arg == VERY_VERBOSE { VERBOSE[0] } else { arg };
+    command
+        .options
+        .iter()
+        .copied()
+        .flatten()
+        .find(|opt| opt.names.contains(&arg))
+}
 
-    let mut strip_prefix = false;
-    let mut ip = None;
+// Say which commands take the option instead, or which of those of the command was meant.
+fn unknown_option(prog_name: &str, command: &Command, arg: &str) -> String {
+    let others = COMMANDS
+        .iter()
+        .filter(|other| find_option(other, arg).is_some())
+        .map(|other| other.name)
+        .collect::<Vec<_>>();
+    if !others.is_empty() {
+        return format!(
+            "{} is not an option of {}, only of {}",
+            arg,
+            command.name,
+            others.join(", ")
+        );
+    }
+    let closest = command
+        .options
+        .iter()
+        .copied()
+        .flatten()
+        .flat_map(|opt| opt.names.iter())
+        .filter(|name| name.starts_with("--"))
+        .min_by_key(|name| edit_distance(arg, name))
+        .filter(|name| edit_distance(arg, name) <= 2);
+    match closest {
+        Some(name) => format!("unknown option {:?}, did you mean `{}'?", arg, name),
+        None => format!(
+            "unknown option {:?}, see `{} {} {}' for the available ones",
+            arg, prog_name, command.name, HELP[1]
+        ),
+    }
+}
+
+fn print_usage(prog_name: &str, command: &Command) {
+    println!("usage ({}):", command.title);
+    for usage in command.usage {
+        println!("  {} {}", prog_name, usage);
+    }
+    println!();
+    for line in command.about {
+        println!("  {}", line);
+    }
+}
+
+fn print_options(options: &[&[Opt]]) {
+    for opt in options.iter().copied().flatten() {
+        let (first, rest) = opt.help.split_first().unwrap_or((&"", &[]));
+        if opt.value.is_empty() {
+            println!("  {}: {}", opt.names.join(", "), first);
+        } else {
+            println!("  {} {}: {}", opt.names.join(", "), opt.value, first);
+        }
+        for line in rest {
+            println!("    {}", line);
+        }
+    }
+}
+
+fn print_help(prog_name: &str, command: &Command) {
+    println!("sf: send files in LAN quickly");
+    println!();
+    print_usage(prog_name, command);
+    println!();
+    println!("available OPTIONS:");
+    print_options(command.options);
+}
+
+fn print_overview(prog_name: &str) {
+    println!("sf: send files in LAN quickly");
+    for command in &COMMANDS {
+        println!();
+        print_usage(prog_name, command);
+    }
+    println!();
+    println!(
+        "all but queue take these OPTIONS, and `{} <COMMAND> {}' lists all of those",
+        prog_name, HELP[1]
+    );
+    println!("each command takes:");
+    print_options(&[GENERAL_OPTIONS]);
+    println!();
+    println!("exit codes:");
+    println!("  0: everything went through");
+    println!("  1: a local file could not be read or written, or something else failed");
+    println!("  2: the transfer finished but some files failed, or verified files differ");
+    println!("  3: the receiver could not be found or connected to, or the connection broke");
+    println!("  4: the peer is not talking the protocol, or a version this one can't");
+    println!("  5: the receiver refused the transfer");
+    println!("  6: the transfer was cancelled");
+    println!("  7: the arguments are not valid");
+}
 
+/// The values of the options given, of any command, before they're checked to make sense
+/// together and turned into what the command runs with.
+#[derive(Default)]
+struct Values {
+    strip_prefix: bool,
+    strip_drive: bool,
+    strip_components: Option<usize>,
+    map: Vec<sf::PathMap>,
+    output: Option<PathBuf>,
+    session_dirs: bool,
+    staging: Option<PathBuf>,
+    case_collisions: Option<CaseCollisions>,
+    duplicates: Option<Duplicates>,
+    normalize: Option<Normalization>,
+    mirror: bool,
+    dry_run: bool,
+    update: bool,
+    resend_changed: bool,
+    legacy: bool,
+    dedup: bool,
+    hashes: bool,
+    receiver_progress: bool,
+    allow_metered: bool,
+    auto_pack: bool,
+    wake: Option<sf::MacAddress>,
+    at: Option<sf::TimeOfDay>,
+    after: Option<Duration>,
+    busy: Vec<sf::DailyWindow>,
+    checksum_db: Option<PathBuf>,
+    sums: Option<SumsFormat>,
+    list_only: bool,
+    list_json: Option<PathBuf>,
+    extract: bool,
+    encrypt_at_rest: Option<sf::Recipient>,
+    identity: Option<PathBuf>,
+    authorized_senders: Option<PathBuf>,
+    hardlinks: bool,
+    specials: bool,
+    max_depth: Option<usize>,
+    one_file_system: bool,
+    xattrs: Option<Vec<XattrNamespace>>,
+    attributes: bool,
+    owner: Option<sf::Owner>,
+    file_mode: Option<u32>,
+    dir_mode: Option<u32>,
+    manifest: Option<PathBuf>,
+    from_tar: Option<PathBuf>,
+    from_stdin: Option<String>,
+    files_from: Option<String>,
+    null: bool,
+    bench_mib: Option<u64>,
+    bench_pattern: Option<source::Pattern>,
+    archive: Option<ArchiveFormat>,
+    order: Option<Order>,
+    compress: Option<Compression>,
+    recv_tar: bool,
+    discard: bool,
+    s3: Option<String>,
+    timeout: Option<u64>,
+    reconnect: Option<u64>,
+    retry: Option<u64>,
+    chunk_size: Option<usize>,
+    send_buffer: Option<usize>,
+    recv_buffer: Option<usize>,
+    nodelay: bool,
+    tui: bool,
+    notify: bool,
+    json: bool,
+    stats: bool,
+    open: bool,
+    qr: bool,
+    http: Option<u16>,
+    metrics: Option<SocketAddr>,
+    downloads: Option<usize>,
+    token: bool,
+    expire: Option<u64>,
+    include_virtual: bool,
+    service: bool,
+    quota_per_peer: Option<u64>,
+    quota: Option<u64>,
+    filter: sf::FileFilter,
+    on_complete: Option<String>,
+    on_session_complete: Option<String>,
+    webhook: Option<sf::Webhook>,
+    verbosity: u8,
+    quiet: bool,
+    exact_sizes: bool,
+    log_file: Option<PathBuf>,
+    /// The names to send files as, after how many of the other arguments they were given.
+    rename: Vec<(usize, PathBuf)>,
+}
+
+// Read the options the command takes, along with the rest of the arguments, such as the ip
+// and the files, in the order they were given.
+fn read_options(
+    prog_name: &str,
+    command: &Command,
+    args: Vec<String>,
+) -> Result<(Values, Vec<String>), String> {
+    let mut values = Values::default();
+    let mut positionals = Vec::new();
+    let mut args = args.into_iter().peekable();
     while let Some(arg) = args.next() {
+        if arg == END_OF_OPTIONS {
+            positionals.extend(args.by_ref());
+            break;
+        }
+        // nothing else is an option, and an ip never starts like one
+        if !arg.starts_with('-') {
+            positionals.push(arg);
+            continue;
+        }
+        let opt =
+            find_option(command, &arg).ok_or_else(|| unknown_option(prog_name, command, &arg))?;
         if HELP.contains(&arg.as_str()) {
-            println!("sf: send files in LAN quickly");
-            println!();
-            println!("usage (receive files):");
-            println!("  {} [OPTIONS...]", prog_name);
-            println!();
-            println!("available OPTIONS:");
-            println!("  {}: display this message and exit", HELP.join(", "));
+            print_help(prog_name, command);
+            process::exit(0);
+        }
+        // the name of the last path given, which is checked to be a file once they're told apart
+        if AS.contains(&arg.as_str()) {
+            let name = args.next().ok_or("missing name to send as")?;
+            values.rename.push((positionals.len(), PathBuf::from(name)));
+            continue;
+        }
+        values.read(&arg, opt, &mut args)?;
+    }
+    Ok((values, positionals))
+}
+
+impl Values {
+    // Read the option, and the value that follows it if it takes one.
+    fn read(
+        &mut self,
+        arg: &str,
+        opt: &Opt,
+        args: &mut iter::Peekable<impl Iterator<Item = String>>,
+    ) -> Result<(), String> {
+        if VERSION.contains(&arg) {
             println!(
-                "  {}: strip the common prefix from the received file paths",
-                STRIP_PREFIX.join(", ")
+                "sf {} (protocol version {})",
+                env!("CARGO_PKG_VERSION"),
+                sf::VERSION
             );
-            println!(
-                "    this is useful when receiving absolute paths from a drive you don't have,"
+            process::exit(0);
+        } else if CAPABILITIES.contains(&arg) {
+            println!("{}", sf::capabilities());
+            process::exit(0);
+        } else if STRIP_PREFIX.contains(&arg) {
+            self.strip_prefix = true;
+        } else if STRIP_DRIVE.contains(&arg) {
+            self.strip_drive = true;
+        } else if MAP.contains(&arg) {
+            self.map
+                .push(parse_map(&args.next().ok_or("missing path mapping")?)?);
+        } else if STRIP_COMPONENTS.contains(&arg) {
+            let value = args.next().ok_or("missing number of directories")?;
+            self.strip_components = Some(parse_number(&value, "number of directories")?);
+        } else if OUTPUT.contains(&arg) {
+            self.output = Some(PathBuf::from(
+                args.next().ok_or("missing output directory")?,
+            ));
+        } else if STAGING.contains(&arg) {
+            self.staging = Some(PathBuf::from(
+                args.next().ok_or("missing staging directory")?,
+            ));
+        } else if SESSION_DIRS.contains(&arg) {
+            self.session_dirs = true;
+        } else if NORMALIZE.contains(&arg) {
+            self.normalize = match args.next().ok_or("missing normalization form")?.as_str() {
+                "nfc" => Some(Normalization::Nfc),
+                "nfd" => Some(Normalization::Nfd),
+                "none" => None,
+                form => {
+                    return Err(format!(
+                        "unknown normalization form {:?}, must be one of: {}",
+                        form,
+                        NORMALIZATION_FORMS.join(", ")
+                    ))
+                }
+            };
+        } else if CASE_COLLISIONS.contains(&arg) {
+            self.case_collisions = Some(parse_case_policy(
+                &args.next().ok_or("missing case collision policy")?,
+            )?);
+        } else if DUPLICATES.contains(&arg) {
+            self.duplicates = Some(parse_duplicate_policy(
+                &args.next().ok_or("missing duplicate policy")?,
+            )?);
+        } else if MIRROR.contains(&arg) {
+            self.mirror = true;
+        } else if DRY_RUN.contains(&arg) {
+            self.dry_run = true;
+        } else if UPDATE.contains(&arg) {
+            self.update = true;
+        } else if RESEND_CHANGED.contains(&arg) {
+            self.resend_changed = true;
+        } else if ARCHIVE.contains(&arg) {
+            self.archive = match args.next().ok_or("missing archive format")?.as_str() {
+                "tar" => Some(ArchiveFormat::Tar),
+                "tar.zst" => Some(ArchiveFormat::TarZst),
+                "zip" => Some(ArchiveFormat::Zip),
+                format => {
+                    return Err(format!(
+                        "unsupported archive format {:?}, must be one of: {}",
+                        format,
+                        ARCHIVE_FORMATS.join(", ")
+                    ))
+                }
+            };
+        } else if ORDER.contains(&arg) {
+            self.order = match args.next().ok_or("missing order")?.as_str() {
+                "as-given" => Some(Order::AsGiven),
+                "small-first" => Some(Order::SmallFirst),
+                "large-first" => Some(Order::LargeFirst),
+                "alpha" => Some(Order::Alpha),
+                order => {
+                    return Err(format!(
+                        "unknown order {:?}, must be one of: {}",
+                        order,
+                        ORDERS.join(", ")
+                    ))
+                }
+            };
+        } else if RECV_TAR.contains(&arg) {
+            self.recv_tar = true;
+        } else if DISCARD.contains(&arg) {
+            self.discard = true;
+        } else if S3.contains(&arg) {
+            self.s3 = Some(args.next().ok_or("missing bucket url")?);
+        } else if LEGACY.contains(&arg) {
+            self.legacy = true;
+        } else if DEDUP.contains(&arg) {
+            self.dedup = true;
+        } else if HASHES.contains(&arg) {
+            self.hashes = true;
+        } else if RECEIVER_PROGRESS.contains(&arg) {
+            self.receiver_progress = true;
+        } else if ALLOW_METERED.contains(&arg) {
+            self.allow_metered = true;
+        } else if COMPRESS.contains(&arg) {
+            self.compress = match args.next().ok_or("missing compression")?.as_str() {
+                "never" => Some(Compression::Never),
+                "always" => Some(Compression::Always),
+                "auto" => Some(Compression::Auto),
+                compress => {
+                    return Err(format!(
+                        "unknown compression {:?}, must be one of: {}",
+                        compress,
+                        COMPRESSIONS.join(", ")
+                    ))
+                }
+            };
+        } else if AUTO_PACK.contains(&arg) {
+            self.auto_pack = true;
+        } else if WAKE.contains(&arg) {
+            self.wake = Some(parse_mac_address(
+                &args.next().ok_or("missing hardware address to wake")?,
+            )?);
+        } else if CHECKSUM_DB.contains(&arg) {
+            self.checksum_db = Some(PathBuf::from(
+                args.next().ok_or("missing checksum database file")?,
+            ));
+        } else if WRITE_SUMS.contains(&arg) {
+            self.sums = match args.next().ok_or("missing sums format")?.as_str() {
+                "sha256sums" => Some(SumsFormat::Sha256Sums),
+                "json" => Some(SumsFormat::Json),
+                format => {
+                    return Err(format!(
+                        "unknown sums format {:?}, must be one of: {}",
+                        format,
+                        SUMS_FORMATS.join(", ")
+                    ))
+                }
+            };
+        } else if LIST_ONLY.contains(&arg) {
+            self.list_only = true;
+        } else if LIST_JSON.contains(&arg) {
+            self.list_json = Some(PathBuf::from(args.next().ok_or("missing list file")?));
+        } else if EXTRACT.contains(&arg) {
+            self.extract = true;
+        } else if ENCRYPT_AT_REST.contains(&arg) {
+            self.encrypt_at_rest = Some(parse_recipient(
+                &args.next().ok_or("missing recipient to encrypt to")?,
+            )?);
+        } else if IDENTITY.contains(&arg) {
+            self.identity = Some(PathBuf::from(args.next().ok_or("missing identity file")?));
+        } else if AUTHORIZED_SENDERS.contains(&arg) {
+            self.authorized_senders = Some(PathBuf::from(
+                args.next().ok_or("missing authorized senders file")?,
+            ));
+        } else if HARDLINKS.contains(&arg) {
+            self.hardlinks = true;
+        } else if SPECIALS.contains(&arg) {
+            self.specials = true;
+        } else if MAX_DEPTH.contains(&arg) {
+            let value = args.next().ok_or("missing number of directories")?;
+            self.max_depth = Some(parse_number(&value, "number of directories")?);
+        } else if ONE_FILE_SYSTEM.contains(&arg) {
+            self.one_file_system = true;
+        } else if ATTRIBUTES.contains(&arg) {
+            self.attributes = true;
+        } else if CHOWN.contains(&arg) {
+            let value = args.next().ok_or("missing user and group")?;
+            self.owner = Some(
+                sf::Owner::parse(&value)
+                    .map_err(|e| format!("invalid owner {:?}: {}", value, e))?,
             );
-            println!("    since the drive portion will be removed as long as all paths share it");
-            println!("    default = {}", strip_prefix);
-            println!();
-            println!("usage (send files):");
-            println!("  {} <IP> [FILES...]", prog_name);
-            println!();
-            println!(
-                "  IP must be either an IP address or `{}' to enable server discovery",
-                AUTO_IP
+        } else if MODE.contains(&arg) {
+            self.file_mode = Some(parse_mode(&args.next().ok_or("missing file mode")?)?);
+        } else if DIR_MODE.contains(&arg) {
+            self.dir_mode = Some(parse_mode(&args.next().ok_or("missing directory mode")?)?);
+        } else if XATTRS.contains(&arg) {
+            self.xattrs = Some(parse_xattrs(
+                &args.next().ok_or("missing xattr namespaces")?,
+            )?);
+        } else if MANIFEST.contains(&arg) {
+            self.manifest = Some(PathBuf::from(args.next().ok_or("missing manifest file")?));
+        } else if FROM_TAR.contains(&arg) {
+            self.from_tar = Some(PathBuf::from(args.next().ok_or("missing archive to send")?));
+        } else if BENCH_SIZE.contains(&arg) {
+            let value = args.next().ok_or("missing benchmark size")?;
+            self.bench_mib = Some(parse_number(&value, "benchmark size")?);
+        } else if BENCH_PATTERN.contains(&arg) {
+            self.bench_pattern = match args.next().ok_or("missing benchmark pattern")?.as_str() {
+                "random" => Some(source::Pattern::Random),
+                "zeros" => Some(source::Pattern::Zeros),
+                "text" => Some(source::Pattern::Text),
+                pattern => {
+                    return Err(format!(
+                        "unsupported benchmark pattern {:?}, must be one of: {}",
+                        pattern,
+                        BENCH_PATTERNS.join(", ")
+                    ))
+                }
+            };
+        } else if FROM_STDIN.contains(&arg) {
+            self.from_stdin = Some(args.next().ok_or("missing name for the input")?);
+        } else if FILES_FROM.contains(&arg) {
+            self.files_from = Some(args.next().ok_or("missing file with the list of paths")?);
+        } else if NULL.contains(&arg) {
+            self.null = true;
+        } else if TIMEOUT.contains(&arg) {
+            let value = args.next().ok_or("missing timeout value")?;
+            self.timeout = Some(parse_number(&value, "timeout")?);
+        } else if RECONNECT.contains(&arg) {
+            let value = args.next().ok_or("missing reconnect value")?;
+            self.reconnect = Some(parse_number(&value, "reconnect time")?);
+        } else if RETRY.contains(&arg) {
+            self.retry = Some(DEFAULT_RETRY_SECS);
+        } else if RETRY_FOR.contains(&arg) {
+            let secs = args.next().ok_or("missing retry value")?;
+            // a unit makes it clearer, and seconds are the only one there is
+            self.retry = Some(parse_number(
+                secs.strip_suffix('s').unwrap_or(&secs),
+                "retry time",
+            )?);
+        } else if AT.contains(&arg) {
+            let time = args.next().ok_or("missing time to send at")?;
+            self.at = Some(
+                sf::TimeOfDay::parse(&time)
+                    .ok_or_else(|| format!("invalid time of the day {:?}", time))?,
+            );
+        } else if AFTER.contains(&arg) {
+            self.after = Some(parse_duration(
+                &args.next().ok_or("missing duration to wait")?,
+            )?);
+        } else if BUSY.contains(&arg) {
+            self.busy.extend(parse_busy_windows(
+                &args.next().ok_or("missing busy windows")?,
+            )?);
+        } else if CHUNK_SIZE.contains(&arg) {
+            self.chunk_size = Some(parse_size(&args.next().ok_or("missing chunk size value")?)?);
+        } else if SEND_BUFFER.contains(&arg) {
+            self.send_buffer = Some(parse_size(
+                &args.next().ok_or("missing send buffer value")?,
+            )?);
+        } else if RECV_BUFFER.contains(&arg) {
+            self.recv_buffer = Some(parse_size(
+                &args.next().ok_or("missing receive buffer value")?,
+            )?);
+        } else if NODELAY.contains(&arg) {
+            self.nodelay = true;
+        } else if TUI.contains(&arg) {
+            self.tui = true;
+        } else if NOTIFY.contains(&arg) {
+            self.notify = true;
+        } else if JSON.contains(&arg) {
+            self.json = true;
+        } else if STATS.contains(&arg) {
+            self.stats = true;
+        } else if OPEN.contains(&arg) {
+            self.open = true;
+        } else if QR.contains(&arg) {
+            self.qr = true;
+        } else if HTTP.contains(&arg) {
+            // when serving, it may be given alone to say how the files are served
+            let optional = opt.value.starts_with('[');
+            match args.next_if(|port| !optional || port.parse::<u16>().is_ok()) {
+                Some(port) => self.http = Some(parse_number(&port, "http port")?),
+                None if optional => {}
+                None => return Err("missing http port".into()),
+            }
+        } else if METRICS.contains(&arg) {
+            self.metrics = Some(parse_metrics_address(
+                &args.next().ok_or("missing metrics port")?,
+            )?);
+        } else if DOWNLOADS.contains(&arg) {
+            let value = args.next().ok_or("missing downloads value")?;
+            self.downloads = Some(parse_number(&value, "number of downloads")?);
+        } else if TOKEN.contains(&arg) {
+            self.token = true;
+        } else if EXPIRE.contains(&arg) {
+            let value = args.next().ok_or("missing expire value")?;
+            self.expire = Some(parse_number(&value, "expire time")?);
+        } else if INCLUDE_VIRTUAL.contains(&arg) {
+            self.include_virtual = true;
+        } else if SERVICE.contains(&arg) {
+            self.service = true;
+        } else if QUOTA_PER_PEER.contains(&arg) {
+            self.quota_per_peer =
+                Some(parse_size(&args.next().ok_or("missing quota value")?)? as u64);
+        } else if QUOTA.contains(&arg) {
+            self.quota = Some(parse_size(&args.next().ok_or("missing quota value")?)? as u64);
+        } else if ACCEPT_EXT.contains(&arg) {
+            self.filter
+                .accept_ext
+                .extend(parse_extensions(&args.next().ok_or("missing extensions")?));
+        } else if REJECT_EXT.contains(&arg) {
+            self.filter
+                .reject_ext
+                .extend(parse_extensions(&args.next().ok_or("missing extensions")?));
+        } else if REJECT_LARGER.contains(&arg) {
+            self.filter.reject_larger =
+                Some(parse_size(&args.next().ok_or("missing size to reject")?)? as u64);
+        } else if ON_COMPLETE.contains(&arg) {
+            self.on_complete = Some(args.next().ok_or("missing command to run")?);
+        } else if ON_SESSION_COMPLETE.contains(&arg) {
+            self.on_session_complete = Some(args.next().ok_or("missing command to run")?);
+        } else if WEBHOOK.contains(&arg) {
+            let url = args.next().ok_or("missing webhook url")?;
+            self.webhook = Some(
+                sf::Webhook::new(&url)
+                    .map_err(|e| format!("invalid webhook url {:?}: {}", url, e))?,
             );
-            process::exit(0); // cannot use ExitCode::SUCCESS because this function expects i32...
+        } else if VERBOSE.contains(&arg) {
+            self.verbosity += 1;
+        } else if arg == VERY_VERBOSE {
+            self.verbosity += 2;
+        } else if QUIET.contains(&arg) {
+            self.quiet = true;
+        } else if BYTES.contains(&arg) {
+            self.exact_sizes = true;
+        } else if LOG_FILE.contains(&arg) {
+            self.log_file = Some(PathBuf::from(args.next().ok_or("missing log file")?));
+        } else {
+            // profiles are replaced with their options before any of them are read
+            return Err(format!("{} cannot be used here", opt.names.join(", ")));
         }
-        if STRIP_PREFIX.contains(&arg.as_str()) {
-            strip_prefix = true;
-            continue;
+        Ok(())
+    }
+
+    fn socket(&self) -> SocketOptions {
+        let timeout = self.timeout.unwrap_or(DEFAULT_TIMEOUT_SECS);
+        SocketOptions {
+            timeout: (timeout != 0).then(|| Duration::from_secs(timeout)),
+            send_buffer: self.send_buffer,
+            recv_buffer: self.recv_buffer,
+            nodelay: self.nodelay,
+        }
+    }
+
+    fn reconnect(&self) -> Option<Duration> {
+        let reconnect = self.reconnect.unwrap_or(DEFAULT_RECONNECT_SECS);
+        (reconnect != 0).then(|| Duration::from_secs(reconnect))
+    }
+
+    // When to start sending, if it's not right away.
+    fn start_at(&self) -> Result<Option<SystemTime>, String> {
+        match (self.at, self.after) {
+            (Some(_), Some(_)) => Err(format!(
+                "only one of {} or {} can be used",
+                AT.join(", "),
+                AFTER.join(", ")
+            )),
+            (Some(at), _) => Ok(Some(SystemTime::now() + at.since(sf::TimeOfDay::now()))),
+            (_, Some(after)) => Ok(Some(SystemTime::now() + after)),
+            _ => Ok(None),
+        }
+    }
+
+    // The options of the sender, whatever it sends.
+    fn send_options(
+        &mut self,
+        rename: Vec<(PathBuf, PathBuf)>,
+        source: Option<Box<dyn Source + Send + Sync>>,
+    ) -> Result<SendOptions, String> {
+        // it takes a while for a machine to come up, and a receiver is only found once it does
+        if self.wake.is_some() && self.retry.is_none() {
+            self.retry = Some(DEFAULT_RETRY_SECS);
+        }
+        let identity = self
+            .identity
+            .take()
+            .map(|path| {
+                sf::Identity::load_or_create(&path)
+                    .map_err(|e| format!("cannot use the identity: {}", e))
+            })
+            .transpose()?;
+        let mut options = SendOptions::default();
+        options.legacy = self.legacy;
+        options.update = self.update;
+        options.archive = self.archive;
+        options.order = self.order.unwrap_or(Order::AsGiven);
+        options.rename = rename;
+        options.dedup = self.dedup;
+        options.hardlinks = self.hardlinks;
+        options.specials = self.specials;
+        options.max_depth = self.max_depth;
+        options.one_file_system = self.one_file_system;
+        options.xattrs = self.xattrs.take().unwrap_or_default();
+        options.attributes = self.attributes;
+        options.hashes = self.hashes;
+        options.receiver_progress = self.receiver_progress;
+        options.allow_metered = self.allow_metered;
+        options.auto_pack = self.auto_pack;
+        options.resend_changed = self.resend_changed;
+        options.compress = self.compress.unwrap_or(Compression::Never);
+        options.identity = identity;
+        options.start_at = self.start_at()?;
+        options.manifest = self.manifest.take();
+        options.source = source;
+        options.socket = self.socket();
+        options.reconnect = self.reconnect();
+        options.retry = self.retry.map(Duration::from_secs);
+        options.chunk_size = self.chunk_size;
+        Ok(options)
+    }
+
+    fn into_settings(self, mode: Mode) -> Settings {
+        Settings {
+            mode,
+            tui: self.tui,
+            notify: self.notify,
+            json: self.json,
+            stats: self.stats,
+            open: self.open,
+            qr: self.qr,
+            metrics: self.metrics,
+            service: self.service,
+            recv_tar: self.recv_tar,
+            on_complete: self.on_complete,
+            on_session_complete: self.on_session_complete,
+            verbosity: self.verbosity,
+            quiet: self.quiet,
+            exact_sizes: self.exact_sizes,
+            log_file: self.log_file,
+            system_service: false,
+            wake: self.wake,
+        }
+    }
+}
+
+// Fail if anything but options was given to a command that only takes those.
+fn no_positionals(command: &Command, positionals: &[String]) -> Result<(), String> {
+    match positionals.first() {
+        Some(arg) => Err(format!(
+            "{} takes only options, but was given {:?}",
+            command.name, arg
+        )),
+        None => Ok(()),
+    }
+}
+
+fn parse_receiver(
+    prog_name: &str,
+    command: &Command,
+    args: Vec<String>,
+) -> Result<Settings, String> {
+    let (mut values, positionals) = read_options(prog_name, command, args)?;
+    no_positionals(command, &positionals)?;
+    let v = &mut values;
+    if v.mirror && v.archive.is_some() {
+        return Err(format!(
+            "{} cannot be used with {}",
+            MIRROR.join(", "),
+            ARCHIVE.join(", ")
+        ));
+    }
+    if v.staging.is_some()
+        && (v.mirror
+            || v.archive.is_some()
+            || v.list_only
+            || v.checksum_db.is_some()
+            || v.http.is_some()
+            || v.recv_tar
+            || v.discard
+            || v.s3.is_some())
+    {
+        return Err(format!(
+            "{} cannot be used with {}, {}, {}, {}, {}, {}, {} or {}",
+            STAGING.join(", "),
+            MIRROR.join(", "),
+            ARCHIVE.join(", "),
+            LIST_ONLY.join(", "),
+            CHECKSUM_DB.join(", "),
+            HTTP.join(", "),
+            RECV_TAR.join(", "),
+            DISCARD.join(", "),
+            S3.join(", ")
+        ));
+    }
+    if v.checksum_db.is_some() && v.archive.is_some() {
+        return Err(format!(
+            "{} cannot be used with {}, since no files are stored",
+            CHECKSUM_DB.join(", "),
+            ARCHIVE.join(", ")
+        ));
+    }
+    if v.sums.is_some() && (v.archive.is_some() || v.list_only || v.http.is_some()) {
+        return Err(format!(
+            "{} cannot be used with {}, {} or {}",
+            WRITE_SUMS.join(", "),
+            ARCHIVE.join(", "),
+            LIST_ONLY.join(", "),
+            HTTP.join(", ")
+        ));
+    }
+    if v.list_json.is_some() && !v.list_only {
+        return Err(format!(
+            "{} can only be used with {}",
+            LIST_JSON.join(", "),
+            LIST_ONLY.join(", ")
+        ));
+    }
+    if v.extract && v.archive.is_some() {
+        return Err(format!(
+            "{} cannot be used with {}",
+            EXTRACT.join(", "),
+            ARCHIVE.join(", ")
+        ));
+    }
+    if v.encrypt_at_rest.is_some()
+        && (v.archive.is_some() || v.checksum_db.is_some() || v.http.is_some())
+    {
+        return Err(format!(
+            "{} cannot be used with {}, {} or {}",
+            ENCRYPT_AT_REST.join(", "),
+            ARCHIVE.join(", "),
+            CHECKSUM_DB.join(", "),
+            HTTP.join(", ")
+        ));
+    }
+    if v.authorized_senders.is_some() && v.http.is_some() {
+        return Err(format!(
+            "{} cannot be used with {}, as browsers can't prove who they are",
+            AUTHORIZED_SENDERS.join(", "),
+            HTTP.join(", ")
+        ));
+    }
+    let authorized_senders = v
+        .authorized_senders
+        .take()
+        .map(|path| {
+            sf::AuthorizedSenders::load(&path)
+                .map_err(|e| format!("cannot read the authorized senders: {}", e))
+        })
+        .transpose()?;
+    if v.session_dirs && v.mirror {
+        return Err(format!(
+            "{} cannot be used with {}, since every transfer starts empty",
+            SESSION_DIRS.join(", "),
+            MIRROR.join(", ")
+        ));
+    }
+
+    let sinks = [v.recv_tar, v.discard, v.s3.is_some()];
+    if sinks.iter().filter(|&&given| given).count() > 1 {
+        return Err(format!(
+            "only one of {}, {} or {} can be used",
+            RECV_TAR.join(", "),
+            DISCARD.join(", "),
+            S3.join(", ")
+        ));
+    }
+    if sinks.contains(&true)
+        && (v.mirror
+            || v.archive.is_some()
+            || v.session_dirs
+            || v.checksum_db.is_some()
+            || v.extract
+            || v.encrypt_at_rest.is_some()
+            || v.sums.is_some())
+    {
+        return Err(format!(
+            "{}, {} and {} cannot be used with {}, {}, {}, {}, {}, {} or {}",
+            RECV_TAR.join(", "),
+            DISCARD.join(", "),
+            S3.join(", "),
+            MIRROR.join(", "),
+            ARCHIVE.join(", "),
+            SESSION_DIRS.join(", "),
+            CHECKSUM_DB.join(", "),
+            EXTRACT.join(", "),
+            ENCRYPT_AT_REST.join(", "),
+            WRITE_SUMS.join(", ")
+        ));
+    }
+    let stores_nothing = sinks.contains(&true) || v.archive.is_some() || v.list_only;
+    for (given, flag) in [
+        (v.owner.is_some(), &CHOWN[..]),
+        (v.file_mode.is_some(), &MODE),
+        (v.dir_mode.is_some(), &DIR_MODE),
+    ] {
+        if given && stores_nothing {
+            return Err(format!(
+                "{} cannot be used with {}, {}, {}, {} or {}, since no files are stored",
+                flag.join(", "),
+                RECV_TAR.join(", "),
+                DISCARD.join(", "),
+                S3.join(", "),
+                ARCHIVE.join(", "),
+                LIST_ONLY.join(", ")
+            ));
+        }
+    }
+    let sink: Option<Box<dyn Sink + Send + Sync>> = if v.recv_tar {
+        Some(Box::new(sink::Tar::new(io::BufWriter::new(io::stdout()))))
+    } else if v.discard {
+        Some(Box::new(sink::Discard))
+    } else if let Some(url) = v.s3.take() {
+        Some(s3_sink(&url)?)
+    } else {
+        None
+    };
+    if v.recv_tar && (v.tui || v.qr || v.service) {
+        return Err(format!(
+            "{} cannot be used with {}, {} or {}, since the output is the archive",
+            RECV_TAR.join(", "),
+            TUI.join(", "),
+            QR.join(", "),
+            SERVICE.join(", ")
+        ));
+    }
+    if (v.recv_tar || v.discard) && (v.on_complete.is_some() || v.on_session_complete.is_some()) {
+        return Err(format!(
+            "{} and {} cannot be used with {} or {}, since no files are stored",
+            RECV_TAR.join(", "),
+            DISCARD.join(", "),
+            ON_COMPLETE.join(", "),
+            ON_SESSION_COMPLETE.join(", ")
+        ));
+    }
+    if v.stats && v.tui {
+        return Err(format!(
+            "{} cannot be used with {}, which shows its own graph",
+            STATS.join(", "),
+            TUI.join(", ")
+        ));
+    }
+    if v.open && (v.service || sinks.contains(&true) || v.archive.is_some()) {
+        return Err(format!(
+            "{} cannot be used with {}, {}, {}, {} or {}, since there's nothing to open",
+            OPEN.join(", "),
+            SERVICE.join(", "),
+            RECV_TAR.join(", "),
+            DISCARD.join(", "),
+            S3.join(", "),
+            ARCHIVE.join(", ")
+        ));
+    }
+    if v.http.is_some() && (v.mirror || v.archive.is_some()) {
+        return Err(format!(
+            "{} cannot be used with {} or {}",
+            HTTP.join(", "),
+            MIRROR.join(", "),
+            ARCHIVE.join(", ")
+        ));
+    }
+    if !v.filter.is_empty() && v.http.is_some() {
+        return Err(format!(
+            "{} cannot be used with {}, as uploads don't say what they are beforehand",
+            [ACCEPT_EXT, REJECT_EXT, REJECT_LARGER].concat().join(", "),
+            HTTP.join(", ")
+        ));
+    }
+    if v.service && (v.tui || v.qr || (v.mirror && !v.dry_run)) {
+        return Err(format!(
+            "{} cannot be used with {}, {} or {}, since nobody is watching",
+            SERVICE.join(", "),
+            TUI.join(", "),
+            QR.join(", "),
+            MIRROR.join(", ")
+        ));
+    }
+    if v.qr && v.tui {
+        return Err(format!(
+            "{} cannot be used with {}",
+            QR.join(", "),
+            TUI.join(", ")
+        ));
+    }
+    if v.tui && v.mirror && !v.dry_run {
+        return Err(format!(
+            "{} cannot be used with {}, since it asks before deleting",
+            TUI.join(", "),
+            MIRROR.join(", ")
+        ));
+    }
+
+    let case_collisions = match v.case_collisions {
+        Some(policy) => policy,
+        None => parse_case_policy(DEFAULT_CASE_POLICY)?,
+    };
+    let mut options = ReceiveOptions::default();
+    options.prefix = if v.strip_prefix {
+        PathPrefix::Strip
+    } else {
+        PathPrefix::Keep
+    };
+    options.strip_drive = v.strip_drive;
+    options.strip_components = v.strip_components.unwrap_or(0);
+    options.map = mem::take(&mut v.map);
+    options.output = v.output.take().unwrap_or_else(|| PathBuf::from("."));
+    options.mirror = v.mirror;
+    options.dry_run = v.dry_run;
+    options.archive = v.archive;
+    options.session_dirs = v.session_dirs;
+    options.staging = v.staging.take();
+    options.case_collisions = case_collisions;
+    options.duplicates = v.duplicates.unwrap_or(Duplicates::KeepLast);
+    options.normalize = v.normalize;
+    options.sink = sink;
+    options.socket = v.socket();
+    options.reconnect = v.reconnect();
+    options.chunk_size = v.chunk_size;
+    options.xattrs = v
+        .xattrs
+        .take()
+        .unwrap_or_else(|| DEFAULT_RECV_XATTRS.to_vec());
+    options.owner = v.owner.take();
+    options.file_mode = v.file_mode;
+    options.dir_mode = v.dir_mode;
+    options.checksums = v.checksum_db.take();
+    options.http = v.http;
+    options.include_virtual = v.include_virtual;
+    options.keep_receiving = v.service;
+    options.quota_per_peer = v.quota_per_peer;
+    options.quota = v.quota;
+    options.list_only = v.list_only;
+    options.list_json = v.list_json.take();
+    options.extract = v.extract;
+    options.encrypt_at_rest = v.encrypt_at_rest.take();
+    options.authorized_senders = authorized_senders;
+    if v.qr {
+        options.key = Some(sf::SessionKey::generate().map_err(|e| e.to_string())?);
+    }
+    options.busy = mem::take(&mut v.busy);
+    options.filter = mem::take(&mut v.filter);
+    options.sums = v.sums;
+    options.webhook = v.webhook.take();
+    Ok(values.into_settings(Mode::Receiver(options)))
+}
+
+fn parse_sender(prog_name: &str, command: &Command, args: Vec<String>) -> Result<Settings, String> {
+    let (mut values, positionals) = read_options(prog_name, command, args)?;
+    let mut positionals = positionals.into_iter();
+    let (ip, key) = parse_server_address(&positionals.next().ok_or("missing ip to send to")?)?;
+    let mut files = positionals.map(PathBuf::from).collect::<Vec<_>>();
+    let v = &mut values;
+
+    // the ip comes first, so the files given start after it
+    let rename = mem::take(&mut v.rename)
+        .into_iter()
+        .map(|(after, name)| match after.checked_sub(2) {
+            Some(i) => Ok((files[i].clone(), name)),
+            None => Err(format!("{} must follow the path it renames", AS.join(", "))),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if v.null && v.files_from.is_none() {
+        return Err(format!(
+            "{} can only be used with {}",
+            NULL.join(", "),
+            FILES_FROM.join(", ")
+        ));
+    }
+    if let Some(list) = v.files_from.take() {
+        if !files.is_empty() {
+            return Err(format!(
+                "no files can be given with {}",
+                FILES_FROM.join(", ")
+            ));
+        }
+        files = read_file_list(&list, v.null)?;
+        if files.is_empty() {
+            return Err(format!("no paths are listed in {:?}", list));
+        }
+        // what's listed is what's sent, as the list is usually made by walking already
+        v.max_depth = v.max_depth.or(Some(0));
+    }
+
+    let sourced = v.from_tar.is_some() || v.from_stdin.is_some();
+    if v.from_tar.is_some() && v.from_stdin.is_some() {
+        return Err(format!(
+            "{} cannot be used with {}",
+            FROM_TAR.join(", "),
+            FROM_STDIN.join(", ")
+        ));
+    }
+    if sourced && !files.is_empty() {
+        return Err(format!(
+            "no files can be given with {} or {}",
+            FROM_TAR.join(", "),
+            FROM_STDIN.join(", ")
+        ));
+    }
+    if sourced && (v.manifest.is_some() || v.archive.is_some()) {
+        return Err(format!(
+            "{} and {} cannot be used with {} or {}",
+            FROM_TAR.join(", "),
+            FROM_STDIN.join(", "),
+            MANIFEST.join(", "),
+            ARCHIVE.join(", ")
+        ));
+    }
+    let source: Option<Box<dyn Source + Send + Sync>> = if let Some(path) = v.from_tar.take() {
+        match source::TarFile::open(&path) {
+            Ok(archive) => Some(Box::new(archive)),
+            Err(e) => return Err(format!("cannot read the archive {:?}: {}", path, e)),
         }
+    } else if let Some(name) = v.from_stdin.take() {
+        match source::Stdin::read(&name) {
+            Ok(stdin) => Some(Box::new(stdin)),
+            Err(e) => return Err(format!("cannot read the standard input: {}", e)),
+        }
+    } else {
+        None
+    };
+
+    let mut options = v.send_options(rename, source)?;
+    options.key = key;
+    let files = check_files(files)?;
+    Ok(values.into_settings(Mode::Sender { ip, files, options }))
+}
+
+fn parse_bench(prog_name: &str, command: &Command, args: Vec<String>) -> Result<Settings, String> {
+    let (mut values, positionals) = read_options(prog_name, command, args)?;
+    let (ip, key) = match &positionals[..] {
+        [] => return Err("missing ip to benchmark against".into()),
+        [ip] => parse_server_address(ip)?,
+        _ => return Err("no files can be given when benchmarking".into()),
+    };
+    let source = source::Synthetic::new(
+        BENCH_NAME,
+        values.bench_mib.unwrap_or(DEFAULT_BENCH_MIB) * 1024 * 1024,
+        values.bench_pattern.unwrap_or(source::Pattern::Random),
+    );
+    let mut options = values.send_options(Vec::new(), Some(Box::new(source)))?;
+    options.key = key;
+    Ok(values.into_settings(Mode::Bench { ip, options }))
+}
+
+fn parse_verify(prog_name: &str, command: &Command, args: Vec<String>) -> Result<Settings, String> {
+    let (mut values, positionals) = read_options(prog_name, command, args)?;
+    let mut positionals = positionals.into_iter();
+    // the receivers that give out a key don't verify, which they say as they refuse
+    let (ip, _) = parse_server_address(&positionals.next().ok_or("missing ip to verify against")?)?;
+    let files = check_files(positionals.map(PathBuf::from).collect())?;
+    let options = VerifyOptions {
+        manifest: values.manifest.take(),
+        socket: values.socket(),
+        events: None,
+        cancel: None,
+    };
+    Ok(values.into_settings(Mode::Verify { ip, files, options }))
+}
+
+fn parse_serve(prog_name: &str, command: &Command, args: Vec<String>) -> Result<Settings, String> {
+    let (values, positionals) = read_options(prog_name, command, args)?;
+    let files = check_files(positionals.into_iter().map(PathBuf::from).collect())?;
+    let expire = match values.expire {
+        None if values.token => Some(DEFAULT_TOKEN_EXPIRE_SECS),
+        expire => expire,
+    };
+    let options = ServeOptions {
+        port: values.http.unwrap_or(DEFAULT_HTTP_PORT),
+        downloads: values.downloads.unwrap_or(DEFAULT_DOWNLOADS),
+        token: values.token,
+        expire: expire.map(Duration::from_secs),
+        include_virtual: values.include_virtual,
+        events: None,
+        cancel: None,
+    };
+    Ok(values.into_settings(Mode::Serve { files, options }))
+}
 
-        // must be the IP; break, and then the files should follow
-        ip = Some(arg);
-        break;
-    }
-
-    let files = args.into_iter().map(PathBuf::from).collect();
-
-    Settings {
-        mode: match ip {
-            Some(ip) => Mode::Sender {
-                ip: if ip == AUTO_IP {
-                    ServerAddress::Auto
-                } else {
-                    ServerAddress::Direct(ip.parse().expect("invalid ip format"))
-                },
-                files,
-            },
-            None => Mode::Receiver {
-                prefix: if strip_prefix {
-                    PathPrefix::Strip
-                } else {
-                    PathPrefix::Keep
-                },
-            },
+fn parse_wake(prog_name: &str, command: &Command, args: Vec<String>) -> Result<Settings, String> {
+    let (values, positionals) = read_options(prog_name, command, args)?;
+    let mac = match &positionals[..] {
+        [] => return Err("missing hardware address to wake".into()),
+        [mac] => parse_mac_address(mac)?,
+        _ => return Err("no files can be given when waking".into()),
+    };
+    Ok(values.into_settings(Mode::Wake { mac }))
+}
+
+fn parse_discover(
+    prog_name: &str,
+    command: &Command,
+    args: Vec<String>,
+) -> Result<Settings, String> {
+    let (values, positionals) = read_options(prog_name, command, args)?;
+    no_positionals(command, &positionals)?;
+    let window = values.retry.map(Duration::from_secs);
+    Ok(values.into_settings(Mode::Discover { window }))
+}
+
+fn parse_history(
+    prog_name: &str,
+    command: &Command,
+    args: Vec<String>,
+) -> Result<Settings, String> {
+    let (values, positionals) = read_options(prog_name, command, args)?;
+    no_positionals(command, &positionals)?;
+    let path = sf::history::default_path().ok_or("cannot tell where the history is kept")?;
+    Ok(values.into_settings(Mode::History { path }))
+}
+
+fn parse_service(
+    prog_name: &str,
+    command: &Command,
+    mut args: Vec<String>,
+) -> Result<Settings, String> {
+    if args.is_empty() {
+        return Err("missing service action".into());
+    }
+    let action = args.remove(0);
+    if HELP.contains(&action.as_str()) {
+        print_help(prog_name, command);
+        process::exit(0);
+    }
+    let receiver = |args: &[String]| {
+        parse_receiver(
+            prog_name,
+            command,
+            iter::once(SERVICE[0].to_string())
+                .chain(args.iter().cloned())
+                .collect(),
+        )
+    };
+    match action.as_str() {
+        "install" => {
+            let settings = receiver(&args)?;
+            // services start in the system directory, so relative paths would end up there
+            if let Mode::Receiver(options) = &settings.mode {
+                match std::path::absolute(&options.output) {
+                    Ok(output) => args.extend([OUTPUT[1].to_string(), path_arg(output)?]),
+                    Err(e) => {
+                        return Err(format!(
+                            "invalid output directory {:?}: {}",
+                            options.output, e
+                        ))
+                    }
+                }
+                if let Some(path) = &options.checksums {
+                    match std::path::absolute(path) {
+                        Ok(path) => args.extend([CHECKSUM_DB[0].to_string(), path_arg(path)?]),
+                        Err(e) => {
+                            return Err(format!("invalid checksum database {:?}: {}", path, e))
+                        }
+                    }
+                }
+            }
+            if let Some(path) = &settings.log_file {
+                match std::path::absolute(path) {
+                    Ok(path) => args.extend([LOG_FILE[0].to_string(), path_arg(path)?]),
+                    Err(e) => return Err(format!("invalid log file {:?}: {}", path, e)),
+                }
+            }
+            Ok(Settings {
+                mode: Mode::InstallService { args },
+                ..settings
+            })
+        }
+        "uninstall" => {
+            if !args.is_empty() {
+                return Err("the service is uninstalled without options".into());
+            }
+            Ok(Settings {
+                mode: Mode::UninstallService,
+                ..receiver(&args)?
+            })
+        }
+        "run" => Ok(Settings {
+            system_service: true,
+            ..receiver(&args)?
+        }),
+        _ => Err(format!(
+            "unknown service action {:?}, must be one of: {}",
+            action,
+            SERVICE_ACTIONS.join(", ")
+        )),
+    }
+}
+
+fn parse_queue(prog_name: &str, command: &Command, args: Vec<String>) -> Result<Settings, String> {
+    let mut args = args.into_iter().peekable();
+    let action = args.next().ok_or("missing queue action")?;
+    let mut queue = None;
+    let mut retries = None;
+    let mut retry_delay = None;
+    let mut jobs = None;
+    // the options of the queue come first, and those of the sender after them
+    while let Some(arg) = args.next_if(|arg| {
+        arg.starts_with('-') && (action != "add" || find_option(command, arg).is_some())
+    }) {
+        if HELP.contains(&arg.as_str()) {
+            print_help(prog_name, command);
+            process::exit(0);
+        } else if QUEUE_FILE.contains(&arg.as_str()) {
+            queue = Some(PathBuf::from(args.next().ok_or("missing queue file")?));
+        } else if RETRIES.contains(&arg.as_str()) {
+            let value = args.next().ok_or("missing retry count")?;
+            retries = Some(parse_number(&value, "retry count")?);
+        } else if RETRY_DELAY.contains(&arg.as_str()) {
+            retry_delay = Some(parse_duration(&args.next().ok_or("missing retry delay")?)?);
+        } else if JOBS.contains(&arg.as_str()) {
+            let value = args.next().ok_or("missing job count")?;
+            jobs = match parse_number(&value, "job count")? {
+                0 => return Err("invalid job count 0, at least one job must run".into()),
+                count => Some(count),
+            };
+        } else {
+            return Err(unknown_option(prog_name, command, &arg));
+        }
+    }
+    if HELP.contains(&action.as_str()) {
+        print_help(prog_name, command);
+        process::exit(0);
+    }
+    let args = args.collect::<Vec<_>>();
+    let queue = match queue.or_else(sf::queue::default_path) {
+        Some(queue) => queue,
+        None => {
+            return Err(format!(
+                "cannot tell where to keep the queue, use {} to say where",
+                QUEUE_FILE.join(", ")
+            ))
+        }
+    };
+
+    if action != "add" && (retries.is_some() || retry_delay.is_some()) {
+        return Err(format!(
+            "{} can only be used when adding to the queue",
+            [RETRIES, RETRY_DELAY].concat().join(", ")
+        ));
+    }
+    if action != "run" && jobs.is_some() {
+        return Err(format!(
+            "{} can only be used when running the queue",
+            JOBS.join(", ")
+        ));
+    }
+    if action != "add" && !args.is_empty() {
+        return Err("only jobs are added to the queue with the options of the sender".into());
+    }
+    let mode = match action.as_str() {
+        "add" => {
+            // checked now, so that jobs which could never run are not added
+            parse_sender(prog_name, find_command(SEND_COMMAND), args.clone())?;
+            Mode::QueueAdd {
+                queue,
+                args,
+                dir: env::current_dir()
+                    .map_err(|e| format!("cannot tell the current directory: {}", e))?,
+                retries: retries.unwrap_or(DEFAULT_RETRIES),
+                retry_delay: retry_delay.unwrap_or(Duration::from_secs(DEFAULT_RETRY_DELAY_SECS)),
+            }
+        }
+        "run" => Mode::QueueRun {
+            queue,
+            jobs: jobs.unwrap_or(DEFAULT_JOBS),
         },
+        "status" => Mode::QueueStatus { queue },
+        _ => {
+            return Err(format!(
+                "unknown queue action {:?}, must be one of: {}",
+                action,
+                QUEUE_ACTIONS.join(", ")
+            ))
+        }
+    };
+    Ok(Values::default().into_settings(mode))
+}
+
+// Replace every profile given with its options, up to the end of the options.
+fn expand_profiles(mut args: impl Iterator<Item = String>) -> Result<Vec<String>, String> {
+    let mut expanded = Vec::new();
+    while let Some(arg) = args.next() {
+        if arg == END_OF_OPTIONS {
+            expanded.push(arg);
+            expanded.extend(args);
+            break;
+        }
+        if !PROFILE.contains(&arg.as_str()) {
+            expanded.push(arg);
+            continue;
+        }
+        let name = args.next().ok_or("missing profile name")?;
+        let path =
+            profile::default_path().ok_or("cannot find the configuration file for the profiles")?;
+        match profile::load(&path, &name) {
+            Ok(options) => expanded.extend(options),
+            Err(e) => return Err(format!("cannot use the profile {:?}: {}", name, e)),
+        }
+    }
+    Ok(expanded)
+}
+
+fn path_arg(path: PathBuf) -> Result<String, String> {
+    path.into_os_string()
+        .into_string()
+        .map_err(|path| format!("the path {:?} must be valid unicode", path))
+}
+
+// Parse the number given to an option, saying what it was meant to be if it's not one.
+fn parse_number<T: FromStr>(value: &str, what: &str) -> Result<T, String>
+where
+    T::Err: fmt::Display,
+{
+    value
+        .parse()
+        .map_err(|e| format!("invalid {} {:?}: {}", what, value, e))
+}
+
+fn parse_duplicate_policy(policy: &str) -> Result<Duplicates, String> {
+    match policy {
+        "error" => Ok(Duplicates::Error),
+        "keep-first" => Ok(Duplicates::KeepFirst),
+        "keep-last" => Ok(Duplicates::KeepLast),
+        _ => Err(format!(
+            "unknown duplicate policy {:?}, must be one of: {}",
+            policy,
+            DUPLICATE_POLICIES.join(", ")
+        )),
+    }
+}
+
+fn parse_case_policy(policy: &str) -> Result<CaseCollisions, String> {
+    match policy {
+        "ignore" => Ok(CaseCollisions::Ignore),
+        "rename" => Ok(CaseCollisions::Rename),
+        "abort" => Ok(CaseCollisions::Abort),
+        _ => Err(format!(
+            "unknown case collision policy {:?}, must be one of: {}",
+            policy,
+            CASE_POLICIES.join(", ")
+        )),
+    }
+}
+
+fn parse_xattrs(namespaces: &str) -> Result<Vec<XattrNamespace>, String> {
+    namespaces
+        .split(',')
+        .map(|namespace| match namespace {
+            "user" => Ok(XattrNamespace::User),
+            "security" => Ok(XattrNamespace::Security),
+            "trusted" => Ok(XattrNamespace::Trusted),
+            "acl" => Ok(XattrNamespace::Acl),
+            _ => Err(format!(
+                "unknown xattr namespace {:?}, must be one of: {}",
+                namespace,
+                XATTR_NAMESPACES.join(", ")
+            )),
+        })
+        .collect()
+}
+
+fn parse_map(rule: &str) -> Result<sf::PathMap, String> {
+    let (from, to) = rule
+        .split_once("=>")
+        .ok_or_else(|| format!("invalid path mapping {:?}, it should be FROM=>TO", rule))?;
+    if to.split(['/', '\\']).any(|part| part == "..") {
+        return Err(format!(
+            "invalid path mapping {:?}, TO can't go up a directory",
+            rule
+        ));
+    }
+    Ok(sf::PathMap {
+        from: from.as_bytes().to_vec(),
+        to: to.as_bytes().to_vec(),
+    })
+}
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!(
+            "invalid mode {:?}, it should be octal such as 0644",
+            mode
+        )),
+    }
+}
+
+// The sink uploading to the bucket at the `url`, which needs the s3 feature.
+#[cfg(feature = "s3")]
+fn s3_sink(url: &str) -> Result<Box<dyn Sink + Send + Sync>, String> {
+    match sink::S3::from_env(url) {
+        Ok(s3) => Ok(Box::new(s3)),
+        Err(e) => Err(format!("cannot upload to {:?}: {}", url, e)),
+    }
+}
+
+#[cfg(not(feature = "s3"))]
+fn s3_sink(url: &str) -> Result<Box<dyn Sink + Send + Sync>, String> {
+    Err(format!(
+        "cannot upload to {:?}, as sf was built without the s3 feature",
+        url
+    ))
+}
+
+// The address to connect to, and the session key to prove knowing if it came in a descriptor
+// that has one.
+fn parse_server_address(ip: &str) -> Result<(ServerAddress, Option<sf::SessionKey>), String> {
+    if ip == AUTO_IP {
+        return Ok((ServerAddress::Auto, None));
+    }
+    if let Some((addr, key)) = sf::parse_connection_descriptor(ip) {
+        return Ok((ServerAddress::Direct(addr), key));
+    }
+    if let Ok(ip) = ip.parse() {
+        return Ok((ServerAddress::Direct(SocketAddr::new(ip, sf::PORT)), None));
+    }
+    if edit_distance(&ip.to_ascii_lowercase(), AUTO_IP) <= 2 {
+        return Err(format!("invalid ip {:?}, did you mean `{}'?", ip, AUTO_IP));
+    }
+    if let Ok(addr) = ip.parse::<SocketAddr>() {
+        return Err(format!(
+            "invalid ip {:?}, did you mean `{}'?",
+            ip,
+            sf::connection_descriptor(addr, None)
+        ));
+    }
+    if Path::new(ip).exists() {
+        return Err(format!(
+            "invalid ip {:?}, which is a path; the ip to send to must come before the files",
+            ip
+        ));
+    }
+    Err(format!(
+        "invalid ip {:?}, must be an address like 192.168.1.2 or `{}' to find the receiver",
+        ip, AUTO_IP
+    ))
+}
+// How many characters must be added, removed or changed to turn one text into the other.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (diagonal + usize::from(ca != cb))
+                .min(above + 1)
+                .min(row[j] + 1);
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+// Files that are missing or cannot be read are better reported before connecting than midway.
+fn check_files(files: Vec<PathBuf>) -> Result<Vec<PathBuf>, String> {
+    for path in &files {
+        let readable = match fs::metadata(path) {
+            Ok(meta) if meta.is_dir() => fs::read_dir(path).map(drop),
+            Ok(meta) if meta.is_file() => fs::File::open(path).map(drop),
+            // opening a FIFO would wait for someone to write to it, and it's not read anyway
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(format!("{:?} does not exist", path))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = readable {
+            return Err(format!("cannot read {:?}: {}", path, e));
+        }
+    }
+    Ok(files)
+}
+
+// Read the paths listed in the file, or the standard input if it's `-`, one per line or
+// separated by NUL characters.
+fn read_file_list(list: &str, null: bool) -> Result<Vec<PathBuf>, String> {
+    let text = if list == "-" {
+        io::read_to_string(io::stdin())
+    } else {
+        fs::read_to_string(list)
+    }
+    .map_err(|e| format!("cannot read the list of paths {:?}: {}", list, e))?;
+    let separator = if null { '\0' } else { '\n' };
+    Ok(text
+        .split(separator)
+        .map(|path| {
+            if null {
+                path
+            } else {
+                path.trim_end_matches('\r')
+            }
+        })
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+// Parse a port to serve on locally, or the whole address to serve on.
+fn parse_metrics_address(address: &str) -> Result<SocketAddr, String> {
+    match address.parse::<u16>() {
+        Ok(port) => Ok(SocketAddr::from(([127, 0, 0, 1], port))),
+        Err(_) => address
+            .parse()
+            .map_err(|_| format!("invalid metrics port or address {:?}", address)),
+    }
+}
+
+fn parse_recipient(recipient: &str) -> Result<sf::Recipient, String> {
+    sf::Recipient::parse(recipient).ok_or_else(|| format!("invalid age recipient {:?}", recipient))
+}
+
+fn parse_mac_address(mac: &str) -> Result<sf::MacAddress, String> {
+    sf::parse_mac_address(mac).ok_or_else(|| format!("invalid hardware address {:?}", mac))
+}
+
+fn parse_extensions(extensions: &str) -> Vec<String> {
+    extensions
+        .split(',')
+        .map(|ext| ext.trim_start_matches('.').to_owned())
+        .filter(|ext| !ext.is_empty())
+        .collect()
+}
+
+fn parse_busy_windows(windows: &str) -> Result<Vec<sf::DailyWindow>, String> {
+    windows
+        .split(',')
+        .map(|window| {
+            sf::DailyWindow::parse(window)
+                .ok_or_else(|| format!("invalid busy window {:?}", window))
+        })
+        .collect()
+}
+
+// Durations are in seconds, optionally followed by a unit (s, m, h or d).
+fn parse_duration(duration: &str) -> Result<Duration, String> {
+    let (digits, secs) = match duration.chars().last() {
+        Some('s') => (&duration[..duration.len() - 1], 1),
+        Some('m') => (&duration[..duration.len() - 1], 60),
+        Some('h') => (&duration[..duration.len() - 1], 60 * 60),
+        Some('d') => (&duration[..duration.len() - 1], 24 * 60 * 60),
+        _ => (duration, 1),
+    };
+    let value = parse_number::<u64>(digits, "duration")?;
+    let secs = value
+        .checked_mul(secs)
+        .ok_or_else(|| format!("duration {:?} is too long", duration))?;
+    Ok(Duration::from_secs(secs))
+}
+
+// Sizes are in bytes, optionally followed by a binary unit (K, M or G).
+fn parse_size(size: &str) -> Result<usize, String> {
+    let (digits, shift) = match size.to_ascii_uppercase().chars().last() {
+        Some('K') => (&size[..size.len() - 1], 10),
+        Some('M') => (&size[..size.len() - 1], 20),
+        Some('G') => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    let value = parse_number::<usize>(digits, "size")?;
+    value
+        .checked_mul(1 << shift)
+        .ok_or_else(|| format!("size {:?} is too large for this platform", size))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &[&str]) -> Result<Settings, String> {
+        parse_command("sf", args.iter().map(|arg| arg.to_string()).collect())
+    }
+
+    #[test]
+    fn bad_arguments_are_reported() {
+        let cases: [(&[&str], &str); 5] = [
+            (
+                &["--timeout", "abc"],
+                "invalid timeout \"abc\": invalid digit found in string",
+            ),
+            (&["--timeout"], "missing timeout value"),
+            (
+                &["--chunk-size", "1T"],
+                "invalid size \"1T\": invalid digit found in string",
+            ),
+            (
+                &["--bogus"],
+                "unknown option \"--bogus\", see `sf recv --help' for the available ones",
+            ),
+            (
+                &["--mirror", "--archive", "tar"],
+                "--mirror cannot be used with -a, --archive",
+            ),
+        ];
+        for (args, error) in cases {
+            assert_eq!(parse_args(args).err().as_deref(), Some(error));
+        }
+    }
+
+    #[test]
+    fn options_belong_to_their_command() {
+        let cases: [(&[&str], &str); 6] = [
+            (
+                &["recv", "--update"],
+                "--update is not an option of recv, only of send",
+            ),
+            (
+                &["send", "--output", "out", "1.2.3.4"],
+                "--output is not an option of send, only of recv, service",
+            ),
+            (
+                &["recv", "--outpt", "out"],
+                "unknown option \"--outpt\", did you mean `--output'?",
+            ),
+            (
+                &["history", "1.2.3.4"],
+                "history takes only options, but was given \"1.2.3.4\"",
+            ),
+            (&["send", "--update"], "missing ip to send to"),
+            (
+                &["recv", "1.2.3.4"],
+                "recv takes only options, but was given \"1.2.3.4\"",
+            ),
+        ];
+        for (args, error) in cases {
+            assert_eq!(parse_args(args).err().as_deref(), Some(error));
+        }
+    }
+
+    #[test]
+    fn commands_can_be_left_out() {
+        let settings = parse_args(&["-v", "--update", "1.2.3.4", "Cargo.toml"]).unwrap();
+        assert!(matches!(settings.mode, Mode::Sender { .. }));
+        assert_eq!(settings.verbosity, 1);
+        let settings = parse_args(&["-o", "out", "--quiet"]).unwrap();
+        assert!(matches!(settings.mode, Mode::Receiver(_)));
+        let settings = parse_args(&["--timeout", "5", "verify", "1.2.3.4", "Cargo.toml"]).unwrap();
+        assert!(matches!(settings.mode, Mode::Verify { .. }));
+        let settings = parse_args(&["serve", "--http", "Cargo.toml"]).unwrap();
+        assert!(matches!(
+            settings.mode,
+            Mode::Serve { options, .. } if options.port == DEFAULT_HTTP_PORT
+        ));
+        let settings = parse_args(&["--http", "8000", "serve", "Cargo.toml"]).unwrap();
+        assert!(matches!(settings.mode, Mode::Serve { options, .. } if options.port == 8000));
+    }
+
+    // The help is made of constants, so what it says of the names defined elsewhere is checked.
+    #[test]
+    fn help_mentions_current_names() {
+        let help = COMMANDS
+            .iter()
+            .flat_map(|command| {
+                let options = command.options.iter().copied().flatten();
+                (command.about.iter()).chain(options.flat_map(|opt| opt.help.iter()))
+            })
+            .copied()
+            .collect::<Vec<_>>()
+            .join("\n");
+        let descriptor =
+            sf::connection_descriptor(SocketAddr::from(([192, 168, 1, 2], sf::PORT)), None);
+        for name in [
+            crate::logger::FILTER_VAR,
+            crate::service::SERVICE_NAME,
+            AUTO_IP,
+            &descriptor,
+        ] {
+            assert!(help.contains(name), "{:?} is not in the help", name);
+        }
     }
 }
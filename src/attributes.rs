@@ -0,0 +1,94 @@
+//! When files were created, and the attributes Windows keeps apart from their permissions,
+//! which only some platforms can read or set.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+/// The file can't be written to.
+pub(crate) const READONLY: u8 = 1;
+/// The file is left out of directory listings, which outside Windows depends on its name.
+#[cfg_attr(not(windows), allow(dead_code))]
+pub(crate) const HIDDEN: u8 = 2;
+
+/// When the file was created, in seconds since the unix epoch, if the platform knows.
+pub(crate) fn created(meta: &fs::Metadata) -> Option<u64> {
+    let created = meta.created().ok()?;
+    Some(created.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// The attributes of the file, as a combination of [`READONLY`] and [`HIDDEN`].
+pub(crate) fn get(meta: &fs::Metadata) -> u8 {
+    let mut attributes = 0;
+    if meta.permissions().readonly() {
+        attributes |= READONLY;
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        use winapi::um::winnt::FILE_ATTRIBUTE_HIDDEN;
+        if meta.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0 {
+            attributes |= HIDDEN;
+        }
+    }
+    attributes
+}
+
+/// Sets when the file was created, where the platform allows it. Elsewhere it's left as it is.
+pub(crate) fn set_created(path: &Path, created: u64) -> io::Result<()> {
+    #[cfg(any(windows, target_os = "macos"))]
+    {
+        #[cfg(target_os = "macos")]
+        use std::os::macos::fs::FileTimesExt;
+        #[cfg(windows)]
+        use std::os::windows::fs::FileTimesExt;
+
+        let created = UNIX_EPOCH + std::time::Duration::from_secs(created);
+        let file = fs::OpenOptions::new().write(true).open(path)?;
+        file.set_times(fs::FileTimes::new().set_created(created))
+    }
+    #[cfg(not(any(windows, target_os = "macos")))]
+    {
+        let _ = (path, created);
+        Ok(())
+    }
+}
+
+/// Sets the attributes of the file that the platform has. Being read-only is kept last, as
+/// nothing else can be changed after it.
+pub(crate) fn set(path: &Path, attributes: u8) -> io::Result<()> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::OsStrExt;
+        use winapi::um::fileapi::{
+            GetFileAttributesW, SetFileAttributesW, INVALID_FILE_ATTRIBUTES,
+        };
+        use winapi::um::winnt::FILE_ATTRIBUTE_HIDDEN;
+
+        let wide = path
+            .as_os_str()
+            .encode_wide()
+            .chain(Some(0))
+            .collect::<Vec<_>>();
+        let current = unsafe { GetFileAttributesW(wide.as_ptr()) };
+        if current == INVALID_FILE_ATTRIBUTES {
+            return Err(io::Error::last_os_error());
+        }
+        let wanted = if attributes & HIDDEN != 0 {
+            current | FILE_ATTRIBUTE_HIDDEN
+        } else {
+            current & !FILE_ATTRIBUTE_HIDDEN
+        };
+        if wanted != current && unsafe { SetFileAttributesW(wide.as_ptr(), wanted) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    let mut permissions = fs::metadata(path)?.permissions();
+    if permissions.readonly() != (attributes & READONLY != 0) {
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(attributes & READONLY != 0);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use crate::{Error, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// Lets a transfer be stopped from elsewhere, such as another thread.
+///
+/// The transfer notices in between chunks, or at once while it waits, and fails with
+/// [`Error::Cancelled`]. A receiver keeps the files it already had in full, and removes the
+/// one it was in the middle of.
+#[derive(Clone, Debug, Default)]
+pub struct CancelToken(Arc<Shared>);
+
+#[derive(Debug, Default)]
+struct Shared {
+    cancelled: AtomicBool,
+    // wakes up whoever is waiting for it to be cancelled
+    notify: Notify,
+}
+
+impl CancelToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Asks every transfer using this token (or one of its clones) to stop.
+    pub fn cancel(&self) {
+        self.0.cancelled.store(true, Ordering::Relaxed);
+        self.0.notify.notify_waiters();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.cancelled.load(Ordering::Relaxed)
+    }
+}
+
+// Fail if the transfer was asked to stop.
+pub(crate) fn check(token: &Option<CancelToken>) -> Result<()> {
+    if is_cancelled(token) {
+        Err(Error::Cancelled)
+    } else {
+        Ok(())
+    }
+}
+
+// Complete once the transfer is asked to stop, which is never without a token.
+pub(crate) async fn cancelled(token: &Option<CancelToken>) {
+    let Some(token) = token else {
+        return std::future::pending().await;
+    };
+    // waiting starts before checking, or a cancellation in between would be missed
+    let notified = token.0.notify.notified();
+    if !token.is_cancelled() {
+        notified.await;
+    }
+}
+
+// Wait for as long as given, unless the transfer is asked to stop first.
+pub(crate) async fn sleep(token: &Option<CancelToken>, duration: Duration) -> Result<()> {
+    tokio::select! {
+        () = tokio::time::sleep(duration) => check(token),
+        () = cancelled(token) => Err(Error::Cancelled),
+    }
+}
+
+pub(crate) fn is_cancelled(token: &Option<CancelToken>) -> bool {
+    token.as_ref().is_some_and(CancelToken::is_cancelled)
+}
@@ -0,0 +1,142 @@
+//! An index of the files under the output directory by the hash of their contents, so that
+//! the files a receiver already has, under any name, don't need to be sent again.
+//!
+//! It's kept between runs in a file with the same format as a manifest, with the names
+//! relative to the output directory. Files that changed since they were indexed, by their
+//! size or modification time, are hashed again when it's opened.
+
+use crate::hash::Digest;
+use crate::manifest::{self, Entry};
+use crate::{cancel, modified_secs, names, Error, Result, PARTIAL_EXTENSION};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+pub(crate) struct Checksums {
+    path: PathBuf,
+    root: PathBuf,
+    // by their name relative to the root
+    files: HashMap<Vec<u8>, Entry>,
+    by_digest: HashMap<Digest, Vec<u8>>,
+    // the name of the index itself, if it's kept among the files it indexes
+    own_name: Option<Vec<u8>>,
+}
+
+impl Checksums {
+    /// Opens the index at `path` of the files under `root`, bringing it up to date.
+    pub fn open(path: &Path, root: &Path, cancel: &Option<cancel::CancelToken>) -> Result<Self> {
+        let mut known = HashMap::new();
+        if path.exists() {
+            for entry in manifest::read(path)? {
+                known.insert(entry.name.clone(), entry);
+            }
+        }
+
+        let mut checksums = Self {
+            path: path.to_path_buf(),
+            root: root.to_path_buf(),
+            files: HashMap::new(),
+            by_digest: HashMap::new(),
+            own_name: None,
+        };
+        checksums.own_name = inside(path, root).map(|path| checksums.name(&path));
+        let mut changed = Vec::new();
+        if root.exists() {
+            for entry in WalkDir::new(root) {
+                let entry = entry?;
+                let path = entry.path();
+                let partial = path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION);
+                let name = checksums.name(path);
+                if !entry.file_type().is_file()
+                    || partial
+                    || Some(&name) == checksums.own_name.as_ref()
+                {
+                    continue;
+                }
+                let meta = entry.metadata()?;
+                match known.remove(&name) {
+                    Some(entry)
+                        if entry.len == meta.len() && entry.modified == modified_secs(&meta) =>
+                    {
+                        checksums.insert(entry)
+                    }
+                    _ => changed.push((name, entry.into_path())),
+                }
+            }
+        }
+
+        if !changed.is_empty() {
+            out!("indexing {} new or changed files...", changed.len());
+            let paths = changed
+                .iter()
+                .map(|(_, path)| path.clone())
+                .collect::<Vec<_>>();
+            let hashed = manifest::hash_files(&paths, cancel)?;
+            for ((name, _), entry) in changed.into_iter().zip(hashed) {
+                checksums.insert(Entry { name, ..entry });
+            }
+        }
+        checksums.save()?;
+        Ok(checksums)
+    }
+
+    /// Finds a file with the given contents, if it's still there as it was indexed.
+    pub fn find(&self, digest: &Digest, len: u64) -> Option<PathBuf> {
+        let entry = &self.files[self.by_digest.get(digest)?];
+        let path = names::relative_path(&entry.name).map(|path| self.root.join(path))?;
+        let meta = fs::metadata(&path).ok()?;
+        let unchanged = meta.len() == entry.len && modified_secs(&meta) == entry.modified;
+        (entry.len == len && unchanged).then_some(path)
+    }
+
+    /// Where the index itself is, if it's kept among the files it indexes.
+    pub fn own_path(&self) -> Option<PathBuf> {
+        names::relative_path(self.own_name.as_ref()?).map(|path| self.root.join(path))
+    }
+
+    /// Indexes the file at `path`, with the given contents.
+    pub fn add(&mut self, path: &Path, digest: Digest) -> Result<()> {
+        let meta = fs::metadata(path).map_err(|e| Error::from(e).at(path))?;
+        self.insert(Entry {
+            name: self.name(path),
+            len: meta.len(),
+            modified: modified_secs(&meta),
+            digest,
+        });
+        Ok(())
+    }
+
+    /// Writes the index back to its file.
+    pub fn save(&self) -> Result<()> {
+        let mut entries = self.files.values().collect::<Vec<_>>();
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        manifest::write(&self.path, entries)
+    }
+
+    fn insert(&mut self, entry: Entry) {
+        if let Some(old) = self.files.get(&entry.name) {
+            // other files with the old contents are only found again once reopened
+            if self.by_digest.get(&old.digest) == Some(&old.name) {
+                self.by_digest.remove(&old.digest);
+            }
+        }
+        self.by_digest.insert(entry.digest, entry.name.clone());
+        self.files.insert(entry.name.clone(), entry);
+    }
+
+    fn name(&self, path: &Path) -> Vec<u8> {
+        names::wire_name(path.strip_prefix(&self.root).unwrap_or(path))
+    }
+}
+
+// The path relative to the root of the file at `path`, if it's under it.
+fn inside(path: &Path, root: &Path) -> Option<PathBuf> {
+    let root = fs::canonicalize(root).ok()?;
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let path = fs::canonicalize(parent).ok()?.join(path.file_name()?);
+    path.strip_prefix(root).ok().map(Path::to_path_buf)
+}
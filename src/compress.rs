@@ -0,0 +1,240 @@
+//! Compressing the file data chunk by chunk as it's sent, when that gets it there sooner.
+//!
+//! Every chunk is sent with its length and the length it was compressed to, or zero if it's
+//! sent as it is, which is what happens when compressing doesn't make it smaller. Files whose
+//! type is known not to compress are not sent in chunks at all, and those known to be text are
+//! compressed harder, as they're worth it.
+
+use crate::deflate::{self, Level};
+use crate::filter::extension;
+use crate::protocol::{chunk_header, CHUNK_HEADER_LEN};
+use crate::Compression;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The most data compressed as one chunk, however large the chunks are otherwise.
+pub(crate) const MAX_COMPRESSED_CHUNK: usize = 1024 * 1024;
+
+// Chunks compressed at the start of every file, to see how well it compresses.
+const SAMPLE_CHUNKS: u64 = 2;
+// Chunks after which another is compressed if they weren't, in case the data changed.
+const SAMPLE_INTERVAL: u64 = 32;
+// The least the data must shrink by to be worth sending compressed, as a fraction of it.
+const MIN_SAVINGS: f64 = 0.1;
+// Writes shorter than this tell little about how fast the network is.
+const MIN_MEASURED_LEN: usize = 64 * 1024;
+
+// Extensions of files that are compressed already, whose data would only waste time.
+const COMPRESSED_EXTENSIONS: [&str; 37] = [
+    "7z", "aac", "apk", "avi", "avif", "br", "bz2", "deb", "docx", "epub", "flac", "gif", "gz",
+    "heic", "jar", "jpeg", "jpg", "lz4", "m4a", "m4v", "mkv", "mov", "mp3", "mp4", "odt", "ogg",
+    "opus", "png", "rar", "rpm", "tgz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+// Extensions of text files, which compress well enough to look harder for repeats.
+const TEXT_EXTENSIONS: [&str; 34] = [
+    "c", "cc", "conf", "cpp", "cs", "css", "csv", "go", "h", "hpp", "htm", "html", "ini", "java",
+    "js", "json", "jsx", "log", "md", "php", "py", "rb", "rs", "rst", "sh", "sql", "svg", "tex",
+    "toml", "ts", "tsv", "txt", "xml", "yaml",
+];
+
+/// How the data of a file is sent when the data is being compressed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Codec {
+    /// As it is, rather than in chunks.
+    None,
+    /// In chunks, compressed with DEFLATE at the level if that makes them smaller.
+    Deflate(Level),
+}
+
+impl Codec {
+    /// The codec for a file with the name, going by its type.
+    pub(crate) fn for_name(name: &[u8]) -> Self {
+        let known = |extensions: &[&str]| {
+            extension(name).is_some_and(|ext| {
+                extensions
+                    .iter()
+                    .any(|known| known.as_bytes().eq_ignore_ascii_case(ext))
+            })
+        };
+        if known(&COMPRESSED_EXTENSIONS) {
+            Codec::None
+        } else if known(&TEXT_EXTENSIONS) {
+            Codec::Deflate(Level::BEST)
+        } else {
+            Codec::Deflate(Level::DEFAULT)
+        }
+    }
+}
+
+// How much of what was measured before is kept with every new write, so that it follows the
+// link as it changes.
+const LINK_DECAY: f64 = 0.875;
+
+/// How fast the data leaves, measured by the side writing it as it goes.
+#[derive(Default)]
+pub(crate) struct LinkSpeed {
+    // how many bytes were written recently, and in how many seconds, the older the less
+    written: Mutex<(f64, f64)>,
+}
+
+impl LinkSpeed {
+    /// Records that `len` bytes took `elapsed` to write.
+    pub(crate) fn record(&self, len: usize, elapsed: Duration) {
+        if len < MIN_MEASURED_LEN {
+            return;
+        }
+        // writes that fit in the buffers of the system return at once, which is why the bytes
+        // and the time are added up apart: those then count for little, rather than for a lot
+        let mut written = self.written.lock().unwrap();
+        written.0 = written.0 * LINK_DECAY + len as f64;
+        written.1 = written.1 * LINK_DECAY + elapsed.as_secs_f64();
+    }
+
+    // In bytes per second, if anything was measured yet.
+    fn get(&self) -> Option<f64> {
+        let (bytes, secs) = *self.written.lock().unwrap();
+        (bytes != 0.0).then(|| bytes / secs.max(1e-6))
+    }
+}
+
+/// Decides which chunks to compress, and frames them for sending.
+pub(crate) struct Compressor {
+    mode: Compression,
+    level: Level,
+    link: Arc<LinkSpeed>,
+    // how many chunks of the current file were framed so far
+    chunks: u64,
+    // what the last compressed chunk shrunk to, as a fraction of it, and how fast it was
+    // compressed, in bytes per second
+    ratio: f64,
+    speed: f64,
+    // reused to frame every chunk in, when they're framed one at a time
+    scratch: Vec<u8>,
+    /// How much data was framed, and how much was sent for it, headers included.
+    pub(crate) raw_len: u64,
+    pub(crate) sent_len: u64,
+}
+
+impl Compressor {
+    pub(crate) fn new(mode: Compression) -> Self {
+        Self {
+            mode,
+            level: Level::DEFAULT,
+            link: Arc::default(),
+            chunks: 0,
+            ratio: 1.0,
+            speed: 0.0,
+            scratch: Vec::new(),
+            raw_len: 0,
+            sent_len: 0,
+        }
+    }
+
+    /// Where the speed of the network is to be recorded as the chunks are written.
+    pub(crate) fn link(&self) -> Arc<LinkSpeed> {
+        Arc::clone(&self.link)
+    }
+
+    /// Starts on the data of another file, which may compress differently, and is compressed
+    /// at the `level`.
+    pub(crate) fn start_file(&mut self, level: Level) {
+        self.level = level;
+        self.chunks = 0;
+    }
+
+    /// Writes the chunk of data, compressed or not, with its header, into `frame`, which must
+    /// have room for the header and the data as it is. Returns how much of it was used.
+    pub(crate) fn frame(&mut self, data: &[u8], frame: &mut [u8]) -> usize {
+        let level = self.next_level();
+        let mut chunk = Chunk::new(data, level, std::mem::take(&mut self.scratch));
+        self.account(&chunk);
+        let frame_len = chunk.frame.len();
+        frame[..frame_len].copy_from_slice(&chunk.frame);
+        chunk.frame.clear();
+        self.scratch = chunk.frame;
+        frame_len
+    }
+
+    /// The level to compress the next chunk at, if it's worth compressing, going by how those
+    /// accounted for so far did.
+    pub(crate) fn next_level(&mut self) -> Option<Level> {
+        let compress = match self.mode {
+            Compression::Never => false,
+            Compression::Always => true,
+            Compression::Auto => {
+                self.chunks < SAMPLE_CHUNKS || self.chunks % SAMPLE_INTERVAL == 0 || self.worth_it()
+            }
+        };
+        self.chunks += 1;
+        compress.then_some(self.level)
+    }
+
+    /// Takes into account how the chunk compressed, if it was, in the order they're sent.
+    pub(crate) fn account(&mut self, chunk: &Chunk) {
+        if let Some((ratio, speed)) = chunk.sampled {
+            self.ratio = ratio;
+            self.speed = speed;
+        }
+        self.raw_len += chunk.raw_len as u64;
+        self.sent_len += (chunk.frame.len() - chunk.prefix_len) as u64;
+    }
+
+    // Whether compressing gets the data there sooner. It's compressed while the system is still
+    // sending the previous chunk, so the slowest of both is what counts: as long as it
+    // compresses faster than the network sends, the data being smaller is all gain.
+    fn worth_it(&self) -> bool {
+        self.ratio <= 1.0 - MIN_SAVINGS && self.link.get().is_none_or(|link| self.speed > link)
+    }
+}
+
+/// A chunk framed on its own, which can be done on any thread, as long as the chunks are then
+/// accounted for in order.
+pub(crate) struct Chunk {
+    /// Whatever came before the chunk, followed by its header and data.
+    pub(crate) frame: Vec<u8>,
+    prefix_len: usize,
+    raw_len: usize,
+    // what it shrunk to, as a fraction of it, and how fast, if it was compressed
+    sampled: Option<(f64, f64)>,
+}
+
+impl Chunk {
+    /// Frames the chunk of data after what's in `frame`, compressed at the `level` if there's
+    /// one and that makes it smaller.
+    pub(crate) fn new(data: &[u8], level: Option<Level>, mut frame: Vec<u8>) -> Self {
+        let prefix_len = frame.len();
+        let start = prefix_len + CHUNK_HEADER_LEN;
+        frame.resize(start, 0);
+        let mut sampled = None;
+        if let Some(level) = level {
+            let started = Instant::now();
+            deflate::compress(data, level, &mut frame);
+            let elapsed = started.elapsed().as_secs_f64().max(1e-6);
+            let compressed_len = frame.len() - start;
+            sampled = Some((
+                compressed_len as f64 / data.len() as f64,
+                data.len() as f64 / elapsed,
+            ));
+        }
+        let compressed_len = frame.len() - start;
+        let compressed_len = if level.is_some() && compressed_len < data.len() {
+            compressed_len as u32
+        } else {
+            frame.truncate(start);
+            frame.extend_from_slice(data);
+            0
+        };
+        trace!(
+            "sending a chunk of {} bytes, compressed to {}",
+            data.len(),
+            compressed_len
+        );
+        frame[prefix_len..start].copy_from_slice(&chunk_header(data.len() as u32, compressed_len));
+        Self {
+            frame,
+            prefix_len,
+            raw_len: data.len(),
+            sampled,
+        }
+    }
+}
@@ -0,0 +1,47 @@
+//! Pausing the receiver from outside of the transfer, without a full screen interface.
+
+use sf::PauseToken;
+use std::io::{self, IsTerminal};
+
+/// Pauses or resumes the transfer every time enter is pressed, if there's a terminal.
+pub fn toggle_on_enter(token: &PauseToken) {
+    if !io::stdin().is_terminal() {
+        return;
+    }
+    let token = token.clone();
+    // the lines answering a question, such as the one from --mirror, don't get here
+    sf::input::on_line(move |_| {
+        token.toggle();
+    });
+}
+
+/// Pauses or resumes the transfer every time the process gets `SIGUSR1`.
+#[cfg(unix)]
+pub fn toggle_on_signal(token: &PauseToken) {
+    use std::sync::OnceLock;
+
+    // signal.h, where Linux numbers it differently from the BSDs
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const SIGUSR1: i32 = 10;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    const SIGUSR1: i32 = 30;
+
+    static TOKEN: OnceLock<PauseToken> = OnceLock::new();
+
+    extern "C" {
+        fn signal(signum: i32, handler: extern "C" fn(i32)) -> usize;
+    }
+
+    // only an atomic update happens here, which is fine to do in a signal handler
+    extern "C" fn toggle(_: i32) {
+        if let Some(token) = TOKEN.get() {
+            token.toggle();
+        }
+    }
+
+    TOKEN.get_or_init(|| token.clone());
+    unsafe { signal(SIGUSR1, toggle) };
+}
+
+#[cfg(not(unix))]
+pub fn toggle_on_signal(_token: &PauseToken) {}
@@ -0,0 +1,586 @@
+//! An encoder for DEFLATE (RFC 1951), the counterpart of the decoder in [`crate::inflate`],
+//! which compresses the file data as it's sent. Repeats are found by following the chain of
+//! earlier positions that start with the same three bytes, and every block is coded with
+//! Huffman codes built for it, or stored as it is if that's shorter.
+
+use crate::inflate::{
+    CODE_LENGTH_ORDER, DISTANCE_BASE, DISTANCE_EXTRA, END_OF_BLOCK, LENGTH_BASE, LENGTH_EXTRA,
+    MAX_BITS, MAX_DISTANCE_CODES, MAX_LITERAL_CODES, WINDOW_SIZE,
+};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// matches this short are not worth it if they're this far, as the distance costs more
+const FAR_SHORT_MATCH: usize = 4096;
+const HASH_BITS: u32 = 15;
+const NO_POSITION: u32 = u32::MAX;
+const BLOCK_SYMBOLS: usize = 64 * 1024; // most symbols coded with the same codes
+const MAX_STORED_LEN: usize = 0xffff;
+const LENGTH_CODES: usize = 19;
+const MAX_LENGTH_BITS: usize = 7; // longest code of the code lengths
+
+/// How hard to look for repeats, which makes the data smaller but takes longer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Level {
+    // how many earlier positions to try at most
+    chain: usize,
+    // whether to check if a match at the next position is longer before taking one
+    lazy: bool,
+    // matches at least this long are taken without looking for longer ones
+    nice: usize,
+}
+
+impl Level {
+    pub const DEFAULT: Level = Level {
+        chain: 32,
+        lazy: true,
+        nice: 128,
+    };
+    pub const BEST: Level = Level {
+        chain: 1024,
+        lazy: true,
+        nice: MAX_MATCH,
+    };
+}
+
+// A literal byte (with no distance), or a match of the length that many bytes back.
+#[derive(Clone, Copy)]
+struct Symbol {
+    len: u16,
+    distance: u16,
+}
+
+impl Symbol {
+    // how many bytes of the data it stands for
+    fn data_len(&self) -> usize {
+        if self.distance == 0 {
+            1
+        } else {
+            self.len as usize
+        }
+    }
+}
+
+/// Compresses the data into a single DEFLATE stream, appended to `out`.
+pub(crate) fn compress(data: &[u8], level: Level, out: &mut Vec<u8>) {
+    let symbols = find_matches(data, level);
+    let mut bits = BitWriter {
+        out,
+        bits: 0,
+        count: 0,
+    };
+    if symbols.is_empty() {
+        write_stored(&mut bits, &[], true);
+    }
+    let block_count = symbols.len().div_ceil(BLOCK_SYMBOLS);
+    let mut start = 0;
+    for (i, block) in symbols.chunks(BLOCK_SYMBOLS).enumerate() {
+        let len = block.iter().map(Symbol::data_len).sum::<usize>();
+        write_block(
+            &mut bits,
+            block,
+            &data[start..start + len],
+            i + 1 == block_count,
+        );
+        start += len;
+    }
+    bits.flush();
+}
+
+// Where earlier positions with the same next three bytes are.
+struct Matcher<'a> {
+    data: &'a [u8],
+    // the last position for every hash
+    head: Vec<u32>,
+    // the position before each one with the same hash
+    prev: Vec<u32>,
+    level: Level,
+}
+
+impl Matcher<'_> {
+    fn hash(&self, i: usize) -> usize {
+        let bytes = u32::from_le_bytes([self.data[i], self.data[i + 1], self.data[i + 2], 0]);
+        (bytes.wrapping_mul(0x9e37_79b1) >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, i: usize) {
+        if i + MIN_MATCH <= self.data.len() {
+            let hash = self.hash(i);
+            self.prev[i] = self.head[hash];
+            self.head[hash] = i as u32;
+        }
+    }
+
+    // The length and distance of the longest match for the data at `i`, which must not be
+    // inserted yet, if there's one.
+    fn longest(&self, i: usize) -> Option<(usize, usize)> {
+        let data = self.data;
+        let max = (data.len() - i).min(MAX_MATCH);
+        if max < MIN_MATCH {
+            return None;
+        }
+        let (mut best_len, mut best_distance) = (0, 0);
+        let mut candidate = self.head[self.hash(i)];
+        for _ in 0..self.level.chain {
+            if candidate == NO_POSITION {
+                break;
+            }
+            let c = candidate as usize;
+            let distance = i - c;
+            if distance > WINDOW_SIZE {
+                break;
+            }
+            // only worth comparing in full if it could be longer than the best so far
+            if data[c + best_len] == data[i + best_len] {
+                let len = data[c..c + max]
+                    .iter()
+                    .zip(&data[i..i + max])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if len > best_len {
+                    (best_len, best_distance) = (len, distance);
+                    if len >= self.level.nice || len == max {
+                        break;
+                    }
+                }
+            }
+            candidate = self.prev[c];
+        }
+        let too_far = best_len == MIN_MATCH && best_distance > FAR_SHORT_MATCH;
+        (best_len >= MIN_MATCH && !too_far).then_some((best_len, best_distance))
+    }
+}
+
+// The data as literals and matches.
+fn find_matches(data: &[u8], level: Level) -> Vec<Symbol> {
+    let mut matcher = Matcher {
+        data,
+        head: vec![NO_POSITION; 1 << HASH_BITS],
+        prev: vec![NO_POSITION; data.len()],
+        level,
+    };
+    let literal = |byte: u8| Symbol {
+        len: byte as u16,
+        distance: 0,
+    };
+    let mut symbols = Vec::new();
+    let mut i = 0;
+    while i < data.len() {
+        let found = matcher.longest(i);
+        matcher.insert(i);
+        let Some((len, distance)) = found else {
+            symbols.push(literal(data[i]));
+            i += 1;
+            continue;
+        };
+        // a longer match may start right after, which is worth a literal
+        if level.lazy && len < level.nice && i + 1 < data.len() {
+            if let Some((next_len, _)) = matcher.longest(i + 1) {
+                if next_len > len {
+                    symbols.push(literal(data[i]));
+                    i += 1;
+                    continue;
+                }
+            }
+        }
+        symbols.push(Symbol {
+            len: len as u16,
+            distance: distance as u16,
+        });
+        for j in i + 1..i + len {
+            matcher.insert(j);
+        }
+        i += len;
+    }
+    symbols
+}
+
+// Write the symbols, which stand for the raw data, as a block coded with codes of its own, or
+// stored as it is if that's shorter.
+fn write_block(bits: &mut BitWriter<'_>, symbols: &[Symbol], raw: &[u8], last: bool) {
+    let mut literal_freqs = [0u32; MAX_LITERAL_CODES];
+    let mut distance_freqs = [0u32; MAX_DISTANCE_CODES];
+    for symbol in symbols {
+        if symbol.distance == 0 {
+            literal_freqs[symbol.len as usize] += 1;
+        } else {
+            literal_freqs[257 + length_code(symbol.len)] += 1;
+            distance_freqs[distance_code(symbol.distance)] += 1;
+        }
+    }
+    literal_freqs[END_OF_BLOCK as usize] = 1;
+    let literal_lens = code_lengths(&literal_freqs, MAX_BITS);
+    let distance_lens = code_lengths(&distance_freqs, MAX_BITS);
+
+    // both sets of lengths are sent without the unused codes at the end, as one
+    let used = |lens: &[u8], min: usize| {
+        lens.iter()
+            .rposition(|&len| len != 0)
+            .map_or(0, |i| i + 1)
+            .max(min)
+    };
+    let literal_count = used(&literal_lens, 257);
+    let distance_count = used(&distance_lens, 1);
+    let runs = run_lengths(
+        &[
+            &literal_lens[..literal_count],
+            &distance_lens[..distance_count],
+        ]
+        .concat(),
+    );
+    let mut length_freqs = [0u32; LENGTH_CODES];
+    for &(symbol, _) in &runs {
+        length_freqs[symbol as usize] += 1;
+    }
+    let length_lens = code_lengths(&length_freqs, MAX_LENGTH_BITS);
+    let length_count = CODE_LENGTH_ORDER
+        .iter()
+        .rposition(|&i| length_lens[i] != 0)
+        .map_or(0, |i| i + 1)
+        .max(4);
+
+    let run_extra = |symbol: u8| match symbol {
+        16 => 2,
+        17 => 3,
+        18 => 7,
+        _ => 0,
+    };
+    let mut coded_len = 3 + 5 + 5 + 4 + 3 * length_count;
+    for &(symbol, _) in &runs {
+        coded_len += length_lens[symbol as usize] as usize + run_extra(symbol);
+    }
+    for symbol in symbols {
+        if symbol.distance == 0 {
+            coded_len += literal_lens[symbol.len as usize] as usize;
+        } else {
+            let (l, d) = (length_code(symbol.len), distance_code(symbol.distance));
+            coded_len += literal_lens[257 + l] as usize + LENGTH_EXTRA[l] as usize;
+            coded_len += distance_lens[d] as usize + DISTANCE_EXTRA[d] as usize;
+        }
+    }
+    coded_len += literal_lens[END_OF_BLOCK as usize] as usize;
+    // the header, the padding up to the next byte at worst, and the length and its complement
+    let stored_len = raw.len().div_ceil(MAX_STORED_LEN).max(1) * (3 + 7 + 32) + 8 * raw.len();
+    if stored_len <= coded_len {
+        write_stored(bits, raw, last);
+        return;
+    }
+
+    bits.put(last as u32, 1);
+    bits.put(2, 2);
+    bits.put((literal_count - 257) as u32, 5);
+    bits.put((distance_count - 1) as u32, 5);
+    bits.put((length_count - 4) as u32, 4);
+    for &i in &CODE_LENGTH_ORDER[..length_count] {
+        bits.put(length_lens[i] as u32, 3);
+    }
+    let length_codes = canonical_codes(&length_lens);
+    for &(symbol, repeat) in &runs {
+        let symbol = symbol as usize;
+        bits.code(length_codes[symbol], length_lens[symbol]);
+        bits.put(repeat as u32, run_extra(symbol as u8) as u8);
+    }
+
+    let literal_codes = canonical_codes(&literal_lens);
+    let distance_codes = canonical_codes(&distance_lens);
+    for symbol in symbols {
+        if symbol.distance == 0 {
+            let i = symbol.len as usize;
+            bits.code(literal_codes[i], literal_lens[i]);
+            continue;
+        }
+        let l = length_code(symbol.len);
+        bits.code(literal_codes[257 + l], literal_lens[257 + l]);
+        bits.put((symbol.len - LENGTH_BASE[l]) as u32, LENGTH_EXTRA[l]);
+        let d = distance_code(symbol.distance);
+        bits.code(distance_codes[d], distance_lens[d]);
+        bits.put(
+            (symbol.distance - DISTANCE_BASE[d]) as u32,
+            DISTANCE_EXTRA[d],
+        );
+    }
+    let end = END_OF_BLOCK as usize;
+    bits.code(literal_codes[end], literal_lens[end]);
+}
+
+fn write_stored(bits: &mut BitWriter<'_>, raw: &[u8], last: bool) {
+    let mut parts = raw.chunks(MAX_STORED_LEN).peekable();
+    // even nothing at all is a block
+    let mut part = parts.next().unwrap_or_default();
+    loop {
+        let final_part = parts.peek().is_none();
+        bits.put((last && final_part) as u32, 1);
+        bits.put(0, 2);
+        bits.flush();
+        let len = part.len() as u16;
+        bits.out.extend(&len.to_le_bytes());
+        bits.out.extend(&(!len).to_le_bytes());
+        bits.out.extend(part);
+        match parts.next() {
+            Some(next) => part = next,
+            None => break,
+        }
+    }
+}
+
+// The lengths of the codes as runs of the same length (literal lengths up to 15, and 16 to
+// repeat the previous one, or 17 and 18 for zeros), along with how many more times than the
+// least they repeat.
+fn run_lengths(lens: &[u8]) -> Vec<(u8, u8)> {
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < lens.len() {
+        let len = lens[i];
+        let run = lens[i..].iter().take_while(|&&l| l == len).count();
+        if len == 0 && run >= 3 {
+            let run = run.min(138);
+            if run >= 11 {
+                runs.push((18, (run - 11) as u8));
+            } else {
+                runs.push((17, (run - 3) as u8));
+            }
+            i += run;
+            continue;
+        }
+        runs.push((len, 0));
+        i += 1;
+        // the rest of the run repeats the one just sent
+        let mut left = run - 1;
+        while left >= 3 {
+            let repeat = left.min(6);
+            runs.push((16, (repeat - 3) as u8));
+            left -= repeat;
+            i += repeat;
+        }
+    }
+    runs
+}
+
+// The length of the Huffman code of every symbol from how often it's used, none longer than
+// `limit`. At least two symbols get a code, as decoders may not take a single one.
+fn code_lengths(freqs: &[u32], limit: usize) -> Vec<u8> {
+    let mut freqs = freqs.to_vec();
+    for i in 0..freqs.len() {
+        if freqs.iter().filter(|&&freq| freq > 0).count() >= 2 {
+            break;
+        }
+        if freqs[i] == 0 {
+            freqs[i] = 1;
+        }
+    }
+    loop {
+        let used = (0..freqs.len())
+            .filter(|&i| freqs[i] > 0)
+            .collect::<Vec<_>>();
+        // the tree is built bottom up by joining the two least used nodes, with the leaves
+        // first, each knowing its parent
+        let mut parents = vec![usize::MAX; used.len()];
+        let mut nodes = used
+            .iter()
+            .enumerate()
+            .map(|(node, &i)| Reverse((freqs[i] as u64, node)))
+            .collect::<BinaryHeap<_>>();
+        while let (Some(Reverse((a_freq, a))), Some(Reverse((b_freq, b)))) =
+            (nodes.pop(), nodes.pop())
+        {
+            let parent = parents.len();
+            parents.push(usize::MAX);
+            parents[a] = parent;
+            parents[b] = parent;
+            nodes.push(Reverse((a_freq + b_freq, parent)));
+        }
+
+        let mut lens = vec![0u8; freqs.len()];
+        for (node, &i) in used.iter().enumerate() {
+            let mut depth = 0;
+            let mut at = node;
+            while parents[at] != usize::MAX {
+                at = parents[at];
+                depth += 1;
+            }
+            lens[i] = depth;
+        }
+        if lens.iter().all(|&len| len as usize <= limit) {
+            return lens;
+        }
+        // flatter frequencies make for a shallower tree, until it fits
+        for freq in freqs.iter_mut().filter(|freq| **freq > 0) {
+            *freq = freq.div_ceil(2);
+        }
+    }
+}
+
+// The canonical codes for the lengths, as in RFC 1951, section 3.2.2.
+fn canonical_codes(lens: &[u8]) -> Vec<u16> {
+    let mut counts = [0u16; MAX_BITS + 1];
+    for &len in lens {
+        counts[len as usize] += 1;
+    }
+    counts[0] = 0;
+    let mut next = [0u16; MAX_BITS + 1];
+    let mut code = 0;
+    for bits in 1..=MAX_BITS {
+        code = (code + counts[bits - 1]) << 1;
+        next[bits] = code;
+    }
+    lens.iter()
+        .map(|&len| {
+            if len == 0 {
+                return 0;
+            }
+            let code = next[len as usize];
+            next[len as usize] += 1;
+            code
+        })
+        .collect()
+}
+
+fn length_code(len: u16) -> usize {
+    LENGTH_BASE.partition_point(|&base| base <= len) - 1
+}
+
+fn distance_code(distance: u16) -> usize {
+    DISTANCE_BASE.partition_point(|&base| base <= distance) - 1
+}
+
+// Writes values a few bits at a time, starting from the least significant one.
+struct BitWriter<'a> {
+    out: &'a mut Vec<u8>,
+    bits: u64,
+    count: u8,
+}
+
+impl BitWriter<'_> {
+    fn put(&mut self, value: u32, n: u8) {
+        self.bits |= (value as u64) << self.count;
+        self.count += n;
+        while self.count >= 8 {
+            self.out.push(self.bits as u8);
+            self.bits >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Codes are written starting from their most significant bit, unlike everything else.
+    fn code(&mut self, code: u16, len: u8) {
+        let reversed = code.reverse_bits() >> (16 - len as u32);
+        self.put(reversed as u32, len);
+    }
+
+    // Pad what's left up to the next byte.
+    fn flush(&mut self) {
+        if self.count > 0 {
+            self.out.push(self.bits as u8);
+            self.bits = 0;
+            self.count = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::inflate::Inflate;
+    use flate2::read::DeflateDecoder;
+    use flate2::write::DeflateEncoder;
+    use std::io::{Read, Write};
+
+    // Bytes that look random, always the same ones, from an alphabet of `symbols`.
+    fn noise(len: usize, symbols: u32) -> Vec<u8> {
+        let mut state = 0x2545_f491_u32;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state % symbols) as u8
+            })
+            .collect()
+    }
+
+    // Data with something for every part of the encoder: nothing to repeat, short and long
+    // repeats, repeats as far back as they can be, and more symbols than fit in one block.
+    fn samples() -> Vec<Vec<u8>> {
+        let far = noise(WINDOW_SIZE, 256);
+        let text = b"The quick brown fox jumps over the lazy dog, then sleeps. ".repeat(2000);
+        vec![
+            Vec::new(),
+            b"a".to_vec(),
+            b"hello".to_vec(),
+            vec![0; 300_000],
+            text,
+            noise(200_000, 256),
+            noise(300_000, 4),
+            [&far[..], &far[..], &far[..100], b"x", &far[..]].concat(),
+        ]
+    }
+
+    fn inflate(stream: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        Inflate::new(stream).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn streams_round_trip() {
+        for data in samples() {
+            for level in [Level::DEFAULT, Level::BEST] {
+                let mut stream = Vec::new();
+                compress(&data, level, &mut stream);
+                assert!(
+                    inflate(&stream) == data,
+                    "{} bytes at {:?}",
+                    data.len(),
+                    level
+                );
+                // and zlib reads them the same
+                let mut read = Vec::new();
+                DeflateDecoder::new(&stream[..])
+                    .read_to_end(&mut read)
+                    .unwrap();
+                assert!(read == data, "{} bytes at {:?}", data.len(), level);
+            }
+        }
+    }
+
+    #[test]
+    fn streams_by_zlib_are_read() {
+        for data in samples() {
+            for level in [0, 1, 6, 9] {
+                let mut encoder = DeflateEncoder::new(Vec::new(), flate2::Compression::new(level));
+                encoder.write_all(&data).unwrap();
+                let stream = encoder.finish().unwrap();
+                assert!(
+                    inflate(&stream) == data,
+                    "{} bytes at {}",
+                    data.len(),
+                    level
+                );
+            }
+        }
+    }
+
+    // Nothing is a single empty stored block, data with nothing to repeat is stored as it is
+    // with little more than the headers of the blocks, and data that repeats takes far less.
+    #[test]
+    fn streams_are_as_small_as_expected() {
+        let mut stream = Vec::new();
+        compress(&[], Level::DEFAULT, &mut stream);
+        assert_eq!(stream, [0x01, 0x00, 0x00, 0xff, 0xff]);
+
+        let data = noise(200_000, 256);
+        let mut stream = Vec::new();
+        compress(&data, Level::DEFAULT, &mut stream);
+        assert!(
+            stream.len() <= data.len() + data.len() / 1000,
+            "{}",
+            stream.len()
+        );
+
+        let mut stream = Vec::new();
+        compress(&[0; 300_000], Level::DEFAULT, &mut stream);
+        assert!(stream.len() < 1000, "{}", stream.len());
+    }
+}
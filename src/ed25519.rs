@@ -0,0 +1,180 @@
+//! Ed25519 signatures (RFC 8032), with which a sender proves it holds the secret key behind
+//! the public key a receiver knows it by. The signatures are made and checked by the
+//! ed25519-dalek crate.
+
+use ed25519_dalek::{Signature, Signer, VerifyingKey};
+
+pub const SEED_LEN: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+pub const PUBLIC_KEY_LEN: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+pub const SIGNATURE_LEN: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+/// The secret key that signs, from which its public key follows.
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    pub fn from_seed(seed: &[u8; SEED_LEN]) -> Self {
+        Self(ed25519_dalek::SigningKey::from_bytes(seed))
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.0.verifying_key().to_bytes()
+    }
+
+    pub fn sign(&self, message: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.0.sign(message).to_bytes()
+    }
+}
+
+/// Whether the signature of the message was made with the secret key of the public key.
+///
+/// Signatures are only valid in their one canonical form, and keys and points of small order
+/// are refused, so that a signature that was seen can't be turned into another valid one, nor
+/// a key be made up for which a signature is valid for many messages.
+pub fn verify(
+    public: &[u8; PUBLIC_KEY_LEN],
+    message: &[u8],
+    signature: &[u8; SIGNATURE_LEN],
+) -> bool {
+    let Ok(public) = VerifyingKey::from_bytes(public) else {
+        return false;
+    };
+    public
+        .verify_strict(message, &Signature::from_bytes(signature))
+        .is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::{from_hex, to_hex};
+    use std::convert::TryInto;
+
+    // The order of the base point, in little-endian.
+    const L: [u8; 32] = [
+        0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58, 0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde,
+        0x14, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+    ];
+
+    // Tests 1, 2, 3 and SHA(abc) of section 7.1 of RFC 8032: secret key, public key, message
+    // and signature.
+    const VECTORS: [[&str; 4]; 4] = [
+        [
+            "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60",
+            "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a",
+            "",
+            "e5564300c360ac729086e2cc806e828a84877f1eb8e5d974d873e065224901555fb8821590a33bacc61e39701cf9b46bd25bf5f0595bbe24655141438e7a100b",
+        ],
+        [
+            "4ccd089b28ff96da9db6c346ec114e0f5b8a319f35aba624da8cf6ed4fb8a6fb",
+            "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c",
+            "72",
+            "92a009a9f0d4cab8720e820b5f642540a2b27b5416503f8fb3762223ebdb69da085ac1e43e15996e458f3613d0f11d8c387b2eaeb4302aeeb00d291612bb0c00",
+        ],
+        [
+            "c5aa8df43f9f837bedb7442f31dcb7b166d38535076f094b85ce3a2e0b4458f7",
+            "fc51cd8e6218a1a38da47ed00230f0580816ed13ba3303ac5deb911548908025",
+            "af82",
+            "6291d657deec24024827e69c3abe01a30ce548a284743a445e3680d7db5ac3ac18ff9b538d16f290ae67f760984dc6594a7c15e9716ed28dc027beceea1ec40a",
+        ],
+        [
+            "833fe62409237b9d62ec77587520911e9a759cec1d19755b7da901b96dca3d42",
+            "ec172b93ad5e563bf4932c70e1245034c35467ef2efd4d64ebf819683467e2bf",
+            "ddaf35a193617abacc417349ae20413112e6fa4e89a97ea20a9eeee64b55d39a2192992a274fc1a836ba3c23a3feebbd454d4423643ce80e2a9ac94fa54ca49f",
+            "dc2a4459e7369633a52b1bf277839a00201009a3efbf3ecb69bea2186c26b58909351fc9ac90b3ecfdfbc7c66431e0303dca179c138ac17ad9bef1177331a704",
+        ],
+    ];
+
+    fn bytes<const N: usize>(hex: &str) -> [u8; N] {
+        from_hex(hex).unwrap().try_into().unwrap()
+    }
+
+    // A valid signature, with its key and message, to tamper with.
+    fn signed() -> ([u8; PUBLIC_KEY_LEN], &'static [u8], [u8; SIGNATURE_LEN]) {
+        let key = SigningKey::from_seed(&[7; SEED_LEN]);
+        let message = b"sf-identity";
+        (key.public_key(), message, key.sign(message))
+    }
+
+    // Add two numbers of 32 bytes in little-endian, ignoring what goes over.
+    fn add(a: &[u8], b: &[u8]) -> [u8; 32] {
+        let mut sum = [0; 32];
+        let mut carry = 0u16;
+        for i in 0..32 {
+            let digit = a[i] as u16 + b[i] as u16 + carry;
+            sum[i] = digit as u8;
+            carry = digit >> 8;
+        }
+        sum
+    }
+
+    #[test]
+    fn known_answers() {
+        for [secret, public, message, signature] in VECTORS {
+            let key = SigningKey::from_seed(&bytes(secret));
+            let message = from_hex(message).unwrap();
+            assert_eq!(to_hex(&key.public_key()), public);
+            assert_eq!(to_hex(&key.sign(&message)), signature);
+            assert!(verify(&bytes(public), &message, &bytes(signature)));
+        }
+    }
+
+    #[test]
+    fn other_messages_are_refused() {
+        let (public, message, signature) = signed();
+        assert!(verify(&public, message, &signature));
+        assert!(!verify(&public, b"sf-identitY", &signature));
+        let mut flipped = signature;
+        flipped[0] ^= 1;
+        assert!(!verify(&public, message, &flipped));
+        let other = SigningKey::from_seed(&[8; SEED_LEN]).public_key();
+        assert!(!verify(&other, message, &signature));
+    }
+
+    // s and s + L are the same modulo L, so both would pass the equation, but only the one
+    // below L is the signature.
+    #[test]
+    fn non_canonical_s_is_refused() {
+        let (public, message, signature) = signed();
+        let mut malleated = signature;
+        malleated[32..].copy_from_slice(&add(&signature[32..], &L));
+        assert!(!verify(&public, message, &malleated));
+    }
+
+    // The neutral point, encoded canonically as y = 1 and also as y = p + 1, along with the
+    // rest of the points of small order, would let a made up key or R verify any message.
+    #[test]
+    fn small_order_points_are_refused() {
+        let (public, message, signature) = signed();
+        let mut identity = [0; 32];
+        identity[0] = 1;
+        let mut p_plus_1 = [0xff; 32];
+        p_plus_1[0] = 0xee;
+        p_plus_1[31] = 0x7f;
+        // of order 2 (y = -1), and of order 4 (y = 0)
+        let mut minus_1 = p_plus_1;
+        minus_1[0] = 0xec;
+        let zero = [0; 32];
+        for point in [identity, p_plus_1, minus_1, zero] {
+            assert!(!verify(&point, message, &signature));
+            // with such a point as R and s = 0, the equation can hold whatever the message
+            let mut forged = [0; SIGNATURE_LEN];
+            forged[..32].copy_from_slice(&point);
+            assert!(!verify(&public, message, &forged));
+            assert!(!verify(&point, message, &forged));
+        }
+    }
+
+    // An x of zero has no sign, so encoding it with the sign bit set is not how the signer
+    // would have written the point, and must not stand for it.
+    #[test]
+    fn negative_zero_is_refused() {
+        let (public, message, signature) = signed();
+        let mut negative_zero = [0; 32];
+        negative_zero[0] = 1;
+        negative_zero[31] = 0x80;
+        assert!(!verify(&negative_zero, message, &signature));
+        let mut forged = [0; SIGNATURE_LEN];
+        forged[..32].copy_from_slice(&negative_zero);
+        assert!(!verify(&public, message, &forged));
+    }
+}
@@ -0,0 +1,200 @@
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+/// Everything that can make a transfer fail.
+#[derive(Debug)]
+pub enum Error {
+    /// The peer refused the connection, most likely because it's not running.
+    Refused {
+        addr: SocketAddr,
+    },
+    /// The connection with the peer failed, or it stopped responding.
+    Network {
+        peer: Option<SocketAddr>,
+        source: io::Error,
+    },
+    /// The receiver could not be found on the local network.
+    Discovery(io::Error),
+    /// The peer is not talking the protocol, or not the part of it that was expected.
+    Handshake(String),
+    /// The peer sent something that is not valid according to the protocol.
+    ProtocolViolation(String),
+    /// The peer uses a protocol version this one can't talk to.
+    VersionMismatch {
+        theirs: u8,
+        ours: u8,
+    },
+    /// Reading or writing a local file failed.
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The receiver gave up on the transfer, for the reason it gave.
+    Aborted(String),
+    /// The receiver turned the sender away, for being busy or not one it accepts.
+    Rejected(String),
+    /// Verification found files that differ in the receiver.
+    Differs,
+    /// The transfer went through, but these many of its files failed.
+    Incomplete {
+        failed: usize,
+    },
+    /// The transfer was stopped through its [`CancelToken`](crate::CancelToken).
+    Cancelled,
+    Other(String),
+}
+
+impl Error {
+    /// The code the process should exit with when its arguments are not valid, before there's
+    /// any transfer to fail. Like those of [`Error::exit_code`], it must not change.
+    pub const INVALID_ARGUMENTS_EXIT_CODE: i32 = 7;
+
+    /// The code the process should exit with after failing with this error. Scripts rely on
+    /// these, so they must not change.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io { .. } | Error::Other(_) => 1,
+            Error::Differs | Error::Incomplete { .. } => 2,
+            Error::Refused { .. } | Error::Network { .. } | Error::Discovery(_) => 3,
+            Error::Handshake(_) | Error::ProtocolViolation(_) | Error::VersionMismatch { .. } => 4,
+            Error::Aborted(_) | Error::Rejected(_) => 5,
+            Error::Cancelled => 6,
+        }
+    }
+
+    /// Blames the file at `path` if the error came from some I/O that didn't say where.
+    pub(crate) fn at(self, path: &Path) -> Self {
+        match self {
+            Error::Io { path: None, source } => Error::Io {
+                path: Some(path.to_path_buf()),
+                source,
+            },
+            e => e,
+        }
+    }
+
+    /// Blames the connection with `peer` if the error came from some I/O that didn't say where.
+    /// Only meant for the errors of transfers whose file operations already say where they
+    /// happened.
+    pub(crate) fn with_peer(self, peer: SocketAddr) -> Self {
+        match self {
+            Error::Io { path: None, source } if source.kind() == io::ErrorKind::InvalidData => {
+                Error::ProtocolViolation(source.to_string())
+            }
+            Error::Io { path: None, source } => Error::Network {
+                peer: Some(peer),
+                source,
+            },
+            Error::Network { peer: None, source } => Error::Network {
+                peer: Some(peer),
+                source,
+            },
+            e => e,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Refused { addr } => write!(
+                f,
+                "connection to {} refused, make sure the receiver is running and reachable",
+                addr
+            ),
+            Error::Network {
+                peer: Some(peer),
+                source,
+            } => write!(f, "connection with {} failed: {}", peer, source),
+            Error::Network { peer: None, source } => write!(f, "connection failed: {}", source),
+            Error::Discovery(source) => write!(
+                f,
+                "could not discover the receiver ({}), use its ip address instead",
+                source
+            ),
+            Error::Handshake(reason) => write!(f, "handshake failed: {}", reason),
+            Error::ProtocolViolation(reason) => write!(f, "protocol violation: {}", reason),
+            Error::VersionMismatch { theirs, ours } => write!(
+                f,
+                "incompatible protocol version {} (this side uses {}), upgrade the older side{}",
+                theirs,
+                ours,
+                // only the receiver checks, and a newer sender can still talk the older version
+                if theirs > ours && *ours >= crate::LEGACY_VERSION {
+                    " or send with --legacy"
+                } else {
+                    ""
+                }
+            ),
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{:?}: {}", path, source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Aborted(reason) => write!(f, "the receiver aborted the transfer: {}", reason),
+            Error::Rejected(reason) => write!(f, "turned the sender away: {}", reason),
+            Error::Differs => write!(f, "the receiver's files differ"),
+            Error::Incomplete { failed } => write!(f, "{} of the files failed", failed),
+            Error::Cancelled => write!(f, "the transfer was cancelled"),
+            Error::Other(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Network { source, .. } | Error::Discovery(source) | Error::Io { source, .. } => {
+                Some(source)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<walkdir::Error> for Error {
+    fn from(e: walkdir::Error) -> Self {
+        Error::Io {
+            path: e.path().map(Path::to_path_buf),
+            source: e.into(),
+        }
+    }
+}
+
+impl From<std::num::TryFromIntError> for Error {
+    fn from(_: std::num::TryFromIntError) -> Self {
+        Error::ProtocolViolation("number out of range".into())
+    }
+}
+
+impl From<std::str::Utf8Error> for Error {
+    fn from(_: std::str::Utf8Error) -> Self {
+        Error::ProtocolViolation("name is not valid UTF-8".into())
+    }
+}
+
+impl From<std::string::FromUtf8Error> for Error {
+    fn from(_: std::string::FromUtf8Error) -> Self {
+        Error::ProtocolViolation("name is not valid UTF-8".into())
+    }
+}
+
+impl From<String> for Error {
+    fn from(reason: String) -> Self {
+        Error::Other(reason)
+    }
+}
+
+impl From<&str> for Error {
+    fn from(reason: &str) -> Self {
+        Error::Other(reason.to_owned())
+    }
+}
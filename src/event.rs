@@ -0,0 +1,114 @@
+//! What happens during a transfer, so that frontends can show it however they like.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::time::Duration;
+
+/// Something that happened during a transfer or a verification.
+///
+/// Files are identified by their `index` in the list of files being transferred.
+#[derive(Clone, Debug)]
+pub enum TransferEvent {
+    /// The receiver is waiting for a sender to connect to this address.
+    Listening { addr: SocketAddr },
+    /// The file is being served, and can be downloaded from the `url`.
+    Shared {
+        index: usize,
+        path: PathBuf,
+        url: String,
+    },
+    /// The connection with the peer was established, or established again after being lost.
+    Connected { peer: SocketAddr },
+    /// The receiver stores the files of this transfer in their own directory, inside the
+    /// output directory.
+    Storing { output: PathBuf },
+    /// The receiver took this long to answer the first list of files, which is about as long
+    /// as a round trip over the network. Only measured by the sender, if not updating.
+    RoundTrip { rtt: Duration },
+    /// The file started being transferred. The `path` is where it's read from when sending,
+    /// and where it's stored when receiving.
+    FileStarted {
+        index: usize,
+        path: PathBuf,
+        len: u64,
+    },
+    /// The file was not transferred because the receiver already has it.
+    FileSkipped { index: usize, path: PathBuf },
+    /// This many `bytes` of the file have been transferred so far.
+    Progress { index: usize, bytes: u64 },
+    /// The file was transferred completely.
+    FileDone { index: usize },
+    /// The file changed while it was being sent, so the receiver got the length it was told
+    /// about, but maybe some of the old data and some of the new. Comes before it's done.
+    FileChanged { index: usize, path: PathBuf },
+    /// The receiver has stored this many `bytes` of file data so far in the transfer, as it
+    /// says every second to the senders that ask.
+    Stored { bytes: u64 },
+    /// This many `raw` bytes of file data went over the network compressed into `sent`. The
+    /// receiver says so after the data of every file sent in chunks, and the sender once done.
+    Compressed { raw: u64, sent: u64 },
+    /// The receiver stopped reading until it's resumed.
+    Paused,
+    /// The receiver went on reading after being paused.
+    Resumed,
+    /// The sender could not read the file, for the `reason` it gave, so it was skipped. Files
+    /// it found it couldn't read before listing them have no index.
+    Unreadable {
+        index: Option<usize>,
+        path: PathBuf,
+        reason: String,
+    },
+    /// Verification found that the file is missing or differs in the receiver.
+    Corrupt { path: PathBuf },
+    /// Everything was transferred or verified.
+    Finished,
+    /// The transfer failed for this `reason`, and the receiver goes on to wait for the next
+    /// sender, as it keeps receiving. Otherwise the failure is what the transfer returns.
+    Failed { reason: String },
+}
+
+/// Gets told about every event of a transfer, as soon as it happens.
+pub type EventHandler = Box<dyn Fn(TransferEvent) + Send + Sync>;
+
+/// Creates a handler which sends the events over a channel, to be received elsewhere.
+pub fn channel() -> (EventHandler, Receiver<TransferEvent>) {
+    let (tx, rx) = mpsc::channel();
+    let handler = Box::new(move |event| {
+        // nobody listening anymore is not a reason to stop the transfer
+        let _ = tx.send(event);
+    });
+    (handler, rx)
+}
+
+// Hand the event over to the handler, if there is one.
+pub(crate) fn emit(handler: &Option<EventHandler>, event: TransferEvent) {
+    if let Some(handler) = handler {
+        handler(event);
+    }
+}
+
+// Shorthands for the events of every file, which only copy the path if anyone is listening.
+
+pub(crate) fn emit_started(handler: &Option<EventHandler>, index: usize, path: &Path, len: u64) {
+    if let Some(handler) = handler {
+        handler(TransferEvent::FileStarted {
+            index,
+            path: path.to_path_buf(),
+            len,
+        });
+    }
+}
+
+pub(crate) fn emit_skipped(handler: &Option<EventHandler>, index: usize, path: &Path) {
+    if let Some(handler) = handler {
+        handler(TransferEvent::FileSkipped {
+            index,
+            path: path.to_path_buf(),
+        });
+    }
+}
+
+pub(crate) fn emit_progress(handler: &Option<EventHandler>, index: usize, bytes: u64) {
+    emit(handler, TransferEvent::Progress { index, bytes });
+}
@@ -0,0 +1,831 @@
+//! Unpacking the archives a receiver is sent into its output directory as they arrive, so
+//! that they never need to be stored whole: tar archives, plain or compressed with gzip or
+//! zstd, and zip files. Members are named like any file sent, so they can't end up outside of it,
+//! and are held to the same filters. What they unpack to is capped, so that a small archive
+//! can't fill the disk.
+
+use crate::inflate::Inflate;
+use crate::sink::{Directory, Sink, SinkWriter};
+use crate::zip::{self, Crc32};
+use crate::{names, tar, Error, FileFilter, Result};
+use std::convert::TryInto;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+const BUFFER_COUNT: usize = 4; // chunks received but not yet unpacked, at most
+const MAX_PAX_LEN: u64 = 1024 * 1024; // longest extended header accepted
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const GZIP_HEADER_CRC: u8 = 0x02; // header flags
+const GZIP_EXTRA: u8 = 0x04;
+const GZIP_NAME: u8 = 0x08;
+const GZIP_COMMENT: u8 = 0x10;
+
+/// The kinds of archive that can be extracted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl Format {
+    /// The kind of archive a file is, by the extension of its `name`, if it's one.
+    pub fn of(name: &[u8]) -> Option<Self> {
+        let name = name.to_ascii_lowercase();
+        if name.ends_with(b".tar.gz") || name.ends_with(b".tgz") {
+            Some(Format::TarGz)
+        } else if name.ends_with(b".tar.zst") {
+            Some(Format::TarZst)
+        } else if name.ends_with(b".tar") {
+            Some(Format::Tar)
+        } else if name.ends_with(b".zip") {
+            Some(Format::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// What the members of an archive are held to.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Limits {
+    /// The members it rejects are skipped, as the files sent would be.
+    pub filter: FileFilter,
+    /// The most bytes all of the members may unpack to, past which extracting fails.
+    pub max_len: Option<u64>,
+}
+
+/// What was extracted out of an archive.
+#[derive(Debug, Default)]
+pub(crate) struct Extracted {
+    pub paths: Vec<PathBuf>,
+    /// How many bytes the files stored take.
+    pub len: u64,
+}
+
+/// An archive being extracted while its data is still being received, unpacked in a thread
+/// of its own, which stores its members under a directory.
+pub(crate) struct Extraction {
+    writer: ChannelWriter,
+    unpacker: thread::JoinHandle<(Directory, Result<Extracted>)>,
+}
+
+impl Extraction {
+    /// Starts extracting an archive of the given `format` into `dir`, storing its members
+    /// through `directory`, which is given back once it's finished, within the `limits`.
+    pub(crate) fn start(
+        format: Format,
+        dir: PathBuf,
+        mut directory: Directory,
+        limits: Limits,
+    ) -> Self {
+        let (tx, rx) = mpsc::sync_channel::<Vec<u8>>(BUFFER_COUNT);
+        let unpacker = thread::spawn(move || {
+            let mut members = Members {
+                dir: &dir,
+                directory: &mut directory,
+                limits,
+                unpacked: 0,
+                extracted: Extracted::default(),
+            };
+            let mut input = BufReader::new(Channel {
+                rx,
+                chunk: Vec::new(),
+                pos: 0,
+            });
+            let unpacked = (|| {
+                match format {
+                    Format::Tar => unpack_tar(&mut input, &mut members)?,
+                    Format::TarGz => unpack_tar(&mut Gzip::new(&mut input)?, &mut members)?,
+                    Format::TarZst => {
+                        let mut zstd = zstd::Decoder::with_buffer(&mut input)?.single_frame();
+                        unpack_tar(&mut zstd, &mut members)?
+                    }
+                    Format::Zip => unpack_zip(&mut input, &mut members)?,
+                };
+                // whatever is left, such as the padding after a tar archive, must still be taken
+                io::copy(&mut input, &mut io::sink())?;
+                Ok(())
+            })();
+            let extracted = members.extracted;
+            (directory, unpacked.map(|()| extracted))
+        });
+        Self {
+            writer: ChannelWriter { tx, closed: false },
+            unpacker,
+        }
+    }
+
+    /// Waits for the archive to be unpacked, after all of its data was `received`, and
+    /// returns the directory back along with the files extracted.
+    pub(crate) fn finish(self, received: Result<()>) -> (Directory, Result<Extracted>) {
+        // the unpacker only stops taking data before the end once it fails, which is then
+        // the reason receiving failed, while otherwise it fails for running out of data
+        let closed = self.writer.closed;
+        drop(self.writer);
+        let (directory, unpacked) = self.unpacker.join().unwrap();
+        if closed {
+            (directory, unpacked)
+        } else {
+            (directory, received.and(unpacked))
+        }
+    }
+}
+
+// The data of the archive is written into it as it's received.
+impl Write for Extraction {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+// Where the members of the archive go.
+struct Members<'a> {
+    dir: &'a Path,
+    directory: &'a mut Directory,
+    limits: Limits,
+    // everything the members unpacked to so far, even those skipped
+    unpacked: u64,
+    extracted: Extracted,
+}
+
+impl Members<'_> {
+    // Where the member with the given name goes, unless it has no name or it would go up
+    // and out of the directory, which the names of the files sent can't either.
+    fn path(&self, name: &[u8]) -> Option<PathBuf> {
+        names::relative_path(name)
+            .filter(|path| !path.as_os_str().is_empty())
+            .map(|path| self.dir.join(path))
+    }
+
+    // Store the member with the given name, and `len` if the archive says it up front, with
+    // all the data `write` puts in it.
+    fn store(
+        &mut self,
+        name: &[u8],
+        len: Option<u64>,
+        modified: Option<u64>,
+        write: impl FnOnce(&mut dyn Write) -> Result<()>,
+    ) -> Result<()> {
+        let max_len = self.limits.max_len;
+        let path = match self.path(name) {
+            Some(path) => path,
+            None => {
+                self.skip(name, "its name points outside of the output directory");
+                return write(&mut Limited::new(
+                    &mut io::sink(),
+                    &mut self.unpacked,
+                    max_len,
+                ));
+            }
+        };
+        if let Some(why) = self.limits.filter.rejects(name, len.unwrap_or(0)) {
+            self.skip(name, &why);
+            return write(&mut Limited::new(
+                &mut io::sink(),
+                &mut self.unpacked,
+                max_len,
+            ));
+        }
+        let path = self.directory.stored_path(path);
+        debug!("extracting {:?}", path);
+        self.directory
+            .open_path(&path, modified)
+            .map_err(|e| Error::from(e).at(&path))?;
+        let mut f = SinkWriter(&mut *self.directory);
+        let mut limited = Limited::new(&mut f, &mut self.unpacked, max_len);
+        // those that say they're smaller than they are can't get past the filter either
+        limited.max_member_len = self.limits.filter.reject_larger;
+        let written = write(&mut limited);
+        let (len, too_large) = (limited.len, limited.too_large);
+        if let Err(e) = written {
+            self.directory.abort_entry();
+            return Err(e.at(&path));
+        }
+        if too_large {
+            self.directory.abort_entry();
+            let max = self.limits.filter.reject_larger.unwrap_or(0);
+            self.skip(name, &format!("it's larger than {} bytes", max));
+            return Ok(());
+        }
+        self.directory
+            .close_entry()
+            .map_err(|e| Error::from(e).at(&path))?;
+        self.extracted.paths.push(path);
+        self.extracted.len += len;
+        Ok(())
+    }
+
+    fn create_dir(&mut self, name: &[u8]) -> Result<()> {
+        if let Some(path) = self.path(name) {
+            self.directory
+                .create_dir(&path)
+                .map_err(|e| Error::from(e).at(&path))?;
+        }
+        Ok(())
+    }
+
+    fn skip(&self, name: &[u8], why: &str) {
+        out!("not extracting {:?}, {}", names::display(name), why);
+    }
+}
+
+fn unpack_tar(input: &mut impl Read, members: &mut Members<'_>) -> Result<()> {
+    // what the extended headers say about the entry after them
+    let mut long_name = None;
+    let mut long_len = None;
+    let mut block = [0u8; tar::BLOCK_SIZE];
+    loop {
+        // some archives end without the blocks of zeros, and that's fine too
+        if !read_block(input, &mut block)? {
+            return Ok(());
+        }
+        let header = match tar::parse_header(&block)? {
+            Some(header) => header,
+            // the rest is padding, but it must be read for gzip to check what it decompressed
+            None => {
+                io::copy(input, &mut io::sink())?;
+                return Ok(());
+            }
+        };
+        let len = match header.kind {
+            kind if header.is_file() || kind == tar::KIND_DIRECTORY => {
+                long_len.take().unwrap_or(header.len)
+            }
+            _ => header.len,
+        };
+
+        let mut data = input.by_ref().take(len);
+        match header.kind {
+            tar::KIND_PAX | tar::KIND_GNU_LONG_NAME => {
+                if len > MAX_PAX_LEN {
+                    return Err(invalid("an extended header of the tar archive is too long"));
+                }
+                let mut value = Vec::new();
+                data.read_to_end(&mut value)?;
+                if header.kind == tar::KIND_GNU_LONG_NAME {
+                    long_name = Some(tar::until_nul(&value).to_vec());
+                } else {
+                    if let Some(name) = tar::pax_value(&value, b"path") {
+                        long_name = Some(name.to_vec());
+                    }
+                    long_len = tar::pax_value(&value, b"size")
+                        .and_then(|size| std::str::from_utf8(size).ok()?.parse().ok());
+                }
+            }
+            _ if header.is_file() => {
+                let name = long_name.take().unwrap_or(header.name);
+                let (mtime, write) = (Some(header.mtime), |f: &mut dyn Write| {
+                    copy_exact(&mut data, f, len)
+                });
+                members.store(&name, Some(len), mtime, write)?;
+            }
+            tar::KIND_DIRECTORY => {
+                let name = long_name.take().unwrap_or(header.name);
+                members.create_dir(&name)?;
+            }
+            tar::KIND_PAX_GLOBAL => {}
+            _ => {
+                let name = long_name.take().unwrap_or(header.name);
+                members.skip(&name, "it's not a regular file");
+            }
+        }
+        io::copy(&mut data, &mut io::sink())?;
+        let mut padding = [0; tar::BLOCK_SIZE];
+        input.read_exact(&mut padding[..tar::padding(len)])?;
+    }
+}
+
+// Read a whole block, or nothing if the input is over.
+fn read_block(input: &mut impl Read, block: &mut [u8; tar::BLOCK_SIZE]) -> io::Result<bool> {
+    let mut filled = 0;
+    while filled < block.len() {
+        match input.read(&mut block[filled..]) {
+            Ok(0) if filled == 0 => return Ok(false),
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(true)
+}
+
+// Copy exactly `len` bytes, failing if the input ends before.
+fn copy_exact(input: &mut impl Read, output: &mut dyn Write, len: u64) -> Result<()> {
+    if io::copy(&mut input.take(len), output)? != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(())
+}
+
+fn unpack_zip(input: &mut impl BufRead, members: &mut Members<'_>) -> Result<()> {
+    loop {
+        match read_u32(input)? {
+            zip::LOCAL_HEADER => {}
+            // the central directory repeats what the local headers said, after all of them
+            zip::CENTRAL_HEADER | zip::END => return Ok(()),
+            _ => return Err(invalid("not a zip archive, or a corrupt one")),
+        }
+        let mut header = [0; 26];
+        input.read_exact(&mut header)?;
+        let u16_at = |i: usize| u16::from_le_bytes([header[i], header[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(header[i..i + 4].try_into().unwrap());
+        let (flags, method) = (u16_at(2), u16_at(4));
+        let (time, date) = (u16_at(6), u16_at(8));
+        let mut crc = u32_at(10);
+        let mut compressed_len = u32_at(14) as u64;
+        let mut len = u32_at(18) as u64;
+        let mut name = vec![0; u16_at(22) as usize];
+        let mut extra = vec![0; u16_at(24) as usize];
+        input.read_exact(&mut name)?;
+        input.read_exact(&mut extra)?;
+
+        let mut modified = dos_time(date, time);
+        let mut zip64 = false;
+        for (id, field) in extra_fields(&extra) {
+            match id {
+                // only the sizes that didn't fit are there, uncompressed first
+                zip::ZIP64_EXTRA => {
+                    zip64 = true;
+                    let mut sizes = field
+                        .chunks_exact(8)
+                        .map(|size| u64::from_le_bytes(size.try_into().unwrap()));
+                    if len == u32::MAX as u64 {
+                        len = sizes.next().unwrap_or(len);
+                    }
+                    if compressed_len == u32::MAX as u64 {
+                        compressed_len = sizes.next().unwrap_or(compressed_len);
+                    }
+                }
+                zip::TIMESTAMP_EXTRA if field.first().is_some_and(|flags| flags & 1 != 0) => {
+                    if let Some(secs) = field.get(1..5) {
+                        modified = Some(u32::from_le_bytes(secs.try_into().unwrap()) as u64);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let descriptor = flags & zip::HAS_DESCRIPTOR != 0;
+        let is_dir = name.last() == Some(&b'/');
+        if flags & zip::ENCRYPTED != 0 || !matches!(method, zip::STORED | zip::DEFLATED) {
+            if descriptor {
+                // there's no telling where its data ends, so nothing after it can be found
+                return Err(invalid(
+                    "an entry of the zip archive is encrypted or compressed in an unknown way",
+                ));
+            }
+            members.skip(&name, "it's encrypted or compressed in an unknown way");
+            io::copy(&mut input.by_ref().take(compressed_len), &mut io::sink())?;
+            continue;
+        }
+        if method == zip::STORED && descriptor {
+            return Err(invalid(
+                "an entry of the zip archive is stored without saying how long it is",
+            ));
+        }
+
+        let mut digest = Crc32::new();
+        let mut write = |f: &mut dyn Write| -> Result<()> {
+            let mut f = CrcWriter(f, &mut digest);
+            match method {
+                zip::STORED => copy_exact(input, &mut f, compressed_len),
+                _ => {
+                    io::copy(&mut Inflate::new(&mut *input), &mut f)?;
+                    Ok(())
+                }
+            }
+        };
+        if is_dir {
+            write(&mut io::sink())?;
+            members.create_dir(&name)?;
+        } else {
+            // the sizes are only said after the data when there's a descriptor
+            members.store(&name, Some(len).filter(|_| !descriptor), modified, write)?;
+        }
+
+        if descriptor {
+            // the signature of the descriptor is optional, and the checksum may be in its place
+            let first = read_u32(input)?;
+            crc = if first == zip::DESCRIPTOR {
+                read_u32(input)?
+            } else {
+                first
+            };
+            let mut sizes = [0; 16];
+            input.read_exact(&mut sizes[..if zip64 { 16 } else { 8 }])?;
+        }
+        if digest.finish() != crc {
+            return Err(invalid(&format!(
+                "the data of {:?} in the zip archive is corrupt",
+                names::display(&name)
+            )));
+        }
+    }
+}
+
+// The identifiers and data of the extra fields of a zip entry.
+fn extra_fields(mut extra: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let id = u16::from_le_bytes(extra.get(..2)?.try_into().unwrap());
+        let len = u16::from_le_bytes(extra.get(2..4)?.try_into().unwrap()) as usize;
+        let field = extra.get(4..4 + len)?;
+        extra = &extra[4 + len..];
+        Some((id, field))
+    })
+}
+
+// The seconds since the epoch of an MS-DOS date and time, which zip files use.
+fn dos_time(date: u16, time: u16) -> Option<u64> {
+    let (year, month, day) = (
+        1980 + (date >> 9) as i64,
+        (date >> 5 & 15) as i64,
+        (date & 31) as i64,
+    );
+    if !(1..=12).contains(&month) || day == 0 {
+        return None;
+    }
+    // from the civil date to the days since the epoch, as in Howard Hinnant's algorithm
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let doy = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+    let secs = (time >> 11) as i64 * 3600 + (time >> 5 & 63) as i64 * 60 + (time & 31) as i64 * 2;
+    (days * 86400 + secs).try_into().ok()
+}
+
+fn read_u32(input: &mut impl Read) -> io::Result<u32> {
+    let mut buffer = [0; 4];
+    input.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+fn invalid(reason: &str) -> Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string()).into()
+}
+
+/// The data of every member of a gzip file, which is often just one.
+pub(crate) struct Gzip<R> {
+    inflate: Inflate<R>,
+    crc: Crc32,
+    len: u32,
+    done: bool,
+}
+
+impl<R: BufRead> Gzip<R> {
+    /// Reads the header of the first member, and decompresses the rest as it's read.
+    pub fn new(mut input: R) -> io::Result<Self> {
+        read_gzip_header(&mut input)?;
+        Ok(Self {
+            inflate: Inflate::new(input),
+            crc: Crc32::new(),
+            len: 0,
+            done: false,
+        })
+    }
+}
+
+impl<R: BufRead> Read for Gzip<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while !self.done {
+            let n = self.inflate.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                self.crc.update(&buf[..n]);
+                self.len = self.len.wrapping_add(n as u32);
+                return Ok(n);
+            }
+
+            let input = self.inflate.get_mut();
+            let crc = read_u32(input)?;
+            let len = read_u32(input)?;
+            let digest = std::mem::replace(&mut self.crc, Crc32::new()).finish();
+            if crc != digest || len != self.len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the gzip data is corrupt",
+                ));
+            }
+            // files compressed on their own can be put together, and are then one after another
+            if input.fill_buf()?.is_empty() {
+                self.done = true;
+            } else {
+                read_gzip_header(input)?;
+                self.inflate.reset();
+                self.len = 0;
+            }
+        }
+        Ok(0)
+    }
+}
+
+fn read_gzip_header(input: &mut impl BufRead) -> io::Result<()> {
+    let mut header = [0; 10];
+    input.read_exact(&mut header)?;
+    if header[..2] != GZIP_MAGIC || header[2] != zip::DEFLATED as u8 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not gzip data, or compressed in an unknown way",
+        ));
+    }
+    let flags = header[3];
+    if flags & GZIP_EXTRA != 0 {
+        let mut len = [0; 2];
+        input.read_exact(&mut len)?;
+        let len = u16::from_le_bytes(len) as u64;
+        io::copy(&mut input.by_ref().take(len), &mut io::sink())?;
+    }
+    for flag in [GZIP_NAME, GZIP_COMMENT] {
+        if flags & flag != 0 {
+            input.read_until(0, &mut Vec::new())?;
+        }
+    }
+    if flags & GZIP_HEADER_CRC != 0 {
+        input.read_exact(&mut [0; 2])?;
+    }
+    Ok(())
+}
+
+// Checks what is written to the inner writer.
+// Counts what a member unpacks to, failing once all of them go over the most they may, and
+// dropping the rest of the member once it's larger than it may be by itself.
+struct Limited<'a> {
+    inner: &'a mut dyn Write,
+    unpacked: &'a mut u64,
+    max_len: Option<u64>,
+    // how long the member is so far
+    len: u64,
+    max_member_len: Option<u64>,
+    too_large: bool,
+}
+
+impl<'a> Limited<'a> {
+    fn new(inner: &'a mut dyn Write, unpacked: &'a mut u64, max_len: Option<u64>) -> Self {
+        Limited {
+            inner,
+            unpacked,
+            max_len,
+            len: 0,
+            max_member_len: None,
+            too_large: false,
+        }
+    }
+}
+
+impl Write for Limited<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        *self.unpacked = self.unpacked.saturating_add(buf.len() as u64);
+        if let Some(max) = self.max_len.filter(|max| *self.unpacked > *max) {
+            return Err(io::Error::other(format!(
+                "refusing to extract the archive, it unpacks to more than the {} bytes it may",
+                max
+            )));
+        }
+        self.len += buf.len() as u64;
+        self.too_large |= self.max_member_len.is_some_and(|max| self.len > max);
+        if !self.too_large {
+            self.inner.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+struct CrcWriter<'a, W: ?Sized>(&'a mut W, &'a mut Crc32);
+
+impl<W: Write + ?Sized> Write for CrcWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.0.write(buf)?;
+        self.1.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+// The data received, handed to the thread unpacking it in the chunks it arrived in.
+struct Channel {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for Channel {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            // the receiver is done once it drops its end
+            match self.rx.recv() {
+                Ok(chunk) => (self.chunk, self.pos) = (chunk, 0),
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+struct ChannelWriter {
+    tx: mpsc::SyncSender<Vec<u8>>,
+    // whether the unpacker stopped taking data
+    closed: bool,
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.tx.send(buf.to_vec()).is_err() {
+            self.closed = true;
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "the archive stopped being extracted",
+            ));
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    // A directory of its own for every test, emptied before it runs.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("sf-extract-test-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // A tar archive of the members, with their names as they are.
+    fn tar(members: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        for (name, data) in members {
+            archive.extend(tar::header(name, data.len() as u64, 1_600_000_000));
+            archive.extend(*data);
+            archive.extend(vec![0; tar::padding(data.len() as u64)]);
+        }
+        archive.extend([0; 2 * tar::BLOCK_SIZE]);
+        archive
+    }
+
+    // A zip archive of the stored members, with their names as they are, and the length each
+    // says it unpacks to.
+    fn zip(members: &[(&[u8], &[u8], u32)]) -> Vec<u8> {
+        let (mut archive, mut entries) = (Vec::new(), Vec::new());
+        for (name, data, said_len) in members {
+            let mut crc = Crc32::new();
+            crc.update(data);
+            let entry = zip::Entry {
+                name: name.to_vec(),
+                len: data.len() as u64,
+                mtime: 1_600_000_000,
+                crc: crc.finish(),
+                offset: archive.len() as u64,
+            };
+            let mut header = zip::local_header(&entry);
+            header[22..26].copy_from_slice(&said_len.to_le_bytes());
+            archive.extend(header);
+            archive.extend(*data);
+            entries.push(entry);
+        }
+        let offset = archive.len() as u64;
+        for entry in &entries {
+            archive.extend(zip::central_header(entry));
+        }
+        let len = archive.len() as u64 - offset;
+        archive.extend(zip::end(entries.len(), offset, len));
+        archive
+    }
+
+    fn extract(format: Format, archive: &[u8], output: &Path, limits: Limits) -> Result<Extracted> {
+        let directory = Directory::new(output);
+        let mut extraction = Extraction::start(format, output.to_path_buf(), directory, limits);
+        for chunk in archive.chunks(1000) {
+            if extraction.write_all(chunk).is_err() {
+                break;
+            }
+        }
+        extraction.finish(Ok(())).1
+    }
+
+    // Names that go up are skipped, and absolute ones are taken as relative to the output,
+    // like those of the files sent.
+    #[test]
+    fn members_stay_in_the_output() {
+        let dir = scratch("escape");
+        let (output, outside) = (dir.join("out").join("nested"), dir.join("outside"));
+        let absolute = outside.join("absolute");
+        let absolute = absolute.to_str().unwrap().as_bytes();
+        let members: [(&[u8], &[u8]); 4] = [
+            (b"../escaped", b"up"),
+            (b"kept/../../escaped", b"up and back"),
+            (absolute, b"absolute"),
+            (b"kept/file", b"kept"),
+        ];
+        let archives = [
+            (Format::Tar, tar(&members)),
+            (
+                Format::Zip,
+                zip(&members.map(|(name, data)| (name, data, data.len() as u32))),
+            ),
+        ];
+        for (format, archive) in archives {
+            let extracted = extract(format, &archive, &output, Limits::default()).unwrap();
+            assert_eq!(extracted.paths.len(), 2, "{:?}", format);
+            assert_eq!(fs::read(output.join("kept/file")).unwrap(), b"kept");
+            let relative = names::relative_path(absolute).unwrap();
+            assert_eq!(fs::read(output.join(relative)).unwrap(), b"absolute");
+            assert!(!dir.join("out").join("escaped").exists());
+            assert!(!dir.join("escaped").exists());
+            assert!(!outside.exists());
+            fs::remove_dir_all(&output).unwrap();
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A small archive that unpacks to a lot is refused past the limit, and members the filter
+    // rejects are skipped, even if they say they're smaller than they are.
+    #[test]
+    fn oversized_members_are_not_stored() {
+        let dir = scratch("oversized");
+        let output = dir.join("out");
+        let large = vec![0; 8 * 1024 * 1024];
+        let archive = tar(&[(b"small", b"small"), (b"large", &large)]);
+        let compressed = zstd::encode_all(&archive[..], 3).unwrap();
+        assert!(compressed.len() < 64 * 1024);
+
+        let limits = Limits {
+            max_len: Some(1024 * 1024),
+            ..Limits::default()
+        };
+        let e = extract(Format::TarZst, &compressed, &output, limits).unwrap_err();
+        assert!(e.to_string().contains("unpacks to more than"), "{}", e);
+        assert!(!output.join("large").exists());
+
+        let limits = Limits {
+            filter: FileFilter {
+                reject_larger: Some(1024),
+                ..FileFilter::default()
+            },
+            max_len: Some(16 * 1024 * 1024),
+        };
+        let extracted = extract(Format::TarZst, &compressed, &output, limits.clone()).unwrap();
+        assert_eq!(extracted.paths, [output.join("small")]);
+        assert_eq!(extracted.len, 5);
+        assert!(!output.join("large").exists());
+
+        let lying = zip(&[(b"lying", &large[..4096], 16), (b"honest", b"honest", 6)]);
+        let extracted = extract(Format::Zip, &lying, &output, limits).unwrap();
+        assert_eq!(extracted.paths, [output.join("honest")]);
+        assert!(!output.join("lying").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Links in archives are never made, so the members after them can't be stored through
+    // them either.
+    #[test]
+    fn links_in_archives_are_not_made() {
+        let dir = scratch("links");
+        let (output, outside) = (dir.join("out"), dir.join("outside"));
+        fs::create_dir_all(&outside).unwrap();
+        let target = outside.to_str().unwrap().as_bytes();
+        let mut link = tar::header(b"escape", 0, 1_600_000_000);
+        link[156] = b'2';
+        link[157..157 + target.len()].copy_from_slice(target);
+        link[148..156].copy_from_slice(b"        ");
+        let checksum: u32 = link.iter().map(|&b| b as u32).sum();
+        link[148..155].copy_from_slice(format!("{:06o}\0", checksum).as_bytes());
+        let mut archive = link;
+        archive.extend(tar(&[(b"escape/file", b"inside")]));
+
+        let extracted = extract(Format::Tar, &archive, &output, Limits::default()).unwrap();
+        assert_eq!(extracted.paths, [output.join("escape/file")]);
+        assert!(!output.join("escape").is_symlink());
+        assert_eq!(fs::read(output.join("escape/file")).unwrap(), b"inside");
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
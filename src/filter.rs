@@ -0,0 +1,50 @@
+//! Which of the files listed by the sender the receiver accepts, by their extension and size,
+//! so that the data of the rest is never sent.
+
+/// The files a receiver accepts. Those it rejects are skipped, and the sender is told why.
+#[derive(Clone, Debug, Default)]
+pub struct FileFilter {
+    /// Only accept the files with one of these extensions (without the dot, and in any case),
+    /// or any file if empty.
+    pub accept_ext: Vec<String>,
+    /// Reject the files with any of these extensions.
+    pub reject_ext: Vec<String>,
+    /// Reject the files larger than this many bytes.
+    pub reject_larger: Option<u64>,
+}
+
+impl FileFilter {
+    /// Whether every file is accepted.
+    pub fn is_empty(&self) -> bool {
+        self.accept_ext.is_empty() && self.reject_ext.is_empty() && self.reject_larger.is_none()
+    }
+
+    /// Why the file with the name (as sent) and length is rejected, if it is.
+    pub(crate) fn rejects(&self, name: &[u8], len: u64) -> Option<String> {
+        let ext = extension(name);
+        let listed = |exts: &[String]| {
+            ext.is_some_and(|ext| exts.iter().any(|e| e.as_bytes().eq_ignore_ascii_case(ext)))
+        };
+        if !self.accept_ext.is_empty() && !listed(&self.accept_ext) {
+            return Some("its extension is not accepted".to_owned());
+        }
+        if listed(&self.reject_ext) {
+            return Some("its extension is rejected".to_owned());
+        }
+        match self.reject_larger {
+            Some(max) if len > max => Some(format!("it's larger than {} bytes", max)),
+            _ => None,
+        }
+    }
+}
+
+// The part of the last component of the name after its last dot, unless that's the first
+// character, as in `.bashrc`.
+pub(crate) fn extension(name: &[u8]) -> Option<&[u8]> {
+    let base = name.rsplit(|&b| b == b'/').next()?;
+    let dot = base
+        .iter()
+        .rposition(|&b| b == b'.')
+        .filter(|&dot| dot > 0)?;
+    Some(&base[dot + 1..])
+}
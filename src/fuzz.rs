@@ -0,0 +1,76 @@
+//! Entry points for the fuzz targets in `fuzz/`, which are not part of the API. They must
+//! never panic, whatever the data, since it's what a peer could send.
+
+use crate::extract::Gzip;
+use crate::protocol::{read_file_list, Header, Metadata};
+use crate::{
+    common_prefix_len, output_path, parse_verify_list, strip_components, strip_names,
+    CaseCollisions, Duplicates, FoldedNames, Normalization, PathPrefix,
+};
+use std::io;
+use std::path::Path;
+
+/// Parses a file list sent with the given protocol `version`, and names the files the way
+/// the receiver would store them.
+pub fn file_list(version: u8, data: &[u8]) {
+    let mut files = match read_file_list(&mut &data[..], version, data.len()) {
+        Ok(files) => files,
+        Err(_) => return,
+    };
+    let prefix_len = common_prefix_len(files.iter().map(|file| &file.name[..]), PathPrefix::Strip);
+    if strip_names(&mut files, prefix_len, PathPrefix::Strip).is_err() {
+        return;
+    }
+    strip_components(&mut files, true, 1);
+    let mut folded = FoldedNames::new(
+        CaseCollisions::Rename,
+        Duplicates::KeepFirst,
+        Some(Normalization::Nfc),
+    );
+    for (i, file) in files.iter_mut().enumerate() {
+        if folded.check(i, file).is_ok() {
+            let _ = output_path(Path::new("out"), &file.name);
+        }
+    }
+}
+
+/// Reads a header sent with the given protocol `version`, and metadata, and checks that they
+/// are encoded back into what was read. Unlike the others, this panics if they're not.
+pub fn round_trip(version: u8, data: &[u8]) {
+    let mut rest = data;
+    if let Ok(header) = Header::read(&mut rest, version) {
+        let read_len = data.len() - rest.len();
+        if let Some(list) = rest.get(..header.list_len) {
+            let mut sent = vec![b's', b'f', b'-', version];
+            sent.extend(&data[..read_len]);
+            sent.extend(list);
+            let mut encoded = header.encode(list).expect("header too long to encode");
+            // since version 7 the length is not needed to read the header, so it isn't checked
+            if version >= 7 {
+                encoded[4..8].copy_from_slice(&sent[4..8]);
+            }
+            assert_eq!(encoded, sent);
+        }
+    }
+    if let Some(metadata) = Metadata::parse(data) {
+        let encoded = metadata.encode().expect("metadata too long to encode");
+        assert_eq!(encoded.len(), metadata.encoded_len());
+        assert_eq!(Metadata::parse(&encoded), Some(metadata));
+    }
+}
+
+/// Parses a file list sent to verify the files.
+pub fn verify_list(data: &[u8]) {
+    if let Ok(files) = parse_verify_list(data) {
+        for (_, _, name) in files {
+            let _ = output_path(Path::new("out"), name);
+        }
+    }
+}
+
+/// Decompresses gzip data, as is done with the `.tar.gz` files extracted by the receiver.
+pub fn gzip(data: &[u8]) {
+    if let Ok(mut gzip) = Gzip::new(data) {
+        let _ = io::copy(&mut gzip, &mut io::sink());
+    }
+}
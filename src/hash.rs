@@ -0,0 +1,137 @@
+use hmac::{Hmac, Mac};
+use sha2::{Digest as _, Sha256};
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+pub type Digest = [u8; 32];
+
+/// Hashes the entire contents of the file at `path`.
+pub fn hash_file(path: &Path) -> io::Result<Digest> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break Ok(hasher.finalize().into());
+        }
+        hasher.update(&buffer[..n]);
+    }
+}
+
+/// Authenticates the data with the key, as in RFC 2104.
+pub fn hmac_sha256(key: &[u8], data: &[u8]) -> Digest {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("keys of any length can be used");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+/// Hashes everything written through it to the inner writer, if asked to.
+pub struct Writer<W> {
+    inner: W,
+    hasher: Option<Sha256>,
+}
+
+impl<W> Writer<W> {
+    pub fn new(inner: W, hashed: bool) -> Self {
+        Self {
+            inner,
+            hasher: hashed.then(Sha256::new),
+        }
+    }
+
+    /// What was written into, along with the hash of everything written, if it was asked for.
+    pub fn finish(self) -> (W, Option<Digest>) {
+        let digest = self.hasher.map(|hasher| hasher.finalize().into());
+        (self.inner, digest)
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(hasher) = &mut self.hasher {
+            hasher.update(&buf[..n]);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::to_hex;
+
+    // The examples of FIPS 180-4, as given by NIST, and the longer message of its test vectors.
+    // Hash the data as it's written through.
+    fn sha256(data: &[u8]) -> Digest {
+        let mut writer = Writer::new(io::sink(), true);
+        writer.write_all(data).unwrap();
+        writer.finish().1.unwrap()
+    }
+
+    #[test]
+    fn sha256_known_answers() {
+        let cases: [(&[u8], &str); 3] = [
+            (
+                b"",
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            ),
+            (
+                b"abc",
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq",
+                "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            ),
+        ];
+        for (data, digest) in cases {
+            assert_eq!(to_hex(&sha256(data)), digest);
+        }
+        assert_eq!(
+            to_hex(&sha256(&vec![b'a'; 1_000_000])),
+            "cdc76e5c9914fb9281a1c7e284d73e67f1809a48a497200e046d39ccc7112cd0"
+        );
+    }
+
+    // Test cases 1, 2 and 6 of RFC 4231, the last of which has a key longer than a block.
+    #[test]
+    fn hmac_sha256_known_answers() {
+        assert_eq!(
+            to_hex(&hmac_sha256(&[0x0b; 20], b"Hi There")),
+            "b0344c61d8db38535ca8afceaf0bf12b881dc200c9833da726e9376c2e32cff7"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(b"Jefe", b"what do ya want for nothing?")),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            to_hex(&hmac_sha256(
+                &[0xaa; 131],
+                b"Test Using Larger Than Block-Size Key - Hash Key First"
+            )),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn writer_hashes_what_it_writes() {
+        let mut writer = Writer::new(Vec::new(), true);
+        for chunk in b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq".chunks(5) {
+            writer.write_all(chunk).unwrap();
+        }
+        let (written, digest) = writer.finish();
+        assert_eq!(written.len(), 56);
+        assert_eq!(
+            to_hex(&digest.unwrap()),
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1"
+        );
+        assert_eq!(Writer::new(io::sink(), false).finish().1, None);
+    }
+}
@@ -0,0 +1,128 @@
+//! A record of the transfers made, one after another, so that `sf history` can list them.
+//!
+//! Every transfer is a line of its own with a JSON object, which says `when` it ended (in
+//! seconds since the epoch), what was `done` (`sent` or `received`), the `peer` if it
+//! connected, how many `files` and `bytes` went through and in how many `secs`, how many
+//! files were `skipped` and how many `failed`, the `average` and `peak` bytes per second, the
+//! compression `ratio` if the data was compressed, and the `error` it failed with, if it did.
+//! Appending a line leaves the rest of the file alone, and the lines that can't be read are
+//! skipped. Lines written before some of these were kept read as zero or none of them.
+
+use crate::queue::state_dir;
+use crate::{json, Error, Result};
+use std::fmt::Write as _;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+/// A transfer that was made.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// When it ended, in seconds since the epoch.
+    pub when: u64,
+    /// What was done, such as `sent` or `received`.
+    pub done: String,
+    pub peer: Option<String>,
+    pub files: u64,
+    pub bytes: u64,
+    pub secs: u64,
+    /// Files that the peer already had.
+    pub skipped: u64,
+    /// Files that started being transferred but never finished, or could not be read.
+    pub failed: u64,
+    /// Bytes per second over the whole transfer.
+    pub average: u64,
+    /// Bytes per second during the fastest second of the transfer.
+    pub peak: u64,
+    /// The length of the compressed data as a fraction of the original, if it was compressed.
+    pub ratio: Option<f64>,
+    /// Why it failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Where the history is kept unless told otherwise, next to the queue.
+pub fn default_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("sf").join("history.jsonl"))
+}
+
+impl Record {
+    /// The transfer as a JSON object, the way it's kept in the history.
+    pub fn to_json(&self) -> String {
+        let mut json = format!(
+            "{{\"when\": {}, \"done\": {}",
+            self.when,
+            json::string(&self.done)
+        );
+        if let Some(peer) = &self.peer {
+            let _ = write!(json, ", \"peer\": {}", json::string(peer));
+        }
+        let _ = write!(
+            json,
+            ", \"files\": {}, \"bytes\": {}, \"secs\": {}, \"skipped\": {}, \"failed\": {}, \
+             \"average\": {}, \"peak\": {}",
+            self.files, self.bytes, self.secs, self.skipped, self.failed, self.average, self.peak
+        );
+        if let Some(ratio) = self.ratio {
+            let _ = write!(json, ", \"ratio\": {:.3}", ratio);
+        }
+        if let Some(error) = &self.error {
+            let _ = write!(json, ", \"error\": {}", json::string(error));
+        }
+        json.push('}');
+        json
+    }
+}
+
+/// Adds the transfer to the end of the history at `path`.
+pub fn append(path: &Path, record: &Record) -> Result<()> {
+    let mut line = record.to_json();
+    line.push('\n');
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).map_err(|e| Error::from(e).at(parent))?;
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| Error::from(e).at(path))
+}
+
+/// Reads the history at `path`, oldest first, which is empty if there's no file yet.
+pub fn load(path: &Path) -> Result<Vec<Record>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let text = fs::read_to_string(path).map_err(|e| Error::from(e).at(path))?;
+    Ok(text
+        .lines()
+        .filter_map(|line| {
+            let record = json::parse(line)?;
+            let number = |key: &str| record.get(key).and_then(json::Value::as_u64);
+            let text = |key: &str| {
+                record
+                    .get(key)
+                    .and_then(json::Value::as_str)
+                    .map(str::to_owned)
+            };
+            Some(Record {
+                when: number("when")?,
+                done: text("done")?,
+                peer: text("peer"),
+                files: number("files")?,
+                bytes: number("bytes")?,
+                secs: number("secs")?,
+                skipped: number("skipped").unwrap_or(0),
+                failed: number("failed").unwrap_or(0),
+                average: number("average").unwrap_or(0),
+                peak: number("peak").unwrap_or(0),
+                ratio: record.get("ratio").and_then(json::Value::as_f64),
+                error: text("error"),
+            })
+        })
+        .collect())
+}
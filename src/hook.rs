@@ -0,0 +1,186 @@
+//! Commands run by the receiver as files arrive, for whatever should happen to them next.
+
+use sf::{EventHandler, TransferEvent};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// A command to run, along with the variables that describe what it's run for.
+struct Run {
+    command: String,
+    env: Vec<(&'static str, OsString)>,
+}
+
+#[derive(Default)]
+struct Session {
+    peer: Option<SocketAddr>,
+    // where the files of this session are, if not in the output directory itself
+    output: Option<PathBuf>,
+    // path and length of every file that started, until it's done
+    started: HashMap<usize, (PathBuf, u64)>,
+    // the commands of the files done, while they wait to be moved into the output
+    staged: Vec<Run>,
+    files: usize,
+    bytes: u64,
+}
+
+/// Runs the commands after each file and session, one at a time and in order, without
+/// holding up the transfer.
+pub struct Hooks {
+    runs: Sender<Run>,
+    worker: JoinHandle<()>,
+    session: Arc<Mutex<Session>>,
+    on_session: Option<String>,
+    output: PathBuf,
+}
+
+impl Hooks {
+    /// Starts following the events of the transfer, which still reach the existing handler.
+    /// `output` is where the files of a session are said to be. If they're `staged`, the
+    /// command of every file only runs once the session is done and they're all there.
+    pub fn new(
+        events: &mut Option<EventHandler>,
+        on_file: Option<String>,
+        on_session: Option<String>,
+        output: PathBuf,
+        staged: bool,
+    ) -> Self {
+        let (runs, pending) = mpsc::channel::<Run>();
+        let worker = thread::spawn(move || {
+            for run in pending {
+                execute(run);
+            }
+        });
+
+        let session = Arc::new(Mutex::new(Session::default()));
+        let previous = events.take();
+        *events = Some({
+            let (runs, session) = (runs.clone(), Arc::clone(&session));
+            let (on_session, output) = (on_session.clone(), output.clone());
+            Box::new(move |event| {
+                let mut session = session.lock().unwrap();
+                match &event {
+                    TransferEvent::Connected { peer } => session.peer = Some(*peer),
+                    TransferEvent::Storing { output } => session.output = Some(output.clone()),
+                    TransferEvent::FileStarted { index, path, len } => {
+                        session.started.insert(*index, (path.clone(), *len));
+                    }
+                    TransferEvent::FileDone { index } => {
+                        if let Some((path, len)) = session.started.remove(index) {
+                            session.files += 1;
+                            session.bytes += len;
+                            if let Some(command) = &on_file {
+                                let run = Run {
+                                    command: command.clone(),
+                                    env: vec![
+                                        ("SF_PATH", path.into_os_string()),
+                                        ("SF_BYTES", len.to_string().into()),
+                                        ("SF_PEER", peer_var(session.peer)),
+                                        ("SF_STATUS", "done".into()),
+                                    ],
+                                };
+                                if staged {
+                                    session.staged.push(run);
+                                } else {
+                                    let _ = runs.send(run);
+                                }
+                            }
+                        }
+                    }
+                    TransferEvent::Finished => {
+                        // a service receives one session after another
+                        let mut done = std::mem::take(&mut *session);
+                        for run in done.staged.drain(..) {
+                            let _ = runs.send(run);
+                        }
+                        if let Some(command) = &on_session {
+                            let _ = runs.send(session_run(command, &done, &output, "done", None));
+                        }
+                    }
+                    _ => {}
+                }
+                drop(session);
+                if let Some(previous) = &previous {
+                    previous(event);
+                }
+            })
+        });
+
+        Hooks {
+            runs,
+            worker,
+            session,
+            on_session,
+            output,
+        }
+    }
+
+    /// Runs the command of the session if the transfer failed, and waits for every command
+    /// to be done.
+    pub fn finish(self, result: &sf::Result<()>) {
+        if let (Some(command), Err(e)) = (&self.on_session, result) {
+            let session = self.session.lock().unwrap();
+            let _ = self.runs.send(session_run(
+                command,
+                &session,
+                &self.output,
+                "failed",
+                Some(e.to_string()),
+            ));
+        }
+        drop(self.runs);
+        let _ = self.worker.join();
+    }
+}
+
+fn session_run(
+    command: &str,
+    session: &Session,
+    output: &Path,
+    status: &str,
+    error: Option<String>,
+) -> Run {
+    let output = session.output.as_deref().unwrap_or(output);
+    let mut env = vec![
+        ("SF_PATH", output.as_os_str().to_os_string()),
+        ("SF_FILES", session.files.to_string().into()),
+        ("SF_BYTES", session.bytes.to_string().into()),
+        ("SF_PEER", peer_var(session.peer)),
+        ("SF_STATUS", status.into()),
+    ];
+    if let Some(error) = error {
+        env.push(("SF_ERROR", error.into()));
+    }
+    Run {
+        command: command.to_string(),
+        env,
+    }
+}
+
+fn peer_var(peer: Option<SocketAddr>) -> OsString {
+    peer.map(|peer| peer.to_string()).unwrap_or_default().into()
+}
+
+// Run the command through the shell, so that it can be a whole pipeline.
+fn execute(run: Run) {
+    let mut command = if cfg!(windows) {
+        let mut command = Command::new("cmd");
+        command.arg("/C").arg(&run.command);
+        command
+    } else {
+        let mut command = Command::new("sh");
+        command.arg("-c").arg(&run.command);
+        command
+    };
+    command.envs(run.env).stdin(Stdio::null());
+    match command.status() {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("the command {:?} failed ({})", run.command, status),
+        Err(e) => eprintln!("could not run the command {:?}: {}", run.command, e),
+    }
+}
@@ -0,0 +1,696 @@
+//! A minimal web server, for those without sf: files can be uploaded to a receiver from a
+//! browser, or downloaded from the links of a server.
+//!
+//! The upload page sends every file with its own `PUT upload` request, naming it in the
+//! query string, which is far simpler to handle than a multipart form. Every response closes
+//! the connection, so that there's no need to keep track of them.
+//!
+//! Both the upload page and the served files are behind a token, only known to those given
+//! the link. Under the token of the served files there's also a page linking to every one of
+//! them, so that a whole tree can be fetched by following them.
+
+use crate::cancel::{self, check, is_cancelled};
+use crate::event::{emit, emit_progress, emit_started};
+use crate::net::{SocketOptions, TimedStream};
+use crate::{
+    accept, is_unchanged, output_path, pipe, sink, store_file, CancelToken, Duplicates, Error,
+    FoldedNames, ListedFile, Quota, ReceiveOptions, Result, ServeOptions, TransferEvent, Usage,
+};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::future::{self, Future};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::io::BufReader;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+const TIMEOUT: Duration = Duration::from_secs(30);
+const MAX_HEAD_LEN: u64 = 16 * 1024;
+const BUFFER_LEN: usize = 64 * 1024;
+
+const PAGE: &str = r#"<!DOCTYPE html>
+<meta charset="utf-8">
+<meta name="viewport" content="width=device-width, initial-scale=1">
+<title>sf</title>
+<h1>Send files</h1>
+<p><label>Files <input type="file" id="files" multiple></label>
+<p><label>Folder <input type="file" id="folder" webkitdirectory></label>
+<p><label><input type="checkbox" id="update"> Skip files that were already sent</label>
+<p><button id="send">Send</button>
+<ul id="log"></ul>
+<script>
+send.onclick = async () => {
+    send.disabled = true;
+    for (const file of [...files.files, ...folder.files]) {
+        const name = file.webkitRelativePath || file.name;
+        const item = log.appendChild(document.createElement('li'));
+        item.textContent = name + ': sending...';
+        const query = new URLSearchParams({ name, modified: Math.floor(file.lastModified / 1000) });
+        if (update.checked) {
+            query.set('update', '1');
+        }
+        try {
+            const response = await fetch('upload?' + query, { method: 'PUT', body: file });
+            item.textContent = name + ': ' + await response.text();
+        } catch (e) {
+            item.textContent = name + ': ' + e;
+        }
+    }
+    send.disabled = false;
+};
+</script>
+"#;
+
+struct Request {
+    method: String,
+    // still percent-encoded
+    path: String,
+    query: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Request {
+    // Read the request line and headers, leaving the body (if any) in the reader.
+    async fn read(reader: &mut (impl AsyncBufRead + Unpin)) -> Result<Self> {
+        let mut limited = reader.take(MAX_HEAD_LEN);
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            if limited.read_line(&mut line).await? == 0 {
+                return Err(Error::ProtocolViolation("incomplete request".into()));
+            }
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            lines.push(line.to_string());
+        }
+
+        let mut lines = lines.into_iter();
+        let request_line = lines.next().unwrap_or_default();
+        let mut parts = request_line.split(' ');
+        let method = parts.next().unwrap_or("").to_string();
+        let target = parts.next().unwrap_or("");
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        Ok(Request {
+            method,
+            path: path.to_string(),
+            query: query.to_string(),
+            headers: lines
+                .filter_map(|line| {
+                    let (name, value) = line.split_once(':')?;
+                    Some((name.to_string(), value.trim().to_string()))
+                })
+                .collect(),
+        })
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn text(status: &'static str, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: body.into(),
+        }
+    }
+
+    async fn send(&self, stream: &mut (impl AsyncWrite + Unpin)) -> io::Result<()> {
+        write_head(
+            stream,
+            self.status,
+            &[
+                ("Content-Type", self.content_type.to_string()),
+                ("Content-Length", self.body.len().to_string()),
+            ],
+        )
+        .await?;
+        stream.write_all(self.body.as_bytes()).await
+    }
+}
+
+async fn write_head(
+    stream: &mut (impl AsyncWrite + Unpin),
+    status: &str,
+    headers: &[(&str, String)],
+) -> io::Result<()> {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("Connection: close\r\n\r\n");
+    stream.write_all(head.as_bytes()).await
+}
+
+// Apply the timeout to the connection, which is then handled by whoever accepted it.
+fn timed(stream: TcpStream) -> Result<TimedStream> {
+    let options = SocketOptions {
+        timeout: Some(TIMEOUT),
+        send_buffer: None,
+        recv_buffer: None,
+        nodelay: false,
+    };
+    Ok(TimedStream::new(stream, &options)?)
+}
+
+// Whether the token `given` is the right one. It's compared in full even when it's wrong, so
+// that it can't be guessed by timing how long it takes to be refused.
+fn authorized(given: &str, token: &str) -> bool {
+    given.len() == token.len()
+        && given
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Report failures in handling the connection, since they must not stop the server.
+fn report(result: Result<()>, peer: Option<SocketAddr>) {
+    match (result, peer) {
+        (Ok(()), _) | (Err(Error::Cancelled), _) => {}
+        (Err(e), Some(peer)) => out!("failed to serve the browser at {}: {}", peer, e),
+        (Err(e), None) => out!("failed to serve a browser: {}", e),
+    }
+}
+
+// === Uploads
+
+// What every upload is stored with, kept from one to the next like from one transfer to the
+// next, so that uploads are stored just as the files sent with sf are.
+struct Uploads<'a> {
+    token: &'a str,
+    options: &'a ReceiveOptions,
+    // lent to each upload while it's stored
+    directory: Option<sink::Directory>,
+    folded: FoldedNames,
+    usage: Arc<Mutex<Usage>>,
+    count: usize,
+}
+
+/// Serves the upload page on the listener, behind the `token`, and stores what's uploaded as
+/// the `options` say, counting it towards the quotas with the `usage` of the transfers, until
+/// `stop` is cancelled. Failed requests are reported, but never stop the server.
+pub(crate) async fn serve_uploads(
+    listener: &TcpListener,
+    token: &str,
+    options: &ReceiveOptions,
+    usage: Arc<Mutex<Usage>>,
+    stop: &Option<CancelToken>,
+) {
+    let mut directory = sink::Directory::new(&options.output);
+    directory.owner = options.owner;
+    directory.file_mode = options.file_mode;
+    directory.dir_mode = options.dir_mode;
+    let mut uploads = Uploads {
+        token,
+        options,
+        directory: Some(directory),
+        // every upload is a transfer of its own, so the same name uploaded again is no duplicate
+        folded: FoldedNames::new(
+            options.case_collisions,
+            Duplicates::KeepLast,
+            options.normalize,
+        ),
+        usage,
+        count: 0,
+    };
+    loop {
+        match accept(listener, stop).await {
+            Ok(stream) => {
+                let peer = stream.peer_addr().ok();
+                let result = match timed(stream) {
+                    Ok(mut stream) => handle_upload(&mut stream, &mut uploads).await,
+                    Err(e) => Err(e),
+                };
+                report(result, peer);
+            }
+            Err(Error::Cancelled) => break,
+            Err(e) => out!("failed to accept a browser: {}", e),
+        }
+    }
+}
+
+async fn handle_upload(stream: &mut TimedStream, uploads: &mut Uploads<'_>) -> Result<()> {
+    let peer = stream.peer_addr()?.ip();
+    let mut reader = BufReader::new(stream);
+    let request = Request::read(&mut reader).await?;
+    let content_len = request
+        .header("content-length")
+        .and_then(|len| len.parse::<u64>().ok());
+
+    let path = request.path.strip_prefix('/').unwrap_or_default();
+    let (given, path) = path.split_once('/').unwrap_or((path, ""));
+    let response = match (request.method.as_str(), path) {
+        _ if !authorized(given, uploads.token) => Response::text("404 Not Found", "not found"),
+        ("GET", "") => Response {
+            status: "200 OK",
+            content_type: "text/html; charset=utf-8",
+            body: PAGE.to_string(),
+        },
+        ("PUT", "upload") => match content_len {
+            Some(len) => {
+                let expect = request
+                    .header("expect")
+                    .is_some_and(|expect| expect.eq_ignore_ascii_case("100-continue"));
+                upload(&mut reader, (len, expect), &request.query, peer, uploads).await?
+            }
+            None => Response::text("411 Length Required", "the upload must have a length"),
+        },
+        (_, "") | (_, "upload") => Response::text("405 Method Not Allowed", "not allowed"),
+        _ => Response::text("404 Not Found", "not found"),
+    };
+    Ok(response.send(reader.get_mut()).await?)
+}
+
+// Store the body of the request, sent by the browser at `peer`, as the file named in the query.
+// If the browser `expect`s to be told to go on before sending the body, it's only told once the
+// file is known to be accepted.
+async fn upload(
+    reader: &mut BufReader<&mut TimedStream>,
+    (len, expect): (u64, bool),
+    query: &str,
+    peer: IpAddr,
+    uploads: &mut Uploads<'_>,
+) -> io::Result<Response> {
+    let mut name = None;
+    let mut modified = None;
+    let mut update = false;
+    for pair in query.split('&') {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "name" => name = decode(value, true),
+            "modified" => modified = value.parse().ok(),
+            "update" => update = value == "1",
+            _ => {}
+        }
+    }
+
+    let (name, file_len) = match (name, usize::try_from(len)) {
+        (Some(name), Ok(file_len)) => (name, file_len),
+        _ => return Ok(Response::text("400 Bad Request", "bad file name")),
+    };
+    let mut file = ListedFile {
+        len: file_len,
+        modified,
+        name: name.into_bytes(),
+        ..ListedFile::default()
+    };
+    uploads.count += 1;
+    if let Err(e) = uploads.folded.check(uploads.count, &mut file) {
+        return Ok(Response::text("409 Conflict", e.to_string()));
+    }
+    let output = &uploads.options.output;
+    let path = match output_path(output, &file.name) {
+        Ok(path) => path,
+        Err(e) => return Ok(Response::text("400 Bad Request", e.to_string())),
+    };
+    if update && is_unchanged(&path, len, modified) {
+        out!("skipping unchanged file {:?} from a browser", path);
+        return Ok(Response::text("200 OK", "unchanged"));
+    }
+
+    if let Err(e) = sink::check_links(output, path.parent().unwrap_or(output)) {
+        return Ok(Response::text("403 Forbidden", e.to_string()));
+    }
+    let mut quota = Quota::new(Arc::clone(&uploads.usage), peer, uploads.options);
+    if let Err(e) = quota.reserve_len(len) {
+        return Ok(Response::text("413 Payload Too Large", e.to_string()));
+    }
+
+    // some clients (like curl) wait to be told to go on before sending the body
+    if expect {
+        write_head(reader.get_mut(), "100 Continue", &[]).await?;
+    }
+    out!("receiving file {:?} from a browser...", path);
+    let directory = uploads.directory.take().expect("uploads stored at once");
+    let (directory, result) = store_file(directory, &path, modified, reader, len).await;
+    uploads.directory = Some(directory);
+    Ok(match result {
+        Ok(()) => {
+            quota.charge(len);
+            Response::text("201 Created", "done")
+        }
+        Err(e) => {
+            out!("failed to receive {:?} from a browser: {}", path, e);
+            Response::text("500 Internal Server Error", e.to_string())
+        }
+    })
+}
+
+// === Downloads
+
+/// A file available for download at `/<token>/<name>`.
+pub(crate) struct Served {
+    pub path: PathBuf,
+    pub name: String,
+}
+
+// Which part of a file is wanted.
+enum Range {
+    Full,
+    // first and last byte, inclusive
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+impl Range {
+    // Parse the `Range` header for a file of `len` bytes. Only a single range is supported,
+    // so the whole file is sent for anything else, which clients must accept.
+    fn parse(header: Option<&str>, len: u64) -> Self {
+        let spec = match header.and_then(|header| header.strip_prefix("bytes=")) {
+            Some(spec) if !spec.contains(',') => spec.trim(),
+            _ => return Range::Full,
+        };
+        let (start, end) = match spec.split_once('-') {
+            Some(bounds) => bounds,
+            None => return Range::Full,
+        };
+        if start.is_empty() {
+            // the last bytes of the file
+            return match end.parse::<u64>() {
+                Ok(0) => Range::Unsatisfiable,
+                Ok(_) if len == 0 => Range::Unsatisfiable,
+                Ok(suffix) => Range::Part(len.saturating_sub(suffix), len - 1),
+                Err(_) => Range::Full,
+            };
+        }
+        match (start.parse::<u64>(), end) {
+            (Ok(start), _) if start >= len => Range::Unsatisfiable,
+            (Ok(start), "") => Range::Part(start, len - 1),
+            (Ok(start), end) => match end.parse::<u64>() {
+                Ok(end) if end >= start => Range::Part(start, end.min(len - 1)),
+                _ => Range::Full,
+            },
+            (Err(_), _) => Range::Full,
+        }
+    }
+}
+
+/// Serves every file at its link until each has been downloaded in full as many times as
+/// allowed. The same file may be downloaded by several clients at once, and downloads can
+/// be resumed by asking for what's left of them.
+pub(crate) async fn serve_files(
+    listener: &TcpListener,
+    token: &str,
+    files: &[Served],
+    options: &ServeOptions,
+) -> Result<()> {
+    // the downloads each file has left, which are only used up once they complete
+    let remaining = Mutex::new(vec![options.downloads; files.len()]);
+    let done = || remaining.lock().unwrap().iter().all(|&left| left == 0);
+    let deadline = options.expire.map(|expire| Instant::now() + expire);
+    let expired = move || deadline.is_some_and(|deadline| Instant::now() >= deadline);
+
+    // every download goes on at the same time as the others, until the browser is done
+    let mut downloads: Vec<Pin<Box<dyn Future<Output = ()> + Send + '_>>> = Vec::new();
+    let result = loop {
+        if let Err(e) = check(&options.cancel) {
+            break Err(e);
+        }
+        if done() {
+            break Ok(());
+        }
+        if expired() {
+            out!("the files are no longer served, as the time to download them is up");
+            break Ok(());
+        }
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            // one of the downloads completed, which may have been the last one allowed
+            () = progress(&mut downloads, false) => continue,
+            () = cancel::cancelled(&options.cancel) => continue,
+            () = until(deadline) => continue,
+        };
+        match accepted {
+            Ok((stream, _)) => {
+                let remaining = &remaining;
+                downloads.push(Box::pin(async move {
+                    let peer = stream.peer_addr().ok();
+                    let result = match timed(stream) {
+                        Ok(mut stream) => {
+                            handle_download(&mut stream, token, files, remaining, expired, options)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    report(result, peer);
+                }));
+            }
+            Err(e) => out!("failed to accept a browser: {}", e),
+        }
+    };
+    // the downloads still going on are not cut short, and notice for themselves if cancelled
+    progress(&mut downloads, true).await;
+    if result.is_ok() {
+        emit(&options.events, TransferEvent::Finished);
+    }
+    result
+}
+
+// Drive the `downloads` until one of them completes, or until all of them did if `all`,
+// never completing while there are none.
+async fn progress(downloads: &mut Vec<Pin<Box<dyn Future<Output = ()> + Send + '_>>>, all: bool) {
+    future::poll_fn(|cx| {
+        let before = downloads.len();
+        downloads.retain_mut(|download| download.as_mut().poll(cx).is_pending());
+        let finished = if all {
+            downloads.is_empty()
+        } else {
+            downloads.len() < before
+        };
+        if finished {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    })
+    .await
+}
+
+// Complete once the `deadline` is reached, which is never without one.
+async fn until(deadline: Option<Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+        None => future::pending().await,
+    }
+}
+
+async fn handle_download(
+    stream: &mut TimedStream,
+    token: &str,
+    files: &[Served],
+    remaining: &Mutex<Vec<usize>>,
+    expired: impl Fn() -> bool,
+    options: &ServeOptions,
+) -> Result<()> {
+    let request = Request::read(&mut BufReader::new(&mut *stream)).await?;
+    let head = request.method == "HEAD";
+    if request.method != "GET" && !head {
+        let response = Response::text("405 Method Not Allowed", "not allowed");
+        return Ok(response.send(stream).await?);
+    }
+
+    let path = decode(&request.path, false).unwrap_or_default();
+    let (given, name) = path
+        .strip_prefix('/')
+        .and_then(|path| path.split_once('/'))
+        .unwrap_or(("", ""));
+    let authorized = authorized(given, token);
+    if authorized && expired() {
+        return Ok(Response::text("410 Gone", "the link expired")
+            .send(stream)
+            .await?);
+    }
+    if authorized && name.is_empty() {
+        let response = listing(files, &remaining.lock().unwrap());
+        return Ok(response.send(stream).await?);
+    }
+    let index = match files.iter().position(|file| file.name == name) {
+        Some(index) if authorized => index,
+        _ => {
+            let response = Response::text("404 Not Found", "not found");
+            return Ok(response.send(stream).await?);
+        }
+    };
+    if remaining.lock().unwrap()[index] == 0 {
+        let response = Response::text("410 Gone", "no downloads left");
+        return Ok(response.send(stream).await?);
+    }
+
+    let file = &files[index];
+    let mut f = File::open(&file.path).map_err(|e| Error::from(e).at(&file.path))?;
+    let len = f
+        .metadata()
+        .map_err(|e| Error::from(e).at(&file.path))?
+        .len();
+    // some clients only understand the plain name, which can't have anything fancy in it
+    let base_name = file.name.rsplit('/').next().unwrap_or(&file.name);
+    let plain_name = base_name
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect::<String>();
+    let mut headers = vec![
+        ("Content-Type", "application/octet-stream".to_string()),
+        ("Accept-Ranges", "bytes".to_string()),
+        (
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+                plain_name,
+                encode(base_name)
+            ),
+        ),
+    ];
+    let (status, start, end) = match Range::parse(request.header("range"), len) {
+        Range::Full => ("200 OK", 0, len),
+        Range::Part(first, last) => {
+            headers.push(("Content-Range", format!("bytes {}-{}/{}", first, last, len)));
+            ("206 Partial Content", first, last + 1)
+        }
+        Range::Unsatisfiable => {
+            headers.push(("Content-Range", format!("bytes */{}", len)));
+            return Ok(write_head(stream, "416 Range Not Satisfiable", &headers).await?);
+        }
+    };
+    headers.push(("Content-Length", (end - start).to_string()));
+    write_head(stream, status, &headers).await?;
+    if head {
+        return Ok(());
+    }
+
+    out!(
+        "serving file {:?} ({} bytes from {})...",
+        file.path,
+        end - start,
+        start
+    );
+    if let Ok(peer) = stream.peer_addr() {
+        emit(&options.events, TransferEvent::Connected { peer });
+    }
+    emit_started(&options.events, index, &file.path, len);
+    f.seek(SeekFrom::Start(start))
+        .map_err(|e| Error::from(e).at(&file.path))?;
+    // the file is read on a thread of its own, while what was read before it is sent
+    let reading = (f, end - start);
+    let mut reading = pipe::ReadAhead::start(BUFFER_LEN, reading, |(f, left), buffer| {
+        if *left == 0 {
+            return Ok(0);
+        }
+        let wanted = (*left).min(buffer.len() as u64) as usize;
+        let n = f.read(&mut buffer[..wanted])?;
+        if n == 0 {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+        }
+        *left -= n as u64;
+        Ok(n)
+    });
+    let mut position = start;
+    while let Some(chunk) = reading.next().await {
+        if is_cancelled(&options.cancel) {
+            return Err(Error::Cancelled);
+        }
+        stream.write_all(&chunk).await?;
+        position += chunk.len() as u64;
+        reading.give_back(chunk);
+        emit_progress(&options.events, index, position);
+    }
+    let (_, read) = reading.finish().await;
+    read.map_err(|e| Error::from(e).at(&file.path))?;
+
+    // only reaching the end counts as a download, so that it can be resumed if interrupted
+    if end == len {
+        let mut remaining = remaining.lock().unwrap();
+        remaining[index] = remaining[index].saturating_sub(1);
+        out!(
+            "file {:?} was downloaded ({} left)",
+            file.path,
+            remaining[index]
+        );
+        emit(&options.events, TransferEvent::FileDone { index });
+    }
+    Ok(())
+}
+
+// A page linking to every file that can still be downloaded, relative to the page itself.
+fn listing(files: &[Served], remaining: &[usize]) -> Response {
+    let mut body = String::from(
+        "<!DOCTYPE html>\n<meta charset=\"utf-8\">\n<title>sf</title>\n<h1>Files</h1>\n<ul>\n",
+    );
+    for (file, &left) in files.iter().zip(remaining) {
+        if left > 0 {
+            body.push_str(&format!(
+                "<li><a href=\"{}\">{}</a>\n",
+                encode(&file.name),
+                escape(&file.name)
+            ));
+        }
+    }
+    body.push_str("</ul>\n");
+    Response {
+        status: "200 OK",
+        content_type: "text/html; charset=utf-8",
+        body,
+    }
+}
+
+// Escape the text so that it shows as is in a page.
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Percent-encodes the name so that it can be used in the path of a link.
+pub(crate) fn encode(name: &str) -> String {
+    let mut encoded = String::with_capacity(name.len());
+    for c in name.bytes() {
+        match c {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(c as char)
+            }
+            c => encoded.push_str(&format!("%{:02X}", c)),
+        }
+    }
+    encoded
+}
+
+// Decode a percent-encoded path or value of the query string (where `+` is a space),
+// or `None` if it's not valid UTF-8 once decoded.
+fn decode(value: &str, query: bool) -> Option<String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut iter = value.bytes();
+    while let Some(c) = iter.next() {
+        bytes.push(match c {
+            b'+' if query => b' ',
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?
+            }
+            c => c,
+        });
+    }
+    String::from_utf8(bytes).ok()
+}
@@ -0,0 +1,212 @@
+//! Keys with which senders prove who they are, and the list of those a receiver accepts.
+//!
+//! An identity is a file holding the secret key of a sender, as a line with `sf-ed25519-secret`
+//! and the key in hex, created the first time it's used. Its public key is a line with
+//! `sf-ed25519` and the key in hex, which is what goes in the `authorized_senders` file of the
+//! receiver, one per line, optionally followed by a comment saying whose it is. Empty lines
+//! and those starting with `#` are ignored in both.
+//!
+//! A session key is a random secret a receiver gives out instead, such as in the descriptor of
+//! its QR code, so that it only takes files from whoever was shown it.
+
+use crate::ed25519::{self, SigningKey};
+use crate::hash::{hmac_sha256, Digest};
+use crate::manifest::{from_hex, to_hex};
+use crate::{random, Error, Result};
+use std::convert::TryInto;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+
+const PUBLIC_TAG: &str = "sf-ed25519";
+const SECRET_TAG: &str = "sf-ed25519-secret";
+// what a sender signs to identify itself, along with the session and the challenge
+const SIGNATURE_CONTEXT: &[u8] = b"sf-identity";
+// what a sender authenticates with the session key to prove it knows it, in the same way
+const KEY_CONTEXT: &[u8] = b"sf-key";
+const SESSION_KEY_LEN: usize = 16;
+
+/// The public key of an identity, by which a receiver knows a sender.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; ed25519::PUBLIC_KEY_LEN]);
+
+impl PublicKey {
+    /// Parses a public key in the form it's displayed, like `sf-ed25519 3d4017c3...`.
+    pub fn parse(text: &str) -> Option<PublicKey> {
+        let (tag, key) = text.trim().split_once(' ')?;
+        if tag != PUBLIC_TAG {
+            return None;
+        }
+        Some(PublicKey(from_hex(key.trim())?.try_into().ok()?))
+    }
+
+    /// Whether the signature of the challenge in the session was made with this key.
+    pub(crate) fn verify(
+        &self,
+        session: u64,
+        challenge: &[u8],
+        signature: &[u8; ed25519::SIGNATURE_LEN],
+    ) -> bool {
+        ed25519::verify(&self.0, &signed_message(session, challenge), signature)
+    }
+
+    pub(crate) fn from_bytes(bytes: [u8; ed25519::PUBLIC_KEY_LEN]) -> PublicKey {
+        PublicKey(bytes)
+    }
+
+    pub(crate) fn as_bytes(&self) -> &[u8; ed25519::PUBLIC_KEY_LEN] {
+        &self.0
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", PUBLIC_TAG, to_hex(&self.0))
+    }
+}
+
+/// The secret key of a sender, with which it signs the challenge of the receiver.
+pub struct Identity(SigningKey);
+
+impl Identity {
+    /// Reads the identity in the file at `path`, or creates a new one there if there's no
+    /// file yet, readable only by its owner.
+    pub fn load_or_create(path: &Path) -> Result<Identity> {
+        let invalid = || Error::Other(format!("invalid identity {:?}", path));
+        if path.exists() {
+            let text = fs::read_to_string(path).map_err(|e| Error::from(e).at(path))?;
+            let secret = lines(&text)
+                .find_map(|line| line.strip_prefix(SECRET_TAG))
+                .and_then(|hex| from_hex(hex.trim()))
+                .ok_or_else(invalid)?;
+            let seed = secret.try_into().map_err(|_| invalid())?;
+            return Ok(Identity(SigningKey::from_seed(&seed)));
+        }
+
+        let mut seed = [0; ed25519::SEED_LEN];
+        random::fill(&mut seed)?;
+        let identity = Identity(SigningKey::from_seed(&seed));
+        let text = format!(
+            "# public key: {}\n{} {}\n",
+            identity.public_key(),
+            SECRET_TAG,
+            to_hex(&seed)
+        );
+        let mut file = OpenOptions::new();
+        file.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut file, 0o600);
+        file.open(path)
+            .and_then(|mut file| file.write_all(text.as_bytes()))
+            .map_err(|e| Error::from(e).at(path))?;
+        out!("created a new identity in {:?}", path);
+        Ok(identity)
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.0.public_key())
+    }
+
+    /// Signs the challenge the receiver sent in the session.
+    pub(crate) fn sign(&self, session: u64, challenge: &[u8]) -> [u8; ed25519::SIGNATURE_LEN] {
+        self.0.sign(&signed_message(session, challenge))
+    }
+}
+
+/// The senders a receiver accepts files from, by their public key, with what it knows them by.
+pub struct AuthorizedSenders(Vec<(PublicKey, String)>);
+
+impl AuthorizedSenders {
+    /// Reads the list in the file at `path`.
+    pub fn load(path: &Path) -> Result<AuthorizedSenders> {
+        let text = fs::read_to_string(path).map_err(|e| Error::from(e).at(path))?;
+        let mut senders = Vec::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            // the comment is whatever follows the key
+            let mut words = line.splitn(3, ' ');
+            let key = format!(
+                "{} {}",
+                words.next().unwrap_or_default(),
+                words.next().unwrap_or_default()
+            );
+            let key = PublicKey::parse(&key).ok_or_else(|| {
+                Error::Other(format!("invalid key in {:?}, line {}", path, i + 1))
+            })?;
+            senders.push((key, words.next().unwrap_or_default().trim().to_owned()));
+        }
+        Ok(AuthorizedSenders(senders))
+    }
+
+    /// How the sender with the key is known, if it's one of those accepted: its comment, or
+    /// the key itself if it has none.
+    pub(crate) fn find(&self, key: &PublicKey) -> Option<String> {
+        self.0
+            .iter()
+            .find(|(known, _)| known == key)
+            .map(|(key, comment)| match comment.as_str() {
+                "" => key.to_string(),
+                comment => comment.to_owned(),
+            })
+    }
+}
+
+/// A secret a receiver gives out, which the senders prove they know to be accepted.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SessionKey([u8; SESSION_KEY_LEN]);
+
+impl SessionKey {
+    /// Makes up a new random key.
+    pub fn generate() -> Result<SessionKey> {
+        let mut key = [0; SESSION_KEY_LEN];
+        random::fill(&mut key)?;
+        Ok(SessionKey(key))
+    }
+
+    /// Parses a key in the form it's displayed, in hex.
+    pub fn parse(text: &str) -> Option<SessionKey> {
+        Some(SessionKey(from_hex(text)?.try_into().ok()?))
+    }
+
+    /// Proves the key is known by authenticating the challenge the receiver sent in the
+    /// session with it.
+    pub(crate) fn prove(&self, session: u64, challenge: &[u8]) -> Digest {
+        let mut message = KEY_CONTEXT.to_vec();
+        message.extend(&session.to_le_bytes());
+        message.extend(challenge);
+        hmac_sha256(&self.0, &message)
+    }
+
+    /// Whether the `proof` was made with this key, taking as long whatever it is.
+    pub(crate) fn verify(&self, session: u64, challenge: &[u8], proof: &Digest) -> bool {
+        let expected = self.prove(session, challenge);
+        expected
+            .iter()
+            .zip(proof)
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl fmt::Display for SessionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&to_hex(&self.0))
+    }
+}
+
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+fn signed_message(session: u64, challenge: &[u8]) -> Vec<u8> {
+    let mut message = SIGNATURE_CONTEXT.to_vec();
+    message.extend(&session.to_le_bytes());
+    message.extend(challenge);
+    message
+}
@@ -0,0 +1,391 @@
+//! A decoder for DEFLATE (RFC 1951), the compression used by gzip and zip, which reads the
+//! compressed data as it arrives. Codes are decoded one bit at a time, as in zlib's puff,
+//! which is simple rather than fast, but still faster than most networks.
+
+use std::io::{self, BufRead, Read};
+
+pub(crate) const WINDOW_SIZE: usize = 32 * 1024; // farthest back a match can refer to
+pub(crate) const MAX_BITS: usize = 15; // longest code
+pub(crate) const MAX_LITERAL_CODES: usize = 286;
+pub(crate) const MAX_DISTANCE_CODES: usize = 30;
+const FIXED_LITERAL_CODES: usize = 288;
+pub(crate) const END_OF_BLOCK: u16 = 256;
+
+// the length and distance of a match are a base plus as many extra bits as given
+pub(crate) const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131,
+    163, 195, 227, 258,
+];
+pub(crate) const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+pub(crate) const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537,
+    2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+pub(crate) const DISTANCE_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13,
+    13,
+];
+// the order in which the lengths of the code length code are sent
+pub(crate) const CODE_LENGTH_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+/// Decompresses a single DEFLATE stream read from `input`, which is left right after the
+/// end of the stream once it's been read in full.
+pub struct Inflate<R> {
+    input: Bits<R>,
+    window: Vec<u8>,
+    // how many bytes were decompressed, which is also where the next one goes in the window
+    total: u64,
+    state: State,
+    last_block: bool,
+    // what is left to copy from a match that didn't fit in the last read
+    copy_len: usize,
+    copy_distance: usize,
+}
+
+enum State {
+    Header,
+    Stored(usize),
+    Codes(Box<(Huffman, Huffman)>),
+    Done,
+}
+
+impl<R: BufRead> Inflate<R> {
+    pub fn new(input: R) -> Self {
+        Self {
+            input: Bits {
+                input,
+                bits: 0,
+                count: 0,
+            },
+            window: vec![0; WINDOW_SIZE],
+            total: 0,
+            state: State::Header,
+            last_block: false,
+            copy_len: 0,
+            copy_distance: 0,
+        }
+    }
+
+    /// The input, which after the end of the stream continues with whatever follows it.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.input.input
+    }
+
+    /// Starts decompressing a new stream from the input.
+    pub fn reset(&mut self) {
+        self.input.bits = 0;
+        self.input.count = 0;
+        self.total = 0;
+        self.state = State::Header;
+        self.last_block = false;
+        self.copy_len = 0;
+    }
+
+    fn put(&mut self, byte: u8, buf: &mut [u8], n: &mut usize) {
+        buf[*n] = byte;
+        *n += 1;
+        self.window[self.total as usize % WINDOW_SIZE] = byte;
+        self.total += 1;
+    }
+
+    // Read the header of the next block, and set the state to decode its data.
+    fn start_block(&mut self) -> io::Result<()> {
+        if self.last_block {
+            self.state = State::Done;
+            return Ok(());
+        }
+        self.last_block = self.input.bits(1)? == 1;
+        self.state = match self.input.bits(2)? {
+            0 => {
+                // the lengths of stored blocks start at the next byte
+                self.input.bits = 0;
+                self.input.count = 0;
+                let len = self.input.bits(16)?;
+                if self.input.bits(16)? != !len & 0xffff {
+                    return Err(invalid("stored block length does not match its complement"));
+                }
+                State::Stored(len as usize)
+            }
+            1 => State::Codes(Box::new(fixed_codes())),
+            2 => State::Codes(Box::new(self.dynamic_codes()?)),
+            _ => return Err(invalid("invalid block type")),
+        };
+        Ok(())
+    }
+
+    // Read the codes a dynamic block was compressed with.
+    fn dynamic_codes(&mut self) -> io::Result<(Huffman, Huffman)> {
+        let literal_count = self.input.bits(5)? as usize + 257;
+        let distance_count = self.input.bits(5)? as usize + 1;
+        let length_count = self.input.bits(4)? as usize + 4;
+        if literal_count > MAX_LITERAL_CODES || distance_count > MAX_DISTANCE_CODES {
+            return Err(invalid("too many codes"));
+        }
+
+        let mut lengths = [0; 19];
+        for &i in &CODE_LENGTH_ORDER[..length_count] {
+            lengths[i] = self.input.bits(3)? as u8;
+        }
+        let length_code = Huffman::new(&lengths)?;
+
+        // both sets of lengths are sent as one, and repeats can go from one into the other
+        let mut lengths = vec![0u8; literal_count + distance_count];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = self.input.decode(&length_code)?;
+            let (value, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 if i > 0 => (lengths[i - 1], 3 + self.input.bits(2)? as usize),
+                16 => return Err(invalid("repeated length with no previous one")),
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(invalid("too many code lengths"));
+            }
+            lengths[i..i + repeat].fill(value);
+            i += repeat;
+        }
+        if lengths[END_OF_BLOCK as usize] == 0 {
+            return Err(invalid("no code for the end of the block"));
+        }
+        let (literals, distances) = lengths.split_at(literal_count);
+        Ok((Huffman::new(literals)?, Huffman::new(distances)?))
+    }
+}
+
+impl<R: BufRead> Read for Inflate<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut n = 0;
+        while n < buf.len() {
+            if self.copy_len > 0 {
+                let from = (self.total as usize + WINDOW_SIZE - self.copy_distance) % WINDOW_SIZE;
+                self.put(self.window[from], buf, &mut n);
+                self.copy_len -= 1;
+                continue;
+            }
+            match &mut self.state {
+                State::Header => self.start_block()?,
+                State::Stored(0) => self.state = State::Header,
+                State::Stored(left) => {
+                    let available = self.input.input.fill_buf()?;
+                    if available.is_empty() {
+                        return Err(truncated());
+                    }
+                    let len = (*left).min(available.len()).min(buf.len() - n);
+                    *left -= len;
+                    buf[n..n + len].copy_from_slice(&available[..len]);
+                    for &byte in &available[..len] {
+                        self.window[self.total as usize % WINDOW_SIZE] = byte;
+                        self.total += 1;
+                    }
+                    self.input.input.consume(len);
+                    n += len;
+                }
+                State::Codes(codes) => {
+                    let (literals, distances) = &**codes;
+                    let symbol = self.input.decode(literals)?;
+                    if symbol < 256 {
+                        self.put(symbol as u8, buf, &mut n);
+                        continue;
+                    }
+                    if symbol == END_OF_BLOCK {
+                        self.state = State::Header;
+                        continue;
+                    }
+                    let i = symbol as usize - 257;
+                    if i >= LENGTH_BASE.len() {
+                        return Err(invalid("invalid length code"));
+                    }
+                    let len = LENGTH_BASE[i] as usize + self.input.bits(LENGTH_EXTRA[i])? as usize;
+                    let i = self.input.decode(distances)? as usize;
+                    if i >= DISTANCE_BASE.len() {
+                        return Err(invalid("invalid distance code"));
+                    }
+                    let distance =
+                        DISTANCE_BASE[i] as usize + self.input.bits(DISTANCE_EXTRA[i])? as usize;
+                    if distance as u64 > self.total {
+                        return Err(invalid("distance goes back before the start"));
+                    }
+                    self.copy_len = len;
+                    self.copy_distance = distance;
+                }
+                State::Done => break,
+            }
+        }
+        Ok(n)
+    }
+}
+
+// Reads the input a few bits at a time, taking no more bytes from it than needed.
+struct Bits<R> {
+    input: R,
+    bits: u32,
+    count: u8,
+}
+
+impl<R: BufRead> Bits<R> {
+    fn bits(&mut self, n: u8) -> io::Result<u32> {
+        while self.count < n {
+            let byte = match self.input.fill_buf()?.first() {
+                Some(&byte) => byte,
+                None => return Err(truncated()),
+            };
+            self.input.consume(1);
+            self.bits |= (byte as u32) << self.count;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << n) - 1);
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    // Codes are sent starting from their most significant bit, unlike everything else.
+    fn decode(&mut self, huffman: &Huffman) -> io::Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..=MAX_BITS {
+            code |= self.bits(1)? as i32;
+            let count = huffman.counts[len] as i32;
+            if code - count < first {
+                return Ok(huffman.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid code"))
+    }
+}
+
+// A canonical Huffman code, from how many codes there are of each length and the symbols
+// sorted by their code.
+struct Huffman {
+    counts: [u16; MAX_BITS + 1],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> io::Result<Self> {
+        let mut counts = [0u16; MAX_BITS + 1];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+        // incomplete codes are fine, since a code that isn't there fails once decoded
+        let mut left = 1i32;
+        for &count in &counts[1..] {
+            left = (left << 1) - count as i32;
+            if left < 0 {
+                return Err(invalid("over-subscribed code"));
+            }
+        }
+
+        let mut offsets = [0u16; MAX_BITS + 2];
+        for len in 1..=MAX_BITS {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Ok(Self { counts, symbols })
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; FIXED_LITERAL_CODES];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    let literals = Huffman::new(&lengths).expect("fixed code is valid");
+    let distances = Huffman::new(&[5; MAX_DISTANCE_CODES]).expect("fixed code is valid");
+    (literals, distances)
+}
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("corrupt compressed data: {}", reason),
+    )
+}
+
+fn truncated() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "the compressed data ends before its last block",
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::from_hex;
+
+    fn inflate(stream: &[u8]) -> io::Result<Vec<u8>> {
+        let mut data = Vec::new();
+        Inflate::new(stream).read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    // Streams made by zlib, with a block of every kind.
+    #[test]
+    fn known_streams_are_decoded() {
+        let dynamic = b"dacbcbabaabbaabdcbbaaaddbadbacabaabbadbaaabbbabbacbadbaadbbbdbbaacaddaaa\
+            aaddabbdbabaabdbadadcabdabcbababdbabcdbacacaaacb";
+        let streams: [(&str, &[u8]); 6] = [
+            ("0300", b""),
+            ("010500faff68656c6c6f", b"hello"),
+            ("cb48cdc9c90700", b"hello"),
+            // a match that goes on into what it copies
+            ("4b4c840100", b"aaaaaaaaaa"),
+            ("4b4c4a4e444500", b"abcabcabcabcabcabc"),
+            (
+                "2d8c810d00510c41677dd87f86a3ff124d4b112c0b8136f1168928fceb99a61e851f4d69e675cd\
+                 7019265d6471d278b56bbf87afd2f55a1f",
+                dynamic,
+            ),
+        ];
+        for (stream, data) in streams {
+            let stream = from_hex(stream).unwrap();
+            assert_eq!(inflate(&stream).unwrap(), data, "{:?}", data);
+        }
+    }
+
+    // What follows the stream is left to be read, even within the same buffer.
+    #[test]
+    fn only_the_stream_is_read() {
+        let mut input = from_hex("cb48cdc9c90700").unwrap();
+        input.extend(b"rest");
+        let mut inflate = Inflate::new(&input[..]);
+        let mut data = Vec::new();
+        inflate.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"hello");
+        assert_eq!(*inflate.get_mut(), b"rest");
+    }
+
+    #[test]
+    fn broken_streams_are_refused() {
+        let streams = [
+            // the reserved block type
+            ("07", io::ErrorKind::InvalidData),
+            ("010500fbff68656c6c6f", io::ErrorKind::InvalidData),
+            ("010500faff6865", io::ErrorKind::UnexpectedEof),
+            ("cb48cd", io::ErrorKind::UnexpectedEof),
+            // a match before any data, a fixed block with length 3 at distance 1
+            ("0302", io::ErrorKind::InvalidData),
+        ];
+        for (stream, kind) in streams {
+            let e = inflate(&from_hex(stream).unwrap()).unwrap_err();
+            assert_eq!(e.kind(), kind, "{}: {}", stream, e);
+        }
+    }
+}
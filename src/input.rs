@@ -0,0 +1,83 @@
+//! The lines typed on the standard input.
+//!
+//! A single thread reads them all, so that a question asked mid-transfer doesn't compete with
+//! whatever else listens to the input. Each line goes to the question waiting for an answer, if
+//! there is one, or to the handler set with [`on_line`] otherwise.
+
+#[cfg(not(test))]
+use std::io::{self, BufRead};
+use std::sync::{Mutex, OnceLock};
+#[cfg(not(test))]
+use std::thread;
+use tokio::sync::oneshot;
+
+type Handler = Box<dyn Fn(String) + Send>;
+
+#[derive(Default)]
+struct Consumers {
+    // who gets the lines nobody asked for
+    handler: Option<Handler>,
+    // the questions waiting for their answer, oldest first
+    waiting: Vec<oneshot::Sender<String>>,
+    // the input was closed, so no more lines will come
+    closed: bool,
+}
+
+static CONSUMERS: OnceLock<Mutex<Consumers>> = OnceLock::new();
+
+fn consumers() -> &'static Mutex<Consumers> {
+    CONSUMERS.get_or_init(|| {
+        // the tests give the answers themselves, rather than wait on whoever runs them
+        #[cfg(not(test))]
+        // nothing ever stops the wait for the next line, so the thread is left behind at the end
+        thread::spawn(|| {
+            for line in io::stdin().lock().lines().map_while(Result::ok) {
+                let _ = take(line);
+            }
+            let mut consumers = consumers().lock().unwrap();
+            consumers.closed = true;
+            // dropping them tells the questions that there won't be an answer
+            consumers.waiting.clear();
+        });
+        Mutex::default()
+    })
+}
+
+// Hands the line to the oldest question waiting for an answer, if there is one, and says
+// whether there was.
+fn take(line: String) -> bool {
+    let mut consumers = consumers().lock().unwrap();
+    if !consumers.waiting.is_empty() {
+        let _ = consumers.waiting.remove(0).send(line);
+        return true;
+    }
+    if let Some(handler) = &consumers.handler {
+        handler(line);
+    }
+    false
+}
+
+// Answers the question waiting, as if the line had been typed, and says whether one was.
+#[cfg(test)]
+pub(crate) fn answer(line: &str) -> bool {
+    !consumers().lock().unwrap().waiting.is_empty() && take(line.to_owned())
+}
+
+/// Calls `handler` with every line which isn't the answer to a question, starting to read the
+/// input if nothing did yet. Replaces the previous handler.
+pub fn on_line(handler: impl Fn(String) + Send + 'static) {
+    consumers().lock().unwrap().handler = Some(Box::new(handler));
+}
+
+// Waits for the next line without blocking the runtime, or `None` once the input is closed.
+pub(crate) async fn read_line() -> Option<String> {
+    let (answer, line) = oneshot::channel();
+    {
+        let mut consumers = consumers().lock().unwrap();
+        if consumers.closed {
+            return None;
+        }
+        consumers.waiting.push(answer);
+    }
+    line.await.ok()
+}
@@ -1,37 +1,105 @@
 use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-#[derive(Clone, Copy, Debug)]
-pub struct Address {
+// How the interfaces of containers, virtual machines and tunnels are usually named. Their
+// networks rarely have the peer in them, so broadcasting there doesn't reach anyone.
+const VIRTUAL_PREFIXES: [&str; 13] = [
+    "docker", "veth", "br-", "virbr", "vbox", "vmnet", "tun", "tap", "utun", "bridge", "lxcbr",
+    "lxdbr", "cni",
+];
+// The same, in the descriptive names used by Windows.
+const VIRTUAL_PARTS: [&str; 6] = [
+    "virtualbox",
+    "vmware",
+    "vethernet",
+    "hyper-v",
+    "docker",
+    "wsl",
+];
+
+/// An address of a network interface, along with what is known about the interface.
+#[derive(Clone, Debug)]
+pub struct NetInterface {
+    pub name: String,
     pub ip: IpAddr,
-    pub subnet_mask: IpAddr,
+    pub netmask: IpAddr,
+    /// Where to send packets for every host in the network, if the interface supports it.
+    pub broadcast: Option<IpAddr>,
+    pub is_wireless: bool,
+    /// Whether the default route goes through the interface, which is then almost always
+    /// the one connected to the local network.
+    pub default_route: bool,
+    /// Whether the interface seems to belong to a container, virtual machine or tunnel.
+    pub is_virtual: bool,
+    /// Whether the interface is up and can handle packets.
+    pub is_up: bool,
+    /// The largest packet the interface can send, if known.
+    pub mtu: Option<u32>,
+    /// Whether the system considers the network of the interface metered, such as a phone's
+    /// hotspot, where data is limited or paid for. Only known on Windows and macOS.
+    pub is_metered: bool,
+}
+
+// The netmask with the given amount of leading ones.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn prefix_netmask(ip: IpAddr, len: u8) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => Ipv4Addr::from(u32::MAX.checked_shl(32 - len as u32).unwrap_or(0)).into(),
+        IpAddr::V6(_) => {
+            Ipv6Addr::from(u128::MAX.checked_shl(128 - len as u32).unwrap_or(0)).into()
+        }
+    }
+}
+
+fn is_virtual_name(name: &str) -> bool {
+    let name = name.to_lowercase();
+    VIRTUAL_PREFIXES
+        .iter()
+        .any(|prefix| name.starts_with(prefix))
+        || VIRTUAL_PARTS.iter().any(|part| name.contains(part))
 }
 
-/// Returns a list of addresses whose interface is up and can handle packets.
+// The address every host in the network of `ip` listens to. IPv6 has no such thing.
+fn broadcast_of(ip: IpAddr, netmask: IpAddr) -> Option<IpAddr> {
+    match (ip, netmask) {
+        (IpAddr::V4(ip), IpAddr::V4(mask)) => {
+            Some(Ipv4Addr::from(u32::from(ip) | !u32::from(mask)).into())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the addresses of every interface, except for loopback ones.
 #[cfg(windows)]
-pub fn get_ip_addresses() -> io::Result<Vec<Address>> {
+pub fn get_ip_addresses() -> io::Result<Vec<NetInterface>> {
     use winapi::shared::ifdef::IfOperStatusUp;
-    use winapi::shared::ipifcons::IF_TYPE_SOFTWARE_LOOPBACK;
+    use winapi::shared::ipifcons::{IF_TYPE_IEEE80211, IF_TYPE_SOFTWARE_LOOPBACK, IF_TYPE_TUNNEL};
     use winapi::shared::winerror::{ERROR_BUFFER_OVERFLOW, ERROR_SUCCESS};
     use winapi::shared::ws2def::{AF_INET, AF_INET6, SOCKADDR_IN};
     use winapi::shared::ws2ipdef::SOCKADDR_IN6;
     use winapi::um::iptypes::{
-        GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_FRIENDLY_NAME,
-        GAA_FLAG_SKIP_MULTICAST, IP_ADAPTER_ADDRESSES,
+        GAA_FLAG_SKIP_ANYCAST, GAA_FLAG_SKIP_DNS_SERVER, GAA_FLAG_SKIP_MULTICAST,
+        IP_ADAPTER_ADDRESSES,
     };
 
     let mut result = Vec::new();
 
+    // nothing is sent to the address, it's only used to find the route packets would take
+    let mut default_index = 0;
+    let public = u32::from_ne_bytes(Ipv4Addr::new(1, 1, 1, 1).octets());
+    if unsafe { winapi::um::iphlpapi::GetBestInterface(public, &mut default_index) }
+        != ERROR_SUCCESS
+    {
+        default_index = 0;
+    }
+
     let mut buffer_size: u32 = 16 * 1024;
     let adapter_addresses = loop {
         let mut adapter_addresses = vec![0u8; buffer_size as usize];
         let error = unsafe {
             winapi::um::iphlpapi::GetAdaptersAddresses(
                 AF_INET as u32, // AF_INET
-                GAA_FLAG_SKIP_ANYCAST
-                    | GAA_FLAG_SKIP_MULTICAST
-                    | GAA_FLAG_SKIP_DNS_SERVER
-                    | GAA_FLAG_SKIP_FRIENDLY_NAME,
+                GAA_FLAG_SKIP_ANYCAST | GAA_FLAG_SKIP_MULTICAST | GAA_FLAG_SKIP_DNS_SERVER,
                 std::ptr::null_mut(),
                 adapter_addresses.as_mut_ptr() as *mut IP_ADAPTER_ADDRESSES,
                 &mut buffer_size as *mut u32,
@@ -49,35 +117,52 @@ pub fn get_ip_addresses() -> io::Result<Vec<Address>> {
         unsafe { (adapter_addresses.as_ptr() as *const IP_ADAPTER_ADDRESSES).as_ref() };
 
     while let Some(adapter) = adapter_ref {
-        if adapter.IfType == IF_TYPE_SOFTWARE_LOOPBACK || adapter.OperStatus != IfOperStatusUp {
+        if adapter.IfType == IF_TYPE_SOFTWARE_LOOPBACK {
             adapter_ref = unsafe { adapter.Next.as_ref() };
             continue;
         }
 
+        let name = {
+            let mut len = 0;
+            while unsafe { *adapter.FriendlyName.add(len) } != 0 {
+                len += 1;
+            }
+            String::from_utf16_lossy(unsafe {
+                std::slice::from_raw_parts(adapter.FriendlyName, len)
+            })
+        };
+
+        let is_metered = is_metered(&adapter.Luid);
         let mut address_ref = unsafe { adapter.FirstUnicastAddress.as_ref() };
         while let Some(address) = address_ref {
             let sock_addr = unsafe { *address.Address.lpSockaddr };
-            match sock_addr.sa_family as i32 {
+            let ip: IpAddr = match sock_addr.sa_family as i32 {
                 AF_INET => {
                     let ipv4 = unsafe { *(address.Address.lpSockaddr as *const SOCKADDR_IN) };
                     let addr = unsafe { ipv4.sin_addr.S_un.S_addr() };
-                    let mask = (1u32 << address.OnLinkPrefixLength) - 1;
-                    result.push(Address {
-                        ip: Ipv4Addr::from(addr.to_be()).into(),
-                        subnet_mask: Ipv4Addr::from(mask.to_be()).into(),
-                    });
+                    Ipv4Addr::from(addr.to_be()).into()
                 }
                 AF_INET6 => {
                     let ipv6 = unsafe { *(address.Address.lpSockaddr as *const SOCKADDR_IN6) };
                     let addr = unsafe { ipv6.sin6_addr.u.Byte() };
-                    let mask = 1u128 << address.OnLinkPrefixLength;
-                    result.push(Address {
-                        ip: Ipv6Addr::from(*addr).into(),
-                        subnet_mask: Ipv6Addr::from(mask.to_be()).into(),
-                    });
+                    Ipv6Addr::from(*addr).into()
                 }
                 family => panic!("invalid socket address family {}", family),
-            }
+            };
+            let netmask = prefix_netmask(ip, address.OnLinkPrefixLength);
+            result.push(NetInterface {
+                name: name.clone(),
+                ip,
+                netmask,
+                broadcast: broadcast_of(ip, netmask),
+                default_route: default_index != 0
+                    && unsafe { adapter.u.s().IfIndex } == default_index,
+                is_wireless: adapter.IfType == IF_TYPE_IEEE80211,
+                is_virtual: adapter.IfType == IF_TYPE_TUNNEL || is_virtual_name(&name),
+                is_up: adapter.OperStatus == IfOperStatusUp,
+                mtu: Some(adapter.Mtu),
+                is_metered,
+            });
 
             address_ref = unsafe { address.Next.as_ref() };
         }
@@ -88,80 +173,340 @@ pub fn get_ip_addresses() -> io::Result<Vec<Address>> {
     Ok(result)
 }
 
-/// Returns a list of addresses whose interface is up and can handle packets.
+// Whether the Windows Connection Manager says using the network of the adapter costs, be it
+// a fixed amount up to some limit or a variable one per byte.
+#[cfg(windows)]
+fn is_metered(luid: &winapi::shared::ifdef::NET_LUID) -> bool {
+    use std::os::raw::c_void;
+    use std::ptr;
+    use winapi::shared::guiddef::GUID;
+    use winapi::shared::netioapi::ConvertInterfaceLuidToGuid;
+
+    // wcmapi.h
+    const WCM_INTF_PROPERTY_CONNECTION_COST: u32 = 4;
+    const WCM_CONNECTION_COST_FIXED: u32 = 0x2;
+    const WCM_CONNECTION_COST_VARIABLE: u32 = 0x4;
+    #[repr(C)]
+    struct WcmConnectionCostData {
+        connection_cost: u32,
+        cost_source: u32,
+    }
+    #[link(name = "wcmapi")]
+    extern "system" {
+        fn WcmQueryProperty(
+            interface: *const GUID,
+            profile_name: *const u16,
+            property: u32,
+            reserved: *mut c_void,
+            data_size: *mut u32,
+            data: *mut *mut u8,
+        ) -> u32;
+        fn WcmFreeMemory(memory: *mut c_void);
+    }
+
+    let mut guid = unsafe { std::mem::zeroed::<GUID>() };
+    if unsafe { ConvertInterfaceLuidToGuid(luid, &mut guid) } != 0 {
+        return false;
+    }
+    let (mut size, mut data) = (0, ptr::null_mut());
+    let error = unsafe {
+        WcmQueryProperty(
+            &guid,
+            ptr::null(),
+            WCM_INTF_PROPERTY_CONNECTION_COST,
+            ptr::null_mut(),
+            &mut size,
+            &mut data,
+        )
+    };
+    if error != 0 || data.is_null() {
+        return false;
+    }
+    let cost = if size as usize >= std::mem::size_of::<WcmConnectionCostData>() {
+        unsafe { (*(data as *const WcmConnectionCostData)).connection_cost }
+    } else {
+        0
+    };
+    unsafe { WcmFreeMemory(data as *mut c_void) };
+    cost & (WCM_CONNECTION_COST_FIXED | WCM_CONNECTION_COST_VARIABLE) != 0
+}
+
+// The layout of socket addresses differs in the header, where the BSDs (macOS included) store
+// the length before a smaller family, and in the value of the families themselves.
 #[cfg(not(windows))]
 #[allow(non_camel_case_types)]
-pub fn get_ip_addresses() -> io::Result<Vec<Address>> {
-    use std::ptr;
+mod sys {
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    )))]
+    mod os {
+        pub type sa_family_t = u16;
 
-    type in_port_t = u16;
-    type sa_family_t = u16;
+        // socket.h
+        pub const AF_INET: sa_family_t = 2;
+        pub const AF_INET6: sa_family_t = 10;
 
-    // socket.h
-    const AF_INET: u16 = 2;
-    const AF_INET6: u16 = 10;
+        #[repr(C)]
+        #[derive(Debug, Clone, Copy)]
+        pub struct sockaddr_header {
+            pub sa_family: sa_family_t,
+        }
 
-    // idk
-    #[repr(C)]
-    struct sockaddr {
-        sa_family: sa_family_t,
-        sa_data: [u8; 0],
+        impl sockaddr_header {
+            // How many bytes of the address can be read, at most `max`.
+            pub fn len(&self, max: usize) -> usize {
+                max
+            }
+        }
+
+        // Linux describes its interfaces in sysfs.
+        pub fn is_wireless(name: &str) -> bool {
+            std::path::Path::new(&format!("/sys/class/net/{}/wireless", name)).exists()
+        }
+
+        pub fn mtu(name: &str) -> Option<u32> {
+            std::fs::read_to_string(format!("/sys/class/net/{}/mtu", name))
+                .ok()?
+                .trim()
+                .parse()
+                .ok()
+        }
+
+        // The names of the interfaces with a default route, for either IP version.
+        pub fn default_route_names() -> Vec<String> {
+            let mut names = Vec::new();
+            // columns: interface, destination, gateway, flags, ..., mask
+            if let Ok(table) = std::fs::read_to_string("/proc/net/route") {
+                for line in table.lines().skip(1) {
+                    let columns = line.split_whitespace().collect::<Vec<_>>();
+                    if columns.len() > 7 && columns[1] == "00000000" && columns[7] == "00000000" {
+                        names.push(columns[0].to_string());
+                    }
+                }
+            }
+            // columns: destination, prefix length, source, prefix length, next hop, metric,
+            // references, uses, flags, interface
+            if let Ok(table) = std::fs::read_to_string("/proc/net/ipv6_route") {
+                for line in table.lines() {
+                    let columns = line.split_whitespace().collect::<Vec<_>>();
+                    if columns.len() > 9
+                        && columns[1] == "00"
+                        && columns[0].bytes().all(|c| c == b'0')
+                        && columns[9] != "lo"
+                    {
+                        names.push(columns[9].to_string());
+                    }
+                }
+            }
+            names
+        }
+        // Nothing marks them as such short of asking NetworkManager, which may not be there.
+        pub fn metered_names() -> Vec<String> {
+            Vec::new()
+        }
+    }
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "dragonfly",
+        target_os = "openbsd",
+        target_os = "netbsd"
+    ))]
+    mod os {
+        pub type sa_family_t = u8;
+
+        // sys/socket.h
+        pub const AF_INET: sa_family_t = 2;
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        pub const AF_INET6: sa_family_t = 30;
+        #[cfg(any(target_os = "freebsd", target_os = "dragonfly"))]
+        pub const AF_INET6: sa_family_t = 28;
+        #[cfg(any(target_os = "openbsd", target_os = "netbsd"))]
+        pub const AF_INET6: sa_family_t = 24;
+
+        #[repr(C)]
+        #[derive(Debug, Clone, Copy)]
+        pub struct sockaddr_header {
+            pub sa_len: u8,
+            pub sa_family: sa_family_t,
+        }
+
+        impl sockaddr_header {
+            // How many bytes of the address can be read, at most `max`. Netmasks are often
+            // shorter than the address they belong to, with the rest being implicitly zero.
+            pub fn len(&self, max: usize) -> usize {
+                (self.sa_len as usize).min(max)
+            }
+        }
+
+        // Neither can be known without asking the system in ways that differ between each.
+        pub fn is_wireless(_name: &str) -> bool {
+            false
+        }
+
+        pub fn mtu(_name: &str) -> Option<u32> {
+            None
+        }
+
+        // The names of the interfaces with a default route, for either IP version, as told
+        // by `route`, since asking the routing socket directly takes a lot more.
+        pub fn default_route_names() -> Vec<String> {
+            let mut names = Vec::new();
+            for family in ["-inet", "-inet6"] {
+                let output = std::process::Command::new("route")
+                    .args(["-n", "get", family, "default"])
+                    .stderr(std::process::Stdio::null())
+                    .output();
+                if let Ok(output) = output {
+                    for line in String::from_utf8_lossy(&output.stdout).lines() {
+                        if let Some(name) = line.trim().strip_prefix("interface:") {
+                            names.push(name.trim().to_string());
+                        }
+                    }
+                }
+            }
+            names
+        }
+        // The names of the interfaces macOS considers expensive (such as a phone's hotspot)
+        // or constrained (set to use little data), as told by `ifconfig`.
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        pub fn metered_names() -> Vec<String> {
+            let mut names = Vec::new();
+            let output = std::process::Command::new("ifconfig")
+                .arg("-v")
+                .stderr(std::process::Stdio::null())
+                .output();
+            if let Ok(output) = output {
+                let mut current = None;
+                for line in String::from_utf8_lossy(&output.stdout).lines() {
+                    // every interface starts with an unindented "name: flags=..." line
+                    if !line.starts_with(char::is_whitespace) {
+                        current = line.split(':').next().map(str::to_string);
+                    } else if line.trim_start().starts_with("eflags=")
+                        && (line.contains("EXPENSIVE") || line.contains("CONSTRAINED"))
+                    {
+                        names.extend(current.take());
+                    }
+                }
+            }
+            names
+        }
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        pub fn metered_names() -> Vec<String> {
+            Vec::new()
+        }
     }
+    pub use os::*;
+
+    type in_port_t = u16;
+
+    // net/if.h, which agree on these
+    pub const IFF_UP: u32 = 0x1;
+    pub const IFF_BROADCAST: u32 = 0x2;
+    pub const IFF_LOOPBACK: u32 = 0x8;
+    pub const IFF_RUNNING: u32 = 0x40;
 
     // ip(7)
     #[repr(C)]
     #[derive(Debug, Clone, Copy)]
-    struct sockaddr_in {
-        sin_family: sa_family_t,
-        sin_port: in_port_t,
-        sin_addr: in_addr,
-        pad: [u8; 8],
-    };
+    pub struct sockaddr_in {
+        pub sin_header: sockaddr_header,
+        pub sin_port: in_port_t,
+        pub sin_addr: in_addr,
+        pub pad: [u8; 8],
+    }
 
     #[repr(C)]
     #[derive(Debug, Clone, Copy)]
-    struct in_addr {
-        s_addr: u32,
-    };
+    pub struct in_addr {
+        pub s_addr: u32,
+    }
 
     // ipv6(7)
     #[repr(C)]
     #[derive(Debug, Clone, Copy)]
-    struct sockaddr_in6 {
-        sin6_family: sa_family_t,
-        sin6_port: in_port_t,
-        sin6_flowinfo: u32,
-        sin6_addr: in6_addr,
-        sin6_scope_id: u32,
-    };
+    pub struct sockaddr_in6 {
+        pub sin6_header: sockaddr_header,
+        pub sin6_port: in_port_t,
+        pub sin6_flowinfo: u32,
+        pub sin6_addr: in6_addr,
+        pub sin6_scope_id: u32,
+    }
 
     #[repr(C)]
     #[derive(Debug, Clone, Copy)]
-    struct in6_addr {
-        s6_addr: [u8; 16],
-    };
+    pub struct in6_addr {
+        pub s6_addr: [u8; 16],
+    }
 
-    // getifaddrs(3)
+    // getifaddrs(3), the same everywhere
     #[repr(C)]
-    struct ifaddrs {
-        ifa_next: *const ifaddrs,
-        ifa_name: *const u8,
-        ifa_flags: u32,
-        ifa_addr: *const sockaddr,
-        ifa_netmask: *const sockaddr,
-        ifu_dstaddr: *const sockaddr,
-        ifa_data: *const [u8; 0],
-    };
+    pub struct ifaddrs {
+        pub ifa_next: *const ifaddrs,
+        pub ifa_name: *const u8,
+        pub ifa_flags: u32,
+        pub ifa_addr: *const sockaddr_header,
+        pub ifa_netmask: *const sockaddr_header,
+        pub ifu_dstaddr: *const sockaddr_header,
+        pub ifa_data: *const [u8; 0],
+    }
 
     extern "C" {
-        fn getifaddrs(ifap: *const *const ifaddrs) -> u32;
-        fn freeifaddrs(ifa: *const ifaddrs);
+        pub fn getifaddrs(ifap: *const *const ifaddrs) -> i32;
+        pub fn freeifaddrs(ifa: *const ifaddrs);
     }
+}
+
+// Read the IP address of the given family stored at `addr`, whose own family may not be set
+// (as is the case with some netmasks). Bytes missing from the end are taken to be zero.
+#[cfg(not(windows))]
+unsafe fn read_ip(addr: *const sys::sockaddr_header, family: sys::sa_family_t) -> Option<IpAddr> {
+    use std::mem::{size_of, MaybeUninit};
+
+    unsafe fn read<T>(addr: &sys::sockaddr_header) -> T {
+        let mut value = MaybeUninit::<T>::zeroed();
+        let len = addr.len(size_of::<T>());
+        std::ptr::copy_nonoverlapping(
+            addr as *const _ as *const u8,
+            value.as_mut_ptr() as *mut u8,
+            len,
+        );
+        value.assume_init()
+    }
+
+    let addr = addr.as_ref()?;
+    match family {
+        sys::AF_INET => {
+            let ipv4 = read::<sys::sockaddr_in>(addr);
+            Some(Ipv4Addr::from(ipv4.sin_addr.s_addr.to_be()).into())
+        }
+        sys::AF_INET6 => {
+            let ipv6 = read::<sys::sockaddr_in6>(addr);
+            Some(Ipv6Addr::from(ipv6.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+/// Returns the addresses of every interface, except for loopback ones.
+#[cfg(not(windows))]
+pub fn get_ip_addresses() -> io::Result<Vec<NetInterface>> {
+    use std::ffi::CStr;
+    use std::ptr;
 
     let mut result = Vec::new();
 
-    let if_addr_struct: *const ifaddrs = ptr::null();
-    let ret = unsafe { getifaddrs(&if_addr_struct as *const _) };
+    let default_route_names = sys::default_route_names();
+    let metered_names = sys::metered_names();
+
+    let if_addr_struct: *const sys::ifaddrs = ptr::null();
+    let ret = unsafe { sys::getifaddrs(&if_addr_struct as *const _) };
     if ret != 0 {
         return Err(io::Error::last_os_error());
     }
@@ -170,41 +515,44 @@ pub fn get_ip_addresses() -> io::Result<Vec<Address>> {
     while let Some(ifa) = ifa_ref {
         ifa_ref = unsafe { ifa.ifa_next.as_ref() };
 
-        let addr = match unsafe { ifa.ifa_addr.as_ref() } {
-            Some(addr) => addr,
+        let family = match unsafe { ifa.ifa_addr.as_ref() } {
+            Some(addr) => addr.sa_family,
             None => continue,
         };
+        let ip = match unsafe { read_ip(ifa.ifa_addr, family) } {
+            Some(ip) if !ip.is_loopback() && ifa.ifa_flags & sys::IFF_LOOPBACK == 0 => ip,
+            _ => continue,
+        };
+        // point-to-point interfaces may not have a netmask at all
+        let netmask = unsafe { read_ip(ifa.ifa_netmask, family) }.unwrap_or(match ip {
+            IpAddr::V4(_) => Ipv4Addr::BROADCAST.into(),
+            IpAddr::V6(_) => Ipv6Addr::from(u128::MAX).into(),
+        });
+        let broadcast = if ifa.ifa_flags & sys::IFF_BROADCAST != 0 {
+            unsafe { read_ip(ifa.ifu_dstaddr, family) }.or_else(|| broadcast_of(ip, netmask))
+        } else {
+            None
+        };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name as *const _) }
+            .to_string_lossy()
+            .into_owned();
 
-        match addr.sa_family {
-            AF_INET => {
-                let ipv4 = unsafe { *(ifa.ifa_addr as *const sockaddr_in) };
-                let addr = Ipv4Addr::from(ipv4.sin_addr.s_addr.to_be());
-                if !addr.is_loopback() {
-                    let ipv4 = unsafe { *(ifa.ifa_netmask as *const sockaddr_in) };
-                    let mask = Ipv4Addr::from(ipv4.sin_addr.s_addr.to_be());
-                    result.push(Address {
-                        ip: addr.into(),
-                        subnet_mask: mask.into(),
-                    });
-                }
-            }
-            AF_INET6 => {
-                let ipv6 = unsafe { *(ifa.ifa_addr as *const sockaddr_in6) };
-                let addr = Ipv6Addr::from(ipv6.sin6_addr.s6_addr);
-                if !addr.is_loopback() {
-                    let ipv6 = unsafe { *(ifa.ifa_netmask as *const sockaddr_in6) };
-                    let mask = Ipv6Addr::from(ipv6.sin6_addr.s6_addr);
-                    result.push(Address {
-                        ip: addr.into(),
-                        subnet_mask: mask.into(),
-                    });
-                }
-            }
-            _ => {}
-        }
+        result.push(NetInterface {
+            is_wireless: sys::is_wireless(&name),
+            mtu: sys::mtu(&name),
+            default_route: default_route_names.contains(&name),
+            is_metered: metered_names.contains(&name),
+            is_virtual: is_virtual_name(&name),
+            name,
+            ip,
+            netmask,
+            broadcast,
+            is_up: ifa.ifa_flags & (sys::IFF_UP | sys::IFF_RUNNING)
+                == sys::IFF_UP | sys::IFF_RUNNING,
+        });
     }
 
-    unsafe { freeifaddrs(if_addr_struct) };
+    unsafe { sys::freeifaddrs(if_addr_struct) };
 
     Ok(result)
 }
@@ -0,0 +1,183 @@
+//! Running the send jobs kept in the queue, each as a process of its own, so that one failing
+//! in any way leaves the others (and the queue) alone.
+
+use sf::queue::{self, Job, JobState};
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// How often to check whether jobs finished, or new ones can start.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Adds a job to the queue, returning its identifier.
+pub fn add(
+    path: &Path,
+    args: Vec<String>,
+    dir: PathBuf,
+    retries: u64,
+    retry_delay: Duration,
+) -> sf::Result<u64> {
+    queue::update(path, |jobs| {
+        let id = jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1;
+        jobs.push(Job {
+            id,
+            state: JobState::Pending,
+            args,
+            dir,
+            retries,
+            retry_delay,
+            attempts: 0,
+            next_attempt: 0,
+            error: None,
+        });
+        id
+    })
+}
+
+/// Runs the jobs waiting in the queue, up to `parallel` at once, until none are left waiting
+/// (including those that will be tried again). Jobs added meanwhile are run too.
+pub fn run(path: &Path, parallel: usize) -> sf::Result<()> {
+    // those still running were interrupted along with the previous run
+    queue::update(path, |jobs| {
+        for job in jobs.iter_mut().filter(|job| job.state == JobState::Running) {
+            job.state = JobState::Pending;
+        }
+    })?;
+    let exe = env::current_exe()?;
+    let mut running = Vec::<(u64, Child)>::new();
+    loop {
+        let mut finished = Vec::new();
+        running.retain_mut(|(id, child)| match child.try_wait() {
+            Ok(None) => true,
+            Ok(Some(status)) if status.success() => {
+                finished.push((*id, None));
+                false
+            }
+            Ok(Some(status)) => {
+                finished.push((*id, Some(status.to_string())));
+                false
+            }
+            Err(e) => {
+                finished.push((*id, Some(e.to_string())));
+                false
+            }
+        });
+        for (id, error) in finished {
+            finish(path, id, error)?;
+        }
+
+        let now = unix_now();
+        let ready = |job: &Job| job.state == JobState::Pending && job.next_attempt <= now;
+        // only written to when something starts, as it's read often
+        let free = parallel.saturating_sub(running.len());
+        let started = if free > 0 && queue::load(path)?.iter().any(ready) {
+            queue::update(path, |jobs| {
+                jobs.iter_mut()
+                    .filter(|job| ready(job))
+                    .take(free)
+                    .map(|job| {
+                        job.state = JobState::Running;
+                        job.attempts += 1;
+                        job.clone()
+                    })
+                    .collect::<Vec<_>>()
+            })?
+        } else {
+            Vec::new()
+        };
+        for job in started {
+            println!(
+                "starting job {} (attempt {} of {}): {}",
+                job.id,
+                job.attempts,
+                job.retries + 1,
+                job.args.join(" ")
+            );
+            match Command::new(&exe)
+                .args(&job.args)
+                .current_dir(&job.dir)
+                .spawn()
+            {
+                Ok(child) => running.push((job.id, child)),
+                Err(e) => finish(path, job.id, Some(format!("could not start: {}", e)))?,
+            }
+        }
+
+        let waiting = queue::load(path)?
+            .iter()
+            .any(|job| job.state == JobState::Pending);
+        if running.is_empty() && !waiting {
+            break;
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    println!("no jobs left in the queue");
+    Ok(())
+}
+
+/// Prints every job in the queue, and how it went.
+pub fn status(path: &Path) -> sf::Result<()> {
+    let jobs = queue::load(path)?;
+    if jobs.is_empty() {
+        println!("no jobs in the queue");
+        return Ok(());
+    }
+    let now = unix_now();
+    println!("{:>4}  {:<8} {:>8}  ARGUMENTS", "ID", "STATE", "ATTEMPTS");
+    for job in jobs {
+        println!(
+            "{:>4}  {:<8} {:>8}  {}",
+            job.id,
+            job.state,
+            format!("{}/{}", job.attempts, job.retries + 1),
+            job.args.join(" ")
+        );
+        if job.state == JobState::Pending && job.next_attempt > now {
+            println!("      trying again in {}s", job.next_attempt - now);
+        }
+        if let Some(error) = job.error {
+            println!("      last error: {}", error);
+        }
+    }
+    Ok(())
+}
+
+// Records how the last attempt of the job went, and whether to try again.
+fn finish(path: &Path, id: u64, error: Option<String>) -> sf::Result<()> {
+    let now = unix_now();
+    queue::update(path, |jobs| {
+        let Some(job) = jobs.iter_mut().find(|job| job.id == id) else {
+            // removed from the file meanwhile
+            return;
+        };
+        match &error {
+            None => {
+                job.state = JobState::Done;
+                println!("job {} is done", id);
+            }
+            Some(error) if job.attempts <= job.retries => {
+                job.state = JobState::Pending;
+                job.next_attempt = now + job.retry_delay.as_secs();
+                println!(
+                    "job {} failed ({}), trying again in {}s",
+                    id,
+                    error,
+                    job.retry_delay.as_secs()
+                );
+            }
+            Some(error) => {
+                job.state = JobState::Failed;
+                println!("job {} failed ({}), giving up", id, error);
+            }
+        }
+        job.error = error;
+    })
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
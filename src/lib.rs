@@ -0,0 +1,6912 @@
+//! Send files in LAN quickly.
+//!
+//! The transfers talk to their peers through asynchronous I/O on [tokio], so [`send_async`],
+//! [`recv_async`] and the like can be awaited from within a tokio runtime, while [`send`],
+//! [`recv`] and the others run them to completion on a small runtime of their own. These must
+//! not be called from within a runtime, which can't block on another.
+//!
+//! [tokio]: https://tokio.rs
+
+// Whether the transfers should keep what they're doing to themselves.
+static QUIET: AtomicBool = AtomicBool::new(false);
+// Whether what they're doing goes to the standard error, because the output carries data.
+static TO_STDERR: AtomicBool = AtomicBool::new(false);
+// Whether sizes are printed as the exact number of bytes, rather than rounded to a unit.
+static EXACT_SIZES: AtomicBool = AtomicBool::new(false);
+
+// Like `println!`, but only if the output is not being kept quiet. Defined before the
+// modules so that they can use it too. Everything printed is also logged, quiet or not.
+macro_rules! out {
+    ($($arg:tt)*) => {{
+        log::info!(
+            target: $crate::MESSAGES_TARGET,
+            "{}{}",
+            $crate::session::Prefix,
+            format_args!($($arg)*)
+        );
+        if $crate::QUIET.load(std::sync::atomic::Ordering::Relaxed) {
+        } else if $crate::TO_STDERR.load(std::sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    }};
+}
+
+// Like `log::debug!`, but saying which session it's about.
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        log::debug!("{}{}", $crate::session::Prefix, format_args!($($arg)*))
+    };
+}
+
+// Like `log::trace!`, but saying which session it's about.
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        log::trace!("{}{}", $crate::session::Prefix, format_args!($($arg)*))
+    };
+}
+
+mod age;
+mod archive;
+mod attributes;
+mod cancel;
+mod checksums;
+mod compress;
+mod deflate;
+mod ed25519;
+mod error;
+pub mod event;
+mod extract;
+mod filter;
+#[cfg(feature = "fuzzing")]
+#[doc(hidden)]
+pub mod fuzz;
+mod hash;
+pub mod history;
+mod http;
+mod identity;
+mod inflate;
+pub mod input;
+mod ip;
+mod json;
+mod manifest;
+mod names;
+mod net;
+mod owner;
+mod pause;
+mod pipe;
+mod protocol;
+pub mod queue;
+mod random;
+mod reflink;
+#[cfg(feature = "s3")]
+mod s3;
+mod schedule;
+mod session;
+pub mod sink;
+pub mod source;
+mod special;
+mod tar;
+pub mod task;
+mod webhook;
+mod wol;
+mod xattr;
+mod zip;
+
+pub use age::Recipient;
+use cancel::check;
+pub use cancel::CancelToken;
+use checksums::Checksums;
+use compress::{Codec, Compressor, MAX_COMPRESSED_CHUNK};
+use deflate::Level;
+pub use error::Error;
+use event::{emit, emit_progress, emit_skipped, emit_started};
+pub use event::{EventHandler, TransferEvent};
+use extract::Extraction;
+pub use filter::FileFilter;
+pub use identity::{AuthorizedSenders, Identity, PublicKey, SessionKey};
+use inflate::Inflate;
+pub use ip::{get_ip_addresses, NetInterface};
+pub use net::SocketOptions;
+use net::TimedStream;
+pub use owner::Owner;
+pub use pause::PauseToken;
+use protocol::{FileData, Header, ListEntry, Metadata};
+use protocol::{CHUNK_HEADER_LEN, FRAME_HEADER_LEN};
+pub use schedule::{DailyWindow, TimeOfDay};
+pub use sink::Sink;
+use sink::SinkWriter;
+pub use source::Source;
+use std::borrow::Cow;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use walkdir::WalkDir;
+pub use webhook::Webhook;
+pub use wol::{parse_mac_address, wake, MacAddress};
+pub use xattr::XattrNamespace;
+
+// Transfer parameters
+pub const VERSION: u8 = 24;
+const MIN_VERSION: u8 = 2; // oldest version that can still be received
+                           // version used when sending with `legacy`, which any receiver since the first one understands
+pub const LEGACY_VERSION: u8 = MIN_VERSION;
+const DEFAULT_CHUNK_SIZE: usize = 4 * 1024 * 1024; // used before chunk sizes were negotiated
+const MIN_CHUNK_SIZE: usize = 64 * 1024;
+const MAX_AUTO_CHUNK_SIZE: usize = 16 * 1024 * 1024; // largest size picked without being asked
+const MAX_CHUNK_SIZE: usize = 256 * 1024 * 1024;
+const SIGNAL_DELAY: Duration = Duration::from_secs(2);
+const PATH_SEPARATORS: [u8; 2] = [b'/', b'\\'];
+const PARTIAL_EXTENSION: &str = "sf-part";
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MIN_RETRY_DELAY: Duration = Duration::from_millis(250);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(8);
+const ACK: u8 = 0x06;
+const ABORT: u8 = 0x15; // sent instead of any other reply, unlike which it can't be
+const BUSY: u8 = 0x16; // sent instead of taking the transfer, while the receiver is busy
+const ABORT_DELAY: Duration = Duration::from_secs(2); // how long the sender has to read why
+const MAX_REASON_LEN: usize = 4 * 1024; // longest reason given for aborting a transfer
+const CHALLENGE_LEN: usize = 32; // random bytes the sender signs to prove its identity
+const STORED: u8 = 0x11; // how much the receiver has stored, said before any other reply
+const STORED_INTERVAL: Duration = Duration::from_secs(1); // how often the receiver says so
+const STALL_DELAY: Duration = Duration::from_secs(10); // storing nothing for this long is a stall
+const REPLY_CHECK_INTERVAL: Duration = Duration::from_millis(100); // while sending the data
+const BATCH_FILES: usize = 1024; // most files listed in a single batch
+const BATCH_LEN: usize = 256 * 1024; // a batch stops growing once its list is this long
+const MAX_BATCH_LEN: usize = 16 * 1024 * 1024; // longest list accepted in a single batch
+const MAX_LIST_LEN: usize = 256 * 1024 * 1024; // longest list accepted before batches existed
+const MAX_NAME_LEN: usize = 64 * 1024;
+const MAX_FILE_COUNT: usize = 16 * 1024 * 1024; // most files accepted in a single transfer
+const MAX_RESENDS: usize = 3; // most times the files that changed while being sent are sent again
+const MAX_METADATA_LEN: usize = 1024 * 1024; // most metadata sent for a single file
+const PACKED_FILE_LEN: u64 = 64 * 1024; // files up to this size are sent together
+const AUTO_PACK_FILES: usize = 256; // small files past which it's worth packing them
+const METERED_LIMIT: u64 = 1024 * 1024 * 1024; // most sent over a metered network unless allowed
+const MAX_EXPANSION: u64 = 100; // most times its size an archive may unpack to when extracted
+const MIN_EXTRACTED_LEN: u64 = 64 * 1024 * 1024; // what even the smallest archives may unpack to
+
+// Transfer flags
+const FLAG_UPDATE: u8 = 0x01; // skip files that are identical in the receiver
+const FLAG_STORED: u8 = 0x02; // the receiver says how much it has stored every so often
+const FLAG_PACK: u8 = 0x04; // the sender can pack small files into archives to be unpacked
+const FLAG_IDENTITY: u8 = 0x08; // the sender can prove its identity if challenged
+const FLAG_COMPRESS: u8 = 0x10; // the file data is sent in chunks, which may be compressed
+const FLAG_KEY: u8 = 0x20; // the sender can prove it knows the session key if challenged
+
+// Whether the receiver wants a file
+const SKIP: u8 = 0;
+const WANT: u8 = 1;
+const REJECT: u8 = 2; // not sent either, as the receiver leaves it out, such as by its filters
+
+// Whether the sender follows with the data of a wanted file
+const DATA_SENT: u8 = 0;
+const DATA_SKIPPED: u8 = 1; // the sender could not read the file, and says why instead
+
+// Kinds of the frames everything after the header is sent in, since version 23
+const FRAME_FILE_LIST: u8 = 1;
+const FRAME_FILE_DATA: u8 = 2;
+const FRAME_METADATA: u8 = 3;
+const FRAME_ERROR: u8 = 4;
+const FRAME_ACK: u8 = 5;
+const FRAME_EXTENSION: u8 = 6; // skipped by whoever reads it where it expects another frame
+
+// What the receiver was doing when the connection was lost
+const RESUME_DATA: u8 = 0;
+const RESUME_LIST: u8 = 1;
+
+// Tags of the metadata of a file
+const TAG_XATTR: u8 = 1;
+const TAG_HASH: u8 = 2;
+const TAG_ROOT: u8 = 3;
+const TAG_PACK: u8 = 4;
+const TAG_CODEC: u8 = 5;
+const TAG_CREATED: u8 = 6;
+const TAG_ATTRIBUTES: u8 = 7;
+const TAG_DIR: u8 = 8;
+const TAG_SPECIAL: u8 = 9;
+
+// How the data of a file is sent when the sender compresses it
+const CODEC_NONE: u8 = 0; // as it is
+const CODEC_DEFLATE: u8 = 1; // in chunks, each compressed with DEFLATE or not
+
+// Verification results
+const SAME: u8 = 0;
+const MISSING: u8 = 1;
+const MISMATCHED: u8 = 2;
+
+// Connection addresses
+pub const PORT: u16 = 8370; // concat(value of 'S', value of 'F')
+const SIGNALING_PORT: u16 = 8369;
+const CLIENT_BROADCAST_PORT: u16 = 38369;
+const DESCRIPTOR_SCHEME: &str = "sf://";
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// The target of the records logged for what is printed, which frontends logging to the same
+/// place as they print can leave out.
+pub const MESSAGES_TARGET: &str = "sf::messages";
+
+/// Stops the transfers from printing what they're doing to the standard output,
+/// for frontends that show it some other way. Questions are still asked.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether the transfers were told not to print what they're doing with [`set_quiet`].
+pub fn is_quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Makes the transfers print what they're doing to the standard error instead, for when
+/// the standard output carries data.
+pub fn set_messages_to_stderr(to_stderr: bool) {
+    TO_STDERR.store(to_stderr, Ordering::Relaxed);
+}
+
+/// Makes sizes be printed as the exact number of bytes, rather than like `1.4 GiB`.
+pub fn set_exact_sizes(exact: bool) {
+    EXACT_SIZES.store(exact, Ordering::Relaxed);
+}
+
+/// Formats a size for people to read, like `1.4 GiB`, unless exact sizes were asked for
+/// with [`set_exact_sizes`].
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() && !EXACT_SIZES.load(Ordering::Relaxed) {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+/// Formats a duration for people to read, like `4.2s`, `12s`, `3m 05s` or `1h 02m`.
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=9 => format!("{:.1}s", duration.as_secs_f64()),
+        10..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, secs / 60 % 60),
+    }
+}
+
+/// How the receiver names the files it stores.
+#[derive(Clone, Copy, Default)]
+pub enum PathPrefix {
+    /// Keep the names as sent.
+    #[default]
+    Keep,
+    /// Remove the directories all of the names have in common.
+    Strip,
+}
+
+/// Stores the files sent under `from` in `to` instead, such as `home/alice/` in `projects/`.
+/// Both are names as sent, with forward slashes, after any stripping.
+#[derive(Clone, Debug)]
+pub struct PathMap {
+    pub from: Vec<u8>,
+    pub to: Vec<u8>,
+}
+
+/// What the receiver does with names that differ only in case, which are the same file on
+/// case-insensitive filesystems, such as those of Windows and macOS.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CaseCollisions {
+    /// Store them as sent, for filesystems that tell them apart.
+    Ignore,
+    /// Store the later ones with a number after their name, like `README (2).md`.
+    Rename,
+    /// Fail the transfer before receiving them.
+    Abort,
+}
+
+impl Default for CaseCollisions {
+    fn default() -> Self {
+        // the filesystems of these store names that differ only in case as the same file
+        if cfg!(any(windows, target_os = "macos")) {
+            CaseCollisions::Rename
+        } else {
+            CaseCollisions::Ignore
+        }
+    }
+}
+
+/// What the receiver does with a file listed under the same name as another listed before it,
+/// once stripped, mapped and normalized, as when the sender was given overlapping paths.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Duplicates {
+    /// Fail the transfer before receiving it.
+    Error,
+    /// Reject it, so that the file listed first is kept.
+    KeepFirst,
+    /// Store it over the other, so that the file listed last is kept.
+    #[default]
+    KeepLast,
+}
+
+/// The Unicode normalization form the receiver stores the names in, so that the same name
+/// is the same bytes regardless of the system the files come from. macOS uses NFD, for
+/// example, while most other systems use NFC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Normalization {
+    /// Composed characters, such as `é` as a single code point.
+    Nfc,
+    /// Decomposed characters, such as `é` as an `e` followed by a combining accent.
+    Nfd,
+}
+
+/// The order in which the sender sends the files.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Order {
+    /// As they were given, with the files inside each directory sorted by name, so that the
+    /// same files are always listed in the same order.
+    #[default]
+    AsGiven,
+    /// The smallest files first, so that many small files are usable before a large one.
+    SmallFirst,
+    /// The largest files first.
+    LargeFirst,
+    /// By their name, byte by byte.
+    Alpha,
+}
+
+/// When the sender compresses the file data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Send the data as it is.
+    #[default]
+    Never,
+    /// Compress all of it, and send the chunks that don't get smaller as they are.
+    Always,
+    /// Compress the first chunks of every file, and one every so often after them, and only
+    /// the rest too while the data shrinks and compressing it is faster than sending it.
+    Auto,
+}
+
+/// The kinds of archive that files can be sent or stored in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    /// A tar archive compressed with zstd.
+    TarZst,
+    /// A zip archive, with the files stored as they are.
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// The extension of the files in this format, without the leading dot.
+    pub fn extension(self) -> &'static str {
+        match self {
+            ArchiveFormat::Tar => "tar",
+            ArchiveFormat::TarZst => "tar.zst",
+            ArchiveFormat::Zip => "zip",
+        }
+    }
+}
+
+/// How to list the hashes of the received files, to verify them later without sf.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SumsFormat {
+    /// A `SHA256SUMS` file, as `sha256sum` writes and checks it.
+    Sha256Sums,
+    /// A `sf-manifest.json` file, like the manifests written when sending, with the sizes too.
+    Json,
+}
+
+impl SumsFormat {
+    /// The name of the file it's written to, in the output directory.
+    pub fn file_name(self) -> &'static str {
+        match self {
+            SumsFormat::Sha256Sums => "SHA256SUMS",
+            SumsFormat::Json => "sf-manifest.json",
+        }
+    }
+}
+
+/// How [`send`] and the like send the files.
+///
+/// More options may be added at any time, so start from the defaults and change those needed.
+#[derive(Default)]
+#[non_exhaustive]
+pub struct SendOptions {
+    /// Use the oldest protocol version, for receivers not yet upgraded. Only the files
+    /// themselves can be sent with it, without any of the options that need a later version.
+    pub legacy: bool,
+    /// Skip the files the receiver already has with the same size and modification time.
+    pub update: bool,
+    /// Send each directory as a single archive.
+    pub archive: Option<ArchiveFormat>,
+    /// The order in which the files are sent.
+    pub order: Order,
+    /// Send the files under each path, one of those given, under the name paired with it
+    /// instead, such as `release/app` for the file `build/output/app` if `build/output` is
+    /// renamed `release`.
+    pub rename: Vec<(PathBuf, PathBuf)>,
+    /// Send the contents of identical files only once, and let the receiver copy them.
+    pub dedup: bool,
+    /// Have the receiver link the files that are hard links to the same data, rather than
+    /// storing the data once for each of them.
+    pub hardlinks: bool,
+    /// Send the FIFOs found for the receiver to make again, rather than skipping them like
+    /// sockets and devices, which only mean something where they are.
+    pub specials: bool,
+    /// Look only this many directories deep inside the directories given, if there's a limit.
+    pub max_depth: Option<usize>,
+    /// Don't look inside the directories on another file system than the directory given,
+    /// such as mounted network shares, which are sent empty instead.
+    pub one_file_system: bool,
+    /// Send the extended attributes of the files in these namespaces.
+    pub xattrs: Vec<XattrNamespace>,
+    /// Send when every file was created, and whether it's read-only or hidden, for the
+    /// receiver to restore where its platform allows.
+    pub attributes: bool,
+    /// Send the hash of every file along with it, so that a receiver with an index of its
+    /// files can copy those it already has instead. Every file is read one more time for it.
+    pub hashes: bool,
+    /// Have the receiver say every second how much of the data it has stored, rather than only
+    /// once it has all of it, to tell how far along it really is and whether it stalled.
+    pub receiver_progress: bool,
+    /// Send even if the network to the receiver is metered and the files add up to more than
+    /// a gigabyte, rather than only warning about it below that.
+    pub allow_metered: bool,
+    /// Pack the small files of each directory into an archive sent as a single file, if there
+    /// are many of them and the receiver can unpack it, rather than listing each of them.
+    pub auto_pack: bool,
+    /// Send the files that changed while being sent once more at the end of the list, so
+    /// that the receiver has them as they are now, a few times at most. Only since version 7
+    /// can more files be listed once the data is being sent, and the receiver stores them
+    /// over the earlier ones as long as it keeps the last of the files with the same name.
+    pub resend_changed: bool,
+    /// Prove to the receiver that the files come from this identity, which it may require.
+    pub identity: Option<Identity>,
+    /// Prove to the receiver that this is the key it gave out, such as in the descriptor of
+    /// its QR code, which it requires then.
+    pub key: Option<SessionKey>,
+    /// Wait until this time before looking at the files and connecting to the receiver.
+    pub start_at: Option<SystemTime>,
+    /// Compress the file data as it's sent, if at all.
+    pub compress: Compression,
+    /// Write a manifest of the files, with their hashes, here before sending them.
+    pub manifest: Option<PathBuf>,
+    /// Send the files of this source, such as the contents of a tar archive, instead of the
+    /// files given. They are read in full, as they can't be found to be copies or links.
+    pub source: Option<Box<dyn Source + Send + Sync>>,
+    pub socket: SocketOptions,
+    /// How long to wait for the receiver after the connection is lost, if at all.
+    pub reconnect: Option<Duration>,
+    /// How long to keep trying to connect to the receiver before it's listening, if at all.
+    pub retry: Option<Duration>,
+    /// The chunk size to propose, or `None` to pick one based on the file sizes.
+    pub chunk_size: Option<usize>,
+    /// Told about the progress of the transfer.
+    pub events: Option<EventHandler>,
+    /// Stops the transfer once cancelled.
+    pub cancel: Option<CancelToken>,
+}
+
+pub struct VerifyOptions {
+    /// Verify the files in this manifest, written when they were sent, instead of local files.
+    pub manifest: Option<PathBuf>,
+    pub socket: SocketOptions,
+    /// Told about the progress of the verification.
+    pub events: Option<EventHandler>,
+    /// Stops the verification once cancelled.
+    pub cancel: Option<CancelToken>,
+}
+
+/// How [`recv`] and the like receive the files.
+///
+/// More options may be added at any time, so start from the defaults and change those needed.
+/// By default, the files are stored in the current directory.
+#[non_exhaustive]
+pub struct ReceiveOptions {
+    pub prefix: PathPrefix,
+    /// Remove the drive, like `C:`, from the names that have one.
+    pub strip_drive: bool,
+    /// Remove this many leading directories from every name, after the prefix and the drive.
+    /// Files with no more directories than that are rejected.
+    pub strip_components: usize,
+    /// Store the files under other directories than those they were sent in, following the
+    /// first of these rules that applies to each, after stripping their names.
+    pub map: Vec<PathMap>,
+    /// Directory where the received files are stored.
+    pub output: PathBuf,
+    /// Delete the files in the output directory that were not sent.
+    pub mirror: bool,
+    /// Only list the files that `mirror` would delete.
+    pub dry_run: bool,
+    /// Store all the files in a single archive.
+    pub archive: Option<ArchiveFormat>,
+    /// Store the files of every transfer in their own `sf-<timestamp>-<sender>` directory
+    /// inside the output directory, so that transfers from different senders don't mix.
+    pub session_dirs: bool,
+    /// Write the files of every transfer into its own directory inside this one first, and only
+    /// move them into the output directory once all of them were received, and match their
+    /// hash if the sender said it. Which files are unchanged, what copies are made from, and
+    /// the paths reported are still those of the output directory. It must be on the same
+    /// file system as the output directory.
+    pub staging: Option<PathBuf>,
+    /// What to do with names that differ only in case from one received before. Files going
+    /// into an archive or a sink are always stored as sent.
+    pub case_collisions: CaseCollisions,
+    /// What to do with files listed under the same name as another before them.
+    pub duplicates: Duplicates,
+    /// Normalize the received names to this form, if any. Names that are not valid UTF-8
+    /// are left as they are.
+    pub normalize: Option<Normalization>,
+    /// Where the files go instead of the output directory, such as a tar stream on the standard
+    /// output. Every file is received in full, as none can be skipped or copied there.
+    pub sink: Option<Box<dyn Sink + Send + Sync>>,
+    pub socket: SocketOptions,
+    /// How long to wait for the sender after the connection is lost, if at all.
+    pub reconnect: Option<Duration>,
+    /// The largest chunk size accepted, or `None` for no limit of its own.
+    pub chunk_size: Option<usize>,
+    /// Set the extended attributes sent in these namespaces on the received files.
+    pub xattrs: Vec<XattrNamespace>,
+    /// Give the received files, and the directories made for them, to this user and group.
+    pub owner: Option<Owner>,
+    /// Give the received files these permissions, rather than those the umask leaves them.
+    /// Only unix has them, so they're ignored elsewhere.
+    pub file_mode: Option<u32>,
+    /// Like `file_mode`, but for the directories made for the received files.
+    pub dir_mode: Option<u32>,
+    /// Keep an index of the files in the output directory by their hash in this file, and
+    /// copy the files the sender hashed from there rather than receiving those again.
+    pub checksums: Option<PathBuf>,
+    /// Port on which to also serve a page where files can be uploaded from a browser,
+    /// for as long as the transfer goes on.
+    pub http: Option<u16>,
+    /// Consider the interfaces of containers and virtual machines like any other when picking
+    /// the one to listen on, instead of only when there are no others.
+    pub include_virtual: bool,
+    /// Listen on this socket instead of binding one, such as one handed over by the service
+    /// manager.
+    pub listener: Option<TcpListener>,
+    /// Wait for the next sender after every transfer until cancelled, rather than stopping
+    /// after the first. Transfers that fail are reported without stopping the rest.
+    pub keep_receiving: bool,
+    /// Told about the progress of the transfer.
+    pub events: Option<EventHandler>,
+    /// Stops the transfer once cancelled.
+    pub cancel: Option<CancelToken>,
+    /// Stops reading from the sender for as long as it's paused.
+    pub pause: Option<PauseToken>,
+    /// The most bytes received from each sender, by address, across all the transfers
+    /// received. Transfers that would go over it are refused as their files are listed.
+    pub quota_per_peer: Option<u64>,
+    /// The most bytes received from every sender together, counted in the same way.
+    pub quota: Option<u64>,
+    /// Only list the files the sender is about to send, and decline all of them.
+    pub list_only: bool,
+    /// Also write that list to this file as JSON.
+    pub list_json: Option<PathBuf>,
+    /// Unpack the tar (plain, gzipped or with zstd) and zip archives sent into the directory
+    /// they were sent to, as they arrive, rather than storing the archives themselves.
+    pub extract: bool,
+    /// Encrypt every file to this recipient as it's stored, rather than storing it as it was
+    /// sent, with the extension of encrypted files added to its name.
+    pub encrypt_at_rest: Option<Recipient>,
+    /// Only accept files from the senders in this list, once they prove they hold the secret
+    /// key of their identity, rather than from anyone. Verification is refused then, as it
+    /// doesn't say who asks.
+    pub authorized_senders: Option<AuthorizedSenders>,
+    /// Only accept files from the senders that prove they know this key, which is given out
+    /// in the [`connection_descriptor`] shown as a QR code. Verification is refused then too.
+    pub key: Option<SessionKey>,
+    /// Turn away the senders that connect within these times of the day, telling them when
+    /// to come back, which they wait for before trying again.
+    pub busy: Vec<DailyWindow>,
+    /// Only accept the files the filter lets through. The sender is told which were rejected
+    /// as they're listed, and doesn't send their data.
+    pub filter: FileFilter,
+    /// Once all the files are stored, hash them as they are in the output and list them there
+    /// in a file of this format.
+    pub sums: Option<SumsFormat>,
+    /// Post a summary of every transfer received here once it's over, whether it went well or
+    /// failed, with the hash of every file as stored unless they went to an archive or a sink.
+    pub webhook: Option<Webhook>,
+}
+
+impl Default for ReceiveOptions {
+    fn default() -> Self {
+        ReceiveOptions {
+            prefix: PathPrefix::default(),
+            strip_drive: false,
+            strip_components: 0,
+            map: Vec::new(),
+            output: PathBuf::from("."),
+            mirror: false,
+            dry_run: false,
+            archive: None,
+            session_dirs: false,
+            staging: None,
+            case_collisions: CaseCollisions::default(),
+            duplicates: Duplicates::default(),
+            normalize: None,
+            sink: None,
+            socket: SocketOptions::default(),
+            reconnect: None,
+            chunk_size: None,
+            xattrs: Vec::new(),
+            owner: None,
+            file_mode: None,
+            dir_mode: None,
+            checksums: None,
+            http: None,
+            include_virtual: false,
+            listener: None,
+            keep_receiving: false,
+            events: None,
+            cancel: None,
+            pause: None,
+            quota_per_peer: None,
+            quota: None,
+            list_only: false,
+            list_json: None,
+            extract: false,
+            encrypt_at_rest: None,
+            authorized_senders: None,
+            key: None,
+            busy: Vec::new(),
+            filter: FileFilter::default(),
+            sums: None,
+            webhook: None,
+        }
+    }
+}
+
+pub struct ServeOptions {
+    /// Port on which the files are served.
+    pub port: u16,
+    /// How many times each file can be downloaded in full before it stops being served.
+    pub downloads: usize,
+    /// Print a single code granting access to the files, and the link to the page listing
+    /// them under it, rather than a link for every file.
+    pub token: bool,
+    /// Stop serving the files after this long, even if they can still be downloaded.
+    pub expire: Option<Duration>,
+    /// Consider virtual interfaces like any other, as with [`ReceiveOptions::include_virtual`].
+    pub include_virtual: bool,
+    /// Told about every file being served, and about their downloads.
+    pub events: Option<EventHandler>,
+    /// Stops serving the files once cancelled.
+    pub cancel: Option<CancelToken>,
+}
+
+// === Transfer logic
+
+// net packet format:
+// * "sf-"
+// * version: u8
+// * header len: u32 (the len of everything up to the end of the file list before version 7)
+// * session id: u64 (since version 4)
+// * flags: u8 (since version 5)
+// * chunk size: u32 (since version 6, proposed by the sender)
+// * file count: u32 (since version 7)
+// * common prefix len: u32 (since version 7, of all the file names)
+// * ready: u8 (since version 15, sent by the receiver, ack to take the transfer, or busy
+//   followed by how many seconds it will be busy for as u32, after which it closes the
+//   connection, and the sender may connect again once that time has passed)
+// * chunk size: u32 (since version 7, sent by the receiver, the one both ends will use)
+// * packing: u8 (since version 13, sent by the receiver if the sender set the flag for it,
+//   whether the receiver will unpack the small files the sender packs together)
+// * if the sender set the flag for its identity (since version 14):
+//   * ack: u8 (sent by the receiver)
+//   * challenge: [u8; 32] (sent by the receiver, random)
+//   * public key: [u8; 32] (of the Ed25519 key of the sender)
+//   * signature: [u8; 64] (of "sf-identity", the session id as u64 and the challenge)
+// * if the sender set the flag for the session key (since version 24):
+//   * ack: u8 (sent by the receiver)
+//   * challenge: [u8; 32] (sent by the receiver, random)
+//   * proof: [u8; 32] (HMAC-SHA256 with the key of "sf-key", the session id as u64 and the
+//     challenge, which the receiver checks if it gave out a key, and ignores otherwise)
+// * for each batch of files (since version 7, the whole list is a single batch before):
+//   * file count: u32 (since version 7, zero after the last batch)
+//   * list len: u32 (since version 7)
+//   * for each file:
+//     * file len: u64
+//     * modification time: u64 (since version 5, seconds since the unix epoch)
+//     * name len: u32
+//     * name: [u8]
+//     * copy of: u32 (since version 8, one more than the index of an earlier file with the
+//       same contents, or zero)
+//     * link: u8 (since version 9, whether the copy should be a hard link to the earlier file)
+//     * metadata frame kind: u8 (since version 23)
+//     * metadata len: u32 (since version 10)
+//     * for each piece of metadata (since version 10, those with unknown tags are ignored):
+//       * tag: u8
+//       * value len: u32
+//       * value: [u8] (for extended attributes, the name len as u32, name and value, for the
+//         hash, the SHA-256 of the file data, for the root, the length as u32 of the part
+//         of the name before the name of the argument the file was found under, and for a
+//         pack, nothing, as the file is then a tar archive of files named after the last
+//         part of their names, to be unpacked next to the file instead, and since version
+//         18, for the codec, a u8 saying how the data of the file is sent if the sender set
+//         the flag to compress it: as it is, or in chunks, rather than always in chunks, for
+//         the creation time, the seconds since the unix epoch as u64, for the attributes,
+//         a u8 with whether the file is read-only (1) and hidden (2), and since version 19,
+//         for a directory, nothing, as the entry is then an empty directory to create, whose
+//         length is zero and which is never wanted, and since version 21, for a special file,
+//         a u8 with its kind, only a FIFO (1) so far, as the entry is then a special file to
+//         make instead, which is never wanted either)
+//   * for each file (since version 5, sent by the receiver):
+//     * wanted: u8 (whether the file data should be sent or skipped, as copies are made by
+//       the receiver, or since version 16, rejected, which is skipped too)
+//   * chunk size: u32 (only in version 6, sent by the receiver, the one both ends will use)
+//   * for each wanted file:
+//     * state: u8 (since version 20, whether the data follows, or the sender could not read
+//       the file, in which case the reason len as u32 and reason in UTF-8 follow instead; only
+//       sent from the start of the file, and not when resuming past it)
+//     * file data: [u8] (as it is, unless the sender set the flag to send it in chunks)
+//     * for each chunk of the file data (since version 17, if the sender set the flag for it,
+//       and since version 18, unless the codec of the file says it's sent as it is):
+//       * len: u32 (of the data, no more than the chunk size)
+//       * compressed len: u32 (of the DEFLATE stream it was compressed into, or zero if it's
+//         sent as it is)
+//       * data: [u8]
+// * ack: u8 (since version 4, sent by the receiver)
+//
+// while receiving the data (since version 12, if the sender set the flag for it), the receiver
+// says every so often, before any other reply:
+// * stored: u8
+// * stored len: u64 (of all the file data stored so far in the transfer)
+//
+// once the receiver has the header (since version 11), it may give up on the transfer at any
+// point, instead of its next reply or while the sender is still sending, and send:
+// * abort: u8
+// * reason len: u32
+// * reason: [u8] (UTF-8)
+//
+// if the connection is lost (since version 4), the sender connects again and sends:
+// * "sf+"
+// * version: u8
+// * session id: u64
+// * if the sender proved its identity in the header (since version 22), the receiver challenges
+//   it again in the same way, and the key must be the same:
+//   * ack: u8 (sent by the receiver)
+//   * challenge: [u8; 32] (sent by the receiver, random)
+//   * public key: [u8; 32]
+//   * signature: [u8; 64] (of "sf-identity", the session id as u64 and the new challenge)
+// * if the sender set the flag for the session key in the header (since version 24), the
+//   receiver challenges it again in the same way:
+//   * ack: u8 (sent by the receiver)
+//   * challenge: [u8; 32] (sent by the receiver, random)
+//   * proof: [u8; 32] (of "sf-key", the session id as u64 and the new challenge)
+// to which the receiver replies with the point where the transfer should continue:
+// * file index: u32
+// * file offset: u64
+// * state: u8 (since version 7, whether the list of the batch at the index is needed again)
+// * if the list is not needed (since version 7):
+//   * remaining file count: u32 (in the batch of the receiver, from the index on)
+//   * for each remaining file:
+//     * wanted: u8
+//
+// since version 23, everything either side says after the replies to the header and the
+// challenge of the identity, including the reply to a sender resuming, is sent in frames
+// rather than found by where it is, each of them being:
+// * kind: u8 (file list, file data, metadata, error, ack or extension)
+// * len: u32
+// * payload: [u8]
+// with what's in them being as before, but for the lens the frames already give:
+// * every batch of the list is a file list frame, with the file count as u32 and the files
+// * the wanted files of every batch, the place to resume from, and the last ack of the
+//   receiver, are ack frames
+// * the state of a wanted file is a file data frame with nothing in it if the data follows,
+//   or an error frame with the reason if it doesn't
+// * the data of every file is in as many file data frames as needed, none of which has the
+//   data of more than one file, and every chunk of the data is in one of its own
+// * the reason to abort is an error frame
+// * how much the receiver has stored is an extension frame, with the stored byte as the id
+//   of the extension, followed by the stored len as u64
+// extension frames are skipped by whoever reads one where it expects another frame, so that
+// either side can say more without a new version, as long as the other can do without it.
+//
+// before version 23, the fields are found by where they are, but everything that can grow
+// says how long it is: every batch of the list, the metadata of every file, whose tags are
+// skipped when unknown, and since version 17, every chunk of the data. Anything else new
+// comes with a new version, agreed on in the header, so that older peers keep working.
+//
+// version history:
+// * 24: the sender can prove it knows the key the receiver gave out, such as in its QR code
+// * 23: everything after the header is sent in frames of a kind that say how long they are
+// * 22: the sender proves its identity again when it resumes, if it proved it before
+// * 21: special files such as FIFOs can be listed, for the receiver to make them again
+// * 20: the sender can skip the files it can't read, and say why, rather than fail
+// * 19: empty directories can be listed, which older receivers would store as empty files
+// * 18: files can say how their data is sent, so that not all of it has to be in chunks
+// * 17: the file data can be sent in chunks, each compressed or not
+// * 16: the receiver can say the files it rejects, rather than only skip them
+// * 15: the receiver can turn the sender away while it's busy
+// * 14: the sender can prove its identity with a signature of a challenge from the receiver
+// * 13: small files can be packed into archives the receiver unpacks
+// * 12: the receiver can say how much it has stored while receiving the data
+// * 11: the receiver can abort the transfer and say why
+// * 10: files can carry metadata, such as their extended attributes
+// * 9: copies can be hard links
+// * 8: files can be listed as copies of earlier ones
+// * 7: the file list is sent in batches interleaved with the data
+// * 6: the chunk size is negotiated
+// * 5: files can be skipped by the receiver, and their modification time is kept
+// * 4: sessions can be resumed after the connection is lost
+// * 3: names always use forward slashes as the path separator
+// * 2: names use the separator native to the sender
+/// Sends the files, and those inside the directories, to the receiver at `addr`.
+pub fn send(addr: SocketAddr, files: Vec<PathBuf>, options: &SendOptions) -> Result<()> {
+    task::block_on(send_paths(addr, files, options))
+}
+
+async fn send_paths(addr: SocketAddr, files: Vec<PathBuf>, options: &SendOptions) -> Result<()> {
+    let outgoing = prepare_send(files, options).await?;
+    if !options.allow_metered {
+        check_metered(addr, &outgoing.files)?;
+    }
+    send_files(addr, None, outgoing, options)
+        .await
+        .map_err(|e| e.with_peer(addr))
+}
+
+// What the sender has ready to send once it knows the files.
+struct Outgoing {
+    files: Vec<Entry>,
+    roots: Vec<Option<usize>>,
+    hashes: Hashes,
+    version: u8,
+    flags: u8,
+}
+
+// Where received files go instead of the output directory, lent to each session in turn.
+type BoxedSink = Box<dyn Sink + Send + Sync>;
+
+// The hash of every file listed with one, or why it could not be read to be hashed.
+type Hashes = HashMap<PathBuf, std::result::Result<hash::Digest, String>>;
+
+// Find the files to send, and how to send them, with everything the `options` ask to be done
+// before connecting to the receiver.
+async fn prepare_send(files: Vec<PathBuf>, options: &SendOptions) -> Result<Outgoing> {
+    let version = if options.legacy {
+        LEGACY_VERSION
+    } else {
+        VERSION
+    };
+    prepare_send_as(files, version, options).await
+}
+
+// Like `prepare_send`, but to send them with the given protocol `version`.
+async fn prepare_send_as(
+    files: Vec<PathBuf>,
+    version: u8,
+    options: &SendOptions,
+) -> Result<Outgoing> {
+    if let Some(wait) = options
+        .start_at
+        .and_then(|start_at| start_at.duration_since(SystemTime::now()).ok())
+    {
+        out!(
+            "waiting until {} before sending...",
+            TimeOfDay::now().later(wait)
+        );
+        cancel::sleep(&options.cancel, wait).await?;
+    }
+    let mut flags = if options.update { FLAG_UPDATE } else { 0 };
+    if options.receiver_progress {
+        flags |= FLAG_STORED;
+    }
+    if let Some(identity) = &options.identity {
+        out!("sending as {}", identity.public_key());
+        flags |= FLAG_IDENTITY;
+    }
+    if options.compress != Compression::Never {
+        flags |= FLAG_COMPRESS;
+    }
+    if options.key.is_some() {
+        flags |= FLAG_KEY;
+    }
+
+    if flags & FLAG_UPDATE != 0 && version < 5 {
+        return Err("only unchanged files can be skipped since protocol version 5".into());
+    }
+    if flags & FLAG_STORED != 0 && version < 12 {
+        return Err("only since protocol version 12 can the receiver say what it stored".into());
+    }
+    if flags & FLAG_IDENTITY != 0 && version < 14 {
+        return Err("only since protocol version 14 can the sender prove its identity".into());
+    }
+    if flags & FLAG_COMPRESS != 0 && version < 17 {
+        return Err("only since protocol version 17 can the data be compressed".into());
+    }
+    if flags & FLAG_KEY != 0 && version < 24 {
+        return Err("only since protocol version 24 can the sender prove it knows a key".into());
+    }
+    if !options.xattrs.is_empty() && version < 10 {
+        return Err("only since protocol version 10 can extended attributes be sent".into());
+    }
+    if options.hashes && version < 10 {
+        return Err("only since protocol version 10 can hashes be sent".into());
+    }
+    if options.attributes && version < 10 {
+        return Err("only since protocol version 10 can attributes be sent".into());
+    }
+    if options.dedup && version < 8 {
+        return Err("only since protocol version 8 can files be sent as copies".into());
+    }
+    if options.hardlinks && version < 9 {
+        return Err("only since protocol version 9 can hard links be kept".into());
+    }
+
+    let files = distinct_paths(files, options.max_depth);
+    let args = files.clone();
+    let sourced = match &options.source {
+        Some(_) if !files.is_empty() => {
+            return Err("files cannot be sent along with a source".into());
+        }
+        Some(_) if options.manifest.is_some() => {
+            return Err("a manifest can only be written for the files given".into());
+        }
+        Some(source) => Some(
+            source
+                .files()
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(i, file)| Entry::Source(i, file))
+                .collect(),
+        ),
+        None => None,
+    };
+    let scan = Scan {
+        archive: options.archive,
+        walk: Walk {
+            max_depth: options.max_depth,
+            one_file_system: options.one_file_system,
+        },
+        specials: options.specials,
+        rename: options.rename.clone(),
+        order: options.order,
+        hardlinks: options.hardlinks,
+        dedup: options.dedup,
+        manifest: options.manifest.clone(),
+        hashes: options.hashes,
+        version,
+        cancel: options.cancel.clone(),
+    };
+    let scanned = task::blocking(move || scan.run(files, sourced)).await?;
+    for (path, e) in scanned.unreadable {
+        out!("skipping {:?}, as it can't be read: {}", path, e);
+        let reason = e.to_string();
+        emit(
+            &options.events,
+            TransferEvent::Unreadable {
+                index: None,
+                path,
+                reason,
+            },
+        );
+    }
+    let files = scanned.files;
+    if version >= 13 && offer_packing(scanned.small, options) {
+        flags |= FLAG_PACK;
+    }
+    // with a single argument, stripping the common prefix already keeps its contents apart,
+    // but a new name is only kept if the receiver is told where it begins
+    let roots = if (args.len() > 1 || !options.rename.is_empty()) && version >= 10 {
+        root_lens(&files, &args, &options.rename)
+    } else {
+        Vec::new()
+    };
+    let hashes = scanned.hashes;
+    Ok(Outgoing {
+        files,
+        roots,
+        hashes,
+        version,
+        flags,
+    })
+}
+
+// What finding the files to send needs from the options, owned so that walking and hashing them
+// is done on a thread of its own rather than holding up the runtime.
+struct Scan {
+    archive: Option<ArchiveFormat>,
+    walk: Walk,
+    specials: bool,
+    rename: Vec<(PathBuf, PathBuf)>,
+    order: Order,
+    hardlinks: bool,
+    dedup: bool,
+    manifest: Option<PathBuf>,
+    hashes: bool,
+    version: u8,
+    cancel: Option<CancelToken>,
+}
+
+// The files found to send, in the order they're sent in.
+struct Scanned {
+    files: Vec<Entry>,
+    unreadable: Unreadable,
+    // how many files are small enough to be packed together
+    small: usize,
+    hashes: Hashes,
+}
+
+impl Scan {
+    // Find the files under the paths given, unless they're the ones from a source, and put
+    // them in order, doing everything else they need before connecting to the receiver.
+    fn run(self, paths: Vec<PathBuf>, sourced: Option<Vec<Entry>>) -> Result<Scanned> {
+        let (files, unreadable) = match sourced {
+            Some(files) => (files, Vec::new()),
+            None => collect_entries(paths, &self)?,
+        };
+        // copies and links refer to earlier files, so they're only found once they're in order
+        let mut files = order_entries(files, self.order, &self.rename)?;
+        if self.version < 19 {
+            files.retain(|file| !matches!(file, Entry::Dir(_)));
+        }
+        if self.version < 21 {
+            files.retain(|file| !matches!(file, Entry::Special(..)));
+        }
+        let small = if self.version >= 13 {
+            count_small(&files)?
+        } else {
+            0
+        };
+        if self.hardlinks {
+            mark_links(&mut files)?;
+        }
+        if self.dedup {
+            mark_copies(&mut files)?;
+        }
+        if let Some(path) = &self.manifest {
+            let paths = files
+                .iter()
+                .flat_map(|file| match file {
+                    Entry::Archive(_, archive) => {
+                        archive.members.iter().map(|m| m.path.clone()).collect()
+                    }
+                    Entry::Dir(_) | Entry::Special(..) => Vec::new(),
+                    file => vec![file.path().to_path_buf()],
+                })
+                .collect::<Vec<_>>();
+            manifest::write(path, &manifest::hash_files(&paths, &self.cancel)?)?;
+            out!("wrote the manifest to {:?}", path);
+        }
+        // hashing can take longer than the receiver waits in between batches
+        let hashes = if self.hashes {
+            hash_entries(&files, self.version, &self.cancel)?
+        } else {
+            Hashes::new()
+        };
+        Ok(Scanned {
+            files,
+            unreadable,
+            small,
+            hashes,
+        })
+    }
+}
+
+// Hash the files listed with their hash, in parallel. Since version 20, those which can't be
+// read are skipped once it's their turn, rather than failing before anything is sent.
+fn hash_entries(files: &[Entry], version: u8, cancel: &Option<CancelToken>) -> Result<Hashes> {
+    let mut queue = files
+        .iter()
+        .filter(|file| matches!(file, Entry::File(_) | Entry::Copy(..) | Entry::Link(..)))
+        .map(Entry::path);
+    let mut hashes = Hashes::new();
+    out!("hashing the files...");
+    pipe::ordered_pool(
+        || queue.next().filter(|_| !cancel::is_cancelled(cancel)),
+        |path| (path, hash::hash_file(path)),
+        |(path, hashed)| -> Result<()> {
+            let hashed = match hashed {
+                Ok(digest) => Ok(digest),
+                Err(e) if version >= 20 => Err(e.to_string()),
+                Err(e) => return Err(Error::from(e).at(path)),
+            };
+            hashes.insert(path.to_path_buf(), hashed);
+            Ok(())
+        },
+    )?;
+    cancel::check(cancel)?;
+    Ok(hashes)
+}
+
+// How many of the files are small enough to be packed together.
+fn count_small(files: &[Entry]) -> Result<usize> {
+    let mut small = 0;
+    for file in files {
+        if let Entry::File(path) = file {
+            let meta = fs::metadata(path).map_err(|e| Error::from(e).at(path))?;
+            if meta.len() <= PACKED_FILE_LEN {
+                small += 1;
+            }
+        }
+    }
+    Ok(small)
+}
+
+// Whether to offer the receiver to pack the `small` files together, which only pays off if
+// there are many, and can't be done if the receiver should see each one to tell it apart.
+fn offer_packing(small: usize, options: &SendOptions) -> bool {
+    if small <= AUTO_PACK_FILES {
+        return false;
+    }
+    if !options.auto_pack {
+        out!(
+            "found {} small files, which would be sent faster packed together",
+            small
+        );
+        return false;
+    }
+    if options.update || options.hashes || !options.xattrs.is_empty() {
+        out!("not packing the small files, the receiver needs to see each of them");
+        return false;
+    }
+    out!(
+        "found {} small files, offering to pack them together",
+        small
+    );
+    true
+}
+
+// Warn if the files would go through a metered network, and refuse if they add up to a lot.
+fn check_metered(addr: SocketAddr, files: &[Entry]) -> Result<()> {
+    let interface = match route_interface(addr) {
+        Some(interface) if interface.is_metered => interface,
+        _ => return Ok(()),
+    };
+    let mut total = 0u64;
+    for file in files {
+        total += file.len_and_modified()?.0;
+    }
+    if total >= METERED_LIMIT {
+        return Err(Error::Other(format!(
+            "the network of {} is metered, and {} would be sent over it",
+            interface.name,
+            format_bytes(total)
+        )));
+    }
+    out!(
+        "the network of {} is metered, and {} will be sent over it",
+        interface.name,
+        format_bytes(total)
+    );
+    Ok(())
+}
+
+// The interface packets to `addr` would leave through, found by asking the system to route
+// a socket there. Nothing is sent.
+fn route_interface(addr: SocketAddr) -> Option<NetInterface> {
+    let local = if addr.is_ipv4() {
+        SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0))
+    } else {
+        SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0))
+    };
+    let socket = UdpSocket::bind(local).ok()?;
+    socket.connect(addr).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    get_ip_addresses()
+        .ok()?
+        .into_iter()
+        .find(|interface| interface.ip == ip)
+}
+
+// Send the files to the receiver at `addr`, or over the `local` connection to the receiver in
+// this process if there's one, which can't be lost or resumed.
+async fn send_files(
+    addr: SocketAddr,
+    local: Option<TimedStream>,
+    outgoing: Outgoing,
+    options: &SendOptions,
+) -> Result<()> {
+    let session_id = new_session_id();
+    let sending = send_session(addr, local, outgoing, session_id, options);
+    session::scope(session_id, sending).await
+}
+
+async fn send_session(
+    addr: SocketAddr,
+    mut local: Option<TimedStream>,
+    outgoing: Outgoing,
+    session_id: u64,
+    options: &SendOptions,
+) -> Result<()> {
+    let Outgoing {
+        mut files,
+        mut roots,
+        mut hashes,
+        version,
+        flags,
+    } = outgoing;
+    let socket = options.socket;
+    let reconnect = options.reconnect.filter(|_| local.is_none());
+
+    // before version 7, the entire list goes in the header
+    let mut batch = if version >= 7 {
+        list_batch(
+            &files,
+            &roots,
+            &hashes,
+            0,
+            version,
+            (BATCH_FILES, BATCH_LEN),
+            options,
+        )?
+    } else {
+        list_batch(
+            &files,
+            &roots,
+            &hashes,
+            0,
+            version,
+            (usize::MAX, usize::MAX),
+            options,
+        )?
+    };
+
+    // calculate file list buffer
+    // only since version 4 does the receiver know about it
+    let session = (version >= 4).then_some(session_id);
+
+    let mut chunk_size = options
+        .chunk_size
+        .unwrap_or_else(|| auto_chunk_size(&batch.lens))
+        .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+    let names = files
+        .iter()
+        .map(|file| file.name(&options.rename))
+        .collect::<Vec<_>>();
+    let prefix_len = common_prefix_len(names.iter().map(|n| &n[..]), PathPrefix::Strip);
+    let header = Header {
+        version,
+        session,
+        flags,
+        chunk_size: Some(chunk_size.try_into()?),
+        file_count: Some(files.len().try_into()?),
+        prefix_len: Some(prefix_len.try_into()?),
+        list_len: batch.list.len(),
+    };
+    let buffer = header.encode(&batch.list)?;
+    debug!(
+        "sending {} files with protocol version {}, flags {:#04x} and chunks of {} bytes",
+        files.len(),
+        version,
+        flags,
+        chunk_size
+    );
+    debug!("sending the header {:?}", header);
+
+    let mut stream = loop {
+        let mut stream = match local.take() {
+            Some(stream) => stream,
+            None => {
+                out!("connecting to server {}...", addr);
+                connect_retrying(addr, &socket, options.retry, &options.cancel).await?
+            }
+        };
+        out!("sending file list...");
+        stream.write_all(&buffer).await?;
+        if version < 15 {
+            break stream;
+        }
+        let mut ready = [0u8];
+        read_reply(&mut stream, &mut ready).await??;
+        match ready[0] {
+            ACK => break stream,
+            BUSY => {
+                let mut u32_buffer = [0u8; 4];
+                stream.read_exact(&mut u32_buffer).await?;
+                let busy = Duration::from_secs(u32::from_le_bytes(u32_buffer).into());
+                out!(
+                    "the receiver is busy until {}, waiting until then...",
+                    TimeOfDay::now().later(busy)
+                );
+                drop(stream);
+                cancel::sleep(&options.cancel, busy).await?;
+            }
+            tag => {
+                return Err(Error::ProtocolViolation(format!(
+                    "receiver is neither ready nor busy: {:#04x}",
+                    tag
+                )))
+            }
+        }
+    };
+    emit(&options.events, TransferEvent::Connected { peer: addr });
+
+    if (5..7).contains(&version) {
+        stream.read_exact(&mut batch.wanted).await?;
+    }
+    if version >= 6 {
+        let mut u32_buffer = [0u8; 4];
+        stream.read_exact(&mut u32_buffer).await?;
+        let agreed: usize = u32::from_le_bytes(u32_buffer).try_into()?;
+        if !(MIN_CHUNK_SIZE..=chunk_size).contains(&agreed) {
+            return Err(Error::ProtocolViolation(format!(
+                "receiver picked an invalid chunk size: {}",
+                agreed
+            )));
+        }
+        chunk_size = agreed;
+    }
+    out!("using chunks of {} KiB", chunk_size / 1024);
+    if flags & FLAG_PACK != 0 {
+        let mut packing = [0u8];
+        stream.read_exact(&mut packing).await?;
+        if packing[0] != 0 {
+            (files, roots) = pack_entries(files, &roots, &options.rename)?;
+            batch = list_batch(
+                &files,
+                &roots,
+                &hashes,
+                0,
+                version,
+                (BATCH_FILES, BATCH_LEN),
+                options,
+            )?;
+        } else {
+            out!("the receiver can't unpack files, sending them one by one");
+        }
+    }
+    if let (Some(identity), Some(session)) = (&options.identity, session) {
+        prove_identity(&mut stream, identity, session).await?;
+    }
+    if let (Some(key), Some(session)) = (&options.key, session) {
+        prove_key(&mut stream, key, session).await?;
+    }
+
+    let mut position = Position {
+        index: 0,
+        offset: 0,
+        list_pending: version >= 7,
+    };
+    let mut replies = Replies::new(options.receiver_progress, version);
+    let mut compressor = (flags & FLAG_COMPRESS != 0).then(|| Compressor::new(options.compress));
+    // the files that changed while being sent since they were last listed
+    let mut changed = HashSet::new();
+    let mut resends = 0;
+    loop {
+        let sent = match send_batch(
+            &mut stream,
+            &files,
+            &mut batch,
+            &mut position,
+            (&mut replies, &mut compressor, &mut changed),
+            (chunk_size, version),
+            options,
+        )
+        .await?
+        {
+            // an empty batch marks the end of the list
+            Ok(()) if version >= 7 && !batch.lens.is_empty() => {
+                if options.resend_changed
+                    && batch.end() == files.len()
+                    && !changed.is_empty()
+                    && resends < MAX_RESENDS
+                {
+                    out!(
+                        "sending {} files again, as they changed while being sent...",
+                        changed.len()
+                    );
+                    roots.resize(files.len(), None);
+                    // in the order they were listed, as they would be sent otherwise
+                    let mut again = changed.drain().collect::<Vec<_>>();
+                    again.sort_unstable();
+                    for index in again {
+                        // hashing it again would keep the receiver waiting, so it goes without
+                        hashes.remove(files[index].path());
+                        files.push(Entry::File(files[index].path().to_path_buf()));
+                        roots.push(roots[index]);
+                    }
+                    resends += 1;
+                }
+                batch = list_batch(
+                    &files,
+                    &roots,
+                    &hashes,
+                    batch.end(),
+                    version,
+                    (BATCH_FILES, BATCH_LEN),
+                    options,
+                )?;
+                position.list_pending = true;
+                continue;
+            }
+            // wait until the receiver confirms everything arrived, or it may need resuming
+            Ok(()) if session.is_some() => read_ack(&mut stream, version, &mut [0]).await?,
+            sent => sent,
+        };
+
+        match (sent, session, reconnect) {
+            (Ok(()), _, _) => break,
+            // the receiver may have stopped reading to say why, and there's no resuming then
+            (Err(e), _, _) if version >= 11 && stream.peek_pending().is_ok_and(|b| b.is_some()) => {
+                read_ack(&mut stream, version, &mut [0]).await??;
+                return Err(Error::ProtocolViolation(format!(
+                    "receiver replied while being sent files ({})",
+                    e
+                )));
+            }
+            (Err(e), Some(session), Some(window)) => {
+                out!("connection lost ({}), reconnecting...", e);
+                // only the sender that proved to be who it is in the header can resume
+                let identity = options.identity.as_ref().filter(|_| version >= 22);
+                let key = options.key.as_ref().filter(|_| version >= 24);
+                let resumed = resume_session(
+                    addr,
+                    session,
+                    (version, identity, key),
+                    &socket,
+                    window,
+                    &options.cancel,
+                )
+                .await?;
+                emit(&options.events, TransferEvent::Connected { peer: addr });
+                let rest;
+                (stream, position.index, position.offset, rest) = resumed;
+                debug!(
+                    "resuming from file {} at offset {}",
+                    position.index, position.offset
+                );
+                // since version 23, the rest of the reply came in the same frame
+                let mut rest = &rest[..];
+                let reply: &mut (dyn AsyncRead + Unpin + Send) = if version >= 23 {
+                    &mut rest
+                } else {
+                    &mut stream
+                };
+                if version >= 7 {
+                    let mut state = [0u8];
+                    reply.read_exact(&mut state).await?;
+                    if position.index > files.len() {
+                        return Err(Error::ProtocolViolation(
+                            "receiver asked to resume from an unexpected file".into(),
+                        ));
+                    }
+                    if state[0] == RESUME_LIST {
+                        batch = list_batch(
+                            &files,
+                            &roots,
+                            &hashes,
+                            position.index,
+                            version,
+                            (BATCH_FILES, BATCH_LEN),
+                            options,
+                        )?;
+                        position.list_pending = true;
+                    } else {
+                        // the receiver may still be in an earlier batch, so it's listed again
+                        let mut u32_buffer = [0u8; 4];
+                        reply.read_exact(&mut u32_buffer).await?;
+                        let remaining: usize = u32::from_le_bytes(u32_buffer).try_into()?;
+                        if remaining > files.len() - position.index {
+                            return Err(Error::ProtocolViolation(
+                                "receiver asked to resume an unexpected batch".into(),
+                            ));
+                        }
+                        batch = list_batch(
+                            &files,
+                            &roots,
+                            &hashes,
+                            position.index,
+                            version,
+                            (remaining, usize::MAX),
+                            options,
+                        )?;
+                        reply.read_exact(&mut batch.wanted).await?;
+                        position.list_pending = false;
+                    }
+                }
+            }
+            (Err(e), _, _) => return Err(e.into()),
+        }
+    }
+
+    if let Some(compressor) = compressor.filter(|c| c.raw_len != 0) {
+        emit(
+            &options.events,
+            TransferEvent::Compressed {
+                raw: compressor.raw_len,
+                sent: compressor.sent_len,
+            },
+        );
+    }
+    emit(&options.events, TransferEvent::Finished);
+    Ok(())
+}
+
+// Consecutive files whose list is sent together.
+struct Batch {
+    start: usize,
+    lens: Vec<u64>,
+    // when every file was last modified, as listed, to tell if it changed while being sent
+    modified: Vec<u64>,
+    // how the data of every file is sent if it's being compressed
+    codecs: Vec<Codec>,
+    // why every file that could not be read while listing it is skipped, since version 20
+    unreadable: Vec<Option<String>>,
+    list: Vec<u8>,
+    wanted: Vec<u8>,
+}
+
+impl Batch {
+    fn end(&self) -> usize {
+        self.start + self.lens.len()
+    }
+}
+
+// Where the sender is at within the current batch.
+struct Position {
+    index: usize,
+    offset: u64,
+    // whether the receiver has yet to get the list of the batch
+    list_pending: bool,
+}
+
+// List the files from `start` on, stopping after `max_files` or once the list is `max_len` long,
+// along with their `roots`, their `hashes` and the metadata the `options` ask for.
+fn list_batch(
+    files: &[Entry],
+    roots: &[Option<usize>],
+    hashes: &Hashes,
+    start: usize,
+    version: u8,
+    (max_files, max_len): (usize, usize),
+    options: &SendOptions,
+) -> Result<Batch> {
+    let mut batch = Batch {
+        start,
+        lens: Vec::new(),
+        modified: Vec::new(),
+        codecs: Vec::new(),
+        unreadable: Vec::new(),
+        list: Vec::new(),
+        wanted: Vec::new(),
+    };
+    for (i, file) in files.iter().enumerate().skip(start).take(max_files) {
+        if batch.list.len() >= max_len {
+            break;
+        }
+
+        let (file_len, modified) = file
+            .len_and_modified()
+            .map_err(|e| Error::from(e).at(file.path()))?;
+        batch.lens.push(file_len);
+        batch.modified.push(modified);
+
+        let name = file.name(&options.rename);
+        let codec = match file {
+            // named after its first file, which says nothing of what else is in the archive
+            Entry::Pack(..) => Codec::Deflate(Level::DEFAULT),
+            _ => match Codec::for_name(&name) {
+                // before version 18, the receiver expects all of the data in chunks
+                Codec::None if version < 18 => Codec::Deflate(Level::DEFAULT),
+                codec => codec,
+            },
+        };
+        batch.codecs.push(codec);
+        let (metadata, unreadable) = if version >= 10 {
+            let root = roots.get(i).copied().flatten();
+            let codec = (version >= 18 && options.compress != Compression::Never).then_some(codec);
+            file_metadata(file, root, codec, hashes, options)?
+        } else {
+            (Metadata::default(), None)
+        };
+        batch.unreadable.push(unreadable);
+        let entry = ListEntry {
+            len: file_len,
+            modified,
+            name: &name,
+            copy_of: match file {
+                Entry::Copy(_, source) | Entry::Link(_, source) => Some(*source),
+                _ => None,
+            },
+            link: matches!(file, Entry::Link(..)),
+            metadata: &metadata,
+        };
+        entry.encode(version, &mut batch.list)?;
+    }
+    batch.wanted = vec![WANT; batch.lens.len()];
+    Ok(batch)
+}
+
+// The metadata sent along with the file. The extended attributes that can't be read are left
+// out, since the file itself can still be sent. A file that could not be read to be hashed is
+// listed without its hash, along with the reason to skip it once it's its turn.
+fn file_metadata(
+    file: &Entry,
+    root: Option<usize>,
+    codec: Option<Codec>,
+    hashes: &Hashes,
+    options: &SendOptions,
+) -> Result<(Metadata, Option<String>)> {
+    let mut metadata = Metadata {
+        chunked: codec.map(|codec| codec != Codec::None),
+        root,
+        packed: matches!(file, Entry::Pack(..)),
+        directory: matches!(file, Entry::Dir(..)),
+        special: match file {
+            Entry::Special(_, kind) => Some(*kind),
+            _ => None,
+        },
+        ..Metadata::default()
+    };
+    if matches!(
+        file,
+        Entry::Archive(..)
+            | Entry::Pack(..)
+            | Entry::Source(..)
+            | Entry::Dir(..)
+            | Entry::Special(..)
+    ) {
+        return Ok((metadata, None));
+    }
+    let path = file.path();
+    match hashes.get(path) {
+        Some(Ok(digest)) => metadata.hash = Some(*digest),
+        Some(Err(e)) => return Ok((metadata, Some(e.clone()))),
+        None => {}
+    }
+    if options.attributes {
+        let meta = fs::metadata(path).map_err(|e| Error::from(e).at(path))?;
+        metadata.created = attributes::created(&meta);
+        metadata.attributes = Some(attributes::get(&meta));
+    }
+    if options.xattrs.is_empty() {
+        return Ok((metadata, None));
+    }
+    let attributes = match xattr::get_all(path, &options.xattrs) {
+        Ok(attributes) => attributes,
+        Err(e) => {
+            out!("cannot read the extended attributes of {:?}: {}", path, e);
+            return Ok((metadata, None));
+        }
+    };
+    for (name, value) in attributes {
+        if metadata.encoded_len() + 9 + name.len() + value.len() > MAX_METADATA_LEN {
+            out!(
+                "not sending the extended attribute {:?} of {:?}, it's too large",
+                names::display(&name),
+                path
+            );
+            continue;
+        }
+        metadata.xattrs.push((name, value));
+    }
+    Ok((metadata, None))
+}
+
+// Send the list of the batch if the receiver doesn't have it yet, and then the data of every
+// wanted file from the current position on. Failing to read the files is fatal, but the inner
+// result is the outcome of using the connection, which may be recoverable.
+async fn send_batch(
+    stream: &mut TimedStream,
+    files: &[Entry],
+    batch: &mut Batch,
+    position: &mut Position,
+    (replies, compressor, changed): (&mut Replies, &mut Option<Compressor>, &mut HashSet<usize>),
+    (chunk_size, version): (usize, u8),
+    options: &SendOptions,
+) -> Result<io::Result<()>> {
+    let (events, cancel) = (&options.events, &options.cancel);
+    if position.list_pending {
+        let buffer = protocol::encode_batch(version, batch.lens.len(), &batch.list)?;
+        let sent_at = Instant::now();
+        if let Err(e) = stream.write_all(&buffer).await {
+            return Ok(Err(e));
+        }
+        if let Err(e) = read_ack(stream, version, &mut batch.wanted).await? {
+            return Ok(Err(e));
+        }
+        // the receiver answers the first list as soon as it has it, unless it's looking for
+        // unchanged files, and by then it has long accepted the connection
+        if batch.start == 0 && !options.update {
+            let rtt = sent_at.elapsed();
+            emit(events, TransferEvent::RoundTrip { rtt });
+        }
+        position.list_pending = false;
+    }
+
+    let file_count = files.len().to_string();
+    while position.index < batch.end() {
+        check(cancel)?;
+        let i = position.index;
+        let file_len = batch.lens[i - batch.start];
+        if batch.wanted[i - batch.start] != WANT {
+            let rejected = batch.wanted[i - batch.start] == REJECT;
+            skip_file(files, i, rejected, &file_count, events);
+            position.index += 1;
+            continue;
+        }
+        emit_started(events, i, files[i].path(), file_len);
+        if position.offset == 0 {
+            out!(
+                "[{n:>p$}/{c}] sending file {:?} ({})...",
+                files[i].path(),
+                format_bytes(file_len),
+                n = i,
+                p = file_count.len(),
+                c = file_count
+            );
+        } else {
+            out!(
+                "resuming file {:?} from byte {}...",
+                files[i].path(),
+                position.offset
+            );
+        }
+
+        let mut state = Vec::new();
+        let opened = match &files[i] {
+            Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _) => {
+                open_listed(batch, i, path, position.offset, version, &mut state)?.map(Some)
+            }
+            _ => {
+                if position.offset == 0 && version >= 20 {
+                    tell_sent(version, &mut state);
+                }
+                Ok(None)
+            }
+        };
+        if let Err(e) = stream.write_all(&state).await {
+            return Ok(Err(e));
+        }
+        let opened = match opened {
+            Ok(opened) => opened,
+            Err(reason) => {
+                skip_unreadable(files, i, &reason, &file_count, events);
+                position.index += 1;
+                position.offset = 0;
+                continue;
+            }
+        };
+
+        // the files after the first that were packed but could not be read
+        let mut unreadable = Vec::new();
+        let mut progress = Progress {
+            index: i,
+            offset: position.offset,
+            replies: &mut *replies,
+            events,
+        };
+        let sent = match (&files[i], opened) {
+            // sending many tiny files one by one is slow, so they're packed together
+            (Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _), Some(file))
+                if position.offset == 0 && file_len <= PACKED_FILE_LEN =>
+            {
+                let mut packed = Vec::new();
+                let codec = batch.codecs[i - batch.start];
+                read_packed(
+                    file,
+                    path,
+                    (file_len, version),
+                    &mut packed,
+                    compressor_for(compressor, codec),
+                )?;
+                let mut end = i + 1;
+                while end < batch.end() {
+                    let file_len = batch.lens[end - batch.start];
+                    if batch.wanted[end - batch.start] != WANT {
+                        let rejected = batch.wanted[end - batch.start] == REJECT;
+                        skip_file(files, end, rejected, &file_count, events);
+                    } else {
+                        let path = match &files[end] {
+                            Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _)
+                                if file_len <= PACKED_FILE_LEN
+                                    && packed.len() as u64 + file_len <= chunk_size as u64 =>
+                            {
+                                path
+                            }
+                            _ => break,
+                        };
+                        out!(
+                            "[{n:>p$}/{c}] sending file {:?} ({})...",
+                            path,
+                            format_bytes(file_len),
+                            n = end,
+                            p = file_count.len(),
+                            c = file_count
+                        );
+                        emit_started(events, end, path, file_len);
+                        match open_listed(batch, end, path, 0, version, &mut packed)? {
+                            Ok(file) => {
+                                let codec = batch.codecs[end - batch.start];
+                                let compressor = compressor_for(compressor, codec);
+                                let len = (file_len, version);
+                                read_packed(file, path, len, &mut packed, compressor)?;
+                            }
+                            Err(reason) => {
+                                skip_unreadable(files, end, &reason, &file_count, events);
+                                unreadable.push(end);
+                            }
+                        }
+                    }
+                    end += 1;
+                }
+                let sent = match stream.write_all(&packed).await {
+                    Ok(()) => progress.replies.poll(stream, events).await,
+                    Err(e) => Err(e),
+                };
+                if sent.is_ok() {
+                    // the last one is done below, like any other file
+                    let done = |&j: &usize| {
+                        batch.wanted[j - batch.start] == WANT && !unreadable.contains(&j)
+                    };
+                    for index in (i..end - 1).filter(done) {
+                        check_changed(files, index, batch, changed, events);
+                        emit(events, TransferEvent::FileDone { index });
+                    }
+                    position.index = end - 1;
+                }
+                sent
+            }
+            // the data has to be read to be compressed, so it can't be sent straight from the file
+            (Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _), Some(mut file))
+                if compressor.is_some() && batch.codecs[i - batch.start] != Codec::None =>
+            {
+                let at = |e: io::Error| Error::from(e).at(path);
+                file.seek(SeekFrom::Start(position.offset)).map_err(at)?;
+                let file = Fitted::new(file, file_len - position.offset);
+                let compressor = compressor_for(compressor, batch.codecs[i - batch.start]);
+                send_data(
+                    stream,
+                    path,
+                    file,
+                    (chunk_size, version),
+                    compressor,
+                    cancel,
+                    &mut progress,
+                )
+                .await?
+            }
+            (Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _), Some(mut file)) => {
+                let at = |e: io::Error| Error::from(e).at(path);
+                // sent a chunk at a time, so that there's some progress to report
+                loop {
+                    let offset = progress.offset;
+                    if offset == file_len {
+                        break Ok(());
+                    }
+                    check(cancel)?;
+                    let len = (file_len - offset).min(chunk_size as u64);
+                    // what the file shrunk by is only made up for when reading it
+                    let shrunk = file.metadata().map_err(at)?.len() < offset + len;
+                    let sent = if shrunk {
+                        Some(Ok(0))
+                    } else {
+                        if version >= 23 {
+                            let header = protocol::frame_header(FRAME_FILE_DATA, len as u32);
+                            if let Err(e) = stream.write_all(&header).await {
+                                break Err(e);
+                            }
+                        }
+                        stream.send_file(&file, offset, len).await.map_err(at)?
+                    };
+                    match sent {
+                        Some(Ok(sent)) if sent == len => {
+                            if let Err(e) = progress.sent(stream, len).await {
+                                break Err(e);
+                            }
+                        }
+                        Some(Err(e)) => break Err(e),
+                        // the platform can't, or the file shrunk, so the rest is read instead
+                        sent => {
+                            let sent = sent.and_then(|sent| sent.ok()).unwrap_or(0);
+                            progress.offset += sent;
+                            let offset = progress.offset;
+                            file.seek(SeekFrom::Start(offset)).map_err(at)?;
+                            let mut file = Fitted::new(file, file_len - offset);
+                            // the frame already said how much of the data is in it
+                            if version >= 23 && !shrunk {
+                                let mut rest = vec![0; (len - sent) as usize];
+                                file.read_exact(&mut rest).map_err(at)?;
+                                if let Err(e) = stream.write_all(&rest).await {
+                                    break Err(e);
+                                }
+                                progress.offset += rest.len() as u64;
+                                emit_progress(events, i, progress.offset);
+                            }
+                            break send_data(
+                                stream,
+                                path,
+                                file,
+                                (chunk_size, version),
+                                None,
+                                cancel,
+                                &mut progress,
+                            )
+                            .await?;
+                        }
+                    }
+                }
+            }
+            (Entry::File(_) | Entry::Copy(..) | Entry::Link(..), None) => {
+                unreachable!("file to send was not opened")
+            }
+            (Entry::Source(index, file), _) => {
+                let source = options.source.as_deref().expect("entry without a source");
+                let data = source
+                    .open(*index, position.offset)
+                    .map_err(|e| Error::from(e).at(&file.path))?;
+                let compressor = compressor_for(compressor, batch.codecs[i - batch.start]);
+                let sent = send_data(
+                    stream,
+                    &file.path,
+                    data,
+                    (chunk_size, version),
+                    compressor,
+                    cancel,
+                    &mut progress,
+                )
+                .await?;
+                // the receiver would take the next file as the rest of this one
+                if sent.is_ok() && progress.offset != file_len {
+                    return Err(format!("{:?} shrunk while being sent", file.path).into());
+                }
+                sent
+            }
+            // there's no data, even if the receiver asked for it
+            (Entry::Dir(_) | Entry::Special(..), _) => Ok(()),
+            (Entry::Archive(path, archive), _) => {
+                let data = archive
+                    .open(position.offset)
+                    .map_err(|e| Error::from(e).at(path))?;
+                let compressor = compressor_for(compressor, batch.codecs[i - batch.start]);
+                send_data(
+                    stream,
+                    path,
+                    data,
+                    (chunk_size, version),
+                    compressor,
+                    cancel,
+                    &mut progress,
+                )
+                .await?
+            }
+            (Entry::Pack(path, members), _) => {
+                let mut archive = tar::ArchiveReader::new(members);
+                archive.skip(position.offset);
+                let compressor = compressor_for(compressor, batch.codecs[i - batch.start]);
+                send_data(
+                    stream,
+                    path,
+                    archive,
+                    (chunk_size, version),
+                    compressor,
+                    cancel,
+                    &mut progress,
+                )
+                .await?
+            }
+        };
+        if let Err(e) = sent {
+            return Ok(Err(e));
+        }
+        if !unreadable.contains(&position.index) {
+            check_changed(files, position.index, batch, changed, events);
+            emit(
+                events,
+                TransferEvent::FileDone {
+                    index: position.index,
+                },
+            );
+        }
+        position.index += 1;
+        position.offset = 0;
+    }
+    Ok(Ok(()))
+}
+
+// Read the reply of the receiver into `buffer`, unless it aborted the transfer instead, which
+// is fatal. The inner result is the outcome of using the connection, which may be recoverable.
+async fn read_reply(stream: &mut TimedStream, buffer: &mut [u8]) -> Result<io::Result<()>> {
+    let Some((first, rest)) = buffer.split_first_mut() else {
+        return Ok(Ok(()));
+    };
+    let mut tag = [0u8];
+    if let Err(e) = stream.read_exact(&mut tag).await {
+        return Ok(Err(e));
+    }
+    // how much was stored is no longer news once the receiver replies
+    while tag[0] == STORED {
+        let mut stored = [0u8; 8];
+        if let Err(e) = stream.read_exact(&mut stored).await {
+            return Ok(Err(e));
+        }
+        if let Err(e) = stream.read_exact(&mut tag).await {
+            return Ok(Err(e));
+        }
+    }
+    if tag[0] != ABORT {
+        *first = tag[0];
+        return Ok(stream.read_exact(rest).await.map(drop));
+    }
+
+    let mut u32_buffer = [0u8; 4];
+    stream.read_exact(&mut u32_buffer).await?;
+    let reason_len: usize = u32::from_le_bytes(u32_buffer).try_into()?;
+    if reason_len > MAX_REASON_LEN {
+        return Err(Error::ProtocolViolation(format!(
+            "reason to abort is too long: {} bytes",
+            reason_len
+        )));
+    }
+    let mut reason = vec![0; reason_len];
+    stream.read_exact(&mut reason).await?;
+    Err(Error::Aborted(
+        String::from_utf8_lossy(&reason).into_owned(),
+    ))
+}
+
+// Read the reply of the receiver into `buffer` like `read_reply`, from the ack frame it comes
+// in since version 23, or the error frame with the reason it aborted instead.
+async fn read_ack(
+    stream: &mut TimedStream,
+    version: u8,
+    buffer: &mut [u8],
+) -> Result<io::Result<()>> {
+    if version < 23 {
+        return read_reply(stream, buffer).await;
+    }
+    if buffer.is_empty() {
+        return Ok(Ok(()));
+    }
+    // how much was stored is no longer news once the receiver replies
+    let (kind, payload) = match protocol::read_frame(stream, buffer.len().max(MAX_REASON_LEN)).await
+    {
+        Ok(frame) => frame,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+            return Err(Error::ProtocolViolation(e.to_string()))
+        }
+        Err(e) => return Ok(Err(e)),
+    };
+    match kind {
+        FRAME_ACK if payload.len() == buffer.len() => {
+            buffer.copy_from_slice(&payload);
+            Ok(Ok(()))
+        }
+        FRAME_ERROR => Err(Error::Aborted(
+            String::from_utf8_lossy(&payload).into_owned(),
+        )),
+        _ => Err(Error::ProtocolViolation(format!(
+            "receiver replied with a frame of kind {} and {} bytes",
+            kind,
+            payload.len()
+        ))),
+    }
+}
+
+// What the receiver says while the data is being sent, which is checked every so often.
+struct Replies {
+    // whether the receiver says it in frames, since version 23
+    framed: bool,
+    checked: Instant,
+    // when the receiver last said how much it has stored, if it was asked to
+    stored_at: Option<Instant>,
+    stalled: bool,
+}
+
+impl Replies {
+    fn new(receiver_progress: bool, version: u8) -> Self {
+        Self {
+            framed: version >= 23,
+            checked: Instant::now(),
+            stored_at: receiver_progress.then(Instant::now),
+            stalled: false,
+        }
+    }
+
+    // Read how much the receiver has stored, if it's time to check again. Anything else it
+    // says, such as why it aborted, makes sending stop, to be read as the reply it is.
+    async fn poll(
+        &mut self,
+        stream: &mut TimedStream,
+        events: &Option<EventHandler>,
+    ) -> io::Result<()> {
+        let now = Instant::now();
+        if now - self.checked < REPLY_CHECK_INTERVAL {
+            return Ok(());
+        }
+        // if nothing was sent for a while, the receiver had nothing to store either
+        if now - self.checked >= STALL_DELAY {
+            self.stored_at = self.stored_at.map(|_| now);
+        }
+        self.checked = now;
+
+        while let Some(tag) = stream.peek_pending()? {
+            let told = if self.framed { FRAME_EXTENSION } else { STORED };
+            if tag != told {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionAborted,
+                    "the receiver stopped reading to reply",
+                ));
+            }
+            let mut message = [0u8; 9];
+            if self.framed {
+                let mut header = [0u8; FRAME_HEADER_LEN];
+                stream.read_exact(&mut header).await?;
+                let len = u32::from_le_bytes(header[1..].try_into().unwrap());
+                let mut extension = AsyncReadExt::take(&mut *stream, len.into());
+                // there are no other extensions the sender knows of
+                if len as usize != message.len() {
+                    tokio::io::copy(&mut extension, &mut tokio::io::sink()).await?;
+                    continue;
+                }
+                extension.read_exact(&mut message).await?;
+                if message[0] != STORED {
+                    continue;
+                }
+            } else {
+                stream.read_exact(&mut message).await?;
+            }
+            let bytes = u64::from_le_bytes(message[1..].try_into().unwrap());
+            emit(events, TransferEvent::Stored { bytes });
+            if self.stalled {
+                out!("the receiver is storing the files again");
+            }
+            self.stored_at = Some(Instant::now());
+            self.stalled = false;
+        }
+        if let Some(stored_at) = self.stored_at {
+            if !self.stalled && stored_at.elapsed() >= STALL_DELAY {
+                out!(
+                    "the receiver has stored nothing for {}s, it may be stalled",
+                    stored_at.elapsed().as_secs()
+                );
+                self.stalled = true;
+            }
+        }
+        Ok(())
+    }
+}
+
+// How much of the data of the file at `index` was sent, which is reported as it goes.
+struct Progress<'a> {
+    index: usize,
+    offset: u64,
+    replies: &'a mut Replies,
+    events: &'a Option<EventHandler>,
+}
+
+impl Progress<'_> {
+    // Count another `len` bytes as sent, and see what the receiver has to say meanwhile.
+    async fn sent(&mut self, stream: &mut TimedStream, len: u64) -> io::Result<()> {
+        self.offset += len;
+        emit_progress(self.events, self.index, self.offset);
+        self.replies.poll(stream, self.events).await
+    }
+}
+
+// Say that the file at `i` is skipped because it can't be read, for the `reason` given.
+fn skip_unreadable(
+    files: &[Entry],
+    i: usize,
+    reason: &str,
+    file_count: &str,
+    events: &Option<EventHandler>,
+) {
+    let path = files[i].path();
+    out!(
+        "[{n:>p$}/{c}] skipping file {:?}, as it can't be read: {}",
+        path,
+        reason,
+        n = i,
+        p = file_count.len(),
+        c = file_count
+    );
+    emit(
+        events,
+        TransferEvent::Unreadable {
+            index: Some(i),
+            path: path.to_path_buf(),
+            reason: reason.to_owned(),
+        },
+    );
+}
+
+// Let it be known that the receiver didn't want the file at `i`, or `rejected` it outright.
+fn skip_file(
+    files: &[Entry],
+    i: usize,
+    rejected: bool,
+    file_count: &str,
+    events: &Option<EventHandler>,
+) {
+    match &files[i] {
+        // never wanted, since there's nothing to send but its name
+        Entry::Dir(path) => {
+            out!(
+                "[{n:>p$}/{c}] sent empty directory {:?}",
+                path,
+                n = i,
+                p = file_count.len(),
+                c = file_count
+            );
+            return;
+        }
+        Entry::Special(path, _) if !rejected => {
+            out!(
+                "[{n:>p$}/{c}] sent special file {:?}",
+                path,
+                n = i,
+                p = file_count.len(),
+                c = file_count
+            );
+            return;
+        }
+        _ if rejected => out!(
+            "[{n:>p$}/{c}] skipping file {:?}, rejected by the receiver",
+            files[i].path(),
+            n = i,
+            p = file_count.len(),
+            c = file_count
+        ),
+        Entry::Copy(path, source) => out!(
+            "[{n:>p$}/{c}] skipping file {:?}, a copy of {:?}",
+            path,
+            files[*source].path(),
+            n = i,
+            p = file_count.len(),
+            c = file_count
+        ),
+        Entry::Link(path, source) => out!(
+            "[{n:>p$}/{c}] skipping file {:?}, a link to {:?}",
+            path,
+            files[*source].path(),
+            n = i,
+            p = file_count.len(),
+            c = file_count
+        ),
+        _ => out!(
+            "[{n:>p$}/{c}] skipping unchanged file {:?}",
+            files[i].path(),
+            n = i,
+            p = file_count.len(),
+            c = file_count
+        ),
+    }
+    emit_skipped(events, i, files[i].path());
+}
+
+// The compressor to send the data of a file with, ready for it, unless the data is sent as it
+// is, as it's not being compressed or its codec says so.
+fn compressor_for(compressor: &mut Option<Compressor>, codec: Codec) -> Option<&mut Compressor> {
+    let Codec::Deflate(level) = codec else {
+        return None;
+    };
+    let compressor = compressor.as_mut()?;
+    compressor.start_file(level);
+    Some(compressor)
+}
+
+// Open the file at `i` in the batch, found at `path`, like `open_sent`, unless it could not
+// even be read to list it, in which case it's skipped the same way.
+fn open_listed(
+    batch: &Batch,
+    i: usize,
+    path: &Path,
+    offset: u64,
+    version: u8,
+    state: &mut Vec<u8>,
+) -> Result<std::result::Result<File, String>> {
+    match &batch.unreadable[i - batch.start] {
+        Some(reason) if offset == 0 => {
+            tell_skipped(reason, version, state);
+            Ok(Err(reason.clone()))
+        }
+        _ => open_sent(path, offset, version, state),
+    }
+}
+
+// Open the file at `path` to send its data from `offset`. When starting it, since version 20,
+// the `state` says first whether it could be opened, and one that can't is skipped rather than
+// failing the whole transfer, with the reason returned instead.
+fn open_sent(
+    path: &Path,
+    offset: u64,
+    version: u8,
+    state: &mut Vec<u8>,
+) -> Result<std::result::Result<File, String>> {
+    let telling = offset == 0 && version >= 20;
+    match File::open(path) {
+        Ok(file) => {
+            if telling {
+                tell_sent(version, state);
+            }
+            Ok(Ok(file))
+        }
+        Err(e) if telling => {
+            let reason = e.to_string();
+            tell_skipped(&reason, version, state);
+            Ok(Err(reason))
+        }
+        Err(e) => Err(Error::from(e).at(path)),
+    }
+}
+
+// Say in the `state` of a file that its data follows.
+fn tell_sent(version: u8, state: &mut Vec<u8>) {
+    if version >= 23 {
+        state.extend(&protocol::frame_header(FRAME_FILE_DATA, 0));
+    } else {
+        state.push(DATA_SENT);
+    }
+}
+
+// Say in the `state` of a file that it's skipped, and the `reason` why.
+fn tell_skipped(reason: &str, version: u8, state: &mut Vec<u8>) {
+    let mut reason_len = reason.len().min(MAX_REASON_LEN);
+    while !reason.is_char_boundary(reason_len) {
+        reason_len -= 1;
+    }
+    if version >= 23 {
+        state.extend(&protocol::frame_header(FRAME_ERROR, reason_len as u32));
+    } else {
+        state.push(DATA_SKIPPED);
+        state.extend(&(reason_len as u32).to_le_bytes());
+    }
+    state.extend(&reason.as_bytes()[..reason_len]);
+}
+
+// Append the entire contents of the file, which must be `file_len` bytes long, to `packed`,
+// in chunks if they're being compressed, and in frames since version 23.
+fn read_packed(
+    file: File,
+    path: &Path,
+    (file_len, version): (u64, u8),
+    packed: &mut Vec<u8>,
+    compressor: Option<&mut Compressor>,
+) -> Result<()> {
+    let mut data = Vec::new();
+    Fitted::new(file, file_len)
+        .read_to_end(&mut data)
+        .map_err(|e| Error::from(e).at(path))?;
+    let Some(compressor) = compressor else {
+        if version >= 23 && !data.is_empty() {
+            protocol::push_frame(packed, FRAME_FILE_DATA, &data)?;
+        } else {
+            packed.extend(data);
+        }
+        return Ok(());
+    };
+    let header_len = if version >= 23 { FRAME_HEADER_LEN } else { 0 };
+    for chunk in data.chunks(MAX_COMPRESSED_CHUNK) {
+        let start = packed.len();
+        packed.resize(start + header_len + CHUNK_HEADER_LEN + chunk.len(), 0);
+        let len = compressor.frame(chunk, &mut packed[start + header_len..]);
+        if version >= 23 {
+            let header = protocol::frame_header(FRAME_FILE_DATA, len as u32);
+            packed[start..start + header_len].copy_from_slice(&header);
+        }
+        packed.truncate(start + header_len + len);
+    }
+    Ok(())
+}
+
+// Reads exactly `len` bytes of a file that may change while it's read, since the receiver
+// expects as many as it was told: what it grew by is left out, and what it shrunk by is made
+// up for with zeros.
+struct Fitted<R> {
+    file: R,
+    left: u64,
+}
+
+impl<R: Read> Fitted<R> {
+    fn new(file: R, len: u64) -> Self {
+        Fitted { file, left: len }
+    }
+}
+
+impl<R: Read> Read for Fitted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.left.try_into().unwrap_or(usize::MAX));
+        if len == 0 {
+            return Ok(0);
+        }
+        let read = match self.file.read(&mut buf[..len])? {
+            0 => {
+                buf[..len].fill(0);
+                len
+            }
+            read => read,
+        };
+        self.left -= read as u64;
+        Ok(read)
+    }
+}
+
+// Let it be known if the file at `index` is no longer as it was listed, after sending it, and
+// add it to those that `changed` if it isn't there yet.
+fn check_changed(
+    files: &[Entry],
+    index: usize,
+    batch: &Batch,
+    changed: &mut HashSet<usize>,
+    events: &Option<EventHandler>,
+) {
+    let path = match &files[index] {
+        Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _) => path,
+        _ => return,
+    };
+    let listed = (
+        batch.lens[index - batch.start],
+        batch.modified[index - batch.start],
+    );
+    let now = fs::metadata(path).map(|meta| (meta.len(), modified_secs(&meta)));
+    if now.as_ref().map_or(true, |now| *now != listed) {
+        out!(
+            "{:?} changed while being sent, so the receiver may not have it as it is now",
+            path
+        );
+        emit(
+            events,
+            TransferEvent::FileChanged {
+                index,
+                path: path.to_path_buf(),
+            },
+        );
+        // one that is gone can't be sent again
+        if now.is_ok() {
+            changed.insert(index);
+        }
+    }
+}
+
+// Write the rest of the file into the stream, in chunks that may be compressed if there's a
+// `compressor`, and in frames since version 23. The chunks are read ahead on a thread of their
+// own while the last ones are still being sent, and compressed on a pool of them. Failing to read the file is
+// fatal, but the inner result is the outcome of using the connection, which may be recoverable.
+async fn send_data(
+    stream: &mut TimedStream,
+    path: &Path,
+    file: impl Read + Send + 'static,
+    (chunk_size, version): (usize, u8),
+    compressor: Option<&mut Compressor>,
+    cancel: &Option<CancelToken>,
+    progress: &mut Progress<'_>,
+) -> Result<io::Result<()>> {
+    // the header of the frame is written in front of the data, once its length is known
+    let header_len = if version >= 23 { FRAME_HEADER_LEN } else { 0 };
+    let framed = move |buffer: &mut [u8], len: usize| {
+        if header_len != 0 {
+            let header = protocol::frame_header(FRAME_FILE_DATA, len as u32);
+            buffer[..header_len].copy_from_slice(&header);
+        }
+        header_len + len
+    };
+    let mut compressor = compressor;
+    let link = compressor.as_ref().map(|compressor| compressor.link());
+    // lent to the reader for as long as it goes on
+    let lent = compressor
+        .as_deref_mut()
+        .map(|compressor| std::mem::replace(compressor, Compressor::new(Compression::Never)));
+    let stopped = cancel.clone();
+    // stopping early looks like the end of the file, so it's checked again afterwards
+    let mut reader = match lent {
+        None => pipe::ReadAhead::start(
+            header_len + chunk_size,
+            (file, Compressor::new(Compression::Never)),
+            move |(file, _), buffer| {
+                if cancel::is_cancelled(&stopped) {
+                    return Ok(0);
+                }
+                match file.read(&mut buffer[header_len..])? {
+                    0 => Ok(0),
+                    len => Ok(framed(buffer, len)),
+                }
+            },
+        ),
+        // compressing takes longer than reading, so the chunks are compressed in parallel
+        Some(compressor) => {
+            let data_len = chunk_size.min(MAX_COMPRESSED_CHUNK);
+            pipe::ReadAhead::start_pooled(
+                (file, compressor),
+                move |(file, compressor)| {
+                    if cancel::is_cancelled(&stopped) {
+                        return Ok(None);
+                    }
+                    // smaller chunks compress worse, so they're filled as much as the file allows
+                    let mut data = vec![0; data_len];
+                    let mut len = 0;
+                    while len < data.len() {
+                        match file.read(&mut data[len..]) {
+                            Ok(0) => break,
+                            Ok(n) => len += n,
+                            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    if len == 0 {
+                        return Ok(None);
+                    }
+                    data.truncate(len);
+                    Ok(Some((data, compressor.next_level())))
+                },
+                move |(data, level)| {
+                    let mut chunk = compress::Chunk::new(&data, level, vec![0; header_len]);
+                    let len = chunk.frame.len() - header_len;
+                    framed(&mut chunk.frame, len);
+                    chunk
+                },
+                |(_, compressor), chunk| {
+                    compressor.account(&chunk);
+                    chunk.frame
+                },
+            )
+        }
+    };
+
+    let mut sent = Ok(());
+    while let Some(buffer) = reader.next().await {
+        let started = Instant::now();
+        if let Err(e) = stream.write_all(&buffer).await {
+            sent = Err(e);
+            break;
+        }
+        let len = match &link {
+            Some(link) => {
+                link.record(buffer.len(), started.elapsed());
+                let chunk = &buffer[header_len..header_len + CHUNK_HEADER_LEN];
+                protocol::parse_chunk_header(chunk.try_into().unwrap()).0
+            }
+            None => buffer.len() - header_len,
+        };
+        reader.give_back(buffer);
+        if let Err(e) = progress.sent(stream, len as u64).await {
+            sent = Err(e);
+            break;
+        }
+    }
+    let ((_, lent), read) = reader.finish().await;
+    if let Some(compressor) = compressor {
+        *compressor = lent;
+    }
+    read.map_err(|e| Error::from(e).at(path))?;
+    check(cancel)?;
+    Ok(sent)
+}
+
+// Pick a chunk size large enough to move the largest file at once, but without wasting
+// memory on buffers far bigger than any of the files being sent.
+fn auto_chunk_size(file_lens: &[u64]) -> usize {
+    let largest = file_lens.iter().copied().max().unwrap_or(0);
+    let largest: usize = largest.try_into().unwrap_or(usize::MAX);
+    largest
+        .checked_next_power_of_two()
+        .unwrap_or(usize::MAX)
+        .clamp(MIN_CHUNK_SIZE, MAX_AUTO_CHUNK_SIZE)
+}
+
+// Something to be sent as a single file.
+enum Entry {
+    File(PathBuf),
+    // a file with the same contents as the entry at the index, which the receiver can copy
+    Copy(PathBuf, usize),
+    // a hard link to the same data as the entry at the index, which the receiver can link
+    Link(PathBuf, usize),
+    // a directory sent as an archive of all of its files
+    Archive(PathBuf, archive::Archive),
+    // small files of the directory packed into an archive the receiver unpacks
+    Pack(PathBuf, Vec<tar::Member>),
+    // the file at the index of the source
+    Source(usize, source::FileInfo),
+    // a directory with none of the files sent in it, which would be lost otherwise
+    Dir(PathBuf),
+    // a file with no data, such as a FIFO, of the kind the receiver makes again
+    Special(PathBuf, u8),
+}
+
+impl Entry {
+    fn path(&self) -> &Path {
+        match self {
+            Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _) => path,
+            Entry::Archive(path, _) | Entry::Pack(path, _) => path,
+            Entry::Source(_, file) => &file.path,
+            Entry::Dir(path) | Entry::Special(path, _) => path,
+        }
+    }
+
+    // The name it's sent with, after the path it was found under is renamed.
+    fn name(&self, rename: &[(PathBuf, PathBuf)]) -> Vec<u8> {
+        match self {
+            Entry::File(path)
+            | Entry::Copy(path, _)
+            | Entry::Link(path, _)
+            | Entry::Dir(path)
+            | Entry::Special(path, _) => names::wire_name(&renamed(path, rename)),
+            Entry::Archive(path, archive) => {
+                let mut name = names::wire_name(&renamed(path, rename));
+                // "dir/" should become "dir.tar", not "dir/.tar"
+                while name.last() == Some(&b'/') {
+                    name.pop();
+                }
+                name.push(b'.');
+                name.extend(archive.format.extension().as_bytes());
+                name
+            }
+            // named after its first file, which is where the receiver unpacks it
+            Entry::Pack(_, members) => names::wire_name(&renamed(&members[0].path, rename)),
+            Entry::Source(_, file) => file.name.clone(),
+        }
+    }
+
+    fn len_and_modified(&self) -> io::Result<(u64, u64)> {
+        match self {
+            Entry::File(path) | Entry::Copy(path, _) | Entry::Link(path, _) => {
+                let meta = fs::metadata(path)?;
+                Ok((meta.len(), modified_secs(&meta)))
+            }
+            Entry::Archive(_, archive) => Ok((
+                archive.len(),
+                archive.members.iter().map(|m| m.mtime).max().unwrap_or(0),
+            )),
+            Entry::Pack(_, members) => Ok((
+                tar::ArchiveReader::new(members).len(),
+                members.iter().map(|m| m.mtime).max().unwrap_or(0),
+            )),
+            Entry::Source(_, file) => Ok((file.len, file.modified)),
+            Entry::Dir(path) | Entry::Special(path, _) => {
+                Ok((0, modified_secs(&fs::metadata(path)?)))
+            }
+        }
+    }
+}
+
+// Connect to the peer, telling a peer that isn't listening apart from other failures.
+async fn connect(addr: SocketAddr, socket: &SocketOptions) -> Result<TimedStream> {
+    match TcpStream::connect(addr).await {
+        Ok(stream) => Ok(TimedStream::new(stream, socket)?),
+        Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => Err(Error::Refused { addr }),
+        Err(source) => Err(Error::Network {
+            peer: Some(addr),
+            source,
+        }),
+    }
+}
+
+// Connect to the peer, trying again with a growing delay until it's listening or the `window`
+// is over. Without a window, the first failure is final.
+async fn connect_retrying(
+    addr: SocketAddr,
+    socket: &SocketOptions,
+    window: Option<Duration>,
+    cancel: &Option<CancelToken>,
+) -> Result<TimedStream> {
+    let deadline = window.map(|window| Instant::now() + window);
+    let mut delay = MIN_RETRY_DELAY;
+    loop {
+        check(cancel)?;
+        let e = match connect(addr, socket).await {
+            Err(e @ (Error::Refused { .. } | Error::Network { .. })) => e,
+            result => return result,
+        };
+        let left = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+            None => return Err(e),
+        };
+        if left.is_zero() {
+            return Err(e);
+        }
+        let wait = delay.min(left);
+        match &e {
+            Error::Network { source, .. } => out!(
+                "cannot reach the receiver at {} ({}), retrying in {:.1}s...",
+                addr,
+                source,
+                wait.as_secs_f64()
+            ),
+            _ => out!(
+                "the receiver at {} is not listening yet, retrying in {:.1}s...",
+                addr,
+                wait.as_secs_f64()
+            ),
+        }
+        cancel::sleep(cancel, wait).await?;
+        delay = (delay * 2).min(MAX_RETRY_DELAY);
+    }
+}
+
+// Connect to the receiver again within the given window, and learn where to continue from,
+// along with the rest of the reply since version 23, as it comes in the same frame.
+async fn resume_session(
+    addr: SocketAddr,
+    session: u64,
+    (version, identity, key): (u8, Option<&Identity>, Option<&SessionKey>),
+    socket: &SocketOptions,
+    window: Duration,
+    cancel: &Option<CancelToken>,
+) -> Result<(TimedStream, usize, u64, Vec<u8>)> {
+    let deadline = Instant::now() + window;
+    let stream = loop {
+        check(cancel)?;
+        match TcpStream::connect(addr).await {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => cancel::sleep(cancel, RECONNECT_DELAY).await?,
+            Err(e) => {
+                return Err(Error::Network {
+                    peer: Some(addr),
+                    source: io::Error::new(
+                        e.kind(),
+                        format!("could not reconnect within {}s: {}", window.as_secs(), e),
+                    ),
+                })
+            }
+        }
+    };
+    let mut stream = TimedStream::new(stream, socket)?;
+
+    let mut buffer = vec![b's', b'f', b'+', version];
+    buffer.extend(&session.to_le_bytes());
+    stream.write_all(&buffer).await?;
+    if let Some(identity) = identity {
+        prove_identity(&mut stream, identity, session).await?;
+    }
+    if let Some(key) = key {
+        prove_key(&mut stream, key, session).await?;
+    }
+
+    let mut reply = [0u8; 12];
+    let mut rest = Vec::new();
+    if version >= 23 {
+        let (kind, payload) = match protocol::read_frame(&mut stream, 17 + MAX_FILE_COUNT).await {
+            Ok(frame) => frame,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                return Err(Error::ProtocolViolation(e.to_string()))
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if kind != FRAME_ACK || payload.len() < reply.len() {
+            return Err(Error::ProtocolViolation(format!(
+                "receiver resumed with a frame of kind {} and {} bytes",
+                kind,
+                payload.len()
+            )));
+        }
+        reply.copy_from_slice(&payload[..12]);
+        rest = payload[12..].to_vec();
+    } else {
+        stream.read_exact(&mut reply).await?;
+    }
+    Ok((
+        stream,
+        u32::from_le_bytes(reply[..4].try_into().unwrap()).try_into()?,
+        u64::from_le_bytes(reply[4..].try_into().unwrap()),
+        rest,
+    ))
+}
+
+// Answer the challenge of the receiver with a signature of it by the `identity`, bound to the
+// `session`, to prove the files come from whoever holds its secret key.
+async fn prove_identity(stream: &mut TimedStream, identity: &Identity, session: u64) -> Result<()> {
+    let challenge = read_challenge(stream).await?;
+    let mut reply = identity.public_key().as_bytes().to_vec();
+    reply.extend(&identity.sign(session, &challenge));
+    stream.write_all(&reply).await?;
+    debug!("proved to be {}", identity.public_key());
+    Ok(())
+}
+
+// Answer the challenge of the receiver with proof of knowing the `key` it gave out, bound to
+// the `session` like that of the identity.
+async fn prove_key(stream: &mut TimedStream, key: &SessionKey, session: u64) -> Result<()> {
+    let challenge = read_challenge(stream).await?;
+    stream.write_all(&key.prove(session, &challenge)).await?;
+    debug!("proved to know the session key");
+    Ok(())
+}
+
+// Read the random challenge the receiver sends after an ack.
+async fn read_challenge(stream: &mut TimedStream) -> Result<[u8; CHALLENGE_LEN]> {
+    let mut challenge = [0u8; 1 + CHALLENGE_LEN];
+    read_reply(stream, &mut challenge).await??;
+    if challenge[0] != ACK {
+        return Err(Error::ProtocolViolation(format!(
+            "receiver sent an unknown challenge {:#04x}",
+            challenge[0]
+        )));
+    }
+    Ok(challenge[1..].try_into().unwrap())
+}
+
+// verify packet format:
+// * "sf?"
+// * version: u8
+// * file list len: u32
+// * for each file:
+//   * file len: u64
+//   * file hash: [u8; 32] (SHA-256)
+//   * name len: u32
+//   * name: [u8]
+// to which the receiver replies with:
+// * for each file:
+//   * result: u8 (same, missing or mismatched)
+// * extra file count: u32
+// * for each extra file:
+//   * name len: u32
+//   * name: [u8]
+/// Checks that the receiver at `addr` has the same files, and no others in the same directories.
+pub fn verify(addr: SocketAddr, files: Vec<PathBuf>, options: &VerifyOptions) -> Result<()> {
+    task::block_on(verify_paths(addr, files, options))
+}
+
+async fn verify_paths(
+    addr: SocketAddr,
+    files: Vec<PathBuf>,
+    options: &VerifyOptions,
+) -> Result<()> {
+    let entries = match &options.manifest {
+        Some(_) if !files.is_empty() => {
+            return Err("files cannot be verified along with a manifest".into())
+        }
+        Some(path) => manifest::read(path)?,
+        None => {
+            let cancel = options.cancel.clone();
+            task::blocking(move || manifest::hash_files(&collect_files(files)?, &cancel)).await?
+        }
+    };
+    let verifying = verify_files(addr, entries, options);
+    session::scope(new_session_id(), verifying)
+        .await
+        .map_err(|e| e.with_peer(addr))
+}
+
+async fn verify_files(
+    addr: SocketAddr,
+    files: Vec<manifest::Entry>,
+    options: &VerifyOptions,
+) -> Result<()> {
+    let mut buffer = vec![b's', b'f', b'?', VERSION, 0, 0, 0, 0];
+    for file in files.iter() {
+        buffer.extend(&file.len.to_le_bytes());
+        buffer.extend(&file.digest);
+        let name_len: u32 = file.name.len().try_into()?;
+        buffer.extend(&name_len.to_le_bytes());
+        buffer.extend(&file.name);
+    }
+
+    let buffer_len: u32 = buffer.len().try_into()?;
+    buffer[4..8].copy_from_slice(&buffer_len.to_le_bytes());
+
+    out!("connecting to server {}...", addr);
+    let mut stream = connect(addr, &options.socket).await?;
+    emit(&options.events, TransferEvent::Connected { peer: addr });
+
+    out!("sending file list...");
+    stream.write_all(&buffer).await?;
+
+    let mut results = vec![0u8; files.len()];
+    stream.read_exact(&mut results).await?;
+
+    let mut u32_buffer = [0u8; 4];
+    stream.read_exact(&mut u32_buffer).await?;
+    let extra_count = u32::from_le_bytes(u32_buffer) as usize;
+    if extra_count > MAX_FILE_COUNT {
+        return Err(Error::ProtocolViolation(format!(
+            "too many extra files: {}",
+            extra_count
+        )));
+    }
+    let mut extra = Vec::new();
+    for _ in 0..extra_count {
+        stream.read_exact(&mut u32_buffer).await?;
+        let name_len = u32::from_le_bytes(u32_buffer) as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(Error::ProtocolViolation(format!(
+                "extra file name is too long: {}",
+                name_len
+            )));
+        }
+        let mut name = vec![0u8; name_len];
+        stream.read_exact(&mut name).await?;
+        extra.push(name);
+    }
+
+    let (mut missing, mut mismatched) = (0, 0);
+    for (file, result) in files.iter().zip(results) {
+        let name = names::display(&file.name);
+        match result {
+            SAME => {}
+            MISSING => {
+                missing += 1;
+                out!("missing: {:?}", name);
+            }
+            _ => {
+                mismatched += 1;
+                out!("mismatched: {:?}", name);
+            }
+        }
+        if result != SAME {
+            emit(
+                &options.events,
+                TransferEvent::Corrupt {
+                    path: PathBuf::from(name.into_owned()),
+                },
+            );
+        }
+    }
+    for name in extra.iter() {
+        out!("extra: {:?}", names::display(name));
+    }
+
+    out!(
+        "{} files verified: {} identical, {} missing, {} mismatched, {} extra",
+        files.len(),
+        files.len() - missing - mismatched,
+        missing,
+        mismatched,
+        extra.len()
+    );
+    emit(&options.events, TransferEvent::Finished);
+    if missing + mismatched + extra.len() != 0 {
+        return Err(Error::Differs);
+    }
+    Ok(())
+}
+
+fn modified_secs(meta: &fs::Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
+
+fn new_session_id() -> u64 {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    // the hasher is seeded with random keys, which is all that's needed here
+    RandomState::new().build_hasher().finish()
+}
+
+/// Waits for a sender, and receives its files or lets it verify them.
+pub fn recv(options: ReceiveOptions) -> Result<()> {
+    task::block_on(receive_all(options))
+}
+
+// Receive from one sender after another, for as long as the `options` say to.
+async fn receive_all(mut options: ReceiveOptions) -> Result<()> {
+    // every path is built on top of the output directory, so they can all be long
+    if cfg!(windows) {
+        options.output = names::extended_length(&options.output)
+            .map_err(|e| Error::from(e).at(&options.output))?;
+    }
+    let interface = local_interface(options.include_virtual);
+    let http = match options.http {
+        Some(port) => {
+            let listener = tokio::net::TcpListener::bind((interface.ip, port)).await?;
+            // only those given the link can upload, like those the files are served to
+            let mut token = [0u8; 16];
+            random::fill(&mut token)?;
+            let token = manifest::to_hex(&token);
+            out!(
+                "files can also be uploaded from a browser at http://{}/{}/",
+                listener.local_addr()?,
+                token
+            );
+            Some((listener, token))
+        }
+        None => None,
+    };
+
+    let mut sink = options.sink.take();
+    let mut state = ReceiverState {
+        usage: Arc::default(),
+        checksums: match &options.checksums {
+            Some(path) => Some(open_checksums(path, &options.output, &options.cancel).await?),
+            None => None,
+        },
+    };
+    let hashed = sink.is_none() && options.archive.is_none();
+    let notifier = options
+        .webhook
+        .take()
+        .map(|webhook| webhook.follow(&mut options.events, hashed));
+
+    // the page is served for as long as the transfer goes on
+    let stop = Some(CancelToken::new());
+    let usage = Arc::clone(&state.usage);
+    let uploads = async {
+        if let Some((listener, token)) = &http {
+            http::serve_uploads(listener, token, &options, usage, &stop).await;
+        }
+    };
+    let receiving = async {
+        let result = loop {
+            let result = receive(&interface, &options, &mut sink, &mut state).await;
+            if let Some(checksums) = &state.checksums {
+                if let Err(e) = checksums.save() {
+                    out!("cannot save the index of the received files: {}", e);
+                }
+            }
+            if let Some(notifier) = &notifier {
+                notifier.report(&result);
+            }
+            match result {
+                Ok(()) if options.keep_receiving => {}
+                Err(e) if options.keep_receiving && !matches!(e, Error::Cancelled) => {
+                    out!("transfer failed: {}", e);
+                    let reason = e.to_string();
+                    emit(&options.events, TransferEvent::Failed { reason });
+                }
+                result => break result,
+            }
+        };
+        if let Some(stop) = &stop {
+            stop.cancel();
+        }
+        if let Some(notifier) = notifier {
+            notifier.finish();
+        }
+        result
+    };
+    let ((), result) = tokio::join!(uploads, receiving);
+    result
+}
+
+/// Sends the files, as [`send`] does, to a receiver running in this process, as [`recv`] does,
+/// without going through the network. Everything that happens to the files along the way
+/// still does, from walking the directories and the filters of the receiver to compressing
+/// and hashing the data, so it can stand in for a transfer, or copy the files the way one
+/// would. The files come from the `source` of the sending options if there's one, and go to
+/// the `sink` of the receiving options if there's one.
+///
+/// There's no connection to lose or discover, so the receiver does not listen, is never busy,
+/// and only takes this one transfer.
+pub fn transfer_local(files: Vec<PathBuf>, send: &SendOptions, recv: ReceiveOptions) -> Result<()> {
+    task::block_on(transfer_within(files, send, recv))
+}
+
+async fn transfer_within(
+    files: Vec<PathBuf>,
+    send: &SendOptions,
+    mut recv: ReceiveOptions,
+) -> Result<()> {
+    let outgoing = prepare_send(files, send).await?;
+    if cfg!(windows) {
+        recv.output =
+            names::extended_length(&recv.output).map_err(|e| Error::from(e).at(&recv.output))?;
+    }
+    recv.busy.clear();
+    let mut sink = recv.sink.take();
+    let mut state = ReceiverState {
+        usage: Arc::default(),
+        checksums: match &recv.checksums {
+            Some(path) => Some(open_checksums(path, &recv.output, &recv.cancel).await?),
+            None => None,
+        },
+    };
+
+    let (sender, receiver) = TimedStream::pair(&send.socket);
+    let peer = receiver.peer_addr()?;
+    let (sent, received) = tokio::join!(
+        send_files(peer, Some(sender), outgoing, send),
+        receive_from(None, receiver, &recv, &mut sink, &mut state),
+    );
+    if let Some(checksums) = &state.checksums {
+        if let Err(e) = checksums.save() {
+            out!("cannot save the index of the received files: {}", e);
+        }
+    }
+    // the sender only sees the connection go away when the receiver gives up, so what went
+    // wrong is better told by the receiver
+    received.and(sent)
+}
+
+/// Serves the files, and those inside the directories, over HTTP to anyone with the link
+/// printed for each of them, so that they can be downloaded from a browser. Stops once every
+/// file has been downloaded in full as many times as allowed, or once the time to do so is up.
+pub fn serve(files: Vec<PathBuf>, options: &ServeOptions) -> Result<()> {
+    task::block_on(serve_paths(files, options))
+}
+
+async fn serve_paths(files: Vec<PathBuf>, options: &ServeOptions) -> Result<()> {
+    let interface = local_interface(options.include_virtual);
+    let paths = collect_files(files)?;
+    let names = paths
+        .iter()
+        .map(|path| String::from_utf8_lossy(&names::wire_name(path)).into_owned())
+        .collect::<Vec<_>>();
+    let prefix_len = common_prefix_len(names.iter().map(|name| name.as_bytes()), PathPrefix::Strip);
+    let files = paths
+        .into_iter()
+        .zip(names)
+        .map(|(path, name)| http::Served {
+            path,
+            name: name[prefix_len..].to_string(),
+        })
+        .collect::<Vec<_>>();
+
+    // long and random enough that nobody can find the files without being given the link
+    let mut token = [0u8; 16];
+    random::fill(&mut token)?;
+    let token = manifest::to_hex(&token);
+    let listener = tokio::net::TcpListener::bind((interface.ip, options.port)).await?;
+    let local_addr = listener.local_addr()?;
+    emit(
+        &options.events,
+        TransferEvent::Listening { addr: local_addr },
+    );
+    let expire = match options.expire {
+        Some(expire) => format!(" for the next {} seconds", expire.as_secs()),
+        None => String::new(),
+    };
+    out!(
+        "serving {} files, each can be downloaded {} times{}:",
+        files.len(),
+        options.downloads,
+        expire
+    );
+    if options.token {
+        out!("  code: {}", token);
+        out!("  http://{}/{}/", local_addr, token);
+    }
+    for (index, file) in files.iter().enumerate() {
+        let url = format!(
+            "http://{}/{}/{}",
+            local_addr,
+            token,
+            http::encode(&file.name)
+        );
+        if !options.token {
+            out!("  {}", url);
+        }
+        emit(
+            &options.events,
+            TransferEvent::Shared {
+                index,
+                path: file.path.clone(),
+                url,
+            },
+        );
+    }
+    http::serve_files(&listener, &token, &files, options).await
+}
+
+// The interface to listen on, preferably the one with the default route and, unless told
+// otherwise, not a virtual one.
+fn local_interface(include_virtual: bool) -> NetInterface {
+    get_ip_addresses()
+        .expect("failed to get ip addresses")
+        .into_iter()
+        .filter(|interface| interface.is_up)
+        .min_by_key(|interface| {
+            (
+                interface.is_virtual && !include_virtual,
+                !interface.default_route,
+            )
+        })
+        .expect("no network interface is up")
+}
+
+async fn receive(
+    interface: &NetInterface,
+    options: &ReceiveOptions,
+    sink: &mut Option<BoxedSink>,
+    state: &mut ReceiverState,
+) -> Result<()> {
+    let socket = options.socket;
+    out!(
+        "waiting for client on {} (attempting to broadcast own ip)...",
+        interface.ip
+    );
+    let listener = match &options.listener {
+        Some(listener) => listener.try_clone()?,
+        None => TcpListener::bind((interface.ip, PORT))?,
+    };
+    net::configure_listener(&listener, &socket)?;
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listener)?;
+    emit(
+        &options.events,
+        TransferEvent::Listening {
+            addr: listener.local_addr()?,
+        },
+    );
+    let stream = match survey_potential_clients(&listener, interface, &options.cancel).await {
+        Ok(s) => s,
+        Err(Error::Cancelled) => return Err(Error::Cancelled),
+        Err(e) => {
+            out!(
+                "cannot broadcast ip to potential clients, direct ip must be used:\n  {}",
+                e
+            );
+            accept(&listener, &options.cancel).await?
+        }
+    };
+    let stream = TimedStream::new(stream, &socket)?;
+    receive_from(Some(listener), stream, options, sink, state).await
+}
+
+// Receive the files from the sender that connected, or let it verify them. Without a
+// `listener` to reconnect to, the transfer can't be resumed if the connection is lost.
+async fn receive_from(
+    listener: Option<tokio::net::TcpListener>,
+    stream: TimedStream,
+    options: &ReceiveOptions,
+    sink: &mut Option<BoxedSink>,
+    state: &mut ReceiverState,
+) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    let receiving = receive_session(listener, stream, peer, options, sink, state);
+    session::scope(new_session_id(), receiving).await
+}
+
+async fn receive_session(
+    listener: Option<tokio::net::TcpListener>,
+    mut stream: TimedStream,
+    peer: SocketAddr,
+    options: &ReceiveOptions,
+    sink: &mut Option<BoxedSink>,
+    state: &mut ReceiverState,
+) -> Result<()> {
+    debug!("connection from {}", peer);
+    emit(&options.events, TransferEvent::Connected { peer });
+
+    let output = if options.session_dirs {
+        session_dir(&options.output, peer)
+    } else {
+        options.output.clone()
+    };
+    let staged = options
+        .staging
+        .as_ref()
+        .map(|staging| session_dir(staging, peer));
+
+    let mut header = [0u8; 4];
+    if let Err(e) = stream.read_exact(&mut header).await {
+        return Err(Error::from(e).with_peer(peer));
+    }
+    let result = match &header[..3] {
+        b"sf-" => {
+            let target = Target {
+                output: &output,
+                staged: staged.as_deref(),
+            };
+            let result =
+                receive_files(listener, stream, header[3], &target, options, sink, state).await;
+            // nothing is moved into the output unless the whole transfer went through
+            if let (Err(_), Some(staged)) = (&result, &staged) {
+                let _ = fs::remove_dir_all(staged);
+            }
+            result
+        }
+        b"sf?" if header[3] == VERSION => receive_verify(stream, options).await,
+        b"sf?" => Err(Error::VersionMismatch {
+            theirs: header[3],
+            ours: VERSION,
+        }),
+        _ => Err(Error::Handshake(format!(
+            "unknown header {:?}, is the peer using sf?",
+            &header[..3]
+        ))),
+    };
+    result.map_err(|e| e.with_peer(peer))
+}
+
+// How long until the receiver is no longer busy, if it is now. Windows that follow each other
+// are waited out together.
+fn busy_for(windows: &[DailyWindow]) -> Option<Duration> {
+    let mut now = TimeOfDay::now();
+    let mut busy = Duration::ZERO;
+    while let Some(window) = windows
+        .iter()
+        .find(|window| window.remaining(now).is_some())
+    {
+        let remaining = window.remaining(now)?;
+        busy += remaining;
+        now = window.end;
+        if busy.as_secs() >= 24 * 60 * 60 {
+            break;
+        }
+    }
+    (!busy.is_zero()).then_some(busy)
+}
+
+// A directory inside the output named after the time and the sender, which is not there yet.
+fn session_dir(output: &Path, peer: SocketAddr) -> PathBuf {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    // the colons of IPv6 addresses are not valid in names on Windows
+    let sender = peer.ip().to_string().replace(':', "_");
+    let name = format!("sf-{}-{}", now, sender);
+    let mut dir = output.join(&name);
+    let mut n = 1;
+    while dir.exists() {
+        n += 1;
+        dir = output.join(format!("{}-{}", name, n));
+    }
+    dir
+}
+
+// Where the files of a transfer go. They're compared with what's in the `output`, and said
+// to be there, but their data is written into the `staged` directory first, if any.
+struct Target<'a> {
+    output: &'a Path,
+    staged: Option<&'a Path>,
+}
+
+impl Target<'_> {
+    // The directory the files are written into.
+    fn root(&self) -> &Path {
+        self.staged.unwrap_or(self.output)
+    }
+
+    // Where the data of the file at `path` in the output is written.
+    fn written(&self, path: &Path) -> PathBuf {
+        match (self.staged, path.strip_prefix(self.output)) {
+            (Some(staged), Ok(name)) => staged.join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    // Where the file written at `path` ends up in the output.
+    fn stored(&self, path: &Path) -> PathBuf {
+        match self.staged.map(|staged| path.strip_prefix(staged)) {
+            Some(Ok(name)) => self.output.join(name),
+            _ => path.to_path_buf(),
+        }
+    }
+
+    // Where the file at `path` in the output can be read now, which is where it was written
+    // if it was received in this transfer.
+    fn readable(&self, path: &Path) -> PathBuf {
+        let written = self.written(path);
+        if written.exists() {
+            written
+        } else {
+            path.to_path_buf()
+        }
+    }
+}
+
+// Open the index of the files under `root`, off the runtime, as bringing it up to date hashes
+// those that changed.
+async fn open_checksums(
+    path: &Path,
+    root: &Path,
+    cancel: &Option<CancelToken>,
+) -> Result<Checksums> {
+    let (path, root, cancel) = (path.to_path_buf(), root.to_path_buf(), cancel.clone());
+    task::blocking(move || Checksums::open(&path, &root, &cancel)).await
+}
+
+// Move the files `staged` into the output once they were all received. Either all of them are
+// moved or, if any can't be, none are.
+fn commit_staged(staged: &Path, output: &Path) -> Result<()> {
+    if !staged.exists() {
+        return Ok(());
+    }
+    out!(
+        "moving the received files from {:?} into {:?}...",
+        staged,
+        output
+    );
+    let fail = |e: io::Error| {
+        Error::Other(format!(
+            "cannot move the received files from {:?} into {:?}, so none were: {}",
+            staged, output, e
+        ))
+    };
+    fs::create_dir_all(output).map_err(fail)?;
+    let mut moves = Vec::new();
+    plan_moves(staged, output, &mut moves).map_err(fail)?;
+
+    // the files replaced are kept aside until everything is in place, to put them back if not
+    let mut name = staged.file_name().unwrap_or_default().to_os_string();
+    name.push(".replaced");
+    let replaced = staged.with_file_name(name);
+    let mut done = Vec::with_capacity(moves.len());
+    let moved = moves.iter().enumerate().try_for_each(|(i, (from, to))| {
+        let aside = replaced.join(i.to_string());
+        let replacing = fs::symlink_metadata(to).is_ok();
+        if replacing {
+            fs::create_dir_all(&replaced)?;
+            fs::rename(to, &aside)?;
+        }
+        done.push((from, to, replacing.then_some(aside)));
+        fs::rename(from, to)
+    });
+    if let Err(e) = moved {
+        for (from, to, aside) in done.into_iter().rev() {
+            let _ = fs::rename(to, from);
+            if let Some(aside) = aside {
+                let _ = fs::rename(aside, to);
+            }
+        }
+        let _ = fs::remove_dir_all(&replaced);
+        return Err(fail(e));
+    }
+
+    let _ = fs::remove_dir_all(&replaced);
+    // only the directories whose contents were moved one by one are left behind
+    for entry in WalkDir::new(staged)
+        .contents_first(true)
+        .into_iter()
+        .flatten()
+    {
+        let _ = fs::remove_dir(entry.path());
+    }
+    Ok(())
+}
+
+// Find what to rename to move everything in `from` into `to`. Directories that `to` doesn't
+// have are moved whole, so they appear at once, and those it has get what's inside them moved
+// instead. A link in `to` is never followed, since it could lead out of the output, so only
+// files replace links, and a directory can't be moved where there's a link.
+fn plan_moves(from: &Path, to: &Path, moves: &mut Vec<(PathBuf, PathBuf)>) -> io::Result<()> {
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let target = to.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        match fs::symlink_metadata(&target) {
+            Ok(meta) if is_dir && meta.is_dir() => plan_moves(&entry.path(), &target, moves)?,
+            Ok(meta) if is_dir || meta.is_dir() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("{:?} is in the way", target),
+                ))
+            }
+            _ => moves.push((entry.path(), target)),
+        }
+    }
+    Ok(())
+}
+
+async fn receive_files(
+    listener: Option<tokio::net::TcpListener>,
+    mut stream: TimedStream,
+    version: u8,
+    target: &Target<'_>,
+    options: &ReceiveOptions,
+    sink: &mut Option<BoxedSink>,
+    state: &mut ReceiverState,
+) -> Result<()> {
+    let output = target.output;
+    out!("receiving file list...");
+    let to_sink = sink.is_some();
+    if options.session_dirs && !to_sink {
+        out!("storing the files of this transfer in {:?}", output);
+        emit(
+            &options.events,
+            TransferEvent::Storing {
+                output: output.to_path_buf(),
+            },
+        );
+    }
+
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(Error::VersionMismatch {
+            theirs: version,
+            ours: VERSION,
+        });
+    }
+    if version != VERSION {
+        out!("sender uses older protocol version {}", version);
+    }
+
+    let header = Header::receive(&mut stream, version).await?;
+    debug!("received the header {:?}", header);
+    let (session, flags) = (header.session, header.flags);
+    let proposed_chunk_size = match header.chunk_size {
+        Some(chunk_size) => chunk_size.try_into()?,
+        None => DEFAULT_CHUNK_SIZE,
+    };
+    let chunk_size = proposed_chunk_size
+        .min(options.chunk_size.unwrap_or(MAX_CHUNK_SIZE))
+        .clamp(MIN_CHUNK_SIZE, MAX_CHUNK_SIZE);
+    debug!(
+        "sender uses protocol version {}, flags {:#04x} and chunks of {} bytes",
+        version, flags, proposed_chunk_size
+    );
+    if let Some(session) = session {
+        // to find the same transfer in the log of the sender
+        debug!("sender calls this session {:016x}", session);
+    }
+
+    let unchanged = |file: &ListedFile| {
+        flags & FLAG_UPDATE != 0
+            && options.archive.is_none()
+            && !to_sink
+            && output_path(output, &file.name)
+                .is_ok_and(|path| is_unchanged(&path, file.len as u64, file.modified))
+    };
+
+    // packed files are only unpacked into a directory, where each would be stored on its own
+    let unpacking = flags & FLAG_PACK != 0
+        && version >= 13
+        && !options.list_only
+        && !to_sink
+        && options.archive.is_none()
+        // the files in a pack could not be told apart to filter them
+        && options.filter.is_empty();
+
+    let mut folded = FoldedNames::new(
+        options.case_collisions,
+        options.duplicates,
+        options.normalize,
+    );
+    let mut first_batch = None;
+    let (file_count, prefix_len) = if let Some(file_count) = header.file_count {
+        let file_count: usize = file_count.try_into()?;
+        let prefix_len = match (options.prefix, header.prefix_len) {
+            (PathPrefix::Strip, Some(prefix_len)) => prefix_len.try_into()?,
+            _ => 0,
+        };
+
+        if let Some(busy) = busy_for(&options.busy) {
+            if version >= 15 {
+                let busy_secs: u32 = busy.as_secs().try_into()?;
+                let mut reply = vec![BUSY];
+                reply.extend(&busy_secs.to_le_bytes());
+                stream.write_all(&reply).await?;
+            }
+            return Err(Error::Rejected(format!(
+                "busy until {}",
+                TimeOfDay::now().later(busy)
+            )));
+        }
+        if version >= 15 {
+            stream.write_all(&[ACK]).await?;
+        }
+        let agreed: u32 = chunk_size.try_into()?;
+        stream.write_all(&agreed.to_le_bytes()).await?;
+        if flags & FLAG_PACK != 0 {
+            stream.write_all(&[unpacking as u8]).await?;
+        }
+        (file_count, prefix_len)
+    } else {
+        let mut files = protocol::receive_file_list(&mut stream, version, header.list_len).await?;
+        if version < 5 && !options.filter.is_empty() {
+            return Err("only since protocol version 5 can files be rejected".into());
+        }
+
+        let prefix_len = common_prefix_len(files.iter().map(|file| &file.name[..]), options.prefix);
+        strip_names(&mut files, prefix_len, options.prefix)?;
+        strip_components(&mut files, options.strip_drive, options.strip_components);
+        map_names(&mut files, &options.map);
+        if version < 5 && files.iter().any(|file| file.rejected.is_some()) {
+            return Err("only since protocol version 5 can files be rejected".into());
+        }
+
+        let mut reply = Vec::new();
+        for (i, file) in files.iter_mut().enumerate() {
+            folded.check(i, file)?;
+            if version < 5 && file.rejected.is_some() {
+                return Err("only since protocol version 5 can files be rejected".into());
+            }
+            if file.rejected.is_none() {
+                file.rejected = options.filter.rejects(&file.name, file.len as u64);
+            }
+            file.wanted = !options.list_only && file.rejected.is_none() && !unchanged(file);
+            reply.push(if file.wanted { WANT } else { SKIP });
+        }
+        if version == 6 {
+            let agreed: u32 = chunk_size.try_into()?;
+            reply.extend(&agreed.to_le_bytes());
+        }
+        if version >= 5 {
+            stream.write_all(&reply).await?;
+        }
+        let file_count = files.len();
+        first_batch = Some(files);
+        (file_count, 0)
+    };
+    out!("using chunks of {} KiB", chunk_size / 1024);
+
+    let ReceiverState { usage, checksums } = state;
+    let quota = Quota::new(Arc::clone(usage), stream.peer_addr()?.ip(), options);
+    let stored = if flags & FLAG_STORED != 0 {
+        Some(StoredReports {
+            framed: version >= 23,
+            stored: 0,
+            told_at: Instant::now(),
+        })
+    } else {
+        None
+    };
+    let mut peer = Peer {
+        listener,
+        stream: BufReader::with_capacity(PACKED_FILE_LEN as usize, stream),
+        session,
+        sender: None,
+        keyed: false,
+        key: options.key,
+        socket: options.socket,
+        reconnect: options.reconnect,
+        version,
+        prefix: options.prefix,
+        prefix_len,
+        strip_drive: options.strip_drive,
+        strip_components: options.strip_components,
+        map: &options.map,
+        unpacking,
+        compressed: flags & FLAG_COMPRESS != 0 && version >= 17,
+        first_batch,
+        listed: 0,
+        batch_start: 0,
+        wanted: Vec::new(),
+        filter: &options.filter,
+        rejected: HashSet::new(),
+        quota,
+        stored,
+        events: &options.events,
+        cancel: &options.cancel,
+        pause: &options.pause,
+    };
+    // the sender is told why the transfer failed, rather than finding the connection gone
+    let result = async {
+        peer.identify(flags, options.authorized_senders.as_ref())
+            .await?;
+        peer.check_key(flags).await?;
+        if options.list_only {
+            return list_files(&mut peer, options).await;
+        }
+        if sink.is_some() {
+            out!("receiving files into the sink...");
+            receive_into(&mut peer, file_count, options, chunk_size, sink).await?;
+            peer.finish().await;
+            emit(&options.events, TransferEvent::Finished);
+            return Ok(());
+        }
+        if let Some(format) = options.archive {
+            return receive_archive(&mut peer, file_count, format, output, options, chunk_size)
+                .await;
+        }
+
+        let mut directory = match &options.encrypt_at_rest {
+            Some(recipient) => sink::Directory::encrypted(target.root(), recipient.clone()),
+            None => sink::Directory::new(target.root()),
+        };
+        directory.owner = options.owner;
+        directory.file_mode = options.file_mode;
+        directory.dir_mode = options.dir_mode;
+        let extracting = |file: &ListedFile| {
+            options
+                .extract
+                .then(|| extract::Format::of(&file.name))
+                .flatten()
+        };
+        let mut received = HashSet::new();
+        // what the filters left out, which is not listed with the hashes either
+        let mut rejected = HashSet::new();
+        // where every file so far is stored, to make the copies from
+        let mut paths = Vec::new();
+
+        // the files stored before under any name, by their index, found through their hash
+        let mut known = HashMap::new();
+
+        let file_count = file_count.to_string();
+        loop {
+            let batch = peer
+                .next_batch(&mut |i, file| {
+                    folded.check(i, file)?;
+                    // archives are only ever there once extracted, so they're always wanted
+                    if file.packed || extracting(file).is_some() {
+                        return Ok(true);
+                    }
+                    if unchanged(file) || file.copy_of.is_some() {
+                        return Ok(false);
+                    }
+                    let found = match (checksums.as_ref(), &file.hash) {
+                        (Some(checksums), Some(digest)) => checksums.find(digest, file.len as u64),
+                        _ => None,
+                    };
+                    match found {
+                        Some(source) => {
+                            known.insert(i, source);
+                            Ok(false)
+                        }
+                        None => Ok(true),
+                    }
+                })
+                .await?;
+            let Some((start, files)) = batch else {
+                break;
+            };
+            for (i, file) in (start..).zip(files) {
+                let path = output_path(output, &file.name)?;
+                paths.push(if file.directory || file.special.is_some() {
+                    path
+                } else {
+                    directory.stored_path(path)
+                });
+                let path = paths[i].as_path();
+                if let Some(reason) = &file.rejected {
+                    out!(
+                        "[{n:>p$}/{c}] rejecting file {:?}, as {}",
+                        path,
+                        reason,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit_skipped(&options.events, i, path);
+                    // left as it was, rather than deleted as if it had not been sent, unless
+                    // it's where another file with the same name was stored
+                    if received.insert(path.to_path_buf()) {
+                        rejected.insert(path.to_path_buf());
+                    }
+                    continue;
+                }
+                if file.directory {
+                    out!(
+                        "[{n:>p$}/{c}] creating empty directory {:?}",
+                        path,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    directory
+                        .create_dir(&target.written(path))
+                        .map_err(|e| Error::from(e).at(path))?;
+                    received.insert(path.to_path_buf());
+                    continue;
+                }
+                if let Some(kind) = file.special {
+                    out!(
+                        "[{n:>p$}/{c}] making special file {:?}",
+                        path,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    match directory.create_special(&target.written(path), kind) {
+                        Ok(()) => {
+                            received.insert(path.to_path_buf());
+                        }
+                        // only some systems can, and a file without data is not worth failing
+                        Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                            out!("cannot make special file {:?}: {}", path, e);
+                        }
+                        Err(e) => return Err(Error::from(e).at(path)),
+                    }
+                    continue;
+                }
+                let skipped = if file.wanted {
+                    peer.sender_skipped(i).await?
+                } else {
+                    None
+                };
+                if let Some(reason) = skipped {
+                    out!(
+                        "[{n:>p$}/{c}] skipping file {:?}, as the sender can't read it: {}",
+                        path,
+                        reason,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit(
+                        &options.events,
+                        TransferEvent::Unreadable {
+                            index: Some(i),
+                            path: path.to_path_buf(),
+                            reason,
+                        },
+                    );
+                    // left as it was, rather than deleted as if it had not been sent
+                    received.insert(path.to_path_buf());
+                    continue;
+                }
+                if file.packed {
+                    let dir = path.parent().unwrap_or(output);
+                    out!(
+                        "[{n:>p$}/{c}] receiving packed files into {:?}...",
+                        dir,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit_started(&options.events, i, path, file.len as u64);
+                    // the files packed were listed, and reserved, one by one already
+                    let extraction = Extraction::start(
+                        extract::Format::Tar,
+                        target.written(dir),
+                        directory,
+                        extract::Limits::default(),
+                    );
+                    let (extraction, result) =
+                        peer.receive_file(i, extraction, &file, chunk_size).await;
+                    let unpacked;
+                    (directory, unpacked) = extraction.finish(result);
+                    let unpacked = unpacked.map_err(|e| e.at(dir))?;
+                    emit(&options.events, TransferEvent::FileDone { index: i });
+                    received.extend(unpacked.paths.iter().map(|path| target.stored(path)));
+                    continue;
+                }
+                if let Some(format) = extracting(&file) {
+                    out!(
+                        "[{n:>p$}/{c}] receiving and extracting archive {:?}...",
+                        path,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit_started(&options.events, i, path, file.len as u64);
+                    let dir = path.parent().unwrap_or(output);
+                    // the members may take up what the archive would have, and what's left of
+                    // the quotas, but no more than the most any archive may unpack to
+                    let len = file.len as u64;
+                    let limit = len.saturating_mul(MAX_EXPANSION).max(MIN_EXTRACTED_LEN);
+                    let limits = extract::Limits {
+                        filter: options.filter.clone(),
+                        max_len: Some(match peer.quota.headroom() {
+                            Some(headroom) => limit.min(headroom.saturating_add(len)),
+                            None => limit,
+                        }),
+                    };
+                    let extraction =
+                        Extraction::start(format, target.written(dir), directory, limits);
+                    let (extraction, result) =
+                        peer.receive_file(i, extraction, &file, chunk_size).await;
+                    let extracted;
+                    (directory, extracted) = extraction.finish(result);
+                    let extracted = extracted.map_err(|e| e.at(path))?;
+                    // the archive itself was counted once received
+                    peer.quota
+                        .charge_unlisted(extracted.len.saturating_sub(len));
+                    emit(&options.events, TransferEvent::FileDone { index: i });
+                    received.extend(extracted.paths.iter().map(|path| target.stored(path)));
+                    continue;
+                }
+                let source = match file.copy_of {
+                    Some(source) if source < i => Some(paths[source].as_path()),
+                    Some(_) => {
+                        return Err(Error::ProtocolViolation(format!(
+                            "file {} is a copy of a file not listed before it",
+                            i
+                        )))
+                    }
+                    None => known.get(&i).map(PathBuf::as_path),
+                };
+                let source = source.map(|source| target.readable(source));
+                if let Some(source) = source.as_deref().filter(|_| file.link && !unchanged(&file)) {
+                    out!(
+                        "[{n:>p$}/{c}] linking file {:?} to {:?}...",
+                        path,
+                        source,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit_started(&options.events, i, path, file.len as u64);
+                    link_file(source, &target.written(path), file.modified, &mut directory)?;
+                    emit(&options.events, TransferEvent::FileDone { index: i });
+                    received.insert(path.to_path_buf());
+                    continue;
+                }
+                if let Some(source) = source.as_deref().filter(|_| !unchanged(&file)) {
+                    out!(
+                        "[{n:>p$}/{c}] copying file {:?} from {:?}...",
+                        path,
+                        source,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit_started(&options.events, i, path, file.len as u64);
+                    let written = target.written(path);
+                    directory
+                        .copy_path(&written, source, file.modified)
+                        .map_err(|e| Error::from(e).at(path))?;
+                    apply_xattrs(&written, &file.xattrs, &options.xattrs);
+                    apply_attributes(&written, &file);
+                    if let (Some(checksums), Some(digest)) = (checksums.as_mut(), file.hash) {
+                        checksums.add(path, digest)?;
+                    }
+                    emit(&options.events, TransferEvent::FileDone { index: i });
+                    received.insert(path.to_path_buf());
+                    continue;
+                }
+                if !file.wanted {
+                    out!(
+                        "[{n:>p$}/{c}] skipping unchanged file {:?}",
+                        path,
+                        n = i,
+                        p = file_count.len(),
+                        c = file_count
+                    );
+                    emit_skipped(&options.events, i, path);
+                    received.insert(path.to_path_buf());
+                    continue;
+                }
+                out!(
+                    "[{n:>p$}/{c}] receiving file {:?} ({})...",
+                    path,
+                    format_bytes(file.len as u64),
+                    n = i,
+                    p = file_count.len(),
+                    c = file_count
+                );
+                emit_started(&options.events, i, path, file.len as u64);
+                let written = target.written(path);
+                directory
+                    .open_path(&written, file.modified)
+                    .map_err(|e| Error::from(e).at(path))?;
+                // before leaving the staging directory, files are checked against their hash
+                let verify = options.staging.is_some() && file.hash.is_some();
+                let writer =
+                    hash::Writer::new(SinkWriter(directory), checksums.is_some() || verify);
+                let (writer, result) = peer.receive_file(i, writer, &file, chunk_size).await;
+                let (SinkWriter(received_into), digest) = writer.finish();
+                directory = received_into;
+                match result {
+                    Ok(()) => directory
+                        .close_entry()
+                        .map_err(|e| Error::from(e).at(path))?,
+                    Err(e) => {
+                        directory.abort_entry();
+                        return Err(e.at(path));
+                    }
+                }
+                if verify && file.hash != digest {
+                    return Err(Error::Other(format!(
+                        "the hash of {:?} does not match the one sent with it",
+                        path
+                    )));
+                }
+                apply_xattrs(&written, &file.xattrs, &options.xattrs);
+                apply_attributes(&written, &file);
+                if let (Some(checksums), Some(digest)) = (checksums.as_mut(), digest) {
+                    // what was received is indexed, in case it's not what the sender said
+                    if file.hash.is_some_and(|sent| sent != digest) {
+                        out!("the hash of {:?} does not match the one sent with it", path);
+                    }
+                    checksums.add(path, digest)?;
+                }
+                emit(&options.events, TransferEvent::FileDone { index: i });
+                received.insert(path.to_path_buf());
+            }
+        }
+
+        peer.finish().await;
+
+        if let Some(staged) = target.staged {
+            let (staged, output) = (staged.to_path_buf(), output.to_path_buf());
+            task::blocking(move || commit_staged(&staged, &output)).await?;
+        }
+        if options.mirror {
+            if let Some(path) = checksums.as_ref().and_then(Checksums::own_path) {
+                received.insert(path);
+            }
+            mirror(output, &received, options.dry_run).await?;
+        }
+        if let Some(format) = options.sums {
+            let stored = received.difference(&rejected).cloned().collect();
+            let (output, cancel) = (output.to_path_buf(), options.cancel.clone());
+            task::blocking(move || write_sums(&output, stored, format, &cancel)).await?;
+        }
+
+        emit(&options.events, TransferEvent::Finished);
+        Ok(())
+    }
+    .await;
+    if let Err(e) = &result {
+        peer.abort(e).await;
+    }
+    result
+}
+
+// Hash the files `stored` in the output as they are now, and list them there in a file of the
+// `format`, named as they are within the output.
+fn write_sums(
+    output: &Path,
+    stored: Vec<PathBuf>,
+    format: SumsFormat,
+    cancel: &Option<CancelToken>,
+) -> Result<()> {
+    let mut paths = stored
+        .into_iter()
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    paths.sort();
+    let mut entries = manifest::hash_files(&paths, cancel)?;
+    for (entry, path) in entries.iter_mut().zip(&paths) {
+        entry.name = names::wire_name(path.strip_prefix(output).unwrap_or(path));
+    }
+    let path = output.join(format.file_name());
+    match format {
+        SumsFormat::Sha256Sums => manifest::write_sums(&path, &entries)?,
+        SumsFormat::Json => manifest::write(&path, &entries)?,
+    }
+    out!("wrote the hashes of {} files to {:?}", entries.len(), path);
+    Ok(())
+}
+
+// Print the files the sender lists, and write them to the JSON file if any, without wanting
+// any of them, so that the sender is done once it has listed them all.
+async fn list_files(peer: &mut Peer<'_>, options: &ReceiveOptions) -> Result<()> {
+    if peer.version < 5 {
+        return Err("only since protocol version 5 can the files be declined".into());
+    }
+    let mut files = Vec::new();
+    while let Some((_, batch)) = peer.next_batch(&mut |_, _| Ok(false)).await? {
+        files.extend(
+            batch
+                .into_iter()
+                .filter(|file| !file.directory && file.special.is_none()),
+        );
+    }
+    peer.finish().await;
+
+    let mut total = 0u64;
+    let mut json = String::from("{\n  \"files\": [");
+    for (i, file) in files.iter().enumerate() {
+        let name = names::display(&file.name);
+        out!("{:>10} {}", format_bytes(file.len as u64), name);
+        total += file.len as u64;
+        json.push_str(&format!(
+            "{}\n    {{\"path\": {}, \"size\": {}",
+            if i == 0 { "" } else { "," },
+            json::string(&name),
+            file.len
+        ));
+        if let Some(modified) = file.modified {
+            json.push_str(&format!(", \"mtime\": {}", modified));
+        }
+        json.push('}');
+    }
+    json.push_str(&format!("\n  ],\n  \"total\": {}\n}}\n", total));
+    out!(
+        "{} files, {} in total, all declined",
+        files.len(),
+        format_bytes(total)
+    );
+
+    if let Some(path) = &options.list_json {
+        fs::write(path, json).map_err(|e| Error::from(e).at(path))?;
+        out!("wrote the list to {:?}", path);
+    }
+    emit(&options.events, TransferEvent::Finished);
+    Ok(())
+}
+
+// Pack every received file into a single archive in the output directory.
+async fn receive_archive(
+    peer: &mut Peer<'_>,
+    file_count: usize,
+    format: ArchiveFormat,
+    output: &Path,
+    options: &ReceiveOptions,
+    chunk_size: usize,
+) -> Result<()> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let path = output.join(format!("sf-{}.{}", now, format.extension()));
+    out!("receiving files into archive {:?}...", path);
+
+    fs::create_dir_all(output).map_err(|e| Error::from(e).at(output))?;
+    let part_path = partial_path(&path);
+    let f = File::create(&part_path).map_err(|e| Error::from(e).at(&part_path))?;
+    let sink = archive::sink(format, f).map_err(|e| Error::from(e).at(&part_path))?;
+    let mut archive = Some(sink);
+    let result = receive_into(peer, file_count, options, chunk_size, &mut archive).await;
+    drop(archive);
+
+    match result {
+        Ok(()) => fs::rename(&part_path, &path).map_err(|e| Error::from(e).at(&path))?,
+        Err(e) => {
+            let _ = fs::remove_file(&part_path);
+            return Err(e.at(&part_path));
+        }
+    }
+
+    peer.finish().await;
+    emit(&options.events, TransferEvent::Finished);
+    Ok(())
+}
+
+// Hand every received file over to the sink, in full. The sink is lent to the thread writing the
+// data of each file, and always put back in the slot by the time this returns.
+async fn receive_into<S: Sink + Send + 'static>(
+    peer: &mut Peer<'_>,
+    file_count: usize,
+    options: &ReceiveOptions,
+    chunk_size: usize,
+    slot: &mut Option<S>,
+) -> Result<()> {
+    let file_count = file_count.to_string();
+    // names that differ in case don't collide in a sink, unless normalized into the same one
+    let mut folded = FoldedNames::new(
+        CaseCollisions::Ignore,
+        options.duplicates,
+        options.normalize,
+    );
+    let mut wanted = |i, file: &mut ListedFile| {
+        folded.check(i, file)?;
+        Ok(true)
+    };
+    while let Some((start, files)) = peer.next_batch(&mut wanted).await? {
+        for (i, file) in (start..).zip(files) {
+            // sinks only store files, which is all the directories would have been for
+            if file.directory || file.special.is_some() {
+                continue;
+            }
+            let path = names::relative_path(&file.name).unwrap_or_default();
+            if let Some(reason) = &file.rejected {
+                out!(
+                    "[{n:>p$}/{c}] rejecting file {:?}, as {}",
+                    names::display(&file.name),
+                    reason,
+                    n = i,
+                    p = file_count.len(),
+                    c = file_count
+                );
+                emit_skipped(&options.events, i, &path);
+                continue;
+            }
+            if let Some(reason) = peer.sender_skipped(i).await? {
+                out!(
+                    "[{n:>p$}/{c}] skipping file {:?}, as the sender can't read it: {}",
+                    names::display(&file.name),
+                    reason,
+                    n = i,
+                    p = file_count.len(),
+                    c = file_count
+                );
+                emit(
+                    &options.events,
+                    TransferEvent::Unreadable {
+                        index: Some(i),
+                        path,
+                        reason,
+                    },
+                );
+                continue;
+            }
+            out!(
+                "[{n:>p$}/{c}] receiving file {:?} ({})...",
+                names::display(&file.name),
+                format_bytes(file.len as u64),
+                n = i,
+                p = file_count.len(),
+                c = file_count
+            );
+            emit_started(&options.events, i, &path, file.len as u64);
+            let mut sink = slot.take().expect("sink lent twice");
+            if let Err(e) = sink.open_entry(&file.name, file.len.try_into()?, file.modified) {
+                *slot = Some(sink);
+                return Err(Error::from(e).at(&path));
+            }
+            let (SinkWriter(mut sink), result) = peer
+                .receive_file(i, SinkWriter(sink), &file, chunk_size)
+                .await;
+            let result = match result {
+                Ok(()) => sink.close_entry().map_err(|e| Error::from(e).at(&path)),
+                Err(e) => {
+                    sink.abort_entry();
+                    Err(e)
+                }
+            };
+            *slot = Some(sink);
+            result?;
+            emit(&options.events, TransferEvent::FileDone { index: i });
+        }
+    }
+    Ok(slot.as_mut().expect("sink not put back").finish()?)
+}
+
+// A file as listed by the sender.
+#[derive(Default)]
+struct ListedFile {
+    len: usize,
+    modified: Option<u64>,
+    // relative to the output directory, once the common prefix is stripped
+    name: Vec<u8>,
+    // the index of an earlier file with the same contents
+    copy_of: Option<usize>,
+    // whether it should be a hard link to that file rather than a copy
+    link: bool,
+    // the names and values of its extended attributes
+    xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    // the hash of its data, if the sender included it
+    hash: Option<hash::Digest>,
+    // how long the part of the name before the argument it was sent under is, if known
+    root: Option<usize>,
+    // whether it's an archive of small files to be unpacked next to it
+    packed: bool,
+    // whether its data is sent in chunks, if the sender said
+    chunked: Option<bool>,
+    // when it was created, and whether it's read-only or hidden, if the sender said
+    created: Option<u64>,
+    attributes: Option<u8>,
+    // whether it's an empty directory to create, rather than a file
+    directory: bool,
+    // the kind of special file to make, such as a FIFO, rather than a file
+    special: Option<u8>,
+    wanted: bool,
+    // why the filters of the receiver leave it out, if they do
+    rejected: Option<String>,
+}
+
+// The connection to the sender, which can be re-established if the session allows it.
+struct Peer<'a> {
+    // where the sender reconnects to, if it can
+    listener: Option<tokio::net::TcpListener>,
+    // many tiny files are read at once rather than one by one
+    stream: BufReader<TimedStream>,
+    session: Option<u64>,
+    // the key the sender proved to hold, which it must prove again to resume (since version 22)
+    sender: Option<PublicKey>,
+    // whether the sender proved to know the session key, which it must again to resume, and the
+    // one given out, if any, which it must be (since version 24)
+    keyed: bool,
+    key: Option<SessionKey>,
+    socket: SocketOptions,
+    reconnect: Option<Duration>,
+    version: u8,
+    prefix: PathPrefix,
+    prefix_len: usize,
+    strip_drive: bool,
+    strip_components: usize,
+    map: &'a [PathMap],
+    // whether the sender was told it can pack files together
+    unpacking: bool,
+    // whether the sender sends the data in chunks, which may be compressed
+    compressed: bool,
+    // before version 7, the list is received all at once in the header
+    first_batch: Option<Vec<ListedFile>>,
+    // how many files the sender has listed so far
+    listed: usize,
+    batch_start: usize,
+    wanted: Vec<u8>,
+    filter: &'a FileFilter,
+    // the files left out by the filter so far, which others can't be copies of
+    rejected: HashSet<usize>,
+    quota: Quota,
+    stored: Option<StoredReports>,
+    events: &'a Option<EventHandler>,
+    cancel: &'a Option<CancelToken>,
+    pause: &'a Option<PauseToken>,
+}
+
+impl Peer<'_> {
+    // Receive the next batch of the file list and let the sender know which of its files
+    // are `wanted`, waiting for the sender to reconnect as many times as needed. Returns the
+    // index of the first file in the batch, or `None` once the list is over.
+    async fn next_batch(
+        &mut self,
+        wanted: &mut (dyn FnMut(usize, &mut ListedFile) -> Result<bool> + Send),
+    ) -> Result<Option<(usize, Vec<ListedFile>)>> {
+        if self.version < 7 {
+            let Some(files) = self.first_batch.take() else {
+                return Ok(None);
+            };
+            self.quota.reserve(&files)?;
+            self.listed = files.len();
+            return Ok(Some((0, files)));
+        }
+
+        let start = self.listed;
+        loop {
+            check(self.cancel)?;
+            let mut files = match self.read_batch().await? {
+                Ok(files) => files,
+                Err(e) => {
+                    self.resume(e, start, 0, &[RESUME_LIST]).await?;
+                    continue;
+                }
+            };
+            if files.is_empty() {
+                return Ok(None);
+            }
+            if self.listed + files.len() > MAX_FILE_COUNT {
+                return Err(Error::ProtocolViolation(format!(
+                    "too many files: more than {}",
+                    MAX_FILE_COUNT
+                )));
+            }
+
+            for (i, file) in (start..).zip(files.iter_mut()) {
+                if file.rejected.is_none() {
+                    file.rejected = self.filter.rejects(&file.name, file.len as u64);
+                }
+                // there's nothing to copy from, so the data is needed after all
+                if file
+                    .copy_of
+                    .is_some_and(|source| self.rejected.contains(&source))
+                {
+                    file.copy_of = None;
+                    file.link = false;
+                }
+                // it may be rejected while checked too, as when another has the same name
+                let want = file.rejected.is_none() && wanted(i, file)?;
+                if file.rejected.is_some() {
+                    self.rejected.insert(i);
+                    file.wanted = false;
+                    continue;
+                }
+                // there's no data to a directory or a special file, only its name
+                file.wanted = want && !file.directory && file.special.is_none();
+            }
+            self.quota.reserve(&files)?;
+            self.wanted = files
+                .iter()
+                .map(|file| match &file.rejected {
+                    Some(_) if self.version >= 16 => REJECT,
+                    _ if file.wanted => WANT,
+                    _ => SKIP,
+                })
+                .collect();
+            if let Err(e) = write_reply(self.stream.get_mut(), self.version, &self.wanted).await {
+                // the sender may not know what was wanted, so it will have to list them again
+                self.resume(e, start, 0, &[RESUME_LIST]).await?;
+                continue;
+            }
+
+            trace!(
+                "listed files {} to {}, {} of them wanted",
+                start,
+                start + files.len(),
+                files.iter().filter(|file| file.wanted).count()
+            );
+            self.listed += files.len();
+            self.batch_start = start;
+            return Ok(Some((start, files)));
+        }
+    }
+
+    // Read a single batch of the file list. Malformed lists are fatal, but the inner result
+    // is the outcome of using the connection, which may be recoverable.
+    async fn read_batch(&mut self) -> Result<io::Result<Vec<ListedFile>>> {
+        let mut files = match protocol::read_batch(&mut self.stream, self.version).await? {
+            Ok(files) => files,
+            Err(e) => return Ok(Err(e)),
+        };
+        if !self.unpacking && files.iter().any(|file| file.packed) {
+            return Err(Error::ProtocolViolation(
+                "sender packed files without being told it can".into(),
+            ));
+        }
+        strip_names(&mut files, self.prefix_len, self.prefix)?;
+        strip_components(&mut files, self.strip_drive, self.strip_components);
+        map_names(&mut files, self.map);
+        Ok(Ok(files))
+    }
+
+    // Receive the data of the `file` at `index` into `f`, waiting for the sender to reconnect
+    // as many times as needed, and give it back once done with it.
+    async fn receive_file<W: Write + Send + 'static>(
+        &mut self,
+        index: usize,
+        mut f: W,
+        file: &ListedFile,
+        chunk_size: usize,
+    ) -> (W, Result<()>) {
+        let file_len = file.len;
+        let mut written = 0;
+        let mut resumed = false;
+        loop {
+            // starting over, the sender says again whether it can read the file
+            if resumed && written == 0 && self.version >= 20 {
+                match self.read_state().await {
+                    Ok(Ok(None)) => {}
+                    Ok(Ok(Some(reason))) => {
+                        let reason = format!("the sender can no longer read the file: {}", reason);
+                        return (f, Err(Error::Other(reason)));
+                    }
+                    Ok(Err(e)) => {
+                        if let Err(e) = self.resume_data(e, index, 0).await {
+                            return (f, Err(e));
+                        }
+                        continue;
+                    }
+                    Err(e) => return (f, Err(e)),
+                }
+            }
+            let mut progress = Written {
+                index,
+                len: written,
+                stored: &mut self.stored,
+                events: self.events,
+            };
+            let mut data = FileData::new(&mut self.stream, self.version);
+            let tokens = (self.cancel, self.pause);
+            let len = (file_len, chunk_size);
+            let received;
+            (f, received) = if self.compressed && file.chunked != Some(false) {
+                receive_chunks(&mut data, f, len, tokens, &mut progress).await
+            } else {
+                receive_data(&mut data, f, len, tokens, &mut progress).await
+            };
+            written = progress.len;
+            let received = match received.and_then(|received| Ok((received, check(tokens.0)?))) {
+                Ok((received, ())) => received,
+                Err(e) => return (f, Err(e)),
+            };
+            match received {
+                // a frame that goes on past the file would have the start of the next one
+                Ok(()) if !data.is_done() => {
+                    let reason = "sender sent a frame longer than the rest of the file".into();
+                    return (f, Err(Error::ProtocolViolation(reason)));
+                }
+                Ok(()) => {
+                    self.quota.charge(file_len as u64);
+                    return (f, Ok(()));
+                }
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    return (f, Err(Error::ProtocolViolation(e.to_string())))
+                }
+                Err(e) => {
+                    if let Err(e) = self.resume_data(e, index, written).await {
+                        return (f, Err(e));
+                    }
+                    resumed = true;
+                }
+            }
+        }
+    }
+
+    // Read whether the sender follows with the data of the wanted file at `index`, or the
+    // reason it can't read it, waiting for the sender to reconnect as many times as needed.
+    async fn sender_skipped(&mut self, index: usize) -> Result<Option<String>> {
+        if self.version < 20 {
+            return Ok(None);
+        }
+        loop {
+            match self.read_state().await? {
+                Ok(skipped) => return Ok(skipped),
+                Err(e) => self.resume_data(e, index, 0).await?,
+            }
+        }
+    }
+
+    // Read what the sender says before the data of a file, and the reason if it skipped it.
+    // Malformed states are fatal, but the inner result is the outcome of using the connection,
+    // which may be recoverable.
+    async fn read_state(&mut self) -> Result<io::Result<Option<String>>> {
+        if self.version >= 23 {
+            return match protocol::read_frame(&mut self.stream, MAX_REASON_LEN).await {
+                Ok((FRAME_FILE_DATA, payload)) if payload.is_empty() => Ok(Ok(None)),
+                Ok((FRAME_ERROR, reason)) => {
+                    Ok(Ok(Some(String::from_utf8_lossy(&reason).into_owned())))
+                }
+                Ok((kind, payload)) => Err(Error::ProtocolViolation(format!(
+                    "sender neither sent nor skipped a file, but sent a frame of kind {} and {} bytes",
+                    kind,
+                    payload.len()
+                ))),
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => {
+                    Err(Error::ProtocolViolation(e.to_string()))
+                }
+                Err(e) => Ok(Err(e)),
+            };
+        }
+        let mut state = [0u8];
+        if let Err(e) = self.stream.read_exact(&mut state).await {
+            return Ok(Err(e));
+        }
+        match state[0] {
+            DATA_SENT => return Ok(Ok(None)),
+            DATA_SKIPPED => {}
+            state => {
+                return Err(Error::ProtocolViolation(format!(
+                    "sender neither sent nor skipped a file: {:#04x}",
+                    state
+                )))
+            }
+        }
+        let mut u32_buffer = [0u8; 4];
+        if let Err(e) = self.stream.read_exact(&mut u32_buffer).await {
+            return Ok(Err(e));
+        }
+        let reason_len: usize = u32::from_le_bytes(u32_buffer).try_into()?;
+        if reason_len > MAX_REASON_LEN {
+            return Err(Error::ProtocolViolation(format!(
+                "reason to skip a file is too long: {} bytes",
+                reason_len
+            )));
+        }
+        let mut reason = vec![0; reason_len];
+        if let Err(e) = self.stream.read_exact(&mut reason).await {
+            return Ok(Err(e));
+        }
+        Ok(Ok(Some(String::from_utf8_lossy(&reason).into_owned())))
+    }
+
+    // Wait for the sender to reconnect after the connection was lost with `e` while receiving
+    // the data of the file at `index`, and let it know to continue from `offset`.
+    async fn resume_data(&mut self, e: io::Error, index: usize, offset: usize) -> Result<()> {
+        let mut state = Vec::new();
+        if self.version >= 7 {
+            let remaining = &self.wanted[index - self.batch_start..];
+            let remaining_len: u32 = remaining.len().try_into()?;
+            state.push(RESUME_DATA);
+            state.extend(&remaining_len.to_le_bytes());
+            state.extend(remaining);
+        }
+        self.resume(e, index, offset, &state).await
+    }
+
+    // Wait for the sender to reconnect after the connection was lost with `e`, if the session
+    // allows it, and let it know to continue from the file at `index`.
+    async fn resume(
+        &mut self,
+        e: io::Error,
+        index: usize,
+        offset: usize,
+        state: &[u8],
+    ) -> Result<()> {
+        match (self.session, self.reconnect, &self.listener) {
+            (Some(session), Some(window), Some(listener)) => {
+                out!(
+                    "connection lost ({}), waiting for the sender to reconnect...",
+                    e
+                );
+                let index: u32 = index.try_into()?;
+                let offset: u64 = offset.try_into()?;
+                let mut reply = Vec::new();
+                reply.extend(&index.to_le_bytes());
+                reply.extend(&offset.to_le_bytes());
+                reply.extend(state);
+
+                let stream = await_resume(
+                    listener,
+                    session,
+                    (
+                        self.version,
+                        self.sender.as_ref(),
+                        self.keyed.then_some(self.key.as_ref()),
+                    ),
+                    &reply,
+                    &self.socket,
+                    window,
+                    self.cancel,
+                )
+                .await?;
+                self.stream = BufReader::with_capacity(PACKED_FILE_LEN as usize, stream);
+                debug!("sender resumed from file {} at offset {}", index, offset);
+                if let Ok(peer) = self.stream.get_ref().peer_addr() {
+                    emit(self.events, TransferEvent::Connected { peer });
+                }
+                Ok(())
+            }
+            _ => Err(Error::Network {
+                peer: None,
+                source: e,
+            }),
+        }
+    }
+
+    // Have the sender prove it holds the secret key of its identity if it has one, and say who
+    // it is. Senders without one are only accepted when anyone is.
+    async fn identify(&mut self, flags: u8, authorized: Option<&AuthorizedSenders>) -> Result<()> {
+        let session = match self.session {
+            Some(session) if flags & FLAG_IDENTITY != 0 && self.version >= 14 => session,
+            _ if authorized.is_some() => {
+                return Err(Error::Rejected(
+                    "only authorized senders are accepted, and the sender has no identity".into(),
+                ))
+            }
+            _ => return Ok(()),
+        };
+        let challenge = send_challenge(self.stream.get_mut()).await?;
+        let key = read_proof(&mut self.stream, session, &challenge).await?;
+        let sender = match authorized {
+            Some(authorized) => authorized
+                .find(&key)
+                .ok_or_else(|| Error::Rejected(format!("sender {} is not authorized", key)))?,
+            None => key.to_string(),
+        };
+        out!("sender is {}", sender);
+        if self.version >= 22 {
+            self.sender = Some(key);
+        } else if authorized.is_some() && self.reconnect.take().is_some() {
+            // whoever knew the session could resume it without proving anything
+            out!("the sender can't prove its identity again, so it won't be able to reconnect");
+        }
+        Ok(())
+    }
+
+    // Have the sender prove it knows the session key, if the receiver gave one out. Senders
+    // that prove it anyway are challenged all the same, as they wait for it.
+    async fn check_key(&mut self, flags: u8) -> Result<()> {
+        let session =
+            match self.session {
+                Some(session) if flags & FLAG_KEY != 0 && self.version >= 24 => session,
+                _ if self.key.is_some() => return Err(Error::Rejected(
+                    "only senders that know the session key are accepted, and the sender has none"
+                        .into(),
+                )),
+                _ => return Ok(()),
+            };
+        let challenge = send_challenge(self.stream.get_mut()).await?;
+        read_key_proof(&mut self.stream, session, &challenge, self.key.as_ref()).await?;
+        self.keyed = true;
+        Ok(())
+    }
+
+    // Let the sender know why the transfer failed with `e`, so that it stops sending and says
+    // so, giving it some time to read it before the connection is closed.
+    async fn abort(&mut self, e: &Error) {
+        if self.version < 11 || matches!(e, Error::Network { .. }) {
+            return;
+        }
+        let mut reason = e.to_string();
+        let mut reason_len = reason.len().min(MAX_REASON_LEN);
+        while !reason.is_char_boundary(reason_len) {
+            reason_len -= 1;
+        }
+        reason.truncate(reason_len);
+
+        let mut message = Vec::new();
+        if self.version >= 23 {
+            message.extend(&protocol::frame_header(FRAME_ERROR, reason_len as u32));
+        } else {
+            message.push(ABORT);
+            message.extend(&(reason_len as u32).to_le_bytes());
+        }
+        message.extend(reason.as_bytes());
+        let stream = self.stream.get_mut();
+        let told = async {
+            stream.write_all(&message).await?;
+            stream.shutdown_write().await?;
+            stream.drain(ABORT_DELAY).await
+        };
+        if let Err(e) = told.await {
+            debug!("cannot tell the sender why the transfer failed: {}", e);
+        }
+    }
+
+    // Let the sender know that everything was received.
+    async fn finish(&mut self) {
+        if self.session.is_some() {
+            // everything is on disk; if the sender misses this, there is nothing left to resume
+            let _ = write_reply(self.stream.get_mut(), self.version, &[ACK]).await;
+        }
+    }
+}
+
+// How much of the data of the file at `index` was written, which is reported as it goes.
+struct Written<'a> {
+    index: usize,
+    len: usize,
+    stored: &'a mut Option<StoredReports>,
+    events: &'a Option<EventHandler>,
+}
+
+impl Written<'_> {
+    // Count another `len` bytes as written, and tell the sender through the `stream` if it's
+    // time to say how much is stored.
+    async fn add(&mut self, stream: &mut TimedStream, len: usize) {
+        self.len += len;
+        emit_progress(self.events, self.index, self.len as u64);
+        let Some(message) = self
+            .stored
+            .as_mut()
+            .and_then(|stored| stored.add(len as u64))
+        else {
+            return;
+        };
+        // if the connection is lost, reading from it will say so
+        if let Err(e) = stream.write_all(&message).await {
+            debug!("cannot tell the sender how much is stored: {}", e);
+        }
+    }
+}
+
+// Tells the sender every so often how much of the file data is stored, if it asked to know.
+struct StoredReports {
+    // whether it's said in an extension frame, since version 23
+    framed: bool,
+    stored: u64,
+    told_at: Instant,
+}
+
+impl StoredReports {
+    // Count another `len` bytes as stored, and return what to tell the sender if it's time.
+    fn add(&mut self, len: u64) -> Option<Vec<u8>> {
+        self.stored += len;
+        if self.told_at.elapsed() < STORED_INTERVAL {
+            return None;
+        }
+        self.told_at = Instant::now();
+        let mut message = Vec::new();
+        if self.framed {
+            message.extend(&protocol::frame_header(FRAME_EXTENSION, 9));
+        }
+        message.push(STORED);
+        message.extend(&self.stored.to_le_bytes());
+        Some(message)
+    }
+}
+
+// Delete the files under `root` which were not received, asking for confirmation first.
+async fn mirror(root: &Path, received: &HashSet<PathBuf>, dry_run: bool) -> Result<()> {
+    // walking and deleting are done off the runtime, with what they need owned
+    let root = root.to_path_buf();
+    let received = Arc::new(received.clone());
+    let stale = {
+        let (root, received) = (root.clone(), Arc::clone(&received));
+        task::blocking(move || find_stale(&root, &received)).await?
+    };
+
+    if stale.is_empty() {
+        out!("mirror: no files to delete");
+        return Ok(());
+    }
+    let mut size = 0;
+    for (path, len) in stale.iter() {
+        out!("mirror: {:?} ({}) was not sent", path, format_bytes(*len));
+        size += len;
+    }
+    if dry_run {
+        out!(
+            "mirror: {} files ({}) would be deleted (dry run)",
+            stale.len(),
+            format_bytes(size)
+        );
+        return Ok(());
+    }
+
+    print!("mirror: delete {} files? [y/N] ", stale.len());
+    io::stdout().flush()?;
+    // the same reader as the one pausing on enter, which would otherwise take the answer
+    let answer = input::read_line().await.unwrap_or_default();
+    if !["y", "yes"].contains(&answer.trim().to_lowercase().as_str()) {
+        out!("mirror: nothing deleted");
+        return Ok(());
+    }
+
+    let count = stale.len();
+    task::blocking(move || delete_stale(&root, stale, &received)).await?;
+    out!("mirror: deleted {} files", count);
+
+    Ok(())
+}
+
+// The files under the `root` that weren't `received`, along with how large each one is.
+fn find_stale(root: &Path, received: &HashSet<PathBuf>) -> Result<Vec<(PathBuf, u64)>> {
+    let mut stale = Vec::new();
+    for entry in WalkDir::new(root) {
+        let entry = entry?;
+        if entry.file_type().is_file() && !received.contains(entry.path()) {
+            let len = entry.metadata().map_or(0, |meta| meta.len());
+            stale.push((entry.into_path(), len));
+        }
+    }
+    Ok(stale)
+}
+
+// Delete the `stale` files under the `root`, and the directories left empty by it.
+fn delete_stale(
+    root: &Path,
+    stale: Vec<(PathBuf, u64)>,
+    received: &HashSet<PathBuf>,
+) -> Result<()> {
+    let mut parents = BTreeSet::new();
+    for (path, _) in stale.iter() {
+        fs::remove_file(path).map_err(|e| Error::from(e).at(path))?;
+        parents.extend(path.ancestors().skip(1).take_while(|p| *p != root));
+    }
+    // the empty directories that were sent stay, like the files
+    parents.retain(|dir| !received.contains(*dir));
+    // deepest directories first, so that emptied trees are removed entirely;
+    // directories which still have something in them will fail to be removed
+    for dir in parents.into_iter().rev() {
+        let _ = fs::remove_dir(dir);
+    }
+    Ok(())
+}
+
+async fn receive_verify(mut stream: TimedStream, options: &ReceiveOptions) -> Result<()> {
+    if options.authorized_senders.is_some() {
+        return Err(Error::Rejected(
+            "only authorized senders are accepted, and verifying doesn't say who asks".into(),
+        ));
+    }
+    if options.key.is_some() {
+        return Err(Error::Rejected(
+            "only senders that know the session key are accepted, and verifying doesn't prove it"
+                .into(),
+        ));
+    }
+    out!("receiving file list to verify...");
+    let mut u32_buffer = [0u8; 4];
+    stream.read_exact(&mut u32_buffer).await?;
+    let buffer_len: usize = u32::from_le_bytes(u32_buffer).try_into()?;
+
+    // minus 4 header, 4 buffer len
+    let list_len = match buffer_len.checked_sub(8) {
+        Some(len) if len <= MAX_LIST_LEN => len,
+        _ => {
+            return Err(Error::ProtocolViolation(format!(
+                "bad file list length: {}",
+                buffer_len
+            )))
+        }
+    };
+    let buffer = protocol::receive_list(&mut stream, list_len).await?;
+    let files = parse_verify_list(&buffer).map_err(|e| Error::ProtocolViolation(e.to_string()))?;
+
+    let common_prefix_len =
+        common_prefix_len(files.iter().map(|(_, _, name)| *name), options.prefix);
+    let files = files
+        .into_iter()
+        .map(|(file_len, digest, name)| {
+            let name = &name[common_prefix_len..];
+            let name = names::strip_components(name, options.strip_drive, options.strip_components)
+                .ok_or_else(|| {
+                    Error::Other(format!(
+                        "nothing is left of {:?} once stripped",
+                        names::display(name)
+                    ))
+                })?;
+            let mapped = names::map(name, &options.map);
+            let path = output_path(&options.output, mapped.as_deref().unwrap_or(name))?;
+            Ok((file_len, digest, path))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // every file is read, so it's done off the runtime
+    let output = options.output.clone();
+    let cancel = options.cancel.clone();
+    let (verified, extra) = task::blocking(move || compare_files(files, &output, &cancel)).await?;
+    let mut results = Vec::with_capacity(verified.len());
+    for (path, result) in verified {
+        if result != SAME {
+            emit(&options.events, TransferEvent::Corrupt { path });
+        }
+        results.push(result);
+    }
+
+    let (missing, mismatched) = (
+        results.iter().filter(|r| **r == MISSING).count(),
+        results.iter().filter(|r| **r == MISMATCHED).count(),
+    );
+    out!(
+        "{} files verified: {} identical, {} missing, {} mismatched, {} extra",
+        results.len(),
+        results.len() - missing - mismatched,
+        missing,
+        mismatched,
+        extra.len()
+    );
+
+    let mut buffer = results;
+    let extra_count: u32 = extra.len().try_into()?;
+    buffer.extend(&extra_count.to_le_bytes());
+    for name in extra {
+        let name_len: u32 = name.len().try_into()?;
+        buffer.extend(&name_len.to_le_bytes());
+        buffer.extend(name);
+    }
+    stream.write_all(&buffer).await?;
+
+    emit(&options.events, TransferEvent::Finished);
+    Ok(())
+}
+
+// How each file listed to verify compares, with the names as sent of the extra ones found.
+type Compared = (Vec<(PathBuf, u8)>, Vec<Vec<u8>>);
+
+// Compare the files with the length and hash the sender listed them with, and find the extra
+// ones in the directories verified.
+fn compare_files(
+    files: Vec<(u64, hash::Digest, PathBuf)>,
+    output: &Path,
+    cancel: &Option<CancelToken>,
+) -> Result<Compared> {
+    let mut results = Vec::with_capacity(files.len());
+    let mut listed = HashSet::new();
+    let file_count = files.len().to_string();
+    let mut queue = files.into_iter().enumerate();
+    pipe::ordered_pool(
+        || {
+            if cancel::is_cancelled(cancel) {
+                return None;
+            }
+            let (i, (file_len, digest, path)) = queue.next()?;
+            out!(
+                "[{n:>p$}/{c}] verifying file {:?}...",
+                path,
+                n = i,
+                p = file_count.len(),
+                c = file_count
+            );
+            Some((file_len, digest, path))
+        },
+        |(file_len, digest, path)| {
+            let result = match fs::metadata(&path) {
+                Err(_) => Ok(MISSING),
+                Ok(meta) if meta.len() != file_len => Ok(MISMATCHED),
+                Ok(_) => {
+                    hash::hash_file(&path).map(|d| if d != digest { MISMATCHED } else { SAME })
+                }
+            };
+            (path, result)
+        },
+        |(path, result)| -> Result<()> {
+            let result = result.map_err(|e| Error::from(e).at(&path))?;
+            listed.insert(path.clone());
+            results.push((path, result));
+            Ok(())
+        },
+    )?;
+    check(cancel)?;
+
+    // only the directories being verified are checked for extra files, since the rest of
+    // the working directory most likely has nothing to do with what was sent
+    let roots = listed
+        .iter()
+        .filter_map(|path| {
+            let mut components = path.strip_prefix(output).ok()?.components();
+            let root = components.next()?;
+            components.next().map(|_| output.join(root.as_os_str()))
+        })
+        .collect::<BTreeSet<_>>();
+
+    let mut extra = Vec::new();
+    for root in roots.into_iter().filter(|root| root.is_dir()) {
+        for entry in WalkDir::new(root) {
+            let entry = entry?;
+            if entry.path().is_file() && !listed.contains(entry.path()) {
+                let name = entry
+                    .path()
+                    .strip_prefix(output)
+                    .expect("walked path outside of its root");
+                extra.push(names::wire_name(name));
+            }
+        }
+    }
+    Ok((results, extra))
+}
+
+// Determine how many bytes to strip from the start of every name.
+fn common_prefix_len<'a>(names: impl Iterator<Item = &'a [u8]>, prefix: PathPrefix) -> usize {
+    let mut common_prefix = match prefix {
+        // the common prefix will only ever shorten, so if it starts empty, there won't be any
+        PathPrefix::Keep => Some(&b""[..]),
+        PathPrefix::Strip => None,
+    };
+
+    for name in names {
+        common_prefix = Some(match common_prefix {
+            None => name,
+            Some(prefix) => {
+                if let Some(equal_up_to) = prefix.iter().zip(name).position(|(x, y)| x != y) {
+                    &prefix[..equal_up_to]
+                } else {
+                    prefix
+                }
+            }
+        });
+    }
+
+    let common_prefix = common_prefix.unwrap_or_default();
+    if let Some(sep_idx) = common_prefix
+        .iter()
+        .rposition(|c| PATH_SEPARATORS.contains(c))
+    {
+        // +1 to exclude the separator itself
+        sep_idx + 1
+    } else {
+        // there is no parent, it's all separate files at the same level, so there is nothing to strip
+        0
+    }
+}
+
+// Read the rest of the file from the stream into `f`, keeping track of how much has been
+// `written`, and give it back once done with it. The data is written on a thread of its own
+// while the next of it is still being received. Failing to write the file is fatal, but the
+// inner result is the outcome of using the connection, which may be recoverable.
+async fn receive_data<W: Write + Send + 'static>(
+    data: &mut FileData<&mut BufReader<TimedStream>>,
+    f: W,
+    (file_len, chunk_size): (usize, usize),
+    (cancel, pause): (&Option<CancelToken>, &Option<PauseToken>),
+    written: &mut Written<'_>,
+) -> (W, Result<io::Result<()>>) {
+    let mut remaining = file_len - written.len;
+    if remaining == 0 {
+        return (f, Ok(Ok(())));
+    }
+    let mut storing = pipe::WriteBehind::start(chunk_size.min(remaining), f, |f, data| {
+        f.write_all(data)?;
+        Ok(data.len())
+    });
+    // stopping early looks like the end of the file, so the caller must check again
+    let received = loop {
+        if remaining == 0 {
+            break Ok(());
+        }
+        pause::wait(pause, cancel, written.events).await;
+        if cancel::is_cancelled(cancel) {
+            break Ok(());
+        }
+        // it only stops taking more once it failed, which is told once it's finished
+        let Some((mut buffer, stored)) = storing.chunk().await else {
+            break Ok(());
+        };
+        if stored != 0 {
+            written.add(data.get_mut().get_mut(), stored).await;
+        }
+        let len = remaining.min(buffer.len());
+        let n = match data.read(&mut buffer[..len]).await {
+            Ok(0) => {
+                break Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "connection ended without receiving full file",
+                ))
+            }
+            Ok(n) => n,
+            Err(e) => break Err(e),
+        };
+        buffer.truncate(n);
+        storing.write(buffer);
+        remaining -= n;
+    };
+    let (f, stored) = storing.finish().await;
+    match stored {
+        Ok(stored) => {
+            written.add(data.get_mut().get_mut(), stored).await;
+            (f, Ok(received))
+        }
+        Err(e) => (f, Err(e.into())),
+    }
+}
+
+// Like `receive_data`, but reading the data in the chunks it was sent in, and decompressing
+// those that were compressed along with writing them. Only whole chunks are written, so that
+// the sender can resume from what was.
+async fn receive_chunks<W: Write + Send + 'static>(
+    data: &mut FileData<&mut BufReader<TimedStream>>,
+    f: W,
+    (file_len, chunk_size): (usize, usize),
+    (cancel, pause): (&Option<CancelToken>, &Option<PauseToken>),
+    written: &mut Written<'_>,
+) -> (W, Result<io::Result<()>>) {
+    let mut remaining = file_len - written.len;
+    if remaining == 0 {
+        return (f, Ok(Ok(())));
+    }
+    // a chunk is never larger compressed, or it would have been sent as it was
+    let buffer_len = CHUNK_HEADER_LEN + chunk_size.min(remaining);
+    let mut storing = pipe::WriteBehind::start(
+        buffer_len,
+        (f, Vec::new(), None),
+        |(f, inflated, violation): &mut (W, Vec<u8>, Option<String>), chunk: &[u8]| {
+            let (header, chunk) = chunk.split_at(CHUNK_HEADER_LEN);
+            let (len, compressed_len) = protocol::parse_chunk_header(header.try_into().unwrap());
+            if compressed_len == 0 {
+                f.write_all(chunk)?;
+                return Ok(len);
+            }
+            inflated.resize(len, 0);
+            let mut inflate = Inflate::new(chunk);
+            let reason = match inflate
+                .read_exact(inflated)
+                .and_then(|()| inflate.read(&mut [0]))
+            {
+                Ok(0) => {
+                    f.write_all(inflated)?;
+                    return Ok(len);
+                }
+                Ok(_) => "sender sent a chunk longer than it said".to_string(),
+                Err(e) => format!("sender sent a bad chunk ({})", e),
+            };
+            *violation = Some(reason);
+            Err(io::Error::new(io::ErrorKind::InvalidData, "invalid chunk"))
+        },
+    );
+    // how much data came in chunks, and how much it took to send it
+    let (mut raw, mut sent) = (0u64, 0u64);
+    let received = loop {
+        if remaining != 0 {
+            pause::wait(pause, cancel, written.events).await;
+        }
+        if remaining == 0 || cancel::is_cancelled(cancel) {
+            break Ok(());
+        }
+        let Some((mut buffer, stored)) = storing.chunk().await else {
+            break Ok(());
+        };
+        if stored != 0 {
+            written.add(data.get_mut().get_mut(), stored).await;
+        }
+        let (header, chunk) = buffer.split_at_mut(CHUNK_HEADER_LEN);
+        if let Err(e) = data.read_exact(header).await {
+            break Err(e);
+        }
+        let (len, compressed_len) = protocol::parse_chunk_header((&*header).try_into().unwrap());
+        trace!(
+            "received a chunk of {} bytes, compressed to {}, {} left",
+            len,
+            compressed_len,
+            remaining
+        );
+        if len == 0 || len > remaining.min(chunk.len()) {
+            let reason = format!("sender sent a chunk of {} bytes", len);
+            return (
+                storing.finish().await.0 .0,
+                Err(Error::ProtocolViolation(reason)),
+            );
+        }
+        if compressed_len >= len {
+            let reason = format!(
+                "sender compressed a chunk of {} bytes into {}",
+                len, compressed_len
+            );
+            return (
+                storing.finish().await.0 .0,
+                Err(Error::ProtocolViolation(reason)),
+            );
+        }
+        let wire_len = if compressed_len == 0 {
+            len
+        } else {
+            compressed_len
+        };
+        if let Err(e) = data.read_exact(&mut chunk[..wire_len]).await {
+            break Err(e);
+        }
+        buffer.truncate(CHUNK_HEADER_LEN + wire_len);
+        storing.write(buffer);
+        remaining -= len;
+        raw += len as u64;
+        sent += wire_len as u64;
+    };
+    if raw != 0 {
+        emit(written.events, TransferEvent::Compressed { raw, sent });
+    }
+    let ((f, _, violation), stored) = storing.finish().await;
+    match (stored, violation) {
+        (_, Some(reason)) => (f, Err(Error::ProtocolViolation(reason))),
+        (Ok(stored), None) => {
+            written.add(data.get_mut().get_mut(), stored).await;
+            (f, Ok(received))
+        }
+        (Err(e), None) => (f, Err(e.into())),
+    }
+}
+
+// Write the `reply` to the sender, in an ack frame since version 23.
+async fn write_reply(stream: &mut TimedStream, version: u8, reply: &[u8]) -> io::Result<()> {
+    if version < 23 {
+        return stream.write_all(reply).await;
+    }
+    let mut message = Vec::with_capacity(FRAME_HEADER_LEN + reply.len());
+    message.extend(&protocol::frame_header(FRAME_ACK, reply.len() as u32));
+    message.extend(reply);
+    stream.write_all(&message).await
+}
+
+// Wait for a connection, unless asked to stop waiting first.
+async fn accept(
+    listener: &tokio::net::TcpListener,
+    cancel: &Option<CancelToken>,
+) -> Result<TcpStream> {
+    check(cancel)?;
+    tokio::select! {
+        accepted = listener.accept() => Ok(accepted?.0),
+        () = cancel::cancelled(cancel) => Err(Error::Cancelled),
+    }
+}
+
+// Send the sender a random challenge to sign, preceded by an ack.
+async fn send_challenge(stream: &mut TimedStream) -> Result<[u8; CHALLENGE_LEN]> {
+    let mut challenge = [0u8; CHALLENGE_LEN];
+    random::fill(&mut challenge)?;
+    stream.write_all(&[ACK]).await?;
+    stream.write_all(&challenge).await?;
+    Ok(challenge)
+}
+
+// Read the public key of the sender and its signature of the `challenge`, returning the key
+// if the signature was made with it for this `session`.
+async fn read_proof(
+    stream: &mut (impl AsyncRead + Unpin),
+    session: u64,
+    challenge: &[u8],
+) -> Result<PublicKey> {
+    let mut reply = [0u8; ed25519::PUBLIC_KEY_LEN + ed25519::SIGNATURE_LEN];
+    stream.read_exact(&mut reply).await?;
+    let (key, signature) = reply.split_at(ed25519::PUBLIC_KEY_LEN);
+    let key = PublicKey::from_bytes(key.try_into().unwrap());
+    if !key.verify(session, challenge, signature.try_into().unwrap()) {
+        return Err(Error::Rejected(format!(
+            "the sender could not prove to be {}",
+            key
+        )));
+    }
+    Ok(key)
+}
+
+// Read the proof of the sender that it knows the session key, and check it against the `key`
+// given out if there is one.
+async fn read_key_proof(
+    stream: &mut (impl AsyncRead + Unpin),
+    session: u64,
+    challenge: &[u8],
+    key: Option<&SessionKey>,
+) -> Result<()> {
+    let mut proof = [0u8; 32];
+    stream.read_exact(&mut proof).await?;
+    if key.is_some_and(|key| !key.verify(session, challenge, &proof)) {
+        return Err(Error::Rejected(
+            "the sender does not know the session key".into(),
+        ));
+    }
+    Ok(())
+}
+
+// Wait for the sender of the session to connect again within the given window, proving to
+// be the `sender` if it did before, and to know the session `key` if it did before (which is
+// checked if there's one), and let it know where to continue from with the `reply`.
+async fn await_resume(
+    listener: &tokio::net::TcpListener,
+    session: u64,
+    (version, sender, key): (u8, Option<&PublicKey>, Option<Option<&SessionKey>>),
+    reply: &[u8],
+    socket: &SocketOptions,
+    window: Duration,
+    cancel: &Option<CancelToken>,
+) -> Result<TimedStream> {
+    let deadline = tokio::time::Instant::now() + window;
+    loop {
+        let Ok(accepted) = tokio::time::timeout_at(deadline, accept(listener, cancel)).await else {
+            break Err(Error::Network {
+                peer: None,
+                source: io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!(
+                        "sender did not reconnect within {}s, aborting",
+                        window.as_secs()
+                    ),
+                ),
+            });
+        };
+        let mut stream = TimedStream::new(accepted?, socket)?;
+
+        let mut header = [0u8; 12];
+        let resumes_session = stream.read_exact(&mut header).await.is_ok()
+            && &header[..3] == b"sf+"
+            && header[3] == version
+            && header[4..] == session.to_le_bytes();
+
+        if resumes_session {
+            let proven = match sender {
+                Some(sender) => match send_challenge(&mut stream).await {
+                    Ok(challenge) => read_proof(&mut stream, session, &challenge)
+                        .await
+                        .is_ok_and(|key| key == *sender),
+                    Err(_) => false,
+                },
+                None => true,
+            };
+            let proven = proven
+                && match key {
+                    Some(key) => match send_challenge(&mut stream).await {
+                        Ok(challenge) => read_key_proof(&mut stream, session, &challenge, key)
+                            .await
+                            .is_ok(),
+                        Err(_) => false,
+                    },
+                    None => true,
+                };
+            if proven {
+                write_reply(&mut stream, version, reply).await?;
+                break Ok(stream);
+            }
+            out!("ignoring connection that could not prove to be the sender");
+            continue;
+        }
+        out!("ignoring unrelated connection while waiting for the sender");
+    }
+}
+
+// Where the file with the given name is stored, which must not be outside of the output
+// directory. See `names::relative_path` for how the name is turned into a path.
+fn output_path(output: &Path, name: &[u8]) -> Result<PathBuf> {
+    match names::relative_path(name) {
+        None => Err(Error::ProtocolViolation(format!(
+            "file name {:?} points outside of the output directory",
+            names::display(name)
+        ))),
+        Some(path) if path.as_os_str().is_empty() => Err(Error::ProtocolViolation(format!(
+            "file name {:?} is empty",
+            names::display(name)
+        ))),
+        Some(path) => Ok(output.join(path)),
+    }
+}
+
+// Whether the file at `path` already has the given length and modification time.
+fn is_unchanged(path: &Path, len: u64, modified: Option<u64>) -> bool {
+    fs::metadata(path).is_ok_and(|meta| meta.len() == len && Some(modified_secs(&meta)) == modified)
+}
+
+// Store exactly `len` bytes of the `data` at `path`, within the output of the `directory`, which
+// is given back along with the outcome.
+async fn store_file(
+    mut directory: sink::Directory,
+    path: &Path,
+    modified: Option<u64>,
+    data: &mut (impl AsyncRead + Unpin),
+    len: u64,
+) -> (sink::Directory, Result<()>) {
+    if let Err(e) = directory.open_path(path, modified) {
+        return (directory, Err(Error::from(e).at(path)));
+    }
+    let (mut directory, copied) = copy_data(data, len, directory).await;
+    let result = match copied {
+        Ok(()) => directory.close_entry().map_err(|e| Error::from(e).at(path)),
+        Err(e) => {
+            directory.abort_entry();
+            Err(e.at(path))
+        }
+    };
+    (directory, result)
+}
+
+// Copy exactly `len` bytes of the `data` into the current entry of the sink, which is written
+// on a thread of its own while more is read.
+async fn copy_data<S: Sink + Send + 'static>(
+    data: &mut (impl AsyncRead + Unpin),
+    len: u64,
+    sink: S,
+) -> (S, Result<()>) {
+    let chunk_size = MIN_CHUNK_SIZE.min(len as usize);
+    let mut storing = pipe::WriteBehind::start(chunk_size, sink, |sink, chunk| {
+        sink.write_chunk(chunk)?;
+        Ok(chunk.len())
+    });
+    let mut left = len;
+    let copied = loop {
+        if left == 0 {
+            break Ok(());
+        }
+        // it only stops taking more once it failed, which is told once it's finished
+        let Some((mut buffer, _)) = storing.chunk().await else {
+            break Ok(());
+        };
+        let wanted = buffer.len().min(left as usize);
+        let n = match data.read(&mut buffer[..wanted]).await {
+            Ok(0) => break Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Ok(n) => n,
+            Err(e) => break Err(e),
+        };
+        buffer.truncate(n);
+        storing.write(buffer);
+        left -= n as u64;
+    };
+    let (sink, stored) = storing.finish().await;
+    (sink, copied.and(stored.map(drop)).map_err(Error::from))
+}
+
+// Make the file at `path` a hard link to the one at `source`, or a copy of it if the file
+// system can't link them.
+fn link_file(
+    source: &Path,
+    path: &Path,
+    modified: Option<u64>,
+    directory: &mut sink::Directory,
+) -> Result<()> {
+    directory
+        .create_parent(path)
+        .map_err(|e| Error::from(e).at(path))?;
+
+    // linked under another name first, since the file may be replacing an existing one
+    let part_path = partial_path(path);
+    let _ = fs::remove_file(&part_path);
+    match fs::hard_link(source, &part_path) {
+        Ok(()) => fs::rename(&part_path, path).map_err(|e| Error::from(e).at(path)),
+        Err(e) => {
+            out!(
+                "cannot link {:?} to {:?} ({}), copying it instead",
+                path,
+                source,
+                e
+            );
+            directory
+                .copy_path(path, source, modified)
+                .map_err(|e| Error::from(e).at(path))
+        }
+    }
+}
+
+// Path where the data for the file at `path` is written until it has been fully received.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".");
+    name.push(PARTIAL_EXTENSION);
+    path.with_file_name(name)
+}
+
+// The length, hash and name of every file in the list sent to verify them.
+fn parse_verify_list(mut list: &[u8]) -> io::Result<Vec<(u64, hash::Digest, &[u8])>> {
+    let malformed = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed file list: {}", what),
+        )
+    };
+
+    let mut files = Vec::new();
+    while !list.is_empty() {
+        if files.len() == MAX_FILE_COUNT {
+            return Err(malformed("too many files"));
+        }
+        let (file_len, rest) = list
+            .split_first_chunk::<8>()
+            .ok_or_else(|| malformed("entry goes past the end of the list"))?;
+        let (digest, rest) = rest
+            .split_first_chunk::<32>()
+            .ok_or_else(|| malformed("entry goes past the end of the list"))?;
+        let (name_len, rest) = rest
+            .split_first_chunk::<4>()
+            .ok_or_else(|| malformed("entry goes past the end of the list"))?;
+        let name_len = u32::from_le_bytes(*name_len) as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(malformed("name is too long"));
+        }
+        if name_len > rest.len() {
+            return Err(malformed("entry goes past the end of the list"));
+        }
+        let (name, rest) = rest.split_at(name_len);
+        files.push((u64::from_le_bytes(*file_len), *digest, name));
+        list = rest;
+    }
+    Ok(files)
+}
+
+// Set the extended attributes of the file that are in the `allowed` namespaces.
+fn apply_xattrs(path: &Path, xattrs: &[(Vec<u8>, Vec<u8>)], allowed: &[XattrNamespace]) {
+    for (name, value) in xattrs {
+        if !xattr::namespace(name).is_some_and(|ns| allowed.contains(&ns)) {
+            continue;
+        }
+        if let Err(e) = xattr::set(path, name, value) {
+            out!(
+                "cannot set the extended attribute {:?} of {:?}: {}",
+                names::display(name),
+                path,
+                e
+            );
+        }
+    }
+}
+
+// Restore when the file was created and its attributes, as far as the platform allows.
+fn apply_attributes(path: &Path, file: &ListedFile) {
+    if let Some(created) = file.created {
+        if let Err(e) = attributes::set_created(path, created) {
+            out!("cannot set when {:?} was created: {}", path, e);
+        }
+    }
+    if let Some(attributes) = file.attributes {
+        if let Err(e) = attributes::set(path, attributes) {
+            out!("cannot set the attributes of {:?}: {}", path, e);
+        }
+    }
+}
+
+// Remove the first `prefix_len` bytes from the name of every file or, when stripping the prefix,
+// the part before the name of the argument it was sent under, if the sender said where it is.
+fn strip_names(files: &mut [ListedFile], prefix_len: usize, prefix: PathPrefix) -> Result<()> {
+    for file in files.iter_mut() {
+        // the sender knows better what each of its arguments was
+        let prefix_len = match (prefix, file.root) {
+            (PathPrefix::Strip, Some(root)) => root,
+            _ => prefix_len,
+        };
+        if prefix_len > file.name.len() {
+            return Err(Error::ProtocolViolation(format!(
+                "bad common prefix for {:?}",
+                names::display(&file.name)
+            )));
+        }
+        file.name.drain(..prefix_len);
+    }
+    Ok(())
+}
+
+// Remove the drive and the first `count` directories from the name of every file, as asked,
+// rejecting those that would have nothing left.
+fn strip_components(files: &mut [ListedFile], drive: bool, count: usize) {
+    if !drive && count == 0 {
+        return;
+    }
+    for file in files.iter_mut() {
+        match names::strip_components(&file.name, drive, count) {
+            Some(rest) => {
+                let start = file.name.len() - rest.len();
+                file.name.drain(..start);
+            }
+            None => file.rejected = Some("nothing is left of its name once stripped".into()),
+        }
+    }
+}
+
+// Rename the files that a rule in `map` applies to, rejecting those that would have nothing
+// left of their name.
+fn map_names(files: &mut [ListedFile], map: &[PathMap]) {
+    for file in files.iter_mut().filter(|file| file.rejected.is_none()) {
+        match names::map(&file.name, map) {
+            Some(name)
+                if names::relative_path(&name).is_some_and(|path| path.as_os_str().is_empty()) =>
+            {
+                file.rejected = Some("nothing is left of its name once mapped".into())
+            }
+            Some(name) => file.name = name,
+            None => {}
+        }
+    }
+}
+
+// What the receiver keeps track of from one transfer to the next.
+struct ReceiverState {
+    // shared with the uploads from browsers, which go on at the same time
+    usage: Arc<Mutex<Usage>>,
+    checksums: Option<Checksums>,
+}
+
+// How many bytes have been received from every sender so far.
+#[derive(Default)]
+struct Usage {
+    per_peer: HashMap<IpAddr, u64>,
+    total: u64,
+}
+
+// The part of the usage a single transfer can take up, before going over the quotas.
+struct Quota {
+    usage: Arc<Mutex<Usage>>,
+    peer: IpAddr,
+    // listed as wanted, but not received yet
+    pending: u64,
+    per_peer: Option<u64>,
+    total: Option<u64>,
+}
+
+impl Quota {
+    fn new(usage: Arc<Mutex<Usage>>, peer: IpAddr, options: &ReceiveOptions) -> Self {
+        Quota {
+            usage,
+            peer,
+            pending: 0,
+            per_peer: options.quota_per_peer,
+            total: options.quota,
+        }
+    }
+
+    // Count the wanted files towards the quotas, failing if they would go over them.
+    fn reserve(&mut self, files: &[ListedFile]) -> Result<()> {
+        let len = files
+            .iter()
+            .filter(|file| file.wanted)
+            .map(|file| file.len as u64)
+            .fold(0, u64::saturating_add);
+        self.reserve_len(len)
+    }
+
+    // Count `len` more bytes towards the quotas than those still pending.
+    fn reserve_len(&mut self, len: u64) -> Result<()> {
+        let len = self.pending.saturating_add(len);
+        let usage = self.usage.lock().unwrap();
+        let peer_used = usage.per_peer.get(&self.peer).copied().unwrap_or(0);
+        let limits = [
+            (self.per_peer, peer_used, format!("for {}", self.peer)),
+            (self.total, usage.total, "for all senders".to_string()),
+        ];
+        for (limit, used, whose) in limits {
+            match limit {
+                Some(limit) if used.saturating_add(len) > limit => {
+                    return Err(Error::Other(format!(
+                        "refusing the transfer, it would go over the quota of {} bytes {} \
+                         ({} received before, {} more listed)",
+                        limit, whose, used, len
+                    )));
+                }
+                _ => {}
+            }
+        }
+        self.pending = len;
+        Ok(())
+    }
+
+    // How many more bytes than those pending can be received before going over the quotas,
+    // if there are any.
+    fn headroom(&self) -> Option<u64> {
+        let usage = self.usage.lock().unwrap();
+        let peer_used = usage.per_peer.get(&self.peer).copied().unwrap_or(0);
+        [(self.per_peer, peer_used), (self.total, usage.total)]
+            .iter()
+            .filter_map(|&(limit, used)| {
+                Some(limit?.saturating_sub(used.saturating_add(self.pending)))
+            })
+            .min()
+    }
+
+    // Count a file that was received in full as used.
+    fn charge(&mut self, len: u64) {
+        self.pending = self.pending.saturating_sub(len);
+        self.charge_unlisted(len);
+    }
+
+    // Count `len` bytes that were never listed as used, such as those of extracted members.
+    fn charge_unlisted(&mut self, len: u64) {
+        let mut usage = self.usage.lock().unwrap();
+        let used = usage.per_peer.entry(self.peer).or_default();
+        *used = used.saturating_add(len);
+        usage.total = usage.total.saturating_add(len);
+    }
+}
+
+// The names received so far, normalized and folded to the same case as asked, to find those
+// that would be the same file once stored.
+struct FoldedNames {
+    policy: CaseCollisions,
+    duplicates: Duplicates,
+    normalize: Option<Normalization>,
+    // the index of the file stored under each folded name
+    seen: HashMap<String, usize>,
+    // the index of the file stored under each name, exactly as it's stored
+    names: HashMap<Vec<u8>, usize>,
+}
+
+impl FoldedNames {
+    fn new(
+        policy: CaseCollisions,
+        duplicates: Duplicates,
+        normalize: Option<Normalization>,
+    ) -> Self {
+        FoldedNames {
+            policy,
+            duplicates,
+            normalize,
+            seen: HashMap::new(),
+            names: HashMap::new(),
+        }
+    }
+
+    fn fold(&self, name: &[u8]) -> String {
+        match self.policy {
+            CaseCollisions::Ignore => names::display(name).into_owned(),
+            _ => names::display(name).to_lowercase(),
+        }
+    }
+
+    // Check the name of the file at `index`, normalizing it, rejecting it if it's the same as
+    // one before as the policy for duplicates says, and renaming it if it collides in case
+    // unless that policy is to abort. Names that end up the same after being normalized are
+    // renamed even if case is ignored. Checking the same file again, as when a batch is listed
+    // again, leaves it as it was.
+    fn check(&mut self, index: usize, file: &mut ListedFile) -> Result<()> {
+        if let Some(form) = self.normalize {
+            if let Cow::Owned(name) = names::normalize(&file.name, form) {
+                file.name = name;
+            }
+        }
+        // a directory listed twice is only made once
+        if file.directory {
+            return self.check_case(index, file);
+        }
+        match self.names.get(&file.name) {
+            Some(&other) if other != index => match self.duplicates {
+                Duplicates::Error => {
+                    return Err(Error::Other(format!(
+                        "file {} named {:?} has the same name as file {}, and would be stored \
+                         over it",
+                        index,
+                        names::display(&file.name),
+                        other
+                    )));
+                }
+                Duplicates::KeepFirst => {
+                    file.rejected = Some(format!("file {} has the same name", other));
+                    return Ok(());
+                }
+                Duplicates::KeepLast => {
+                    out!(
+                        "storing file {} named {:?} over file {}, which has the same name",
+                        index,
+                        names::display(&file.name),
+                        other
+                    );
+                    self.seen.insert(self.fold(&file.name), index);
+                    self.names.insert(file.name.clone(), index);
+                    return Ok(());
+                }
+            },
+            _ => {}
+        }
+        self.check_case(index, file)?;
+        self.names.insert(file.name.clone(), index);
+        Ok(())
+    }
+
+    // Check the name of the file at `index` against those that differ from it only in case,
+    // or in normalization, renaming it if it collides unless the policy is to abort.
+    fn check_case(&mut self, index: usize, file: &mut ListedFile) -> Result<()> {
+        if self.normalize.is_none() && self.policy == CaseCollisions::Ignore {
+            return Ok(());
+        }
+        let folded = self.fold(&file.name);
+        let other = match self.seen.get(&folded) {
+            Some(&other) if other != index => other,
+            _ => {
+                self.seen.insert(folded, index);
+                return Ok(());
+            }
+        };
+        let how = match self.normalize {
+            Some(_) => "in case or unicode normalization",
+            None => "in case",
+        };
+        if self.policy == CaseCollisions::Abort {
+            return Err(Error::Other(format!(
+                "file {} named {:?} differs only {} from file {}, and they would be the same \
+                 file once stored",
+                index,
+                names::display(&file.name),
+                how,
+                other
+            )));
+        }
+
+        for n in 2.. {
+            let name = names::numbered(&file.name, n);
+            let folded = self.fold(&name);
+            if self.seen.get(&folded).is_none_or(|&other| other == index) {
+                out!(
+                    "storing {:?} as {:?}, since another file differs from it only {}",
+                    names::display(&file.name),
+                    names::display(&name),
+                    how
+                );
+                self.seen.insert(folded, index);
+                file.name = name;
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+// === Async API
+
+/// Like [`send`], but on the tokio runtime it's awaited in, and completes once the transfer
+/// is done.
+pub async fn send_async(addr: SocketAddr, files: Vec<PathBuf>, options: SendOptions) -> Result<()> {
+    send_paths(addr, files, &options).await
+}
+
+/// Like [`verify`], but on the tokio runtime it's awaited in, and completes once the
+/// verification is done.
+pub async fn verify_async(
+    addr: SocketAddr,
+    files: Vec<PathBuf>,
+    options: VerifyOptions,
+) -> Result<()> {
+    verify_paths(addr, files, &options).await
+}
+
+/// Like [`recv`], but on the tokio runtime it's awaited in, and completes once the transfer
+/// is done.
+pub async fn recv_async(options: ReceiveOptions) -> Result<()> {
+    receive_all(options).await
+}
+
+/// Like [`serve`], but on the tokio runtime it's awaited in, and completes once every
+/// download is done.
+pub async fn serve_async(files: Vec<PathBuf>, options: ServeOptions) -> Result<()> {
+    serve_paths(files, &options).await
+}
+
+/// Like [`discover_server`], but on the tokio runtime it's awaited in.
+pub async fn discover_server_async() -> Result<SocketAddr> {
+    let mut buf = [0; 20];
+    let socket = tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SIGNALING_PORT))
+        .await
+        .map_err(Error::Discovery)?;
+    let (_, from) = socket.recv_from(&mut buf).await.map_err(Error::Discovery)?;
+    debug!("received the announcement {:02x?} from {}", buf, from);
+    deserialize_socket_addr(buf)
+        .map_err(|e| Error::Discovery(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))
+}
+
+/// Like [`discover_server_within`], but on the tokio runtime it's awaited in.
+pub async fn discover_server_within_async(window: Duration) -> Result<SocketAddr> {
+    let deadline = Instant::now() + window;
+    let mut delay = MIN_RETRY_DELAY;
+    let mut buf = [0; 20];
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let received =
+            match tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SIGNALING_PORT)).await {
+                Ok(socket) => tokio::time::timeout(left, socket.recv_from(&mut buf))
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                Err(e) => Err(e),
+            };
+        match received {
+            Ok((_, from)) => {
+                debug!("received the announcement {:02x?} from {}", buf, from);
+                break;
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
+                return Err(Error::Discovery(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("no receiver announced itself within {}s", window.as_secs()),
+                )));
+            }
+            Err(e) if left.is_zero() => return Err(Error::Discovery(e)),
+            Err(e) => {
+                let wait = delay.min(left);
+                out!(
+                    "cannot listen for receivers ({}), retrying in {:.1}s...",
+                    e,
+                    wait.as_secs_f64()
+                );
+                tokio::time::sleep(wait).await;
+                delay = (delay * 2).min(MAX_RETRY_DELAY);
+            }
+        }
+    }
+    deserialize_socket_addr(buf)
+        .map_err(|e| Error::Discovery(io::Error::new(io::ErrorKind::InvalidData, e.to_string())))
+}
+
+// === Automatic discovery
+
+// The alternative would be to use multicast, but broadcasting should work just fine in LAN.
+// (Attempting to broadcast outside the subnet is very likely to just get the packet dropped.)
+fn make_broadcast_addr(addr: SocketAddr, subnet_mask: IpAddr) -> SocketAddr {
+    match (addr, subnet_mask) {
+        (SocketAddr::V4(addr), IpAddr::V4(mask)) => {
+            let mut octets = addr.ip().octets();
+            for (o, m) in octets.iter_mut().zip(mask.octets().iter()) {
+                *o |= !m;
+            }
+            SocketAddr::new(Ipv4Addr::from(octets).into(), addr.port())
+        }
+        (SocketAddr::V6(addr), IpAddr::V6(mask)) => {
+            let mut octets = addr.ip().octets();
+            for (o, m) in octets.iter_mut().zip(mask.octets().iter()) {
+                *o |= !m;
+            }
+            SocketAddr::new(Ipv6Addr::from(octets).into(), addr.port())
+        }
+        _ => panic!("subnet mask version differs from socket address ip version"),
+    }
+}
+
+fn serialize_socket_addr(addr: SocketAddr) -> [u8; 20] {
+    let mut buffer = [0; 20];
+    match addr {
+        SocketAddr::V4(addr) => {
+            buffer[0] = 4;
+            buffer[1..5].copy_from_slice(&addr.ip().octets());
+            buffer[5..7].copy_from_slice(&addr.port().to_be_bytes());
+        }
+        SocketAddr::V6(addr) => {
+            buffer[0] = 6;
+            buffer[1..17].copy_from_slice(&addr.ip().octets());
+            buffer[17..19].copy_from_slice(&addr.port().to_be_bytes());
+        }
+    }
+    buffer
+}
+
+fn deserialize_socket_addr(buffer: [u8; 20]) -> Result<SocketAddr> {
+    match buffer[0] {
+        4 => {
+            let ip: [u8; 4] = buffer[1..5].try_into().unwrap();
+            let port = buffer[5..7].try_into().unwrap();
+            Ok(SocketAddr::new(
+                Ipv4Addr::from(ip).into(),
+                u16::from_be_bytes(port),
+            ))
+        }
+        6 => {
+            let ip: [u8; 16] = buffer[1..17].try_into().unwrap();
+            let port = buffer[17..19].try_into().unwrap();
+            Ok(SocketAddr::new(
+                Ipv6Addr::from(ip).into(),
+                u16::from_be_bytes(port),
+            ))
+        }
+        _ => Err("invalid socket addr version".into()),
+    }
+}
+
+// Broadcast a signal to survey for potential clients for them to connect via automatic mode.
+// If any of the steps fail, bail, in order to fallback to direct a connection.
+async fn survey_potential_clients(
+    listener: &tokio::net::TcpListener,
+    interface: &NetInterface,
+    cancel: &Option<CancelToken>,
+) -> Result<TcpStream> {
+    let mut listener_addr = listener.local_addr()?;
+    // a listener may have been bound to every interface, so the address is the one announced
+    if listener_addr.ip().is_unspecified() {
+        listener_addr.set_ip(interface.ip);
+    }
+    let serliazed_addr = serialize_socket_addr(listener_addr);
+    let listener_net_broadcast_ip = interface
+        .broadcast
+        .unwrap_or_else(|| make_broadcast_addr(listener_addr, interface.netmask).ip());
+
+    let socket =
+        tokio::net::UdpSocket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_BROADCAST_PORT)).await?;
+    debug!(
+        "announcing {} to {}:{} as {:02x?}",
+        listener_addr, listener_net_broadcast_ip, SIGNALING_PORT, serliazed_addr
+    );
+    loop {
+        check(cancel)?;
+        // the dots are only meant for someone watching, and would fill logs otherwise
+        if !QUIET.load(Ordering::Relaxed) && io::stdout().is_terminal() {
+            print!(".");
+            io::stdout().flush().unwrap();
+        }
+        socket
+            .send_to(&serliazed_addr, (listener_net_broadcast_ip, SIGNALING_PORT))
+            .await?;
+        trace!("announced {} again", listener_addr);
+        tokio::select! {
+            accepted = listener.accept() => break Ok(accepted?.0),
+            () = cancel::cancelled(cancel) => break Err(Error::Cancelled),
+            () = tokio::time::sleep(SIGNAL_DELAY) => {}
+        }
+    }
+}
+
+/// Waits for a receiver to announce its address on the local network.
+pub fn discover_server() -> Result<SocketAddr> {
+    task::block_on(discover_server_async())
+}
+
+/// Like [`discover_server`], but gives up once the `window` is over, and keeps trying with a
+/// growing delay if listening for the announcements fails meanwhile.
+pub fn discover_server_within(window: Duration) -> Result<SocketAddr> {
+    task::block_on(discover_server_within_async(window))
+}
+
+/// Describes what this build supports as a JSON object, so that scripts wrapping it (and
+/// whoever reads an error about a peer) can tell which side lacks what.
+pub fn capabilities() -> String {
+    let list = |items: &[&str]| {
+        items
+            .iter()
+            .map(|item| json::string(item))
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+    let mut xattrs = Vec::new();
+    if cfg!(any(target_os = "linux", target_os = "android")) {
+        xattrs.extend(["user", "security", "trusted", "acl"]);
+    }
+    let mut transports = vec!["tcp", "http-upload", "http-download"];
+    if cfg!(feature = "s3") {
+        transports.push("s3");
+    }
+    format!(
+        concat!(
+            "{{\"sf\": {}, \"protocol\": {{\"min\": {}, \"max\": {}, \"legacy\": {}}}, ",
+            "\"transports\": [{}], \"compression\": [{}], \"hashes\": [{}], ",
+            "\"encryption\": [{}], \"authentication\": [{}], \"xattrs\": [{}]}}"
+        ),
+        json::string(env!("CARGO_PKG_VERSION")),
+        MIN_VERSION,
+        VERSION,
+        LEGACY_VERSION,
+        list(&transports),
+        list(&["deflate"]),
+        list(&["sha256"]),
+        list(&["age-x25519"]),
+        list(&["ed25519"]),
+        list(&xattrs),
+    )
+}
+
+/// Describes where a receiver is listening, and the session key it gave out if any, compactly
+/// enough to be shown as a QR code, so that a sender can connect to it without typing either.
+pub fn connection_descriptor(addr: SocketAddr, key: Option<&SessionKey>) -> String {
+    match key {
+        Some(key) => format!("{}{}/{}", DESCRIPTOR_SCHEME, addr, key),
+        None => format!("{}{}", DESCRIPTOR_SCHEME, addr),
+    }
+}
+
+/// Parses a descriptor made by [`connection_descriptor`].
+pub fn parse_connection_descriptor(descriptor: &str) -> Option<(SocketAddr, Option<SessionKey>)> {
+    let rest = descriptor.trim().strip_prefix(DESCRIPTOR_SCHEME)?;
+    let (addr, key) = rest.split_once('/').unwrap_or((rest, ""));
+    let key = match key.trim_end_matches('/') {
+        "" => None,
+        key => Some(SessionKey::parse(key)?),
+    };
+    Some((addr.parse().ok()?, key))
+}
+
+fn collect_files(files: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+    let tree = collect_tree(files, Walk::default())?;
+    match tree.unreadable.into_iter().next() {
+        Some((path, e)) => Err(Error::from(e).at(&path)),
+        None => Ok(tree.files),
+    }
+}
+
+// What was found under the paths given.
+#[derive(Default)]
+struct Tree {
+    files: Vec<PathBuf>,
+    // the directories that have none of the files in them, at any depth
+    dirs: Vec<PathBuf>,
+    // what's neither a file nor a directory, such as FIFOs, which has no data to read
+    specials: Vec<(PathBuf, fs::FileType)>,
+    unreadable: Unreadable,
+}
+
+// What can't be read inside the paths given, and why.
+type Unreadable = Vec<(PathBuf, io::Error)>;
+
+// How far to look inside the paths given.
+#[derive(Clone, Copy, Default)]
+struct Walk {
+    max_depth: Option<usize>,
+    one_file_system: bool,
+}
+
+// Find the files under the paths given, and everything else found along the way. What can't
+// be read inside the paths is left out, but paths that can't be read are an error.
+fn collect_tree(files: Vec<PathBuf>, walk: Walk) -> Result<Tree> {
+    let mut tree = Tree::default();
+    for arg in files {
+        // sorted so that the list, and the manifests and resumes made from it, don't depend
+        // on the order the file system happens to have
+        let mut walk_dir = WalkDir::new(arg)
+            .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+            .same_file_system(walk.one_file_system);
+        if let Some(depth) = walk.max_depth {
+            walk_dir = walk_dir.max_depth(depth);
+        }
+        for entry in walk_dir {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.depth() > 0 && e.io_error().is_some() => {
+                    let path = e.path().map(Path::to_path_buf).unwrap_or_default();
+                    tree.unreadable.push((path, e.into_io_error().unwrap()));
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            if entry.path().is_file() {
+                // opened only to find out if it can be, as it's read much later
+                match File::open(entry.path()) {
+                    Ok(_) => tree.files.push(entry.into_path()),
+                    Err(e) if entry.depth() > 0 => tree.unreadable.push((entry.into_path(), e)),
+                    Err(e) => return Err(Error::from(e).at(entry.path())),
+                }
+            } else if entry.file_type().is_dir() {
+                // a directory too deep to be looked inside is not empty, only unknown, but
+                // one given is still sent, as if it was a file
+                if entry.depth() == 0 || walk.max_depth != Some(entry.depth()) {
+                    tree.dirs.push(entry.into_path());
+                }
+            } else if let Ok(meta) = fs::metadata(entry.path()) {
+                // links to directories are not followed, and are left out along with these
+                if special::describe(meta.file_type()).is_some() {
+                    tree.specials.push((entry.into_path(), meta.file_type()));
+                }
+            }
+        }
+    }
+    // a directory that can't be listed is not empty, only unknown
+    let unreadable = &tree.unreadable;
+    tree.dirs
+        .retain(|dir| !unreadable.iter().any(|(path, _)| path == dir));
+    // only the deepest are needed, as the others are made for them
+    let parents = tree
+        .files
+        .iter()
+        .chain(&tree.dirs)
+        .chain(tree.specials.iter().map(|(path, _)| path))
+        .flat_map(|path| path.ancestors().skip(1))
+        .map(Path::to_path_buf)
+        .collect::<HashSet<_>>();
+    tree.dirs.retain(|dir| !parents.contains(dir));
+    Ok(tree)
+}
+
+// Leave out the paths given more than once, even if spelled differently, and those inside
+// another path given, no deeper than `max_depth`, as their files are found under it already
+// and would be sent twice.
+fn distinct_paths(files: Vec<PathBuf>, max_depth: Option<usize>) -> Vec<PathBuf> {
+    let canonical = files
+        .iter()
+        .map(|path| fs::canonicalize(path).unwrap_or_else(|_| path.clone()))
+        .collect::<Vec<_>>();
+    // where each path is first given, to look them up without comparing every pair
+    let mut first = HashMap::new();
+    for (i, path) in canonical.iter().enumerate().rev() {
+        first.insert(path.as_path(), i);
+    }
+    let mut distinct = Vec::new();
+    for (i, path) in files.iter().enumerate() {
+        let same = Some(first[canonical[i].as_path()]).filter(|j| *j < i);
+        // directories as deep as the limit are found, but what's inside them isn't
+        let reach = match max_depth {
+            Some(depth) if canonical[i].is_dir() => depth.saturating_sub(1),
+            Some(depth) => depth,
+            None => usize::MAX,
+        };
+        let outer = canonical[i]
+            .ancestors()
+            .skip(1)
+            .take(reach)
+            .find_map(|ancestor| first.get(ancestor).copied());
+        match (same, outer) {
+            (_, Some(j)) => out!(
+                "not sending {:?} on its own, as it's inside {:?}, which is sent",
+                path,
+                files[j]
+            ),
+            (Some(j), None) => out!(
+                "not sending {:?} again, as it's the same as {:?}",
+                path,
+                files[j]
+            ),
+            (None, None) => distinct.push(path.clone()),
+        }
+    }
+    distinct
+}
+
+// Find the entries to send under the paths given, with directories sent as a single archive
+// if the `scan` says so, along with what's left out for not being readable and why.
+fn collect_entries(files: Vec<PathBuf>, scan: &Scan) -> Result<(Vec<Entry>, Unreadable)> {
+    let mut entries = Vec::new();
+    let mut unreadable = Vec::new();
+    for arg in files {
+        let archived = scan.archive.filter(|_| arg.is_dir());
+        let tree = collect_tree(vec![arg.clone()], scan.walk)?;
+        unreadable.extend(tree.unreadable);
+        for (path, file_type) in tree.specials {
+            match special::kind(file_type).filter(|_| scan.specials && archived.is_none()) {
+                Some(kind) => entries.push(Entry::Special(path, kind)),
+                None => out!(
+                    "skipping {:?}, as it's a {}",
+                    path,
+                    special::describe(file_type).unwrap_or("special file")
+                ),
+            }
+        }
+        let Some(format) = archived else {
+            entries.extend(tree.files.into_iter().map(Entry::File));
+            entries.extend(tree.dirs.into_iter().map(Entry::Dir));
+            continue;
+        };
+
+        // members keep the name of the directory, as if the archive was extracted in place
+        let root = renamed(&arg, &scan.rename).into_owned();
+        let parent = root.parent().unwrap_or_else(|| Path::new(""));
+        let mut members = Vec::new();
+        for path in tree.files {
+            let meta = fs::metadata(&path).map_err(|e| Error::from(e).at(&path))?;
+            members.push(tar::Member {
+                name: names::wire_name(
+                    renamed(&path, &scan.rename)
+                        .strip_prefix(parent)
+                        .expect("walked path outside of its root"),
+                ),
+                len: meta.len(),
+                mtime: modified_secs(&meta),
+                path,
+            });
+        }
+        let archive = archive::Archive::new(format, members, &scan.cancel)?;
+        entries.push(Entry::Archive(arg, archive));
+    }
+    // directories given along with what's inside them are made for it anyway
+    let parents = entries
+        .iter()
+        .flat_map(|entry| entry.path().ancestors().skip(1))
+        .map(Path::to_path_buf)
+        .collect::<HashSet<_>>();
+    entries.retain(|entry| !matches!(entry, Entry::Dir(path) if parents.contains(path)));
+    Ok((entries, unreadable))
+}
+
+// How long the part of the name of every entry is before the name of the argument it was found
+// under, such as `/home/` for `/home/dir/file` found under `/home/dir`, so that the receiver
+// can keep the name of each argument. Entries from a source were found under none.
+fn root_lens(
+    entries: &[Entry],
+    args: &[PathBuf],
+    rename: &[(PathBuf, PathBuf)],
+) -> Vec<Option<usize>> {
+    let args = args.iter().map(PathBuf::as_path).collect::<HashSet<_>>();
+    entries
+        .iter()
+        .map(|entry| {
+            // the outermost, as it's the one the entry was found under
+            let arg = entry
+                .path()
+                .ancestors()
+                .filter(|ancestor| args.contains(*ancestor))
+                .last()?;
+            let path = renamed(entry.path(), rename);
+            // a new name is kept whole, even if it has several parts
+            let parent = match renamed(arg, rename) {
+                Cow::Owned(_) => Path::new(""),
+                Cow::Borrowed(arg) => arg.parent().unwrap_or_else(|| Path::new("")),
+            };
+            let relative = names::wire_name(path.strip_prefix(parent).ok()?);
+            names::wire_name(&path).len().checked_sub(relative.len())
+        })
+        .collect()
+}
+
+// The path as it's sent, with the first of the paths it's under that's renamed replaced by
+// its new name.
+fn renamed<'a>(path: &'a Path, rename: &[(PathBuf, PathBuf)]) -> Cow<'a, Path> {
+    for (from, to) in rename {
+        match path.strip_prefix(from) {
+            // joining nothing would still add a trailing separator
+            Ok(rest) if rest.as_os_str().is_empty() => return Cow::Owned(to.clone()),
+            Ok(rest) => return Cow::Owned(to.join(rest)),
+            Err(_) => {}
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+// Put the entries in the `order` they're sent in. Those which are the same size, or all of
+// them if they're sent as given, keep their order.
+fn order_entries(
+    entries: Vec<Entry>,
+    order: Order,
+    rename: &[(PathBuf, PathBuf)],
+) -> Result<Vec<Entry>> {
+    let by_len = match order {
+        Order::AsGiven => return Ok(entries),
+        Order::Alpha => {
+            let mut entries = entries;
+            entries.sort_by_cached_key(|entry| entry.name(rename));
+            return Ok(entries);
+        }
+        Order::SmallFirst | Order::LargeFirst => entries,
+    };
+    let mut entries = by_len
+        .into_iter()
+        .map(|entry| match entry.len_and_modified() {
+            Ok((len, _)) => Ok((len, entry)),
+            Err(e) => Err(Error::from(e).at(entry.path())),
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if order == Order::SmallFirst {
+        entries.sort_by_key(|&(len, _)| len);
+    } else {
+        entries.sort_by_key(|&(len, _)| Reverse(len));
+    }
+    Ok(entries.into_iter().map(|(_, entry)| entry).collect())
+}
+
+// Turn the files which are hard links to the same data as an earlier one into links to it.
+fn mark_links(entries: &mut [Entry]) -> Result<()> {
+    let mut originals = HashMap::new();
+    let mut links = 0;
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let path = match entry {
+            Entry::File(path) => path,
+            _ => continue,
+        };
+        if let Some(id) = file_id(path).map_err(|e| Error::from(e).at(path))? {
+            let source = *originals.entry(id).or_insert(i);
+            if source != i {
+                *entry = Entry::Link(std::mem::take(path), source);
+                links += 1;
+            }
+        }
+    }
+    out!("found {} hard links to files sent before them", links);
+    Ok(())
+}
+
+// What tells apart the data a file links to, if it has more than one link to it.
+#[cfg(unix)]
+fn file_id(path: &Path) -> io::Result<Option<(u64, u64)>> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = fs::metadata(path)?;
+    Ok((meta.nlink() > 1).then(|| (meta.dev(), meta.ino())))
+}
+
+// The standard library can't tell yet, so hard links are sent as separate files.
+#[cfg(not(unix))]
+fn file_id(_path: &Path) -> io::Result<Option<(u64, u64)>> {
+    Ok(None)
+}
+
+// Turn the files with the same contents as an earlier one into copies of it. Only files of
+// the same length can be identical, so those are the only ones hashed.
+fn mark_copies(entries: &mut [Entry]) -> Result<()> {
+    out!("looking for duplicate files...");
+    let mut by_len = HashMap::<u64, Vec<usize>>::new();
+    for (i, entry) in entries.iter().enumerate() {
+        if let Entry::File(path) = entry {
+            let len = fs::metadata(path)
+                .map_err(|e| Error::from(e).at(path))?
+                .len();
+            // there's nothing to save by copying empty files
+            if len != 0 {
+                by_len.entry(len).or_default().push(i);
+            }
+        }
+    }
+
+    let (mut copies, mut saved) = (0, 0);
+    for (len, indices) in by_len.into_iter().filter(|(_, indices)| indices.len() > 1) {
+        let mut originals = HashMap::new();
+        for i in indices {
+            let path = entries[i].path();
+            let digest = hash::hash_file(path).map_err(|e| Error::from(e).at(path))?;
+            let source = *originals.entry(digest).or_insert(i);
+            if source != i {
+                if let Entry::File(path) = &mut entries[i] {
+                    entries[i] = Entry::Copy(std::mem::take(path), source);
+                }
+                copies += 1;
+                saved += len;
+            }
+        }
+    }
+    out!(
+        "found {} duplicate files, saving {} from being sent",
+        copies,
+        format_bytes(saved)
+    );
+    Ok(())
+}
+
+// Pack every run of small files in the same directory and under the same root into a single
+// entry, along with the roots of the new entries. The files others are copies of are left
+// alone, since the receiver needs to know where it stored them.
+fn pack_entries(
+    entries: Vec<Entry>,
+    roots: &[Option<usize>],
+    rename: &[(PathBuf, PathBuf)],
+) -> Result<(Vec<Entry>, Vec<Option<usize>>)> {
+    let sources = entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Copy(_, source) | Entry::Link(_, source) => Some(*source),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+
+    let (mut packed, mut packed_roots) = (Vec::new(), Vec::new());
+    // the index of the entry every one of them ended up in
+    let mut moved = Vec::with_capacity(entries.len());
+    for (i, entry) in entries.into_iter().enumerate() {
+        let root = roots.get(i).copied().flatten();
+        let member = match &entry {
+            Entry::File(path) if !sources.contains(&i) => pack_member(path, rename)?,
+            _ => None,
+        };
+        let Some(member) = member else {
+            moved.push(packed.len());
+            packed.push(entry);
+            packed_roots.push(root);
+            continue;
+        };
+        let dir = member.path.parent().unwrap_or_else(|| Path::new(""));
+        // the receiver unpacks them next to the name of the first one
+        let same_dir = |first: &tar::Member| {
+            first.path.parent() == member.path.parent()
+                && renamed(&first.path, rename).parent() == renamed(&member.path, rename).parent()
+        };
+        match packed.last_mut() {
+            Some(Entry::Pack(_, members))
+                if same_dir(&members[0]) && packed_roots.last() == Some(&root) =>
+            {
+                members.push(member)
+            }
+            _ => {
+                packed.push(Entry::Pack(dir.to_path_buf(), vec![member]));
+                packed_roots.push(root);
+            }
+        }
+        moved.push(packed.len() - 1);
+    }
+
+    let mut packs = 0;
+    for entry in packed.iter_mut() {
+        match entry {
+            // a file on its own is better sent as it is
+            Entry::Pack(_, members) if members.len() == 1 => {
+                *entry = Entry::File(members.pop().expect("pack without files").path)
+            }
+            Entry::Pack(..) => packs += 1,
+            Entry::Copy(_, source) | Entry::Link(_, source) => *source = moved[*source],
+            _ => {}
+        }
+    }
+    out!(
+        "packed {} small files into {} archives",
+        moved.len() - packed.len() + packs,
+        packs
+    );
+    Ok((packed, packed_roots))
+}
+
+// The file as a member of a pack, if it's small enough to be worth packing.
+fn pack_member(path: &Path, rename: &[(PathBuf, PathBuf)]) -> Result<Option<tar::Member>> {
+    let meta = fs::metadata(path).map_err(|e| Error::from(e).at(path))?;
+    let name = match renamed(path, rename).file_name() {
+        Some(name) if meta.len() <= PACKED_FILE_LEN => names::wire_name(Path::new(name)),
+        _ => return Ok(None),
+    };
+    Ok(Some(tar::Member {
+        path: path.to_path_buf(),
+        name,
+        len: meta.len(),
+        mtime: modified_secs(&meta),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(unix)]
+    use std::env;
+    #[cfg(unix)]
+    use tokio::io::AsyncWrite;
+
+    fn identity(name: &str) -> Identity {
+        let dir = std::env::temp_dir().join(format!("sf-lib-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        let _ = fs::remove_file(&path);
+        Identity::load_or_create(&path).unwrap()
+    }
+
+    // A directory of its own for every test, emptied before it runs.
+    #[cfg(unix)]
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("sf-lib-test-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Pass everything read on to the other end until there's nothing more, and say what it was.
+    #[cfg(unix)]
+    async fn relay(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin) -> Vec<u8> {
+        let mut seen = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while let Ok(n @ 1..) = from.read(&mut buffer).await {
+            seen.extend(&buffer[..n]);
+            if to.write_all(&buffer[..n]).await.is_err() {
+                break;
+            }
+        }
+        let _ = to.shutdown().await;
+        seen
+    }
+
+    // Send the `files` with the protocol `version` to a receiver in this process, and return
+    // what the sender sent and what the receiver replied.
+    #[cfg(unix)]
+    async fn record_session(
+        files: Vec<PathBuf>,
+        version: u8,
+        send: &SendOptions,
+        recv: &ReceiveOptions,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let (sent, received, sent_bytes, replied_bytes) =
+            run_session(files, version, send, recv).await;
+        received.unwrap();
+        sent.unwrap();
+        (sent_bytes, replied_bytes)
+    }
+
+    // Send the `files` as in `record_session`, and return how both ends fared too.
+    #[cfg(unix)]
+    async fn run_session(
+        files: Vec<PathBuf>,
+        version: u8,
+        send: &SendOptions,
+        recv: &ReceiveOptions,
+    ) -> (Result<()>, Result<()>, Vec<u8>, Vec<u8>) {
+        let outgoing = prepare_send_as(files, version, send).await.unwrap();
+        let (sender, from_sender) = TimedStream::pair(&send.socket);
+        let (to_receiver, receiver) = TimedStream::pair(&send.socket);
+        let peer = receiver.peer_addr().unwrap();
+        let mut state = ReceiverState {
+            usage: Arc::default(),
+            checksums: None,
+        };
+        let mut sink = None;
+        let (sent_read, sent_write) = tokio::io::split(from_sender);
+        let (replied_read, replied_write) = tokio::io::split(to_receiver);
+        // the session id is random otherwise, and is in the header since version 4
+        tokio::join!(
+            send_session(peer, Some(sender), outgoing, 0x5f5f_5f5f, send),
+            receive_from(None, receiver, recv, &mut sink, &mut state),
+            relay(sent_read, replied_write),
+            relay(replied_read, sent_write),
+        )
+    }
+
+    // Every version is checked in as a whole session, from the header to the last reply, so
+    // that a change to what any of them sends or expects is noticed, rather than only whether
+    // both sides still agree. Set `SF_BLESS_FIXTURES` to write them again after changing them
+    // on purpose.
+    #[cfg(unix)]
+    #[test]
+    fn golden_sessions_match() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let bless = env::var_os("SF_BLESS_FIXTURES").is_some();
+        let dir = scratch("sessions");
+        let tree = dir.join("tree");
+        let files = [
+            ("empty", Vec::new()),
+            ("hello.txt", b"hello, world\n".repeat(3)),
+            (
+                "nested/data.bin",
+                (0..2000u32).map(|i| (i * i) as u8).collect(),
+            ),
+        ];
+        for (name, data) in &files {
+            let path = tree.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, data).unwrap();
+            let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+            File::options()
+                .write(true)
+                .open(&path)
+                .and_then(|f| f.set_modified(modified))
+                .unwrap();
+            // the mode is sent too, so it can't depend on the umask
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+        }
+
+        for version in MIN_VERSION..=VERSION {
+            // what each version added to the list and the data is part of the session too
+            let send = SendOptions {
+                rename: vec![(tree.clone(), PathBuf::from("tree"))],
+                hashes: version >= 10,
+                compress: if version >= 17 {
+                    Compression::Always
+                } else {
+                    Compression::Never
+                },
+                ..SendOptions::default()
+            };
+            let recv = ReceiveOptions {
+                prefix: PathPrefix::Strip,
+                output: dir.join(format!("out-v{}", version)),
+                ..ReceiveOptions::default()
+            };
+            let recording = record_session(vec![tree.clone()], version, &send, &recv);
+            let (sent, replied) = task::block_on(recording);
+            // renamed roots are sent as such since version 10, and stripping keeps those
+            let received_tree = if version >= 10 {
+                recv.output.join("tree")
+            } else {
+                recv.output.clone()
+            };
+            for (name, data) in &files {
+                let received = fs::read(received_tree.join(name)).unwrap();
+                assert!(
+                    received == *data,
+                    "{} was received with version {}",
+                    name,
+                    version
+                );
+            }
+
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests")
+                .join("fixtures")
+                .join(format!("session-v{}.bin", version));
+            let mut session = (sent.len() as u32).to_le_bytes().to_vec();
+            session.extend(&sent);
+            session.extend(&replied);
+            if bless {
+                fs::write(&path, &session).unwrap();
+                continue;
+            }
+            let fixture = fs::read(&path)
+                .unwrap_or_else(|e| panic!("cannot read the fixture {:?}: {}", path, e));
+            let (len, rest) = fixture.split_at(4);
+            let (fixture_sent, fixture_replied) =
+                rest.split_at(u32::from_le_bytes(len.try_into().unwrap()) as usize);
+            assert!(
+                fixture_sent == sent,
+                "the sender of version {} no longer sends what's in {:?}",
+                version,
+                path
+            );
+            assert!(
+                fixture_replied == replied,
+                "the receiver of version {} no longer replies what's in {:?}",
+                version,
+                path
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A link in the output leading somewhere else is never written through, and nothing is
+    // moved if anything can't be.
+    #[cfg(unix)]
+    #[test]
+    fn staged_files_stay_in_the_output() {
+        use std::os::unix::fs::symlink;
+
+        let dir = scratch("commit-links");
+        let (staged, output, outside) = (dir.join("staged"), dir.join("out"), dir.join("outside"));
+        fs::create_dir_all(staged.join("linked")).unwrap();
+        fs::create_dir_all(&output).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        fs::write(staged.join("first"), b"new").unwrap();
+        fs::write(staged.join("linked").join("file"), b"new").unwrap();
+        symlink(&outside, output.join("linked")).unwrap();
+
+        assert!(commit_staged(&staged, &output).is_err());
+        assert!(!output.join("first").exists());
+        assert!(!outside.join("file").exists());
+        assert_eq!(fs::read(staged.join("first")).unwrap(), b"new");
+
+        // a file replaces the link itself, rather than what it points to
+        fs::remove_dir_all(staged.join("linked")).unwrap();
+        fs::write(staged.join("linked"), b"new").unwrap();
+        commit_staged(&staged, &output).unwrap();
+        assert!(!fs::symlink_metadata(output.join("linked"))
+            .unwrap()
+            .is_symlink());
+        assert_eq!(fs::read(output.join("linked")).unwrap(), b"new");
+        assert_eq!(fs::read(output.join("first")).unwrap(), b"new");
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        assert!(!staged.exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // A receiver that gave out a key only takes files from the senders that prove they know
+    // it, while one that didn't lets those that do send anyway.
+    #[cfg(unix)]
+    #[test]
+    fn only_senders_with_the_key_are_accepted() {
+        let dir = scratch("session-key");
+        let file = dir.join("hello.txt");
+        fs::write(&file, b"hello, world\n").unwrap();
+        let (key, other) = (
+            SessionKey::generate().unwrap(),
+            SessionKey::generate().unwrap(),
+        );
+        let cases = [
+            (Some(key), Some(key), true),
+            (None, Some(key), true),
+            (Some(other), Some(key), false),
+            (Some(key), None, false),
+        ];
+        for (i, &(given_out, known, accepted)) in cases.iter().enumerate() {
+            let send = SendOptions {
+                key: known,
+                ..SendOptions::default()
+            };
+            let recv = ReceiveOptions {
+                prefix: PathPrefix::Strip,
+                output: dir.join(format!("out-{}", i)),
+                key: given_out,
+                ..ReceiveOptions::default()
+            };
+            let session = run_session(vec![file.clone()], VERSION, &send, &recv);
+            let (sent, received, _, _) = task::block_on(session);
+            assert_eq!(sent.is_ok(), accepted, "case {} was sent: {:?}", i, sent);
+            assert_eq!(received.is_ok(), accepted, "case {} was received", i);
+            assert_eq!(recv.output.join("hello.txt").exists(), accepted);
+            // both ends say the sender was turned away, rather than that something broke
+            for result in [sent, received] {
+                let code = result.err().map(|e| e.exit_code());
+                assert_eq!(code, (!accepted).then_some(5), "case {}", i);
+            }
+        }
+
+        // and only since the version it was added in can the sender prove it
+        let send = SendOptions {
+            key: Some(key),
+            ..SendOptions::default()
+        };
+        assert!(task::block_on(prepare_send_as(vec![file], 23, &send)).is_err());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Receive the `files` into a mirrored output, answering the question about what to delete
+    // with `answer` once it's asked, and return whether it was. The question is answered from
+    // the same thread as the session runs on, so it can only be if asking doesn't block it.
+    #[cfg(unix)]
+    async fn mirror_session(files: Vec<PathBuf>, recv: &ReceiveOptions, answer: &str) -> bool {
+        let answered = std::cell::Cell::new(false);
+        let answering = async {
+            while !input::answer(answer) {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+            answered.set(true);
+            std::future::pending::<()>().await
+        };
+        let send = SendOptions::default();
+        let session = run_session(files, VERSION, &send, recv);
+        let (sent, received, _, _) = tokio::select! {
+            ended = session => ended,
+            _ = answering => unreachable!(),
+        };
+        sent.unwrap();
+        received.unwrap();
+        answered.get()
+    }
+
+    // Only the files that were not sent are deleted, and only once that's confirmed, while
+    // those the filters left out stay as they were.
+    #[cfg(unix)]
+    #[test]
+    fn mirroring_deletes_what_was_not_sent() {
+        let dir = scratch("mirror");
+        let (tree, output) = (dir.join("tree"), dir.join("out"));
+        fs::create_dir_all(&tree).unwrap();
+        fs::write(tree.join("kept.txt"), b"new").unwrap();
+        fs::write(tree.join("filtered.log"), b"new").unwrap();
+        fs::create_dir_all(output.join("old").join("deep")).unwrap();
+        fs::write(output.join("stale.txt"), b"old").unwrap();
+        fs::write(output.join("old").join("deep").join("gone.txt"), b"old").unwrap();
+        fs::write(output.join("filtered.log"), b"old").unwrap();
+        let files = vec![tree.join("kept.txt"), tree.join("filtered.log")];
+        let recv = ReceiveOptions {
+            prefix: PathPrefix::Strip,
+            output: output.clone(),
+            mirror: true,
+            filter: FileFilter {
+                reject_ext: vec!["log".into()],
+                ..FileFilter::default()
+            },
+            ..ReceiveOptions::default()
+        };
+        let stale = [output.join("stale.txt"), output.join("old/deep/gone.txt")];
+
+        // a dry run only lists them, without asking
+        let dry_run = ReceiveOptions {
+            dry_run: true,
+            prefix: PathPrefix::Strip,
+            output: output.clone(),
+            mirror: true,
+            filter: recv.filter.clone(),
+            ..ReceiveOptions::default()
+        };
+        let send = SendOptions::default();
+        let session = run_session(files.clone(), VERSION, &send, &dry_run);
+        let (sent, received, _, _) = task::block_on(session);
+        sent.unwrap();
+        received.unwrap();
+        assert!(stale.iter().all(|path| path.exists()));
+
+        for declined in ["", "n", "no thanks"] {
+            let session = mirror_session(files.clone(), &recv, declined);
+            assert!(task::block_on(session));
+            assert!(stale.iter().all(|path| path.exists()));
+        }
+
+        assert!(task::block_on(mirror_session(files.clone(), &recv, "Yes")));
+        assert!(stale.iter().all(|path| !path.exists()));
+        assert!(!output.join("old").exists());
+        assert_eq!(fs::read(output.join("kept.txt")).unwrap(), b"new");
+        assert_eq!(fs::read(output.join("filtered.log")).unwrap(), b"old");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn descriptors_carry_the_key() {
+        let addr = SocketAddr::from(([192, 168, 1, 2], PORT));
+        let key = SessionKey::generate().unwrap();
+        for key in [None, Some(key)] {
+            let descriptor = connection_descriptor(addr, key.as_ref());
+            assert!(parse_connection_descriptor(&descriptor) == Some((addr, key)));
+        }
+        assert!(parse_connection_descriptor("sf://192.168.1.2:8370/")
+            .unwrap()
+            .1
+            .is_none());
+        assert!(parse_connection_descriptor("sf://192.168.1.2:8370/nothex").is_none());
+        assert!(parse_connection_descriptor("sf://192.168.1.2:8370/00ff").is_none());
+        assert!(parse_connection_descriptor("192.168.1.2:8370").is_none());
+    }
+
+    // Only the sender that proved to be who it is can resume the session, even if someone
+    // else knows its id.
+    #[test]
+    fn resuming_needs_the_same_identity() {
+        let (sender, impostor) = (identity("sender"), identity("impostor"));
+        let socket = SocketOptions {
+            timeout: Some(Duration::from_secs(10)),
+            send_buffer: None,
+            recv_buffer: None,
+            nodelay: false,
+        };
+        let session = 0x5f5f_5f5f;
+        let key = sender.public_key();
+        let (session_key, other_key) = (
+            SessionKey::generate().unwrap(),
+            SessionKey::generate().unwrap(),
+        );
+        // before version 23 the reply is as it is, and then it's in a frame, and since
+        // version 24 the session key is proven again too
+        for version in [22, VERSION] {
+            let keyed = (version >= 24).then_some(&session_key);
+            task::block_on(async {
+                let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+                let addr = listener.local_addr().unwrap();
+                let mut reply = 3u32.to_le_bytes().to_vec();
+                reply.extend(&5u64.to_le_bytes());
+                reply.push(RESUME_LIST);
+                let window = Duration::from_secs(10);
+                let receiving = await_resume(
+                    &listener,
+                    session,
+                    (version, Some(&key), keyed.map(Some)),
+                    &reply,
+                    &socket,
+                    window,
+                    &None,
+                );
+
+                let resuming = async {
+                    let window = Duration::from_secs(1);
+                    // knowing the session is not enough
+                    let mut stream = TcpStream::connect(addr).await.unwrap();
+                    stream
+                        .write_all(&[b's', b'f', b'+', version])
+                        .await
+                        .unwrap();
+                    stream.write_all(&session.to_le_bytes()).await.unwrap();
+                    stream.shutdown().await.unwrap();
+                    stream.read_to_end(&mut Vec::new()).await.unwrap();
+                    let identity = (version, Some(&impostor), keyed);
+                    let resumed = resume_session(addr, session, identity, &socket, window, &None);
+                    assert!(resumed.await.is_err());
+                    if version >= 24 {
+                        let identity = (version, Some(&sender), Some(&other_key));
+                        let resumed =
+                            resume_session(addr, session, identity, &socket, window, &None);
+                        assert!(resumed.await.is_err());
+                    }
+                    let identity = (version, Some(&sender), keyed);
+                    let resumed = resume_session(addr, session, identity, &socket, window, &None);
+                    let (mut stream, index, offset, rest) = resumed.await.unwrap();
+                    assert_eq!((index, offset), (3, 5));
+                    if version >= 23 {
+                        assert_eq!(rest, [RESUME_LIST]);
+                    } else {
+                        let mut state = [0u8];
+                        stream.read_exact(&mut state).await.unwrap();
+                        assert_eq!(state, [RESUME_LIST]);
+                    }
+                };
+                let (received, ()) = tokio::join!(receiving, resuming);
+                received.unwrap();
+            });
+        }
+    }
+}
@@ -0,0 +1,149 @@
+//! Where what is logged goes, for looking into what happened in more detail than what is
+//! printed, or afterwards.
+
+#[cfg(windows)]
+use crate::service;
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use std::cmp::Reverse;
+use std::env;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The variable with the filters that replace the level given by the flags, like `debug`
+/// for everything, or `sf=trace,warn` for a target and everything else.
+pub const FILTER_VAR: &str = "SF_LOG";
+
+struct Logger {
+    // the most verbose level of each target prefix, the longest ones first
+    filters: Vec<(String, LevelFilter)>,
+    default: LevelFilter,
+    output: Output,
+}
+
+enum Output {
+    // only what is not printed anyway, which is the details
+    Stderr,
+    File(Mutex<File>),
+    // the details are left out, there's no telling how much there would be
+    #[cfg(windows)]
+    EventLog(service::EventLog),
+}
+
+impl Logger {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.filters
+            .iter()
+            .find(|(prefix, _)| target.starts_with(prefix.as_str()))
+            .map_or(self.default, |&(_, level)| level)
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = format!(
+            "{}.{:03} {:5} {}: {}\n",
+            now.as_secs(),
+            now.subsec_millis(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        match &self.output {
+            Output::File(file) => {
+                let _ = file.lock().unwrap().write_all(line.as_bytes());
+            }
+            Output::Stderr if record.level() >= Level::Debug => {
+                let _ = io::stderr().write_all(line.as_bytes());
+            }
+            Output::Stderr => {}
+            #[cfg(windows)]
+            Output::EventLog(events) if record.level() <= Level::Info => {
+                events.report(record.level(), &record.args().to_string());
+            }
+            #[cfg(windows)]
+            Output::EventLog(_) => {}
+        }
+    }
+
+    fn flush(&self) {
+        if let Output::File(file) = &self.output {
+            let _ = file.lock().unwrap().flush();
+        }
+    }
+}
+
+/// Starts logging to the file at `path`, or if there is none, to the event log when running
+/// as a Windows service, or to the standard error otherwise. `verbosity` is how many times
+/// more detail was asked for.
+pub fn init(verbosity: u8, path: Option<&Path>, system_service: bool) -> io::Result<()> {
+    let default = match verbosity {
+        0 => LevelFilter::Info,
+        1 => LevelFilter::Debug,
+        _ => LevelFilter::Trace,
+    };
+    let output = match path {
+        Some(path) => Output::File(Mutex::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        #[cfg(windows)]
+        None if system_service => Output::EventLog(service::EventLog::open()?),
+        None => Output::Stderr,
+    };
+    #[cfg(not(windows))]
+    let _ = system_service;
+    let mut logger = Logger {
+        filters: Vec::new(),
+        default,
+        output,
+    };
+    if let Ok(filters) = env::var(FILTER_VAR) {
+        parse_filters(&filters, &mut logger);
+    }
+
+    let max = logger
+        .filters
+        .iter()
+        .map(|&(_, level)| level)
+        .fold(logger.default, std::cmp::max);
+    log::set_max_level(max);
+    log::set_boxed_logger(Box::new(logger)).map_err(io::Error::other)
+}
+
+fn parse_filters(filters: &str, logger: &mut Logger) {
+    for filter in filters.split(',').map(str::trim).filter(|f| !f.is_empty()) {
+        let (target, level) = match filter.split_once('=') {
+            Some((target, level)) => (Some(target), level),
+            None => (None, filter),
+        };
+        let level = match level.parse() {
+            Ok(level) => level,
+            Err(_) => {
+                eprintln!(
+                    "ignoring {:?} in {}, it's not a log level",
+                    filter, FILTER_VAR
+                );
+                continue;
+            }
+        };
+        match target {
+            Some(target) => logger.filters.push((target.to_string(), level)),
+            None => logger.default = level,
+        }
+    }
+    logger
+        .filters
+        .sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+}
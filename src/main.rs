@@ -1,350 +1,420 @@
 mod args;
-mod ip;
-
-use ip::get_ip_addresses;
-use std::collections::HashSet;
-use std::convert::TryInto;
-use std::error::Error;
-use std::fs::{self, File};
-use std::io::{self, Read, Write};
-use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
-use std::path::{Path, PathBuf};
+mod control;
+mod hook;
+mod jobs;
+mod logger;
+mod metrics;
+mod notify;
+mod open;
+mod profile;
+mod qr;
+mod service;
+mod speed;
+mod stats;
+mod tui;
+
+use sf::task::block_on;
+use sf::{format_bytes, format_duration};
 use std::process::exit;
-use std::thread;
-use std::time::Duration;
-use walkdir::WalkDir;
-
-// Transfer parameters
-const VERSION: u8 = 3;
-const CHUNK_SIZE: usize = 4 * 1024 * 1024;
-const SIGNAL_DELAY: Duration = Duration::from_secs(2);
-const PATH_SEPARATORS: [u8; 2] = [b'/', b'\\'];
-
-// Connection addresses
-const PORT: u16 = 8370; // concat(value of 'S', value of 'F')
-const SIGNALING_PORT: u16 = 8369;
-const CLIENT_BROADCAST_PORT: u16 = 38369;
-
-type Result<T> = std::result::Result<T, Box<dyn Error>>;
-
-// === Transfer logic
-
-// net packet format:
-// * "sf-"
-// * version: u8
-// * file list len: u32
-// * for each file:
-//   * file len: u64
-//   * name len: u32
-//   * name: [u8]
-// * for each file:
-//   * file data: [u8]
-fn send(addr: SocketAddr, files: Vec<PathBuf>) -> Result<()> {
-    // calculate file list buffer
-    let mut buffer = vec![b's', b'f', b'-', VERSION, 0, 0, 0, 0];
-
-    for file in files.iter() {
-        let file_len: u64 = fs::metadata(file)?.len().try_into()?;
-        buffer.extend(&file_len.to_le_bytes());
-
-        let name = file.to_string_lossy();
-        let name = name.as_bytes();
-        let name_len: u32 = name.len().try_into()?;
-        buffer.extend(&name_len.to_le_bytes());
-
-        // windows seems to handle forward slashes to separate directories correctly, but
-        // linux will happily use backslashes in the file name; map those to forward slashes
-        buffer.extend(name.into_iter().map(|c| match *c {
-            b'\\' => b'/',
-            c => c,
-        }));
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+fn run(settings: args::Settings) -> sf::Result<()> {
+    let (on_complete, on_session_complete) = (settings.on_complete, settings.on_session_complete);
+    sf::set_quiet(settings.quiet);
+    sf::set_exact_sizes(settings.exact_sizes);
+    // the output is the archive, so everything else goes elsewhere
+    if settings.recv_tar {
+        sf::set_messages_to_stderr(true);
     }
-
-    let buffer_len: u32 = buffer.len().try_into()?;
-    buffer[4..8].copy_from_slice(&buffer_len.to_le_bytes());
-
-    println!("connecting to server {}...", addr);
-    let mut stream = TcpStream::connect(addr)?;
-
-    println!("sending file list...");
-    stream.write_all(&buffer)?;
-
-    let mut buffer = vec![0; CHUNK_SIZE];
-    let file_count = files.len().to_string();
-    for (i, file) in files.into_iter().enumerate() {
-        println!(
-            "[{n:>p$}/{c}] sending file {:?}...",
-            file,
-            n = i,
-            p = file_count.len(),
-            c = file_count
-        );
-        let mut file = File::open(file)?;
-        while let Ok(n) = file.read(&mut buffer) {
-            if n == 0 {
-                break;
+    let bench = matches!(settings.mode, args::Mode::Bench { .. });
+    let mut graph = None;
+    // only transfers are summarized, since verifying and serving report as they go
+    let (done, summarize, tracker, result) = match settings.mode {
+        args::Mode::Sender {
+            ip,
+            files,
+            mut options,
+        } => {
+            let addr = server_address(ip, options.retry, settings.wake)?;
+            let tracker = stats::Tracker::new(&mut options.events);
+            if options.receiver_progress {
+                show_stored(&mut options.events);
             }
-            stream.write_all(&buffer[..n])?;
+            if settings.stats {
+                graph = Some(speed::SpeedGraph::new(&mut options.events));
+            }
+            (
+                "sent",
+                true,
+                tracker,
+                block_on(sf::send_async(addr, files, options)),
+            )
         }
-    }
-
-    Ok(())
-}
-
-fn recv(prefix: args::PathPrefix) -> Result<()> {
-    let addr = get_ip_addresses().expect("failed to get ip addresses")[0];
-    println!(
-        "waiting for client on {} (attempting to broadcast own ip)...",
-        addr.ip
-    );
-    let mut stream = {
-        let listener = TcpListener::bind((addr.ip, PORT))?;
-        match survey_potential_clients(&listener, addr.subnet_mask) {
-            Ok(s) => s,
-            Err(e) => {
-                println!(
-                    "cannot broadcast ip to potential clients, direct ip must be used:\n  {}",
-                    e
-                );
-                listener.set_nonblocking(false).unwrap();
-                listener.accept().expect("no client connected").0
+        args::Mode::Verify {
+            ip,
+            files,
+            mut options,
+        } => {
+            let addr = server_address(ip, None, None)?;
+            let tracker = stats::Tracker::new(&mut options.events);
+            (
+                "verified",
+                false,
+                tracker,
+                block_on(sf::verify_async(addr, files, options)),
+            )
+        }
+        args::Mode::Bench { ip, mut options } => {
+            let addr = server_address(ip, options.retry, settings.wake)?;
+            let tracker = stats::Tracker::new(&mut options.events);
+            if settings.stats {
+                graph = Some(speed::SpeedGraph::new(&mut options.events));
             }
+            (
+                "benchmarked",
+                false,
+                tracker,
+                block_on(sf::send_async(addr, Vec::new(), options)),
+            )
         }
-    };
-
-    println!("receiving file list...");
-    let mut files = Vec::new(); // (file len, file name)
-
-    let mut u32_buffer = [0u8; 4];
-    let mut u64_buffer = [0u8; 8];
-
-    stream.read_exact(&mut u32_buffer)?;
-
-    if &u32_buffer[..3] != b"sf-" {
-        return Err(format!("bad header: {:?}", &u32_buffer[..3]).into());
-    }
-    if u32_buffer[3] != VERSION {
-        return Err(format!("incompatible version: {:?}", u32_buffer[3]).into());
-    }
-
-    stream.read_exact(&mut u32_buffer)?;
-    let buffer_len: usize = u32::from_le_bytes(u32_buffer).try_into()?;
-
-    // minus 4 header, 4 buffer len
-    let mut buffer = vec![0u8; buffer_len - 8];
-    stream.read_exact(&mut buffer)?;
-
-    let mut common_prefix = match prefix {
-        // the common prefix will only ever shorten, so if it starts empty, there won't be any
-        args::PathPrefix::Keep => Some(&buffer[..0]),
-        args::PathPrefix::Strip => None,
-    };
-
-    let mut i = 0;
-    while i < buffer.len() {
-        u64_buffer.copy_from_slice(&buffer[i..i + 8]);
-        i += 8;
-        let file_len: usize = u64::from_le_bytes(u64_buffer).try_into()?;
-
-        u32_buffer.copy_from_slice(&buffer[i..i + 4]);
-        i += 4;
-        let name_len: usize = u32::from_le_bytes(u32_buffer).try_into()?;
-
-        let name = &buffer[i..i + name_len];
-        i += name_len;
-
-        common_prefix = Some(match common_prefix {
-            None => name,
-            Some(prefix) => {
-                if let Some(equal_up_to) = prefix.iter().zip(name).position(|(x, y)| x != y) {
-                    &prefix[..equal_up_to]
-                } else {
-                    prefix
-                }
+        args::Mode::Wake { mac } => {
+            sf::wake(mac)?;
+            println!("sent the wake-up packet to {}", format_mac_address(mac));
+            return Ok(());
+        }
+        args::Mode::InstallService { args } => {
+            service::install(&args)?;
+            println!(
+                "installed and started the `{}' service, which keeps receiving from now on",
+                service::SERVICE_NAME
+            );
+            return Ok(());
+        }
+        args::Mode::UninstallService => {
+            service::uninstall()?;
+            println!(
+                "stopped and removed the `{}' service",
+                service::SERVICE_NAME
+            );
+            return Ok(());
+        }
+        args::Mode::QueueAdd {
+            queue,
+            args,
+            dir,
+            retries,
+            retry_delay,
+        } => {
+            let id = jobs::add(&queue, args, dir, retries, retry_delay)?;
+            println!("added job {} to the queue in {:?}", id, queue);
+            return Ok(());
+        }
+        args::Mode::QueueRun { queue, jobs } => return jobs::run(&queue, jobs),
+        args::Mode::QueueStatus { queue } => return jobs::status(&queue),
+        args::Mode::Discover { window } => {
+            let addr = server_address(args::ServerAddress::Auto, window, None)?;
+            println!("found a receiver at {}", addr.ip());
+            return Ok(());
+        }
+        args::Mode::History { path } => return show_history(&path),
+        args::Mode::Serve { files, mut options } => {
+            let tracker = stats::Tracker::new(&mut options.events);
+            (
+                "served",
+                false,
+                tracker,
+                block_on(sf::serve_async(files, options)),
+            )
+        }
+        args::Mode::Receiver(mut options) => {
+            let tracker = stats::Tracker::new(&mut options.events);
+            let hooks = (on_complete.is_some() || on_session_complete.is_some()).then(|| {
+                hook::Hooks::new(
+                    &mut options.events,
+                    on_complete,
+                    on_session_complete,
+                    options.output.clone(),
+                    options.staging.is_some(),
+                )
+            });
+            let opener = settings
+                .open
+                .then(|| open::Opener::new(&mut options.events, options.output.clone()));
+            if settings.qr {
+                show_qr(&mut options.events, options.key);
             }
-        });
-
-        files.push((file_len, std::str::from_utf8(name)?));
-    }
-
-    let common_prefix = match common_prefix {
-        None => &buffer[..0], // any empty string
-        Some(b) => b,
-    };
-    let common_prefix_len = if let Some(sep_idx) = common_prefix
-        .iter()
-        .rposition(|c| PATH_SEPARATORS.contains(c))
-    {
-        // +1 to exclude the separator itself
-        sep_idx + 1
-    } else {
-        // there is no parent, it's all separate files at the same level, so there is nothing to strip
-        0
-    };
-
-    let mut created_dirs = HashSet::new();
-    let mut buffer = vec![0; CHUNK_SIZE];
-
-    let file_count = files.len().to_string();
-    for (i, (mut file_len, name)) in files.into_iter().enumerate() {
-        let path = Path::new(&name[common_prefix_len..]);
-        println!(
-            "[{n:>p$}/{c}] receiving file {:?}...",
-            path,
-            n = i,
-            p = file_count.len(),
-            c = file_count
-        );
-        if let Some(parent) = path.parent() {
-            if created_dirs.insert(parent) {
-                fs::create_dir_all(parent)?;
+            if settings.stats {
+                graph = Some(speed::SpeedGraph::new(&mut options.events));
+            }
+            if let Some(addr) = settings.metrics {
+                metrics::serve(&mut options.events, addr)?;
             }
+            if settings.service {
+                options.listener = service::inherited_listener();
+                options.cancel = Some(service::cancel_on_terminate());
+            }
+            let token = options.pause.get_or_insert_with(sf::PauseToken::new);
+            control::toggle_on_signal(token);
+            // the full screen interface reads the keys itself
+            if !settings.tui && !settings.service {
+                control::toggle_on_enter(token);
+            }
+            let result = if settings.tui {
+                tui::receive(options)
+            } else {
+                block_on(sf::recv_async(options))
+            };
+            let result = match result {
+                // being told to stop is how a service is meant to end
+                Err(sf::Error::Cancelled) if settings.service => Ok(()),
+                result => result,
+            };
+            if let Some(hooks) = hooks {
+                hooks.finish(&result);
+            }
+            if let Some(opener) = opener {
+                opener.finish(&result);
+            }
+            ("received", true, tracker, result)
         }
-
-        let mut f = File::create(path)?;
-        while file_len != 0 {
-            let len = file_len.min(buffer.len());
-            let n = stream.read(&mut buffer[..len])?;
-            if n == 0 {
-                return Err("connection ended without receiving full file".into());
+    };
+    if let Some(graph) = graph {
+        graph.finish();
+    }
+    let stats = tracker.stats();
+    // there's nothing to say about a transfer that never started
+    if summarize && stats.connected {
+        let record = history_record(done, &stats, &result);
+        let summary = if settings.json {
+            record.to_json()
+        } else {
+            let mut summary = format!("{} {}", done, stats);
+            for (path, reason) in &stats.unreadable {
+                summary.push_str(&format!("\n  could not read {:?}: {}", path, reason));
             }
-            file_len -= n;
-            f.write_all(&buffer[..n])?;
+            summary
+        };
+        log::info!(target: sf::MESSAGES_TARGET, "{}", summary);
+        if settings.recv_tar {
+            eprintln!("{}", summary);
+        } else {
+            println!("{}", summary);
         }
+        record_history(&record);
+    }
+    if bench && result.is_ok() {
+        report_bench(&stats);
+    }
+    if settings.notify {
+        notify::notify(done, &stats, &result);
+    }
+    match result {
+        Ok(()) if stats.failed + stats.unreadable.len() > 0 => Err(sf::Error::Incomplete {
+            failed: stats.failed + stats.unreadable.len(),
+        }),
+        result => result,
     }
-
-    Ok(())
 }
 
-// === Automatic discovery
-
-// The alternative would be to use multicast, but broadcasting should work just fine in LAN.
-// (Attempting to broadcast outside the subnet is very likely to just get the packet dropped.)
-fn make_broadcast_addr(addr: SocketAddr, subnet_mask: IpAddr) -> SocketAddr {
-    match (addr, subnet_mask) {
-        (SocketAddr::V4(addr), IpAddr::V4(mask)) => {
-            let mut octets = addr.ip().octets();
-            for (o, m) in octets.iter_mut().zip(mask.octets().iter()) {
-                *o |= !m;
-            }
-            SocketAddr::new(Ipv4Addr::from(octets).into(), addr.port())
+fn server_address(
+    ip: args::ServerAddress,
+    retry: Option<Duration>,
+    wake: Option<sf::MacAddress>,
+) -> sf::Result<std::net::SocketAddr> {
+    if let Some(mac) = wake {
+        sf::wake(mac)?;
+        if !sf::is_quiet() {
+            println!(
+                "sent the wake-up packet to {}, waiting for it to come up...",
+                format_mac_address(mac)
+            );
         }
-        (SocketAddr::V6(addr), IpAddr::V6(mask)) => {
-            let mut octets = addr.ip().octets();
-            for (o, m) in octets.iter_mut().zip(mask.octets().iter()) {
-                *o |= !m;
+    }
+    match ip {
+        args::ServerAddress::Auto => {
+            if !sf::is_quiet() {
+                println!("attempting to discover the server's ip...");
+            }
+            match retry {
+                Some(window) => block_on(sf::discover_server_within_async(window)),
+                None => block_on(sf::discover_server_async()),
             }
-            SocketAddr::new(Ipv6Addr::from(octets).into(), addr.port())
         }
-        _ => panic!("subnet mask version differs from socket address ip version"),
+        args::ServerAddress::Direct(addr) => Ok(addr),
     }
 }
 
-fn serialize_socket_addr(addr: SocketAddr) -> [u8; 20] {
-    let mut buffer = [0; 20];
-    match addr {
-        SocketAddr::V4(addr) => {
-            buffer[0] = 4;
-            buffer[1..5].copy_from_slice(&addr.ip().octets());
-            buffer[5..7].copy_from_slice(&addr.port().to_be_bytes());
-        }
-        SocketAddr::V6(addr) => {
-            buffer[0] = 6;
-            buffer[1..17].copy_from_slice(&addr.ip().octets());
-            buffer[17..19].copy_from_slice(&addr.port().to_be_bytes());
-        }
+// What the history says about the transfer that just ended with the `result`.
+fn history_record(
+    done: &str,
+    stats: &stats::Stats,
+    result: &sf::Result<()>,
+) -> sf::history::Record {
+    sf::history::Record {
+        when: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        done: done.to_string(),
+        peer: stats.peer.map(|peer| peer.ip().to_string()),
+        files: stats.files as u64,
+        bytes: stats.bytes,
+        secs: stats.elapsed.as_secs(),
+        skipped: stats.skipped as u64,
+        failed: (stats.failed + stats.unreadable.len()) as u64,
+        average: stats.average,
+        peak: stats.peak,
+        ratio: stats.ratio,
+        error: result.as_ref().err().map(ToString::to_string),
     }
-    buffer
 }
 
-fn deserialize_socket_addr(buffer: [u8; 20]) -> Result<SocketAddr> {
-    match buffer[0] {
-        4 => {
-            let ip: [u8; 4] = buffer[1..5].try_into().unwrap();
-            let port = buffer[5..7].try_into().unwrap();
-            Ok(SocketAddr::new(
-                Ipv4Addr::from(ip).into(),
-                u16::from_be_bytes(port),
-            ))
-        }
-        6 => {
-            let ip: [u8; 16] = buffer[1..17].try_into().unwrap();
-            let port = buffer[17..19].try_into().unwrap();
-            Ok(SocketAddr::new(
-                Ipv6Addr::from(ip).into(),
-                u16::from_be_bytes(port),
-            ))
-        }
-        _ => Err("invalid socket addr version".into()),
+// Remember the transfer in the history, which is not worth failing over.
+fn record_history(record: &sf::history::Record) {
+    let Some(path) = sf::history::default_path() else {
+        return;
+    };
+    if let Err(e) = sf::history::append(&path, record) {
+        log::warn!("cannot record the transfer in the history: {}", e);
     }
 }
 
-// Broadcast a signal to survey for potential clients for them to connect via automatic mode.
-// If any of the steps fail, bail, in order to fallback to direct a connection.
-fn survey_potential_clients(listener: &TcpListener, subnet_mask: IpAddr) -> Result<TcpStream> {
-    let listener_addr = listener.local_addr()?;
-    let serliazed_addr = serialize_socket_addr(listener_addr);
-    let listener_net_broadcast_ip = make_broadcast_addr(listener_addr, subnet_mask).ip();
-
-    listener.set_nonblocking(true)?;
-    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, CLIENT_BROADCAST_PORT))?;
-    loop {
-        print!(".");
-        io::stdout().flush().unwrap();
-        match listener.accept() {
-            Ok((s, _)) => break Ok(s),
-            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                socket.send_to(&serliazed_addr, (listener_net_broadcast_ip, SIGNALING_PORT))?;
-                thread::sleep(SIGNAL_DELAY);
-                continue;
+fn show_history(path: &std::path::Path) -> sf::Result<()> {
+    let records = sf::history::load(path)?;
+    if records.is_empty() {
+        println!("no transfers were made yet");
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    for record in records {
+        println!(
+            "{:>9} ago  {} {} files ({}) {} {} in {}{}{}",
+            format_ago(now.saturating_sub(record.when)),
+            record.done,
+            record.files,
+            format_bytes(record.bytes),
+            if record.done == "sent" { "to" } else { "from" },
+            record.peer.as_deref().unwrap_or("?"),
+            format_duration(Duration::from_secs(record.secs)),
+            match record.ratio {
+                Some(ratio) => format!(", compressed to {:.0}%", ratio * 100.0),
+                None => String::new(),
+            },
+            match &record.error {
+                Some(error) => format!(", failed: {}", error),
+                None => String::new(),
             }
-            Err(e) => break Err(e.into()),
-        }
+        );
     }
+    Ok(())
 }
 
-fn discover_server() -> Result<SocketAddr> {
-    let mut buf = [0; 20];
-    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, SIGNALING_PORT))?;
-    socket.recv_from(&mut buf)?;
-    deserialize_socket_addr(buf)
+// How long ago something happened, in the largest unit that fits.
+fn format_ago(secs: u64) -> String {
+    match secs {
+        0..=59 => format!("{}s", secs),
+        60..=3599 => format!("{}m", secs / 60),
+        3600..=86399 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400),
+    }
 }
 
-// === CLI
+fn format_mac_address(mac: sf::MacAddress) -> String {
+    mac.iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<Vec<_>>()
+        .join(":")
+}
 
-fn run(settings: args::Settings) -> Result<()> {
-    match settings.mode {
-        args::Mode::Sender { ip, files } => {
-            let addr = match ip {
-                args::ServerAddress::Auto => {
-                    println!("attempting to discover the server's ip...");
-                    discover_server()?
-                }
-                args::ServerAddress::Direct(ip) => SocketAddr::new(ip, PORT),
-            };
+fn report_bench(stats: &stats::Stats) {
+    println!(
+        "sent {} in {}",
+        format_bytes(stats.bytes),
+        format_duration(stats.elapsed)
+    );
+    println!(
+        "throughput: {}/s ({:.0} Mbit/s) on average, {}/s at peak",
+        format_bytes(stats.average),
+        stats.average as f64 * 8.0 / 1e6,
+        format_bytes(stats.peak)
+    );
+    match stats.round_trip {
+        Some(rtt) => println!("round trip: {:.2} ms", rtt.as_secs_f64() * 1000.0),
+        None => println!("round trip: not measured"),
+    }
+}
 
-            let mut paths = Vec::new();
-            for arg in files {
-                for entry in WalkDir::new(arg) {
-                    let entry = entry?;
-                    if entry.path().is_file() {
-                        paths.push(entry.into_path());
-                    }
-                }
+// Print a QR code with the descriptor of the address and the session key once the receiver is
+// listening.
+fn show_qr(events: &mut Option<sf::EventHandler>, key: Option<sf::SessionKey>) {
+    let previous = events.take();
+    *events = Some(Box::new(move |event| {
+        if let sf::TransferEvent::Listening { addr } = &event {
+            let descriptor = sf::connection_descriptor(*addr, key.as_ref());
+            match qr::render(&descriptor) {
+                Some(code) => print!("{}", code),
+                None => println!("the address is too long to fit in a QR code"),
             }
+            println!("scan the code or pass {} as the IP to send", descriptor);
+        }
+        if let Some(previous) = &previous {
+            previous(event);
+        }
+    }));
+}
 
-            send(addr, paths)
+// Print every few seconds how much the receiver has stored, as it says while being sent data.
+fn show_stored(events: &mut Option<sf::EventHandler>) {
+    const EVERY: Duration = Duration::from_secs(5);
+    let previous = events.take();
+    let shown_at = Mutex::new(None::<Instant>);
+    *events = Some(Box::new(move |event| {
+        if let sf::TransferEvent::Stored { bytes } = &event {
+            let mut shown_at = shown_at.lock().unwrap();
+            if shown_at.is_none_or(|at| at.elapsed() >= EVERY) {
+                *shown_at = Some(Instant::now());
+                println!("the receiver has stored {} so far", format_bytes(*bytes));
+            }
         }
-        args::Mode::Receiver { prefix } => recv(prefix),
-    }
+        if let Some(previous) = &previous {
+            previous(event);
+        }
+    }));
 }
 
-fn main() {
-    exit(match run(args::parse()) {
+fn exit_code(result: sf::Result<()>) -> i32 {
+    match result {
         Ok(_) => 0,
         Err(e) => {
+            log::error!("{}", e);
             eprintln!("FATAL: {}", e);
-            1
+            e.exit_code()
+        }
+    }
+}
+
+fn main() {
+    let settings = match args::parse() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            exit(sf::Error::INVALID_ARGUMENTS_EXIT_CODE);
         }
-    });
+    };
+    let log_file = settings.log_file.as_deref();
+    if let Err(e) = logger::init(settings.verbosity, log_file, settings.system_service) {
+        eprintln!("FATAL: cannot log to {:?}: {}", log_file, e);
+        exit(1);
+    }
+    if settings.system_service {
+        exit(match service::dispatch(move || exit_code(run(settings))) {
+            Ok(code) => code,
+            Err(e) => {
+                eprintln!("FATAL: cannot run as a service: {}", e);
+                1
+            }
+        });
+    }
+    exit(exit_code(run(settings)));
 }
@@ -0,0 +1,189 @@
+//! A record of the files sent, with their hashes, that can later be verified against the
+//! receiver.
+//!
+//! It's a JSON object with the `files`, each with its `path`, `size`, `mtime` (in seconds
+//! since the epoch) and `sha256`. Names which are not valid UTF-8 also have `path_bytes`,
+//! the exact name in hex, since `path` can only show them approximately.
+//!
+//! The received files can be listed in it too, or in a `SHA256SUMS` file, which only has the
+//! hashes and the names but can be checked with `sha256sum -c`.
+
+use crate::hash::{self, Digest};
+use crate::{cancel, modified_secs};
+use crate::{json, names, pipe, Error, Result};
+use std::convert::TryInto;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Bumped whenever the format changes in a way older readers can't handle
+const MANIFEST_VERSION: u64 = 1;
+
+/// A file as it's named to the receiver, along with what identifies its contents.
+pub(crate) struct Entry {
+    pub name: Vec<u8>,
+    pub len: u64,
+    pub modified: u64,
+    pub digest: Digest,
+}
+
+/// Hashes the files, in parallel but keeping their order.
+pub(crate) fn hash_files(
+    files: &[PathBuf],
+    cancel: &Option<cancel::CancelToken>,
+) -> Result<Vec<Entry>> {
+    let file_count = files.len().to_string();
+    let mut queue = files.iter().enumerate();
+    let mut entries = Vec::with_capacity(files.len());
+    pipe::ordered_pool(
+        || {
+            if cancel::is_cancelled(cancel) {
+                return None;
+            }
+            let (i, file) = queue.next()?;
+            out!(
+                "[{n:>p$}/{c}] hashing file {:?}...",
+                file,
+                n = i,
+                p = file_count.len(),
+                c = file_count
+            );
+            Some(file)
+        },
+        |file| {
+            let hashed = fs::metadata(file)
+                .and_then(|meta| Ok((meta.len(), modified_secs(&meta), hash::hash_file(file)?)));
+            (file, hashed)
+        },
+        |(file, hashed)| -> Result<()> {
+            let (len, modified, digest) = hashed.map_err(|e| Error::from(e).at(file))?;
+            entries.push(Entry {
+                name: names::wire_name(file),
+                len,
+                modified,
+                digest,
+            });
+            Ok(())
+        },
+    )?;
+    cancel::check(cancel)?;
+    Ok(entries)
+}
+
+/// Writes the manifest with the entries to `path`.
+pub(crate) fn write<'a>(path: &Path, entries: impl IntoIterator<Item = &'a Entry>) -> Result<()> {
+    let mut out = format!(
+        "{{\n  \"sf_manifest\": {},\n  \"files\": [",
+        MANIFEST_VERSION
+    );
+    for (i, entry) in entries.into_iter().enumerate() {
+        let _ = write!(
+            out,
+            "{}\n    {{\"path\": {}, ",
+            if i == 0 { "" } else { "," },
+            json::string(&names::display(&entry.name))
+        );
+        if std::str::from_utf8(&entry.name).is_err() {
+            let _ = write!(out, "\"path_bytes\": \"{}\", ", to_hex(&entry.name));
+        }
+        let _ = write!(
+            out,
+            "\"size\": {}, \"mtime\": {}, \"sha256\": \"{}\"}}",
+            entry.len,
+            entry.modified,
+            to_hex(&entry.digest)
+        );
+    }
+    out.push_str("\n  ]\n}\n");
+    fs::write(path, out).map_err(|e| Error::from(e).at(path))
+}
+
+/// Writes the entries to `path` in the format of `sha256sum`. Names with a backslash or a line
+/// break have those escaped, and the line starts with a backslash to say so.
+pub(crate) fn write_sums(path: &Path, entries: &[Entry]) -> Result<()> {
+    let mut out = Vec::new();
+    for entry in entries {
+        let escaped = entry
+            .name
+            .iter()
+            .any(|b| matches!(b, b'\\' | b'\n' | b'\r'));
+        if escaped {
+            out.push(b'\\');
+        }
+        out.extend(to_hex(&entry.digest).as_bytes());
+        out.extend(b"  ");
+        for &b in &entry.name {
+            match b {
+                b'\\' => out.extend(b"\\\\"),
+                b'\n' => out.extend(b"\\n"),
+                b'\r' => out.extend(b"\\r"),
+                b => out.push(b),
+            }
+        }
+        out.push(b'\n');
+    }
+    fs::write(path, out).map_err(|e| Error::from(e).at(path))
+}
+
+/// Reads back a manifest written by [`write`].
+pub(crate) fn read(path: &Path) -> Result<Vec<Entry>> {
+    let text = fs::read_to_string(path).map_err(|e| Error::from(e).at(path))?;
+    let invalid = |reason: &str| Error::Other(format!("invalid manifest {:?}: {}", path, reason));
+
+    let manifest = json::parse(&text).ok_or_else(|| invalid("not valid JSON"))?;
+    match manifest.get("sf_manifest").and_then(json::Value::as_u64) {
+        Some(MANIFEST_VERSION) => {}
+        Some(_) => return Err(invalid("made by a newer version")),
+        None => return Err(invalid("not a manifest")),
+    }
+    let files = manifest
+        .get("files")
+        .and_then(json::Value::as_array)
+        .ok_or_else(|| invalid("no files"))?;
+
+    files
+        .iter()
+        .map(|file| {
+            let name = match file.get("path_bytes").and_then(json::Value::as_str) {
+                Some(hex) => from_hex(hex).ok_or_else(|| invalid("path_bytes is not hex"))?,
+                None => file
+                    .get("path")
+                    .and_then(json::Value::as_str)
+                    .ok_or_else(|| invalid("a file has no path"))?
+                    .as_bytes()
+                    .to_vec(),
+            };
+            let number = |key: &str| {
+                file.get(key)
+                    .and_then(json::Value::as_u64)
+                    .ok_or_else(|| invalid(&format!("{:?} has no {}", names::display(&name), key)))
+            };
+            let digest = file
+                .get("sha256")
+                .and_then(json::Value::as_str)
+                .and_then(from_hex)
+                .and_then(|digest| digest.try_into().ok())
+                .ok_or_else(|| invalid(&format!("{:?} has no sha256", names::display(&name))))?;
+            Ok(Entry {
+                len: number("size")?,
+                modified: number("mtime")?,
+                digest,
+                name,
+            })
+        })
+        .collect()
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
@@ -0,0 +1,264 @@
+//! Metrics of the receiver in the text format Prometheus scrapes, served over HTTP, so that
+//! monitoring can tell when the transfers it expects stop happening.
+
+use sf::{EventHandler, TransferEvent};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+// How far back the throughput is measured, and how often it's noted down over that time
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+const RATE_STEP: Duration = Duration::from_secs(1);
+
+#[derive(Default)]
+struct Peer {
+    sessions: u64,
+    bytes: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    sessions: u64,
+    failures: u64,
+    files: u64,
+    bytes: u64,
+    // when the last session that went well ended, in seconds since the unix epoch
+    last_success: Option<u64>,
+    // the peer of the session going on, if any
+    peer: Option<IpAddr>,
+    peers: BTreeMap<IpAddr, Peer>,
+    // length and bytes received of every file that started, until it's done
+    started: HashMap<usize, (u64, u64)>,
+    // how many bytes were received in total at some recent times, oldest first
+    marks: VecDeque<(Instant, u64)>,
+}
+
+impl Counters {
+    fn handle(&mut self, event: &TransferEvent) {
+        match *event {
+            TransferEvent::Connected { peer } => self.peer = Some(peer.ip()),
+            TransferEvent::FileStarted { index, len, .. } => {
+                self.started.insert(index, (len, 0));
+            }
+            TransferEvent::Progress { index, bytes } => {
+                if let Some((_, received)) = self.started.get_mut(&index) {
+                    // a resumed file may go back a bit
+                    let new = bytes.saturating_sub(*received);
+                    *received = bytes;
+                    self.add_bytes(new);
+                }
+            }
+            TransferEvent::FileDone { index } => {
+                if let Some((len, received)) = self.started.remove(&index) {
+                    self.files += 1;
+                    self.add_bytes(len.saturating_sub(received));
+                }
+            }
+            TransferEvent::Finished => {
+                self.end_session();
+                self.last_success = Some(unix_now());
+            }
+            TransferEvent::Failed { .. } => {
+                self.end_session();
+                self.failures += 1;
+            }
+            _ => {}
+        }
+    }
+
+    fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        if let Some(peer) = self.peer {
+            self.peers.entry(peer).or_default().bytes += bytes;
+        }
+        let now = Instant::now();
+        if self
+            .marks
+            .back()
+            .is_none_or(|(at, _)| now.duration_since(*at) >= RATE_STEP)
+        {
+            self.marks.push_back((now, self.bytes));
+        }
+    }
+
+    fn end_session(&mut self) {
+        self.sessions += 1;
+        if let Some(peer) = self.peer.take() {
+            self.peers.entry(peer).or_default().sessions += 1;
+        }
+        self.started.clear();
+    }
+
+    // How many bytes were received per second lately, which is none if nothing was.
+    fn rate(&mut self) -> u64 {
+        let now = Instant::now();
+        while self
+            .marks
+            .front()
+            .is_some_and(|(at, _)| now.duration_since(*at) > RATE_WINDOW)
+        {
+            self.marks.pop_front();
+        }
+        match self.marks.front() {
+            Some(&(at, bytes)) => {
+                let elapsed = now.duration_since(at).max(RATE_STEP);
+                ((self.bytes - bytes) as f64 / elapsed.as_secs_f64()) as u64
+            }
+            None => 0,
+        }
+    }
+
+    fn render(&mut self) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+            let _ = writeln!(text, "# HELP {} {}", name, help);
+            let _ = writeln!(text, "# TYPE {} {}", name, kind);
+            for (labels, value) in samples {
+                let _ = writeln!(text, "{}{} {}", name, labels, value);
+            }
+        };
+        let rate = self.rate();
+        metric(
+            "sf_sessions_total",
+            "counter",
+            "Transfers received, whether they went well or failed.",
+            &[(String::new(), self.sessions)],
+        );
+        metric(
+            "sf_session_failures_total",
+            "counter",
+            "Transfers that failed.",
+            &[(String::new(), self.failures)],
+        );
+        metric(
+            "sf_received_files_total",
+            "counter",
+            "Files received in full.",
+            &[(String::new(), self.files)],
+        );
+        metric(
+            "sf_received_bytes_total",
+            "counter",
+            "Bytes of file data received, including those of files that failed.",
+            &[(String::new(), self.bytes)],
+        );
+        metric(
+            "sf_receive_rate_bytes",
+            "gauge",
+            "Bytes received per second over the last few seconds.",
+            &[(String::new(), rate)],
+        );
+        metric(
+            "sf_receiving",
+            "gauge",
+            "Whether a transfer is going on.",
+            &[(String::new(), self.peer.is_some() as u64)],
+        );
+        if let Some(last_success) = self.last_success {
+            metric(
+                "sf_last_success_timestamp_seconds",
+                "gauge",
+                "When the last transfer that went well ended, in seconds since the epoch.",
+                &[(String::new(), last_success)],
+            );
+        }
+        let label = |peer: &IpAddr| format!("{{peer=\"{}\"}}", peer);
+        metric(
+            "sf_peer_sessions_total",
+            "counter",
+            "Transfers received from every sender.",
+            &self
+                .peers
+                .iter()
+                .map(|(peer, totals)| (label(peer), totals.sessions))
+                .collect::<Vec<_>>(),
+        );
+        metric(
+            "sf_peer_received_bytes_total",
+            "counter",
+            "Bytes of file data received from every sender.",
+            &self
+                .peers
+                .iter()
+                .map(|(peer, totals)| (label(peer), totals.bytes))
+                .collect::<Vec<_>>(),
+        );
+        text
+    }
+}
+
+/// Serves the metrics of the receiver at `/metrics` on the address, counted from the events
+/// of the transfers, which still reach the existing handler.
+pub fn serve(events: &mut Option<EventHandler>, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    if !sf::is_quiet() {
+        eprintln!(
+            "serving metrics at http://{}/metrics",
+            listener.local_addr()?
+        );
+    }
+
+    let counters = Arc::new(Mutex::new(Counters::default()));
+    let previous = events.take();
+    *events = Some({
+        let counters = Arc::clone(&counters);
+        Box::new(move |event| {
+            counters.lock().unwrap().handle(&event);
+            if let Some(previous) = &previous {
+                previous(event);
+            }
+        })
+    });
+
+    // nothing ever stops the wait for the next scrape, so the thread is left behind at the end
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|stream| respond(&stream, &counters));
+            if let Err(e) = result {
+                log::warn!("failed to serve the metrics: {}", e);
+            }
+        }
+    });
+    Ok(())
+}
+
+// Answer a single request, and close the connection.
+fn respond(stream: &TcpStream, counters: &Mutex<Counters>) -> io::Result<()> {
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    let mut reader = BufReader::new(stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // the headers say nothing that changes the answer, but are read so the client sees it
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", counters.lock().unwrap().render()),
+        (Some(_), Some("/metrics")) => ("405 Method Not Allowed", "not allowed\n".to_string()),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4; charset=utf-8\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
@@ -0,0 +1,406 @@
+//! Names are sent as raw bytes, with forward slashes as the separator, so that files whose
+//! names are not valid UTF-8 can still be transferred.
+//!
+//! On Unix, names are bytes already. On Windows, names are UTF-16, which may not be valid
+//! either. Bytes that are not valid UTF-8 become unpaired surrogates in the range U+DC80 to
+//! U+DCFF, which Windows accepts in names, and become the same bytes again when sent back.
+//!
+//! Windows also refuses a few characters anywhere in a name, names ending in a dot or a
+//! space, and names of devices such as `aux.txt`, regardless of their extension. Rather than
+//! failing mid-transfer, those are stored with an escaped name instead:
+//!
+//! * `%` itself becomes `%25`, so that names which look escaped already stay apart;
+//! * refused characters (`<>:"|?*` and control characters) become `%XX`, their hex value;
+//! * a dot or a space at the end becomes `%2E` or `%20`;
+//! * device names get their first letter escaped, so `aux.txt` becomes `%61ux.txt`.
+//!
+//! Every escaped name can thus be turned back into the one sent, by replacing each `%XX`
+//! with the byte it stands for.
+
+use crate::{Normalization, PathMap, PATH_SEPARATORS};
+use std::borrow::Cow;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use unicode_normalization::{is_nfc, is_nfd, UnicodeNormalization};
+
+const REFUSED_CHARS: [u8; 7] = [b'<', b'>', b':', b'"', b'|', b'?', b'*'];
+const DEVICE_NAMES: [&str; 6] = ["CON", "PRN", "AUX", "NUL", "CONIN$", "CONOUT$"];
+const NUMBERED_DEVICE_NAMES: [&str; 2] = ["COM", "LPT"];
+const EXTENDED_PREFIX: &str = r"\\?\";
+const EXTENDED_UNC_PREFIX: &str = r"\\?\UNC\";
+
+/// The name of the path as sent to a peer.
+pub(crate) fn wire_name(path: &Path) -> Vec<u8> {
+    // Windows seems to handle forward slashes to separate directories correctly, but linux
+    // will happily use backslashes in the file name.
+    let mut name = to_bytes(path);
+    for c in name.iter_mut() {
+        if *c == b'\\' {
+            *c = b'/';
+        }
+    }
+    name
+}
+
+/// The path a name sent by a peer stands for, relative to the directory it's stored in.
+/// Leading roots (and drives, on Windows) are ignored, and on Windows, names it would refuse
+/// are escaped. Returns `None` if the name goes up a directory.
+pub(crate) fn relative_path(name: &[u8]) -> Option<PathBuf> {
+    let separators: &[u8] = if cfg!(windows) { b"/\\" } else { b"/" };
+    let mut path = PathBuf::new();
+    for (i, part) in name.split(|c| separators.contains(c)).enumerate() {
+        match part {
+            b"" | b"." => {}
+            b".." => return None,
+            [drive, b':'] if cfg!(windows) && i == 0 && drive.is_ascii_alphabetic() => {}
+            part if cfg!(windows) => path.push(from_bytes(&escape_windows(part))),
+            part => path.push(from_bytes(part)),
+        }
+    }
+    Some(path)
+}
+
+/// The rest of the name once its drive, if `drive` and it has one, and its first `count`
+/// components are removed, like `tar --strip-components` does. Returns `None` if that
+/// leaves nothing.
+pub(crate) fn strip_components(name: &[u8], drive: bool, count: usize) -> Option<&[u8]> {
+    fn trim_separators(name: &[u8]) -> &[u8] {
+        let start = name
+            .iter()
+            .position(|c| !PATH_SEPARATORS.contains(c))
+            .unwrap_or(name.len());
+        &name[start..]
+    }
+
+    let mut rest = trim_separators(name);
+    if drive {
+        if let [letter, b':', after @ ..] = rest {
+            if letter.is_ascii_alphabetic()
+                && after.first().is_none_or(|c| PATH_SEPARATORS.contains(c))
+            {
+                rest = trim_separators(after);
+            }
+        }
+    }
+    for _ in 0..count {
+        // the last component is the file itself, which can't be stripped
+        let end = rest.iter().position(|c| PATH_SEPARATORS.contains(c))?;
+        rest = trim_separators(&rest[end..]);
+    }
+    Some(rest).filter(|rest| !rest.is_empty())
+}
+
+/// The name with the first of the rules in `map` that it starts with applied, or `None` if
+/// none is. Separators at the start of either are ignored, like they are when storing it.
+pub(crate) fn map(name: &[u8], map: &[PathMap]) -> Option<Vec<u8>> {
+    let trim = |name: &'_ [u8]| {
+        let start = name
+            .iter()
+            .position(|c| !PATH_SEPARATORS.contains(c))
+            .unwrap_or(name.len());
+        name[start..].to_vec()
+    };
+    let name = trim(name);
+    map.iter().find_map(|rule| {
+        let from = trim(&rule.from);
+        let rest = name.strip_prefix(&from[..])?;
+        // only whole directories match, so `a` doesn't take `ab/c` along with `a/c`
+        let whole = rest.is_empty()
+            || from.last().is_none_or(|c| PATH_SEPARATORS.contains(c))
+            || PATH_SEPARATORS.contains(&rest[0]);
+        if !whole {
+            return None;
+        }
+        let mut mapped = rule.to.clone();
+        mapped.extend(rest);
+        Some(mapped)
+    })
+}
+
+/// The name with ` (n)` added before the extension of its last component, like `a (2).txt`.
+pub(crate) fn numbered(name: &[u8], n: usize) -> Vec<u8> {
+    let start = name.iter().rposition(|&c| c == b'/').map_or(0, |i| i + 1);
+    // a dot at the start of the name is not an extension
+    let end = match name[start..].iter().rposition(|&c| c == b'.') {
+        Some(i) if i > 0 => start + i,
+        _ => name.len(),
+    };
+    let mut numbered = name[..end].to_vec();
+    numbered.extend(format!(" ({})", n).as_bytes());
+    numbered.extend(&name[end..]);
+    numbered
+}
+
+/// The name in the normalization `form`, borrowing it if it already is, or if it's not valid
+/// UTF-8 and can't be normalized.
+pub(crate) fn normalize(name: &[u8], form: Normalization) -> Cow<'_, [u8]> {
+    let text = match std::str::from_utf8(name) {
+        Ok(text) => text,
+        Err(_) => return Cow::Borrowed(name),
+    };
+    let normalized = match form {
+        Normalization::Nfc if !is_nfc(text) => text.nfc().collect::<String>(),
+        Normalization::Nfd if !is_nfd(text) => text.nfd().collect::<String>(),
+        _ => return Cow::Borrowed(name),
+    };
+    Cow::Owned(normalized.into_bytes())
+}
+
+/// Shows the name to humans, replacing what is not valid UTF-8.
+pub(crate) fn display(name: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(name)
+}
+
+#[cfg(not(windows))]
+fn to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(windows))]
+fn from_bytes(bytes: &[u8]) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes.to_vec())
+}
+
+#[cfg(windows)]
+fn to_bytes(path: &Path) -> Vec<u8> {
+    use std::os::windows::ffi::OsStrExt;
+    wide_to_bytes(path.as_os_str().encode_wide())
+}
+
+#[cfg(windows)]
+fn from_bytes(bytes: &[u8]) -> OsString {
+    use std::os::windows::ffi::OsStringExt;
+    OsString::from_wide(&bytes_to_wide(bytes))
+}
+
+// Encode the name as UTF-16, turning every byte which is not valid UTF-8 (all of which are
+// above 0x7F) into an unpaired surrogate.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn bytes_to_wide(bytes: &[u8]) -> Vec<u16> {
+    let mut wide = Vec::with_capacity(bytes.len());
+    let mut rest = bytes;
+    loop {
+        match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                wide.extend(valid.encode_utf16());
+                return wide;
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                wide.extend(std::str::from_utf8(valid).unwrap().encode_utf16());
+                let invalid_len = e.error_len().unwrap_or(invalid.len());
+                wide.extend(invalid[..invalid_len].iter().map(|&c| 0xdc00 | c as u16));
+                rest = &invalid[invalid_len..];
+            }
+        }
+    }
+}
+
+// The reverse of `bytes_to_wide`. Other unpaired surrogates can't come from bytes, so they
+// are kept as they would be encoded in UTF-8 if that were allowed.
+#[cfg_attr(not(windows), allow(dead_code))]
+fn wide_to_bytes(wide: impl IntoIterator<Item = u16>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for c in char::decode_utf16(wide) {
+        match c.map_err(|e| e.unpaired_surrogate()) {
+            Ok(c) => bytes.extend(c.encode_utf8(&mut [0; 4]).as_bytes()),
+            Err(s @ 0xdc80..=0xdcff) => bytes.push(s as u8),
+            Err(s) => bytes.extend([
+                0xe0 | (s >> 12) as u8,
+                0x80 | (s >> 6 & 0x3f) as u8,
+                0x80 | (s & 0x3f) as u8,
+            ]),
+        }
+    }
+    bytes
+}
+
+/// Escapes a single component of a path as described in the module documentation,
+/// borrowing it if it's fine as is.
+pub(crate) fn escape_windows(part: &[u8]) -> Cow<'_, [u8]> {
+    let stem_len = part.iter().position(|&c| c == b'.').unwrap_or(part.len());
+    let mut stem = &part[..stem_len];
+    while let [rest @ .., b' '] = stem {
+        stem = rest;
+    }
+    let device = is_device_name(stem);
+    // the escape character goes first, or escaped names could not be told from those sent so
+    let refused = |c: u8| c == b'%' || c < 0x20 || REFUSED_CHARS.contains(&c);
+    let bad_end = matches!(part.last(), Some(b'.') | Some(b' '));
+    if !device && !bad_end && !part.iter().copied().any(refused) {
+        return Cow::Borrowed(part);
+    }
+
+    let mut escaped = Vec::with_capacity(part.len() + 4);
+    for (i, &c) in part.iter().enumerate() {
+        if refused(c) || (bad_end && i == part.len() - 1) || (device && i == 0) {
+            escaped.extend(format!("%{:02X}", c).bytes());
+        } else {
+            escaped.push(c);
+        }
+    }
+    Cow::Owned(escaped)
+}
+
+fn is_device_name(stem: &[u8]) -> bool {
+    let upper = stem.to_ascii_uppercase();
+    DEVICE_NAMES.iter().any(|name| upper == name.as_bytes())
+        || NUMBERED_DEVICE_NAMES.iter().any(|name| {
+            upper.strip_prefix(name.as_bytes()).is_some_and(|n| {
+                // superscript digits (in UTF-8) count as well
+                matches!(
+                    n,
+                    [b'0'..=b'9'] | [0xc2, 0xb9] | [0xc2, 0xb2] | [0xc2, 0xb3]
+                )
+            })
+        })
+}
+
+/// Turns the path into an absolute one with the `\\?\` prefix, which lets Windows use paths
+/// longer than 260 characters. Every path built on top of it must only use backslashes,
+/// since the prefix also turns off the conversion of forward slashes.
+pub(crate) fn extended_length(path: &Path) -> io::Result<PathBuf> {
+    let absolute = std::path::absolute(path)?;
+    let text = match absolute.to_str() {
+        Some(text) if !text.starts_with(EXTENDED_PREFIX) => text,
+        // there's nothing to do, or no way to do it without mangling the path
+        _ => return Ok(absolute),
+    };
+    let mut extended = OsString::new();
+    match text.strip_prefix(r"\\") {
+        Some(unc) => {
+            extended.push(EXTENDED_UNC_PREFIX);
+            extended.push(unc);
+        }
+        None => {
+            extended.push(EXTENDED_PREFIX);
+            extended.push(text);
+        }
+    }
+    Ok(extended.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // The name sent, from the one stored, as the module documentation says it can be found.
+    fn unescape(escaped: &[u8]) -> Vec<u8> {
+        let mut name = Vec::new();
+        let mut rest = escaped;
+        while let Some((&c, after)) = rest.split_first() {
+            rest = after;
+            if let (b'%', [high, low, after @ ..]) = (c, rest) {
+                let hex = std::str::from_utf8(&[*high, *low]).unwrap().to_owned();
+                name.push(u8::from_str_radix(&hex, 16).unwrap());
+                rest = after;
+            } else {
+                name.push(c);
+            }
+        }
+        name
+    }
+
+    // Names Windows refuses are stored as ones it takes, which no other name is stored as,
+    // and those it takes are kept as they are unless they could be mistaken for escaped.
+    #[test]
+    fn escaped_names_round_trip() {
+        let names: [&[u8]; 17] = [
+            b"plain.txt",
+            b"a:b.txt",
+            b"a%3Ab.txt",
+            b"100%",
+            b"%",
+            b"aux",
+            b"aux.txt",
+            b"%61ux.txt",
+            b"AUX  .tar.gz",
+            b"com1.log",
+            "lpt\u{b9}".as_bytes(),
+            b"auxiliary.txt",
+            b"ends with a dot.",
+            b"ends with a space ",
+            b"who? <me> | \"you\" *",
+            b"tab\there\n",
+            b"latin-1 \xe9t\xe9",
+        ];
+        let mut stored = HashSet::new();
+        for name in names {
+            let escaped = escape_windows(name);
+            assert_eq!(unescape(&escaped), name, "{:?}", display(&escaped));
+            assert!(stored.insert(escaped.to_vec()), "{:?}", display(&escaped));
+            // and stored as is, as only the escape character in it is escaped again
+            let again: Vec<u8> = escaped
+                .iter()
+                .flat_map(|&c| if c == b'%' { b"%25".to_vec() } else { vec![c] })
+                .collect();
+            assert_eq!(escape_windows(&escaped), again);
+        }
+        assert!(matches!(escape_windows(b"plain.txt"), Cow::Borrowed(_)));
+        assert_eq!(&*escape_windows(b"aux.txt"), b"%61ux.txt");
+        assert_eq!(&*escape_windows(b"a%3Ab.txt"), b"a%253Ab.txt");
+    }
+
+    // Bytes that aren't valid UTF-8 survive the trip through the names of Windows.
+    #[test]
+    fn names_round_trip_through_utf16() {
+        let names: [&[u8]; 6] = [
+            b"plain",
+            "\u{fc}n\u{ef}c\u{f6}d\u{e9}".as_bytes(),
+            b"latin-1 \xe9t\xe9",
+            b"\xff\xfe",
+            b"cut short \xe2\x82",
+            b"\xed\xa0\x80 like a surrogate",
+        ];
+        for name in names {
+            assert_eq!(wide_to_bytes(bytes_to_wide(name)), name, "{:?}", name);
+        }
+    }
+
+    // The paths names are stored at are sent with those same names again, short of the roots
+    // and empty or `.` parts, which don't change where they are.
+    #[test]
+    fn stored_paths_round_trip() {
+        let names: [&[u8]; 3] = [
+            b"a/b/c.txt",
+            "\u{fc}n\u{ef}/c\u{f6}d\u{e9}".as_bytes(),
+            b"d/\xe9t\xe9",
+        ];
+        for name in names {
+            assert_eq!(wire_name(&relative_path(name).unwrap()), name);
+        }
+        assert_eq!(wire_name(&relative_path(b"/a/./b//c").unwrap()), b"a/b/c");
+        assert!(relative_path(b"a/../b").is_none());
+    }
+
+    #[test]
+    fn names_are_stripped_and_mapped() {
+        let strip = |name: &'static [u8], drive, count| strip_components(name, drive, count);
+        assert_eq!(strip(b"C:\\Users\\me\\x", true, 2), Some(&b"x"[..]));
+        assert_eq!(strip(b"D:/data/y", true, 0), Some(&b"data/y"[..]));
+        assert_eq!(strip(b"C:/data/y", false, 1), Some(&b"data/y"[..]));
+        assert_eq!(strip(b"//a//b", false, 1), Some(&b"b"[..]));
+        assert_eq!(strip(b"a/b", false, 2), None);
+        assert_eq!(strip(b"C:", true, 0), None);
+
+        let rules = [
+            PathMap {
+                from: b"/home/alice/".to_vec(),
+                to: b"projects/".to_vec(),
+            },
+            PathMap {
+                from: b"a".to_vec(),
+                to: b"b".to_vec(),
+            },
+        ];
+        assert_eq!(map(b"/home/alice/x", &rules), Some(b"projects/x".to_vec()));
+        assert_eq!(map(b"a/c", &rules), Some(b"b/c".to_vec()));
+        assert_eq!(map(b"a", &rules), Some(b"b".to_vec()));
+        assert_eq!(map(b"ab/c", &rules), None);
+        assert_eq!(map(b"home/alicex", &rules), None);
+    }
+}
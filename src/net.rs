@@ -0,0 +1,602 @@
+use std::fs::File;
+use std::future::Future;
+use std::io;
+use std::net::{Ipv4Addr, SocketAddr, TcpListener};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::TcpStream;
+use tokio::time::Sleep;
+
+// How much an in-memory connection holds before writing to it waits for the peer to read,
+// about as much as the buffers of the system hold for a socket.
+const MEMORY_CAPACITY: usize = 1024 * 1024;
+
+/// Tuning applied to every connection.
+#[derive(Clone, Copy)]
+pub struct SocketOptions {
+    /// How long the peer may stay unresponsive before giving up.
+    pub timeout: Option<Duration>,
+    /// Size of the kernel send buffer (`SO_SNDBUF`), or the system default.
+    pub send_buffer: Option<usize>,
+    /// Size of the kernel receive buffer (`SO_RCVBUF`), or the system default.
+    pub recv_buffer: Option<usize>,
+    /// Whether to disable Nagle's algorithm (`TCP_NODELAY`).
+    pub nodelay: bool,
+}
+
+impl Default for SocketOptions {
+    fn default() -> Self {
+        // as long as a person would wait before thinking something went wrong
+        SocketOptions {
+            timeout: Some(Duration::from_secs(30)),
+            send_buffer: None,
+            recv_buffer: None,
+            nodelay: false,
+        }
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::io;
+    pub use std::os::windows::io::AsRawSocket as AsSocket;
+    pub use winapi::shared::ws2def::{SOL_SOCKET, SO_KEEPALIVE, SO_RCVBUF, SO_SNDBUF};
+    use winapi::um::winsock2::{setsockopt, SOCKET};
+
+    pub fn set_option<S: AsSocket>(
+        socket: &S,
+        level: i32,
+        name: i32,
+        value: i32,
+    ) -> io::Result<()> {
+        let ret = unsafe {
+            setsockopt(
+                socket.as_raw_socket() as SOCKET,
+                level,
+                name,
+                &value as *const i32 as *const i8,
+                std::mem::size_of::<i32>() as i32,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(windows))]
+mod sys {
+    use std::io;
+    pub use std::os::unix::io::AsRawFd as AsSocket;
+
+    // socket.h
+    #[cfg(target_os = "linux")]
+    pub const SOL_SOCKET: i32 = 1;
+    #[cfg(target_os = "linux")]
+    pub const SO_SNDBUF: i32 = 7;
+    #[cfg(target_os = "linux")]
+    pub const SO_RCVBUF: i32 = 8;
+    #[cfg(target_os = "linux")]
+    pub const SO_KEEPALIVE: i32 = 9;
+    #[cfg(not(target_os = "linux"))]
+    pub const SOL_SOCKET: i32 = 0xffff;
+    #[cfg(not(target_os = "linux"))]
+    pub const SO_KEEPALIVE: i32 = 8;
+    #[cfg(not(target_os = "linux"))]
+    pub const SO_SNDBUF: i32 = 0x1001;
+    #[cfg(not(target_os = "linux"))]
+    pub const SO_RCVBUF: i32 = 0x1002;
+
+    // tcp(7)
+    #[cfg(target_os = "linux")]
+    pub const IPPROTO_TCP: i32 = 6;
+    #[cfg(target_os = "linux")]
+    pub const TCP_KEEPIDLE: i32 = 4;
+
+    extern "C" {
+        fn setsockopt(sockfd: i32, level: i32, optname: i32, optval: *const u8, optlen: u32)
+            -> i32;
+    }
+
+    pub fn set_option<S: AsSocket>(
+        socket: &S,
+        level: i32,
+        name: i32,
+        value: i32,
+    ) -> io::Result<()> {
+        let ret = unsafe {
+            setsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &value as *const i32 as *const u8,
+                std::mem::size_of::<i32>() as u32,
+            )
+        };
+        if ret != 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Enables TCP keepalive on the stream so that dead peers are eventually noticed by the OS.
+pub fn set_keepalive(stream: &TcpStream) -> io::Result<()> {
+    // how long a connection may stay idle before keepalive probes are sent
+    #[cfg(target_os = "linux")]
+    const KEEPALIVE_IDLE_SECS: i32 = 10;
+
+    sys::set_option(stream, sys::SOL_SOCKET, sys::SO_KEEPALIVE, 1)?;
+    // the default idle time before probing is two hours, which is not very useful here
+    #[cfg(target_os = "linux")]
+    sys::set_option(
+        stream,
+        sys::IPPROTO_TCP,
+        sys::TCP_KEEPIDLE,
+        KEEPALIVE_IDLE_SECS,
+    )?;
+    Ok(())
+}
+
+/// Sets the kernel buffer sizes of the socket, if any were configured.
+fn set_buffers<S: sys::AsSocket>(socket: &S, options: &SocketOptions) -> io::Result<()> {
+    let size = |n: usize| n.min(i32::MAX as usize) as i32;
+    if let Some(n) = options.send_buffer {
+        sys::set_option(socket, sys::SOL_SOCKET, sys::SO_SNDBUF, size(n))?;
+    }
+    if let Some(n) = options.recv_buffer {
+        sys::set_option(socket, sys::SOL_SOCKET, sys::SO_RCVBUF, size(n))?;
+    }
+    Ok(())
+}
+
+/// Applies the buffer sizes to the listener, so that accepted connections inherit them.
+/// The receive buffer can only grow the TCP window this way, before the connection exists.
+pub fn configure_listener(listener: &TcpListener, options: &SocketOptions) -> io::Result<()> {
+    set_buffers(listener, options)
+}
+
+/// A connected stream whose reads and writes fail with a descriptive error
+/// when the peer has not responded within the configured timeout.
+pub struct TimedStream {
+    stream: Connection,
+    timeout: Option<Duration>,
+    // when the read or the write that is waiting gives up, if one is
+    read_deadline: Option<Pin<Box<Sleep>>>,
+    write_deadline: Option<Pin<Box<Sleep>>>,
+    // the byte the peer sent that was looked at, but not read yet
+    peeked: Option<u8>,
+}
+
+enum Connection {
+    Tcp(TcpStream),
+    // both ends are in this process, see `TimedStream::pair`
+    Memory(DuplexStream),
+}
+
+impl TimedStream {
+    pub fn new(stream: TcpStream, options: &SocketOptions) -> io::Result<Self> {
+        set_keepalive(&stream)?;
+        set_buffers(&stream, options)?;
+        stream.set_nodelay(options.nodelay)?;
+        Ok(Self::with_timeout(Connection::Tcp(stream), options.timeout))
+    }
+
+    /// Both ends of a connection held in memory, without a socket, for a sender and a
+    /// receiver running in the same process. Only the timeout of the options applies.
+    pub fn pair(options: &SocketOptions) -> (Self, Self) {
+        let (a, b) = tokio::io::duplex(MEMORY_CAPACITY);
+        let end = |stream| Self::with_timeout(Connection::Memory(stream), options.timeout);
+        (end(a), end(b))
+    }
+
+    fn with_timeout(stream: Connection, timeout: Option<Duration>) -> Self {
+        Self {
+            stream,
+            timeout,
+            read_deadline: None,
+            write_deadline: None,
+            peeked: None,
+        }
+    }
+
+    /// The address of the peer, which is the loopback one with port zero for a connection
+    /// held in memory.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match &self.stream {
+            Connection::Tcp(stream) => stream.peer_addr(),
+            Connection::Memory(_) => Ok(SocketAddr::from((Ipv4Addr::LOCALHOST, 0))),
+        }
+    }
+
+    /// The next byte the peer sent, if there's one waiting to be read, checked without
+    /// waiting for it. It's still there to be read afterwards.
+    pub fn peek_pending(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_some() {
+            return Ok(self.peeked);
+        }
+        // polled once, with nobody to wake, since nothing waits for it
+        let waker = Waker::from(Arc::new(NoWake));
+        let mut cx = Context::from_waker(&waker);
+        let mut byte = [0u8];
+        let mut buf = ReadBuf::new(&mut byte);
+        match self.stream.poll_read(&mut cx, &mut buf) {
+            Poll::Ready(Ok(())) if buf.filled().len() == 1 => {
+                self.peeked = Some(byte[0]);
+                Ok(self.peeked)
+            }
+            Poll::Ready(Err(e)) => Err(e),
+            _ => Ok(None),
+        }
+    }
+
+    /// Closes the writing half of the connection, so that the peer sees it end after what
+    /// was written so far.
+    pub async fn shutdown_write(&mut self) -> io::Result<()> {
+        tokio::io::AsyncWriteExt::shutdown(self).await
+    }
+
+    /// Reads and throws away whatever the peer sends until it closes the connection, or for
+    /// at most `within`. Closing it with data left unread would reset it instead, and the
+    /// peer could lose what was written last.
+    pub async fn drain(&mut self, within: Duration) -> io::Result<()> {
+        let mut buffer = vec![0; 64 * 1024];
+        let drained = tokio::time::timeout(within, async {
+            loop {
+                match self.read(&mut buffer).await {
+                    Ok(0) => break Ok(()),
+                    Ok(_) => {}
+                    Err(e) if is_connection_error(&e) => break Ok(()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => break Err(e),
+                }
+            }
+        });
+        drained.await.unwrap_or(Ok(()))
+    }
+
+    /// Sends `len` bytes of the file starting at `offset` straight from the page cache,
+    /// without copying them through userspace, and returns how many were sent, which are
+    /// fewer only if the file shrunk. Returns `None` if the platform can't do this, in which
+    /// case nothing was sent. Failing to read the file is an error, but the inner result is
+    /// the outcome of using the connection, which may be recoverable.
+    #[cfg(target_os = "linux")]
+    pub async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Option<io::Result<u64>>> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+
+        // sendfile(2) transfers at most this much at once
+        const MAX_COUNT: u64 = 0x7fff_f000;
+
+        let Connection::Tcp(stream) = &self.stream else {
+            return Ok(None);
+        };
+        let (start, end) = (offset, offset + len);
+        let mut offset = offset as libc::off64_t;
+        while (offset as u64) < end {
+            let writable = match self.timeout {
+                Some(timeout) => tokio::time::timeout(timeout, stream.writable())
+                    .await
+                    .unwrap_or_else(|_| Err(io::ErrorKind::TimedOut.into())),
+                None => stream.writable().await,
+            };
+            if let Err(e) = writable {
+                return Ok(Some(Err(self.map_err(e))));
+            }
+            let count = (end - offset as u64).min(MAX_COUNT) as usize;
+            // a socket that turns out to be full is waited on again
+            let sent = stream.try_io(Interest::WRITABLE, || {
+                let (out_fd, in_fd) = (stream.as_raw_fd(), file.as_raw_fd());
+                let sent = unsafe { libc::sendfile64(out_fd, in_fd, &mut offset, count) };
+                if sent < 0 {
+                    Err(io::Error::last_os_error())
+                } else {
+                    Ok(sent)
+                }
+            });
+            match sent {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => match e.raw_os_error() {
+                    Some(libc::EINTR) => {}
+                    // the file or socket don't support it, but nothing was sent yet
+                    Some(libc::EINVAL) | Some(libc::ENOSYS) if offset as u64 == start => {
+                        return Ok(None)
+                    }
+                    _ if is_connection_error(&e) => return Ok(Some(Err(self.map_err(e)))),
+                    _ => return Err(e),
+                },
+            }
+        }
+        Ok(Some(Ok(offset as u64 - start)))
+    }
+
+    /// Sends `len` bytes of the file starting at `offset` straight from the file cache, without
+    /// copying them through userspace, and returns how many were sent, which are fewer only if
+    /// the file shrunk. Returns `None` if the socket can't do this, in which case nothing was
+    /// sent. Failing to read the file is an error, but the inner result is the outcome of using
+    /// the connection, which may be recoverable.
+    #[cfg(windows)]
+    pub async fn send_file(
+        &mut self,
+        file: &File,
+        offset: u64,
+        len: u64,
+    ) -> io::Result<Option<io::Result<u64>>> {
+        use std::os::windows::io::{AsRawHandle, AsRawSocket};
+        use winapi::shared::winerror::{ERROR_INVALID_FUNCTION, ERROR_NOT_SUPPORTED};
+        use winapi::shared::winerror::{WSAEINVAL, WSAEOPNOTSUPP};
+
+        let Connection::Tcp(stream) = &self.stream else {
+            return Ok(None);
+        };
+        // `TransmitFile` waits for the data to be sent, so it's done on a thread meant for
+        // blocking, which is always waited for so that the socket and the file outlive it
+        let (socket, handle) = (stream.as_raw_socket(), file.as_raw_handle() as usize);
+        let timeout = self.timeout;
+        let transmitting = tokio::task::spawn_blocking(move || {
+            transmit_file(socket, handle, (offset, len), timeout)
+        });
+        let (sent, result) = crate::task::joined(transmitting).await;
+        let unsupported = [
+            ERROR_INVALID_FUNCTION,
+            ERROR_NOT_SUPPORTED,
+            WSAEINVAL,
+            WSAEOPNOTSUPP,
+        ];
+        match result {
+            Ok(()) => Ok(Some(Ok(sent))),
+            Err(e)
+                if sent == 0 && unsupported.contains(&(e.raw_os_error().unwrap_or(0) as u32)) =>
+            {
+                Ok(None)
+            }
+            Err(e) if is_connection_error(&e) => Ok(Some(Err(self.map_err(e)))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends `len` bytes of the file starting at `offset` straight from the page cache,
+    /// without copying them through userspace. Returns `None` if the platform can't do
+    /// this, in which case nothing was sent.
+    #[cfg(not(any(target_os = "linux", windows)))]
+    pub async fn send_file(
+        &mut self,
+        _file: &File,
+        _offset: u64,
+        _len: u64,
+    ) -> io::Result<Option<io::Result<u64>>> {
+        Ok(None)
+    }
+
+    fn map_err(&self, e: io::Error) -> io::Error {
+        map_err(self.timeout, e)
+    }
+}
+
+fn map_err(timeout: Option<Duration>, e: io::Error) -> io::Error {
+    match (e.kind(), timeout) {
+        (io::ErrorKind::WouldBlock, Some(t)) | (io::ErrorKind::TimedOut, Some(t)) => {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("peer unresponsive for {}s, aborting", t.as_secs()),
+            )
+        }
+        _ => e,
+    }
+}
+
+// Send `len` bytes of the file with the `handle`, starting at `offset`, through the `socket`,
+// waiting for at most the `timeout` for every part to be sent. Returns how many were sent,
+// which are fewer only if the file shrunk, along with what stopped it if it failed.
+#[cfg(windows)]
+fn transmit_file(
+    socket: std::os::windows::io::RawSocket,
+    handle: usize,
+    (offset, len): (u64, u64),
+    timeout: Option<Duration>,
+) -> (u64, io::Result<()>) {
+    use std::ptr;
+    use winapi::shared::minwindef::{DWORD, FALSE, TRUE};
+    use winapi::shared::winerror::{ERROR_IO_PENDING, ERROR_OPERATION_ABORTED, WAIT_TIMEOUT};
+    use winapi::um::handleapi::CloseHandle;
+    use winapi::um::ioapiset::CancelIoEx;
+    use winapi::um::minwinbase::OVERLAPPED;
+    use winapi::um::mswsock::TransmitFile;
+    use winapi::um::synchapi::{CreateEventW, WaitForSingleObject};
+    use winapi::um::winbase::INFINITE;
+    use winapi::um::winnt::HANDLE;
+    use winapi::um::winsock2::{WSAGetLastError, WSAGetOverlappedResult, SOCKET};
+
+    // small enough that a part not sent within the timeout means the peer is unresponsive
+    const PART_LEN: u64 = 1024 * 1024;
+
+    let event = unsafe { CreateEventW(ptr::null_mut(), TRUE, FALSE, ptr::null()) };
+    if event.is_null() {
+        return (0, Err(io::Error::last_os_error()));
+    }
+    let wait = timeout.map_or(INFINITE, |t| {
+        t.as_millis().min(INFINITE as u128 - 1) as DWORD
+    });
+    let mut sent = 0;
+    let result = loop {
+        if sent == len {
+            break Ok(());
+        }
+        let position = offset + sent;
+        let mut overlapped: OVERLAPPED = unsafe { std::mem::zeroed() };
+        unsafe {
+            let at = overlapped.u.s_mut();
+            at.Offset = position as DWORD;
+            at.OffsetHigh = (position >> 32) as DWORD;
+        }
+        // with the lowest bit set, finishing isn't also told to the completion port of the
+        // runtime, were the socket associated with one
+        overlapped.hEvent = (event as usize | 1) as HANDLE;
+        let count = (len - sent).min(PART_LEN) as DWORD;
+        let file = handle as HANDLE;
+        let done = unsafe {
+            TransmitFile(
+                socket as SOCKET,
+                file,
+                count,
+                0,
+                &mut overlapped,
+                ptr::null_mut(),
+                0,
+            )
+        };
+        if done == FALSE {
+            let e = unsafe { WSAGetLastError() };
+            if e as u32 != ERROR_IO_PENDING {
+                break Err(io::Error::from_raw_os_error(e));
+            }
+        }
+        if unsafe { WaitForSingleObject(event, wait) } == WAIT_TIMEOUT {
+            // it must be over before the buffers it uses go away
+            unsafe { CancelIoEx(socket as HANDLE, &mut overlapped) };
+        }
+        let (mut part, mut flags) = (0, 0);
+        let finished = unsafe {
+            WSAGetOverlappedResult(
+                socket as SOCKET,
+                &mut overlapped,
+                &mut part,
+                TRUE,
+                &mut flags,
+            )
+        };
+        if finished == FALSE {
+            let e = io::Error::from_raw_os_error(unsafe { WSAGetLastError() });
+            // cancelled because it took too long
+            break Err(match e.raw_os_error() {
+                Some(code) if code as u32 == ERROR_OPERATION_ABORTED => {
+                    io::ErrorKind::TimedOut.into()
+                }
+                _ => e,
+            });
+        }
+        if part == 0 {
+            break Ok(());
+        }
+        sent += u64::from(part);
+    };
+    unsafe { CloseHandle(event) };
+    (sent, result)
+}
+
+fn is_connection_error(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::BrokenPipe
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::WouldBlock
+    )
+}
+
+// Waits for what was `polled` for as long as the peer may stay unresponsive, which starts over
+// every time it's ready, and fails once it's been waited on for longer than that.
+fn poll_timed<T>(
+    polled: Poll<io::Result<T>>,
+    deadline: &mut Option<Pin<Box<Sleep>>>,
+    timeout: Option<Duration>,
+    cx: &mut Context<'_>,
+) -> Poll<io::Result<T>> {
+    let timeout = match (polled, timeout) {
+        (Poll::Ready(result), _) => {
+            *deadline = None;
+            return Poll::Ready(result.map_err(|e| map_err(timeout, e)));
+        }
+        (Poll::Pending, None) => return Poll::Pending,
+        (Poll::Pending, Some(timeout)) => timeout,
+    };
+    let sleep = deadline.get_or_insert_with(|| Box::pin(tokio::time::sleep(timeout)));
+    match sleep.as_mut().poll(cx) {
+        Poll::Ready(()) => {
+            *deadline = None;
+            Poll::Ready(Err(map_err(Some(timeout), io::ErrorKind::TimedOut.into())))
+        }
+        Poll::Pending => Poll::Pending,
+    }
+}
+
+impl Connection {
+    fn poll_read(&mut self, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncRead for TimedStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if buf.remaining() != 0 {
+            if let Some(byte) = this.peeked.take() {
+                buf.put_slice(&[byte]);
+                return Poll::Ready(Ok(()));
+            }
+        }
+        let polled = this.stream.poll_read(cx, buf);
+        poll_timed(polled, &mut this.read_deadline, this.timeout, cx)
+    }
+}
+
+impl AsyncWrite for TimedStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let polled = match &mut this.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+        };
+        poll_timed(polled, &mut this.write_deadline, this.timeout, cx)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let polled = match &mut this.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Memory(stream) => Pin::new(stream).poll_flush(cx),
+        };
+        poll_timed(polled, &mut this.write_deadline, this.timeout, cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let polled = match &mut this.stream {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+        };
+        poll_timed(polled, &mut this.write_deadline, this.timeout, cx)
+    }
+}
+
+// Wakes nobody, for what is only polled once.
+struct NoWake;
+
+impl Wake for NoWake {
+    fn wake(self: Arc<Self>) {}
+}
@@ -0,0 +1,110 @@
+//! Desktop notifications for when a transfer is over.
+
+use crate::stats::Stats;
+use sf::{format_bytes, format_duration};
+use std::process::{Command, Stdio};
+
+/// Shows a notification saying how the transfer went. `done` describes what the transfer
+/// does once it succeeds, such as "sent".
+pub fn notify(done: &str, stats: &Stats, result: &sf::Result<()>) {
+    let elapsed = format_duration(stats.elapsed);
+    let (title, body) = match result {
+        Ok(()) if stats.files == 0 => ("sf: done", format!("{} in {}", done, elapsed)),
+        Ok(()) => (
+            "sf: done",
+            format!(
+                "{} {} files ({}) in {}",
+                done,
+                stats.files,
+                format_bytes(stats.bytes),
+                elapsed
+            ),
+        ),
+        Err(e) => (
+            "sf: failed",
+            format!(
+                "after {} and {} files ({}): {}",
+                elapsed,
+                stats.files,
+                format_bytes(stats.bytes),
+                e
+            ),
+        ),
+    };
+    if !show(title, &body) {
+        eprintln!("could not show a desktop notification");
+    }
+}
+
+// Show a notification through whatever the desktop offers, returning whether it worked.
+#[cfg(windows)]
+fn show(title: &str, body: &str) -> bool {
+    // toasts need an app id, and the one of PowerShell is always registered
+    const SCRIPT: &str = r#"
+        $null = [Windows.UI.Notifications.ToastNotificationManager, Windows.UI.Notifications, ContentType = WindowsRuntime]
+        $xml = [Windows.UI.Notifications.ToastNotificationManager]::GetTemplateContent([Windows.UI.Notifications.ToastTemplateType]::ToastText02)
+        $text = $xml.GetElementsByTagName('text')
+        $null = $text.Item(0).AppendChild($xml.CreateTextNode($env:SF_TITLE))
+        $null = $text.Item(1).AppendChild($xml.CreateTextNode($env:SF_BODY))
+        $toast = [Windows.UI.Notifications.ToastNotification]::new($xml)
+        $id = '{1AC14E77-02E7-4E5D-B744-2EB1AE5198B7}\WindowsPowerShell\v1.0\powershell.exe'
+        [Windows.UI.Notifications.ToastNotificationManager]::CreateToastNotifier($id).Show($toast)
+    "#;
+    // passed through the environment so that nothing in them needs escaping
+    run(Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", SCRIPT])
+        .env("SF_TITLE", title)
+        .env("SF_BODY", body))
+}
+
+#[cfg(target_os = "macos")]
+fn show(title: &str, body: &str) -> bool {
+    const SCRIPT: &str = "on run argv
+        display notification (item 2 of argv) with title (item 1 of argv)
+    end run";
+    run(Command::new("osascript").args(["-e", SCRIPT, title, body]))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn show(title: &str, body: &str) -> bool {
+    // notify-send is not always installed, but the D-Bus service behind it usually is
+    run(Command::new("notify-send").args(["--app-name=sf", title, body]))
+        || run(Command::new("gdbus").args([
+            "call",
+            "--session",
+            "--dest=org.freedesktop.Notifications",
+            "--object-path=/org/freedesktop/Notifications",
+            "--method=org.freedesktop.Notifications.Notify",
+            "sf",
+            "0",
+            "",
+            &gvariant_string(title),
+            &gvariant_string(body),
+            "[]",
+            "{}",
+            "-1",
+        ]))
+}
+
+// Quote the text as a GVariant string, which is how gdbus wants its arguments.
+#[cfg(not(any(windows, target_os = "macos")))]
+fn gvariant_string(text: &str) -> String {
+    let mut quoted = String::from("'");
+    for c in text.chars() {
+        if c == '\'' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('\'');
+    quoted
+}
+
+fn run(command: &mut Command) -> bool {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
@@ -0,0 +1,104 @@
+//! Opening what was received, for files that are meant to be looked at right away.
+
+use sf::{EventHandler, TransferEvent};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+
+#[derive(Default)]
+struct Received {
+    // where the files of the transfer are, if not in the output directory itself
+    output: Option<PathBuf>,
+    started: HashMap<usize, PathBuf>,
+    done: Vec<PathBuf>,
+}
+
+/// Follows where the received files are stored, to open them once the transfer is over.
+pub struct Opener {
+    received: Arc<Mutex<Received>>,
+    output: PathBuf,
+}
+
+impl Opener {
+    /// Starts following the events of the transfer, which still reach the existing handler.
+    pub fn new(events: &mut Option<EventHandler>, output: PathBuf) -> Self {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let previous = events.take();
+        *events = Some({
+            let received = Arc::clone(&received);
+            Box::new(move |event| {
+                {
+                    let mut received = received.lock().unwrap();
+                    match &event {
+                        TransferEvent::Storing { output } => received.output = Some(output.clone()),
+                        TransferEvent::FileStarted { index, path, .. } => {
+                            received.started.insert(*index, path.clone());
+                        }
+                        TransferEvent::FileDone { index } => {
+                            if let Some(path) = received.started.remove(index) {
+                                received.done.push(path);
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some(previous) = &previous {
+                    previous(event);
+                }
+            })
+        });
+        Opener { received, output }
+    }
+
+    /// Opens the file with whatever handles its type if it was the only one received, or
+    /// the directory they were stored in if there were more. Nothing is opened if the
+    /// transfer failed.
+    pub fn finish(self, result: &sf::Result<()>) {
+        if result.is_err() {
+            return;
+        }
+        let received = self.received.lock().unwrap();
+        let path = match &received.done[..] {
+            [] => return,
+            [path] => path.as_path(),
+            _ => received.output.as_deref().unwrap_or(&self.output),
+        };
+        if !open(path) {
+            eprintln!("could not open {:?}", path);
+        }
+    }
+}
+
+// Open the path like double-clicking it would, returning whether it worked.
+#[cfg(windows)]
+fn open(path: &Path) -> bool {
+    // explorer hands files to their program, and shows directories, but exits with 1 anyway
+    Command::new("explorer")
+        .arg(path)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok()
+}
+
+#[cfg(target_os = "macos")]
+fn open(path: &Path) -> bool {
+    run(Command::new("open").arg(path))
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+fn open(path: &Path) -> bool {
+    run(Command::new("xdg-open").arg(path))
+}
+
+#[cfg(not(windows))]
+fn run(command: &mut Command) -> bool {
+    command
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
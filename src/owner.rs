@@ -0,0 +1,142 @@
+//! Who the received files belong to, for receivers that run as root and store them where
+//! someone else should own them.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+/// The user and group to give the received files, and the directories made for them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Owner {
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl Owner {
+    /// Parses `user`, `user:group` or `:group`, where each is a name known to the system or
+    /// a number.
+    pub fn parse(text: &str) -> io::Result<Owner> {
+        if cfg!(not(unix)) {
+            return Err(unsupported());
+        }
+        let (user, group) = match text.split_once(':') {
+            Some((user, group)) => (user, Some(group)),
+            None => (text, None),
+        };
+        let owner = Owner {
+            uid: match user {
+                "" => None,
+                user => Some(user.parse().or_else(|_| lookup_user(user))?),
+            },
+            gid: match group {
+                None | Some("") => None,
+                Some(group) => Some(group.parse().or_else(|_| lookup_group(group))?),
+            },
+        };
+        if owner.uid.is_none() && owner.gid.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "neither a user nor a group was given",
+            ));
+        }
+        Ok(owner)
+    }
+
+    // Give the open file to its owner, before it's renamed to its final name.
+    pub(crate) fn apply_to_file(&self, file: &File) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::fchown(file, self.uid, self.gid);
+        #[cfg(not(unix))]
+        {
+            let _ = file;
+            Ok(())
+        }
+    }
+
+    // Give the directory at `path` to its owner.
+    pub(crate) fn apply(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        return std::os::unix::fs::chown(path, self.uid, self.gid);
+        #[cfg(not(unix))]
+        {
+            let _ = path;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(unix)]
+fn lookup_user(name: &str) -> io::Result<u32> {
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+
+    // only the fields up to the ids, which come first everywhere
+    #[repr(C)]
+    struct Passwd {
+        pw_name: *mut c_char,
+        pw_passwd: *mut c_char,
+        pw_uid: u32,
+        pw_gid: u32,
+    }
+
+    extern "C" {
+        fn getpwnam(name: *const c_char) -> *mut Passwd;
+    }
+
+    let c_name = CString::new(name).map_err(|_| unknown("user", name))?;
+    // looked up once while parsing the arguments, before any thread could race with it
+    let entry = unsafe { getpwnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(unknown("user", name));
+    }
+    Ok(unsafe { (*entry).pw_uid })
+}
+
+#[cfg(unix)]
+fn lookup_group(name: &str) -> io::Result<u32> {
+    use std::ffi::CString;
+    use std::os::raw::c_char;
+
+    #[repr(C)]
+    struct Group {
+        gr_name: *mut c_char,
+        gr_passwd: *mut c_char,
+        gr_gid: u32,
+    }
+
+    extern "C" {
+        fn getgrnam(name: *const c_char) -> *mut Group;
+    }
+
+    let c_name = CString::new(name).map_err(|_| unknown("group", name))?;
+    let entry = unsafe { getgrnam(c_name.as_ptr()) };
+    if entry.is_null() {
+        return Err(unknown("group", name));
+    }
+    Ok(unsafe { (*entry).gr_gid })
+}
+
+#[cfg(not(unix))]
+fn lookup_user(_name: &str) -> io::Result<u32> {
+    Err(unsupported())
+}
+
+#[cfg(not(unix))]
+fn lookup_group(_name: &str) -> io::Result<u32> {
+    Err(unsupported())
+}
+
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "files can only be given an owner on unix",
+    )
+}
+
+#[cfg(unix)]
+fn unknown(what: &str, name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("there is no {} named {:?}", what, name),
+    )
+}
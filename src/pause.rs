@@ -0,0 +1,58 @@
+use crate::event::emit;
+use crate::{cancel, CancelToken, EventHandler, TransferEvent};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// How often a paused transfer checks whether to go on
+const PAUSE_DELAY: Duration = Duration::from_millis(100);
+
+/// Lets a receiver stop reading for a while, such as when the disk is busy with something
+/// else, without losing the connection.
+///
+/// The transfer notices in between chunks, and the sender simply waits for it to go on.
+/// Pausing for longer than the sender's timeout makes it reconnect once resumed.
+#[derive(Clone, Debug, Default)]
+pub struct PauseToken(Arc<AtomicBool>);
+
+impl PauseToken {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn resume(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    /// Pauses if it was going, or resumes if it was paused. Returns whether it's now paused.
+    pub fn toggle(&self) -> bool {
+        !self.0.fetch_xor(true, Ordering::Relaxed)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Wait for as long as the transfer is paused, unless it's cancelled meanwhile.
+pub(crate) async fn wait(
+    token: &Option<PauseToken>,
+    cancel: &Option<CancelToken>,
+    events: &Option<EventHandler>,
+) {
+    let token = match token {
+        Some(token) if token.is_paused() => token,
+        _ => return,
+    };
+    out!("paused, the sender waits until the transfer is resumed");
+    emit(events, TransferEvent::Paused);
+    while token.is_paused() && !cancel::is_cancelled(cancel) {
+        tokio::time::sleep(PAUSE_DELAY).await;
+    }
+    out!("resumed");
+    emit(events, TransferEvent::Resumed);
+}
@@ -0,0 +1,272 @@
+use crate::task;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+/// How many buffers can be in flight at once (per thread, in a pool).
+const BUFFER_COUNT: usize = 2;
+
+/// Reads chunks with `read` on a thread meant for blocking, ahead of whoever awaits them, so
+/// that waiting on one side (e.g. the disk) doesn't stall the other (e.g. the network).
+///
+/// Only a few chunks are read ahead, so every chunk taken should be given back once it's no
+/// longer needed, to be read into again.
+pub struct ReadAhead<S> {
+    full: UnboundedReceiver<Vec<u8>>,
+    empty: UnboundedSender<Vec<u8>>,
+    reader: tokio::task::JoinHandle<(S, io::Result<()>)>,
+}
+
+impl<S: Send + 'static> ReadAhead<S> {
+    /// Starts reading chunks of up to `chunk_size` bytes, with `read` returning how much it
+    /// read into each, until it returns zero bytes or fails. The `state` is what it reads
+    /// from, and is given back once finished.
+    pub fn start<R>(chunk_size: usize, mut state: S, mut read: R) -> Self
+    where
+        R: FnMut(&mut S, &mut [u8]) -> io::Result<usize> + Send + 'static,
+    {
+        let (full_tx, full) = unbounded_channel();
+        let (empty, mut empty_rx) = unbounded_channel::<Vec<u8>>();
+        for _ in 0..BUFFER_COUNT {
+            empty.send(Vec::new()).unwrap();
+        }
+        let reader = tokio::task::spawn_blocking(move || {
+            // once the chunks stop being taken, no more empty buffers come back
+            while let Some(mut buffer) = empty_rx.blocking_recv() {
+                buffer.resize(chunk_size, 0);
+                match read(&mut state, &mut buffer) {
+                    Ok(0) => break,
+                    Ok(n) => buffer.truncate(n),
+                    Err(e) => return (state, Err(e)),
+                }
+                if full_tx.send(buffer).is_err() {
+                    break;
+                }
+            }
+            (state, Ok(()))
+        });
+        Self {
+            full,
+            empty,
+            reader,
+        }
+    }
+
+    /// Like `start`, but every item `read` is turned into what makes a chunk with `work`, on a
+    /// pool of threads as with [`ordered_pool`], for when that takes longer than reading. What
+    /// `work` comes to is handed to `done` in order, along with the state, to make the chunk.
+    pub fn start_pooled<I, O, R, F, D>(state: S, mut read: R, work: F, mut done: D) -> Self
+    where
+        I: Send,
+        O: Send,
+        R: FnMut(&mut S) -> io::Result<Option<I>> + Send + 'static,
+        F: Fn(I) -> O + Send + Sync + 'static,
+        D: FnMut(&mut S, O) -> Vec<u8> + Send + 'static,
+    {
+        let (full_tx, full) = unbounded_channel();
+        let (empty, mut empty_rx) = unbounded_channel::<Vec<u8>>();
+        for _ in 0..BUFFER_COUNT {
+            empty.send(Vec::new()).unwrap();
+        }
+        let reader = tokio::task::spawn_blocking(move || {
+            let state = Mutex::new(state);
+            let mut read_failed = Ok(());
+            // it only stops early once the chunks stop being taken, which is no failure
+            let _ = ordered_pool(
+                || match read(&mut state.lock().unwrap()) {
+                    Ok(item) => item,
+                    Err(e) => {
+                        read_failed = Err(e);
+                        None
+                    }
+                },
+                work,
+                |out| {
+                    // no more chunks are made than those given back, which are made anew
+                    empty_rx.blocking_recv().ok_or(())?;
+                    let chunk = done(&mut state.lock().unwrap(), out);
+                    full_tx.send(chunk).map_err(drop)
+                },
+            );
+            (state.into_inner().unwrap(), read_failed)
+        });
+        Self {
+            full,
+            empty,
+            reader,
+        }
+    }
+
+    /// The next chunk read, or `None` once there are no more.
+    pub async fn next(&mut self) -> Option<Vec<u8>> {
+        self.full.recv().await
+    }
+
+    /// Gives back a chunk that was taken, to read the ones after it into.
+    pub fn give_back(&self, chunk: Vec<u8>) {
+        let _ = self.empty.send(chunk);
+    }
+
+    /// Stops reading, even if there was more, and returns the state along with the outcome
+    /// of reading.
+    pub async fn finish(self) -> (S, io::Result<()>) {
+        let Self {
+            full,
+            empty,
+            reader,
+        } = self;
+        drop((full, empty));
+        task::joined(reader).await
+    }
+}
+
+/// Writes chunks with `write` on a thread meant for blocking, behind whoever hands them over,
+/// so that waiting on one side (e.g. the network) doesn't stall the other (e.g. the disk).
+///
+/// Only a few chunks are waiting to be written at once, and more can only be handed over once
+/// those were. They're written in the order they were handed over.
+pub struct WriteBehind<S> {
+    chunk_size: usize,
+    full: UnboundedSender<Vec<u8>>,
+    empty: UnboundedReceiver<(Vec<u8>, usize)>,
+    writer: tokio::task::JoinHandle<(S, io::Result<()>)>,
+}
+
+impl<S: Send + 'static> WriteBehind<S> {
+    /// Starts writing the chunks of up to `chunk_size` bytes handed over with `write`, which
+    /// returns how much it stored of each, until it fails. The `state` is what it writes into,
+    /// and is given back once finished.
+    pub fn start<W>(chunk_size: usize, mut state: S, mut write: W) -> Self
+    where
+        W: FnMut(&mut S, &[u8]) -> io::Result<usize> + Send + 'static,
+    {
+        let (full, mut full_rx) = unbounded_channel::<Vec<u8>>();
+        let (empty_tx, empty) = unbounded_channel();
+        for _ in 0..BUFFER_COUNT {
+            empty_tx.send((Vec::new(), 0)).unwrap();
+        }
+        let writer = tokio::task::spawn_blocking(move || {
+            while let Some(buffer) = full_rx.blocking_recv() {
+                match write(&mut state, &buffer) {
+                    Ok(stored) => {
+                        let _ = empty_tx.send((buffer, stored));
+                    }
+                    Err(e) => return (state, Err(e)),
+                }
+            }
+            (state, Ok(()))
+        });
+        Self {
+            chunk_size,
+            full,
+            empty,
+            writer,
+        }
+    }
+
+    /// A chunk to fill with data and hand over, once one of those handed over before was
+    /// written, along with how much that one stored, or `None` if writing failed.
+    pub async fn chunk(&mut self) -> Option<(Vec<u8>, usize)> {
+        if self.full.is_closed() {
+            return None;
+        }
+        let (mut chunk, stored) = self.empty.recv().await?;
+        chunk.resize(self.chunk_size, 0);
+        Some((chunk, stored))
+    }
+
+    /// Hands over the chunk to be written, cut down to the data in it.
+    pub fn write(&self, chunk: Vec<u8>) {
+        let _ = self.full.send(chunk);
+    }
+
+    /// Waits for every chunk handed over to be written, and returns the state along with the
+    /// outcome of writing, which is how much was stored by those not taken back as chunks.
+    pub async fn finish(self) -> (S, io::Result<usize>) {
+        let Self {
+            full,
+            mut empty,
+            writer,
+            ..
+        } = self;
+        drop(full);
+        let (state, written) = task::joined(writer).await;
+        let mut stored = 0;
+        while let Ok((_, len)) = empty.try_recv() {
+            stored += len;
+        }
+        (state, written.map(|()| stored))
+    }
+}
+
+/// Runs `work` on every item produced by `next` using a pool of threads sized to the available
+/// cores, and hands the results to `done` in the same order the items were produced.
+///
+/// Only a few items per thread can be in flight at once, so a slow `done` (e.g. the network)
+/// holds back `next` (e.g. the disk) instead of letting results pile up in memory. Stops at
+/// the first error returned by `done`.
+pub fn ordered_pool<I, O, E, N, F, D>(mut next: N, work: F, mut done: D) -> Result<(), E>
+where
+    I: Send,
+    O: Send,
+    N: FnMut() -> Option<I> + Send,
+    F: Fn(I) -> O + Sync,
+    D: FnMut(O) -> Result<(), E>,
+{
+    let workers = thread::available_parallelism().map_or(1, |n| n.get());
+    let (token_tx, token_rx) = mpsc::channel::<()>();
+    let (work_tx, work_rx) = mpsc::channel::<(usize, I)>();
+    let (result_tx, result_rx) = mpsc::channel::<(usize, O)>();
+    let work_rx = Mutex::new(work_rx);
+    for _ in 0..workers * BUFFER_COUNT {
+        token_tx.send(()).unwrap();
+    }
+
+    thread::scope(|scope| {
+        scope.spawn(move || {
+            // once the results stop being collected, no more tokens come back
+            for index in 0.. {
+                if token_rx.recv().is_err() {
+                    break;
+                }
+                let sent = next().map(|item| work_tx.send((index, item)).is_ok());
+                if sent != Some(true) {
+                    break;
+                }
+            }
+        });
+        for _ in 0..workers {
+            let result_tx = result_tx.clone();
+            let (work_rx, work) = (&work_rx, &work);
+            scope.spawn(move || loop {
+                // the lock must be released as soon as a job is taken, not after working on it
+                let job = work_rx.lock().unwrap().recv();
+                let (index, item) = match job {
+                    Ok(job) => job,
+                    Err(_) => break,
+                };
+                if result_tx.send((index, work(item))).is_err() {
+                    break;
+                }
+            });
+        }
+        drop(result_tx);
+        // owned here so that returning early stops the feeder too
+        let token_tx = token_tx;
+
+        // results arrive as soon as they're ready, so they need to be put back in order
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (index, result) in result_rx.iter() {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&expected) {
+                done(result)?;
+                expected += 1;
+                let _ = token_tx.send(());
+            }
+        }
+        Ok(())
+    })
+}
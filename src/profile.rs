@@ -0,0 +1,195 @@
+//! Named sets of options kept in the configuration file, so that those used together again
+//! and again can be given at once with `--profile NAME`.
+//!
+//! The file is written in a small part of TOML, with a `[profile.NAME]` section for every
+//! profile, and a line for every option in it, named as the long flag without its dashes:
+//!
+//! ```toml
+//! [profile.photos]
+//! output = "/home/me/Pictures/incoming"
+//! accept-ext = "jpg,png,heic"
+//! session-dirs = true
+//! notify = true
+//! ```
+//!
+//! Options that take a value are given it as a string or a number, and those that can be
+//! given many times can be given an array. Options that take none are given `true`, or
+//! `false` to leave them out.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const SECTION_PREFIX: &str = "profile.";
+
+/// Where the configuration file is kept, in the directory for the configuration of the
+/// programs of the user.
+pub fn default_path() -> Option<PathBuf> {
+    let dir = if cfg!(windows) {
+        PathBuf::from(env::var_os("APPDATA")?)
+    } else if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        PathBuf::from(dir)
+    } else {
+        Path::new(&env::var_os("HOME")?).join(".config")
+    };
+    Some(dir.join("sf").join("config.toml"))
+}
+
+/// Reads the options of the profile `name` from the configuration file at `path`, as they
+/// would be written on the command line.
+pub fn load(path: &Path, name: &str) -> Result<Vec<String>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {:?}: {}", path, e))?;
+    let mut found = false;
+    // whether the lines read belong to the profile wanted
+    let mut wanted = false;
+    let mut args = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let invalid = |reason: &str| format!("invalid line {} of {:?}: {}", i + 1, path, reason);
+        let line = strip_comment(line).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if let Some(section) = line.strip_prefix('[') {
+            let section = section
+                .strip_suffix(']')
+                .ok_or_else(|| invalid("the section is not closed"))?
+                .trim();
+            let profile = section
+                .strip_prefix(SECTION_PREFIX)
+                .ok_or_else(|| invalid("only [profile.NAME] sections are known"))?;
+            wanted = profile == name;
+            found |= wanted;
+            continue;
+        }
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| invalid("expected an option = value"))?;
+        let key = key.trim();
+        if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(invalid(
+                "the option must be named like the long flag, without dashes",
+            ));
+        }
+        if key == "profile" {
+            return Err(invalid("profiles cannot include other profiles"));
+        }
+        let values = parse_value(value.trim()).map_err(|reason| invalid(&reason))?;
+        if !wanted {
+            continue;
+        }
+        let flag = if key.len() == 1 {
+            format!("-{}", key)
+        } else {
+            format!("--{}", key)
+        };
+        for value in values {
+            match value {
+                Value::Flag(true) => args.push(flag.clone()),
+                Value::Flag(false) => {}
+                Value::Text(text) => args.extend([flag.clone(), text]),
+            }
+        }
+    }
+    if !found {
+        return Err(format!(
+            "there is no profile named {:?} in {:?}",
+            name, path
+        ));
+    }
+    Ok(args)
+}
+
+enum Value {
+    Flag(bool),
+    Text(String),
+}
+
+// Parse the value of an option, which may be an array of them.
+fn parse_value(text: &str) -> Result<Vec<Value>, String> {
+    let Some(inner) = text.strip_prefix('[') else {
+        let (value, rest) = parse_single(text)?;
+        if !rest.trim().is_empty() {
+            return Err(format!("unexpected {:?} after the value", rest.trim()));
+        }
+        return Ok(vec![value]);
+    };
+    let mut values = Vec::new();
+    let mut rest = inner.trim_start();
+    loop {
+        if let Some(after) = rest.strip_prefix(']') {
+            if !after.trim().is_empty() {
+                return Err(format!("unexpected {:?} after the array", after.trim()));
+            }
+            return Ok(values);
+        }
+        let (value, after) = parse_single(rest)?;
+        values.push(value);
+        rest = after.trim_start();
+        if let Some(after) = rest.strip_prefix(',') {
+            rest = after.trim_start();
+        } else if !rest.starts_with(']') {
+            return Err("the array is not closed".to_string());
+        }
+    }
+}
+
+// Parse a single string, number or boolean at the start of the text, and return what's after.
+fn parse_single(text: &str) -> Result<(Value, &str), String> {
+    if let Some(rest) = text.strip_prefix('\'') {
+        // literal strings take everything as it is, which suits paths on Windows
+        let end = rest.find('\'').ok_or("the string is not closed")?;
+        return Ok((Value::Text(rest[..end].to_string()), &rest[end + 1..]));
+    }
+    if let Some(rest) = text.strip_prefix('"') {
+        let mut value = String::new();
+        let mut chars = rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::Text(value), &rest[i + 1..])),
+                '\\' => value.push(match chars.next().map(|(_, c)| c) {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    _ => return Err("unknown escape in the string".to_string()),
+                }),
+                c => value.push(c),
+            }
+        }
+        return Err("the string is not closed".to_string());
+    }
+    let end = text.find([',', ']']).unwrap_or(text.len());
+    let (word, rest) = text.split_at(end);
+    let value = match word.trim() {
+        "true" => Value::Flag(true),
+        "false" => Value::Flag(false),
+        number if number.parse::<i64>().is_ok() => Value::Text(number.to_string()),
+        other => {
+            return Err(format!(
+                "{:?} is not a string, a number or a boolean",
+                other
+            ))
+        }
+    };
+    Ok((value, rest))
+}
+
+// Remove the comment at the end of the line, if any, minding those inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
@@ -0,0 +1,1194 @@
+//! How what the sender and the receiver say to each other is laid out, as described by the
+//! net packet format in the crate root, apart from the loops that take turns saying it.
+//!
+//! Everything that depends on the protocol version is encoded and decoded here, in pairs, so
+//! that what one side writes can be checked to read back the same for every version.
+
+use crate::{hash, Error, ListedFile, Result};
+use crate::{
+    CODEC_DEFLATE, CODEC_NONE, TAG_ATTRIBUTES, TAG_CODEC, TAG_CREATED, TAG_DIR, TAG_HASH, TAG_PACK,
+    TAG_ROOT, TAG_SPECIAL, TAG_XATTR,
+};
+use crate::{FRAME_EXTENSION, FRAME_FILE_DATA, FRAME_FILE_LIST, FRAME_METADATA};
+use crate::{MAX_BATCH_LEN, MAX_FILE_COUNT, MAX_LIST_LEN, MAX_METADATA_LEN, MAX_NAME_LEN};
+use std::convert::TryInto;
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long the header of every chunk of data is: its length, and its compressed length.
+pub(crate) const CHUNK_HEADER_LEN: usize = 8;
+
+/// How long the header of every frame is: its kind, and the length of what's in it.
+pub(crate) const FRAME_HEADER_LEN: usize = 5;
+
+/// What the sender says of the transfer before anything else.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    pub version: u8,
+    /// Since version 4.
+    pub session: Option<u64>,
+    /// Since version 5, and none before.
+    pub flags: u8,
+    /// The chunk size proposed by the sender, since version 6.
+    pub chunk_size: Option<u32>,
+    /// How many files there are, and how long the prefix common to their names is, since
+    /// version 7.
+    pub file_count: Option<u32>,
+    pub prefix_len: Option<u32>,
+    /// Before version 7, how long the list that comes within the header is.
+    pub list_len: usize,
+}
+
+impl Header {
+    /// The header from the magic on, with the `list` in it before version 7, which is what
+    /// its `list_len` is then taken from.
+    pub(crate) fn encode(&self, list: &[u8]) -> Result<Vec<u8>> {
+        let mut buffer = vec![b's', b'f', b'-', self.version, 0, 0, 0, 0];
+        if self.version >= 4 {
+            buffer.extend(&self.session.unwrap_or(0).to_le_bytes());
+        }
+        if self.version >= 5 {
+            buffer.push(self.flags);
+        }
+        if self.version >= 6 {
+            buffer.extend(&self.chunk_size.unwrap_or(0).to_le_bytes());
+        }
+        if self.version >= 7 {
+            buffer.extend(&self.file_count.unwrap_or(0).to_le_bytes());
+            buffer.extend(&self.prefix_len.unwrap_or(0).to_le_bytes());
+        } else {
+            buffer.extend(list);
+        }
+        let buffer_len: u32 = buffer.len().try_into()?;
+        buffer[4..8].copy_from_slice(&buffer_len.to_le_bytes());
+        Ok(buffer)
+    }
+
+    /// Receives the rest of the header once the magic and the `version` were read, up to the
+    /// list that comes in it before version 7.
+    pub(crate) async fn receive(
+        stream: &mut (impl AsyncRead + Unpin),
+        version: u8,
+    ) -> Result<Self> {
+        // the buffer length, session id, flags, chunk size, file count and prefix length
+        let len = match version {
+            7.. => 25,
+            6 => 17,
+            5 => 13,
+            4 => 12,
+            _ => 4,
+        };
+        let mut header = vec![0; len];
+        stream.read_exact(&mut header).await?;
+        Self::read(&mut &header[..], version)
+    }
+
+    /// Reads the rest of the header once the magic and the `version` were read, up to the
+    /// list that comes in it before version 7.
+    pub(crate) fn read(stream: &mut impl Read, version: u8) -> Result<Self> {
+        let mut u32_buffer = [0u8; 4];
+        let mut u64_buffer = [0u8; 8];
+        stream.read_exact(&mut u32_buffer)?;
+        let buffer_len: usize = u32::from_le_bytes(u32_buffer).try_into()?;
+
+        let session = if version >= 4 {
+            stream.read_exact(&mut u64_buffer)?;
+            Some(u64::from_le_bytes(u64_buffer))
+        } else {
+            None
+        };
+        let flags = if version >= 5 {
+            let mut flags = [0u8];
+            stream.read_exact(&mut flags)?;
+            flags[0]
+        } else {
+            0
+        };
+        let chunk_size = if version >= 6 {
+            stream.read_exact(&mut u32_buffer)?;
+            Some(u32::from_le_bytes(u32_buffer))
+        } else {
+            None
+        };
+
+        let mut header = Header {
+            version,
+            session,
+            flags,
+            chunk_size,
+            file_count: None,
+            prefix_len: None,
+            list_len: 0,
+        };
+        if version >= 7 {
+            stream.read_exact(&mut u32_buffer)?;
+            let file_count = u32::from_le_bytes(u32_buffer);
+            if file_count as usize > MAX_FILE_COUNT {
+                return Err(Error::ProtocolViolation(format!(
+                    "too many files: {}",
+                    file_count
+                )));
+            }
+            stream.read_exact(&mut u32_buffer)?;
+            header.file_count = Some(file_count);
+            header.prefix_len = Some(u32::from_le_bytes(u32_buffer));
+            return Ok(header);
+        }
+
+        // minus 4 header, 4 buffer len, 8 session id, 1 flags, 4 chunk size
+        let header_len = match version {
+            6 => 21,
+            5 => 17,
+            4 => 16,
+            _ => 8,
+        };
+        header.list_len = match buffer_len.checked_sub(header_len) {
+            Some(len) if len <= MAX_LIST_LEN => len,
+            _ => {
+                return Err(Error::ProtocolViolation(format!(
+                    "bad file list length: {}",
+                    buffer_len
+                )))
+            }
+        };
+        Ok(header)
+    }
+}
+
+/// A file as the sender lists it.
+pub(crate) struct ListEntry<'a> {
+    pub len: u64,
+    /// Since version 5.
+    pub modified: u64,
+    pub name: &'a [u8],
+    /// The index of an earlier file with the same contents, since version 8.
+    pub copy_of: Option<usize>,
+    /// Whether the copy is a hard link, since version 9.
+    pub link: bool,
+    /// Since version 10.
+    pub metadata: &'a Metadata,
+}
+
+impl ListEntry<'_> {
+    /// Appends the entry to the `list` as it's sent with the `version`.
+    pub(crate) fn encode(&self, version: u8, list: &mut Vec<u8>) -> Result<()> {
+        list.extend(&self.len.to_le_bytes());
+        if version >= 5 {
+            list.extend(&self.modified.to_le_bytes());
+        }
+        let name_len: u32 = self.name.len().try_into()?;
+        list.extend(&name_len.to_le_bytes());
+        list.extend(self.name);
+        if version >= 8 {
+            let copy_of: u32 = match self.copy_of {
+                Some(source) => (source + 1).try_into()?,
+                None => 0,
+            };
+            list.extend(&copy_of.to_le_bytes());
+        }
+        if version >= 9 {
+            list.push(self.link as u8);
+        }
+        if version >= 23 {
+            push_frame(list, FRAME_METADATA, &self.metadata.encode()?)?;
+        } else if version >= 10 {
+            let metadata = self.metadata.encode()?;
+            let metadata_len: u32 = metadata.len().try_into()?;
+            list.extend(&metadata_len.to_le_bytes());
+            list.extend(metadata);
+        }
+        Ok(())
+    }
+}
+
+/// A batch of the list as it's sent since version 7, with the `list` of its `file_count`
+/// entries, in a file list frame since version 23.
+pub(crate) fn encode_batch(version: u8, file_count: usize, list: &[u8]) -> Result<Vec<u8>> {
+    let file_count: u32 = file_count.try_into()?;
+    let mut batch = Vec::with_capacity(FRAME_HEADER_LEN + 4 + list.len());
+    if version >= 23 {
+        batch.extend(&frame_header(FRAME_FILE_LIST, (4 + list.len()).try_into()?));
+        batch.extend(&file_count.to_le_bytes());
+    } else {
+        let list_len: u32 = list.len().try_into()?;
+        batch.extend(&file_count.to_le_bytes());
+        batch.extend(&list_len.to_le_bytes());
+    }
+    batch.extend(list);
+    Ok(batch)
+}
+
+/// Reads a batch of the list, as sent since version 7. Malformed batches are fatal, but the
+/// inner result is the outcome of using the connection, which may be recoverable.
+pub(crate) async fn read_batch(
+    stream: &mut (impl AsyncRead + Unpin),
+    version: u8,
+) -> Result<io::Result<Vec<ListedFile>>> {
+    let (file_count, list_len) = if version >= 23 {
+        let (kind, len) = match read_frame_header(stream).await {
+            Ok(header) => header,
+            Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e.into()),
+            Err(e) => return Ok(Err(e)),
+        };
+        if kind != FRAME_FILE_LIST || len < 4 {
+            return Err(Error::ProtocolViolation(format!(
+                "sender sent a frame of kind {} and {} bytes instead of the file list",
+                kind, len
+            )));
+        }
+        let mut u32_buffer = [0u8; 4];
+        if let Err(e) = stream.read_exact(&mut u32_buffer).await {
+            return Ok(Err(e));
+        }
+        (u32::from_le_bytes(u32_buffer).try_into()?, len - 4)
+    } else {
+        let mut header = [0u8; 8];
+        if let Err(e) = stream.read_exact(&mut header).await {
+            return Ok(Err(e));
+        }
+        (
+            u32::from_le_bytes(header[..4].try_into().unwrap()).try_into()?,
+            u32::from_le_bytes(header[4..].try_into().unwrap()).try_into()?,
+        )
+    };
+    if list_len > MAX_BATCH_LEN {
+        return Err(Error::ProtocolViolation(format!(
+            "file list batch is too large: {} bytes",
+            list_len
+        )));
+    }
+
+    let files = match receive_file_list(stream, version, list_len).await {
+        Ok(files) => files,
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => return Err(e.into()),
+        Err(e) => return Ok(Err(e)),
+    };
+    if files.len() != file_count {
+        return Err(Error::ProtocolViolation(format!(
+            "file list batch has {} files, but {} were announced",
+            files.len(),
+            file_count
+        )));
+    }
+    Ok(Ok(files))
+}
+
+/// Receives a file list, or a batch of it, taking up `list_len` bytes of the stream, which are
+/// read as they arrive and then parsed as `read_file_list` does.
+pub(crate) async fn receive_file_list(
+    stream: &mut (impl AsyncRead + Unpin),
+    version: u8,
+    list_len: usize,
+) -> io::Result<Vec<ListedFile>> {
+    let list = receive_list(stream, list_len).await?;
+    read_file_list(&mut &list[..], version, list_len)
+}
+
+/// Receives a list taking up `list_len` bytes of the stream, growing it only as they arrive,
+/// so that a length that was never going to be sent in full can't make the receiver allocate
+/// all of it up front.
+pub(crate) async fn receive_list(
+    stream: &mut (impl AsyncRead + Unpin),
+    list_len: usize,
+) -> io::Result<Vec<u8>> {
+    let mut list = Vec::new();
+    stream.take(list_len as u64).read_to_end(&mut list).await?;
+    if list.len() != list_len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(list)
+}
+
+// Read the entries of a file list, or a batch of it, taking up `list_len` bytes of the stream.
+// Each entry is checked as it is read, so a corrupt or hostile list can't make the receiver
+// allocate more than what was actually sent. Malformed lists fail with `InvalidData`.
+pub(crate) fn read_file_list(
+    stream: &mut impl Read,
+    version: u8,
+    list_len: usize,
+) -> io::Result<Vec<ListedFile>> {
+    let malformed = |what: &str| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("malformed file list: {}", what),
+        )
+    };
+
+    let mut files = Vec::new();
+    let mut remaining = list_len;
+    let take = |remaining: &mut usize, len: usize| match remaining.checked_sub(len) {
+        Some(rest) => {
+            *remaining = rest;
+            Ok(())
+        }
+        None => Err(malformed("entry goes past the end of the list")),
+    };
+
+    let mut u32_buffer = [0u8; 4];
+    let mut u64_buffer = [0u8; 8];
+    while remaining != 0 {
+        if files.len() == MAX_FILE_COUNT {
+            return Err(malformed("too many files"));
+        }
+        take(
+            &mut remaining,
+            match version {
+                23.. => 30,
+                10.. => 29,
+                9 => 25,
+                8 => 24,
+                5..=7 => 20,
+                _ => 12,
+            },
+        )?;
+        stream.read_exact(&mut u64_buffer)?;
+        let file_len: usize = u64::from_le_bytes(u64_buffer)
+            .try_into()
+            .map_err(|_| malformed("file is too large"))?;
+
+        let modified = if version >= 5 {
+            stream.read_exact(&mut u64_buffer)?;
+            Some(u64::from_le_bytes(u64_buffer))
+        } else {
+            None
+        };
+
+        stream.read_exact(&mut u32_buffer)?;
+        let name_len = u32::from_le_bytes(u32_buffer) as usize;
+        if name_len > MAX_NAME_LEN {
+            return Err(malformed("name is too long"));
+        }
+        take(&mut remaining, name_len)?;
+        let mut name = vec![0u8; name_len];
+        stream.read_exact(&mut name)?;
+        if !decode_name(version, &mut name) {
+            return Err(malformed(
+                "a name has a backslash, which before version 3 may be a path separator",
+            ));
+        }
+
+        let copy_of = if version >= 8 {
+            stream.read_exact(&mut u32_buffer)?;
+            match u32::from_le_bytes(u32_buffer) {
+                0 => None,
+                source => Some(source as usize - 1),
+            }
+        } else {
+            None
+        };
+        let link = if version >= 9 {
+            let mut link = [0u8];
+            stream.read_exact(&mut link)?;
+            link[0] != 0
+        } else {
+            false
+        };
+
+        let metadata = if version >= 10 {
+            if version >= 23 {
+                let mut kind = [0u8];
+                stream.read_exact(&mut kind)?;
+                if kind[0] != FRAME_METADATA {
+                    return Err(malformed("metadata is not in a metadata frame"));
+                }
+            }
+            stream.read_exact(&mut u32_buffer)?;
+            let metadata_len = u32::from_le_bytes(u32_buffer) as usize;
+            if metadata_len > MAX_METADATA_LEN {
+                return Err(malformed("metadata is too long"));
+            }
+            take(&mut remaining, metadata_len)?;
+            let mut metadata = vec![0u8; metadata_len];
+            stream.read_exact(&mut metadata)?;
+            Metadata::parse(&metadata)
+                .ok_or_else(|| malformed("metadata is truncated or invalid"))?
+        } else {
+            Metadata::default()
+        };
+
+        files.push(ListedFile {
+            len: file_len,
+            modified,
+            name,
+            copy_of,
+            link,
+            xattrs: metadata.xattrs,
+            hash: metadata.hash,
+            root: metadata.root,
+            packed: metadata.packed,
+            chunked: metadata.chunked,
+            created: metadata.created,
+            attributes: metadata.attributes,
+            directory: metadata.directory,
+            special: metadata.special,
+            wanted: true,
+            rejected: None,
+        });
+    }
+    Ok(files)
+}
+
+/// What is known of a file from its metadata.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct Metadata {
+    pub xattrs: Vec<(Vec<u8>, Vec<u8>)>,
+    pub hash: Option<hash::Digest>,
+    pub root: Option<usize>,
+    pub packed: bool,
+    /// Whether the data is sent in chunks, since version 18 and only when it's compressed.
+    pub chunked: Option<bool>,
+    /// When the file was created, in seconds since the unix epoch.
+    pub created: Option<u64>,
+    /// Whether the file is read-only or hidden.
+    pub attributes: Option<u8>,
+    /// Whether it's an empty directory rather than a file, since version 19.
+    pub directory: bool,
+    /// The kind of special file it is rather than a file, since version 21.
+    pub special: Option<u8>,
+}
+
+impl Metadata {
+    /// How long it is once encoded.
+    pub(crate) fn encoded_len(&self) -> usize {
+        let xattrs_len = self
+            .xattrs
+            .iter()
+            .map(|(name, value)| 9 + name.len() + value.len())
+            .sum::<usize>();
+        self.chunked.map_or(0, |_| 6)
+            + self.created.map_or(0, |_| 13)
+            + self.attributes.map_or(0, |_| 6)
+            + self.root.map_or(0, |_| 9)
+            + if self.packed { 5 } else { 0 }
+            + if self.directory { 5 } else { 0 }
+            + self.special.map_or(0, |_| 6)
+            + self.hash.map_or(0, |hash| 5 + hash.len())
+            + xattrs_len
+    }
+
+    pub(crate) fn encode(&self) -> Result<Vec<u8>> {
+        let mut metadata = Vec::with_capacity(self.encoded_len());
+        if let Some(chunked) = self.chunked {
+            metadata.push(TAG_CODEC);
+            metadata.extend(&1u32.to_le_bytes());
+            metadata.push(if chunked { CODEC_DEFLATE } else { CODEC_NONE });
+        }
+        if let Some(created) = self.created {
+            metadata.push(TAG_CREATED);
+            metadata.extend(&8u32.to_le_bytes());
+            metadata.extend(&created.to_le_bytes());
+        }
+        if let Some(attributes) = self.attributes {
+            metadata.push(TAG_ATTRIBUTES);
+            metadata.extend(&1u32.to_le_bytes());
+            metadata.push(attributes);
+        }
+        if let Some(root) = self.root {
+            let root: u32 = root.try_into()?;
+            metadata.push(TAG_ROOT);
+            metadata.extend(&4u32.to_le_bytes());
+            metadata.extend(&root.to_le_bytes());
+        }
+        if self.packed {
+            metadata.push(TAG_PACK);
+            metadata.extend(&0u32.to_le_bytes());
+        }
+        if self.directory {
+            metadata.push(TAG_DIR);
+            metadata.extend(&0u32.to_le_bytes());
+        }
+        if let Some(kind) = self.special {
+            metadata.push(TAG_SPECIAL);
+            metadata.extend(&1u32.to_le_bytes());
+            metadata.push(kind);
+        }
+        if let Some(digest) = &self.hash {
+            let digest_len: u32 = digest.len().try_into()?;
+            metadata.push(TAG_HASH);
+            metadata.extend(&digest_len.to_le_bytes());
+            metadata.extend(digest);
+        }
+        for (name, value) in &self.xattrs {
+            let value_len: u32 = (4 + name.len() + value.len()).try_into()?;
+            let name_len: u32 = name.len().try_into()?;
+            metadata.push(TAG_XATTR);
+            metadata.extend(&value_len.to_le_bytes());
+            metadata.extend(&name_len.to_le_bytes());
+            metadata.extend(name);
+            metadata.extend(value);
+        }
+        Ok(metadata)
+    }
+
+    /// The metadata of a file, or `None` if it's malformed.
+    pub(crate) fn parse(mut metadata: &[u8]) -> Option<Self> {
+        fn split_u32(bytes: &[u8]) -> Option<(usize, &[u8])> {
+            let (len, rest) = bytes.split_first_chunk::<4>()?;
+            Some((u32::from_le_bytes(*len) as usize, rest))
+        }
+
+        let mut parsed = Metadata::default();
+        while let Some((&tag, rest)) = metadata.split_first() {
+            let (value_len, rest) = split_u32(rest)?;
+            if value_len > rest.len() {
+                return None;
+            }
+            let (value, rest) = rest.split_at(value_len);
+            metadata = rest;
+            if tag == TAG_XATTR {
+                let (name_len, value) = split_u32(value)?;
+                if name_len > value.len() {
+                    return None;
+                }
+                let (name, value) = value.split_at(name_len);
+                parsed.xattrs.push((name.to_vec(), value.to_vec()));
+            }
+            if tag == TAG_HASH {
+                parsed.hash = Some(value.try_into().ok()?);
+            }
+            if tag == TAG_ROOT {
+                parsed.root = Some(u32::from_le_bytes(value.try_into().ok()?) as usize);
+            }
+            if tag == TAG_PACK {
+                parsed.packed = true;
+            }
+            if tag == TAG_DIR {
+                parsed.directory = true;
+            }
+            if tag == TAG_SPECIAL {
+                parsed.special = match value {
+                    [kind] => Some(*kind),
+                    _ => return None,
+                };
+            }
+            if tag == TAG_CREATED {
+                parsed.created = Some(u64::from_le_bytes(value.try_into().ok()?));
+            }
+            if tag == TAG_ATTRIBUTES {
+                parsed.attributes = Some(*value.first()?);
+            }
+            // there's no knowing how to read data sent with other codecs, so they're not ignored
+            if tag == TAG_CODEC {
+                parsed.chunked = match value {
+                    [CODEC_NONE] => Some(false),
+                    [CODEC_DEFLATE] => Some(true),
+                    _ => return None,
+                };
+            }
+        }
+        Some(parsed)
+    }
+}
+
+/// The header of a chunk of `len` bytes of data, compressed into `compressed_len` bytes, or
+/// zero if it's sent as it is.
+pub(crate) fn chunk_header(len: u32, compressed_len: u32) -> [u8; CHUNK_HEADER_LEN] {
+    let mut header = [0u8; CHUNK_HEADER_LEN];
+    header[..4].copy_from_slice(&len.to_le_bytes());
+    header[4..].copy_from_slice(&compressed_len.to_le_bytes());
+    header
+}
+
+/// The length of the data of a chunk, and its compressed length, from its header.
+pub(crate) fn parse_chunk_header(header: &[u8; CHUNK_HEADER_LEN]) -> (usize, usize) {
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let compressed_len = u32::from_le_bytes(header[4..].try_into().unwrap());
+    (len as usize, compressed_len as usize)
+}
+
+/// The header of a frame of the `kind` with `len` bytes in it.
+pub(crate) fn frame_header(kind: u8, len: u32) -> [u8; FRAME_HEADER_LEN] {
+    let mut header = [kind, 0, 0, 0, 0];
+    header[1..].copy_from_slice(&len.to_le_bytes());
+    header
+}
+
+/// Appends a frame of the `kind` with the `payload` in it to the `buffer`.
+pub(crate) fn push_frame(buffer: &mut Vec<u8>, kind: u8, payload: &[u8]) -> Result<()> {
+    buffer.extend(&frame_header(kind, payload.len().try_into()?));
+    buffer.extend(payload);
+    Ok(())
+}
+
+/// Reads the kind of the next frame and how long it is, skipping the extension frames before
+/// it, as there are none either side needs to know of.
+pub(crate) async fn read_frame_header(
+    stream: &mut (impl AsyncRead + Unpin),
+) -> io::Result<(u8, usize)> {
+    loop {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        stream.read_exact(&mut header).await?;
+        let len = u32::from_le_bytes(header[1..].try_into().unwrap());
+        if header[0] != FRAME_EXTENSION {
+            break Ok((header[0], len as usize));
+        }
+        let skipped = tokio::io::copy(&mut stream.take(len.into()), &mut tokio::io::sink()).await?;
+        if skipped != u64::from(len) {
+            break Err(io::ErrorKind::UnexpectedEof.into());
+        }
+    }
+}
+
+/// Reads the next frame, skipping the extension frames before it, and returns its kind and
+/// what's in it. Frames longer than `max_len` fail with `InvalidData`.
+pub(crate) async fn read_frame(
+    stream: &mut (impl AsyncRead + Unpin),
+    max_len: usize,
+) -> io::Result<(u8, Vec<u8>)> {
+    let (kind, len) = read_frame_header(stream).await?;
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of kind {} is too long: {} bytes", kind, len),
+        ));
+    }
+    let mut payload = Vec::new();
+    stream.take(len as u64).read_to_end(&mut payload).await?;
+    if payload.len() != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok((kind, payload))
+}
+
+/// Reads the data of a file out of the file data frames it's sent in since version 23, or as
+/// it is before. Anything other than file data where it should be fails with `InvalidData`.
+pub(crate) struct FileData<R> {
+    stream: R,
+    framed: bool,
+    // what's left of the current frame
+    left: usize,
+}
+
+impl<R: AsyncRead + Unpin> FileData<R> {
+    pub(crate) fn new(stream: R, version: u8) -> Self {
+        Self {
+            stream,
+            framed: version >= 23,
+            left: 0,
+        }
+    }
+
+    /// Whether the last frame was read to its end, as the data of a file must end with one.
+    pub(crate) fn is_done(&self) -> bool {
+        self.left == 0
+    }
+
+    /// The stream the data is read from, to reply through it in between.
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.stream
+    }
+
+    /// Reads some of the data into `buf`, and returns how much, which is zero at the end of
+    /// the stream.
+    pub(crate) async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.framed {
+            return self.stream.read(buf).await;
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        while self.left == 0 {
+            let (kind, len) = read_frame_header(&mut self.stream).await?;
+            if kind != FRAME_FILE_DATA {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("sender sent a frame of kind {} within the file data", kind),
+                ));
+            }
+            self.left = len;
+        }
+        let len = buf.len().min(self.left);
+        let read = self.stream.read(&mut buf[..len]).await?;
+        self.left -= read;
+        Ok(read)
+    }
+
+    /// Reads exactly enough data to fill `buf`, failing with `UnexpectedEof` if the stream
+    /// ends before that.
+    pub(crate) async fn read_exact(&mut self, mut buf: &mut [u8]) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read(buf).await {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => buf = &mut buf[n..],
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+// Bring a name received with an older protocol version up to date with the current one, or
+// return false if there's no telling what it means.
+fn decode_name(version: u8, name: &mut [u8]) -> bool {
+    // version 2 did not normalize path separators, nor says which the sender uses, so a
+    // backslash is only taken as one where it always is. Elsewhere it may be part of the name,
+    // and files can't be rejected one by one before version 5, so the whole list is refused
+    if version < 3 && name.contains(&b'\\') {
+        if !cfg!(windows) {
+            return false;
+        }
+        for c in name.iter_mut() {
+            if *c == b'\\' {
+                *c = b'/';
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::block_on;
+    use crate::{FLAG_COMPRESS, FLAG_IDENTITY, FLAG_UPDATE, MIN_VERSION, VERSION};
+    use crate::{FRAME_ACK, FRAME_ERROR};
+    use std::env;
+    use std::fs;
+    use std::path::PathBuf;
+
+    // An entry of the list, owning what `ListEntry` borrows.
+    struct Sample {
+        len: u64,
+        modified: u64,
+        name: &'static [u8],
+        copy_of: Option<usize>,
+        link: bool,
+        metadata: Metadata,
+    }
+
+    impl Sample {
+        fn entry(&self) -> ListEntry<'_> {
+            ListEntry {
+                len: self.len,
+                modified: self.modified,
+                name: self.name,
+                copy_of: self.copy_of,
+                link: self.link,
+                metadata: &self.metadata,
+            }
+        }
+    }
+
+    // The files a sender speaking the `version` could list, using all that it can say.
+    fn samples(version: u8) -> Vec<Sample> {
+        let metadata = |metadata: Metadata| {
+            if version >= 10 {
+                metadata
+            } else {
+                Metadata::default()
+            }
+        };
+        let mut samples = vec![
+            Sample {
+                len: 12,
+                modified: 1_700_000_000,
+                name: b"photos/notes.txt",
+                copy_of: None,
+                link: false,
+                metadata: metadata(Metadata {
+                    xattrs: vec![(b"user.origin".to_vec(), b"sf".to_vec())],
+                    hash: Some([0xab; 32]),
+                    root: Some(7),
+                    chunked: (version >= 18).then_some(true),
+                    created: Some(1_600_000_000),
+                    attributes: Some(1),
+                    ..Metadata::default()
+                }),
+            },
+            Sample {
+                len: 4096,
+                modified: 1_700_000_001,
+                name: b"photos/dir/data.jpg",
+                copy_of: None,
+                link: false,
+                metadata: metadata(Metadata {
+                    chunked: (version >= 18).then_some(false),
+                    ..Metadata::default()
+                }),
+            },
+            Sample {
+                len: 0,
+                modified: 0,
+                name: "photos/dir/ñandú".as_bytes(),
+                copy_of: None,
+                link: false,
+                metadata: Metadata::default(),
+            },
+        ];
+        if version >= 8 {
+            samples.push(Sample {
+                len: 4096,
+                modified: 1_700_000_001,
+                name: b"photos/dir/copy.jpg",
+                copy_of: Some(1),
+                link: version >= 9,
+                metadata: Metadata::default(),
+            });
+        }
+        if version >= 13 {
+            samples.push(Sample {
+                len: 10240,
+                modified: 1_700_000_002,
+                name: b"photos/dir/small.tar",
+                copy_of: None,
+                link: false,
+                metadata: Metadata {
+                    packed: true,
+                    ..Metadata::default()
+                },
+            });
+        }
+        if version >= 19 {
+            samples.push(Sample {
+                len: 0,
+                modified: 1_700_000_003,
+                name: b"photos/empty",
+                copy_of: None,
+                link: false,
+                metadata: Metadata {
+                    directory: true,
+                    ..Metadata::default()
+                },
+            });
+        }
+        if version >= 21 {
+            samples.push(Sample {
+                len: 0,
+                modified: 1_700_000_004,
+                name: b"photos/pipe",
+                copy_of: None,
+                link: false,
+                metadata: Metadata {
+                    special: Some(1),
+                    ..Metadata::default()
+                },
+            });
+        }
+        if version < 5 {
+            for sample in &mut samples {
+                sample.modified = 0;
+            }
+        }
+        samples
+    }
+
+    // The header a sender speaking the `version` would send for the `files`.
+    fn header(version: u8, files: &[Sample]) -> Header {
+        Header {
+            version,
+            session: (version >= 4).then_some(0x0123_4567_89ab_cdef),
+            flags: match version {
+                17.. => FLAG_UPDATE | FLAG_IDENTITY | FLAG_COMPRESS,
+                14.. => FLAG_UPDATE | FLAG_IDENTITY,
+                5.. => FLAG_UPDATE,
+                _ => 0,
+            },
+            chunk_size: (version >= 6).then_some(1024 * 1024),
+            file_count: (version >= 7).then_some(files.len() as u32),
+            prefix_len: (version >= 7).then_some(7),
+            list_len: 0,
+        }
+    }
+
+    fn encode_list(version: u8, files: &[Sample]) -> Vec<u8> {
+        let mut list = Vec::new();
+        for file in files {
+            file.entry().encode(version, &mut list).unwrap();
+        }
+        list
+    }
+
+    // What the sender writes until the end of the first batch of the list.
+    fn encode_opening(version: u8) -> Vec<u8> {
+        let files = samples(version);
+        let list = encode_list(version, &files);
+        let header = header(version, &files);
+        if version < 7 {
+            return header.encode(&list).unwrap();
+        }
+        let mut opening = header.encode(&[]).unwrap();
+        opening.extend(encode_batch(version, files.len(), &list).unwrap());
+        opening
+    }
+
+    // Read what the sender wrote until the end of the first batch of the list.
+    fn decode_opening(mut stream: &[u8]) -> (Header, Vec<ListedFile>) {
+        let (magic, rest) = stream.split_at(4);
+        stream = rest;
+        assert_eq!(&magic[..3], b"sf-");
+        let header = block_on(Header::receive(&mut stream, magic[3])).unwrap();
+        let files = if header.version >= 7 {
+            let files = block_on(read_batch(&mut stream, header.version))
+                .unwrap()
+                .unwrap();
+            assert_eq!(Some(files.len() as u32), header.file_count);
+            files
+        } else {
+            block_on(receive_file_list(
+                &mut stream,
+                header.version,
+                header.list_len,
+            ))
+            .unwrap()
+        };
+        assert!(stream.is_empty(), "the list should end with the stream");
+        (header, files)
+    }
+
+    fn assert_listed(version: u8, listed: &[ListedFile], samples: &[Sample]) {
+        assert_eq!(listed.len(), samples.len());
+        for (file, sample) in listed.iter().zip(samples) {
+            let name = String::from_utf8_lossy(sample.name);
+            assert_eq!(file.len as u64, sample.len, "len of {}", name);
+            let modified = (version >= 5).then_some(sample.modified);
+            assert_eq!(file.modified, modified, "modified of {}", name);
+            assert_eq!(file.name, sample.name, "name of {}", name);
+            assert_eq!(file.copy_of, sample.copy_of, "copy of {}", name);
+            assert_eq!(file.link, sample.link, "link of {}", name);
+            let metadata = Metadata {
+                xattrs: file.xattrs.clone(),
+                hash: file.hash,
+                root: file.root,
+                packed: file.packed,
+                chunked: file.chunked,
+                created: file.created,
+                attributes: file.attributes,
+                directory: file.directory,
+                special: file.special,
+            };
+            assert_eq!(metadata, sample.metadata, "metadata of {}", name);
+        }
+    }
+
+    fn fixture_path(version: u8) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("tests")
+            .join("fixtures")
+            .join(format!("opening-v{}.bin", version))
+    }
+
+    #[test]
+    fn header_round_trips() {
+        for version in MIN_VERSION..=VERSION {
+            let files = samples(version);
+            let list = encode_list(version, &files);
+            let mut expected = header(version, &files);
+            let encoded = expected.encode(&list).unwrap();
+            assert_eq!(&encoded[..4], &[b's', b'f', b'-', version]);
+
+            let mut stream = &encoded[4..];
+            let decoded = Header::read(&mut stream, version).unwrap();
+            if version < 7 {
+                expected.list_len = list.len();
+                assert_eq!(stream, &list[..], "the list follows the header");
+            } else {
+                assert!(stream.is_empty(), "nothing follows the header");
+            }
+            assert_eq!(decoded, expected, "header of version {}", version);
+        }
+    }
+
+    #[test]
+    fn list_round_trips() {
+        for version in MIN_VERSION..=VERSION {
+            let files = samples(version);
+            let list = encode_list(version, &files);
+            let listed = read_file_list(&mut &list[..], version, list.len()).unwrap();
+            assert_listed(version, &listed, &files);
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let metadata = Metadata {
+            xattrs: vec![
+                (b"user.a".to_vec(), b"1".to_vec()),
+                (b"user.empty".to_vec(), Vec::new()),
+            ],
+            hash: Some([7; 32]),
+            root: Some(3),
+            packed: true,
+            chunked: Some(false),
+            created: Some(u64::MAX),
+            attributes: Some(3),
+            directory: true,
+            special: Some(1),
+        };
+        let encoded = metadata.encode().unwrap();
+        assert_eq!(encoded.len(), metadata.encoded_len());
+        assert_eq!(Metadata::parse(&encoded), Some(metadata));
+        assert_eq!(Metadata::parse(&[]), Some(Metadata::default()));
+    }
+
+    #[test]
+    fn metadata_skips_unknown_tags() {
+        let mut encoded = vec![200, 3, 0, 0, 0, 1, 2, 3];
+        encoded.extend(
+            Metadata {
+                packed: true,
+                ..Metadata::default()
+            }
+            .encode()
+            .unwrap(),
+        );
+        assert_eq!(
+            Metadata::parse(&encoded),
+            Some(Metadata {
+                packed: true,
+                ..Metadata::default()
+            })
+        );
+    }
+
+    #[test]
+    fn malformed_metadata_is_refused() {
+        let encoded = Metadata {
+            hash: Some([1; 32]),
+            ..Metadata::default()
+        }
+        .encode()
+        .unwrap();
+        assert_eq!(Metadata::parse(&encoded[..encoded.len() - 1]), None);
+        // a hash of the wrong length, and a codec nobody knows how to read
+        assert_eq!(Metadata::parse(&[TAG_HASH, 1, 0, 0, 0, 0]), None);
+        assert_eq!(Metadata::parse(&[TAG_CODEC, 1, 0, 0, 0, 9]), None);
+    }
+
+    #[test]
+    fn chunk_header_round_trips() {
+        let header = chunk_header(1 << 20, 12345);
+        assert_eq!(parse_chunk_header(&header), (1 << 20, 12345));
+        assert_eq!(parse_chunk_header(&chunk_header(7, 0)), (7, 0));
+    }
+
+    #[test]
+    fn frames_skip_extensions() {
+        let mut sent = Vec::new();
+        push_frame(&mut sent, FRAME_EXTENSION, b"unknown").unwrap();
+        push_frame(&mut sent, FRAME_ACK, &[1, 2]).unwrap();
+        push_frame(&mut sent, FRAME_FILE_DATA, b"hello, ").unwrap();
+        push_frame(&mut sent, FRAME_EXTENSION, &[]).unwrap();
+        push_frame(&mut sent, FRAME_FILE_DATA, b"world").unwrap();
+        push_frame(&mut sent, FRAME_ERROR, b"gone").unwrap();
+
+        let mut stream = &sent[..];
+        let frame = block_on(read_frame(&mut stream, 2)).unwrap();
+        assert_eq!(frame, (FRAME_ACK, vec![1, 2]));
+        let mut data = FileData::new(&mut stream, VERSION);
+        let mut received = [0u8; 12];
+        block_on(data.read_exact(&mut received)).unwrap();
+        assert_eq!(&received, b"hello, world");
+        assert!(data.is_done());
+        // the data of a file ends where the file does, and whatever comes next is not data
+        let error = block_on(data.read(&mut [0])).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn long_frames_are_refused() {
+        let mut sent = Vec::new();
+        push_frame(&mut sent, FRAME_ACK, &[1, 2, 3]).unwrap();
+        let error = block_on(read_frame(&mut &sent[..], 2)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        // nor can a frame end before it says it does
+        let error = block_on(read_frame(&mut &sent[..sent.len() - 1], 3)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn list_going_past_its_length_is_refused() {
+        let files = samples(VERSION);
+        let list = encode_list(VERSION, &files);
+        let error = read_file_list(&mut &list[..], VERSION, list.len() - 1)
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn lists_ending_early_are_refused() {
+        let list = [7u8; 44];
+        assert_eq!(
+            block_on(receive_list(&mut &list[..], list.len())).unwrap(),
+            list
+        );
+        // however long it says it is, only what arrives is kept
+        let error = block_on(receive_list(&mut &list[..], 256 << 20)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn long_names_are_refused() {
+        let mut list = Vec::new();
+        list.extend(&0u64.to_le_bytes());
+        list.extend(&(MAX_NAME_LEN as u32 + 1).to_le_bytes());
+        let error = read_file_list(&mut &list[..], 2, usize::MAX).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn backslashes_before_version_3() {
+        let mut list = Vec::new();
+        ListEntry {
+            len: 0,
+            modified: 0,
+            name: b"dir\\file",
+            copy_of: None,
+            link: false,
+            metadata: &Metadata::default(),
+        }
+        .encode(2, &mut list)
+        .unwrap();
+        let listed = read_file_list(&mut &list[..], 2, list.len());
+        if cfg!(windows) {
+            assert_eq!(listed.unwrap()[0].name, b"dir/file");
+        } else {
+            assert_eq!(listed.err().unwrap().kind(), io::ErrorKind::InvalidData);
+        }
+        // later versions always meant it as part of the name
+        let listed = read_file_list(&mut &list[..], 3, list.len()).unwrap();
+        assert_eq!(listed[0].name, b"dir\\file");
+    }
+
+    // The openings of every version are checked in, so that a change to how any of them is
+    // written or read is noticed, rather than only whether both still agree. Set
+    // `SF_BLESS_FIXTURES` to write them again after changing them on purpose.
+    #[test]
+    fn golden_fixtures_match() {
+        let bless = env::var_os("SF_BLESS_FIXTURES").is_some();
+        for version in MIN_VERSION..=VERSION {
+            let path = fixture_path(version);
+            let encoded = encode_opening(version);
+            if bless {
+                fs::write(&path, &encoded).unwrap();
+                continue;
+            }
+            let fixture = fs::read(&path)
+                .unwrap_or_else(|e| panic!("cannot read the fixture {:?}: {}", path, e));
+            assert!(
+                fixture == encoded,
+                "version {} is no longer written as in {:?}",
+                version,
+                path
+            );
+        }
+    }
+
+    #[test]
+    fn golden_fixtures_decode() {
+        for version in MIN_VERSION..=VERSION {
+            let path = fixture_path(version);
+            let fixture = fs::read(&path)
+                .unwrap_or_else(|e| panic!("cannot read the fixture {:?}: {}", path, e));
+            let files = samples(version);
+            let (decoded, listed) = decode_opening(&fixture);
+            let mut expected = header(version, &files);
+            if version < 7 {
+                expected.list_len = encode_list(version, &files).len();
+            }
+            assert_eq!(decoded, expected, "header of version {}", version);
+            assert_listed(version, &listed, &files);
+        }
+    }
+}
@@ -0,0 +1,383 @@
+//! Just enough of QR codes to show a short text in the terminal, so that it can be scanned.
+//!
+//! Only byte mode with the lowest error correction level is used, which in the versions up to
+//! 5 always fits in a single block, so there's no interleaving to worry about.
+
+// Data and error correction codewords of every version, with low error correction
+const CODEWORDS: [(usize, usize); 5] = [(19, 7), (34, 10), (55, 15), (80, 20), (108, 26)];
+const LOW_ECC_BITS: u32 = 0b01;
+const PAD_BYTES: [u8; 2] = [0xec, 0x11];
+const QUIET_ZONE: usize = 4;
+
+// Penalties used to pick the mask that is easiest to scan
+const PENALTY_RUN: i32 = 3;
+const PENALTY_BLOCK: i32 = 3;
+const PENALTY_FINDER: i32 = 40;
+const PENALTY_BALANCE: i32 = 10;
+
+struct Code {
+    size: usize,
+    dark: Vec<Vec<bool>>,
+    // modules which are not data, and must not be masked
+    reserved: Vec<Vec<bool>>,
+}
+
+impl Code {
+    fn new(version: usize) -> Self {
+        let size = 17 + 4 * version;
+        let mut code = Code {
+            size,
+            dark: vec![vec![false; size]; size],
+            reserved: vec![vec![false; size]; size],
+        };
+
+        for i in 0..size {
+            code.set(6, i, i % 2 == 0);
+            code.set(i, 6, i % 2 == 0);
+        }
+        for (x, y) in [(3, 3), (size - 4, 3), (3, size - 4)] {
+            code.finder(x, y);
+        }
+        if version > 1 {
+            let at = 4 * version + 10;
+            for dy in -2..=2_isize {
+                for dx in -2..=2_isize {
+                    let (x, y) = ((at as isize + dx) as usize, (at as isize + dy) as usize);
+                    code.set(x, y, dx.abs().max(dy.abs()) != 1);
+                }
+            }
+        }
+        // reserved for now, drawn once the mask is known
+        code.format(0);
+        code
+    }
+
+    fn set(&mut self, x: usize, y: usize, dark: bool) {
+        self.dark[y][x] = dark;
+        self.reserved[y][x] = true;
+    }
+
+    // The pattern in the corners, with the separator around it.
+    fn finder(&mut self, cx: usize, cy: usize) {
+        for dy in -4..=4_isize {
+            for dx in -4..=4_isize {
+                let (x, y) = (cx as isize + dx, cy as isize + dy);
+                if (0..self.size as isize).contains(&x) && (0..self.size as isize).contains(&y) {
+                    let distance = dx.abs().max(dy.abs());
+                    self.set(x as usize, y as usize, distance != 2 && distance != 4);
+                }
+            }
+        }
+    }
+
+    fn format(&mut self, mask: u32) {
+        let data = (LOW_ECC_BITS << 3) | mask;
+        let mut rem = data;
+        for _ in 0..10 {
+            rem = (rem << 1) ^ ((rem >> 9) * 0x537);
+        }
+        let bits = ((data << 10) | rem) ^ 0x5412;
+        let bit = |i: usize| (bits >> i) & 1 != 0;
+
+        let size = self.size;
+        for i in 0..6 {
+            self.set(8, i, bit(i));
+        }
+        self.set(8, 7, bit(6));
+        self.set(8, 8, bit(7));
+        self.set(7, 8, bit(8));
+        for i in 9..15 {
+            self.set(14 - i, 8, bit(i));
+        }
+        for i in 0..8 {
+            self.set(size - 1 - i, 8, bit(i));
+        }
+        for i in 8..15 {
+            self.set(8, size - 15 + i, bit(i));
+        }
+        self.set(8, size - 8, true);
+    }
+
+    // Fill the modules which are not reserved in a zigzag, two columns at a time, starting from
+    // the bottom right corner.
+    fn place(&mut self, codewords: &[u8]) {
+        let size = self.size;
+        let mut i = 0;
+        let mut right = size - 1;
+        while right >= 1 {
+            // the vertical timing pattern is skipped entirely
+            if right == 6 {
+                right = 5;
+            }
+            let upward = (right + 1) & 2 == 0;
+            for vertical in 0..size {
+                let y = if upward {
+                    size - 1 - vertical
+                } else {
+                    vertical
+                };
+                for x in [right, right - 1] {
+                    if !self.reserved[y][x] && i < codewords.len() * 8 {
+                        self.dark[y][x] = (codewords[i / 8] >> (7 - i % 8)) & 1 != 0;
+                        i += 1;
+                    }
+                }
+            }
+            if right < 2 {
+                break;
+            }
+            right -= 2;
+        }
+    }
+
+    fn apply_mask(&mut self, mask: u32) {
+        for y in 0..self.size {
+            for x in 0..self.size {
+                let flip = match mask {
+                    0 => (x + y) % 2 == 0,
+                    1 => y % 2 == 0,
+                    2 => x % 3 == 0,
+                    3 => (x + y) % 3 == 0,
+                    4 => (x / 3 + y / 2) % 2 == 0,
+                    5 => x * y % 2 + x * y % 3 == 0,
+                    6 => (x * y % 2 + x * y % 3) % 2 == 0,
+                    _ => ((x + y) % 2 + x * y % 3) % 2 == 0,
+                };
+                if flip && !self.reserved[y][x] {
+                    self.dark[y][x] = !self.dark[y][x];
+                }
+            }
+        }
+    }
+
+    fn penalty(&self) -> i32 {
+        let size = self.size;
+        let at = |x: usize, y: usize, vertical: bool| {
+            if vertical {
+                self.dark[x][y]
+            } else {
+                self.dark[y][x]
+            }
+        };
+
+        let mut penalty = 0;
+        for vertical in [false, true] {
+            for y in 0..size {
+                let mut run = 1;
+                for x in 1..=size {
+                    if x < size && at(x, y, vertical) == at(x - 1, y, vertical) {
+                        run += 1;
+                        continue;
+                    }
+                    if run >= 5 {
+                        penalty += PENALTY_RUN + (run - 5);
+                    }
+                    run = 1;
+                }
+
+                // the runs of the line, alternating from a light one that takes in the quiet
+                // zone, to the light one that ends it taking in the other side
+                let mut runs = vec![size];
+                for x in 0..size {
+                    if at(x, y, vertical) == (runs.len() % 2 == 0) {
+                        *runs.last_mut().unwrap() += 1;
+                    } else {
+                        runs.push(1);
+                    }
+                }
+                match runs.len() % 2 {
+                    0 => runs.push(size),
+                    _ => *runs.last_mut().unwrap() += size,
+                }
+
+                // dark-light-dark-dark-dark-light-dark in any width, with four times as much
+                // light on either side
+                for end in (6..runs.len()).step_by(2) {
+                    let n = runs[end - 1];
+                    let (before, after) = (runs[end - 6], runs[end]);
+                    if runs[end - 5..end - 1] == [n, n, 3 * n, n] {
+                        if after >= 4 * n && before >= n {
+                            penalty += PENALTY_FINDER;
+                        }
+                        if before >= 4 * n && after >= n {
+                            penalty += PENALTY_FINDER;
+                        }
+                    }
+                }
+            }
+        }
+
+        for y in 1..size {
+            for x in 1..size {
+                let color = self.dark[y][x];
+                if self.dark[y - 1][x] == color
+                    && self.dark[y][x - 1] == color
+                    && self.dark[y - 1][x - 1] == color
+                {
+                    penalty += PENALTY_BLOCK;
+                }
+            }
+        }
+
+        let total = (size * size) as i32;
+        let dark = self.dark.iter().flatten().filter(|&&d| d).count() as i32;
+        let deviation = ((dark * 20 - total * 10).abs() + total - 1) / total - 1;
+        penalty + deviation * PENALTY_BALANCE
+    }
+}
+
+// Reed-Solomon error correction codewords for the data, over GF(256).
+fn error_correction(data: &[u8], len: usize) -> Vec<u8> {
+    let multiply = |mut x: u8, mut y: u8| {
+        let mut product = 0u8;
+        while y != 0 {
+            if y & 1 != 0 {
+                product ^= x;
+            }
+            // reduced by the polynomial x^8 + x^4 + x^3 + x^2 + 1
+            x = (x << 1) ^ if x & 0x80 != 0 { 0x1d } else { 0 };
+            y >>= 1;
+        }
+        product
+    };
+
+    // the generator polynomial (x - 1)(x - 2)(x - 4)..., highest degree first and implied
+    let mut generator = vec![0u8; len];
+    generator[len - 1] = 1;
+    let mut root = 1u8;
+    for _ in 0..len {
+        for i in 0..len {
+            generator[i] =
+                multiply(generator[i], root) ^ generator.get(i + 1).copied().unwrap_or(0);
+        }
+        root = multiply(root, 2);
+    }
+
+    let mut remainder = vec![0u8; len];
+    for &byte in data {
+        let factor = byte ^ remainder.remove(0);
+        remainder.push(0);
+        for (r, &g) in remainder.iter_mut().zip(generator.iter()) {
+            *r ^= multiply(g, factor);
+        }
+    }
+    remainder
+}
+
+// The modules of the smallest code that can hold the text, or `None` if it's too long.
+fn encode(text: &[u8]) -> Option<Vec<Vec<bool>>> {
+    let (version, &(data_len, ecc_len)) = CODEWORDS
+        .iter()
+        .enumerate()
+        .find(|(_, (data_len, _))| text.len() + 2 <= *data_len)?;
+
+    // byte mode, the length, the text itself, and the terminator (which fits, as there's room
+    // for the padding byte checked above)
+    let mut data = vec![0x40 | (text.len() >> 4) as u8];
+    let mut last = (text.len() as u8) << 4;
+    for &byte in text {
+        data.push(last | byte >> 4);
+        last = byte << 4;
+    }
+    data.push(last);
+    data.extend(PAD_BYTES.iter().cycle().take(data_len - data.len()));
+
+    let mut codewords = data.clone();
+    codewords.extend(error_correction(&data, ecc_len));
+
+    (0..8)
+        .map(|mask| {
+            let mut code = Code::new(version + 1);
+            code.place(&codewords);
+            code.apply_mask(mask);
+            code.format(mask);
+            code
+        })
+        .min_by_key(Code::penalty)
+        .map(|code| code.dark)
+}
+
+/// Draws the text as a QR code using half blocks, two rows per line, or returns `None`
+/// if it doesn't fit. The colors are set explicitly, since the code must be dark on light.
+pub fn render(text: &str) -> Option<String> {
+    let modules = encode(text.as_bytes())?;
+    let size = modules.len() + 2 * QUIET_ZONE;
+    let dark = |x: usize, y: usize| {
+        (QUIET_ZONE..size - QUIET_ZONE).contains(&x)
+            && (QUIET_ZONE..size - QUIET_ZONE).contains(&y)
+            && modules[y - QUIET_ZONE][x - QUIET_ZONE]
+    };
+
+    let mut out = String::new();
+    for y in (0..size).step_by(2) {
+        out.push_str("\x1b[30;107m");
+        for x in 0..size {
+            out.push(match (dark(x, y), dark(x, y + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        out.push_str("\x1b[0m\n");
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use qrcodegen::{QrCode, QrCodeEcc, QrSegment, Version};
+
+    // The modules of the code a reference encoder makes for the text, with the same version,
+    // error correction level and mode (and the mask it picks with the same penalties).
+    fn reference(text: &[u8], version: u8) -> Vec<Vec<bool>> {
+        let segments = [QrSegment::make_bytes(text)];
+        let version = Version::new(version);
+        let code = QrCode::encode_segments_advanced(
+            &segments,
+            QrCodeEcc::Low,
+            version,
+            version,
+            None,
+            false,
+        )
+        .unwrap();
+        let size = code.size();
+        (0..size)
+            .map(|y| (0..size).map(|x| code.get_module(x, y)).collect())
+            .collect()
+    }
+
+    #[test]
+    fn codes_match_the_reference() {
+        let texts: [&[u8]; 7] = [
+            b"",
+            b"sf://192.168.1.2:8370",
+            b"sf://192.168.1.2:8370/0123456789abcdef",
+            b"sf://192.168.100.200:65535/00112233445566778899aabbccddeeff",
+            b"sf://[fe80::1ff:fe23:4567:890a]:8370/00112233445566778899aabbccddeeff",
+            &[0xff; 80],
+            &[0x5f; 106],
+        ];
+        for text in texts {
+            let modules = encode(text).unwrap();
+            let version = (modules.len() - 17) / 4;
+            assert!(
+                modules == reference(text, version as u8),
+                "{:?} was drawn in a different way than the reference",
+                String::from_utf8_lossy(text)
+            );
+        }
+    }
+
+    // Every version is used, and no more than fits in the largest.
+    #[test]
+    fn the_smallest_version_that_fits_is_used() {
+        for (version, &(data_len, _)) in CODEWORDS.iter().enumerate() {
+            let fits = vec![b'x'; data_len - 2];
+            assert_eq!(encode(&fits).unwrap().len(), 17 + 4 * (version + 1));
+        }
+        assert!(encode(&[b'x'; 107]).is_none());
+    }
+}
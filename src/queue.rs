@@ -0,0 +1,208 @@
+//! A list of send jobs kept in a file, to be run one after another (or a few at once) by a
+//! single long-running process, trying those that fail again as many times as each allows.
+//!
+//! It's a JSON object with the `jobs`, each with its `id`, `state`, the `args` to run sf
+//! with and the `dir` to run it in, how many `retries` it allows and the `retry_delay`
+//! between them (in seconds), the `attempts` made so far, when the `next_attempt` can be
+//! made (in seconds since the epoch) and the `error` of the last one, if it failed.
+
+use crate::{json, Error, Result};
+use std::env;
+use std::fmt::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+// Bumped whenever the format changes in a way older readers can't handle
+const QUEUE_VERSION: u64 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting for its turn, or for the time to try again.
+    Pending,
+    Running,
+    Done,
+    /// Failed as many times as it was allowed to.
+    Failed,
+}
+
+impl JobState {
+    const ALL: [JobState; 4] = [
+        JobState::Pending,
+        JobState::Running,
+        JobState::Done,
+        JobState::Failed,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            JobState::Pending => "pending",
+            JobState::Running => "running",
+            JobState::Done => "done",
+            JobState::Failed => "failed",
+        }
+    }
+}
+
+impl fmt::Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A transfer waiting in the queue, or that already ran.
+#[derive(Clone, Debug)]
+pub struct Job {
+    pub id: u64,
+    pub state: JobState,
+    /// The arguments to run sf with, which send files to a receiver.
+    pub args: Vec<String>,
+    /// Where to run it, which the paths in the arguments may be relative to.
+    pub dir: PathBuf,
+    /// How many more times to try after the first attempt fails.
+    pub retries: u64,
+    /// How long to wait before trying again.
+    pub retry_delay: Duration,
+    pub attempts: u64,
+    /// When the next attempt can be made, in seconds since the epoch.
+    pub next_attempt: u64,
+    /// Why the last attempt failed, if it did.
+    pub error: Option<String>,
+}
+
+/// Where the queue is kept unless told otherwise, in the directory for the state of the
+/// programs of the user.
+pub fn default_path() -> Option<PathBuf> {
+    Some(state_dir()?.join("sf").join("queue.json"))
+}
+
+// The directory for the state of the programs of the user.
+pub(crate) fn state_dir() -> Option<PathBuf> {
+    if cfg!(windows) {
+        Some(PathBuf::from(env::var_os("LOCALAPPDATA")?))
+    } else if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(PathBuf::from(dir))
+    } else {
+        Some(
+            Path::new(&env::var_os("HOME")?)
+                .join(".local")
+                .join("state"),
+        )
+    }
+}
+
+/// Reads the queue at `path`, which is empty if there's no file yet.
+pub fn load(path: &Path) -> Result<Vec<Job>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let text = fs::read_to_string(path).map_err(|e| Error::from(e).at(path))?;
+    let invalid = |reason: &str| Error::Other(format!("invalid queue {:?}: {}", path, reason));
+
+    let queue = json::parse(&text).ok_or_else(|| invalid("not valid JSON"))?;
+    match queue.get("sf_queue").and_then(json::Value::as_u64) {
+        Some(QUEUE_VERSION) => {}
+        Some(_) => return Err(invalid("made by a newer version")),
+        None => return Err(invalid("not a queue")),
+    }
+    let jobs = queue
+        .get("jobs")
+        .and_then(json::Value::as_array)
+        .ok_or_else(|| invalid("no jobs"))?;
+
+    jobs.iter()
+        .map(|job| {
+            let number = |key: &str| {
+                job.get(key)
+                    .and_then(json::Value::as_u64)
+                    .ok_or_else(|| invalid(&format!("a job has no {}", key)))
+            };
+            let text = |key: &str| {
+                job.get(key)
+                    .and_then(json::Value::as_str)
+                    .ok_or_else(|| invalid(&format!("a job has no {}", key)))
+            };
+            let state = text("state")?;
+            Ok(Job {
+                id: number("id")?,
+                state: JobState::ALL
+                    .iter()
+                    .copied()
+                    .find(|known| known.name() == state)
+                    .ok_or_else(|| invalid(&format!("unknown state {:?}", state)))?,
+                args: job
+                    .get("args")
+                    .and_then(json::Value::as_array)
+                    .and_then(|args| {
+                        args.iter()
+                            .map(|arg| arg.as_str().map(str::to_owned))
+                            .collect()
+                    })
+                    .ok_or_else(|| invalid("a job has no args"))?,
+                dir: PathBuf::from(text("dir")?),
+                retries: number("retries")?,
+                retry_delay: Duration::from_secs(number("retry_delay")?),
+                attempts: number("attempts")?,
+                next_attempt: number("next_attempt")?,
+                error: job
+                    .get("error")
+                    .and_then(json::Value::as_str)
+                    .map(str::to_owned),
+            })
+        })
+        .collect()
+}
+
+/// Writes the queue to `path`, replacing what was there only once it's all written.
+pub fn save(path: &Path, jobs: &[Job]) -> Result<()> {
+    let mut out = format!("{{\n  \"sf_queue\": {},\n  \"jobs\": [", QUEUE_VERSION);
+    for (i, job) in jobs.iter().enumerate() {
+        let args = job
+            .args
+            .iter()
+            .map(|arg| json::string(arg))
+            .collect::<Vec<_>>();
+        let _ = write!(
+            out,
+            "{}\n    {{\"id\": {}, \"state\": \"{}\", \"args\": [{}], \"dir\": {}, ",
+            if i == 0 { "" } else { "," },
+            job.id,
+            job.state,
+            args.join(", "),
+            json::string(&job.dir.to_string_lossy())
+        );
+        let _ = write!(
+            out,
+            "\"retries\": {}, \"retry_delay\": {}, \"attempts\": {}, \"next_attempt\": {}",
+            job.retries,
+            job.retry_delay.as_secs(),
+            job.attempts,
+            job.next_attempt
+        );
+        if let Some(error) = &job.error {
+            let _ = write!(out, ", \"error\": {}", json::string(error));
+        }
+        out.push('}');
+    }
+    out.push_str("\n  ]\n}\n");
+
+    if let Some(parent) = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+    {
+        fs::create_dir_all(parent).map_err(|e| Error::from(e).at(parent))?;
+    }
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".tmp");
+    let partial = PathBuf::from(partial);
+    fs::write(&partial, out).map_err(|e| Error::from(e).at(&partial))?;
+    fs::rename(&partial, path).map_err(|e| Error::from(e).at(path))
+}
+
+/// Changes the queue at `path` as it's now, so that what others changed meanwhile is kept.
+pub fn update<T>(path: &Path, change: impl FnOnce(&mut Vec<Job>) -> T) -> Result<T> {
+    let mut jobs = load(path)?;
+    let result = change(&mut jobs);
+    save(path, &jobs)?;
+    Ok(result)
+}
@@ -0,0 +1,25 @@
+//! Random bytes from the system, unpredictable enough to be used as keys.
+
+use std::io;
+
+/// Fills the buffer with random bytes.
+#[cfg(unix)]
+pub fn fill(buf: &mut [u8]) -> io::Result<()> {
+    use std::fs::File;
+    use std::io::Read;
+
+    File::open("/dev/urandom")?.read_exact(buf)
+}
+
+/// Fills the buffer with random bytes.
+#[cfg(windows)]
+pub fn fill(buf: &mut [u8]) -> io::Result<()> {
+    use winapi::um::ntsecapi::RtlGenRandom;
+
+    for chunk in buf.chunks_mut(u32::MAX as usize) {
+        if unsafe { RtlGenRandom(chunk.as_mut_ptr().cast(), chunk.len() as u32) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Copies that share the data of the original until either of them changes, on the file
+//! systems that can make them (such as Btrfs, XFS and APFS).
+
+use std::io;
+use std::path::Path;
+
+/// Makes `path`, which must not exist, a copy of the file at `source` sharing its data.
+/// Fails with `Unsupported` where the system can't, and with some other error where the file
+/// system can't or the files are on different ones.
+pub(crate) fn clone_file(source: &Path, path: &Path) -> io::Result<()> {
+    sys::clone_file(source, path)
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::fs::{self, File, OpenOptions};
+    use std::io;
+    use std::os::raw::{c_int, c_ulong};
+    use std::os::unix::io::AsRawFd;
+    use std::path::Path;
+
+    // linux/fs.h, _IOW(0x94, 9, int)
+    const FICLONE: c_ulong = 0x40049409;
+
+    extern "C" {
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    pub fn clone_file(source: &Path, path: &Path) -> io::Result<()> {
+        let source = File::open(source)?;
+        let f = OpenOptions::new().write(true).create_new(true).open(path)?;
+        if unsafe { ioctl(f.as_raw_fd(), FICLONE, source.as_raw_fd()) } != 0 {
+            let e = io::Error::last_os_error();
+            drop(f);
+            let _ = fs::remove_file(path);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::{c_char, c_int};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    extern "C" {
+        fn clonefile(src: *const c_char, dst: *const c_char, flags: u32) -> c_int;
+    }
+
+    pub fn clone_file(source: &Path, path: &Path) -> io::Result<()> {
+        let c_path = |path: &Path| {
+            CString::new(path.as_os_str().as_bytes())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+        };
+        let (source, path) = (c_path(source)?, c_path(path)?);
+        if unsafe { clonefile(source.as_ptr(), path.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn clone_file(_source: &Path, _path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "files can't be cloned on this system",
+        ))
+    }
+}
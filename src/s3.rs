@@ -0,0 +1,470 @@
+//! Storing the received files in an S3-compatible object storage, such as MinIO on the same
+//! network. Each file becomes an object, uploaded with a single `PUT` signed with AWS
+//! Signature Version 4.
+//!
+//! Over `https://` the data is sent as it arrives, leaving it out of the signature as TLS
+//! already keeps it from being changed on the way. Plain `http://` endpoints have nothing else
+//! to protect it, so every file is written to a temporary file first, to sign its hash too.
+
+use crate::hash::{self, Digest};
+use crate::manifest::to_hex;
+use crate::names;
+use crate::sink::Sink;
+use aws_credential_types::Credentials;
+use aws_sigv4::http_request::{
+    self, PayloadChecksumKind, PercentEncodingMode, SignableBody, SignableRequest, SigningSettings,
+    UriPathNormalizationMode,
+};
+use aws_sigv4::sign::v4::SigningParams;
+use aws_smithy_runtime_api::client::identity::Identity;
+use std::env;
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+const DEFAULT_REGION: &str = "us-east-1";
+// Objects larger than this need a multipart upload, which is not done
+const MAX_OBJECT_LEN: u64 = 5 * 1024 * 1024 * 1024;
+// More than enough for the error of a failed upload
+const MAX_ERROR_LEN: u64 = 4096;
+// How long the storage may go without reading or answering before giving up on it
+const TIMEOUT: Duration = Duration::from_secs(60);
+// How many chunks may be on their way to the request at once
+const QUEUED_CHUNKS: usize = 4;
+
+// Tells the spools of the same process apart.
+static SPOOLS: AtomicUsize = AtomicUsize::new(0);
+
+/// Uploads every file as an object into a bucket.
+pub struct S3 {
+    // where the bucket is, like https://host:9000/bucket
+    bucket_url: String,
+    secure: bool,
+    prefix: String,
+    region: String,
+    identity: Identity,
+    agent: ureq::Agent,
+    // the upload of the current entry
+    current: Option<Upload>,
+}
+
+// An object on its way to the bucket.
+enum Upload {
+    // sent as it's written, with the request made on a thread of its own
+    Streaming {
+        data: SyncSender<Vec<u8>>,
+        request: JoinHandle<io::Result<()>>,
+    },
+    // written to a file first, and sent once its hash is known
+    Spooled {
+        object: Object,
+        spool: PathBuf,
+        out: hash::Writer<BufWriter<File>>,
+    },
+}
+
+// Where an object goes, and what it's stored with.
+struct Object {
+    url: String,
+    len: u64,
+    modified: Option<u64>,
+}
+
+impl S3 {
+    /// Uploads into the bucket at the `url`, like `https://host:9000/bucket/some/prefix`,
+    /// with the credentials in the usual `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY` and,
+    /// if set, `AWS_SESSION_TOKEN` and `AWS_REGION` environment variables.
+    pub fn from_env(url: &str) -> io::Result<Self> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        let (scheme, rest) = url
+            .split_once("://")
+            .filter(|(scheme, _)| ["http", "https"].contains(scheme))
+            .ok_or_else(|| invalid("only http:// and https:// endpoints are supported"))?;
+        let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        if host.is_empty() || bucket.is_empty() {
+            return Err(invalid("the url must have a host and a bucket"));
+        }
+        let var =
+            |name: &str| env::var(name).map_err(|_| invalid(&format!("{} must be set", name)));
+
+        let mut prefix = prefix.trim_matches('/').to_string();
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        let credentials = Credentials::new(
+            var("AWS_ACCESS_KEY_ID")?,
+            var("AWS_SECRET_ACCESS_KEY")?,
+            env::var("AWS_SESSION_TOKEN").ok(),
+            None,
+            "environment",
+        );
+        Ok(S3 {
+            bucket_url: format!("{}://{}/{}", scheme, host, uri_encode(bucket)),
+            secure: scheme == "https",
+            prefix,
+            region: env::var("AWS_REGION").unwrap_or_else(|_| DEFAULT_REGION.to_string()),
+            identity: credentials.into(),
+            agent: ureq::AgentBuilder::new()
+                .timeout_connect(TIMEOUT)
+                .timeout_read(TIMEOUT)
+                .timeout_write(TIMEOUT)
+                .build(),
+            current: None,
+        })
+    }
+
+    // The `PUT` of the `object`, signed along with the hash of its data if it's known.
+    fn request(&self, object: &Object, payload: Option<&Digest>) -> io::Result<ureq::Request> {
+        let len = object.len.to_string();
+        let modified = object.modified.map(|modified| modified.to_string());
+        let mut headers = vec![("content-length", len.as_str())];
+        if let Some(modified) = &modified {
+            headers.push(("x-amz-meta-mtime", modified));
+        }
+        let payload = payload.map(|digest| to_hex(digest));
+        let body = match &payload {
+            Some(hash) => SignableBody::Precomputed(hash.clone()),
+            None => SignableBody::UnsignedPayload,
+        };
+
+        // S3 takes the path as it is, rather than encoding and normalizing it again
+        let mut settings = SigningSettings::default();
+        settings.percent_encoding_mode = PercentEncodingMode::Single;
+        settings.uri_path_normalization_mode = UriPathNormalizationMode::Disabled;
+        settings.payload_checksum_kind = PayloadChecksumKind::XAmzSha256;
+        let params = SigningParams::builder()
+            .identity(&self.identity)
+            .region(&self.region)
+            .name("s3")
+            .time(SystemTime::now())
+            .settings(settings)
+            .build()
+            .map_err(io::Error::other)?
+            .into();
+        let signable = SignableRequest::new("PUT", &object.url, headers.iter().copied(), body)
+            .map_err(io::Error::other)?;
+        let (instructions, _) = http_request::sign(signable, &params)
+            .map_err(io::Error::other)?
+            .into_parts();
+
+        let mut request = self.agent.put(&object.url);
+        for (name, value) in headers.iter().copied().chain(instructions.headers()) {
+            request = request.set(name, value);
+        }
+        Ok(request)
+    }
+}
+
+impl Sink for S3 {
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()> {
+        if len > MAX_OBJECT_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "files larger than 5 GiB can't be uploaded in a single request",
+            ));
+        }
+        let path = names::relative_path(name)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "name goes up"))?;
+        let key = format!(
+            "{}{}",
+            self.prefix,
+            names::display(&names::wire_name(&path))
+        );
+        let object = Object {
+            url: format!("{}/{}", self.bucket_url, uri_encode(&key)),
+            len,
+            modified,
+        };
+
+        if self.secure {
+            let request = self.request(&object, None)?;
+            let (data, chunks) = mpsc::sync_channel(QUEUED_CHUNKS);
+            let request = thread::spawn(move || {
+                let body = Chunks {
+                    chunks,
+                    current: io::Cursor::new(Vec::new()),
+                    left: len,
+                };
+                response(request.send(body))
+            });
+            self.current = Some(Upload::Streaming { data, request });
+        } else {
+            let spool = env::temp_dir().join(format!(
+                "sf-s3-{}-{}",
+                std::process::id(),
+                SPOOLS.fetch_add(1, Ordering::Relaxed)
+            ));
+            let f = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(&spool)?;
+            self.current = Some(Upload::Spooled {
+                object,
+                spool,
+                out: hash::Writer::new(BufWriter::new(f), true),
+            });
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        match &mut self.current {
+            Some(Upload::Streaming { data: sender, .. }) => sender
+                .send(data.to_vec())
+                .map_err(|_| io::Error::other("the upload stopped early")),
+            Some(Upload::Spooled { out, .. }) => out.write_all(data),
+            None => Err(io::Error::other("no entry is open")),
+        }
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        match self.current.take() {
+            Some(Upload::Streaming { data, request }) => {
+                drop(data);
+                request
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("the upload panicked")))
+            }
+            Some(Upload::Spooled { object, spool, out }) => {
+                let uploaded = (|| {
+                    let (out, digest) = out.finish();
+                    let mut f = out.into_inner().map_err(|e| e.into_error())?;
+                    f.seek(SeekFrom::Start(0))?;
+                    let request = self.request(&object, digest.as_ref())?;
+                    response(request.send(f))
+                })();
+                let _ = fs::remove_file(spool);
+                uploaded
+            }
+            None => Err(io::Error::other("no entry is open")),
+        }
+    }
+
+    fn abort_entry(&mut self) {
+        match self.current.take() {
+            // the request sees its body end early, and gives up rather than send it short
+            Some(Upload::Streaming { data, request }) => {
+                drop(data);
+                let _ = request.join();
+            }
+            Some(Upload::Spooled { spool, out, .. }) => {
+                drop(out);
+                let _ = fs::remove_file(spool);
+            }
+            None => {}
+        }
+    }
+}
+
+impl Drop for S3 {
+    fn drop(&mut self) {
+        self.abort_entry();
+    }
+}
+
+// The data of an entry as it's written, which must be as long as it said.
+struct Chunks {
+    chunks: Receiver<Vec<u8>>,
+    current: io::Cursor<Vec<u8>>,
+    left: u64,
+}
+
+impl Read for Chunks {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n != 0 || buf.is_empty() {
+                self.left = self.left.saturating_sub(n as u64);
+                return Ok(n);
+            }
+            match self.chunks.recv() {
+                Ok(chunk) => self.current = io::Cursor::new(chunk),
+                Err(_) if self.left == 0 => return Ok(0),
+                Err(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "the entry was given up on",
+                    ))
+                }
+            }
+        }
+    }
+}
+
+// Whether the upload went through, or why it didn't, which the storage says in the body.
+fn response(result: Result<ureq::Response, ureq::Error>) -> io::Result<()> {
+    match result {
+        Ok(_) => Ok(()),
+        Err(ureq::Error::Status(code, response)) => {
+            let status = format!("{} {}", code, response.status_text());
+            let mut body = String::new();
+            let _ = response
+                .into_reader()
+                .take(MAX_ERROR_LEN)
+                .read_to_string(&mut body);
+            Err(io::Error::other(format!(
+                "upload failed with {}: {}",
+                status,
+                body.trim()
+            )))
+        }
+        Err(ureq::Error::Transport(e)) => Err(io::Error::other(e)),
+    }
+}
+
+// Percent-encode everything but the unreserved characters, and the slashes between segments.
+fn uri_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for &b in text.as_bytes() {
+        if b.is_ascii_alphanumeric() || b"-_.~/".contains(&b) {
+            encoded.push(b as char);
+        } else {
+            let _ = write!(encoded, "%{:02X}", b);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    // What a request to the storage came with.
+    struct Received {
+        request_line: String,
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    }
+
+    impl Received {
+        fn header(&self, name: &str) -> Option<&str> {
+            let header = self.headers.iter().find(|(k, _)| k == name);
+            header.map(|(_, v)| v.as_str())
+        }
+    }
+
+    // Answer a single request with the `status` and `body`, and return what it came with.
+    fn serve_once(
+        listener: TcpListener,
+        status: &'static str,
+        body: &'static str,
+    ) -> JoinHandle<Received> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                match line.trim_end().split_once(':') {
+                    Some((k, v)) => headers.push((k.to_ascii_lowercase(), v.trim().to_string())),
+                    None => break,
+                }
+            }
+            let mut received = Received {
+                request_line: request_line.trim_end().to_string(),
+                headers,
+                body: Vec::new(),
+            };
+            let len = received.header("content-length").unwrap().parse().unwrap();
+            received.body = vec![0; len];
+            reader.read_exact(&mut received.body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            )
+            .unwrap();
+            received
+        })
+    }
+
+    // Objects sent to a plain http endpoint are signed along with their data, which must be
+    // spooled to know its hash before sending.
+    #[test]
+    fn uploads_are_signed_with_their_data() {
+        env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE");
+        env::set_var(
+            "AWS_SECRET_ACCESS_KEY",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut s3 = S3::from_env(&format!("http://{}/bucket/in/", addr)).unwrap();
+
+        let server = serve_once(listener, "200 OK", "");
+        let data = b"hello, world\n".repeat(1000);
+        s3.open_entry(
+            b"dir/hello world.txt",
+            data.len() as u64,
+            Some(1_600_000_000),
+        )
+        .unwrap();
+        for chunk in data.chunks(1000) {
+            s3.write_chunk(chunk).unwrap();
+        }
+        s3.close_entry().unwrap();
+        let received = server.join().unwrap();
+        assert_eq!(
+            received.request_line,
+            "PUT /bucket/in/dir/hello%20world.txt HTTP/1.1"
+        );
+        assert!(received.body == data);
+        let mut hashed = hash::Writer::new(io::sink(), true);
+        hashed.write_all(&data).unwrap();
+        let digest = to_hex(&hashed.finish().1.unwrap());
+        assert_eq!(received.header("x-amz-content-sha256"), Some(&*digest));
+        assert_eq!(received.header("x-amz-meta-mtime"), Some("1600000000"));
+        let authorization = received.header("authorization").unwrap();
+        assert!(
+            authorization.starts_with("AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/"),
+            "{}",
+            authorization
+        );
+        assert!(authorization.contains("x-amz-content-sha256"));
+
+        // and why the storage refused one is said
+        let listener = TcpListener::bind(addr).unwrap();
+        let server = serve_once(listener, "403 Forbidden", "<Code>AccessDenied</Code>");
+        s3.open_entry(b"denied", 1, None).unwrap();
+        s3.write_chunk(b"x").unwrap();
+        let e = s3.close_entry().unwrap_err().to_string();
+        assert!(e.contains("403") && e.contains("AccessDenied"), "{}", e);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn only_buckets_on_the_web_are_taken() {
+        env::set_var("AWS_ACCESS_KEY_ID", "AKIDEXAMPLE");
+        env::set_var(
+            "AWS_SECRET_ACCESS_KEY",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+        );
+        for url in [
+            "https://s3.example.com/bucket",
+            "http://localhost:9000/bucket/a/b",
+        ] {
+            assert!(S3::from_env(url).is_ok(), "{}", url);
+        }
+        for url in [
+            "ftp://example.com/bucket",
+            "https://example.com",
+            "https:///bucket",
+        ] {
+            assert!(S3::from_env(url).is_err(), "{}", url);
+        }
+    }
+}
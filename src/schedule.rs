@@ -0,0 +1,131 @@
+//! Times of the day, in local time, at which transfers start or are turned away, so that big
+//! transfers can be left to run overnight.
+
+use std::fmt;
+use std::time::Duration;
+
+const SECS_PER_DAY: u32 = 24 * 60 * 60;
+
+/// A time of every day, to the second.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeOfDay(u32);
+
+impl TimeOfDay {
+    /// Parses a time like `02:00` or `23:59:30`.
+    pub fn parse(text: &str) -> Option<TimeOfDay> {
+        let mut parts = text.split(':');
+        let mut next = |max: u32| parts.next()?.parse::<u32>().ok().filter(|&n| n < max);
+        let hours = next(24)?;
+        let minutes = next(60)?;
+        let seconds = match parts.next() {
+            Some(seconds) => seconds.parse::<u32>().ok().filter(|&n| n < 60)?,
+            None => 0,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(TimeOfDay(hours * 3600 + minutes * 60 + seconds))
+    }
+
+    /// The time of the day it is now.
+    pub fn now() -> TimeOfDay {
+        TimeOfDay(secs_since_midnight() % SECS_PER_DAY)
+    }
+
+    /// The time of the day it is once the duration has passed from this one.
+    pub fn later(self, by: Duration) -> TimeOfDay {
+        TimeOfDay(((self.0 as u64 + by.as_secs()) % SECS_PER_DAY as u64) as u32)
+    }
+
+    /// How long it is from `earlier` until this time of the day comes next, less than a day.
+    pub fn since(self, earlier: TimeOfDay) -> Duration {
+        Duration::from_secs(((self.0 + SECS_PER_DAY - earlier.0) % SECS_PER_DAY) as u64)
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.0 / 3600, self.0 / 60 % 60)?;
+        if self.0 % 60 != 0 {
+            write!(f, ":{:02}", self.0 % 60)?;
+        }
+        Ok(())
+    }
+}
+
+/// A span of every day, from its start up to its end, which may be past midnight.
+#[derive(Clone, Copy, Debug)]
+pub struct DailyWindow {
+    pub start: TimeOfDay,
+    pub end: TimeOfDay,
+}
+
+impl DailyWindow {
+    /// Parses a window like `08:00-18:00` or `22:00-06:00`.
+    pub fn parse(text: &str) -> Option<DailyWindow> {
+        let (start, end) = text.split_once('-')?;
+        Some(DailyWindow {
+            start: TimeOfDay::parse(start)?,
+            end: TimeOfDay::parse(end)?,
+        })
+    }
+
+    /// How long until the window ends, if `now` is inside it.
+    pub fn remaining(&self, now: TimeOfDay) -> Option<Duration> {
+        (now.since(self.start) < self.end.since(self.start)).then(|| self.end.since(now))
+    }
+}
+
+impl fmt::Display for DailyWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+// The seconds since midnight, in the local time zone.
+#[cfg(unix)]
+fn secs_since_midnight() -> u32 {
+    use std::os::raw::{c_char, c_int, c_long};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    #[repr(C)]
+    struct Tm {
+        tm_sec: c_int,
+        tm_min: c_int,
+        tm_hour: c_int,
+        tm_mday: c_int,
+        tm_mon: c_int,
+        tm_year: c_int,
+        tm_wday: c_int,
+        tm_yday: c_int,
+        tm_isdst: c_int,
+        tm_gmtoff: c_long,
+        tm_zone: *const c_char,
+    }
+
+    extern "C" {
+        fn localtime_r(time: *const c_long, result: *mut Tm) -> *mut Tm;
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let mut tm = std::mem::MaybeUninit::<Tm>::uninit();
+    let local = unsafe { localtime_r(&(now as c_long), tm.as_mut_ptr()) };
+    if local.is_null() {
+        // off by the offset of the time zone, which is better than nothing
+        return (now % SECS_PER_DAY as u64) as u32;
+    }
+    let tm = unsafe { tm.assume_init() };
+    (tm.tm_hour * 3600 + tm.tm_min * 60 + tm.tm_sec) as u32
+}
+
+#[cfg(windows)]
+fn secs_since_midnight() -> u32 {
+    use winapi::um::minwinbase::SYSTEMTIME;
+    use winapi::um::sysinfoapi::GetLocalTime;
+
+    let mut time: SYSTEMTIME = unsafe { std::mem::zeroed() };
+    unsafe { GetLocalTime(&mut time) };
+    time.wHour as u32 * 3600 + time.wMinute as u32 * 60 + time.wSecond as u32
+}
@@ -0,0 +1,444 @@
+//! Running the receiver under a service manager, such as systemd or the one of Windows.
+
+use sf::CancelToken;
+use std::io;
+use std::net::TcpListener;
+use std::sync::OnceLock;
+#[cfg(windows)]
+use std::{env, ptr};
+
+// Cancelled once the process is asked to terminate.
+static TOKEN: OnceLock<CancelToken> = OnceLock::new();
+
+/// The listening socket handed over by systemd through socket activation, if any.
+#[cfg(unix)]
+pub fn inherited_listener() -> Option<TcpListener> {
+    use std::env;
+    use std::os::unix::io::FromRawFd;
+
+    // sd_listen_fds(3)
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid = env::var("LISTEN_PID").ok()?.parse::<u32>().ok()?;
+    let fds = env::var("LISTEN_FDS").ok()?.parse::<u32>().ok()?;
+    // the variables are meant for this process only, and not the ones it starts
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+    if pid != std::process::id() || fds == 0 {
+        return None;
+    }
+    if fds > 1 {
+        eprintln!("only the first of the {} sockets handed over is used", fds);
+    }
+    Some(unsafe { TcpListener::from_raw_fd(LISTEN_FDS_START) })
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listener() -> Option<TcpListener> {
+    None
+}
+
+/// A token which is cancelled once the process is asked to terminate.
+#[cfg(unix)]
+pub fn cancel_on_terminate() -> CancelToken {
+    use std::thread;
+    use tokio::runtime::Builder;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let token = TOKEN.get_or_init(CancelToken::new).clone();
+    // cancelling wakes up the transfers, which can't be done from the signal handler itself, so
+    // the handler tokio installs only takes note of the signal, and the cancelling is done by a
+    // thread of its own once it hears of it
+    let terminate = Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .and_then(|runtime| {
+            // installed right away, so that terminating early still cancels
+            let terminate = {
+                let _context = runtime.enter();
+                signal(SignalKind::terminate())?
+            };
+            Ok((runtime, terminate))
+        });
+    match terminate {
+        Ok((runtime, mut terminate)) => {
+            let cancel = token.clone();
+            thread::spawn(move || {
+                if runtime.block_on(terminate.recv()).is_some() {
+                    cancel.cancel();
+                }
+            });
+        }
+        Err(e) => eprintln!("cannot stop cleanly once asked to terminate: {}", e),
+    }
+    token
+}
+
+/// A token which is cancelled once the service is asked to stop, if running as one.
+#[cfg(not(unix))]
+pub fn cancel_on_terminate() -> CancelToken {
+    TOKEN.get_or_init(CancelToken::new).clone()
+}
+
+/// The name the receiver is installed as a Windows service with.
+pub const SERVICE_NAME: &str = "sf";
+
+/// Installs the receiver as a Windows service that starts with the system, running with the
+/// given arguments, and starts it.
+#[cfg(windows)]
+pub fn install(args: &[String]) -> io::Result<()> {
+    use winapi::um::winnt::{SERVICE_AUTO_START, SERVICE_ERROR_NORMAL, SERVICE_WIN32_OWN_PROCESS};
+    use winapi::um::winsvc::{
+        ChangeServiceConfig2W, CreateServiceW, StartServiceW, SC_MANAGER_CREATE_SERVICE,
+        SERVICE_ALL_ACCESS, SERVICE_CONFIG_DESCRIPTION, SERVICE_DESCRIPTIONW,
+    };
+
+    let exe = env::current_exe()?;
+    let mut command = quote(&exe.to_string_lossy());
+    for arg in ["service", "run"]
+        .iter()
+        .copied()
+        .chain(args.iter().map(String::as_str))
+    {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+
+    let manager = Handle::manager(SC_MANAGER_CREATE_SERVICE)?;
+    let name = wide(SERVICE_NAME);
+    let service = Handle::check(unsafe {
+        CreateServiceW(
+            manager.0,
+            name.as_ptr(),
+            wide("sf receiver").as_ptr(),
+            SERVICE_ALL_ACCESS,
+            SERVICE_WIN32_OWN_PROCESS,
+            SERVICE_AUTO_START,
+            SERVICE_ERROR_NORMAL,
+            wide(&command).as_ptr(),
+            ptr::null(),
+            ptr::null_mut(),
+            ptr::null(),
+            ptr::null(),
+            ptr::null(),
+        )
+    })?;
+    // nothing depends on the description being there
+    let mut description = wide("Receives the files sent with sf from the local network");
+    let mut info = SERVICE_DESCRIPTIONW {
+        lpDescription: description.as_mut_ptr(),
+    };
+    unsafe {
+        ChangeServiceConfig2W(
+            service.0,
+            SERVICE_CONFIG_DESCRIPTION,
+            &mut info as *mut _ as *mut _,
+        )
+    };
+    if unsafe { StartServiceW(service.0, 0, ptr::null_mut()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Stops the service if it's running, and removes it.
+#[cfg(windows)]
+pub fn uninstall() -> io::Result<()> {
+    use winapi::shared::winerror::ERROR_SERVICE_NOT_ACTIVE;
+    use winapi::um::winnt::DELETE;
+    use winapi::um::winsvc::{
+        ControlService, DeleteService, OpenServiceW, SC_MANAGER_CONNECT, SERVICE_CONTROL_STOP,
+        SERVICE_STATUS, SERVICE_STOP,
+    };
+
+    let manager = Handle::manager(SC_MANAGER_CONNECT)?;
+    let service = Handle::check(unsafe {
+        OpenServiceW(
+            manager.0,
+            wide(SERVICE_NAME).as_ptr(),
+            SERVICE_STOP | DELETE,
+        )
+    })?;
+    let mut status: SERVICE_STATUS = unsafe { std::mem::zeroed() };
+    if unsafe { ControlService(service.0, SERVICE_CONTROL_STOP, &mut status) } == 0 {
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(ERROR_SERVICE_NOT_ACTIVE as i32) {
+            return Err(e);
+        }
+    }
+    if unsafe { DeleteService(service.0) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Runs the receiver under the Windows service manager, telling it when the service is
+/// running and when it's done. Asking the service to stop cancels the token given by
+/// [`cancel_on_terminate`]. Returns the code to exit with.
+#[cfg(windows)]
+pub fn dispatch(receive: impl FnOnce() -> i32 + Send + 'static) -> io::Result<i32> {
+    use winapi::um::winsvc::{StartServiceCtrlDispatcherW, SERVICE_TABLE_ENTRYW};
+
+    *RECEIVE.lock().unwrap() = Some(Box::new(receive));
+    let mut name = wide(SERVICE_NAME);
+    let table = [
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: name.as_mut_ptr(),
+            lpServiceProc: Some(service_main),
+        },
+        SERVICE_TABLE_ENTRYW {
+            lpServiceName: ptr::null_mut(),
+            lpServiceProc: None,
+        },
+    ];
+    // blocks until the service is stopped
+    if unsafe { StartServiceCtrlDispatcherW(table.as_ptr()) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(*EXIT_CODE.lock().unwrap())
+}
+
+#[cfg(windows)]
+type Receive = Box<dyn FnOnce() -> i32 + Send>;
+
+// What the service runs, and what it exited with, since the service manager calls back
+// into functions that can't capture anything.
+#[cfg(windows)]
+static RECEIVE: std::sync::Mutex<Option<Receive>> = std::sync::Mutex::new(None);
+#[cfg(windows)]
+static EXIT_CODE: std::sync::Mutex<i32> = std::sync::Mutex::new(0);
+
+#[cfg(windows)]
+unsafe extern "system" fn service_main(_: u32, _: *mut *mut u16) {
+    use winapi::shared::winerror::{ERROR_SERVICE_SPECIFIC_ERROR, NO_ERROR};
+    use winapi::um::winsvc::{RegisterServiceCtrlHandlerExW, SERVICE_RUNNING, SERVICE_STOPPED};
+
+    let status = RegisterServiceCtrlHandlerExW(
+        wide(SERVICE_NAME).as_ptr(),
+        Some(control_handler),
+        ptr::null_mut(),
+    );
+    if status.is_null() {
+        return;
+    }
+    // the token must exist before the service manager can be told to stop it
+    cancel_on_terminate();
+    set_status(status, SERVICE_RUNNING, NO_ERROR, 0);
+
+    let code = match RECEIVE.lock().unwrap().take() {
+        Some(receive) => receive(),
+        None => 1,
+    };
+    *EXIT_CODE.lock().unwrap() = code;
+    match code {
+        0 => set_status(status, SERVICE_STOPPED, NO_ERROR, 0),
+        code => set_status(
+            status,
+            SERVICE_STOPPED,
+            ERROR_SERVICE_SPECIFIC_ERROR,
+            code as u32,
+        ),
+    }
+}
+
+#[cfg(windows)]
+unsafe extern "system" fn control_handler(
+    control: u32,
+    _: u32,
+    _: *mut std::ffi::c_void,
+    _: *mut std::ffi::c_void,
+) -> u32 {
+    use winapi::shared::winerror::{ERROR_CALL_NOT_IMPLEMENTED, NO_ERROR};
+    use winapi::um::winsvc::{
+        SERVICE_CONTROL_INTERROGATE, SERVICE_CONTROL_SHUTDOWN, SERVICE_CONTROL_STOP,
+    };
+
+    match control {
+        SERVICE_CONTROL_STOP | SERVICE_CONTROL_SHUTDOWN => {
+            if let Some(token) = TOKEN.get() {
+                token.cancel();
+            }
+            NO_ERROR
+        }
+        SERVICE_CONTROL_INTERROGATE => NO_ERROR,
+        _ => ERROR_CALL_NOT_IMPLEMENTED,
+    }
+}
+
+#[cfg(windows)]
+fn set_status(
+    handle: winapi::um::winsvc::SERVICE_STATUS_HANDLE,
+    state: u32,
+    exit_code: u32,
+    specific_exit_code: u32,
+) {
+    use winapi::um::winnt::SERVICE_WIN32_OWN_PROCESS;
+    use winapi::um::winsvc::{
+        SetServiceStatus, SERVICE_ACCEPT_SHUTDOWN, SERVICE_ACCEPT_STOP, SERVICE_RUNNING,
+        SERVICE_STATUS,
+    };
+
+    let mut status = SERVICE_STATUS {
+        dwServiceType: SERVICE_WIN32_OWN_PROCESS,
+        dwCurrentState: state,
+        dwControlsAccepted: if state == SERVICE_RUNNING {
+            SERVICE_ACCEPT_STOP | SERVICE_ACCEPT_SHUTDOWN
+        } else {
+            0
+        },
+        dwWin32ExitCode: exit_code,
+        dwServiceSpecificExitCode: specific_exit_code,
+        dwCheckPoint: 0,
+        dwWaitHint: 0,
+    };
+    unsafe { SetServiceStatus(handle, &mut status) };
+}
+
+/// Where a service logs to, since it has no console to print to.
+#[cfg(windows)]
+pub struct EventLog(winapi::um::winnt::HANDLE);
+
+// the handle can be used from any thread
+#[cfg(windows)]
+unsafe impl Send for EventLog {}
+#[cfg(windows)]
+unsafe impl Sync for EventLog {}
+
+#[cfg(windows)]
+impl EventLog {
+    /// Starts reporting to the Application log, as coming from the service.
+    pub fn open() -> io::Result<Self> {
+        use winapi::um::winbase::RegisterEventSourceW;
+
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), wide(SERVICE_NAME).as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(EventLog(handle))
+    }
+
+    pub fn report(&self, level: log::Level, message: &str) {
+        use winapi::um::winbase::ReportEventW;
+        use winapi::um::winnt::{
+            EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE,
+        };
+
+        let kind = match level {
+            log::Level::Error => EVENTLOG_ERROR_TYPE,
+            log::Level::Warn => EVENTLOG_WARNING_TYPE,
+            _ => EVENTLOG_INFORMATION_TYPE,
+        };
+        let message = wide(message);
+        let mut strings = [message.as_ptr()];
+        unsafe {
+            ReportEventW(
+                self.0,
+                kind,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_mut_ptr(),
+                ptr::null_mut(),
+            )
+        };
+    }
+}
+
+#[cfg(windows)]
+impl Drop for EventLog {
+    fn drop(&mut self) {
+        unsafe { winapi::um::winbase::DeregisterEventSource(self.0) };
+    }
+}
+
+// A handle to the service manager or one of its services, closed once dropped.
+#[cfg(windows)]
+struct Handle(winapi::um::winsvc::SC_HANDLE);
+
+#[cfg(windows)]
+impl Handle {
+    fn manager(access: u32) -> io::Result<Self> {
+        use winapi::um::winsvc::OpenSCManagerW;
+
+        Self::check(unsafe { OpenSCManagerW(ptr::null(), ptr::null(), access) })
+    }
+
+    fn check(handle: winapi::um::winsvc::SC_HANDLE) -> io::Result<Self> {
+        if handle.is_null() {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(Handle(handle))
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for Handle {
+    fn drop(&mut self) {
+        unsafe { winapi::um::winsvc::CloseServiceHandle(self.0) };
+    }
+}
+
+// The text as a null-terminated UTF-16 string.
+#[cfg(windows)]
+fn wide(text: &str) -> Vec<u16> {
+    text.encode_utf16().chain(std::iter::once(0)).collect()
+}
+
+// Quote the argument so that it's read back as is from the command line, as described in
+// "Parsing C++ command-line arguments".
+#[cfg(windows)]
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains([' ', '\t', '"']) {
+        return arg.to_string();
+    }
+    let mut quoted = String::from('"');
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                // the backslashes before a quote escape each other, and then the quote
+                quoted.extend(std::iter::repeat_n('\\', backslashes * 2 + 1));
+                backslashes = 0;
+            }
+            _ => {
+                quoted.extend(std::iter::repeat_n('\\', backslashes));
+                backslashes = 0;
+            }
+        }
+        if c != '\\' {
+            quoted.push(c);
+        }
+    }
+    // and so do those before the closing quote
+    quoted.extend(std::iter::repeat_n('\\', backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(not(windows))]
+pub fn install(_: &[String]) -> io::Result<()> {
+    Err(not_windows())
+}
+
+#[cfg(not(windows))]
+pub fn uninstall() -> io::Result<()> {
+    Err(not_windows())
+}
+
+#[cfg(not(windows))]
+pub fn dispatch(_: impl FnOnce() -> i32 + Send + 'static) -> io::Result<i32> {
+    Err(not_windows())
+}
+
+#[cfg(not(windows))]
+fn not_windows() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "services can only be installed on Windows, elsewhere use the service manager",
+    )
+}
@@ -0,0 +1,26 @@
+//! The session being transferred by the current task, so that everything logged about it can
+//! say which one it was, even when several go on at once.
+
+use std::fmt;
+use std::future::Future;
+
+tokio::task_local! {
+    static CURRENT: u64;
+}
+
+/// Runs the `future` with `id` as its current session.
+pub(crate) async fn scope<F: Future>(id: u64, future: F) -> F::Output {
+    CURRENT.scope(id, future).await
+}
+
+/// Shows the current session before a message, if there is one.
+pub(crate) struct Prefix;
+
+impl fmt::Display for Prefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match CURRENT.try_with(|id| *id) {
+            Ok(id) => write!(f, "[{:016x}] ", id),
+            Err(_) => Ok(()),
+        }
+    }
+}
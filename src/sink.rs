@@ -0,0 +1,718 @@
+//! Where the received files end up, so that the transfer doesn't need to know.
+//!
+//! The receiver stores files in its output directory by default, where it can also skip
+//! the unchanged ones and make copies and links itself. Any other [`Sink`] gets every file
+//! in full, one after another.
+
+use crate::age::{self, Recipient};
+use crate::owner::Owner;
+use crate::zip::{self, Crc32};
+use crate::{names, output_path, partial_path, reflink, special, tar};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[cfg(feature = "s3")]
+pub use crate::s3::S3;
+
+const ZSTD_LEVEL: i32 = 3; // the default of the zstd tool, which compresses fast enough
+
+/// Stores the received files, one entry at a time.
+pub trait Sink {
+    /// Starts a new entry for the file with the given `name`, which uses forward slashes and
+    /// may not be valid UTF-8. Exactly `len` bytes will be written to it.
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()>;
+
+    /// Appends the data to the current entry.
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Completes the current entry, once all of its data was written.
+    fn close_entry(&mut self) -> io::Result<()>;
+
+    /// Gives up on the current entry, after the transfer failed midway through it.
+    fn abort_entry(&mut self) {}
+
+    /// Completes the sink, once every entry was written.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for &mut S {
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()> {
+        (**self).open_entry(name, len, modified)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).write_chunk(data)
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        (**self).close_entry()
+    }
+
+    fn abort_entry(&mut self) {
+        (**self).abort_entry()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+impl<S: Sink + ?Sized> Sink for Box<S> {
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()> {
+        (**self).open_entry(name, len, modified)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        (**self).write_chunk(data)
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        (**self).close_entry()
+    }
+
+    fn abort_entry(&mut self) {
+        (**self).abort_entry()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        (**self).finish()
+    }
+}
+
+// So that the data can be written with everything that expects a writer.
+pub(crate) struct SinkWriter<S>(pub S);
+
+impl<S: Sink> Write for SinkWriter<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write_chunk(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Stores every file under a directory, as the receiver does by default.
+pub struct Directory {
+    output: PathBuf,
+    // whether the output directory itself is known to be there
+    output_created: bool,
+    created_dirs: DirTree,
+    // who the files are encrypted to, if they're not stored as they are
+    recipient: Option<Recipient>,
+    // who the files and directories are given to, if not whoever receives them
+    pub(crate) owner: Option<Owner>,
+    // the permissions given to the files and directories, if not those the umask leaves
+    pub(crate) file_mode: Option<u32>,
+    pub(crate) dir_mode: Option<u32>,
+    // the final and partial paths of the current entry, and its file
+    current: Option<(PathBuf, PathBuf, Stored, Option<u64>)>,
+}
+
+impl Directory {
+    pub fn new(output: impl Into<PathBuf>) -> Self {
+        Directory {
+            output: output.into(),
+            output_created: false,
+            created_dirs: DirTree::default(),
+            recipient: None,
+            owner: None,
+            file_mode: None,
+            dir_mode: None,
+            current: None,
+        }
+    }
+
+    /// Like `new`, but every file is encrypted to the recipient as it's written, and stored
+    /// with the extension of encrypted files after its name.
+    pub fn encrypted(output: impl Into<PathBuf>, recipient: Recipient) -> Self {
+        Directory {
+            recipient: Some(recipient),
+            ..Directory::new(output)
+        }
+    }
+
+    // Where the file at `path` is really stored, which is elsewhere when encrypting it.
+    pub(crate) fn stored_path(&self, path: PathBuf) -> PathBuf {
+        if self.recipient.is_none() {
+            return path;
+        }
+        let mut path = path.into_os_string();
+        path.push(".");
+        path.push(age::EXTENSION);
+        path.into()
+    }
+
+    // Start the entry at `path`, which is already within the output directory. The data is
+    // written to a partial file first, so that an interrupted transfer does not leave behind
+    // something that looks like a complete file.
+    pub(crate) fn open_path(&mut self, path: &Path, modified: Option<u64>) -> io::Result<()> {
+        self.create_parent(path)?;
+        let part_path = partial_path(path);
+        // removed rather than truncated, so that a link left in its place isn't written through
+        let _ = fs::remove_file(&part_path);
+        let f = File::create(&part_path)?;
+        let stored = match &self.recipient {
+            Some(recipient) => match age::Writer::new(f, recipient) {
+                Ok(f) => Stored::Encrypted(f),
+                Err(e) => {
+                    let _ = fs::remove_file(&part_path);
+                    return Err(e);
+                }
+            },
+            None => Stored::Plain(f),
+        };
+        self.current = Some((path.to_path_buf(), part_path, stored, modified));
+        Ok(())
+    }
+
+    /// Stores the file with the given `name` as a copy of the file at `source`, such as one
+    /// received before. Where the file system can, the copy shares the data of the original
+    /// until either of them changes, rather than taking up the space twice.
+    pub fn copy_entry(
+        &mut self,
+        name: &[u8],
+        source: &Path,
+        modified: Option<u64>,
+    ) -> io::Result<()> {
+        let path = output_path(&self.output, name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.copy_path(&self.stored_path(path), source, modified)
+    }
+
+    // Like `copy_entry`, with a `path` already within the output directory.
+    pub(crate) fn copy_path(
+        &mut self,
+        path: &Path,
+        source: &Path,
+        modified: Option<u64>,
+    ) -> io::Result<()> {
+        self.create_parent(path)?;
+        let part_path = partial_path(path);
+        let _ = fs::remove_file(&part_path);
+        if let Err(e) = reflink::clone_file(source, &part_path) {
+            debug!("cannot clone {:?} ({}), copying it instead", source, e);
+            let copied = File::open(source)
+                .and_then(|mut source| io::copy(&mut source, &mut File::create(&part_path)?));
+            if let Err(e) = copied {
+                let _ = fs::remove_file(&part_path);
+                return Err(e);
+            }
+        }
+        let f = OpenOptions::new().write(true).open(&part_path)?;
+        self.current = Some((path.to_path_buf(), part_path, Stored::Plain(f), modified));
+        self.close_entry()
+    }
+
+    // Make the special file of the `kind` at `path`, which is within the output directory, in
+    // place of whatever file was there, given to the owner with the permissions asked for.
+    pub(crate) fn create_special(&mut self, path: &Path, kind: u8) -> io::Result<()> {
+        self.create_parent(path)?;
+        let part_path = partial_path(path);
+        let _ = fs::remove_file(&part_path);
+        special::create(&part_path, kind)?;
+        let result = match &self.owner {
+            Some(owner) => owner.apply(&part_path),
+            None => Ok(()),
+        };
+        let result = match self.file_mode {
+            Some(mode) => result.and_then(|()| set_mode(&part_path, mode)),
+            None => result,
+        };
+        match result {
+            Ok(()) => fs::rename(&part_path, path),
+            Err(e) => {
+                let _ = fs::remove_file(&part_path);
+                Err(e)
+            }
+        }
+    }
+
+    pub(crate) fn create_parent(&mut self, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) => self.create_dir(parent),
+            None => Ok(()),
+        }
+    }
+
+    // Create the directory, which is within the output directory, and those missing above
+    // it, all given to the owner and with the permissions asked for, if any. Only the part
+    // below the deepest directory created before is created, and none of the way there may
+    // go through links to outside of the output directory.
+    pub(crate) fn create_dir(&mut self, dir: &Path) -> io::Result<()> {
+        if !self.output_created {
+            let missing = self
+                .output
+                .ancestors()
+                .take_while(|dir| !dir.as_os_str().is_empty() && !dir.exists())
+                .map(Path::to_path_buf)
+                .collect::<Vec<_>>();
+            fs::create_dir_all(&self.output)?;
+            for dir in missing.iter().rev() {
+                self.created(dir)?;
+            }
+            self.output_created = true;
+        }
+
+        let components = dir
+            .strip_prefix(&self.output)
+            .map_err(|_| io::Error::other(format!("{:?} is outside of the output", dir)))?
+            .components()
+            .collect::<Vec<_>>();
+        let known = self.created_dirs.known(&components);
+        let mut current = self.output.clone();
+        current.extend(&components[..known]);
+        // whatever else writes to the output could have put links in place of those since
+        check_links(&self.output, &current)?;
+        if known == components.len() {
+            return Ok(());
+        }
+        for component in &components[known..] {
+            current.push(component);
+            match fs::symlink_metadata(&current) {
+                Ok(meta) if meta.file_type().is_symlink() => check_link(&self.output, &current)?,
+                Ok(meta) if meta.is_dir() => {}
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("{:?} is not a directory", current),
+                    ))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    match fs::create_dir(&current) {
+                        Ok(()) => self.created(&current)?,
+                        // made by someone else in the meantime
+                        Err(e) if e.kind() == io::ErrorKind::AlreadyExists && current.is_dir() => {}
+                        Err(e) => return Err(e),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        self.created_dirs.insert(&components);
+        Ok(())
+    }
+
+    // Give the directory just created to the owner, with the permissions asked for, if any.
+    fn created(&self, dir: &Path) -> io::Result<()> {
+        if let Some(owner) = &self.owner {
+            owner.apply(dir)?;
+        }
+        if let Some(mode) = self.dir_mode {
+            set_mode(dir, mode)?;
+        }
+        Ok(())
+    }
+
+    fn current(&mut self) -> io::Result<&mut dyn Write> {
+        match &mut self.current {
+            Some((_, _, Stored::Plain(f), _)) => Ok(f),
+            Some((_, _, Stored::Encrypted(f), _)) => Ok(f),
+            None => Err(no_entry()),
+        }
+    }
+}
+
+// The file an entry is written to, through whatever it goes through first.
+enum Stored {
+    Plain(File),
+    Encrypted(age::Writer<File>),
+}
+
+impl Sink for Directory {
+    fn open_entry(&mut self, name: &[u8], _len: u64, modified: Option<u64>) -> io::Result<()> {
+        let path = output_path(&self.output, name)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        self.open_path(&self.stored_path(path), modified)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.current()?.write_all(data)
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        let (path, part_path, stored, modified) = self.current.take().ok_or_else(no_entry)?;
+        let f = match stored {
+            Stored::Plain(f) => f,
+            Stored::Encrypted(f) => match f.finish() {
+                Ok(f) => f,
+                Err(e) => {
+                    let _ = fs::remove_file(&part_path);
+                    return Err(e);
+                }
+            },
+        };
+        // keeping the modification time allows unchanged files to be detected later on
+        let result = match modified {
+            Some(secs) => f.set_modified(UNIX_EPOCH + Duration::from_secs(secs)),
+            None => Ok(()),
+        };
+        // given away before it has its name, so that it never appears with the wrong owner
+        let result = match &self.owner {
+            Some(owner) => result.and_then(|()| owner.apply_to_file(&f)),
+            None => result,
+        };
+        drop(f);
+        // after the owner, since changing it can clear the set-user-ID and set-group-ID bits
+        let result = match self.file_mode {
+            Some(mode) => result.and_then(|()| set_mode(&part_path, mode)),
+            None => result,
+        };
+        match result {
+            Ok(()) => fs::rename(&part_path, path),
+            Err(e) => {
+                let _ = fs::remove_file(&part_path);
+                Err(e)
+            }
+        }
+    }
+
+    fn abort_entry(&mut self) {
+        if let Some((_, part_path, f, _)) = self.current.take() {
+            drop(f);
+            let _ = fs::remove_file(part_path);
+        }
+    }
+}
+
+/// Writes every file into a tar archive, such as a file or the standard output.
+pub struct Tar<W: Write> {
+    out: W,
+    // zeros still owed after the data of the current entry
+    padding: usize,
+}
+
+impl<W: Write> Tar<W> {
+    pub fn new(out: W) -> Self {
+        Tar { out, padding: 0 }
+    }
+}
+
+impl<W: Write> Sink for Tar<W> {
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()> {
+        // the archive may be extracted anywhere, so names must not go outside of it
+        let name = names::relative_path(name)
+            .map(|path| names::wire_name(&path))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "name goes up"))?;
+        self.out
+            .write_all(&tar::header(&name, len, modified.unwrap_or(0)))?;
+        self.padding = tar::padding(len);
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        self.out.write_all(&vec![0; self.padding])
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.write_all(&tar::TRAILER)?;
+        self.out.flush()
+    }
+}
+
+/// Writes every file into a tar archive compressed with zstd.
+pub struct TarZst<W: Write>(Tar<zstd::Encoder<'static, W>>);
+
+impl<W: Write> TarZst<W> {
+    pub fn new(out: W) -> io::Result<Self> {
+        Ok(TarZst(Tar::new(zstd::Encoder::new(out, ZSTD_LEVEL)?)))
+    }
+}
+
+impl<W: Write> Sink for TarZst<W> {
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()> {
+        self.0.open_entry(name, len, modified)
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_chunk(data)
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        self.0.close_entry()
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.0.finish()?;
+        // flushing only ends the current block, while the frame must be ended too
+        self.0.out.do_finish()?;
+        self.0.out.get_mut().flush()
+    }
+}
+
+/// Writes every file into a zip archive, stored as they are. The checksum of each file goes
+/// in front of its data once it was all written, so the output must be able to seek back.
+pub struct Zip<W: Write + Seek> {
+    out: W,
+    // how much was written, which is where the next entry starts
+    offset: u64,
+    // the files in the archive, listed again at the end, with the current one last
+    entries: Vec<zip::Entry>,
+    crc: Option<Crc32>,
+}
+
+impl<W: Write + Seek> Zip<W> {
+    pub fn new(out: W) -> Self {
+        Zip {
+            out,
+            offset: 0,
+            entries: Vec::new(),
+            crc: None,
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        self.out.write_all(data)?;
+        self.offset += data.len() as u64;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Sink for Zip<W> {
+    fn open_entry(&mut self, name: &[u8], len: u64, modified: Option<u64>) -> io::Result<()> {
+        // the archive may be extracted anywhere, so names must not go outside of it
+        let name = names::relative_path(name)
+            .map(|path| names::wire_name(&path))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "name goes up"))?;
+        let entry = zip::Entry {
+            name,
+            len,
+            mtime: modified.unwrap_or(0),
+            crc: 0,
+            offset: self.offset,
+        };
+        self.write(&zip::local_header(&entry))?;
+        self.entries.push(entry);
+        self.crc = Some(Crc32::new());
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, data: &[u8]) -> io::Result<()> {
+        self.crc.as_mut().ok_or_else(no_entry)?.update(data);
+        self.write(data)
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        let crc = self.crc.take().ok_or_else(no_entry)?.finish();
+        let entry = self.entries.last_mut().ok_or_else(no_entry)?;
+        entry.crc = crc;
+        self.out
+            .seek(SeekFrom::Start(entry.offset + zip::CRC_OFFSET))?;
+        self.out.write_all(&crc.to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(self.offset))?;
+        Ok(())
+    }
+
+    fn abort_entry(&mut self) {
+        // what was written of it stays, but it isn't listed, so it's not in the archive
+        if self.crc.take().is_some() {
+            self.entries.pop();
+        }
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let start = self.offset;
+        for i in 0..self.entries.len() {
+            let header = zip::central_header(&self.entries[i]);
+            self.write(&header)?;
+        }
+        self.write(&zip::end(self.entries.len(), start, self.offset - start))?;
+        self.out.flush()
+    }
+}
+
+/// Throws every file away, to measure how fast a transfer can go without the disk.
+pub struct Discard;
+
+impl Sink for Discard {
+    fn open_entry(&mut self, _name: &[u8], _len: u64, _modified: Option<u64>) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn write_chunk(&mut self, _data: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Makes sure that none of the directories from `output` down to `dir` is a link to somewhere
+/// outside of `output`, since the files stored in it would end up there. Receiving never makes
+/// links, but anything else could have left them in the output directory.
+pub(crate) fn check_links(output: &Path, dir: &Path) -> io::Result<()> {
+    let relative = match dir.strip_prefix(output) {
+        Ok(relative) => relative,
+        Err(_) => return Ok(()),
+    };
+    let mut current = output.to_path_buf();
+    for component in relative.components() {
+        current.push(component);
+        match fs::symlink_metadata(&current) {
+            Ok(meta) if meta.file_type().is_symlink() => check_link(output, &current)?,
+            Ok(_) => {}
+            // nothing below a missing directory exists either
+            Err(e) if e.kind() == io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// Make sure that the `link` points to somewhere within `output`. Links that go nowhere count
+// as outside, as following them could create anything.
+fn check_link(output: &Path, link: &Path) -> io::Result<()> {
+    let root = fs::canonicalize(output)?;
+    if !fs::canonicalize(link).is_ok_and(|target| target.starts_with(root)) {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{:?} links to outside of the output directory", link),
+        ));
+    }
+    Ok(())
+}
+
+// The directories known to be there below the output, by the names of their components, so
+// that those above the one being created need not be looked at again.
+#[derive(Default)]
+struct DirTree {
+    children: HashMap<OsString, DirTree>,
+}
+
+impl DirTree {
+    // How many of the leading `components` are known directories.
+    fn known(&self, components: &[Component]) -> usize {
+        let mut node = self;
+        for (depth, component) in components.iter().enumerate() {
+            match node.children.get(component.as_os_str()) {
+                Some(child) => node = child,
+                None => return depth,
+            }
+        }
+        components.len()
+    }
+
+    fn insert(&mut self, components: &[Component]) {
+        let mut node = self;
+        for component in components {
+            node = node
+                .children
+                .entry(component.as_os_str().to_os_string())
+                .or_default();
+        }
+    }
+}
+
+// Give the file or directory at `path` the permissions in `mode`, which only unix has.
+// Elsewhere they're left as they are.
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, mode);
+        Ok(())
+    }
+}
+
+fn no_entry() -> io::Error {
+    io::Error::other("no entry is open")
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::os::unix::fs::symlink;
+
+    // A directory of its own for every test, emptied before it runs.
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir()
+            .join(format!("sf-sink-test-{}", std::process::id()))
+            .join(name);
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // Links to within the output may be gone through, but those leading out of it, or nowhere,
+    // may not, wherever they are on the way to the directory.
+    #[test]
+    fn links_out_of_the_output_are_refused() {
+        let dir = scratch("check-links");
+        let (output, outside) = (dir.join("out"), dir.join("outside"));
+        fs::create_dir_all(output.join("real").join("deeper")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(output.join("real"), output.join("inside")).unwrap();
+        symlink(&outside, output.join("real").join("out")).unwrap();
+        symlink(dir.join("missing"), output.join("dangling")).unwrap();
+        // relative links are taken from where they are, not from where the receiver runs
+        symlink("../../outside", output.join("real").join("up")).unwrap();
+
+        let fine = [
+            "real/deeper",
+            "inside/deeper",
+            "inside/not/made/yet",
+            "new/dir",
+        ];
+        for fine in fine {
+            check_links(&output, &output.join(fine)).unwrap();
+        }
+        let refused = [
+            "real/out",
+            "real/out/below",
+            "inside/out",
+            "dangling/x",
+            "real/up",
+        ];
+        for refused in refused {
+            let e = check_links(&output, &output.join(refused)).unwrap_err();
+            assert_eq!(e.kind(), io::ErrorKind::PermissionDenied, "{}", refused);
+        }
+        // directories that aren't in the output at all aren't for it to judge
+        check_links(&output, &outside).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Files are never stored through a link leading out of the output, whether it was there
+    // before the transfer or appeared once the directories on the way were known.
+    #[test]
+    fn files_are_not_stored_through_links() {
+        let dir = scratch("store-links");
+        let (output, outside) = (dir.join("out"), dir.join("outside"));
+        fs::create_dir_all(output.join("linked")).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, output.join("before")).unwrap();
+
+        let mut directory = Directory::new(&output);
+        assert!(directory.open_entry(b"before/file", 3, None).is_err());
+        assert!(directory
+            .create_dir(&output.join("before").join("dir"))
+            .is_err());
+
+        directory.open_entry(b"linked/first", 3, None).unwrap();
+        directory.write_chunk(b"new").unwrap();
+        directory.close_entry().unwrap();
+        // swapped for a link once it was created, so its parents are already known
+        fs::remove_dir_all(output.join("linked")).unwrap();
+        symlink(&outside, output.join("linked")).unwrap();
+        assert!(directory.open_entry(b"linked/second", 3, None).is_err());
+        assert_eq!(fs::read_dir(&outside).unwrap().count(), 0);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
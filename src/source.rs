@@ -0,0 +1,313 @@
+//! Where the sent files come from, so that the transfer doesn't need to know.
+//!
+//! The sender reads the files given to it by default, where it can also send them straight
+//! from the disk, pack the small ones together, and find copies and links. Any other
+//! [`Source`] has every file read in full from it, one after another.
+
+use crate::{collect_files, modified_secs, names, tar};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A file that can be sent from a source.
+#[derive(Clone)]
+pub struct FileInfo {
+    /// Where the file is said to be in messages and events.
+    pub path: PathBuf,
+    /// The name the receiver gets, with forward slashes, which may not be valid UTF-8.
+    pub name: Vec<u8>,
+    pub len: u64,
+    /// Seconds since the unix epoch.
+    pub modified: u64,
+}
+
+/// Provides the files to send, and their data.
+pub trait Source {
+    /// Every file, in the order they're sent.
+    fn files(&self) -> &[FileInfo];
+
+    /// Reads the data of the file at `index`, starting `offset` bytes into it. It must have
+    /// the length it was listed with, since it's been told to the receiver already. It's read
+    /// on a thread of its own while the data before it is sent, so it can't borrow the source.
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn Read + Send>>;
+}
+
+/// Sends the files, and those inside the directories, as the sender does by default.
+pub struct Files {
+    files: Vec<FileInfo>,
+}
+
+impl Files {
+    /// Walks the directories to find every file in them.
+    pub fn new(paths: Vec<PathBuf>) -> crate::Result<Self> {
+        let mut files = Vec::new();
+        for path in collect_files(paths)? {
+            let meta = fs::metadata(&path).map_err(|e| crate::Error::from(e).at(&path))?;
+            files.push(FileInfo {
+                name: names::wire_name(&path),
+                len: meta.len(),
+                modified: modified_secs(&meta),
+                path,
+            });
+        }
+        Ok(Files { files })
+    }
+}
+
+impl Source for Files {
+    fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let file = &self.files[index];
+        let mut f = File::open(&file.path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(f.take(file.len - offset)))
+    }
+}
+
+/// Sends the files inside a tar archive, as if it had been extracted first.
+pub struct TarFile {
+    path: PathBuf,
+    files: Vec<FileInfo>,
+    // where the data of each file starts within the archive
+    offsets: Vec<u64>,
+}
+
+impl TarFile {
+    /// Reads the headers of the whole archive to find its files. Directories are implied by
+    /// the names of the files, and anything else, such as links, is left out.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let mut archive = File::open(&path)?;
+        let archive_len = archive.metadata()?.len();
+        let mut source = TarFile {
+            path: path.clone(),
+            files: Vec::new(),
+            offsets: Vec::new(),
+        };
+
+        // what the extended headers say about the entry after them
+        let mut long_name = None;
+        let mut long_len = None;
+        let mut offset = 0;
+        let mut block = [0u8; tar::BLOCK_SIZE];
+        // some archives end without the blocks of zeros, and that's fine too
+        while offset < archive_len {
+            archive.read_exact(&mut block)?;
+            offset += tar::BLOCK_SIZE as u64;
+            let header = match tar::parse_header(&block)? {
+                Some(header) => header,
+                None => break,
+            };
+            let len = match header.kind {
+                kind if header.is_file() || kind == tar::KIND_DIRECTORY => {
+                    long_len.take().unwrap_or(header.len)
+                }
+                _ => header.len,
+            };
+            if len > archive_len - offset {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "the archive ends before the data of an entry",
+                ));
+            }
+
+            match header.kind {
+                tar::KIND_PAX | tar::KIND_GNU_LONG_NAME => {
+                    let mut data = vec![0; len as usize];
+                    archive.read_exact(&mut data)?;
+                    if header.kind == tar::KIND_GNU_LONG_NAME {
+                        long_name = Some(tar::until_nul(&data).to_vec());
+                    } else {
+                        if let Some(name) = tar::pax_value(&data, b"path") {
+                            long_name = Some(name.to_vec());
+                        }
+                        long_len = tar::pax_value(&data, b"size")
+                            .and_then(|size| std::str::from_utf8(size).ok()?.parse().ok());
+                    }
+                }
+                _ if header.is_file() => {
+                    let name = long_name.take().unwrap_or(header.name);
+                    source.files.push(FileInfo {
+                        path: path.join(names::relative_path(&name).unwrap_or_default()),
+                        name,
+                        len,
+                        modified: header.mtime,
+                    });
+                    source.offsets.push(offset);
+                }
+                tar::KIND_DIRECTORY | tar::KIND_PAX_GLOBAL => long_name = None,
+                _ => {
+                    let name = long_name.take().unwrap_or(header.name);
+                    out!(
+                        "not sending {:?} from the archive, it's not a regular file",
+                        names::display(&name)
+                    );
+                }
+            }
+            offset += len + tar::padding(len) as u64;
+            archive.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(source)
+    }
+}
+
+impl Source for TarFile {
+    fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut archive = File::open(&self.path)?;
+        archive.seek(SeekFrom::Start(self.offsets[index] + offset))?;
+        Ok(Box::new(archive.take(self.files[index].len - offset)))
+    }
+}
+
+/// Sends what is read from the standard input as a single file.
+///
+/// The input is read in full before anything is sent, since its length must be known up
+/// front, and kept in a temporary file so that it can be sent again if the connection drops.
+pub struct Stdin {
+    spool: PathBuf,
+    files: Vec<FileInfo>,
+}
+
+impl Stdin {
+    /// Reads the whole input, to be sent under the given `name`.
+    pub fn read(name: &str) -> io::Result<Self> {
+        let spool = env::temp_dir().join(format!("sf-stdin-{}", std::process::id()));
+        let mut f = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&spool)?;
+        // created first, so that the spool is removed if reading fails
+        let mut stdin = Self {
+            files: Vec::new(),
+            spool,
+        };
+        let len = io::copy(&mut io::stdin().lock(), &mut f)?;
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        stdin.files.push(FileInfo {
+            path: Path::new(name).to_path_buf(),
+            name: name.as_bytes().to_vec(),
+            len,
+            modified,
+        });
+        Ok(stdin)
+    }
+}
+
+impl Source for Stdin {
+    fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        let mut f = File::open(&self.spool)?;
+        f.seek(SeekFrom::Start(offset))?;
+        Ok(Box::new(f.take(self.files[index].len - offset)))
+    }
+}
+
+impl Drop for Stdin {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.spool);
+    }
+}
+
+/// What the data of a [`Synthetic`] file looks like.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pattern {
+    /// Every byte is zero, as compressible as data gets.
+    Zeros,
+    /// Bytes that look random, which can't be compressed at all.
+    Random,
+    /// Repeated English text, compressible like most documents.
+    Text,
+}
+
+const TEXT: &[u8] = b"The quick brown fox jumps over the lazy dog, \
+    while the files keep moving across the local network as fast as they can.\n";
+
+/// Sends a single file made up on the fly, to measure how fast a transfer can go without
+/// the disk of the sender.
+pub struct Synthetic {
+    pattern: Pattern,
+    files: Vec<FileInfo>,
+}
+
+impl Synthetic {
+    pub fn new(name: &str, len: u64, pattern: Pattern) -> Self {
+        let modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        Synthetic {
+            pattern,
+            files: vec![FileInfo {
+                path: Path::new(name).to_path_buf(),
+                name: name.as_bytes().to_vec(),
+                len,
+                modified,
+            }],
+        }
+    }
+}
+
+impl Source for Synthetic {
+    fn files(&self) -> &[FileInfo] {
+        &self.files
+    }
+
+    fn open(&self, index: usize, offset: u64) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(
+            Generator {
+                pattern: self.pattern,
+                offset,
+            }
+            .take(self.files[index].len - offset),
+        ))
+    }
+}
+
+// Makes up the data of a synthetic file, where every byte depends only on where it is, so
+// that it can be made again from any offset.
+struct Generator {
+    pattern: Pattern,
+    offset: u64,
+}
+
+impl Read for Generator {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.pattern {
+            Pattern::Zeros => buf.fill(0),
+            Pattern::Text => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    *b = TEXT[((self.offset + i as u64) % TEXT.len() as u64) as usize];
+                }
+            }
+            Pattern::Random => {
+                for (i, b) in buf.iter_mut().enumerate() {
+                    let at = self.offset + i as u64;
+                    *b = splitmix64(at / 8).to_le_bytes()[(at % 8) as usize];
+                }
+            }
+        }
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+}
+
+// A fast mix of the bits, good enough to look random without being so.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
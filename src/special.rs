@@ -0,0 +1,114 @@
+//! Files that are neither regular files nor directories, such as FIFOs and device nodes, which
+//! have no data to send. FIFOs can be made again where they're received, but the others only
+//! mean something where they are.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// A named pipe, which is the only kind the receiver knows how to make.
+pub(crate) const FIFO: u8 = 1;
+
+/// What kind of special file this is, to say why it's skipped, or `None` if it's a regular
+/// file, a directory or a link.
+pub(crate) fn describe(file_type: fs::FileType) -> Option<&'static str> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return Some("FIFO");
+        }
+        if file_type.is_socket() {
+            return Some("socket");
+        }
+        if file_type.is_char_device() {
+            return Some("character device");
+        }
+        if file_type.is_block_device() {
+            return Some("block device");
+        }
+    }
+    let _ = file_type;
+    None
+}
+
+/// The kind of the special file, if it's one that can be made again elsewhere.
+pub(crate) fn kind(file_type: fs::FileType) -> Option<u8> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+        if file_type.is_fifo() {
+            return Some(FIFO);
+        }
+    }
+    let _ = file_type;
+    None
+}
+
+/// Makes a special file of the `kind` at `path`, which must not exist.
+pub(crate) fn create(path: &Path, kind: u8) -> io::Result<()> {
+    match kind {
+        FIFO => sys::make_fifo(path),
+        _ => Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("special files of kind {} can't be made", kind),
+        )),
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::{c_char, c_int};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    #[cfg(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    ))]
+    #[allow(non_camel_case_types)]
+    type mode_t = u16;
+    #[cfg(not(any(
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "openbsd",
+        target_os = "netbsd",
+        target_os = "dragonfly"
+    )))]
+    #[allow(non_camel_case_types)]
+    type mode_t = u32;
+
+    extern "C" {
+        fn mkfifo(path: *const c_char, mode: mode_t) -> c_int;
+    }
+
+    pub fn make_fifo(path: &Path) -> io::Result<()> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        // readable and writable by everyone the umask lets, like files are created
+        if unsafe { mkfifo(path.as_ptr(), 0o666) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub fn make_fifo(_path: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "FIFOs can only be made on unix",
+        ))
+    }
+}
@@ -0,0 +1,149 @@
+//! A graph of the throughput over the last minute, printed every few seconds during a
+//! transfer, to spot when it drops or stalls.
+
+use sf::format_bytes;
+use sf::{EventHandler, TransferEvent};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const TICK: Duration = Duration::from_millis(100);
+const SAMPLE_DELAY: Duration = Duration::from_secs(1);
+// one sample every second, for a minute
+const MAX_SAMPLES: usize = 60;
+const SHOW_EVERY: usize = 5;
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+#[derive(Default)]
+struct Speed {
+    // length and bytes transferred of every file that started, until it's done
+    started: HashMap<usize, (u64, u64)>,
+    total_bytes: u64,
+    sampled_bytes: u64,
+    // bytes transferred during every second, oldest first
+    samples: VecDeque<u64>,
+    // samples taken since the graph was last printed
+    unshown: usize,
+    // whether a transfer is going on, from its first file until it's finished
+    active: bool,
+}
+
+impl Speed {
+    fn handle(&mut self, event: &TransferEvent) {
+        match *event {
+            TransferEvent::FileStarted { index, len, .. } => {
+                self.started.insert(index, (len, 0));
+                self.active = true;
+            }
+            TransferEvent::Progress { index, bytes } => {
+                if let Some((_, sent)) = self.started.get_mut(&index) {
+                    // a resumed file may go back a bit
+                    self.total_bytes += bytes.saturating_sub(*sent);
+                    *sent = bytes;
+                }
+            }
+            TransferEvent::FileDone { index } => {
+                if let Some((len, sent)) = self.started.remove(&index) {
+                    self.total_bytes += len.saturating_sub(sent);
+                }
+            }
+            // the next transfer of a receiver that keeps going starts its own graph
+            TransferEvent::Finished => {
+                self.started.clear();
+                self.samples.clear();
+                self.unshown = 0;
+                self.sampled_bytes = self.total_bytes;
+                self.active = false;
+            }
+            _ => {}
+        }
+    }
+
+    fn sample(&mut self) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples
+            .push_back(self.total_bytes - self.sampled_bytes);
+        self.sampled_bytes = self.total_bytes;
+        self.unshown += 1;
+    }
+
+    // A bar for every second, the most recent on the right, and how fast it went.
+    fn graph(&self) -> String {
+        let peak = self.samples.iter().copied().max().unwrap_or(0);
+        let bars = self
+            .samples
+            .iter()
+            .map(|&sample| {
+                let height = (sample as u128 * 8).div_ceil(peak.max(1) as u128) as usize;
+                BLOCKS[height]
+            })
+            .collect::<String>();
+        format!(
+            "[{:<w$}] {}/s now, {}/s at peak over the last minute",
+            bars,
+            format_bytes(self.samples.back().copied().unwrap_or(0)),
+            format_bytes(peak),
+            w = MAX_SAMPLES
+        )
+    }
+}
+
+/// Follows the throughput of the transfer to print its graph, as the events of the transfer
+/// still reach the existing handler.
+pub struct SpeedGraph {
+    stop: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl SpeedGraph {
+    /// Starts following the transfer, printing the graph to the standard error, so that it
+    /// never mixes with data going to the standard output.
+    pub fn new(events: &mut Option<EventHandler>) -> Self {
+        let speed = Arc::new(Mutex::new(Speed::default()));
+        let previous = events.take();
+        *events = Some({
+            let speed = Arc::clone(&speed);
+            Box::new(move |event| {
+                speed.lock().unwrap().handle(&event);
+                if let Some(previous) = &previous {
+                    previous(event);
+                }
+            })
+        });
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let stop = Arc::clone(&stop);
+            thread::spawn(move || {
+                let mut next_sample = Instant::now() + SAMPLE_DELAY;
+                while !stop.load(Ordering::Relaxed) {
+                    thread::sleep(TICK);
+                    if Instant::now() < next_sample {
+                        continue;
+                    }
+                    next_sample += SAMPLE_DELAY;
+                    let mut speed = speed.lock().unwrap();
+                    if !speed.active {
+                        continue;
+                    }
+                    speed.sample();
+                    if speed.unshown == SHOW_EVERY {
+                        speed.unshown = 0;
+                        eprintln!("{}", speed.graph());
+                    }
+                }
+            })
+        };
+        SpeedGraph { stop, thread }
+    }
+
+    /// Stops following the transfer, once it's over.
+    pub fn finish(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
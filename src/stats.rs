@@ -0,0 +1,286 @@
+//! Statistics of a transfer, to summarize it once it's over.
+
+use sf::{format_bytes, format_duration};
+use sf::{EventHandler, TransferEvent};
+use std::collections::HashMap;
+use std::fmt;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// How long the throughput is measured over to find its peak
+const PEAK_WINDOW: Duration = Duration::from_secs(1);
+
+struct Tally {
+    // length and bytes transferred of every file that started, until it's done
+    started: HashMap<usize, (u64, u64)>,
+    // files that started but never finished in the sessions before this one
+    failed: usize,
+    files: usize,
+    skipped: usize,
+    changed: usize,
+    unreadable: Vec<(PathBuf, String)>,
+    bytes: u64,
+    // file data compressed, and how long it was once it was
+    compressed: Option<(u64, u64)>,
+    connected: Option<Instant>,
+    peer: Option<SocketAddr>,
+    round_trip: Option<Duration>,
+    paused_since: Option<Instant>,
+    paused: Duration,
+    window_start: Instant,
+    window_bytes: u64,
+    peak: u64,
+}
+
+impl Tally {
+    fn add_bytes(&mut self, bytes: u64) {
+        self.bytes += bytes;
+        self.window_bytes += bytes;
+        let elapsed = self.window_start.elapsed();
+        if elapsed >= PEAK_WINDOW {
+            self.peak = self.peak.max(per_sec(self.window_bytes, elapsed));
+            self.window_start = Instant::now();
+            self.window_bytes = 0;
+        }
+    }
+}
+
+/// What a transfer did, as counted by a [`Tracker`].
+pub struct Stats {
+    /// Files transferred completely.
+    pub files: usize,
+    /// Files not transferred because the receiver already had them.
+    pub skipped: usize,
+    /// Files that started being transferred but never finished.
+    pub failed: usize,
+    /// Files that changed while being sent, which the receiver may not have whole.
+    pub changed: usize,
+    /// Files the sender could not read, and why.
+    pub unreadable: Vec<(PathBuf, String)>,
+    /// Bytes transferred, including those of the files that failed.
+    pub bytes: u64,
+    /// The length of the compressed data as a fraction of the original, if it was compressed.
+    pub ratio: Option<f64>,
+    /// Whether the peers ever connected.
+    pub connected: bool,
+    /// Who the other end was, once it connected.
+    pub peer: Option<SocketAddr>,
+    /// How long the receiver took to answer the sender, if it was measured.
+    pub round_trip: Option<Duration>,
+    /// Time since the peers connected, or since tracking started if they never did.
+    pub elapsed: Duration,
+    /// How much of the elapsed time the receiver spent paused.
+    pub paused: Duration,
+    /// Bytes per second over the whole transfer, except while paused.
+    pub average: u64,
+    /// Bytes per second during the fastest second of the transfer.
+    pub peak: u64,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} files ({}) in {}{}, {} skipped, {} failed{}{}, {}/s on average, {}/s at peak{}",
+            self.files,
+            format_bytes(self.bytes),
+            format_duration(self.elapsed),
+            if self.paused.is_zero() {
+                String::new()
+            } else {
+                format!(" ({} paused)", format_duration(self.paused))
+            },
+            self.skipped,
+            self.failed,
+            if self.changed == 0 {
+                String::new()
+            } else {
+                format!(", {} changed while sent", self.changed)
+            },
+            if self.unreadable.is_empty() {
+                String::new()
+            } else {
+                format!(", {} unreadable", self.unreadable.len())
+            },
+            format_bytes(self.average),
+            format_bytes(self.peak),
+            match self.ratio {
+                Some(ratio) => format!(", compressed to {:.0}%", ratio * 100.0),
+                None => String::new(),
+            }
+        )
+    }
+}
+
+/// Keeps count of what a transfer did, to summarize it once it's over.
+pub struct Tracker {
+    tally: Arc<Mutex<Tally>>,
+    started: Instant,
+}
+
+impl Tracker {
+    /// Starts counting the events of the transfer, which still reach the existing handler.
+    pub fn new(events: &mut Option<EventHandler>) -> Self {
+        let started = Instant::now();
+        let tally = Arc::new(Mutex::new(Tally {
+            started: HashMap::new(),
+            failed: 0,
+            files: 0,
+            skipped: 0,
+            changed: 0,
+            unreadable: Vec::new(),
+            bytes: 0,
+            compressed: None,
+            connected: None,
+            peer: None,
+            round_trip: None,
+            paused_since: None,
+            paused: Duration::ZERO,
+            window_start: started,
+            window_bytes: 0,
+            peak: 0,
+        }));
+        let previous = events.take();
+        *events = Some({
+            let tally = Arc::clone(&tally);
+            Box::new(move |event| {
+                let mut tally = tally.lock().unwrap();
+                match &event {
+                    TransferEvent::Connected { peer } if tally.connected.is_none() => {
+                        let now = Instant::now();
+                        tally.connected = Some(now);
+                        tally.peer = Some(*peer);
+                        tally.window_start = now;
+                    }
+                    TransferEvent::RoundTrip { rtt } => tally.round_trip = Some(*rtt),
+                    TransferEvent::Paused => tally.paused_since = Some(Instant::now()),
+                    TransferEvent::Resumed => {
+                        if let Some(since) = tally.paused_since.take() {
+                            tally.paused += since.elapsed();
+                        }
+                        // the time spent paused says nothing about how fast it can go
+                        tally.window_start = Instant::now();
+                        tally.window_bytes = 0;
+                    }
+                    TransferEvent::FileStarted { index, len, .. } => {
+                        tally.started.insert(*index, (*len, 0));
+                    }
+                    TransferEvent::FileSkipped { .. } => tally.skipped += 1,
+                    TransferEvent::Compressed { raw, sent } => {
+                        let (total_raw, total_sent) = tally.compressed.get_or_insert((0, 0));
+                        *total_raw += raw;
+                        *total_sent += sent;
+                    }
+                    TransferEvent::FileChanged { .. } => tally.changed += 1,
+                    TransferEvent::Unreadable {
+                        index,
+                        path,
+                        reason,
+                    } => {
+                        if let Some(index) = index {
+                            tally.started.remove(index);
+                        }
+                        tally.unreadable.push((path.clone(), reason.clone()));
+                    }
+                    TransferEvent::Progress { index, bytes } => {
+                        if let Some((_, done)) = tally.started.get_mut(index) {
+                            // a resumed file may go back a bit
+                            let new = bytes.saturating_sub(*done);
+                            *done = *bytes;
+                            tally.add_bytes(new);
+                        }
+                    }
+                    TransferEvent::FileDone { index } => {
+                        if let Some((len, done)) = tally.started.remove(index) {
+                            tally.files += 1;
+                            tally.add_bytes(len.saturating_sub(done));
+                        }
+                    }
+                    // the files of the next session, as it keeps receiving, count from 0 again
+                    TransferEvent::Finished | TransferEvent::Failed { .. } => {
+                        tally.failed += tally.started.len();
+                        tally.started.clear();
+                    }
+                    _ => {}
+                }
+                drop(tally);
+                if let Some(previous) = &previous {
+                    previous(event);
+                }
+            })
+        });
+        Tracker { tally, started }
+    }
+
+    /// The statistics of the transfer so far.
+    pub fn stats(&self) -> Stats {
+        let tally = self.tally.lock().unwrap();
+        let elapsed = tally.connected.unwrap_or(self.started).elapsed();
+        let paused = tally.paused + tally.paused_since.map_or(Duration::ZERO, |s| s.elapsed());
+        let average = per_sec(tally.bytes, elapsed.saturating_sub(paused));
+        Stats {
+            files: tally.files,
+            skipped: tally.skipped,
+            failed: tally.failed + tally.started.len(),
+            changed: tally.changed,
+            unreadable: tally.unreadable.clone(),
+            bytes: tally.bytes,
+            ratio: tally.compressed.map(|(raw, sent)| sent as f64 / raw as f64),
+            connected: tally.connected.is_some(),
+            peer: tally.peer,
+            round_trip: tally.round_trip,
+            elapsed,
+            paused,
+            average,
+            // transfers shorter than the window never measured one
+            peak: tally.peak.max(average),
+        }
+    }
+}
+
+fn per_sec(bytes: u64, elapsed: Duration) -> u64 {
+    let secs = elapsed.as_secs_f64();
+    if secs > 0.0 {
+        (bytes as f64 / secs) as u64
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keeping receiving, every session numbers its files from 0 again, so the files one left
+    // unfinished are not finished by those of the next.
+    #[test]
+    fn sessions_are_counted_apart() {
+        let mut events = None;
+        let tracker = Tracker::new(&mut events);
+        let emit = events.unwrap();
+        let started = |index| TransferEvent::FileStarted {
+            index,
+            path: PathBuf::from("file"),
+            len: 10,
+        };
+        emit(started(0));
+        emit(started(1));
+        emit(TransferEvent::FileDone { index: 0 });
+        emit(TransferEvent::Failed {
+            reason: "the connection broke".into(),
+        });
+        emit(started(0));
+        emit(started(1));
+        emit(TransferEvent::FileDone { index: 0 });
+        emit(TransferEvent::FileDone { index: 1 });
+        emit(TransferEvent::Finished);
+        emit(started(0));
+
+        let stats = tracker.stats();
+        assert_eq!(stats.files, 3);
+        assert_eq!(stats.failed, 2);
+        assert_eq!(stats.bytes, 30);
+    }
+}
@@ -0,0 +1,303 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::PathBuf;
+
+pub const BLOCK_SIZE: usize = 512;
+pub const KIND_FILE: u8 = b'0';
+// older archives mark regular files with a NUL, and some with the "contiguous file" kind
+pub const KIND_OLD_FILE: u8 = 0;
+pub const KIND_CONTIGUOUS: u8 = b'7';
+pub const KIND_DIRECTORY: u8 = b'5';
+pub const KIND_PAX: u8 = b'x';
+pub const KIND_PAX_GLOBAL: u8 = b'g';
+pub const KIND_GNU_LONG_NAME: u8 = b'L';
+const MAX_NAME_LEN: usize = 100;
+const MAX_OCTAL_SIZE: u64 = 0o77777777777; // 11 octal digits
+
+/// The archive is terminated by two blocks full of zeros.
+pub const TRAILER: [u8; 2 * BLOCK_SIZE] = [0; 2 * BLOCK_SIZE];
+
+/// Builds the header blocks for a regular file entry. Names which do not fit in the ustar
+/// header, or sizes too large for it, are stored in a preceding pax extended header.
+pub fn header(name: &[u8], size: u64, mtime: u64) -> Vec<u8> {
+    let mut records = Vec::new();
+    if name.len() > MAX_NAME_LEN {
+        records.extend(pax_record(b"path", name));
+    }
+    if size > MAX_OCTAL_SIZE {
+        records.extend(pax_record(b"size", size.to_string().as_bytes()));
+    }
+
+    let mut result = Vec::new();
+    if !records.is_empty() {
+        result.extend(&ustar_header(
+            b"././@PaxHeader",
+            records.len() as u64,
+            mtime,
+            b'x',
+        ));
+        result.extend(&records);
+        result.resize(result.len() + padding(records.len() as u64), 0);
+    }
+    result.extend(&ustar_header(
+        &name[..name.len().min(MAX_NAME_LEN)],
+        size.min(MAX_OCTAL_SIZE),
+        mtime,
+        b'0',
+    ));
+    result
+}
+
+/// Amount of zeros needed after `size` bytes of data to complete the last block.
+pub fn padding(size: u64) -> usize {
+    let rest = (size % BLOCK_SIZE as u64) as usize;
+    if rest == 0 {
+        0
+    } else {
+        BLOCK_SIZE - rest
+    }
+}
+
+fn ustar_header(name: &[u8], size: u64, mtime: u64, kind: u8) -> [u8; BLOCK_SIZE] {
+    let mut block = [0u8; BLOCK_SIZE];
+    block[..name.len()].copy_from_slice(name);
+    write_octal(&mut block[100..108], 0o644);
+    write_octal(&mut block[108..116], 0);
+    write_octal(&mut block[116..124], 0);
+    write_octal(&mut block[124..136], size);
+    write_octal(&mut block[136..148], mtime);
+    block[156] = kind;
+    block[257..263].copy_from_slice(b"ustar\0");
+    block[263..265].copy_from_slice(b"00");
+
+    // the checksum is calculated as if its own field was filled with spaces
+    block[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = block.iter().map(|b| *b as u32).sum();
+    write_octal(&mut block[148..155], checksum as u64);
+    block
+}
+
+// Fields are zero-padded octal numbers terminated by a NUL.
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = format!("{:o}", value);
+    let width = field.len() - 1;
+    let start = width - digits.len().min(width);
+    field[..start].iter_mut().for_each(|b| *b = b'0');
+    field[start..width].copy_from_slice(&digits.as_bytes()[digits.len() - (width - start)..]);
+    field[width] = 0;
+}
+
+// Records look like "<len> <key>=<value>\n", where the length includes itself.
+fn pax_record(key: &[u8], value: &[u8]) -> Vec<u8> {
+    let rest = 1 + key.len() + 1 + value.len() + 1;
+    let mut len = rest;
+    while len != rest + len.to_string().len() {
+        len = rest + len.to_string().len();
+    }
+
+    let mut record = len.to_string().into_bytes();
+    record.push(b' ');
+    record.extend(key);
+    record.push(b'=');
+    record.extend(value);
+    record.push(b'\n');
+    record
+}
+
+/// What a header block says about the entry after it.
+pub struct Header {
+    pub name: Vec<u8>,
+    pub len: u64,
+    pub mtime: u64,
+    pub kind: u8,
+}
+
+impl Header {
+    pub fn is_file(&self) -> bool {
+        matches!(self.kind, KIND_FILE | KIND_OLD_FILE | KIND_CONTIGUOUS)
+    }
+}
+
+/// Parses a header block, or returns `None` for the blocks full of zeros that end the
+/// archive. Long names and sizes may be in a pax header before it instead.
+pub fn parse_header(block: &[u8; BLOCK_SIZE]) -> io::Result<Option<Header>> {
+    if block.iter().all(|&b| b == 0) {
+        return Ok(None);
+    }
+    let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, reason.to_string());
+
+    let mut checksum_block = *block;
+    checksum_block[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = checksum_block.iter().map(|&b| b as u64).sum();
+    if read_number(&block[148..156]) != Some(checksum) {
+        return Err(invalid("bad tar header checksum"));
+    }
+
+    let mut name = until_nul(&block[..100]).to_vec();
+    // ustar splits long names in two, with the start in the prefix
+    if &block[257..262] == b"ustar" {
+        let prefix = until_nul(&block[345..500]);
+        if !prefix.is_empty() {
+            name = [prefix, b"/", &name].concat();
+        }
+    }
+    Ok(Some(Header {
+        name,
+        len: read_number(&block[124..136]).ok_or_else(|| invalid("bad tar entry size"))?,
+        mtime: read_number(&block[136..148]).unwrap_or(0),
+        kind: block[156],
+    }))
+}
+
+/// Finds the value of the `key` among the records of a pax header.
+pub fn pax_value<'a>(mut records: &'a [u8], key: &[u8]) -> Option<&'a [u8]> {
+    while !records.is_empty() {
+        let space = records.iter().position(|&b| b == b' ')?;
+        let len: usize = std::str::from_utf8(&records[..space]).ok()?.parse().ok()?;
+        let record = records.get(space + 1..len)?;
+        records = &records[len..];
+        let equals = record.iter().position(|&b| b == b'=')?;
+        if &record[..equals] == key {
+            // without the newline at the end
+            return record.get(equals + 1..record.len() - 1);
+        }
+    }
+    None
+}
+
+/// The text up to the first NUL, as used by names and by GNU long names.
+pub fn until_nul(field: &[u8]) -> &[u8] {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    &field[..end]
+}
+
+// Numbers are octal, padded with zeros or spaces, or big-endian binary after a set high bit.
+fn read_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        return field[1..]
+            .iter()
+            .try_fold(0u64, |n, &b| n.checked_mul(256).map(|n| n | b as u64));
+    }
+    let digits = std::str::from_utf8(until_nul(field)).ok()?.trim();
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// A file to be stored in an archive.
+#[derive(Clone)]
+pub struct Member {
+    pub path: PathBuf,
+    pub name: Vec<u8>,
+    pub len: u64,
+    pub mtime: u64,
+}
+
+enum Segment {
+    Bytes(Vec<u8>),
+    File(Member),
+}
+
+impl Segment {
+    fn len(&self) -> u64 {
+        match self {
+            Segment::Bytes(bytes) => bytes.len() as u64,
+            Segment::File(member) => member.len,
+        }
+    }
+}
+
+/// Produces an archive of the members on the fly, without storing it anywhere.
+pub struct ArchiveReader {
+    segments: Vec<Segment>,
+    current: usize,
+    offset: u64,
+    file: Option<io::Take<File>>,
+}
+
+impl ArchiveReader {
+    pub fn new(members: &[Member]) -> Self {
+        let mut segments = Vec::new();
+        for member in members {
+            segments.push(Segment::Bytes(header(
+                &member.name,
+                member.len,
+                member.mtime,
+            )));
+            segments.push(Segment::File(member.clone()));
+            segments.push(Segment::Bytes(vec![0; padding(member.len)]));
+        }
+        segments.push(Segment::Bytes(TRAILER.to_vec()));
+        Self {
+            segments,
+            current: 0,
+            offset: 0,
+            file: None,
+        }
+    }
+
+    /// Length of the entire archive.
+    pub fn len(&self) -> u64 {
+        self.segments.iter().map(|s| s.len()).sum()
+    }
+
+    /// Skip the first `offset` bytes of the archive without generating them.
+    pub fn skip(&mut self, mut offset: u64) {
+        self.current = 0;
+        self.file = None;
+        while let Some(segment) = self.segments.get(self.current) {
+            if offset < segment.len() {
+                break;
+            }
+            offset -= segment.len();
+            self.current += 1;
+        }
+        self.offset = offset;
+    }
+}
+
+impl Read for ArchiveReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let segment = match self.segments.get(self.current) {
+                Some(segment) => segment,
+                None => return Ok(0),
+            };
+
+            let n = match segment {
+                Segment::Bytes(bytes) => {
+                    let rest = &bytes[self.offset as usize..];
+                    let n = rest.len().min(buf.len());
+                    buf[..n].copy_from_slice(&rest[..n]);
+                    n
+                }
+                Segment::File(member) => {
+                    if self.file.is_none() {
+                        let mut file = File::open(&member.path)?;
+                        file.seek(SeekFrom::Start(self.offset))?;
+                        self.file = Some(file.take(member.len - self.offset));
+                    }
+                    let n = self.file.as_mut().unwrap().read(buf)?;
+                    if n == 0 && self.offset != member.len {
+                        return Err(io::Error::new(
+                            io::ErrorKind::UnexpectedEof,
+                            format!("{:?} shrunk while being archived", member.path),
+                        ));
+                    }
+                    n
+                }
+            };
+
+            self.offset += n as u64;
+            if self.offset == segment.len() {
+                self.current += 1;
+                self.offset = 0;
+                self.file = None;
+            }
+            if n != 0 || buf.is_empty() {
+                return Ok(n);
+            }
+        }
+    }
+}
@@ -0,0 +1,32 @@
+//! Just enough of a runtime to await the transfers from synchronous code, such as the command
+//! line, for those that don't have one of their own.
+
+use std::future::Future;
+
+/// Runs the future to completion on the current thread, along with the I/O and timers it
+/// waits on.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime")
+        .block_on(future)
+}
+
+// Runs the `work` on a thread meant for blocking, so that reading the disk or hashing doesn't
+// hold back the rest of what the runtime is doing meanwhile.
+pub(crate) async fn blocking<T, F>(work: F) -> T
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    joined(tokio::task::spawn_blocking(work)).await
+}
+
+// Waits for the task to finish, panicking in turn if it did.
+pub(crate) async fn joined<T>(task: tokio::task::JoinHandle<T>) -> T {
+    match task.await {
+        Ok(result) => result,
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
@@ -0,0 +1,401 @@
+//! A terminal interface for the receiver, drawn from the events of the transfer.
+
+use sf::{format_bytes, format_duration};
+use sf::{CancelToken, PauseToken, ReceiveOptions, TransferEvent};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const FRAME_DELAY: Duration = Duration::from_millis(100);
+const SAMPLE_DELAY: Duration = Duration::from_secs(1);
+const MAX_SAMPLES: usize = 512;
+const GRAPH_ROWS: usize = 3;
+const BAR_WIDTH: usize = 20;
+const DEFAULT_SIZE: (usize, usize) = (24, 80);
+const BLOCKS: [char; 9] = [' ', '▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+
+// Rows above and below the table
+const HEADER_ROWS: usize = 3 + GRAPH_ROWS;
+const FOOTER_ROWS: usize = 1;
+
+// Keys (or what they start with)
+const CTRL_C: u8 = 0x03;
+const ESC: u8 = 0x1b;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Receiving,
+    Skipped,
+    Unreadable,
+    Done,
+}
+
+struct Row {
+    path: PathBuf,
+    len: u64,
+    bytes: u64,
+    status: Status,
+}
+
+#[derive(Default)]
+struct State {
+    listening: Option<SocketAddr>,
+    peer: Option<SocketAddr>,
+    rows: Vec<Row>,
+    // position of the row of every file index
+    positions: HashMap<usize, usize>,
+    done: usize,
+    total_bytes: u64,
+    // bytes received during every second, oldest first
+    samples: VecDeque<u64>,
+    sampled_bytes: u64,
+    scroll: usize,
+    // whether the table scrolls by itself to keep the latest file in view
+    follow: bool,
+    paused: bool,
+    cancelled: bool,
+    finished: bool,
+}
+
+impl State {
+    fn handle(&mut self, event: TransferEvent) {
+        match event {
+            TransferEvent::Listening { addr } => self.listening = Some(addr),
+            TransferEvent::Connected { peer } => self.peer = Some(peer),
+            TransferEvent::FileStarted { index, path, len } => {
+                self.positions.insert(index, self.rows.len());
+                self.rows.push(Row {
+                    path,
+                    len,
+                    bytes: 0,
+                    status: Status::Receiving,
+                });
+            }
+            TransferEvent::FileSkipped { index, path } => {
+                self.positions.insert(index, self.rows.len());
+                self.rows.push(Row {
+                    path,
+                    len: 0,
+                    bytes: 0,
+                    status: Status::Skipped,
+                });
+            }
+            // only the receiver shows them, and it's told before the file starts
+            TransferEvent::Unreadable {
+                index: Some(index),
+                path,
+                ..
+            } => {
+                self.positions.insert(index, self.rows.len());
+                self.rows.push(Row {
+                    path,
+                    len: 0,
+                    bytes: 0,
+                    status: Status::Unreadable,
+                });
+            }
+            TransferEvent::Progress { index, bytes } => {
+                if let Some(&i) = self.positions.get(&index) {
+                    let row = &mut self.rows[i];
+                    // a resumed file may go back a bit
+                    self.total_bytes += bytes.saturating_sub(row.bytes);
+                    row.bytes = bytes;
+                }
+            }
+            TransferEvent::FileDone { index } => {
+                if let Some(&i) = self.positions.get(&index) {
+                    let row = &mut self.rows[i];
+                    self.total_bytes += row.len.saturating_sub(row.bytes);
+                    row.bytes = row.len;
+                    row.status = Status::Done;
+                    self.done += 1;
+                }
+            }
+            TransferEvent::Paused => self.paused = true,
+            TransferEvent::Resumed => self.paused = false,
+            TransferEvent::Shared { .. }
+            | TransferEvent::Storing { .. }
+            | TransferEvent::RoundTrip { .. }
+            | TransferEvent::Stored { .. }
+            | TransferEvent::Compressed { .. }
+            | TransferEvent::FileChanged { .. }
+            | TransferEvent::Unreadable { index: None, .. }
+            | TransferEvent::Corrupt { .. } => {}
+            TransferEvent::Finished => self.finished = true,
+            // the interface is only for a single transfer, which returns its failure
+            TransferEvent::Failed { .. } => {}
+        }
+    }
+
+    fn sample(&mut self) {
+        if self.samples.len() == MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples
+            .push_back(self.total_bytes - self.sampled_bytes);
+        self.sampled_bytes = self.total_bytes;
+    }
+
+    fn scroll_by(&mut self, delta: isize, page: usize) {
+        let last = self.rows.len().saturating_sub(page);
+        self.scroll = (self.scroll as isize + delta).clamp(0, last as isize) as usize;
+        self.follow = self.scroll == last;
+    }
+
+    fn draw(&mut self, (rows, cols): (usize, usize)) -> String {
+        let page = rows.saturating_sub(HEADER_ROWS + FOOTER_ROWS).max(1);
+        if self.follow {
+            self.scroll = self.rows.len().saturating_sub(page);
+        }
+
+        let mut lines = Vec::with_capacity(rows);
+        lines.push(format!(
+            " sf: {}{}",
+            match self.peer {
+                Some(peer) if self.finished => format!("received from {}", peer),
+                Some(peer) => format!("receiving from {}", peer),
+                None => match self.listening {
+                    Some(addr) => format!("waiting for the sender on {}...", addr),
+                    None => "waiting for the sender...".to_string(),
+                },
+            },
+            if self.cancelled {
+                " [aborting]"
+            } else if self.paused {
+                " [paused]"
+            } else {
+                ""
+            }
+        ));
+        let rate = self.samples.back().copied().unwrap_or(0);
+        // only the files being received say how much is left of them
+        let left = self
+            .rows
+            .iter()
+            .filter(|row| row.status == Status::Receiving)
+            .map(|row| row.len.saturating_sub(row.bytes))
+            .sum::<u64>();
+        lines.push(format!(
+            " {} of {} files, {} received @ {}/s{}",
+            self.done,
+            self.rows.len(),
+            format_bytes(self.total_bytes),
+            format_bytes(rate),
+            if left == 0 || rate == 0 || self.paused {
+                String::new()
+            } else {
+                format!(
+                    ", {} left on the current file",
+                    format_duration(Duration::from_secs(left.div_ceil(rate)))
+                )
+            }
+        ));
+        lines.extend(self.graph(cols.saturating_sub(2)));
+        lines.push(format!(
+            "  {:<w$}  {:>4}  {:>10}  file",
+            "progress",
+            "",
+            "size",
+            w = BAR_WIDTH + 2
+        ));
+
+        for row in self.rows.iter().skip(self.scroll).take(page) {
+            let filled = if row.len == 0 {
+                BAR_WIDTH
+            } else {
+                (row.bytes as u128 * BAR_WIDTH as u128 / row.len as u128) as usize
+            };
+            let (bar, percent) = match row.status {
+                Status::Skipped => (format!("{:<w$}", "unchanged", w = BAR_WIDTH), String::new()),
+                Status::Unreadable => (
+                    format!("{:<w$}", "unreadable", w = BAR_WIDTH),
+                    String::new(),
+                ),
+                _ => (
+                    format!("{}{}", "#".repeat(filled), "-".repeat(BAR_WIDTH - filled)),
+                    format!("{}%", filled * 100 / BAR_WIDTH),
+                ),
+            };
+            lines.push(format!(
+                "  [{}]  {:>4}  {:>10}  {}",
+                bar,
+                percent,
+                format_bytes(row.len),
+                row.path.display()
+            ));
+        }
+        lines.resize(rows.saturating_sub(FOOTER_ROWS), String::new());
+        lines.push(" up/down, pgup/pgdn: scroll   p: pause   q: abort".to_string());
+
+        let mut frame = String::new();
+        for (i, line) in lines.iter().enumerate() {
+            // raw mode doesn't return the carriage on new lines, so every line is placed
+            frame.push_str(&format!("\x1b[{};1H", i + 1));
+            frame.extend(line.chars().take(cols));
+            frame.push_str("\x1b[K");
+        }
+        frame
+    }
+
+    // A bar chart of the throughput over time, the most recent on the right.
+    fn graph(&self, width: usize) -> Vec<String> {
+        let samples = self
+            .samples
+            .iter()
+            .rev()
+            .take(width)
+            .rev()
+            .collect::<Vec<_>>();
+        let peak = samples.iter().copied().max().copied().unwrap_or(0).max(1);
+        (0..GRAPH_ROWS)
+            .rev()
+            .map(|level| {
+                let mut line = " ".to_string();
+                line.extend(samples.iter().map(|&&sample| {
+                    let height =
+                        (sample as u128 * (GRAPH_ROWS * 8) as u128 / peak as u128) as usize;
+                    BLOCKS[height.saturating_sub(level * 8).min(8)]
+                }));
+                line
+            })
+            .collect()
+    }
+}
+
+// Switches the terminal to a full screen interface, and back once dropped.
+struct Terminal {
+    saved: Option<String>,
+}
+
+impl Terminal {
+    fn enter() -> Self {
+        // keys should arrive as they're pressed, without being echoed
+        let saved = stty(&["-g"]).filter(|_| stty(&["raw", "-echo"]).is_some());
+        print!("\x1b[?1049h\x1b[?25l");
+        let _ = io::stdout().flush();
+        Terminal { saved }
+    }
+
+    fn size() -> (usize, usize) {
+        stty(&["size"])
+            .and_then(|size| {
+                let mut parts = size.split_whitespace().map(str::parse);
+                match (parts.next(), parts.next()) {
+                    // terminals that don't know their size say it's zero
+                    (Some(Ok(rows)), Some(Ok(cols))) if rows != 0 && cols != 0 => {
+                        Some((rows, cols))
+                    }
+                    _ => None,
+                }
+            })
+            .unwrap_or(DEFAULT_SIZE)
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        print!("\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        if let Some(saved) = &self.saved {
+            stty(&[saved.trim()]);
+        }
+    }
+}
+
+// Run `stty` on the terminal, returning what it printed if it succeeded.
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty")
+        .args(args)
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    if output.status.success() {
+        String::from_utf8(output.stdout).ok()
+    } else {
+        None
+    }
+}
+
+/// Receives like [`sf::recv`], showing the progress in a full screen interface instead of
+/// printing it. Events still reach the handler already in the options, if any.
+pub fn receive(mut options: ReceiveOptions) -> sf::Result<()> {
+    let state = Arc::new(Mutex::new(State {
+        follow: true,
+        ..State::default()
+    }));
+    let cancel = CancelToken::new();
+    let pause = options.pause.get_or_insert_with(PauseToken::new).clone();
+
+    options.cancel = Some(cancel.clone());
+    let previous = options.events.take();
+    options.events = Some({
+        let state = Arc::clone(&state);
+        Box::new(move |event| {
+            if let Some(previous) = &previous {
+                previous(event.clone());
+            }
+            state.lock().unwrap().handle(event);
+        })
+    });
+
+    let was_quiet = sf::is_quiet();
+    sf::set_quiet(true);
+    let terminal = Terminal::enter();
+
+    // nothing ever stops the wait for the next key, so the thread is left behind at the end
+    {
+        let state = Arc::clone(&state);
+        thread::spawn(move || {
+            let mut buffer = [0u8; 16];
+            let stdin = io::stdin();
+            let mut stdin = stdin.lock();
+            while let Ok(n) = stdin.read(&mut buffer) {
+                if n == 0 {
+                    break;
+                }
+                let page = Terminal::size().0.saturating_sub(HEADER_ROWS + FOOTER_ROWS);
+                let mut state = state.lock().unwrap();
+                match &buffer[..n] {
+                    [b'q'] | [CTRL_C] => {
+                        state.cancelled = true;
+                        cancel.cancel();
+                    }
+                    [b'p'] | [b' '] => state.paused = pause.toggle(),
+                    [ESC, b'[', b'A'] | [b'k'] => state.scroll_by(-1, page),
+                    [ESC, b'[', b'B'] | [b'j'] => state.scroll_by(1, page),
+                    [ESC, b'[', b'5', b'~'] => state.scroll_by(-(page as isize), page),
+                    [ESC, b'[', b'6', b'~'] => state.scroll_by(page as isize, page),
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    let transfer = thread::spawn(move || sf::recv(options));
+
+    let mut size = Terminal::size();
+    let mut next_sample = Instant::now() + SAMPLE_DELAY;
+    while !transfer.is_finished() {
+        thread::sleep(FRAME_DELAY);
+        let mut state = state.lock().unwrap();
+        if Instant::now() >= next_sample {
+            next_sample += SAMPLE_DELAY;
+            state.sample();
+            size = Terminal::size();
+        }
+        let frame = state.draw(size);
+        drop(state);
+        print!("{}", frame);
+        let _ = io::stdout().flush();
+    }
+    let result = transfer.join().unwrap();
+    drop(terminal);
+    sf::set_quiet(was_quiet);
+    result
+}
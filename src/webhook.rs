@@ -0,0 +1,279 @@
+//! Telling another service about every session received, by posting a summary of it as JSON
+//! to a URL, so that it can react to the files as they're delivered without looking for them.
+//!
+//! Both `http://` and `https://` URLs can be used, the latter checked against the web's root
+//! certificates.
+use crate::event::{EventHandler, TransferEvent};
+use crate::hash;
+use crate::json;
+use crate::manifest::to_hex;
+use crate::Result;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::mpsc::{self, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where the summary of every session is posted.
+#[derive(Clone, Debug)]
+pub struct Webhook {
+    url: String,
+}
+
+impl Webhook {
+    /// Posts to the `url`, like `https://example.com/hooks/sf`.
+    pub fn new(url: &str) -> io::Result<Self> {
+        let invalid = |reason: &str| io::Error::new(io::ErrorKind::InvalidInput, reason);
+        let rest = url
+            .strip_prefix("https://")
+            .or_else(|| url.strip_prefix("http://"))
+            .ok_or_else(|| invalid("only http:// and https:// urls are supported"))?;
+        if rest.split('/').next().unwrap_or_default().is_empty() {
+            return Err(invalid("the url must have a host"));
+        }
+        Ok(Webhook {
+            url: url.to_string(),
+        })
+    }
+
+    // Start following the events of the transfer, which still reach the existing handler.
+    pub(crate) fn follow(self, events: &mut Option<EventHandler>, hashed: bool) -> Notifier {
+        let (summaries, pending) = mpsc::channel::<Summary>();
+        let worker = thread::spawn(move || {
+            for summary in pending {
+                if let Err(e) = self.post(&summary.to_json(hashed)) {
+                    out!("could not post the session to the webhook: {}", e);
+                }
+            }
+        });
+
+        let session = Arc::new(Mutex::new(Session::default()));
+        let previous = events.take();
+        *events = Some({
+            let session = Arc::clone(&session);
+            Box::new(move |event| {
+                session.lock().unwrap().handle(&event);
+                if let Some(previous) = &previous {
+                    previous(event);
+                }
+            })
+        });
+        Notifier {
+            summaries,
+            worker,
+            session,
+        }
+    }
+
+    fn post(&self, body: &str) -> io::Result<()> {
+        let agent = ureq::AgentBuilder::new().timeout(TIMEOUT).build();
+        let request = agent
+            .post(&self.url)
+            .set("Content-Type", "application/json");
+        match request.send_string(body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(code, response)) => Err(io::Error::other(format!(
+                "the request failed with {} {}",
+                code,
+                response.status_text()
+            ))),
+            Err(ureq::Error::Transport(e)) => Err(io::Error::other(e)),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Session {
+    peer: Option<SocketAddr>,
+    // when the sender first connected, to tell how long the session took
+    started: Option<(Instant, SystemTime)>,
+    // where the files of this session are, if not in the output directory itself
+    output: Option<PathBuf>,
+    // path and length of every file that started, until it's done
+    receiving: HashMap<usize, (PathBuf, u64)>,
+    done: Vec<(PathBuf, u64)>,
+}
+
+impl Session {
+    fn handle(&mut self, event: &TransferEvent) {
+        match event {
+            // connecting again after losing the connection is still the same session
+            TransferEvent::Connected { peer } if self.peer.is_none() => {
+                self.peer = Some(*peer);
+                self.started = Some((Instant::now(), SystemTime::now()));
+            }
+            TransferEvent::Storing { output } => self.output = Some(output.clone()),
+            TransferEvent::FileStarted { index, path, len } => {
+                self.receiving.insert(*index, (path.clone(), *len));
+            }
+            TransferEvent::FileDone { index } => {
+                if let Some(file) = self.receiving.remove(index) {
+                    self.done.push(file);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+// What's posted once a session is over.
+struct Summary {
+    session: Session,
+    duration: Duration,
+    error: Option<String>,
+}
+
+impl Summary {
+    fn to_json(&self, hashed: bool) -> String {
+        let session = &self.session;
+        let peer = session
+            .peer
+            .map(|peer| peer.to_string())
+            .unwrap_or_default();
+        let started = session
+            .started
+            .and_then(|(_, at)| at.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |since| since.as_secs());
+        let mut out = format!(
+            "{{\"peer\": {}, \"status\": \"{}\", ",
+            json::string(&peer),
+            if self.error.is_some() {
+                "failed"
+            } else {
+                "done"
+            }
+        );
+        if let Some(error) = &self.error {
+            let _ = write!(out, "\"error\": {}, ", json::string(error));
+        }
+        if let Some(output) = &session.output {
+            let _ = write!(
+                out,
+                "\"output\": {}, ",
+                json::string(&output.to_string_lossy())
+            );
+        }
+        let _ = write!(
+            out,
+            "\"started\": {}, \"duration\": {:.3}, \"files\": {}, \"bytes\": {}, \"hashes\": [",
+            started,
+            self.duration.as_secs_f64(),
+            session.done.len(),
+            session.done.iter().map(|(_, len)| len).sum::<u64>()
+        );
+        for (i, (path, len)) in session.done.iter().enumerate() {
+            let _ = write!(
+                out,
+                "{}{{\"path\": {}, \"size\": {}, \"sha256\": ",
+                if i == 0 { "" } else { ", " },
+                json::string(&path.to_string_lossy()),
+                len
+            );
+            // files that are no longer where they were received have nothing to hash
+            match hashed.then(|| hash::hash_file(path)) {
+                Some(Ok(digest)) => {
+                    let _ = write!(out, "\"{}\"}}", to_hex(&digest));
+                }
+                _ => out.push_str("null}"),
+            }
+        }
+        out.push_str("]}");
+        out
+    }
+}
+
+// Posts the summary of every session, one at a time and in order, without holding up the
+// transfers.
+pub(crate) struct Notifier {
+    summaries: Sender<Summary>,
+    worker: JoinHandle<()>,
+    session: Arc<Mutex<Session>>,
+}
+
+impl Notifier {
+    // Post the summary of the session that ended with the `result`, if a sender connected.
+    pub(crate) fn report(&self, result: &Result<()>) {
+        let session = std::mem::take(&mut *self.session.lock().unwrap());
+        let Some((started, _)) = session.started else {
+            return;
+        };
+        let _ = self.summaries.send(Summary {
+            session,
+            duration: started.elapsed(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        });
+    }
+
+    // Wait for every summary to be posted.
+    pub(crate) fn finish(self) {
+        drop(self.summaries);
+        let _ = self.worker.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    // Answer a single request with the `status`, and return the body that came with it.
+    fn serve_once(listener: TcpListener, status: &'static str) -> JoinHandle<String> {
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut len = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_ascii_lowercase();
+                if line.is_empty() {
+                    break;
+                }
+                if let Some(value) = line.strip_prefix("content-length:") {
+                    len = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body).unwrap();
+            write!(
+                reader.get_mut(),
+                "HTTP/1.1 {}\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                status
+            )
+            .unwrap();
+            String::from_utf8(body).unwrap()
+        })
+    }
+
+    #[test]
+    fn summaries_are_posted() {
+        for (status, ok) in [
+            ("204 No Content", true),
+            ("500 Internal Server Error", false),
+        ] {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/hooks/sf", listener.local_addr().unwrap());
+            let server = serve_once(listener, status);
+            let posted = Webhook::new(&url).unwrap().post("{\"files\": 0}");
+            assert_eq!(posted.is_ok(), ok, "{}: {:?}", status, posted);
+            assert_eq!(server.join().unwrap(), "{\"files\": 0}");
+        }
+    }
+
+    #[test]
+    fn only_web_urls_are_taken() {
+        for url in ["https://example.com/hooks/sf", "http://localhost:8080"] {
+            assert!(Webhook::new(url).is_ok(), "{}", url);
+        }
+        for url in ["ftp://example.com", "https:///hooks", "example.com"] {
+            assert!(Webhook::new(url).is_err(), "{}", url);
+        }
+    }
+}
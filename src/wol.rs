@@ -0,0 +1,63 @@
+//! Wake-on-LAN, to turn on a sleeping machine before sending it files. The machine listens
+//! for a "magic packet" with its hardware address repeated, broadcast to the whole network.
+
+use crate::ip::get_ip_addresses;
+use crate::Result;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+
+/// The hardware address of a network interface, which is what a sleeping machine answers to.
+pub type MacAddress = [u8; 6];
+
+const WOL_PORT: u16 = 9; // the discard port, which is what most machines expect it on
+const WOL_ATTEMPTS: usize = 3; // broadcasts are easily lost, and repeating them costs nothing
+
+/// Parses a hardware address like `01:23:45:67:89:ab` or `01-23-45-67-89-AB`.
+pub fn parse_mac_address(text: &str) -> Option<MacAddress> {
+    let mut mac = [0; 6];
+    let mut parts = text.split([':', '-']);
+    for byte in mac.iter_mut() {
+        let part = parts.next()?;
+        if part.len() != 2 {
+            return None;
+        }
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+    parts.next().is_none().then_some(mac)
+}
+
+/// Broadcasts the magic packet that wakes the machine with the given hardware address on
+/// every network this one is in. Nothing says whether it woke up, only finding it does.
+pub fn wake(mac: MacAddress) -> Result<()> {
+    let mut packet = vec![0xff; 6];
+    for _ in 0..16 {
+        packet.extend(&mac);
+    }
+
+    // the limited broadcast only leaves through one interface, so each network gets its own
+    let mut targets = vec![IpAddr::from(Ipv4Addr::BROADCAST)];
+    for interface in get_ip_addresses()? {
+        match interface.broadcast {
+            Some(broadcast) if interface.is_up && !targets.contains(&broadcast) => {
+                targets.push(broadcast)
+            }
+            _ => {}
+        }
+    }
+
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+    socket.set_broadcast(true)?;
+    let mut sent = false;
+    let mut last_error = None;
+    for _ in 0..WOL_ATTEMPTS {
+        for &target in &targets {
+            match socket.send_to(&packet, SocketAddr::new(target, WOL_PORT)) {
+                Ok(_) => sent = true,
+                Err(e) => last_error = Some(e),
+            }
+        }
+    }
+    match last_error {
+        Some(e) if !sent => Err(e.into()),
+        _ => Ok(()),
+    }
+}
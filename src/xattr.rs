@@ -0,0 +1,260 @@
+//! Extended attributes of files, which on Linux also hold their POSIX ACLs.
+
+use std::io;
+use std::path::Path;
+
+/// Groups of extended attributes, told apart by how their names start.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum XattrNamespace {
+    /// Those in `user.`, which is where every attribute goes on systems without namespaces.
+    User,
+    /// Those in `security.`, such as SELinux labels and file capabilities.
+    Security,
+    /// Those in `trusted.`, which only privileged processes can see.
+    Trusted,
+    /// The POSIX ACLs, stored in `system.posix_acl_access` and `system.posix_acl_default`.
+    Acl,
+}
+
+/// The namespace of the attribute with the given name, if it's one of the known ones.
+pub(crate) fn namespace(name: &[u8]) -> Option<XattrNamespace> {
+    if name.starts_with(b"user.") {
+        Some(XattrNamespace::User)
+    } else if name.starts_with(b"security.") {
+        Some(XattrNamespace::Security)
+    } else if name.starts_with(b"trusted.") {
+        Some(XattrNamespace::Trusted)
+    } else if name.starts_with(b"system.posix_acl_") {
+        Some(XattrNamespace::Acl)
+    } else if name.starts_with(b"system.") {
+        None
+    } else {
+        // macOS names them however it likes
+        Some(XattrNamespace::User)
+    }
+}
+
+/// The names and values of the attributes of the file in the given namespaces.
+pub(crate) fn get_all(
+    path: &Path,
+    namespaces: &[XattrNamespace],
+) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let path = sys::c_path(path)?;
+    let list = read_sized(|buffer| unsafe { sys::list(&path, buffer) })?;
+    let mut xattrs = Vec::new();
+    for name in list.split(|&c| c == 0).filter(|name| !name.is_empty()) {
+        if !namespace(name).is_some_and(|ns| namespaces.contains(&ns)) {
+            continue;
+        }
+        let c_name = sys::c_name(name)?;
+        match read_sized(|buffer| unsafe { sys::get(&path, &c_name, buffer) }) {
+            Ok(value) => xattrs.push((name.to_vec(), value)),
+            // removed since it was listed
+            Err(e) if e.raw_os_error() == Some(sys::ENOATTR) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Sets the attribute of the file, replacing its value if it had one.
+pub(crate) fn set(path: &Path, name: &[u8], value: &[u8]) -> io::Result<()> {
+    let (path, name) = (sys::c_path(path)?, sys::c_name(name)?);
+    if unsafe { sys::set(&path, &name, value) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Read something whose size is only known by asking first, in case it grows in between.
+fn read_sized(mut read: impl FnMut(&mut [u8]) -> isize) -> io::Result<Vec<u8>> {
+    loop {
+        let len = read(&mut []);
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut buffer = vec![0; len as usize];
+        let len = read(&mut buffer);
+        if len >= 0 {
+            buffer.truncate(len as usize);
+            return Ok(buffer);
+        }
+        let e = io::Error::last_os_error();
+        if e.raw_os_error() != Some(sys::ERANGE) {
+            return Err(e);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    // errno.h
+    pub const ERANGE: i32 = 34;
+    pub const ENOATTR: i32 = 61; // ENODATA
+
+    extern "C" {
+        fn listxattr(path: *const c_char, list: *mut c_char, size: usize) -> isize;
+        fn getxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *mut c_void,
+            size: usize,
+        ) -> isize;
+        fn setxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *const c_void,
+            size: usize,
+            flags: c_int,
+        ) -> c_int;
+    }
+
+    pub fn c_path(path: &Path) -> io::Result<CString> {
+        c_name(path.as_os_str().as_bytes())
+    }
+
+    pub fn c_name(name: &[u8]) -> io::Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub unsafe fn list(path: &CString, buffer: &mut [u8]) -> isize {
+        listxattr(path.as_ptr(), buffer.as_mut_ptr() as _, buffer.len())
+    }
+
+    pub unsafe fn get(path: &CString, name: &CString, buffer: &mut [u8]) -> isize {
+        getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buffer.as_mut_ptr() as _,
+            buffer.len(),
+        )
+    }
+
+    pub unsafe fn set(path: &CString, name: &CString, value: &[u8]) -> c_int {
+        setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as _,
+            value.len(),
+            0,
+        )
+    }
+}
+
+// The same as on Linux, but with a position (only used for resource forks) and options.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::{c_char, c_int, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::path::Path;
+
+    // errno.h
+    pub const ERANGE: i32 = 34;
+    pub const ENOATTR: i32 = 93;
+
+    extern "C" {
+        fn listxattr(path: *const c_char, list: *mut c_char, size: usize, options: c_int) -> isize;
+        fn getxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *mut c_void,
+            size: usize,
+            position: u32,
+            options: c_int,
+        ) -> isize;
+        fn setxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *const c_void,
+            size: usize,
+            position: u32,
+            options: c_int,
+        ) -> c_int;
+    }
+
+    pub fn c_path(path: &Path) -> io::Result<CString> {
+        c_name(path.as_os_str().as_bytes())
+    }
+
+    pub fn c_name(name: &[u8]) -> io::Result<CString> {
+        CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+
+    pub unsafe fn list(path: &CString, buffer: &mut [u8]) -> isize {
+        listxattr(path.as_ptr(), buffer.as_mut_ptr() as _, buffer.len(), 0)
+    }
+
+    pub unsafe fn get(path: &CString, name: &CString, buffer: &mut [u8]) -> isize {
+        getxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buffer.as_mut_ptr() as _,
+            buffer.len(),
+            0,
+            0,
+        )
+    }
+
+    pub unsafe fn set(path: &CString, name: &CString, value: &[u8]) -> c_int {
+        setxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as _,
+            value.len(),
+            0,
+            0,
+        )
+    }
+}
+
+// Everywhere else, every attempt fails as unsupported.
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    target_os = "ios"
+)))]
+mod sys {
+    use std::io;
+    use std::path::Path;
+
+    pub const ERANGE: i32 = 0;
+    pub const ENOATTR: i32 = 0;
+
+    pub struct Unsupported;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes are not supported on this system",
+        )
+    }
+
+    pub fn c_path(_path: &Path) -> io::Result<Unsupported> {
+        Err(unsupported())
+    }
+
+    pub fn c_name(_name: &[u8]) -> io::Result<Unsupported> {
+        Err(unsupported())
+    }
+
+    pub unsafe fn list(_path: &Unsupported, _buffer: &mut [u8]) -> isize {
+        -1
+    }
+
+    pub unsafe fn get(_path: &Unsupported, _name: &Unsupported, _buffer: &mut [u8]) -> isize {
+        -1
+    }
+
+    pub unsafe fn set(_path: &Unsupported, _name: &Unsupported, _value: &[u8]) -> i32 {
+        -1
+    }
+}
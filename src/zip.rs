@@ -0,0 +1,225 @@
+use std::convert::TryFrom;
+
+pub const LOCAL_HEADER: u32 = 0x04034b50;
+pub const CENTRAL_HEADER: u32 = 0x02014b50;
+pub const END: u32 = 0x06054b50;
+pub const DESCRIPTOR: u32 = 0x08074b50;
+const ZIP64_END: u32 = 0x06064b50;
+const ZIP64_LOCATOR: u32 = 0x07064b50;
+pub const ENCRYPTED: u16 = 0x1; // general purpose flags
+pub const HAS_DESCRIPTOR: u16 = 0x8;
+const UTF8_NAME: u16 = 0x800;
+pub const STORED: u16 = 0; // compression methods
+pub const DEFLATED: u16 = 8;
+pub const ZIP64_EXTRA: u16 = 0x0001; // extra fields
+pub const TIMESTAMP_EXTRA: u16 = 0x5455;
+const VERSION: u16 = 20; // the version needed to extract, which is 4.5 for zip64
+const VERSION_ZIP64: u16 = 45;
+const MADE_BY_UNIX: u16 = 3 << 8;
+const FILE_MODE: u32 = 0o100644;
+
+/// Where the checksum is in a local header, which is only known once the data was written.
+pub const CRC_OFFSET: u64 = 14;
+
+/// A file stored in an archive, as the central directory lists it at the end.
+pub struct Entry {
+    pub name: Vec<u8>,
+    pub len: u64,
+    pub mtime: u64,
+    pub crc: u32,
+    // where its local header starts
+    pub offset: u64,
+}
+
+impl Entry {
+    fn zip64(&self) -> bool {
+        self.len >= u32::MAX as u64 || self.offset >= u32::MAX as u64
+    }
+
+    // The fields shared by the local and central headers, from the version needed on.
+    fn common(&self, extra_len: usize) -> Vec<u8> {
+        let (date, time) = dos_time(self.mtime);
+        let mut fields = Vec::with_capacity(26);
+        fields.extend(if self.zip64() { VERSION_ZIP64 } else { VERSION }.to_le_bytes());
+        let flags = if std::str::from_utf8(&self.name).is_ok() {
+            UTF8_NAME
+        } else {
+            0
+        };
+        fields.extend(flags.to_le_bytes());
+        fields.extend(STORED.to_le_bytes());
+        fields.extend(time.to_le_bytes());
+        fields.extend(date.to_le_bytes());
+        fields.extend(self.crc.to_le_bytes());
+        // stored as they are, so the compressed size is the same
+        let len = self.len.min(u32::MAX as u64) as u32;
+        fields.extend(len.to_le_bytes());
+        fields.extend(len.to_le_bytes());
+        fields.extend((self.name.len() as u16).to_le_bytes());
+        fields.extend((extra_len as u16).to_le_bytes());
+        fields
+    }
+
+    // The modification time in full, as the MS-DOS one is only good to two seconds.
+    fn timestamp(&self) -> Vec<u8> {
+        match u32::try_from(self.mtime) {
+            // only the modification time is there, as the flags say
+            Ok(mtime) => extra_field(
+                TIMESTAMP_EXTRA,
+                &[[1].as_ref(), &mtime.to_le_bytes()].concat(),
+            ),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// Builds the header in front of the data of the `entry`.
+pub fn local_header(entry: &Entry) -> Vec<u8> {
+    let mut extra = Vec::new();
+    if entry.len >= u32::MAX as u64 {
+        extra = extra_field(ZIP64_EXTRA, &[entry.len.to_le_bytes(); 2].concat());
+    }
+    extra.extend(entry.timestamp());
+
+    let mut header = LOCAL_HEADER.to_le_bytes().to_vec();
+    header.extend(entry.common(extra.len()));
+    header.extend(&entry.name);
+    header.extend(extra);
+    header
+}
+
+/// Builds the record of the `entry` in the central directory.
+pub fn central_header(entry: &Entry) -> Vec<u8> {
+    // only the fields too large for their place in the header are there
+    let mut sizes = Vec::new();
+    if entry.len >= u32::MAX as u64 {
+        sizes.extend([entry.len.to_le_bytes(); 2].concat());
+    }
+    if entry.offset >= u32::MAX as u64 {
+        sizes.extend(entry.offset.to_le_bytes());
+    }
+    let mut extra = Vec::new();
+    if !sizes.is_empty() {
+        extra = extra_field(ZIP64_EXTRA, &sizes);
+    }
+    extra.extend(entry.timestamp());
+
+    let mut header = CENTRAL_HEADER.to_le_bytes().to_vec();
+    let version = if entry.zip64() {
+        VERSION_ZIP64
+    } else {
+        VERSION
+    };
+    header.extend((MADE_BY_UNIX | version).to_le_bytes());
+    header.extend(entry.common(extra.len()));
+    header.extend(0u16.to_le_bytes()); // comment length
+    header.extend(0u16.to_le_bytes()); // disk
+    header.extend(0u16.to_le_bytes()); // internal attributes
+    header.extend((FILE_MODE << 16).to_le_bytes());
+    header.extend((entry.offset.min(u32::MAX as u64) as u32).to_le_bytes());
+    header.extend(&entry.name);
+    header.extend(extra);
+    header
+}
+
+/// Builds the end of the archive, after a central directory of `count` entries that starts
+/// at `offset` and is `len` bytes long. Archives too large for it have a zip64 one before it.
+pub fn end(count: usize, offset: u64, len: u64) -> Vec<u8> {
+    let mut end = Vec::new();
+    let zip64 = count >= u16::MAX as usize || offset >= u32::MAX as u64 || len >= u32::MAX as u64;
+    if zip64 {
+        end.extend(ZIP64_END.to_le_bytes());
+        end.extend(44u64.to_le_bytes()); // the size of what's left of it
+        end.extend((MADE_BY_UNIX | VERSION_ZIP64).to_le_bytes());
+        end.extend(VERSION_ZIP64.to_le_bytes());
+        end.extend([0; 8]); // disks
+        end.extend((count as u64).to_le_bytes());
+        end.extend((count as u64).to_le_bytes());
+        end.extend(len.to_le_bytes());
+        end.extend(offset.to_le_bytes());
+
+        end.extend(ZIP64_LOCATOR.to_le_bytes());
+        end.extend(0u32.to_le_bytes());
+        end.extend((offset + len).to_le_bytes());
+        end.extend(1u32.to_le_bytes());
+    }
+
+    end.extend(END.to_le_bytes());
+    end.extend([0; 4]); // disks
+    let count = count.min(u16::MAX as usize) as u16;
+    end.extend(count.to_le_bytes());
+    end.extend(count.to_le_bytes());
+    end.extend((len.min(u32::MAX as u64) as u32).to_le_bytes());
+    end.extend((offset.min(u32::MAX as u64) as u32).to_le_bytes());
+    end.extend(0u16.to_le_bytes()); // comment length
+    end
+}
+
+fn extra_field(id: u16, data: &[u8]) -> Vec<u8> {
+    let mut field = id.to_le_bytes().to_vec();
+    field.extend((data.len() as u16).to_le_bytes());
+    field.extend(data);
+    field
+}
+
+// The MS-DOS date and time of the seconds since the epoch, which can't be before 1980.
+fn dos_time(secs: u64) -> (u16, u16) {
+    let days = (secs / 86400) as i64;
+    let secs = secs % 86400;
+    // from the days since the epoch to the civil date, as in Howard Hinnant's algorithm
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    if !(1980..=2107).contains(&year) {
+        // the earliest date there is, as a year out of range can't be told apart from another
+        return (1 << 5 | 1, 0);
+    }
+    let date = ((year - 1980) << 9 | month << 5 | day) as u16;
+    let time = ((secs / 3600) << 11 | (secs % 3600 / 60) << 5 | (secs % 60 / 2)) as u16;
+    (date, time)
+}
+
+/// The CRC-32 that both gzip and zip check their data with.
+pub struct Crc32(u32);
+
+const CRC_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = CRC_TABLE[((self.0 ^ byte as u32) & 0xff) as usize] ^ (self.0 >> 8);
+        }
+    }
+
+    pub fn finish(self) -> u32 {
+        !self.0
+    }
+}
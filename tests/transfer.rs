@@ -0,0 +1,329 @@
+//! Sending files to a receiver over the loopback interface, and checking they arrive intact.
+
+use sf::{
+    ArchiveFormat, CaseCollisions, Compression, PathPrefix, ReceiveOptions, SendOptions,
+    SocketOptions, TransferEvent,
+};
+use std::fs;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::thread;
+use std::time::Duration;
+
+// A directory of its own for every test, emptied before it runs.
+fn scratch(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir()
+        .join(format!("sf-test-{}", std::process::id()))
+        .join(name);
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn send_options() -> SendOptions {
+    let mut options = SendOptions::default();
+    options.allow_metered = true;
+    options
+}
+
+fn receive_options(output: &Path, listener: TcpListener) -> ReceiveOptions {
+    let mut options = ReceiveOptions::default();
+    options.prefix = PathPrefix::Strip;
+    options.case_collisions = CaseCollisions::Ignore;
+    options.output = output.to_path_buf();
+    options.listener = Some(listener);
+    options
+}
+
+// Send the `files` to a receiver storing them in `output`, and wait for both to be done.
+fn transfer(files: Vec<PathBuf>, options: SendOptions, output: &Path) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = {
+        let options = receive_options(output, listener);
+        thread::spawn(move || sf::recv(options))
+    };
+    sf::send(addr, files, &options).unwrap();
+    receiver.join().unwrap().unwrap();
+}
+
+// Write a few files of different sizes under `dir`, returning their names and contents. The
+// receiver strips the directories all of them are in, so they're stored under the same names.
+fn make_tree(dir: &Path) -> Vec<(PathBuf, Vec<u8>)> {
+    let files = vec![
+        (PathBuf::from("empty"), Vec::new()),
+        (PathBuf::from("hello.txt"), b"hello, world\n".to_vec()),
+        (
+            PathBuf::from("nested/deeper/text.log"),
+            b"the same line, again and again\n".repeat(20_000),
+        ),
+        (
+            PathBuf::from("nested/noise.bin"),
+            (0..3 * 1024 * 1024u32)
+                .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+                .collect(),
+        ),
+    ];
+    for (name, data) in &files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, data).unwrap();
+    }
+    files
+}
+
+fn assert_received(output: &Path, files: &[(PathBuf, Vec<u8>)]) {
+    for (name, data) in files {
+        let received = fs::read(output.join(name))
+            .unwrap_or_else(|e| panic!("{:?} was not received: {}", name, e));
+        assert!(received == *data, "{:?} was received with other data", name);
+    }
+}
+
+#[test]
+fn sends_a_directory() {
+    let dir = scratch("directory");
+    let files = make_tree(&dir.join("tree"));
+    let output = dir.join("out");
+    transfer(vec![dir.join("tree")], send_options(), &output);
+    assert_received(&output, &files);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// The transfers can also be awaited, even as tasks of their own on the same runtime.
+#[test]
+fn sends_from_a_runtime() {
+    let dir = scratch("runtime");
+    let files = make_tree(&dir.join("tree"));
+    let output = dir.join("out");
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let options = receive_options(&output, listener);
+    sf::task::block_on(async {
+        let receiver = tokio::spawn(sf::recv_async(options));
+        sf::send_async(addr, vec![dir.join("tree")], send_options())
+            .await
+            .unwrap();
+        receiver.await.unwrap().unwrap();
+    });
+    assert_received(&output, &files);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sends_compressed_and_hashed() {
+    let dir = scratch("compressed");
+    let files = make_tree(&dir.join("tree"));
+    let output = dir.join("out");
+    let mut options = send_options();
+    options.compress = Compression::Always;
+    options.hashes = true;
+    transfer(vec![dir.join("tree")], options, &output);
+    assert_received(&output, &files);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// Directories sent as a single archive come out the same once the receiver unpacks them.
+#[test]
+fn sends_directories_as_archives() {
+    for format in [
+        ArchiveFormat::Tar,
+        ArchiveFormat::TarZst,
+        ArchiveFormat::Zip,
+    ] {
+        let dir = scratch(&format!("archive-{}", format.extension()));
+        let files = make_tree(&dir.join("tree"));
+        let output = dir.join("out");
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let receiver = {
+            let mut options = receive_options(&output, listener);
+            options.extract = true;
+            thread::spawn(move || sf::recv(options))
+        };
+        let mut options = send_options();
+        options.archive = Some(format);
+        sf::send(addr, vec![dir.join("tree")], &options).unwrap();
+        receiver.join().unwrap().unwrap();
+        assert_received(&output.join("tree"), &files);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
+
+// What an archive unpacks to is held to the quota and the filters of the receiver, like the
+// files sent one by one, rather than to what the archive itself takes.
+#[test]
+fn extracts_archives_within_the_limits() {
+    let dir = scratch("extract-limits");
+    let files = make_tree(&dir.join("tree"));
+    let unpacked_len = files.iter().map(|(_, data)| data.len() as u64).sum::<u64>();
+    let mut options = send_options();
+    options.archive = Some(ArchiveFormat::TarZst);
+    let receive = |output: &Path, configure: &dyn Fn(&mut ReceiveOptions)| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let mut receive_options = receive_options(output, listener);
+        receive_options.extract = true;
+        configure(&mut receive_options);
+        let receiver = thread::spawn(move || sf::recv(receive_options));
+        let sent = sf::send(addr, vec![dir.join("tree")], &options);
+        (sent, receiver.join().unwrap())
+    };
+
+    // the archive is smaller than the quota, but not what's in it
+    let output = dir.join("over-quota");
+    let (_, received) = receive(&output, &|options| options.quota = Some(unpacked_len - 1));
+    let e = received.unwrap_err().to_string();
+    assert!(e.contains("unpacks to more than"), "{}", e);
+
+    let output = dir.join("filtered");
+    let (sent, received) = receive(&output, &|options| {
+        options.quota = Some(unpacked_len);
+        options.filter.reject_ext = vec!["bin".into()];
+    });
+    sent.unwrap();
+    received.unwrap();
+    let (rejected, kept): (Vec<_>, Vec<_>) = files
+        .into_iter()
+        .partition(|(name, _)| name.extension().is_some_and(|ext| ext == "bin"));
+    assert_received(&output.join("tree"), &kept);
+    for (name, _) in rejected {
+        assert!(!output.join("tree").join(name).exists());
+    }
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sends_with_the_legacy_version() {
+    let dir = scratch("legacy");
+    let files = make_tree(&dir.join("tree"));
+    let output = dir.join("out");
+    let mut options = send_options();
+    options.legacy = true;
+    transfer(vec![dir.join("tree")], options, &output);
+    assert_received(&output, &files);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn skips_what_the_receiver_has_when_updating() {
+    let dir = scratch("update");
+    let files = make_tree(&dir.join("tree"));
+    let output = dir.join("out");
+    transfer(vec![dir.join("tree")], send_options(), &output);
+
+    let (changed, _) = &files[1];
+    fs::write(dir.join("tree").join(changed), b"changed").unwrap();
+    let (events, received) = sf::event::channel();
+    let mut options = send_options();
+    options.update = true;
+    options.events = Some(events);
+    transfer(vec![dir.join("tree")], options, &output);
+    assert_eq!(fs::read(output.join(changed)).unwrap(), b"changed");
+    assert_received(&output, &files[2..]);
+    let skipped = received
+        .try_iter()
+        .filter(|event| matches!(event, TransferEvent::FileSkipped { .. }))
+        .count();
+    assert_eq!(skipped, files.len() - 1);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn compares_with_the_output_when_staging() {
+    let dir = scratch("staging");
+    let files = make_tree(&dir.join("tree"));
+    let (output, staging) = (dir.join("out"), dir.join("staging"));
+    transfer(vec![dir.join("tree")], send_options(), &output);
+
+    let (changed, _) = &files[1];
+    fs::write(dir.join("tree").join(changed), b"changed").unwrap();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let (events, received) = sf::event::channel();
+    let receiver = {
+        let mut options = receive_options(&output, listener);
+        options.staging = Some(staging.clone());
+        options.events = Some(events);
+        thread::spawn(move || sf::recv(options))
+    };
+    let mut options = send_options();
+    options.update = true;
+    sf::send(addr, vec![dir.join("tree")], &options).unwrap();
+    receiver.join().unwrap().unwrap();
+
+    assert_eq!(fs::read(output.join(changed)).unwrap(), b"changed");
+    assert_received(&output, &files[2..]);
+    let started = received
+        .try_iter()
+        .filter_map(|event| match event {
+            TransferEvent::FileStarted { path, .. } => Some(path),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(started, [output.join(changed)]);
+    assert_eq!(fs::read_dir(&staging).unwrap().count(), 0);
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn sends_again_what_changed_while_being_sent() {
+    let dir = scratch("changed");
+    let path = dir.join("grows.txt");
+    fs::write(&path, b"before\n").unwrap();
+    let output = dir.join("out");
+    let written = AtomicBool::new(false);
+    let mut options = send_options();
+    options.resend_changed = true;
+    options.events = Some(Box::new({
+        let path = path.clone();
+        move |event| {
+            if matches!(event, TransferEvent::FileStarted { .. }) && !written.swap(true, SeqCst) {
+                fs::write(&path, b"after, and longer\n").unwrap();
+            }
+        }
+    }));
+    transfer(vec![path], options, &output);
+    assert_eq!(
+        fs::read(output.join("grows.txt")).unwrap(),
+        b"after, and longer\n"
+    );
+    fs::remove_dir_all(dir).unwrap();
+}
+
+// The files of later batches take longer to hash than the peers wait for each other, which must
+// not happen while they're connected.
+#[test]
+fn hashes_before_the_peer_times_out() {
+    let dir = scratch("slow-hashes");
+    let tree = dir.join("tree");
+    fs::create_dir_all(&tree).unwrap();
+    for i in 0..1030 {
+        fs::write(tree.join(format!("small-{:04}", i)), i.to_string()).unwrap();
+    }
+    let large = vec![7u8; 16 * 1024 * 1024];
+    for i in 0..4 {
+        fs::write(tree.join(format!("zz-large-{}", i)), &large).unwrap();
+    }
+    let output = dir.join("out");
+    let socket = SocketOptions {
+        timeout: Some(Duration::from_secs(1)),
+        ..SocketOptions::default()
+    };
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let receiver = {
+        let mut options = receive_options(&output, listener);
+        options.socket = socket;
+        thread::spawn(move || sf::recv(options))
+    };
+    let mut options = send_options();
+    options.hashes = true;
+    options.socket = socket;
+    sf::send(addr, vec![tree], &options).unwrap();
+    receiver.join().unwrap().unwrap();
+    assert_eq!(fs::read_dir(&output).unwrap().count(), 1034);
+    assert_eq!(fs::read(output.join("zz-large-3")).unwrap(), large);
+    fs::remove_dir_all(dir).unwrap();
+}